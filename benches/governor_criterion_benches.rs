@@ -1,11 +1,13 @@
 use criterion::{criterion_group, criterion_main};
 
+mod hot_path;
 mod multi_threaded;
 mod realtime_clock;
 mod single_threaded;
 
 criterion_group!(
     benches,
+    hot_path::bench_all,
     realtime_clock::bench_all,
     single_threaded::bench_all,
     multi_threaded::bench_all,
@@ -0,0 +1,55 @@
+//! Benchmarks for the cost of a single rate-limiting decision on a direct rate limiter, without
+//! the overhead of reading a real-time clock.
+//!
+//! `conforming` measures the common case (an uncontended rate limiter that allows the cell
+//! through), `nonconforming` the case of a rate limiter that denies every cell, and
+//! `arrivals_64` a batch of 64 cells checked at once. See the "Performance" section of the guide
+//! for the budget these are expected to stay within.
+
+use criterion::{black_box, Criterion, Throughput};
+use governor::{clock, Quota, RateLimiter};
+use nonzero_ext::*;
+use std::time::Duration;
+
+pub fn bench_all(c: &mut Criterion) {
+    let mut group = c.benchmark_group("hot_path");
+    group.throughput(Throughput::Elements(1));
+
+    group.bench_function("conforming", |b| {
+        let clock = clock::FakeRelativeClock::default();
+        let rl = RateLimiter::direct_with_clock(
+            Quota::new(nonzero!(u32::MAX), Duration::from_nanos(1)).unwrap(),
+            &clock,
+        );
+        b.iter(|| {
+            black_box(rl.check().is_ok());
+        });
+    });
+
+    group.bench_function("nonconforming", |b| {
+        let clock = clock::FakeRelativeClock::default();
+        let rl = RateLimiter::direct_with_clock(Quota::per_hour(nonzero!(1u32)), &clock);
+        rl.check().unwrap();
+        b.iter(|| {
+            black_box(rl.check().is_ok());
+        });
+    });
+
+    group.finish();
+
+    let mut group = c.benchmark_group("hot_path");
+    group.throughput(Throughput::Elements(64));
+    group.bench_function("arrivals_64", |b| {
+        let clock = clock::FakeRelativeClock::default();
+        let rl = RateLimiter::direct_with_clock(
+            Quota::new(nonzero!(u32::MAX), Duration::from_nanos(1)).unwrap(),
+            &clock,
+        );
+        let arrivals = [clock::Clock::now(&clock); 64];
+        let mut conforming = [0u64; 1];
+        b.iter(|| {
+            black_box(rl.check_arrivals(&arrivals, &mut conforming));
+        });
+    });
+    group.finish();
+}
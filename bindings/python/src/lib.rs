@@ -0,0 +1,389 @@
+//! Python bindings for governor's rate limiters, as an extension module built with
+//! [pyo3](https://pyo3.rs).
+//!
+//! The `governor` Python module exposes `Quota`, `DirectRateLimiter` and `KeyedRateLimiter`
+//! (keyed by `str` or `bytes`). Their `until_ready` and `until_key_ready` methods return
+//! `asyncio` coroutines, which sleep on the event loop for as long as the rate limiter says.
+//! (Running the waits on a Tokio runtime, as pyo3-asyncio does, races the runtime's threads
+//! against the interpreter's shutdown.) Build and install the module with
+//! [maturin](https://www.maturin.rs):
+//!
+//! ```sh
+//! cd bindings/python && maturin develop --release
+//! ```
+//!
+//! ```python
+//! import asyncio
+//! from governor import Quota, DirectRateLimiter, KeyedRateLimiter
+//!
+//! lim = DirectRateLimiter(Quota.per_second(50))
+//! assert lim.check() is None
+//!
+//! per_host = KeyedRateLimiter(Quota.per_minute(10).allow_burst(2))
+//!
+//! async def scrape():
+//!     await per_host.until_key_ready("example.com")
+//!
+//! asyncio.run(scrape())
+//! ```
+
+use governor::clock::{Clock, DefaultClock};
+use governor::{
+    DefaultDirectRateLimiter, DefaultKeyedRateLimiter, NegativeMultiDecision, NotUntil, RateLimiter,
+};
+use pyo3::exceptions::PyValueError;
+use pyo3::ffi::c_str;
+use pyo3::prelude::*;
+use pyo3::sync::GILOnceCell;
+use pyo3::{create_exception, intern};
+use std::num::NonZeroU32;
+use std::time::Duration;
+
+create_exception!(
+    governor,
+    InsufficientCapacity,
+    PyValueError,
+    "Raised when more cells are checked at once than the burst size allows."
+);
+
+/// Returns a coroutine that calls `check` with `args` until it returns `None`, sleeping for the
+/// number of seconds that it returns in between.
+fn wait_until_checked<'p, A: IntoPyObject<'p>>(
+    check: Bound<'p, PyAny>,
+    args: A,
+) -> PyResult<Bound<'p, PyAny>> {
+    static WAIT: GILOnceCell<Py<PyAny>> = GILOnceCell::new();
+    let py = check.py();
+    let wait = WAIT.get_or_try_init(py, || {
+        let code = c_str!(
+            r#"
+import asyncio
+
+async def wait(check, args):
+    while True:
+        seconds = check(*args)
+        if seconds is None:
+            return
+        await asyncio.sleep(seconds)
+"#
+        );
+        PyModule::from_code(
+            py,
+            code,
+            c_str!("governor_wait.py"),
+            c_str!("governor_wait"),
+        )?
+        .getattr(intern!(py, "wait"))
+        .map(Bound::unbind)
+    })?;
+    wait.bind(py).call1((check, args))
+}
+
+/// Turns a decision into the number of seconds to wait (`None` if the cells were allowed).
+fn seconds_to_wait(
+    decision: Result<(), NegativeMultiDecision<NotUntil<<DefaultClock as Clock>::Instant>>>,
+) -> PyResult<Option<f64>> {
+    match decision {
+        Ok(()) => Ok(None),
+        Err(NegativeMultiDecision::BatchNonConforming(_, negative)) => {
+            let now = DefaultClock::default().now();
+            Ok(Some(negative.wait_time_from(now).as_secs_f64()))
+        }
+        Err(NegativeMultiDecision::InsufficientCapacity(_)) => Err(InsufficientCapacity::new_err(
+            "the quota's burst size is too small for this many cells",
+        )),
+    }
+}
+
+/// A rate-limiting quota: a number of cells that are replenished per period, and the size of
+/// the bursts that it allows.
+#[pyclass(frozen)]
+#[derive(Clone, Copy)]
+struct Quota(governor::Quota);
+
+#[pymethods]
+impl Quota {
+    /// Replenishes `max_burst` cells per second, in bursts of up to `max_burst` cells.
+    #[staticmethod]
+    fn per_second(max_burst: NonZeroU32) -> Self {
+        Quota(governor::Quota::per_second(max_burst))
+    }
+
+    /// Replenishes `max_burst` cells per minute, in bursts of up to `max_burst` cells.
+    #[staticmethod]
+    fn per_minute(max_burst: NonZeroU32) -> Self {
+        Quota(governor::Quota::per_minute(max_burst))
+    }
+
+    /// Replenishes `max_burst` cells per hour, in bursts of up to `max_burst` cells.
+    #[staticmethod]
+    fn per_hour(max_burst: NonZeroU32) -> Self {
+        Quota(governor::Quota::per_hour(max_burst))
+    }
+
+    /// Replenishes one cell every `seconds` (which may be fractional), in bursts of one cell.
+    #[staticmethod]
+    fn with_period(seconds: f64) -> PyResult<Self> {
+        Duration::try_from_secs_f64(seconds)
+            .ok()
+            .and_then(governor::Quota::with_period)
+            .map(Quota)
+            .ok_or_else(|| PyValueError::new_err("quotas need a positive period"))
+    }
+
+    /// Returns a quota with the same rate, in bursts of up to `max_burst` cells.
+    fn allow_burst(&self, max_burst: NonZeroU32) -> Self {
+        Quota(self.0.allow_burst(max_burst))
+    }
+
+    /// The number of cells in a burst.
+    #[getter]
+    fn burst_size(&self) -> u32 {
+        self.0.burst_size().get()
+    }
+
+    /// The number of seconds that it takes to replenish a single cell.
+    #[getter]
+    fn replenish_interval(&self) -> f64 {
+        self.0.replenish_interval().as_secs_f64()
+    }
+
+    fn __repr__(&self) -> String {
+        format!(
+            "Quota(replenish_interval={}s, burst_size={})",
+            self.replenish_interval(),
+            self.burst_size()
+        )
+    }
+}
+
+/// A rate limiter for a single stream of cells.
+#[pyclass(frozen)]
+struct DirectRateLimiter {
+    limiter: DefaultDirectRateLimiter,
+    #[pyo3(get)]
+    quota: Quota,
+}
+
+#[pymethods]
+impl DirectRateLimiter {
+    #[new]
+    fn new(quota: Quota) -> Self {
+        DirectRateLimiter {
+            limiter: RateLimiter::direct(quota.0),
+            quota,
+        }
+    }
+
+    /// Lets `n` cells through if possible. Returns `None` if they were allowed, or the number
+    /// of seconds to wait otherwise.
+    #[pyo3(signature = (n = NonZeroU32::MIN))]
+    fn check(&self, n: NonZeroU32) -> PyResult<Option<f64>> {
+        seconds_to_wait(self.limiter.check_n(n))
+    }
+
+    /// Returns a coroutine that waits until `n` cells are let through.
+    #[pyo3(signature = (n = NonZeroU32::MIN))]
+    fn until_ready<'p>(slf: &Bound<'p, Self>, n: NonZeroU32) -> PyResult<Bound<'p, PyAny>> {
+        let check = slf.getattr(intern!(slf.py(), "check"))?;
+        wait_until_checked(check, (n,))
+    }
+}
+
+/// A key of a keyed rate limiter: a `str` (which is UTF-8 encoded) or `bytes`.
+#[derive(FromPyObject)]
+enum Key {
+    Str(String),
+    Bytes(Vec<u8>),
+}
+
+impl From<Key> for Vec<u8> {
+    fn from(key: Key) -> Vec<u8> {
+        match key {
+            Key::Str(key) => key.into_bytes(),
+            Key::Bytes(key) => key,
+        }
+    }
+}
+
+/// A rate limiter with a separate state for each key, which all use the same quota.
+#[pyclass(frozen)]
+struct KeyedRateLimiter {
+    limiter: DefaultKeyedRateLimiter<Vec<u8>>,
+    #[pyo3(get)]
+    quota: Quota,
+}
+
+#[pymethods]
+impl KeyedRateLimiter {
+    #[new]
+    fn new(quota: Quota) -> Self {
+        KeyedRateLimiter {
+            limiter: RateLimiter::keyed(quota.0),
+            quota,
+        }
+    }
+
+    /// Lets `n` cells through for `key` if possible. Returns `None` if they were allowed, or
+    /// the number of seconds to wait otherwise.
+    #[pyo3(signature = (key, n = NonZeroU32::MIN))]
+    fn check_key(&self, key: Key, n: NonZeroU32) -> PyResult<Option<f64>> {
+        seconds_to_wait(self.limiter.check_key_n(&key.into(), n))
+    }
+
+    /// Returns a coroutine that waits until `n` cells are let through for `key`.
+    #[pyo3(signature = (key, n = NonZeroU32::MIN))]
+    fn until_key_ready<'p>(
+        slf: &Bound<'p, Self>,
+        key: Bound<'p, PyAny>,
+        n: NonZeroU32,
+    ) -> PyResult<Bound<'p, PyAny>> {
+        let check = slf.getattr(intern!(slf.py(), "check_key"))?;
+        wait_until_checked(check, (key, n))
+    }
+
+    /// Forgets keys whose state is indistinguishable from a fresh one, and returns the number
+    /// of keys that remain.
+    fn retain_recent(&self) -> usize {
+        self.limiter.retain_recent();
+        self.limiter.len()
+    }
+
+    fn __len__(&self) -> usize {
+        self.limiter.len()
+    }
+}
+
+/// Python bindings for governor's rate limiters.
+#[pymodule]
+#[pyo3(name = "governor")]
+fn governor_python(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_class::<Quota>()?;
+    m.add_class::<DirectRateLimiter>()?;
+    m.add_class::<KeyedRateLimiter>()?;
+    m.add(
+        "InsufficientCapacity",
+        m.py().get_type::<InsufficientCapacity>(),
+    )?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use pyo3::ffi::c_str;
+    use std::ffi::CStr;
+    use std::sync::Once;
+
+    /// Runs Python code that can import the module, printing the traceback if it fails.
+    fn run(code: &CStr) {
+        static INIT: Once = Once::new();
+        INIT.call_once(|| {
+            pyo3::append_to_inittab!(governor_python);
+            pyo3::prepare_freethreaded_python();
+        });
+        Python::with_gil(|py| {
+            py.run(code, None, None).unwrap_or_else(|err| {
+                err.print(py);
+                panic!("Python code failed");
+            })
+        });
+    }
+
+    #[test]
+    fn quotas() {
+        run(c_str!(
+            r#"
+from governor import Quota
+assert Quota.per_second(50).burst_size == 50
+assert Quota.per_minute(30).replenish_interval == 2.0
+assert Quota.with_period(0.25).replenish_interval == 0.25
+assert Quota.per_hour(2).allow_burst(5).burst_size == 5
+assert "burst_size=5" in repr(Quota.per_second(5))
+for invalid in (lambda: Quota.per_second(0), lambda: Quota.with_period(0)):
+    try:
+        invalid()
+    except ValueError:
+        pass
+    else:
+        raise AssertionError("invalid quotas must be rejected")
+try:
+    Quota.per_second(2.5)
+except TypeError:
+    pass
+else:
+    raise AssertionError("fractional burst sizes must not be truncated")
+"#
+        ));
+    }
+
+    #[test]
+    fn direct_rate_limiters() {
+        run(c_str!(
+            r#"
+from governor import DirectRateLimiter, InsufficientCapacity, Quota
+lim = DirectRateLimiter(Quota.per_hour(1))
+assert lim.quota.burst_size == 1
+assert lim.check() is None
+wait = lim.check()
+assert 3599 < wait <= 3600, wait
+try:
+    lim.check(2)
+except InsufficientCapacity:
+    pass
+else:
+    raise AssertionError("batches beyond the burst size must be rejected")
+assert issubclass(InsufficientCapacity, ValueError)
+"#
+        ));
+    }
+
+    #[test]
+    fn keyed_rate_limiters() {
+        run(c_str!(
+            r#"
+from governor import KeyedRateLimiter, Quota
+lim = KeyedRateLimiter(Quota.per_hour(1))
+assert lim.check_key("example.com") is None
+# str keys are UTF-8 encoded, so they share their state with the same bytes:
+assert lim.check_key(b"example.com") is not None
+assert lim.check_key(b"example.org") is None
+assert len(lim) == 2
+assert lim.retain_recent() == 2
+"#
+        ));
+    }
+
+    #[test]
+    fn waits_asynchronously() {
+        run(c_str!(
+            r#"
+import asyncio, time
+from governor import DirectRateLimiter, InsufficientCapacity, KeyedRateLimiter, Quota
+
+async def main():
+    quota = Quota.per_second(20).allow_burst(1)
+    lim = DirectRateLimiter(quota)
+    start = time.monotonic()
+    for _ in range(3):
+        await lim.until_ready()
+    assert time.monotonic() - start >= 0.09
+    try:
+        await lim.until_ready(2)
+    except InsufficientCapacity:
+        pass
+    else:
+        raise AssertionError("batches beyond the burst size must be rejected")
+
+    keyed = KeyedRateLimiter(quota)
+    await keyed.until_key_ready("a")
+    await asyncio.gather(keyed.until_key_ready("a"), keyed.until_key_ready(b"b"))
+    assert keyed.check_key("a") is not None
+
+asyncio.run(main())
+# The waits are coroutines, so they can be run on their own:
+asyncio.run(DirectRateLimiter(Quota.per_second(1)).until_ready())
+"#
+        ));
+    }
+}
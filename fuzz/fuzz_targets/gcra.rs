@@ -0,0 +1,11 @@
+//! Runs direct rate limiters against a model of the GCRA, see `governor::testing::fuzz`.
+//!
+//! Run with `cargo +nightly fuzz run gcra`.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    governor::testing::fuzz::run(data);
+});
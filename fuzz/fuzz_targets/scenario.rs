@@ -0,0 +1,13 @@
+//! Runs direct rate limiters against a model of the GCRA, with scenarios that the fuzzer
+//! generates through `arbitrary`, see `governor::testing::fuzz::Scenario`.
+//!
+//! Run with `cargo +nightly fuzz run scenario`.
+
+#![no_main]
+
+use governor::testing::fuzz::Scenario;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|scenario: Scenario| {
+    scenario.run();
+});
@@ -0,0 +1,117 @@
+#ifndef GOVERNOR_H
+#define GOVERNOR_H
+
+/* Generated with cbindgen from src/ffi.rs; do not edit by hand. */
+
+#include <stddef.h>
+#include <stdint.h>
+
+/**
+ * The outcome of checking cells against a rate limiter through the C ABI.
+ */
+typedef enum GovernorDecision {
+  /**
+   * The cells were let through.
+   */
+  GovernorDecision_Allowed = 0,
+  /**
+   * The cells were rate-limited; the time to wait is written to `wait_nanos`.
+   */
+  GovernorDecision_Limited = 1,
+  /**
+   * The rate limiter's burst size is too small to ever let this many cells through at once.
+   */
+  GovernorDecision_InsufficientCapacity = 2,
+  /**
+   * A null pointer or a zero number of cells was passed.
+   */
+  GovernorDecision_InvalidArgument = -1,
+} GovernorDecision;
+
+/**
+ * A direct rate limiter, as created by [`governor_direct_new`].
+ */
+typedef struct GovernorDirect GovernorDirect;
+
+/**
+ * A keyed rate limiter with byte string keys, as created by [`governor_keyed_new`].
+ */
+typedef struct GovernorKeyed GovernorKeyed;
+
+/**
+ * Creates a direct rate limiter that replenishes one cell every `period_nanos` nanoseconds
+ * and allows bursts of up to `burst` cells.
+ *
+ * Returns a null pointer if `period_nanos` or `burst` is zero.
+ */
+struct GovernorDirect *governor_direct_new(uint64_t period_nanos, uint32_t burst);
+
+/**
+ * Checks whether `n` cells may pass through a direct rate limiter.
+ *
+ * If the cells are rate-limited and `wait_nanos` isn't null, the number of nanoseconds to wait
+ * before they can pass is written to it.
+ *
+ * # Safety
+ * `limiter` must be null or a pointer returned by [`governor_direct_new`] that wasn't freed
+ * yet, and `wait_nanos` must be null or valid for writes.
+ */
+enum GovernorDecision governor_direct_check(const struct GovernorDirect *limiter,
+                                            uint32_t n,
+                                            uint64_t *wait_nanos);
+
+/**
+ * Frees a direct rate limiter. Passing a null pointer does nothing.
+ *
+ * # Safety
+ * `limiter` must be null or a pointer returned by [`governor_direct_new`] that wasn't freed
+ * yet. It must not be used after this call.
+ */
+void governor_direct_free(struct GovernorDirect *limiter);
+
+/**
+ * Creates a keyed rate limiter that replenishes one cell every `period_nanos` nanoseconds
+ * and allows bursts of up to `burst` cells, for each key.
+ *
+ * Returns a null pointer if `period_nanos` or `burst` is zero.
+ */
+struct GovernorKeyed *governor_keyed_new(uint64_t period_nanos, uint32_t burst);
+
+/**
+ * Checks whether `n` cells may pass through a keyed rate limiter for the key made up of the
+ * `key_len` bytes at `key`.
+ *
+ * If the cells are rate-limited and `wait_nanos` isn't null, the number of nanoseconds to wait
+ * before they can pass is written to it.
+ *
+ * # Safety
+ * `limiter` must be null or a pointer returned by [`governor_keyed_new`] that wasn't freed
+ * yet, `key` must be valid for reads of `key_len` bytes (or may be null if `key_len` is 0),
+ * and `wait_nanos` must be null or valid for writes.
+ */
+enum GovernorDecision governor_keyed_check(const struct GovernorKeyed *limiter,
+                                           const uint8_t *key,
+                                           size_t key_len,
+                                           uint32_t n,
+                                           uint64_t *wait_nanos);
+
+/**
+ * Removes the keys from a keyed rate limiter whose state is indistinguishable from a fresh
+ * one, and returns the number of keys that remain. Returns 0 for a null pointer.
+ *
+ * # Safety
+ * `limiter` must be null or a pointer returned by [`governor_keyed_new`] that wasn't freed
+ * yet.
+ */
+size_t governor_keyed_retain_recent(const struct GovernorKeyed *limiter);
+
+/**
+ * Frees a keyed rate limiter. Passing a null pointer does nothing.
+ *
+ * # Safety
+ * `limiter` must be null or a pointer returned by [`governor_keyed_new`] that wasn't freed
+ * yet. It must not be used after this call.
+ */
+void governor_keyed_free(struct GovernorKeyed *limiter);
+
+#endif /* GOVERNOR_H */
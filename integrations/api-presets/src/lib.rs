@@ -0,0 +1,396 @@
+//! Rate limiter presets for popular HTTP APIs, kept in sync with the limits that the APIs report
+//! in their responses' headers.
+//!
+//! APIs like GitHub's, Discord's and Slack's limit clients on several levels: documented rates
+//! per route (or "tier"), server-side buckets whose state each response reports in headers like
+//! `X-RateLimit-Remaining`, and `Retry-After` responses when a client went too fast after all.
+//! An [`ApiLimiter`] paces requests per route with the [`Provider`]'s documented rate, and
+//! additionally follows the buckets that the API reports through
+//! [`update`](ApiLimiter::update), so that a request is only sent when neither the local
+//! pacing nor the API's reported state would reject it.
+//!
+//! ```rust
+//! use governor_api_presets::{ApiLimiter, Provider};
+//!
+//! let limiter = ApiLimiter::new(Provider::Discord);
+//! assert!(limiter.check("POST /channels/1/messages").is_ok());
+//! // The response says that the route's bucket is used up for another 2.5s:
+//! limiter.update(
+//!     "POST /channels/1/messages",
+//!     200,
+//!     vec![
+//!         ("X-RateLimit-Bucket", "abcd1234"),
+//!         ("X-RateLimit-Remaining", "0"),
+//!         ("X-RateLimit-Reset-After", "2.5"),
+//!     ],
+//! );
+//! let rejected = limiter.check("POST /channels/1/messages").unwrap_err();
+//! assert!(rejected.wait_time().as_millis() > 2000);
+//! ```
+
+use governor::clock::{Clock, DefaultClock};
+use governor::state::keyed::DefaultKeyedStateStore;
+use governor::{DefaultKeyedRateLimiter, Quota, RateLimiter};
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::num::NonZeroU32;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// The tier of a Slack Web API method, which determines its rate limit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum SlackTier {
+    /// About 1 request per minute.
+    Tier1,
+    /// About 20 requests per minute.
+    Tier2,
+    /// About 50 requests per minute.
+    Tier3,
+    /// About 100 requests per minute.
+    Tier4,
+}
+
+/// An API whose rate limits an [`ApiLimiter`] follows.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Provider {
+    /// The GitHub REST API. Its secondary rate limits allow up to 900 points per minute for
+    /// REST endpoints, with most requests costing one point; its primary limits are reported in
+    /// `X-RateLimit-*` headers, with resets as UNIX timestamps.
+    GitHub,
+
+    /// The Discord API. It allows 50 requests per second across all routes, and reports the
+    /// per-route buckets in `X-RateLimit-*` headers, with the bucket's identifier in
+    /// `X-RateLimit-Bucket` and resets as seconds from now.
+    Discord,
+
+    /// The Slack Web API, whose methods are rate-limited by tier. It reports only `Retry-After`,
+    /// on `429 Too Many Requests` responses.
+    Slack(SlackTier),
+}
+
+impl Provider {
+    /// Returns the documented quota that requests to each route are paced to.
+    pub fn quota(&self) -> Quota {
+        let per_minute = |n| Quota::per_minute(NonZeroU32::new(n).unwrap());
+        match self {
+            Provider::GitHub => per_minute(900),
+            Provider::Discord => Quota::per_second(NonZeroU32::new(50).unwrap()),
+            Provider::Slack(SlackTier::Tier1) => per_minute(1),
+            Provider::Slack(SlackTier::Tier2) => per_minute(20),
+            Provider::Slack(SlackTier::Tier3) => per_minute(50),
+            Provider::Slack(SlackTier::Tier4) => per_minute(100),
+        }
+    }
+}
+
+/// The limit that made an [`ApiLimiter`] reject a request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Limit {
+    /// The provider's documented rate for the route.
+    Pacing,
+    /// The route's bucket, as the API reported it.
+    Bucket,
+    /// A limit across all routes, as the API reported it.
+    Global,
+}
+
+/// A request that an [`ApiLimiter`] rejected.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RateLimited {
+    limit: Limit,
+    wait: Duration,
+}
+
+impl RateLimited {
+    /// Returns the limit that rejected the request.
+    pub fn limit(&self) -> Limit {
+        self.limit
+    }
+
+    /// Returns how long to wait before the request may be sent.
+    pub fn wait_time(&self) -> Duration {
+        self.wait
+    }
+}
+
+impl fmt::Display for RateLimited {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "rate-limited ({:?}), retry in {:?}",
+            self.limit, self.wait
+        )
+    }
+}
+
+impl Error for RateLimited {}
+
+/// The state of a bucket, as the API last reported it.
+#[derive(Debug, Clone, Copy)]
+struct Bucket {
+    remaining: u32,
+    reset_at: Instant,
+}
+
+#[derive(Debug, Default)]
+struct Reported {
+    /// The bucket identifiers of the routes, for APIs that report them.
+    route_buckets: HashMap<String, String>,
+    /// The buckets, by identifier (or by route, for APIs that don't report identifiers).
+    buckets: HashMap<String, Bucket>,
+    /// When a limit across all routes runs out.
+    global_until: Option<Instant>,
+}
+
+impl Reported {
+    fn bucket_id<'a>(&'a self, route: &'a str) -> &'a str {
+        self.route_buckets
+            .get(route)
+            .map(String::as_str)
+            .unwrap_or(route)
+    }
+}
+
+/// Paces requests to an API per route, following the rate limits that it reports.
+#[derive(Debug)]
+pub struct ApiLimiter {
+    provider: Provider,
+    clock: DefaultClock,
+    pacing: DefaultKeyedRateLimiter<String>,
+    reported: Mutex<Reported>,
+}
+
+impl ApiLimiter {
+    /// Constructs a limiter with the provider's documented rate for each route.
+    pub fn new(provider: Provider) -> Self {
+        Self::with_quota(provider, provider.quota())
+    }
+
+    /// Constructs a limiter that paces each route to the given quota, and follows the limits
+    /// that the provider reports.
+    pub fn with_quota(provider: Provider, quota: Quota) -> Self {
+        let clock = DefaultClock::default();
+        let pacing = RateLimiter::new(quota, DefaultKeyedStateStore::default(), &clock);
+        ApiLimiter {
+            provider,
+            clock,
+            pacing,
+            reported: Mutex::new(Reported::default()),
+        }
+    }
+
+    /// Returns the provider whose limits the limiter follows.
+    pub fn provider(&self) -> Provider {
+        self.provider
+    }
+
+    /// Checks whether a request to the route may be sent now, and counts it if so.
+    ///
+    /// Routes are named by the caller, e.g. `"GET /repos/{owner}/{repo}/issues"` or, for
+    /// Discord, with the major parameter (like the channel) filled in.
+    pub fn check(&self, route: &str) -> Result<(), RateLimited> {
+        self.check_at(route, Instant::now())
+    }
+
+    fn check_at(&self, route: &str, now: Instant) -> Result<(), RateLimited> {
+        let mut reported = self.reported.lock().unwrap();
+        if let Some(until) = reported.global_until {
+            if until > now {
+                return Err(RateLimited {
+                    limit: Limit::Global,
+                    wait: until - now,
+                });
+            }
+            reported.global_until = None;
+        }
+        let id = reported.bucket_id(route).to_string();
+        let bucket = match reported.buckets.get(&id) {
+            Some(bucket) if bucket.reset_at <= now => {
+                reported.buckets.remove(&id);
+                None
+            }
+            Some(bucket) if bucket.remaining == 0 => {
+                return Err(RateLimited {
+                    limit: Limit::Bucket,
+                    wait: bucket.reset_at - now,
+                });
+            }
+            bucket => bucket.copied(),
+        };
+        if let Err(not_until) = self.pacing.check_key(&route.to_string()) {
+            return Err(RateLimited {
+                limit: Limit::Pacing,
+                wait: not_until.wait_time_from(self.clock.now()),
+            });
+        }
+        if let Some(mut bucket) = bucket {
+            bucket.remaining -= 1;
+            reported.buckets.insert(id, bucket);
+        }
+        Ok(())
+    }
+
+    /// Updates the route's limits from the status and headers of a response to a request to
+    /// it. Header names are matched case-insensitively.
+    pub fn update<'a, I>(&self, route: &str, status: u16, headers: I)
+    where
+        I: IntoIterator<Item = (&'a str, &'a str)>,
+    {
+        self.update_at(route, status, headers, Instant::now(), SystemTime::now())
+    }
+
+    fn update_at<'a, I>(&self, route: &str, status: u16, headers: I, now: Instant, wall: SystemTime)
+    where
+        I: IntoIterator<Item = (&'a str, &'a str)>,
+    {
+        let mut remaining = None;
+        let mut reset_in = None;
+        let mut retry_after = None;
+        let mut bucket_id = None;
+        let mut global = false;
+        for (name, value) in headers {
+            let value = value.trim();
+            match name.to_ascii_lowercase().as_str() {
+                "x-ratelimit-remaining" => remaining = value.parse::<u32>().ok(),
+                "x-ratelimit-reset" if self.provider == Provider::GitHub => {
+                    reset_in = value
+                        .parse::<u64>()
+                        .ok()
+                        .map(|epoch| UNIX_EPOCH + Duration::from_secs(epoch))
+                        .map(|reset| reset.duration_since(wall).unwrap_or_default());
+                }
+                "x-ratelimit-reset-after" => reset_in = parse_secs(value),
+                "x-ratelimit-bucket" => bucket_id = Some(value.to_string()),
+                "x-ratelimit-global" | "x-ratelimit-scope" => {
+                    global |=
+                        value.eq_ignore_ascii_case("true") || value.eq_ignore_ascii_case("global")
+                }
+                "retry-after" => retry_after = parse_secs(value),
+                _ => {}
+            }
+        }
+
+        let mut reported = self.reported.lock().unwrap();
+        if let Some(id) = bucket_id {
+            reported.route_buckets.insert(route.to_string(), id);
+        }
+        let id = reported.bucket_id(route).to_string();
+        // GitHub reports secondary rate limits with 403 as well as 429:
+        let rejected = status == 429 || (status == 403 && self.provider == Provider::GitHub);
+        match (rejected, retry_after) {
+            (true, Some(wait)) if global => reported.global_until = Some(now + wait),
+            (true, Some(wait)) => {
+                let bucket = Bucket {
+                    remaining: 0,
+                    reset_at: now + wait,
+                };
+                reported.buckets.insert(id, bucket);
+            }
+            _ => {
+                if let (Some(remaining), Some(reset_in)) = (remaining, reset_in) {
+                    let bucket = Bucket {
+                        remaining,
+                        reset_at: now + reset_in,
+                    };
+                    reported.buckets.insert(id, bucket);
+                }
+            }
+        }
+    }
+}
+
+/// Parses a number of seconds, which may have a fractional part.
+fn parse_secs(value: &str) -> Option<Duration> {
+    let secs = value.parse::<f64>().ok()?;
+    if secs.is_finite() && secs >= 0.0 {
+        Some(Duration::from_secs_f64(secs))
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use nonzero_ext::nonzero;
+
+    #[test]
+    fn presets() {
+        assert_eq!(
+            Provider::Slack(SlackTier::Tier2).quota(),
+            Quota::per_minute(nonzero!(20u32))
+        );
+        let limiter = ApiLimiter::new(Provider::Slack(SlackTier::Tier1));
+        assert!(limiter.check("chat.postMessage").is_ok());
+        assert_eq!(
+            limiter.check("chat.postMessage").unwrap_err().limit(),
+            Limit::Pacing
+        );
+        assert!(limiter.check("conversations.list").is_ok());
+    }
+
+    #[test]
+    fn follows_github_headers() {
+        let limiter = ApiLimiter::new(Provider::GitHub);
+        let (now, wall) = (Instant::now(), SystemTime::now());
+        let reset = wall.duration_since(UNIX_EPOCH).unwrap().as_secs() + 60;
+        let reset = reset.to_string();
+        let headers = vec![
+            ("x-ratelimit-remaining", "1"),
+            ("x-ratelimit-reset", reset.as_str()),
+        ];
+        limiter.update_at("GET /user", 200, headers, now, wall);
+        assert!(limiter.check_at("GET /user", now).is_ok());
+        let rejected = limiter.check_at("GET /user", now).unwrap_err();
+        assert_eq!(rejected.limit(), Limit::Bucket);
+        assert!(rejected.wait_time() > Duration::from_secs(58));
+        // Once the bucket resets, requests go through again:
+        assert!(limiter
+            .check_at("GET /user", now + Duration::from_secs(61))
+            .is_ok());
+
+        // Secondary limits come as 403s with Retry-After:
+        limiter.update_at("POST /issues", 403, vec![("Retry-After", "30")], now, wall);
+        assert_eq!(
+            limiter.check_at("POST /issues", now),
+            Err(RateLimited {
+                limit: Limit::Bucket,
+                wait: Duration::from_secs(30),
+            })
+        );
+    }
+
+    #[test]
+    fn follows_discord_buckets() {
+        let limiter = ApiLimiter::new(Provider::Discord);
+        let (now, wall) = (Instant::now(), SystemTime::now());
+        // Two routes share a bucket:
+        for route in &["PATCH /channels/1", "DELETE /channels/1"] {
+            let headers = vec![
+                ("X-RateLimit-Bucket", "shared"),
+                ("X-RateLimit-Remaining", "1"),
+                ("X-RateLimit-Reset-After", "1.5"),
+            ];
+            limiter.update_at(route, 200, headers, now, wall);
+        }
+        assert!(limiter.check_at("PATCH /channels/1", now).is_ok());
+        assert_eq!(
+            limiter.check_at("DELETE /channels/1", now),
+            Err(RateLimited {
+                limit: Limit::Bucket,
+                wait: Duration::from_millis(1500),
+            })
+        );
+
+        let headers = vec![("Retry-After", "0.25"), ("X-RateLimit-Global", "true")];
+        limiter.update_at("GET /users/@me", 429, headers, now, wall);
+        assert_eq!(
+            limiter.check_at("GET /gateway", now).unwrap_err().limit(),
+            Limit::Global
+        );
+        assert!(limiter
+            .check_at("GET /gateway", now + Duration::from_millis(250))
+            .is_ok());
+    }
+}
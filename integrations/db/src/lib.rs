@@ -0,0 +1,462 @@
+//! Throttling database connections and queries with governor.
+//!
+//! A database shared by many instances of an application can be overloaded by a single one
+//! that opens connections in a loop or floods it with writes. A [`DbThrottle`] gates acquiring
+//! connections and issuing statements behind rate limiters, so that each instance stays within
+//! its share. It wraps the futures of any driver or pool, e.g. sqlx's `pool.acquire()` or
+//! deadpool's `pool.get()`, and statements can be throttled by their class (reads, writes,
+//! schema changes), each with its own quota.
+//!
+//! Throttles are configured with the same quota strings as governor's other integrations, and
+//! can be deserialized with serde:
+//!
+//! ```rust
+//! use governor_db::DbThrottle;
+//!
+//! let throttle: DbThrottle = serde_json::from_str(r#"{
+//!     "connections": "10/s",
+//!     "statements": "500/s",
+//!     "by_statement_class": true,
+//!     "class_quotas": {"write": "50/s", "ddl": "1/min"},
+//!     "max_wait_ms": 100
+//! }"#).unwrap();
+//! ```
+//!
+//! Work that would have to wait longer than the throttle's maximum wait is rejected with a
+//! [`Throttled`] error that says how long to wait, instead of piling up on the database.
+//!
+//! ```rust
+//! # use nonzero_ext::*;
+//! use governor::Quota;
+//! use governor_db::{DbThrottle, ThrottleError};
+//!
+//! # futures::executor::block_on(async {
+//! let throttle = DbThrottle::new().statements(Quota::per_minute(nonzero!(1u32)));
+//! let run = || async { Ok::<_, std::io::Error>(1) };
+//! assert_eq!(throttle.query("SELECT 1", run).await.unwrap(), 1);
+//! match throttle.query("SELECT 1", run).await {
+//!     Err(ThrottleError::Throttled(throttled)) => assert!(throttled.wait_time().as_secs() > 50),
+//!     _ => unreachable!(),
+//! }
+//! # });
+//! ```
+
+use governor::clock::{Clock, DefaultClock};
+use governor::state::keyed::DefaultKeyedStateStore;
+use governor::{
+    DefaultDirectRateLimiter, DefaultKeyedRateLimiter, NotUntil, ParseQuotaError, Quota,
+    RateLimiter,
+};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::error::Error;
+use std::fmt;
+use std::future::Future;
+use std::time::Duration;
+
+/// The class of an SQL statement, which statements can be throttled by.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StatementClass {
+    /// Queries that only read, like `SELECT`.
+    Read,
+    /// Statements that modify data, like `INSERT`, `UPDATE` or `DELETE`.
+    Write,
+    /// Statements that modify the schema, like `CREATE`, `ALTER` or `DROP`.
+    Ddl,
+    /// Any other statement, like transaction control.
+    Other,
+}
+
+impl StatementClass {
+    /// Classifies an SQL statement by its first keyword.
+    ///
+    /// This doesn't parse the statement: A statement that starts with `WITH` counts as a read,
+    /// even if its common table expressions modify data.
+    pub fn classify(sql: &str) -> StatementClass {
+        let keyword = sql
+            .trim_start_matches(|c: char| c.is_whitespace() || c == '(')
+            .split(|c: char| !c.is_ascii_alphabetic())
+            .next()
+            .unwrap_or_default()
+            .to_ascii_uppercase();
+        match keyword.as_str() {
+            "SELECT" | "WITH" | "SHOW" | "EXPLAIN" | "VALUES" | "TABLE" => StatementClass::Read,
+            "INSERT" | "UPDATE" | "DELETE" | "MERGE" | "UPSERT" | "REPLACE" | "COPY" => {
+                StatementClass::Write
+            }
+            "CREATE" | "ALTER" | "DROP" | "TRUNCATE" | "RENAME" | "COMMENT" => StatementClass::Ddl,
+            _ => StatementClass::Other,
+        }
+    }
+}
+
+/// What a throttle gated when it rejected work.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Gate {
+    /// Acquiring a connection.
+    Connection,
+    /// Issuing a statement of the given class.
+    Statement(StatementClass),
+}
+
+/// The error of work that the throttle rejected, as it would have had to wait too long.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Throttled {
+    gate: Gate,
+    wait: Duration,
+}
+
+impl Throttled {
+    /// Returns what the throttle gated.
+    pub fn gate(&self) -> Gate {
+        self.gate
+    }
+
+    /// Returns how long the work would have had to wait.
+    pub fn wait_time(&self) -> Duration {
+        self.wait
+    }
+}
+
+impl fmt::Display for Throttled {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.gate {
+            Gate::Connection => write!(f, "too many database connections")?,
+            Gate::Statement(class) => write!(f, "too many database statements ({:?})", class)?,
+        }
+        write!(f, ", retry in {:?}", self.wait)
+    }
+}
+
+impl Error for Throttled {}
+
+/// The error of throttled database work: either the throttle rejected it, or the database
+/// returned an error.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ThrottleError<E> {
+    /// The throttle rejected the work.
+    Throttled(Throttled),
+    /// The database (or driver, or pool) returned an error.
+    Database(E),
+}
+
+impl<E: fmt::Display> fmt::Display for ThrottleError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ThrottleError::Throttled(throttled) => throttled.fmt(f),
+            ThrottleError::Database(err) => err.fmt(f),
+        }
+    }
+}
+
+impl<E: Error + 'static> Error for ThrottleError<E> {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ThrottleError::Throttled(throttled) => Some(throttled),
+            ThrottleError::Database(err) => Some(err),
+        }
+    }
+}
+
+/// Gates acquiring database connections and issuing statements behind rate limiters.
+///
+/// Without quotas, nothing is throttled.
+#[derive(Debug, Deserialize)]
+#[serde(try_from = "DbThrottleConfig")]
+pub struct DbThrottle {
+    clock: DefaultClock,
+    connections: Option<DefaultDirectRateLimiter>,
+    statements: Option<DefaultKeyedRateLimiter<Option<StatementClass>>>,
+    by_statement_class: bool,
+    class_quotas: HashMap<StatementClass, DefaultDirectRateLimiter>,
+    max_wait: Duration,
+}
+
+impl Default for DbThrottle {
+    fn default() -> Self {
+        DbThrottle {
+            clock: DefaultClock::default(),
+            connections: None,
+            statements: None,
+            by_statement_class: false,
+            class_quotas: HashMap::new(),
+            max_wait: Duration::from_secs(0),
+        }
+    }
+}
+
+/// The rate limiter that a piece of work is checked against.
+enum Limiter<'a> {
+    Direct(&'a DefaultDirectRateLimiter),
+    Keyed(
+        &'a DefaultKeyedRateLimiter<Option<StatementClass>>,
+        Option<StatementClass>,
+    ),
+}
+
+impl<'a> Limiter<'a> {
+    fn check(&self) -> Result<(), NotUntil<<DefaultClock as Clock>::Instant>> {
+        match self {
+            Limiter::Direct(limiter) => limiter.check(),
+            Limiter::Keyed(limiter, key) => limiter.check_key(key),
+        }
+    }
+
+    async fn until_ready(&self) {
+        match self {
+            Limiter::Direct(limiter) => limiter.until_ready().await,
+            Limiter::Keyed(limiter, key) => limiter.until_key_ready(key).await,
+        }
+    }
+}
+
+impl DbThrottle {
+    /// Constructs a throttle that doesn't throttle anything yet, and rejects work right away
+    /// once it has to wait.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Limits how quickly connections are acquired.
+    pub fn connections(mut self, quota: Quota) -> Self {
+        self.connections = Some(RateLimiter::direct_with_clock(quota, &self.clock));
+        self
+    }
+
+    /// Limits how quickly statements are issued, in total or (with
+    /// [`by_statement_class`](#method.by_statement_class)) per statement class.
+    pub fn statements(mut self, quota: Quota) -> Self {
+        self.statements = Some(RateLimiter::new(
+            quota,
+            DefaultKeyedStateStore::default(),
+            &self.clock,
+        ));
+        self
+    }
+
+    /// Gives each statement class its own quota of the [`statements`](#method.statements)
+    /// quota, instead of sharing one.
+    pub fn by_statement_class(mut self, by_class: bool) -> Self {
+        self.by_statement_class = by_class;
+        self
+    }
+
+    /// Limits how quickly statements of the given class are issued with a quota of their own,
+    /// instead of the [`statements`](#method.statements) quota.
+    pub fn class_quota(mut self, class: StatementClass, quota: Quota) -> Self {
+        let limiter = RateLimiter::direct_with_clock(quota, &self.clock);
+        self.class_quotas.insert(class, limiter);
+        self
+    }
+
+    /// Lets work wait for up to `max_wait` until its quota allows it, before rejecting it.
+    pub fn max_wait(mut self, max_wait: Duration) -> Self {
+        self.max_wait = max_wait;
+        self
+    }
+
+    /// Waits until the connection quota allows acquiring a connection, and then acquires it
+    /// with the given future, e.g. `pool.acquire()`.
+    pub async fn acquire<F, T, E>(&self, acquire: F) -> Result<T, ThrottleError<E>>
+    where
+        F: Future<Output = Result<T, E>>,
+    {
+        if let Some(limiter) = &self.connections {
+            self.admit(Gate::Connection, Limiter::Direct(limiter))
+                .await?;
+        }
+        acquire.await.map_err(ThrottleError::Database)
+    }
+
+    /// Waits until the quota of the statement's class allows issuing it, and then runs it with
+    /// the given function.
+    pub async fn query<F, Fut, T, E>(&self, sql: &str, run: F) -> Result<T, ThrottleError<E>>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<T, E>>,
+    {
+        let class = StatementClass::classify(sql);
+        let limiter = match (self.class_quotas.get(&class), &self.statements) {
+            (Some(limiter), _) => Some(Limiter::Direct(limiter)),
+            (None, Some(limiter)) => {
+                let key = if self.by_statement_class {
+                    Some(class)
+                } else {
+                    None
+                };
+                Some(Limiter::Keyed(limiter, key))
+            }
+            (None, None) => None,
+        };
+        if let Some(limiter) = limiter {
+            self.admit(Gate::Statement(class), limiter).await?;
+        }
+        run().await.map_err(ThrottleError::Database)
+    }
+
+    async fn admit<E>(&self, gate: Gate, limiter: Limiter<'_>) -> Result<(), ThrottleError<E>> {
+        let not_until = match limiter.check() {
+            Ok(()) => return Ok(()),
+            Err(not_until) => not_until,
+        };
+        let wait = not_until.wait_time_from(self.clock.now());
+        if wait > self.max_wait {
+            return Err(ThrottleError::Throttled(Throttled { gate, wait }));
+        }
+        limiter.until_ready().await;
+        Ok(())
+    }
+}
+
+/// The configuration of a [`DbThrottle`].
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DbThrottleConfig {
+    /// The quota of acquiring connections, like `10/s`.
+    #[serde(default)]
+    pub connections: Option<String>,
+
+    /// The quota of issuing statements.
+    #[serde(default)]
+    pub statements: Option<String>,
+
+    /// Whether each statement class gets its own `statements` quota.
+    #[serde(default)]
+    pub by_statement_class: bool,
+
+    /// The quotas of statement classes that don't use the `statements` quota.
+    #[serde(default)]
+    pub class_quotas: HashMap<StatementClass, String>,
+
+    /// How long work may wait for its quota before it is rejected, in milliseconds.
+    #[serde(default)]
+    pub max_wait_ms: u64,
+}
+
+/// An error in the configuration of a [`DbThrottle`]: a quota couldn't be parsed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DbThrottleConfigError(String, ParseQuotaError);
+
+impl fmt::Display for DbThrottleConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid quota {:?}: {}", self.0, self.1)
+    }
+}
+
+impl Error for DbThrottleConfigError {}
+
+impl TryFrom<DbThrottleConfig> for DbThrottle {
+    type Error = DbThrottleConfigError;
+
+    fn try_from(config: DbThrottleConfig) -> Result<Self, Self::Error> {
+        let parse = |quota: &str| {
+            quota
+                .parse::<Quota>()
+                .map_err(|err| DbThrottleConfigError(quota.to_string(), err))
+        };
+        let mut throttle = DbThrottle::new()
+            .by_statement_class(config.by_statement_class)
+            .max_wait(Duration::from_millis(config.max_wait_ms));
+        if let Some(quota) = &config.connections {
+            throttle = throttle.connections(parse(quota)?);
+        }
+        if let Some(quota) = &config.statements {
+            throttle = throttle.statements(parse(quota)?);
+        }
+        for (class, quota) in &config.class_quotas {
+            throttle = throttle.class_quota(*class, parse(quota)?);
+        }
+        Ok(throttle)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use futures::executor::block_on;
+    use nonzero_ext::nonzero;
+
+    async fn ok() -> Result<(), ()> {
+        Ok(())
+    }
+
+    #[test]
+    fn classifies_statements() {
+        assert_eq!(
+            StatementClass::classify("  select * from t"),
+            StatementClass::Read
+        );
+        assert_eq!(
+            StatementClass::classify("(SELECT 1) UNION (SELECT 2)"),
+            StatementClass::Read
+        );
+        assert_eq!(
+            StatementClass::classify("INSERT INTO t VALUES (1)"),
+            StatementClass::Write
+        );
+        assert_eq!(
+            StatementClass::classify("drop table t"),
+            StatementClass::Ddl
+        );
+        assert_eq!(StatementClass::classify("BEGIN"), StatementClass::Other);
+        assert_eq!(StatementClass::classify(""), StatementClass::Other);
+    }
+
+    #[test]
+    fn throttles_by_statement_class() {
+        block_on(async {
+            let throttle = DbThrottle::new()
+                .statements(Quota::per_minute(nonzero!(1u32)))
+                .by_statement_class(true)
+                .class_quota(StatementClass::Ddl, Quota::per_hour(nonzero!(1u32)));
+            assert!(throttle.query("SELECT 1", ok).await.is_ok());
+            assert!(throttle.query("UPDATE t SET a = 1", ok).await.is_ok());
+            assert!(throttle.query("CREATE TABLE t (a int)", ok).await.is_ok());
+            match throttle.query("DELETE FROM t", ok).await {
+                Err(ThrottleError::Throttled(throttled)) => {
+                    assert_eq!(throttled.gate(), Gate::Statement(StatementClass::Write));
+                    assert!(throttled.wait_time() > Duration::from_secs(50));
+                }
+                other => panic!("not throttled: {:?}", other),
+            }
+            match throttle.query("ALTER TABLE t ADD b int", ok).await {
+                Err(ThrottleError::Throttled(throttled)) => {
+                    assert!(throttled.wait_time() > Duration::from_secs(3500));
+                }
+                other => panic!("not throttled: {:?}", other),
+            }
+        });
+    }
+
+    #[test]
+    fn waits_up_to_max_wait() {
+        block_on(async {
+            let throttle = DbThrottle::new()
+                .connections(Quota::per_second(nonzero!(20u32)).allow_burst(nonzero!(1u32)))
+                .max_wait(Duration::from_millis(200));
+            for _ in 0..3 {
+                assert_eq!(throttle.acquire(ok()).await, Ok(()));
+            }
+            assert_eq!(
+                throttle.acquire(async { Err::<(), _>("exhausted") }).await,
+                Err(ThrottleError::Database("exhausted"))
+            );
+        });
+    }
+
+    #[test]
+    fn deserializes_configuration() {
+        let throttle: DbThrottle =
+            serde_json::from_str(r#"{"statements": "1/min", "class_quotas": {"read": "100/s"}}"#)
+                .unwrap();
+        block_on(async {
+            assert!(throttle.query("UPDATE t SET a = 1", ok).await.is_ok());
+            assert!(throttle.query("DELETE FROM t", ok).await.is_err());
+            assert!(throttle.query("SELECT 1", ok).await.is_ok());
+        });
+        let err = serde_json::from_str::<DbThrottle>(r#"{"connections": "lots"}"#).unwrap_err();
+        assert!(err.to_string().contains("invalid quota"));
+    }
+}
@@ -0,0 +1,252 @@
+//! An [async-graphql](https://docs.rs/async-graphql) extension that rate-limits GraphQL queries
+//! by their cost, with a governor rate limiter.
+//!
+//! A fixed number of requests per client doesn't fit GraphQL well: One query can ask for a
+//! single field, or for thousands of nested objects. [`CostLimit`] instead computes each query's
+//! cost from its complexity and depth (as async-graphql's validation measures them) and debits
+//! that many cells from the client's quota, so that clients can send many cheap queries or few
+//! expensive ones.
+//!
+//! Clients are told apart by the [`ClientKey`] in the request's (or the schema's) data; queries
+//! without one share the quota of the empty key. Queries that exceed their client's quota are
+//! rejected before they execute, with an error whose extensions say when the client may retry:
+//!
+//! ```json
+//! {"message": "rate limit exceeded, retry in 2s",
+//!  "extensions": {"code": "RATE_LIMITED", "cost": 12, "retryAfter": 2, "retryAfterMs": 1500}}
+//! ```
+//!
+//! ```rust,no_run
+//! # use nonzero_ext::*;
+//! use async_graphql::{EmptyMutation, EmptySubscription, Object, Request, Schema};
+//! use governor::Quota;
+//! use governor_graphql::{ClientKey, CostLimit};
+//!
+//! struct Query;
+//!
+//! #[Object]
+//! impl Query {
+//!     async fn answer(&self) -> i32 {
+//!         42
+//!     }
+//! }
+//!
+//! # async fn example() {
+//! let schema = Schema::build(Query, EmptyMutation, EmptySubscription)
+//!     // Clients may spend 1000 complexity points a minute:
+//!     .extension(CostLimit::new(Quota::per_minute(nonzero!(1000u32))))
+//!     .finish();
+//! let request = Request::new("{ answer }").data(ClientKey("alice".to_string()));
+//! let response = schema.execute(request).await;
+//! # }
+//! ```
+
+use async_graphql::extensions::{Extension, ExtensionContext, ExtensionFactory, NextValidation};
+use async_graphql::{ErrorExtensionValues, ServerError, ValidationResult};
+use governor::clock::{Clock, DefaultClock};
+use governor::state::keyed::DefaultKeyedStateStore;
+use governor::{DefaultKeyedRateLimiter, NegativeMultiDecision, Quota, RateLimiter};
+use std::convert::TryFrom;
+use std::fmt;
+use std::num::NonZeroU32;
+use std::sync::Arc;
+
+/// The key of the client that sent a query, which it gets its own quota under.
+///
+/// Add it to the request's data, e.g. with the client's account or IP address.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ClientKey(pub String);
+
+/// Computes the cost of a query, in cells, from the result of its validation.
+type CostFn = dyn Fn(&ValidationResult) -> u32 + Send + Sync;
+
+/// An extension that debits the cost of each query from its client's quota, rejecting the
+/// queries that exceed it.
+///
+/// All schemas that an extension is added to share its rate limiter.
+#[derive(Clone)]
+pub struct CostLimit {
+    shared: Arc<Shared>,
+}
+
+struct Shared {
+    clock: DefaultClock,
+    limiter: DefaultKeyedRateLimiter<String>,
+    cost: Box<CostFn>,
+}
+
+impl CostLimit {
+    /// Constructs an extension that charges each query its complexity (one cell per field,
+    /// unless the schema's fields declare their own complexity), from a per-client quota.
+    pub fn new(quota: Quota) -> Self {
+        Self::with_cost(quota, |validation: &ValidationResult| {
+            u32::try_from(validation.complexity).unwrap_or(u32::MAX)
+        })
+    }
+
+    /// Constructs an extension that charges each query the cost that the function computes
+    /// from its complexity and depth, from a per-client quota. Queries that cost nothing are
+    /// let through without checking the quota.
+    pub fn with_cost<F>(quota: Quota, cost: F) -> Self
+    where
+        F: Fn(&ValidationResult) -> u32 + Send + Sync + 'static,
+    {
+        let clock = DefaultClock::default();
+        let limiter = RateLimiter::new(quota, DefaultKeyedStateStore::default(), &clock);
+        CostLimit {
+            shared: Arc::new(Shared {
+                clock,
+                limiter,
+                cost: Box::new(cost),
+            }),
+        }
+    }
+
+    /// Returns the rate limiter that queries are checked against.
+    pub fn limiter(&self) -> &DefaultKeyedRateLimiter<String> {
+        &self.shared.limiter
+    }
+}
+
+impl fmt::Debug for CostLimit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CostLimit")
+            .field("limiter", &self.shared.limiter)
+            .finish()
+    }
+}
+
+impl ExtensionFactory for CostLimit {
+    fn create(&self) -> Arc<dyn Extension> {
+        Arc::new(CostLimitExtension {
+            shared: Arc::clone(&self.shared),
+        })
+    }
+}
+
+struct CostLimitExtension {
+    shared: Arc<Shared>,
+}
+
+impl CostLimitExtension {
+    /// Debits the query's cost from the client's quota, returning the error to reject it with
+    /// if that exceeds the quota.
+    fn debit(&self, key: &str, cost: u32) -> Result<(), ServerError> {
+        let cells = match NonZeroU32::new(cost) {
+            Some(cells) => cells,
+            None => return Ok(()),
+        };
+        let mut extensions = ErrorExtensionValues::default();
+        extensions.set("cost", cost);
+        let mut error = match self.shared.limiter.check_key_n(&key.to_string(), cells) {
+            Ok(()) => return Ok(()),
+            Err(NegativeMultiDecision::BatchNonConforming(_, not_until)) => {
+                let wait = not_until.wait_time_from(self.shared.clock.now());
+                let secs = wait.as_secs() + u64::from(wait.subsec_nanos() > 0);
+                extensions.set("code", "RATE_LIMITED");
+                extensions.set("retryAfter", secs);
+                extensions.set(
+                    "retryAfterMs",
+                    u64::try_from(wait.as_millis()).unwrap_or(u64::MAX),
+                );
+                ServerError::new(format!("rate limit exceeded, retry in {}s", secs), None)
+            }
+            Err(NegativeMultiDecision::InsufficientCapacity(max)) => {
+                extensions.set("code", "QUERY_TOO_EXPENSIVE");
+                extensions.set("maxCost", max);
+                ServerError::new(
+                    format!("query costs {}, more than the limit of {}", cost, max),
+                    None,
+                )
+            }
+        };
+        error.extensions = Some(extensions);
+        Err(error)
+    }
+}
+
+#[async_trait::async_trait]
+impl Extension for CostLimitExtension {
+    async fn validation(
+        &self,
+        ctx: &ExtensionContext<'_>,
+        next: NextValidation<'_>,
+    ) -> Result<ValidationResult, Vec<ServerError>> {
+        let validation = next.run(ctx).await?;
+        let key = ctx
+            .data_opt::<ClientKey>()
+            .map(|key| key.0.as_str())
+            .unwrap_or_default();
+        self.debit(key, (self.shared.cost)(&validation))
+            .map_err(|error| vec![error])?;
+        Ok(validation)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use async_graphql::{EmptyMutation, EmptySubscription, Object, Request, Schema, Value};
+    use nonzero_ext::nonzero;
+
+    struct Query;
+
+    #[Object]
+    impl Query {
+        async fn a(&self) -> i32 {
+            1
+        }
+
+        async fn b(&self) -> i32 {
+            2
+        }
+    }
+
+    fn schema(extension: CostLimit) -> Schema<Query, EmptyMutation, EmptySubscription> {
+        Schema::build(Query, EmptyMutation, EmptySubscription)
+            .extension(extension)
+            .finish()
+    }
+
+    async fn execute(
+        schema: &Schema<Query, EmptyMutation, EmptySubscription>,
+        client: &str,
+        query: &str,
+    ) -> Option<ErrorExtensionValues> {
+        let request = Request::new(query).data(ClientKey(client.to_string()));
+        let response = schema.execute(request).await;
+        response
+            .errors
+            .into_iter()
+            .next()
+            .map(|error| error.extensions.unwrap())
+    }
+
+    #[tokio::test]
+    async fn charges_complexity_per_client() {
+        let schema = schema(CostLimit::new(Quota::per_minute(nonzero!(3u32))));
+        assert!(execute(&schema, "alice", "{ a b }").await.is_none());
+        let rejected = execute(&schema, "alice", "{ a b }").await.unwrap();
+        assert_eq!(rejected.get("code"), Some(&Value::from("RATE_LIMITED")));
+        assert_eq!(rejected.get("cost"), Some(&Value::from(2)));
+        assert_eq!(rejected.get("retryAfter"), Some(&Value::from(20)));
+        // The cheaper query still fits into alice's quota, and bob has his own:
+        assert!(execute(&schema, "alice", "{ a }").await.is_none());
+        assert!(execute(&schema, "bob", "{ a b }").await.is_none());
+    }
+
+    #[tokio::test]
+    async fn custom_cost() {
+        let schema = schema(CostLimit::with_cost(
+            Quota::per_minute(nonzero!(10u32)),
+            |validation: &ValidationResult| (validation.complexity * 4) as u32,
+        ));
+        let rejected = execute(&schema, "alice", "{ a b a2: a }").await.unwrap();
+        assert_eq!(
+            rejected.get("code"),
+            Some(&Value::from("QUERY_TOO_EXPENSIVE"))
+        );
+        assert_eq!(rejected.get("maxCost"), Some(&Value::from(10)));
+        assert!(execute(&schema, "alice", "{ a b }").await.is_none());
+    }
+}
@@ -0,0 +1,205 @@
+//! Throttling [rdkafka](https://docs.rs/rdkafka) producers to broker quotas with governor.
+//!
+//! Kafka brokers enforce byte-rate quotas on producers by delaying their responses, which
+//! stalls everything else the producer sends. A [`ProducerThrottle`] paces messages before they
+//! reach the producer instead, per topic or per partition, and weighs each message by its
+//! payload size: With a quota of 1024 cells per second and 1 KiB per cell, every topic (or
+//! partition) gets a steady 1 MiB per second.
+//!
+//! ```rust,no_run
+//! # use nonzero_ext::*;
+//! use governor::Quota;
+//! use governor_kafka::{KeyBy, ProducerThrottle};
+//! use rdkafka::producer::{FutureProducer, FutureRecord};
+//! use rdkafka::ClientConfig;
+//! use std::time::Duration;
+//!
+//! # async fn example() -> Result<(), Box<dyn std::error::Error>> {
+//! let producer: FutureProducer = ClientConfig::new()
+//!     .set("bootstrap.servers", "localhost:9092")
+//!     .create()?;
+//! let throttle = ProducerThrottle::new(Quota::per_second(nonzero!(1024u32)), KeyBy::Partition)
+//!     .bytes_per_cell(nonzero!(1024u32));
+//! let record = FutureRecord::to("events").key("user-1").payload("hello").partition(0);
+//! let delivery = throttle
+//!     .send_record(&producer, record, Duration::from_secs(5))
+//!     .await?;
+//! # Ok(())
+//! # }
+//! ```
+
+use governor::state::direct::InsufficientCapacity;
+use governor::{DefaultKeyedRateLimiter, Quota, RateLimiter};
+use rdkafka::message::ToBytes;
+use rdkafka::producer::future_producer::OwnedDeliveryResult;
+use rdkafka::producer::{FutureProducer, FutureRecord};
+use rdkafka::util::Timeout;
+use std::convert::TryFrom;
+use std::fmt;
+use std::future::Future;
+use std::num::NonZeroU32;
+
+/// What messages are paced by: each topic, or each partition of a topic, gets its own quota.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyBy {
+    /// All messages to a topic share its quota.
+    Topic,
+    /// Messages to each partition of a topic share the partition's quota. Messages that leave
+    /// the partition to the producer's partitioner share the topic's quota.
+    Partition,
+}
+
+/// Paces the messages that a producer sends, weighted by their payload size.
+pub struct ProducerThrottle {
+    limiter: DefaultKeyedRateLimiter<(String, Option<i32>)>,
+    key_by: KeyBy,
+    bytes_per_cell: NonZeroU32,
+}
+
+impl ProducerThrottle {
+    /// Constructs a throttle that paces the messages to each topic or partition to the quota.
+    ///
+    /// By default, each message uses one cell per started KiB of its payload (and at least one
+    /// cell); see [`bytes_per_cell`](#method.bytes_per_cell).
+    pub fn new(quota: Quota, key_by: KeyBy) -> Self {
+        ProducerThrottle {
+            limiter: RateLimiter::keyed(quota),
+            key_by,
+            bytes_per_cell: NonZeroU32::new(1024).unwrap(),
+        }
+    }
+
+    /// Sets how many bytes of payload each cell of the quota stands for.
+    pub fn bytes_per_cell(mut self, bytes: NonZeroU32) -> Self {
+        self.bytes_per_cell = bytes;
+        self
+    }
+
+    /// Returns what messages are paced by.
+    pub fn key_by(&self) -> KeyBy {
+        self.key_by
+    }
+
+    /// Returns the rate limiter that messages are paced with.
+    pub fn limiter(&self) -> &DefaultKeyedRateLimiter<(String, Option<i32>)> {
+        &self.limiter
+    }
+
+    /// Returns the number of cells that a message with a payload of `payload_len` bytes uses.
+    pub fn cells(&self, payload_len: usize) -> NonZeroU32 {
+        let bytes = u64::try_from(payload_len).unwrap_or(u64::MAX);
+        let cells = bytes.div_ceil(u64::from(self.bytes_per_cell.get())).max(1);
+        NonZeroU32::new(u32::try_from(cells).unwrap_or(u32::MAX)).unwrap()
+    }
+
+    /// Waits until the quota of the message's topic (or partition) allows its payload through,
+    /// and then sends it with the given function.
+    ///
+    /// Returns `InsufficientCapacity` right away if the payload is larger than the quota's
+    /// burst allows, as waiting would never succeed.
+    pub async fn send<F, Fut>(
+        &self,
+        topic: &str,
+        partition: Option<i32>,
+        payload_len: usize,
+        send: F,
+    ) -> Result<Fut::Output, InsufficientCapacity>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future,
+    {
+        let partition = match self.key_by {
+            KeyBy::Topic => None,
+            KeyBy::Partition => partition,
+        };
+        self.limiter
+            .until_key_n_ready(&(topic.to_string(), partition), self.cells(payload_len))
+            .await?;
+        Ok(send().await)
+    }
+
+    /// Waits until the quota of the record's topic (or partition) allows its payload through,
+    /// and then sends it with the producer.
+    ///
+    /// The `queue_timeout` is passed to [`FutureProducer::send`], and only starts once the
+    /// throttle lets the record through.
+    pub async fn send_record<K, P, T>(
+        &self,
+        producer: &FutureProducer,
+        record: FutureRecord<'_, K, P>,
+        queue_timeout: T,
+    ) -> Result<OwnedDeliveryResult, InsufficientCapacity>
+    where
+        K: ToBytes + ?Sized,
+        P: ToBytes + ?Sized,
+        T: Into<Timeout>,
+    {
+        let payload_len = record.payload.map_or(0, |payload| payload.to_bytes().len());
+        let topic = record.topic;
+        let partition = record.partition;
+        self.send(topic, partition, payload_len, || {
+            producer.send(record, queue_timeout)
+        })
+        .await
+    }
+}
+
+impl fmt::Debug for ProducerThrottle {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ProducerThrottle")
+            .field("limiter", &self.limiter)
+            .field("key_by", &self.key_by)
+            .field("bytes_per_cell", &self.bytes_per_cell)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use nonzero_ext::nonzero;
+
+    #[test]
+    fn weighs_payloads() {
+        let throttle = ProducerThrottle::new(Quota::per_second(nonzero!(10u32)), KeyBy::Topic);
+        assert_eq!(throttle.cells(0), nonzero!(1u32));
+        assert_eq!(throttle.cells(1024), nonzero!(1u32));
+        assert_eq!(throttle.cells(1025), nonzero!(2u32));
+        let throttle = throttle.bytes_per_cell(nonzero!(1u32));
+        assert_eq!(
+            throttle.cells(usize::MAX),
+            NonZeroU32::new(u32::MAX).unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn keys_by_partition() {
+        let throttle = ProducerThrottle::new(Quota::per_minute(nonzero!(4u32)), KeyBy::Partition);
+        let sent = throttle
+            .send("events", Some(0), 4096, || async { "sent" })
+            .await;
+        assert_eq!(sent, Ok("sent"));
+        // Partition 0 used up its burst, but partition 1 and the rest of the topic didn't:
+        let key = |partition| (String::from("events"), partition);
+        assert!(throttle.limiter().check_key(&key(Some(0))).is_err());
+        assert!(throttle.limiter().check_key(&key(Some(1))).is_ok());
+        assert!(throttle.limiter().check_key(&key(None)).is_ok());
+        assert_eq!(
+            throttle.send("events", Some(1), 5000, || async {}).await,
+            Err(InsufficientCapacity(4))
+        );
+    }
+
+    #[tokio::test]
+    async fn keys_by_topic() {
+        let throttle = ProducerThrottle::new(Quota::per_minute(nonzero!(2u32)), KeyBy::Topic);
+        for partition in 0..2 {
+            throttle
+                .send("events", Some(partition), 10, || async {})
+                .await
+                .unwrap();
+        }
+        let key = (String::from("events"), None);
+        assert!(throttle.limiter().check_key(&key).is_err());
+    }
+}
@@ -0,0 +1,261 @@
+//! Pacing object storage requests to the request rates that object stores allow per prefix.
+//!
+//! Object stores limit request rates per partition of a bucket: Amazon S3 allows 3,500
+//! writes and 5,500 reads per second for each prefix, and answers requests above that with
+//! `503 Slow Down`. An [`ObjectStorePacer`] gives every bucket and prefix (up to a configurable
+//! number of path segments) its own rate limiter, with quotas from an [`ObjectStorePreset`] for
+//! common stores, and paces requests to them.
+//!
+//! Stores scale their partitions to the load they see, so the actual limits may be lower (right
+//! after a burst of new traffic) or higher. Responses fed back with
+//! [`record_response`](ObjectStorePacer::record_response) adapt the pacing: Each `503` puts the
+//! prefix's rate limiter under more [pressure](governor::RateLimiter::set_pressure), halving
+//! its rate, and successful responses lift the pressure again step by step.
+//!
+//! ```rust
+//! use governor_object_store::{ObjectStorePacer, ObjectStorePreset, Operation};
+//!
+//! # futures::executor::block_on(async {
+//! let pacer = ObjectStorePacer::new(ObjectStorePreset::s3());
+//! pacer.until_ready("logs", "2024/01/app.log", Operation::Write).await;
+//! // ...send the PUT request, and report its status code:
+//! pacer.record_response("logs", "2024/01/app.log", Operation::Write, 503);
+//! assert_eq!(pacer.pressure("logs", "2024/01/app.log", Operation::Write), 0.5);
+//! # });
+//! ```
+
+use governor::clock::{Clock, DefaultClock};
+use governor::{DefaultDirectRateLimiter, NotUntil, Quota, RateLimiter};
+use std::collections::HashMap;
+use std::num::NonZeroU32;
+use std::sync::{Arc, Mutex};
+
+/// The kind of request, which object stores limit separately.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Operation {
+    /// Requests that read objects or their metadata, like `GET`, `HEAD` and listings.
+    Read,
+    /// Requests that modify objects, like `PUT`, `POST`, `COPY` (a `PUT`) and `DELETE`.
+    Write,
+}
+
+impl Operation {
+    /// Returns the operation that a request with the given HTTP method performs.
+    pub fn from_method(method: &str) -> Operation {
+        if method.eq_ignore_ascii_case("GET") || method.eq_ignore_ascii_case("HEAD") {
+            Operation::Read
+        } else {
+            Operation::Write
+        }
+    }
+}
+
+/// The request rates that an object store allows per partition.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ObjectStorePreset {
+    reads: Quota,
+    writes: Quota,
+    prefix_depth: usize,
+}
+
+impl ObjectStorePreset {
+    /// Constructs a preset with the given quotas, for partitions holding the keys that share
+    /// the first `prefix_depth` `/`-separated segments (0 for entire buckets).
+    pub fn new(reads: Quota, writes: Quota, prefix_depth: usize) -> Self {
+        ObjectStorePreset {
+            reads,
+            writes,
+            prefix_depth,
+        }
+    }
+
+    /// Amazon S3: 5,500 `GET`/`HEAD` and 3,500 `PUT`/`COPY`/`POST`/`DELETE` requests per second
+    /// for each prefix, with prefixes of one segment.
+    pub fn s3() -> Self {
+        ObjectStorePreset::new(
+            Quota::per_second(NonZeroU32::new(5_500).unwrap()),
+            Quota::per_second(NonZeroU32::new(3_500).unwrap()),
+            1,
+        )
+    }
+
+    /// Google Cloud Storage: about 5,000 object reads and 1,000 object writes per second for
+    /// each bucket, before the bucket's capacity has been scaled up.
+    pub fn gcs() -> Self {
+        ObjectStorePreset::new(
+            Quota::per_second(NonZeroU32::new(5_000).unwrap()),
+            Quota::per_second(NonZeroU32::new(1_000).unwrap()),
+            0,
+        )
+    }
+
+    /// Returns the quota of the given operation.
+    pub fn quota(&self, operation: Operation) -> Quota {
+        match operation {
+            Operation::Read => self.reads,
+            Operation::Write => self.writes,
+        }
+    }
+
+    /// Returns the number of segments of the keys' prefixes that partitions are made up of.
+    pub fn prefix_depth(&self) -> usize {
+        self.prefix_depth
+    }
+
+    /// Returns the partition of a bucket that an object key belongs to.
+    pub fn partition(&self, bucket: &str, key: &str) -> String {
+        let mut partition = bucket.to_string();
+        for segment in key.split('/').take(self.prefix_depth) {
+            partition.push('/');
+            partition.push_str(segment);
+        }
+        partition
+    }
+}
+
+/// Paces requests to an object store per bucket, prefix and operation, backing off as the store
+/// asks it to.
+///
+/// Each partition's rate limiters are created the first time a request goes to it, and kept for
+/// as long as the pacer is.
+#[derive(Debug)]
+pub struct ObjectStorePacer {
+    preset: ObjectStorePreset,
+    clock: DefaultClock,
+    limiters: Mutex<HashMap<(String, Operation), Arc<DefaultDirectRateLimiter>>>,
+    recovery: f64,
+}
+
+impl ObjectStorePacer {
+    /// Constructs a pacer with the quotas of the given preset.
+    pub fn new(preset: ObjectStorePreset) -> Self {
+        ObjectStorePacer {
+            preset,
+            clock: DefaultClock::default(),
+            limiters: Mutex::new(HashMap::new()),
+            recovery: 0.01,
+        }
+    }
+
+    /// Sets how much of the quota each successful response gives back to a partition that was
+    /// asked to slow down; by default, 1%.
+    pub fn recovery(mut self, recovery: f64) -> Self {
+        self.recovery = recovery;
+        self
+    }
+
+    /// Returns the preset that the pacer's quotas come from.
+    pub fn preset(&self) -> &ObjectStorePreset {
+        &self.preset
+    }
+
+    /// Returns the rate limiter of the partition that the object belongs to, for the operation.
+    pub fn limiter(
+        &self,
+        bucket: &str,
+        key: &str,
+        operation: Operation,
+    ) -> Arc<DefaultDirectRateLimiter> {
+        let partition = self.preset.partition(bucket, key);
+        let mut limiters = self.limiters.lock().unwrap();
+        let limiter = limiters.entry((partition, operation)).or_insert_with(|| {
+            Arc::new(RateLimiter::direct_with_clock(
+                self.preset.quota(operation),
+                &self.clock,
+            ))
+        });
+        Arc::clone(limiter)
+    }
+
+    /// Checks whether a request for the object may be sent right away.
+    pub fn check(
+        &self,
+        bucket: &str,
+        key: &str,
+        operation: Operation,
+    ) -> Result<(), NotUntil<<DefaultClock as Clock>::Instant>> {
+        self.limiter(bucket, key, operation).check()
+    }
+
+    /// Waits until a request for the object may be sent.
+    pub async fn until_ready(&self, bucket: &str, key: &str, operation: Operation) {
+        self.limiter(bucket, key, operation).until_ready().await
+    }
+
+    /// Feeds the HTTP status of a response back into the pacing: `503 Slow Down` halves the
+    /// rate of the object's partition, and a successful (`2xx`) response raises it again by the
+    /// [recovery](#method.recovery) step, up to the full quota.
+    pub fn record_response(&self, bucket: &str, key: &str, operation: Operation, status: u16) {
+        let limiter = self.limiter(bucket, key, operation);
+        match status {
+            503 => limiter.set_pressure(limiter.pressure() / 2.0),
+            200..=299 if limiter.pressure() < 1.0 => {
+                limiter.set_pressure(limiter.pressure() + self.recovery)
+            }
+            _ => {}
+        }
+    }
+
+    /// Returns the fraction of the quota that the object's partition is currently paced to.
+    pub fn pressure(&self, bucket: &str, key: &str, operation: Operation) -> f64 {
+        self.limiter(bucket, key, operation).pressure()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use nonzero_ext::nonzero;
+
+    #[test]
+    fn partitions_by_prefix() {
+        let s3 = ObjectStorePreset::s3();
+        assert_eq!(s3.partition("logs", "2024/01/app.log"), "logs/2024");
+        assert_eq!(s3.partition("logs", "app.log"), "logs/app.log");
+        assert_eq!(
+            ObjectStorePreset::gcs().partition("logs", "2024/01"),
+            "logs"
+        );
+        assert_eq!(
+            s3.quota(Operation::from_method("head")),
+            Quota::per_second(nonzero!(5_500u32))
+        );
+        assert_eq!(
+            s3.quota(Operation::from_method("DELETE")),
+            Quota::per_second(nonzero!(3_500u32))
+        );
+    }
+
+    #[test]
+    fn paces_partitions_separately() {
+        let preset = ObjectStorePreset::new(
+            Quota::per_minute(nonzero!(2u32)),
+            Quota::per_minute(nonzero!(1u32)),
+            1,
+        );
+        let pacer = ObjectStorePacer::new(preset);
+        assert!(pacer.check("b", "a/1", Operation::Write).is_ok());
+        assert!(pacer.check("b", "a/2", Operation::Write).is_err());
+        assert!(pacer.check("b", "a/2", Operation::Read).is_ok());
+        assert!(pacer.check("b", "c/1", Operation::Write).is_ok());
+        assert!(pacer.check("other", "a/1", Operation::Write).is_ok());
+    }
+
+    #[test]
+    fn backs_off_on_slow_down() {
+        let pacer = ObjectStorePacer::new(ObjectStorePreset::s3()).recovery(0.25);
+        let pressure = || pacer.pressure("b", "a/1", Operation::Read);
+        pacer.record_response("b", "a/1", Operation::Read, 503);
+        pacer.record_response("b", "a/2", Operation::Read, 503);
+        assert_eq!(pressure(), 0.25);
+        // Other errors don't change the pacing, and other operations aren't affected:
+        pacer.record_response("b", "a/1", Operation::Read, 404);
+        assert_eq!(pressure(), 0.25);
+        assert_eq!(pacer.pressure("b", "a/1", Operation::Write), 1.0);
+
+        for _ in 0..4 {
+            pacer.record_response("b", "a/1", Operation::Read, 200);
+        }
+        assert_eq!(pressure(), 1.0);
+    }
+}
@@ -0,0 +1,184 @@
+//! Rate limiting on [RTIC](https://rtic.rs) monotonic timers and
+//! [fugit](https://docs.rs/fugit) instants with governor.
+//!
+//! RTIC firmware keeps time with a monotonic timer from `rtic-monotonics`, whose `now()` returns
+//! a `fugit::Instant` of the timer's ticks. A [`FugitClock`] reads such a timer and hands its
+//! time to governor's rate limiters as [`Nanos`], so that quotas are counted against the same
+//! timer that the firmware schedules its tasks on. When a decision is negative,
+//! [`FugitClock::earliest_possible`] and [`FugitClock::wait_time`] convert it back into the
+//! timer's instants and durations, ready for `Mono::delay_until` and `Mono::delay`.
+//!
+//! ```rust,ignore
+//! use governor::{middleware::NoOpMiddleware, nanos::Nanos, state::*, Quota, RateLimiter};
+//! use governor_rtic::FugitClock;
+//! use rtic_monotonics::systick::prelude::*;
+//!
+//! // A 64-bit monotonic timer that ticks every millisecond:
+//! systick_monotonic!(Mono, 1_000);
+//!
+//! type MonoClock = FugitClock<fn() -> Mono::Instant, 1, 1_000>;
+//! type Limiter = RateLimiter<NotKeyed, InMemoryState, MonoClock, NoOpMiddleware<Nanos>>;
+//!
+//! #[task(local = [limiter, clock])]
+//! async fn send(cx: send::Context) {
+//!     let (limiter, clock): (&mut Limiter, &mut MonoClock) = (cx.local.limiter, cx.local.clock);
+//!     loop {
+//!         if let Err(negative) = limiter.check() {
+//!             Mono::delay_until(clock.earliest_possible(&negative)).await;
+//!             continue;
+//!         }
+//!         // ...send a message.
+//!     }
+//! }
+//!
+//! // At init:
+//! let clock: MonoClock = FugitClock::new(Mono::now);
+//! let limiter = RateLimiter::direct_with_clock(Quota::per_second(nonzero!(10u32)), &clock);
+//! ```
+//!
+//! The clock only reads 64-bit timers (`TimerInstantU64`), as governor's instants must not
+//! wrap around; with a 32-bit timer, use
+//! [`InterruptSafeRateLimiter`](governor::state::InterruptSafeRateLimiter) on its ticks instead.
+//!
+//! On chips without compare-and-swap instructions (like the Cortex-M0's `thumbv6m`), enable the
+//! `critical-section` feature, and link a `critical-section` implementation (e.g. with
+//! `cortex-m`'s `critical-section-single-core` feature).
+#![cfg_attr(not(test), no_std)]
+
+use core::convert::TryFrom;
+use core::fmt;
+use fugit::{Duration, Instant};
+use governor::clock::{Clock, Reference};
+use governor::nanos::Nanos;
+use governor::NotUntil;
+
+const NANOS_PER_SECOND: u128 = 1_000_000_000;
+
+/// A governor clock that reads a monotonic timer's `fugit` instants, e.g. an RTIC monotonic's
+/// `Mono::now`.
+///
+/// The instants are counted in ticks of `NOM / DENOM` seconds each since the timer started,
+/// and converted to [`Nanos`] since then, rounding down.
+///
+/// # Example
+/// ```rust
+/// # use nonzero_ext::*;
+/// use fugit::TimerInstantU64;
+/// use governor::{clock::Clock, nanos::Nanos};
+/// use governor_rtic::FugitClock;
+///
+/// // e.g. a 32.768 kHz timer that has counted to five seconds:
+/// fn now() -> TimerInstantU64<32_768> {
+///     TimerInstantU64::from_ticks(5 * 32_768)
+/// }
+///
+/// let clock = FugitClock::new(now);
+/// assert_eq!(clock.now(), Nanos::from(5_000_000_000u64));
+/// ```
+#[derive(Clone, Copy)]
+pub struct FugitClock<F, const NOM: u32, const DENOM: u32> {
+    now: F,
+}
+
+impl<F, const NOM: u32, const DENOM: u32> FugitClock<F, NOM, DENOM>
+where
+    F: Fn() -> Instant<u64, NOM, DENOM>,
+{
+    /// Constructs a clock that reads the timer with `now`.
+    pub const fn new(now: F) -> Self {
+        FugitClock { now }
+    }
+
+    /// Returns the timer's current instant.
+    pub fn instant(&self) -> Instant<u64, NOM, DENOM> {
+        (self.now)()
+    }
+
+    /// Converts one of the timer's instants to the time since the timer started, rounding down.
+    pub fn to_nanos(instant: Instant<u64, NOM, DENOM>) -> Nanos {
+        let nanos =
+            u128::from(instant.ticks()) * u128::from(NOM) * NANOS_PER_SECOND / u128::from(DENOM);
+        Nanos::from(u64::try_from(nanos).unwrap_or(u64::MAX))
+    }
+
+    /// Converts a time since the timer started to the timer's first instant at or after it.
+    pub fn to_instant(nanos: Nanos) -> Instant<u64, NOM, DENOM> {
+        Instant::<u64, NOM, DENOM>::from_ticks(Self::to_ticks(nanos))
+    }
+
+    /// Returns the instant at which the rate limiter that made a negative decision will allow
+    /// the next cell through.
+    pub fn earliest_possible(&self, not_until: &NotUntil<Nanos>) -> Instant<u64, NOM, DENOM> {
+        Self::to_instant(not_until.earliest_possible())
+    }
+
+    /// Returns how long to wait after the timer's current instant until the rate limiter that
+    /// made a negative decision will allow the next cell through, in the timer's ticks (rounded
+    /// up).
+    pub fn wait_time(&self, not_until: &NotUntil<Nanos>) -> Duration<u64, NOM, DENOM> {
+        let now = Self::to_nanos(self.instant());
+        let wait = not_until.earliest_possible().duration_since(now);
+        Duration::<u64, NOM, DENOM>::from_ticks(Self::to_ticks(wait))
+    }
+
+    fn to_ticks(nanos: Nanos) -> u64 {
+        let per_tick = u128::from(NOM) * NANOS_PER_SECOND;
+        let ticks = (u128::from(nanos.as_u64()) * u128::from(DENOM)).div_ceil(per_tick);
+        u64::try_from(ticks).unwrap_or(u64::MAX)
+    }
+}
+
+impl<F, const NOM: u32, const DENOM: u32> fmt::Debug for FugitClock<F, NOM, DENOM> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("FugitClock")
+            .field("nom", &NOM)
+            .field("denom", &DENOM)
+            .finish()
+    }
+}
+
+impl<F, const NOM: u32, const DENOM: u32> Clock for FugitClock<F, NOM, DENOM>
+where
+    F: Fn() -> Instant<u64, NOM, DENOM> + Clone,
+{
+    type Instant = Nanos;
+
+    fn now(&self) -> Self::Instant {
+        Self::to_nanos(self.instant())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use core::sync::atomic::{AtomicU64, Ordering};
+    use fugit::{TimerDurationU64, TimerInstantU64};
+    use governor::{middleware::NoOpMiddleware, state::*, Quota, RateLimiter};
+    use nonzero_ext::nonzero;
+
+    static TICKS: AtomicU64 = AtomicU64::new(0);
+
+    fn now() -> TimerInstantU64<1_000> {
+        TimerInstantU64::from_ticks(TICKS.load(Ordering::Relaxed))
+    }
+
+    #[test]
+    fn limits_on_the_timers_ticks() {
+        let clock = FugitClock::new(now);
+        let lim: RateLimiter<NotKeyed, InMemoryState, _, NoOpMiddleware<_>> =
+            RateLimiter::direct_with_clock(Quota::per_second(nonzero!(3u32)), &clock);
+        TICKS.store(10_000, Ordering::Relaxed);
+        assert_eq!(lim.check_n(nonzero!(3u32)), Ok(()));
+        let negative = lim.check().unwrap_err();
+        // A cell replenishes every 333 1/3 ms, which a millisecond timer reaches at its 334th tick:
+        assert_eq!(clock.wait_time(&negative), TimerDurationU64::<1_000>::from_ticks(334));
+        assert_eq!(
+            clock.earliest_possible(&negative),
+            TimerInstantU64::<1_000>::from_ticks(10_334)
+        );
+        TICKS.store(10_333, Ordering::Relaxed);
+        assert!(lim.check().is_err());
+        TICKS.store(10_334, Ordering::Relaxed);
+        assert_eq!(lim.check(), Ok(()));
+    }
+}
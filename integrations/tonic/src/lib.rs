@@ -0,0 +1,121 @@
+//! Helpers for rate-limiting [tonic](https://docs.rs/tonic) gRPC services with governor.
+//!
+//! Server-streaming endpoints often produce their messages in a background task that sends
+//! them through a channel, and return the receiving end as the response stream. [`pace_stream`]
+//! paces such a stream to a rate limiter's quota, so that the endpoint's output is smoothed
+//! (and bursts of messages are spread out) without a hand-rolled timer in the producing task.
+//! Since the paced stream only takes the next message from the channel when the quota allows,
+//! a bounded channel also slows the producer down to the quota.
+//!
+//! ```rust,no_run
+//! # use nonzero_ext::*;
+//! use governor::{DefaultDirectRateLimiter, Quota, RateLimiter};
+//! use governor_tonic::{pace_stream, PacedStream};
+//! use std::sync::Arc;
+//! use tokio::sync::mpsc;
+//! use tonic::Status;
+//!
+//! # struct Update;
+//! struct Service {
+//!     limiter: Arc<DefaultDirectRateLimiter>,
+//! }
+//!
+//! impl Service {
+//!     // The body of a server-streaming method, whose stream type is
+//!     // `PacedStream<Result<Update, Status>>`:
+//!     fn updates(&self) -> PacedStream<Result<Update, Status>> {
+//!         let (tx, rx) = mpsc::channel(16);
+//!         tokio::spawn(async move {
+//!             while tx.send(Ok(Update)).await.is_ok() {}
+//!         });
+//!         pace_stream(rx, &self.limiter)
+//!     }
+//! }
+//!
+//! let service = Service {
+//!     limiter: Arc::new(RateLimiter::direct(Quota::per_second(nonzero!(50u32)))),
+//! };
+//! ```
+
+use futures::{Stream, StreamExt};
+use governor::DefaultDirectRateLimiter;
+use std::pin::Pin;
+use std::sync::Arc;
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+
+/// A stream whose items are paced to a rate limiter's quota.
+///
+/// It is `Send` and `'static`, as tonic requires of response streams.
+pub type PacedStream<T> = Pin<Box<dyn Stream<Item = T> + Send + 'static>>;
+
+/// Paces the messages received on a channel to the rate limiter's quota.
+///
+/// Every message uses up one cell of the rate limiter, which may be shared with other streams
+/// (to limit their total rate) or be specific to this one.
+pub fn pace_stream<T>(
+    rx: mpsc::Receiver<T>,
+    limiter: &Arc<DefaultDirectRateLimiter>,
+) -> PacedStream<T>
+where
+    T: Send + 'static,
+{
+    pace(ReceiverStream::new(rx), limiter)
+}
+
+/// Paces the items of any stream to the rate limiter's quota.
+///
+/// The paced stream takes an item from the inner stream, and then waits until the rate limiter
+/// lets it through, so it holds at most one item back.
+pub fn pace<S>(stream: S, limiter: &Arc<DefaultDirectRateLimiter>) -> PacedStream<S::Item>
+where
+    S: Stream + Send + 'static,
+    S::Item: Send,
+{
+    let limiter = Arc::clone(limiter);
+    Box::pin(stream.then(move |item| {
+        let limiter = Arc::clone(&limiter);
+        async move {
+            limiter.until_ready().await;
+            item
+        }
+    }))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use governor::{Quota, RateLimiter};
+    use nonzero_ext::nonzero;
+    use std::time::{Duration, Instant};
+    use tonic::Status;
+
+    #[tokio::test]
+    async fn paces_channel() {
+        let limiter = Arc::new(RateLimiter::direct(
+            Quota::per_second(nonzero!(20u32)).allow_burst(nonzero!(1u32)),
+        ));
+        let (tx, rx) = mpsc::channel(4);
+        for i in 0..3 {
+            tx.send(Ok::<_, Status>(i)).await.unwrap();
+        }
+        drop(tx);
+
+        let start = Instant::now();
+        let received: Vec<u32> = pace_stream(rx, &limiter)
+            .map(|message| message.unwrap())
+            .collect()
+            .await;
+        assert_eq!(received, vec![0, 1, 2]);
+        assert!(start.elapsed() >= Duration::from_millis(100));
+    }
+
+    #[tokio::test]
+    async fn shares_limiter() {
+        let limiter = Arc::new(RateLimiter::direct(Quota::per_minute(nonzero!(2u32))));
+        let first: Vec<_> = pace(futures::stream::iter(0..2), &limiter).collect().await;
+        assert_eq!(first, vec![0, 1]);
+        // The first stream used up the quota's burst:
+        assert!(limiter.check().is_err());
+    }
+}
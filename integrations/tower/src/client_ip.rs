@@ -0,0 +1,377 @@
+//! Finding the IP address of the client that sent a request through trusted proxies.
+//!
+//! Behind a reverse proxy or load balancer, a request's peer address is the proxy's, and the
+//! client's address is only known from a header that the proxies add to: `Forwarded`
+//! ([RFC 7239](https://tools.ietf.org/html/rfc7239)) or `X-Forwarded-For`. Clients can send
+//! these headers too, so only the entries that trusted proxies added can be believed: Keying
+//! rate limits by the header's first entry lets every client pick its own key.
+//!
+//! A [`ClientIpKeyExtractor`] walks the chain of addresses from the peer backwards: As long as
+//! the address it arrived from is a trusted proxy, it believes the proxy's claim about where the
+//! request came from. The first address that isn't a trusted proxy is the client's.
+
+use serde::Deserialize;
+use std::error::Error;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::str::FromStr;
+
+/// A range of IP addresses in CIDR notation, like `10.0.0.0/8` or `fd00::/8`.
+///
+/// A single address (without a prefix length) is the range of just that address.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct IpNet {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+/// An error parsing an [`IpNet`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseIpNetError(String);
+
+impl fmt::Display for ParseIpNetError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid IP address range {:?}", self.0)
+    }
+}
+
+impl Error for ParseIpNetError {}
+
+impl IpNet {
+    /// Constructs the range of addresses that share the first `prefix` bits with `addr`.
+    ///
+    /// Returns `None` if the prefix is longer than the address.
+    pub fn new(addr: IpAddr, prefix: u8) -> Option<IpNet> {
+        let addr = canonical(addr);
+        if prefix > max_prefix(addr) {
+            return None;
+        }
+        Some(IpNet { addr, prefix })
+    }
+
+    /// Returns whether the range contains the address.
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, canonical(ip)) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX
+                    .checked_shl(32 - u32::from(self.prefix))
+                    .unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX
+                    .checked_shl(128 - u32::from(self.prefix))
+                    .unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for IpNet {
+    type Err = ParseIpNetError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = || ParseIpNetError(s.to_string());
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => {
+                let addr: IpAddr = addr.parse().map_err(|_| err())?;
+                (addr, prefix.parse().map_err(|_| err())?)
+            }
+            None => {
+                let addr: IpAddr = s.parse().map_err(|_| err())?;
+                (addr, max_prefix(canonical(addr)))
+            }
+        };
+        IpNet::new(addr, prefix).ok_or_else(err)
+    }
+}
+
+impl std::convert::TryFrom<String> for IpNet {
+    type Error = ParseIpNetError;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl fmt::Display for IpNet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+fn max_prefix(addr: IpAddr) -> u8 {
+    match addr {
+        IpAddr::V4(_) => 32,
+        IpAddr::V6(_) => 128,
+    }
+}
+
+/// Converts IPv4-mapped IPv6 addresses (like `::ffff:192.0.2.1`, as dual-stack sockets report
+/// IPv4 peers) to IPv4 addresses, so that they match IPv4 ranges and key like them.
+fn canonical(addr: IpAddr) -> IpAddr {
+    match addr {
+        IpAddr::V6(v6) => match v6.segments() {
+            [0, 0, 0, 0, 0, 0xffff, high, low] => IpAddr::V4(Ipv4Addr::new(
+                (high >> 8) as u8,
+                high as u8,
+                (low >> 8) as u8,
+                low as u8,
+            )),
+            _ => IpAddr::V6(v6),
+        },
+        v4 => v4,
+    }
+}
+
+/// The header that proxies record the addresses they forwarded requests for in.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ForwardingHeader {
+    /// The standard `Forwarded` header, whose elements' `for` parameters are the addresses.
+    Forwarded,
+    /// The `X-Forwarded-For` header, a list of addresses.
+    #[default]
+    XForwardedFor,
+}
+
+impl ForwardingHeader {
+    /// Returns the header's name, in lowercase.
+    pub fn name(&self) -> &'static str {
+        match self {
+            ForwardingHeader::Forwarded => "forwarded",
+            ForwardingHeader::XForwardedFor => "x-forwarded-for",
+        }
+    }
+}
+
+/// Extracts the IP address of the client that sent a request through trusted proxies, for
+/// keying rate limits by client.
+///
+/// Without trusted proxies, the client's address is the peer address, and the forwarding
+/// header is ignored. With trusted proxies, the extractor only believes the forwarding header's
+/// entries as far as trusted proxies added them, so clients can't pick their own key by sending
+/// the header themselves. Entries that aren't addresses (like `unknown`, obfuscated identifiers,
+/// or garbage) end the chain: The proxy that forwarded them is taken to be the client.
+///
+/// In configuration, this is e.g.
+/// `{"trusted_proxies": ["10.0.0.0/8", "::1"], "header": "x-forwarded-for"}`.
+///
+/// ```rust
+/// use governor_tower::client_ip::ClientIpKeyExtractor;
+/// use std::net::IpAddr;
+///
+/// let extractor = ClientIpKeyExtractor::new().trust("10.0.0.0/8".parse().unwrap());
+/// let proxy: IpAddr = "10.0.0.5".parse().unwrap();
+/// // The client claims to be 192.0.2.1, but only the proxy's entry can be believed:
+/// assert_eq!(
+///     extractor.client_ip(proxy, vec!["192.0.2.1, 198.51.100.7"]),
+///     "198.51.100.7".parse::<IpAddr>().unwrap()
+/// );
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ClientIpKeyExtractor {
+    #[serde(default)]
+    trusted_proxies: Vec<IpNet>,
+    #[serde(default)]
+    header: ForwardingHeader,
+}
+
+impl ClientIpKeyExtractor {
+    /// Constructs an extractor that trusts no proxies, and reads `X-Forwarded-For` once it
+    /// does.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Trusts the proxies in the given range to report the addresses they forwarded requests
+    /// for truthfully.
+    pub fn trust(mut self, proxies: IpNet) -> Self {
+        self.trusted_proxies.push(proxies);
+        self
+    }
+
+    /// Reads the addresses that proxies forwarded requests for from the given header.
+    pub fn header(mut self, header: ForwardingHeader) -> Self {
+        self.header = header;
+        self
+    }
+
+    /// Returns the header that forwarded addresses are read from.
+    pub fn forwarding_header(&self) -> ForwardingHeader {
+        self.header
+    }
+
+    fn is_trusted(&self, ip: IpAddr) -> bool {
+        self.trusted_proxies.iter().any(|net| net.contains(ip))
+    }
+
+    /// Returns the IP address of the client, given the address of the request's peer and the
+    /// values of the forwarding header, in the order that they appear in the request.
+    pub fn client_ip<'a, I>(&self, peer: IpAddr, header_values: I) -> IpAddr
+    where
+        I: IntoIterator<Item = &'a str>,
+    {
+        let mut client = canonical(peer);
+        if !self.is_trusted(client) {
+            return client;
+        }
+        let hops: Vec<Option<IpAddr>> = header_values
+            .into_iter()
+            .flat_map(|value| value.split(','))
+            .map(|entry| match self.header {
+                ForwardingHeader::Forwarded => forwarded_for(entry),
+                ForwardingHeader::XForwardedFor => parse_node(entry),
+            })
+            .collect();
+        // The last entry was added by the proxy closest to us, so walk the chain backwards:
+        for hop in hops.into_iter().rev() {
+            match hop {
+                Some(ip) => {
+                    client = canonical(ip);
+                    if !self.is_trusted(client) {
+                        break;
+                    }
+                }
+                None => break,
+            }
+        }
+        client
+    }
+
+    /// Returns the client's IP address as a rate-limiting key.
+    pub fn key<'a, I>(&self, peer: IpAddr, header_values: I) -> String
+    where
+        I: IntoIterator<Item = &'a str>,
+    {
+        self.client_ip(peer, header_values).to_string()
+    }
+}
+
+/// Returns the address in the `for` parameter of an element of a `Forwarded` header.
+fn forwarded_for(element: &str) -> Option<IpAddr> {
+    element.split(';').find_map(|pair| {
+        let (name, value) = pair.split_once('=')?;
+        if name.trim().eq_ignore_ascii_case("for") {
+            parse_node(value)
+        } else {
+            None
+        }
+    })
+}
+
+/// Parses a node (an address, maybe quoted, bracketed or with a port) of a forwarding header.
+fn parse_node(node: &str) -> Option<IpAddr> {
+    let node = node.trim();
+    let node = node
+        .strip_prefix('"')
+        .and_then(|node| node.strip_suffix('"'))
+        .unwrap_or(node);
+    if let Some(bracketed) = node.strip_prefix('[') {
+        // An IPv6 address, maybe followed by a port:
+        let (addr, _) = bracketed.split_once(']')?;
+        return addr.parse::<Ipv6Addr>().ok().map(IpAddr::V6);
+    }
+    node.parse::<IpAddr>()
+        .ok()
+        .or_else(|| node.parse::<SocketAddr>().ok().map(|addr| addr.ip()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn ranges() {
+        let net: IpNet = "10.0.0.0/8".parse().unwrap();
+        assert!(net.contains(ip("10.255.0.1")));
+        assert!(net.contains(ip("::ffff:10.1.2.3")));
+        assert!(!net.contains(ip("11.0.0.1")));
+        assert!(!net.contains(ip("::1")));
+        let net: IpNet = "fd00::/8".parse().unwrap();
+        assert!(net.contains(ip("fd12::1")));
+        assert!(!net.contains(ip("fe80::1")));
+        let all: IpNet = "0.0.0.0/0".parse().unwrap();
+        assert!(all.contains(ip("192.0.2.1")));
+        let single: IpNet = "192.0.2.1".parse().unwrap();
+        assert_eq!(single.to_string(), "192.0.2.1/32");
+        assert!(!single.contains(ip("192.0.2.2")));
+        assert!("10.0.0.0/33".parse::<IpNet>().is_err());
+        assert!("10.0.0/8".parse::<IpNet>().is_err());
+    }
+
+    #[test]
+    fn untrusted_peers_are_clients() {
+        let extractor = ClientIpKeyExtractor::new();
+        assert_eq!(
+            extractor.client_ip(ip("192.0.2.1"), vec!["198.51.100.7"]),
+            ip("192.0.2.1")
+        );
+        assert_eq!(
+            extractor.client_ip(ip("::ffff:192.0.2.1"), vec![]),
+            ip("192.0.2.1")
+        );
+    }
+
+    #[test]
+    fn walks_trusted_proxies() {
+        let extractor = ClientIpKeyExtractor::new()
+            .trust("10.0.0.0/8".parse().unwrap())
+            .trust("::1".parse().unwrap());
+        let peer = ip("::1");
+        assert_eq!(
+            extractor.client_ip(peer, vec!["6.6.6.6, 192.0.2.1", "10.1.1.1"]),
+            ip("192.0.2.1")
+        );
+        // Only trusted proxies:
+        assert_eq!(extractor.client_ip(peer, vec!["10.1.1.1"]), ip("10.1.1.1"));
+        assert_eq!(extractor.client_ip(peer, vec![]), ip("::1"));
+        // Garbage ends the chain at the proxy that forwarded it:
+        assert_eq!(
+            extractor.client_ip(peer, vec!["192.0.2.1, nonsense, 10.1.1.1"]),
+            ip("10.1.1.1")
+        );
+        assert_eq!(extractor.client_ip(peer, vec!["192.0.2.1,"]), ip("::1"));
+        assert_eq!(
+            extractor.client_ip(peer, vec![" 192.0.2.1:8080 , [2001:db8::1]:443"]),
+            ip("2001:db8::1")
+        );
+        assert_eq!(extractor.key(peer, vec!["192.0.2.1"]), "192.0.2.1");
+    }
+
+    #[test]
+    fn reads_forwarded_headers() {
+        let extractor = ClientIpKeyExtractor::new()
+            .trust("10.0.0.0/8".parse().unwrap())
+            .header(ForwardingHeader::Forwarded);
+        assert_eq!(extractor.forwarding_header().name(), "forwarded");
+        let peer = ip("10.0.0.1");
+        assert_eq!(
+            extractor.client_ip(
+                peer,
+                vec![r#"for=6.6.6.6, For="[2001:db8:cafe::17]:4711";proto=https"#],
+            ),
+            ip("2001:db8:cafe::17")
+        );
+        assert_eq!(
+            extractor.client_ip(peer, vec!["for=192.0.2.60;proto=http;by=203.0.113.43"]),
+            ip("192.0.2.60")
+        );
+        assert_eq!(
+            extractor.client_ip(peer, vec!["for=6.6.6.6, for=unknown"]),
+            peer
+        );
+        assert_eq!(
+            extractor.client_ip(peer, vec!["for=6.6.6.6, proto=https"]),
+            peer
+        );
+    }
+}
@@ -0,0 +1,539 @@
+//! A [tower](https://docs.rs/tower) middleware that rate-limits the requests a service handles
+//! with a governor rate limiter.
+//!
+//! [`RateLimitLayer`] wraps services in a [`RateLimit`] service, which only reports readiness
+//! once the rate limiter lets a request through: Callers that wait for the service to become
+//! ready (as tower's combinators do) are slowed down to the quota, instead of having their
+//! requests rejected. Layers can instead [police](RateLimitLayer::with_enforcement) their
+//! rate limit, failing the requests that the rate limiter doesn't let through right away, or
+//! bound the number of services that wait for it.
+//!
+//! [`RateLimit`] implements [`Load`], measuring how much of the rate limiter's burst capacity is
+//! used up, so that load balancers like tower's `Balance` can prefer endpoints whose limits
+//! have capacity to spare.
+//!
+//! For HTTP services, [`routes::RoutedRateLimitLayer`] applies a different quota and key
+//! strategy per endpoint, configured with a [`routes::RouteTable`]. Behind proxies,
+//! [`client_ip::ClientIpKeyExtractor`] keys requests by the client's IP address without
+//! letting clients spoof it, and [`account::BearerClaimKeyExtractor`] keys them by the account
+//! named in their bearer token.
+//!
+//! ```rust,no_run
+//! # use nonzero_ext::*;
+//! use governor::Quota;
+//! use governor_tower::{RateLimitError, RateLimitLayer};
+//! use std::convert::Infallible;
+//! use tower::{service_fn, ServiceBuilder, ServiceExt};
+//!
+//! # async fn example() -> Result<(), RateLimitError<Infallible>> {
+//! let service = ServiceBuilder::new()
+//!     .layer(RateLimitLayer::new(Quota::per_second(nonzero!(10u32))))
+//!     .service(service_fn(|req: u32| async move { Ok::<_, Infallible>(req) }));
+//! assert_eq!(service.oneshot(42).await?, 42);
+//! # Ok(())
+//! # }
+//! ```
+
+pub mod account;
+pub mod client_ip;
+pub mod routes;
+
+use futures::future::{self, BoxFuture, Either, MapErr, Ready};
+use futures::{FutureExt, TryFutureExt};
+use governor::state::direct::{Enforcement, Overflow};
+use governor::{DefaultDirectRateLimiter, Quota, RateLimiter};
+use std::collections::{HashSet, VecDeque};
+use std::error::Error;
+use std::fmt;
+use std::mem;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+use tower::load::Load;
+use tower_layer::Layer;
+use tower_service::Service;
+
+/// A layer that rate-limits the requests of the services it wraps.
+///
+/// All services that a layer wraps share its rate limiter, and the queue of services that wait
+/// for it.
+#[derive(Debug, Clone)]
+pub struct RateLimitLayer {
+    limiter: Arc<DefaultDirectRateLimiter>,
+    enforcement: Enforcement,
+    queue: Arc<Mutex<WaitQueue>>,
+}
+
+impl RateLimitLayer {
+    /// Constructs a layer that lets requests through at the rate of the given quota.
+    pub fn new(quota: Quota) -> Self {
+        Self::with_limiter(Arc::new(RateLimiter::direct(quota)))
+    }
+
+    /// Constructs a layer that lets requests through with the given rate limiter, which may be
+    /// shared with other layers or parts of the program.
+    pub fn with_limiter(limiter: Arc<DefaultDirectRateLimiter>) -> Self {
+        RateLimitLayer {
+            limiter,
+            enforcement: Enforcement::default(),
+            queue: Default::default(),
+        }
+    }
+
+    /// Sets how the services that the layer wraps enforce the rate limit.
+    ///
+    /// When policing, services are always ready, and calls that the rate limiter doesn't let
+    /// through right away fail with [`RateLimitError::RateLimited`]. When shaping (the
+    /// default), services wait in `poll_ready` until the rate limiter lets a call through; at
+    /// most `max_queue_depth` of them wait for the rate limiter at once, and the `overflow`
+    /// decides what happens to any more: With [`Overflow::Backpressure`], they wait for their
+    /// turn, which doesn't change how fast calls go through. With [`Overflow::DropNewest`] or
+    /// [`Overflow::DropOldest`], the service that arrived or the one that waited longest
+    /// becomes ready, and its call fails with [`RateLimitError::QueueFull`].
+    ///
+    /// ```rust
+    /// # use nonzero_ext::*;
+    /// use governor::{state::direct::Enforcement, Quota};
+    /// use governor_tower::{RateLimitError, RateLimitLayer};
+    /// use tower::{service_fn, Layer, ServiceExt};
+    ///
+    /// # #[tokio::main(flavor = "current_thread")] async fn main() {
+    /// let layer = RateLimitLayer::new(Quota::per_hour(nonzero!(1u32)))
+    ///     .with_enforcement(Enforcement::Police);
+    /// let service = layer.layer(service_fn(|req: u32| async move {
+    ///     Ok::<_, std::convert::Infallible>(req)
+    /// }));
+    /// assert_eq!(service.clone().oneshot(1).await.unwrap(), 1);
+    /// assert!(matches!(
+    ///     service.oneshot(2).await,
+    ///     Err(RateLimitError::RateLimited)
+    /// ));
+    /// # }
+    /// ```
+    pub fn with_enforcement(self, enforcement: Enforcement) -> Self {
+        RateLimitLayer {
+            enforcement,
+            queue: Default::default(),
+            ..self
+        }
+    }
+
+    /// Returns how the services that the layer wraps enforce the rate limit.
+    pub fn enforcement(&self) -> Enforcement {
+        self.enforcement
+    }
+}
+
+impl<S> Layer<S> for RateLimitLayer {
+    type Service = RateLimit<S>;
+
+    fn layer(&self, inner: S) -> RateLimit<S> {
+        RateLimit {
+            inner,
+            limiter: Arc::clone(&self.limiter),
+            enforcement: self.enforcement,
+            queue: Arc::clone(&self.queue),
+            state: State::Idle,
+        }
+    }
+}
+
+/// The errors of a [`RateLimit`] service.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RateLimitError<E> {
+    /// The service polices its rate limit, and the rate limiter didn't let the call through
+    /// right away.
+    RateLimited,
+
+    /// The service shapes its rate limit, and the call was dropped because too many services
+    /// waited for the rate limiter.
+    QueueFull,
+
+    /// The wrapped service failed.
+    Inner(E),
+}
+
+impl<E: fmt::Display> fmt::Display for RateLimitError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RateLimitError::RateLimited => f.write_str("rate limit exceeded"),
+            RateLimitError::QueueFull => f.write_str("too many requests wait for the rate limit"),
+            RateLimitError::Inner(err) => err.fmt(f),
+        }
+    }
+}
+
+impl<E: Error + 'static> Error for RateLimitError<E> {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            RateLimitError::Inner(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+/// The services of a layer that wait for its rate limiter, oldest first.
+#[derive(Debug, Default)]
+struct WaitQueue {
+    next_id: u64,
+    /// The waiting services' IDs, and the wakers of their last `poll_ready`.
+    waiting: VecDeque<(u64, Option<Waker>)>,
+    /// The services that were dropped from the queue to make room for newer ones.
+    dropped: HashSet<u64>,
+    /// The services that wait for room in the queue.
+    blocked: Vec<Waker>,
+}
+
+/// A service's place in the wait queue, which it gives up when dropped.
+struct Ticket {
+    queue: Arc<Mutex<WaitQueue>>,
+    id: u64,
+}
+
+impl Ticket {
+    /// Returns whether the service was dropped from the queue; if not, registers the waker to
+    /// wake it with if it is.
+    fn dropped(&self, waker: &Waker) -> bool {
+        let mut queue = self.queue.lock().unwrap();
+        if queue.dropped.remove(&self.id) {
+            return true;
+        }
+        if let Some((_, registered)) = queue.waiting.iter_mut().find(|(id, _)| *id == self.id) {
+            *registered = Some(waker.clone());
+        }
+        false
+    }
+}
+
+impl Drop for Ticket {
+    fn drop(&mut self) {
+        let blocked = {
+            let mut queue = self.queue.lock().unwrap();
+            queue.dropped.remove(&self.id);
+            queue.waiting.retain(|(id, _)| *id != self.id);
+            mem::take(&mut queue.blocked)
+        };
+        blocked.into_iter().for_each(Waker::wake);
+    }
+}
+
+/// A service that rate-limits the requests of the service it wraps.
+///
+/// When [shaping](RateLimitLayer::with_enforcement), the service becomes ready once the rate
+/// limiter lets a cell through and the wrapped service is ready. When policing, it becomes
+/// ready right away if the rate limiter doesn't let a cell through. Each call uses up the cell
+/// that readiness acquired (or fails without one), so `poll_ready` must return
+/// `Poll::Ready(Ok(()))` before every call.
+pub struct RateLimit<S> {
+    inner: S,
+    limiter: Arc<DefaultDirectRateLimiter>,
+    enforcement: Enforcement,
+    queue: Arc<Mutex<WaitQueue>>,
+    state: State,
+}
+
+enum State {
+    /// No cell has been acquired for the next call.
+    Idle,
+    /// Waiting for the rate limiter to let a cell through, with a place in the wait queue.
+    Waiting(BoxFuture<'static, ()>, Ticket),
+    /// A cell has been acquired for the next call.
+    Permitted,
+    /// The next call fails without a cell, with the given error.
+    Rejected(RateLimitError<()>),
+}
+
+impl<S> RateLimit<S> {
+    /// Wraps a service, rate-limiting its requests with the given rate limiter, shaping them
+    /// with the default [enforcement](RateLimitLayer::with_enforcement).
+    pub fn new(inner: S, limiter: Arc<DefaultDirectRateLimiter>) -> Self {
+        RateLimitLayer::with_limiter(limiter).layer(inner)
+    }
+
+    /// Returns the rate limiter.
+    pub fn limiter(&self) -> &DefaultDirectRateLimiter {
+        &self.limiter
+    }
+
+    /// Returns how the service enforces the rate limit.
+    pub fn enforcement(&self) -> Enforcement {
+        self.enforcement
+    }
+
+    /// Takes a place in the wait queue (or makes room for one, or waits for one when the
+    /// queue exerts backpressure) and starts waiting for the rate limiter.
+    fn enqueue(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+        let (max_queue_depth, overflow) = match self.enforcement {
+            Enforcement::Police => {
+                self.state = State::Rejected(RateLimitError::RateLimited);
+                return Poll::Ready(());
+            }
+            Enforcement::Shape {
+                max_queue_depth,
+                overflow,
+            } => (max_queue_depth.get(), overflow),
+        };
+        let mut queue = self.queue.lock().unwrap();
+        if queue.waiting.len() >= max_queue_depth {
+            match overflow {
+                Overflow::Backpressure => {
+                    if !queue.blocked.iter().any(|w| w.will_wake(cx.waker())) {
+                        queue.blocked.push(cx.waker().clone());
+                    }
+                    return Poll::Pending;
+                }
+                Overflow::DropNewest => {
+                    self.state = State::Rejected(RateLimitError::QueueFull);
+                    return Poll::Ready(());
+                }
+                Overflow::DropOldest => {
+                    if let Some((id, waker)) = queue.waiting.pop_front() {
+                        queue.dropped.insert(id);
+                        waker.into_iter().for_each(Waker::wake);
+                    }
+                }
+            }
+        }
+        let id = queue.next_id;
+        queue.next_id += 1;
+        queue.waiting.push_back((id, Some(cx.waker().clone())));
+        drop(queue);
+        let ticket = Ticket {
+            queue: Arc::clone(&self.queue),
+            id,
+        };
+        let limiter = Arc::clone(&self.limiter);
+        self.state = State::Waiting(async move { limiter.until_ready().await }.boxed(), ticket);
+        Poll::Ready(())
+    }
+
+    /// Returns the wrapped service.
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+
+    /// Returns the wrapped service.
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+}
+
+impl<S, Request> Service<Request> for RateLimit<S>
+where
+    S: Service<Request>,
+{
+    type Response = S::Response;
+    type Error = RateLimitError<S::Error>;
+    #[allow(clippy::type_complexity)]
+    type Future = Either<
+        MapErr<S::Future, fn(S::Error) -> RateLimitError<S::Error>>,
+        Ready<Result<S::Response, RateLimitError<S::Error>>>,
+    >;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        loop {
+            match &mut self.state {
+                State::Idle => {
+                    if self.limiter.check().is_ok() {
+                        self.state = State::Permitted;
+                    } else if self.enqueue(cx).is_pending() {
+                        return Poll::Pending;
+                    }
+                }
+                State::Waiting(wait, ticket) => {
+                    if ticket.dropped(cx.waker()) {
+                        self.state = State::Rejected(RateLimitError::QueueFull);
+                        continue;
+                    }
+                    match wait.poll_unpin(cx) {
+                        // Dropping the ticket makes room in the queue:
+                        Poll::Ready(()) => self.state = State::Permitted,
+                        Poll::Pending => return Poll::Pending,
+                    }
+                }
+                State::Permitted => {
+                    return self.inner.poll_ready(cx).map_err(RateLimitError::Inner)
+                }
+                State::Rejected(_) => return Poll::Ready(Ok(())),
+            }
+        }
+    }
+
+    fn call(&mut self, request: Request) -> Self::Future {
+        match mem::replace(&mut self.state, State::Idle) {
+            State::Permitted => {
+                Either::Left(self.inner.call(request).map_err(RateLimitError::Inner))
+            }
+            State::Rejected(RateLimitError::RateLimited) => {
+                Either::Right(future::err(RateLimitError::RateLimited))
+            }
+            State::Rejected(_) => Either::Right(future::err(RateLimitError::QueueFull)),
+            _ => panic!("RateLimit service called before it was ready"),
+        }
+    }
+}
+
+/// Rate-limited services measure their load as the fraction of the rate limiter's burst
+/// capacity that is used up: 0 if a burst of requests could go through right away, 1 if the
+/// next request has to wait. See
+/// [`RateLimiter::saturation`](governor::RateLimiter::saturation).
+impl<S> Load for RateLimit<S> {
+    type Metric = f64;
+
+    fn load(&self) -> f64 {
+        self.limiter.saturation()
+    }
+}
+
+/// Clones share the rate limiter and the wait queue, but acquire cells for their calls on
+/// their own.
+impl<S: Clone> Clone for RateLimit<S> {
+    fn clone(&self) -> Self {
+        RateLimit {
+            inner: self.inner.clone(),
+            limiter: Arc::clone(&self.limiter),
+            enforcement: self.enforcement,
+            queue: Arc::clone(&self.queue),
+            state: State::Idle,
+        }
+    }
+}
+
+impl<S: fmt::Debug> fmt::Debug for RateLimit<S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RateLimit")
+            .field("inner", &self.inner)
+            .field("limiter", &self.limiter)
+            .field("enforcement", &self.enforcement)
+            .field("permitted", &matches!(self.state, State::Permitted))
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use nonzero_ext::nonzero;
+    use std::convert::Infallible;
+    use std::time::{Duration, Instant};
+    use tower::{service_fn, ServiceExt};
+
+    fn echo() -> impl Service<u32, Response = u32, Error = Infallible> + Clone {
+        service_fn(|req: u32| async move { Ok::<_, Infallible>(req) })
+    }
+
+    #[tokio::test]
+    async fn waits_for_capacity() {
+        let layer =
+            RateLimitLayer::new(Quota::per_second(nonzero!(20u32)).allow_burst(nonzero!(1u32)));
+        let mut service = layer.layer(echo());
+        let start = Instant::now();
+        for i in 0..3 {
+            assert_eq!(service.ready().await.unwrap().call(i).await.unwrap(), i);
+        }
+        assert!(start.elapsed() >= Duration::from_millis(100));
+    }
+
+    #[tokio::test]
+    async fn reports_load() {
+        let limiter = Arc::new(RateLimiter::direct(Quota::per_minute(nonzero!(4u32))));
+        let mut service = RateLimitLayer::with_limiter(Arc::clone(&limiter)).layer(echo());
+        assert_eq!(service.load(), 0.0);
+        for i in 0..2 {
+            service.ready().await.unwrap().call(i).await.unwrap();
+        }
+        // The real clock replenishes a little of the used-up capacity after the calls:
+        assert!((service.load() - 0.5).abs() < 0.01);
+        assert!((service.clone().load() - 0.5).abs() < 0.01);
+    }
+
+    #[tokio::test]
+    async fn polices() {
+        let layer = RateLimitLayer::new(Quota::per_hour(nonzero!(1u32)))
+            .with_enforcement(Enforcement::Police);
+        assert_eq!(layer.enforcement(), Enforcement::Police);
+        let mut service = layer.layer(echo());
+        assert_eq!(service.ready().await.unwrap().call(1).await, Ok(1));
+        assert_eq!(
+            service.ready().await.unwrap().call(2).await,
+            Err(RateLimitError::RateLimited)
+        );
+    }
+
+    fn shaping(overflow: Overflow) -> RateLimitLayer {
+        RateLimitLayer::new(Quota::per_second(nonzero!(20u32)).allow_burst(nonzero!(1u32)))
+            .with_enforcement(Enforcement::shape(nonzero!(1usize), overflow))
+    }
+
+    #[tokio::test]
+    async fn drops_the_newest_caller() {
+        let layer = shaping(Overflow::DropNewest);
+        let (mut first, mut second, mut third) = (
+            layer.layer(echo()),
+            layer.layer(echo()),
+            layer.layer(echo()),
+        );
+        assert_eq!(first.ready().await.unwrap().call(1).await, Ok(1));
+        assert!(second.ready().now_or_never().is_none());
+        // The queue is full, so the third service is ready, but its call is dropped:
+        assert_eq!(
+            third.ready().await.unwrap().call(3).await,
+            Err(RateLimitError::QueueFull)
+        );
+        assert_eq!(second.ready().await.unwrap().call(2).await, Ok(2));
+    }
+
+    #[tokio::test]
+    async fn drops_the_oldest_caller() {
+        let layer = shaping(Overflow::DropOldest);
+        let (mut first, mut second, mut third) = (
+            layer.layer(echo()),
+            layer.layer(echo()),
+            layer.layer(echo()),
+        );
+        assert_eq!(first.ready().await.unwrap().call(1).await, Ok(1));
+        assert!(second.ready().now_or_never().is_none());
+        assert!(third.ready().now_or_never().is_none());
+        assert_eq!(
+            second.ready().await.unwrap().call(2).await,
+            Err(RateLimitError::QueueFull)
+        );
+        assert_eq!(third.ready().await.unwrap().call(3).await, Ok(3));
+    }
+
+    #[tokio::test]
+    async fn exerts_backpressure() {
+        let layer = shaping(Overflow::Backpressure);
+        let (mut first, mut second, mut third) = (
+            layer.layer(echo()),
+            layer.layer(echo()),
+            layer.layer(echo()),
+        );
+        assert_eq!(first.ready().await.unwrap().call(1).await, Ok(1));
+        assert!(second.ready().now_or_never().is_none());
+        assert!(third.ready().now_or_never().is_none());
+        // Dropping the waiting service makes room for the next one:
+        drop(second);
+        assert_eq!(third.ready().await.unwrap().call(3).await, Ok(3));
+    }
+
+    #[test]
+    fn displays_errors() {
+        let err = RateLimitError::Inner(std::fmt::Error);
+        assert_eq!(err.to_string(), std::fmt::Error.to_string());
+        assert!(err.source().is_some());
+        assert!(RateLimitError::<std::fmt::Error>::QueueFull
+            .source()
+            .is_none());
+        assert_eq!(
+            RateLimitError::<Infallible>::RateLimited.to_string(),
+            "rate limit exceeded"
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "before it was ready")]
+    fn call_without_readiness() {
+        let mut service = RateLimitLayer::new(Quota::per_second(nonzero!(1u32))).layer(echo());
+        drop(service.call(1));
+    }
+}
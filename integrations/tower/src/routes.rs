@@ -0,0 +1,700 @@
+//! Rate-limiting HTTP requests with a different quota per endpoint.
+//!
+//! A [`RouteTable`] maps path patterns (and optionally methods) to quotas and the way that
+//! requests are keyed, e.g. `/login` limited per client IP address to 5 requests a minute, and
+//! `/api/*` limited per API token to 100 requests a second. [`RoutedRateLimitLayer`] applies a
+//! table to an HTTP service, answering requests that exceed their route's quota with
+//! `429 Too Many Requests`, or with the response that a custom [`Responder`] builds.
+//!
+//! Route tables can be deserialized with serde from a list of routes:
+//!
+//! ```rust
+//! use governor_tower::routes::RouteTable;
+//!
+//! let table: RouteTable = serde_json::from_str(r#"[
+//!     {"path": "/login", "methods": ["POST"], "quota": "5/min", "key": "peer_ip"},
+//!     {"path": "/api/*", "quota": "100/s", "key": {"header": "authorization"}}
+//! ]"#).unwrap();
+//! assert_eq!(table.len(), 2);
+//! ```
+//!
+//! Routes are matched in order, and requests that match no route aren't rate-limited.
+
+use crate::account::BearerClaimKeyExtractor;
+use crate::client_ip::ClientIpKeyExtractor;
+use futures::future::{self, Either, Ready};
+use governor::clock::{Clock, DefaultClock};
+use governor::state::keyed::DefaultKeyedStateStore;
+use governor::{DefaultKeyedRateLimiter, NotUntil, ParseQuotaError, Quota, RateLimiter};
+use http::{header, HeaderMap, HeaderValue, Method, Request, Response, StatusCode, Uri};
+use serde::Deserialize;
+use std::convert::TryFrom;
+use std::error::Error;
+use std::fmt;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tower_layer::Layer;
+use tower_service::Service;
+
+/// The instants that the rate limiters of a route table measure time in.
+pub type Instant = <DefaultClock as Clock>::Instant;
+
+/// What a route's requests are keyed by, so that each key gets its own quota.
+///
+/// In configuration, this is `"global"`, `"peer_ip"`, `{"client_ip": {"trusted_proxies":
+/// ["10.0.0.0/8"]}}`, `{"bearer_claim": "<claim>"}` or `{"header": "<name>"}`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum KeyStrategy {
+    /// All requests share the quota.
+    #[default]
+    Global,
+
+    /// Requests are keyed by the IP address of the peer, as found in the request's
+    /// [`SocketAddr`] extension (which the server has to insert). Behind proxies, this is the
+    /// proxy's address; use [`ClientIp`](#variant.ClientIp) there.
+    PeerIp,
+
+    /// Requests are keyed by the IP address of the client that sent them through trusted
+    /// proxies, starting from the request's [`SocketAddr`] extension.
+    ClientIp(ClientIpKeyExtractor),
+
+    /// Requests are keyed by a claim of their bearer token, e.g. the account in its subject.
+    BearerClaim(BearerClaimKeyExtractor),
+
+    /// Requests are keyed by the value of the given header, e.g. an API key.
+    Header(String),
+}
+
+impl KeyStrategy {
+    /// Returns the key of a request. Requests without the key's information share the empty
+    /// key, so that leaving it out doesn't escape the quota.
+    fn key<B>(&self, request: &Request<B>) -> String {
+        match self {
+            KeyStrategy::Global => String::new(),
+            KeyStrategy::PeerIp => request
+                .extensions()
+                .get::<SocketAddr>()
+                .map(|addr| addr.ip().to_string())
+                .unwrap_or_default(),
+            KeyStrategy::ClientIp(extractor) => match request.extensions().get::<SocketAddr>() {
+                Some(peer) => {
+                    let values = request
+                        .headers()
+                        .get_all(extractor.forwarding_header().name())
+                        .iter()
+                        // Values that aren't text end the chain of forwarded addresses:
+                        .map(|value| value.to_str().unwrap_or(""));
+                    extractor.key(peer.ip(), values)
+                }
+                None => String::new(),
+            },
+            KeyStrategy::BearerClaim(extractor) => extractor.key(request).unwrap_or_default(),
+            KeyStrategy::Header(name) => request
+                .headers()
+                .get(name.as_str())
+                .and_then(|value| value.to_str().ok())
+                .unwrap_or_default()
+                .to_string(),
+        }
+    }
+}
+
+/// A path pattern: either an exact path, or a prefix followed by `*`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum PathPattern {
+    Exact(String),
+    Prefix(String),
+}
+
+impl PathPattern {
+    fn parse(pattern: &str) -> PathPattern {
+        match pattern.strip_suffix('*') {
+            Some(prefix) => PathPattern::Prefix(prefix.to_string()),
+            None => PathPattern::Exact(pattern.to_string()),
+        }
+    }
+
+    fn matches(&self, path: &str) -> bool {
+        match self {
+            PathPattern::Exact(exact) => path == exact,
+            PathPattern::Prefix(prefix) => path.starts_with(prefix.as_str()),
+        }
+    }
+}
+
+/// A route of a [`RouteTable`], as it is configured.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RouteConfig {
+    /// The path pattern: an exact path like `/login`, or a prefix followed by `*`, like
+    /// `/api/*`.
+    pub path: String,
+
+    /// The methods that the route applies to; all methods if empty.
+    #[serde(default)]
+    pub methods: Vec<String>,
+
+    /// The quota, like `5/min` or `100 per second`.
+    pub quota: String,
+
+    /// What the route's requests are keyed by.
+    #[serde(default)]
+    pub key: KeyStrategy,
+}
+
+/// An error in the configuration of a route table.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RouteConfigError {
+    /// The route's quota couldn't be parsed.
+    InvalidQuota(String, ParseQuotaError),
+    /// The route's method isn't a valid HTTP method.
+    InvalidMethod(String),
+}
+
+impl fmt::Display for RouteConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RouteConfigError::InvalidQuota(quota, err) => {
+                write!(f, "invalid quota {:?}: {}", quota, err)
+            }
+            RouteConfigError::InvalidMethod(method) => write!(f, "invalid method {:?}", method),
+        }
+    }
+}
+
+impl Error for RouteConfigError {}
+
+/// A route: the requests it applies to, and the rate limiter they are checked against.
+struct Route {
+    pattern: PathPattern,
+    methods: Vec<Method>,
+    key: KeyStrategy,
+    quota: Quota,
+    limiter: DefaultKeyedRateLimiter<String>,
+}
+
+impl Route {
+    fn matches<B>(&self, request: &Request<B>) -> bool {
+        (self.methods.is_empty() || self.methods.contains(request.method()))
+            && self.pattern.matches(request.uri().path())
+    }
+}
+
+impl fmt::Debug for Route {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Route")
+            .field("pattern", &self.pattern)
+            .field("methods", &self.methods)
+            .field("key", &self.key)
+            .field("quota", &self.quota)
+            .finish()
+    }
+}
+
+/// A table of routes, each with its own quota and key strategy.
+#[derive(Debug, Default, Deserialize)]
+#[serde(try_from = "Vec<RouteConfig>")]
+pub struct RouteTable {
+    clock: DefaultClock,
+    routes: Vec<Route>,
+}
+
+impl RouteTable {
+    /// Constructs an empty route table.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a route for the requests whose path matches `pattern` (an exact path, or a prefix
+    /// followed by `*`) and whose method is one of `methods` (or any method, if it is empty).
+    pub fn route(
+        mut self,
+        pattern: &str,
+        methods: &[Method],
+        quota: Quota,
+        key: KeyStrategy,
+    ) -> Self {
+        let limiter = RateLimiter::new(quota, DefaultKeyedStateStore::default(), &self.clock);
+        self.routes.push(Route {
+            pattern: PathPattern::parse(pattern),
+            methods: methods.to_vec(),
+            key,
+            quota,
+            limiter,
+        });
+        self
+    }
+
+    /// Returns the number of routes.
+    pub fn len(&self) -> usize {
+        self.routes.len()
+    }
+
+    /// Returns whether the table has no routes.
+    pub fn is_empty(&self) -> bool {
+        self.routes.is_empty()
+    }
+
+    /// Returns the quota of the first route that matches the request, if any does.
+    pub fn quota_for<B>(&self, request: &Request<B>) -> Option<Quota> {
+        self.find(request).map(|route| route.quota)
+    }
+
+    fn find<B>(&self, request: &Request<B>) -> Option<&Route> {
+        self.routes.iter().find(|route| route.matches(request))
+    }
+
+    /// Checks a request against the first route that matches it. Requests that match no route
+    /// are let through.
+    pub fn check<B>(&self, request: &Request<B>) -> Result<(), NotUntil<Instant>> {
+        match self.find(request) {
+            Some(route) => route.limiter.check_key(&route.key.key(request)),
+            None => Ok(()),
+        }
+    }
+
+    /// Returns the current time of the route table's rate limiters' clock.
+    pub fn now(&self) -> Instant {
+        self.clock.now()
+    }
+}
+
+impl TryFrom<Vec<RouteConfig>> for RouteTable {
+    type Error = RouteConfigError;
+
+    fn try_from(routes: Vec<RouteConfig>) -> Result<Self, Self::Error> {
+        routes
+            .into_iter()
+            .try_fold(RouteTable::new(), |table, route| {
+                let quota = route
+                    .quota
+                    .parse::<Quota>()
+                    .map_err(|err| RouteConfigError::InvalidQuota(route.quota.clone(), err))?;
+                let methods = route
+                    .methods
+                    .iter()
+                    .map(|method| {
+                        Method::from_bytes(method.to_ascii_uppercase().as_bytes())
+                            .map_err(|_| RouteConfigError::InvalidMethod(method.clone()))
+                    })
+                    .collect::<Result<Vec<_>, _>>()?;
+                Ok(table.route(&route.path, &methods, quota, route.key))
+            })
+    }
+}
+
+/// A request that exceeded its route's quota, as handed to a [`Responder`].
+#[derive(Debug)]
+pub struct Rejection<'a> {
+    not_until: NotUntil<Instant>,
+    now: Instant,
+    quota: Quota,
+    key: &'a str,
+    method: &'a Method,
+    uri: &'a Uri,
+    headers: &'a HeaderMap,
+}
+
+impl<'a> Rejection<'a> {
+    /// Returns the rate limiter's negative outcome, which tells when the request's key may send
+    /// another request.
+    pub fn not_until(&self) -> &NotUntil<Instant> {
+        &self.not_until
+    }
+
+    /// Returns how long the client has to wait before its next request can conform.
+    pub fn wait_time(&self) -> Duration {
+        self.not_until.wait_time_from(self.now)
+    }
+
+    /// Returns the wait time in whole seconds (rounded up), as used in `Retry-After` headers.
+    pub fn retry_after_secs(&self) -> u64 {
+        let wait = self.wait_time();
+        wait.as_secs() + u64::from(wait.subsec_nanos() > 0)
+    }
+
+    /// Returns the quota of the route that the request exceeded.
+    pub fn quota(&self) -> Quota {
+        self.quota
+    }
+
+    /// Returns the key that the request was rate-limited under (empty for routes with a
+    /// global quota).
+    pub fn key(&self) -> &'a str {
+        self.key
+    }
+
+    /// Returns the request's method.
+    pub fn method(&self) -> &'a Method {
+        self.method
+    }
+
+    /// Returns the request's URI.
+    pub fn uri(&self) -> &'a Uri {
+        self.uri
+    }
+
+    /// Returns the request's headers, e.g. to pick a language for the response.
+    pub fn headers(&self) -> &'a HeaderMap {
+        self.headers
+    }
+}
+
+/// Builds the responses to requests that exceeded their route's quota.
+///
+/// This is implemented by closures taking a [`Rejection`], so responses can be customized
+/// (e.g. with a JSON body, custom headers or a localized message) with
+/// [`RoutedRateLimitLayer::with_responder`]:
+///
+/// ```rust
+/// use governor_tower::routes::{Rejection, RouteTable, RoutedRateLimitLayer};
+/// use http::{header, Response, StatusCode};
+///
+/// let layer = RoutedRateLimitLayer::new(RouteTable::new()).with_responder(|rejection: &Rejection| {
+///     Response::builder()
+///         .status(StatusCode::TOO_MANY_REQUESTS)
+///         .header(header::CONTENT_TYPE, "application/json")
+///         .header(header::RETRY_AFTER, rejection.retry_after_secs())
+///         .body(format!(r#"{{"retry_after": {}}}"#, rejection.retry_after_secs()))
+///         .unwrap()
+/// });
+/// ```
+pub trait Responder<B> {
+    /// Builds the response to a rejected request.
+    fn respond(&self, rejection: &Rejection<'_>) -> Response<B>;
+}
+
+impl<F, B> Responder<B> for F
+where
+    F: Fn(&Rejection<'_>) -> Response<B>,
+{
+    fn respond(&self, rejection: &Rejection<'_>) -> Response<B> {
+        self(rejection)
+    }
+}
+
+/// The default [`Responder`]: a plain `429 Too Many Requests` response with an empty body and
+/// a `Retry-After` header.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TooManyRequests;
+
+impl<B: Default> Responder<B> for TooManyRequests {
+    fn respond(&self, rejection: &Rejection<'_>) -> Response<B> {
+        let mut response = Response::new(B::default());
+        *response.status_mut() = StatusCode::TOO_MANY_REQUESTS;
+        response.headers_mut().insert(
+            header::RETRY_AFTER,
+            HeaderValue::from(rejection.retry_after_secs()),
+        );
+        response
+    }
+}
+
+/// A layer that rate-limits the requests of the HTTP services it wraps with a [`RouteTable`],
+/// answering rejected requests with the responses its [`Responder`] builds.
+#[derive(Debug)]
+pub struct RoutedRateLimitLayer<R = TooManyRequests> {
+    table: Arc<RouteTable>,
+    responder: Arc<R>,
+}
+
+impl RoutedRateLimitLayer {
+    /// Constructs a layer that rate-limits requests according to the given route table,
+    /// answering rejected requests with [`TooManyRequests`].
+    pub fn new(table: RouteTable) -> Self {
+        RoutedRateLimitLayer {
+            table: Arc::new(table),
+            responder: Arc::new(TooManyRequests),
+        }
+    }
+}
+
+impl<R> RoutedRateLimitLayer<R> {
+    /// Answers rejected requests with the responses that the given responder builds.
+    pub fn with_responder<Other>(self, responder: Other) -> RoutedRateLimitLayer<Other> {
+        RoutedRateLimitLayer {
+            table: self.table,
+            responder: Arc::new(responder),
+        }
+    }
+}
+
+impl<R> Clone for RoutedRateLimitLayer<R> {
+    fn clone(&self) -> Self {
+        RoutedRateLimitLayer {
+            table: Arc::clone(&self.table),
+            responder: Arc::clone(&self.responder),
+        }
+    }
+}
+
+impl<S, R> Layer<S> for RoutedRateLimitLayer<R> {
+    type Service = RoutedRateLimit<S, R>;
+
+    fn layer(&self, inner: S) -> RoutedRateLimit<S, R> {
+        RoutedRateLimit {
+            inner,
+            table: Arc::clone(&self.table),
+            responder: Arc::clone(&self.responder),
+        }
+    }
+}
+
+/// An HTTP service that answers requests which exceed their route's quota with its
+/// [`Responder`]'s response (by default, `429 Too Many Requests` with a `Retry-After` header),
+/// and passes all others to the service it wraps.
+#[derive(Debug)]
+pub struct RoutedRateLimit<S, R = TooManyRequests> {
+    inner: S,
+    table: Arc<RouteTable>,
+    responder: Arc<R>,
+}
+
+impl<S, R> RoutedRateLimit<S, R> {
+    /// Returns the route table.
+    pub fn table(&self) -> &RouteTable {
+        &self.table
+    }
+}
+
+impl<S: Clone, R> Clone for RoutedRateLimit<S, R> {
+    fn clone(&self) -> Self {
+        RoutedRateLimit {
+            inner: self.inner.clone(),
+            table: Arc::clone(&self.table),
+            responder: Arc::clone(&self.responder),
+        }
+    }
+}
+
+impl<S, R, ReqBody, ResBody> Service<Request<ReqBody>> for RoutedRateLimit<S, R>
+where
+    S: Service<Request<ReqBody>, Response = Response<ResBody>>,
+    R: Responder<ResBody>,
+{
+    type Response = Response<ResBody>;
+    type Error = S::Error;
+    type Future = Either<S::Future, Ready<Result<Response<ResBody>, S::Error>>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: Request<ReqBody>) -> Self::Future {
+        let route = match self.table.find(&request) {
+            Some(route) => route,
+            None => return Either::Left(self.inner.call(request)),
+        };
+        let key = route.key.key(&request);
+        match route.limiter.check_key(&key) {
+            Ok(()) => Either::Left(self.inner.call(request)),
+            Err(not_until) => {
+                let rejection = Rejection {
+                    not_until,
+                    now: self.table.now(),
+                    quota: route.quota,
+                    key: &key,
+                    method: request.method(),
+                    uri: request.uri(),
+                    headers: request.headers(),
+                };
+                Either::Right(future::ready(Ok(self.responder.respond(&rejection))))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::convert::Infallible;
+    use tower::{service_fn, ServiceExt};
+
+    fn table() -> RouteTable {
+        serde_json::from_str(
+            r#"[
+                {"path": "/login", "methods": ["post"], "quota": "2/min", "key": "peer_ip"},
+                {"path": "/api/*", "quota": "1/min", "key": {"header": "authorization"}}
+            ]"#,
+        )
+        .unwrap()
+    }
+
+    fn request(method: Method, path: &str) -> Request<()> {
+        Request::builder()
+            .method(method)
+            .uri(path)
+            .body(())
+            .unwrap()
+    }
+
+    #[test]
+    fn routes_by_path_and_method() {
+        let table = table();
+        let login = request(Method::POST, "/login");
+        assert_eq!(table.quota_for(&login), Some("2/min".parse().unwrap()));
+        assert_eq!(table.quota_for(&request(Method::GET, "/login")), None);
+        assert_eq!(
+            table.quota_for(&request(Method::GET, "/api/users")),
+            Some("1/min".parse().unwrap())
+        );
+        assert_eq!(table.quota_for(&request(Method::GET, "/apiary")), None);
+
+        assert!(table.check(&login).is_ok());
+        assert!(table.check(&login).is_ok());
+        assert!(table.check(&login).is_err());
+        let mut elsewhere = request(Method::POST, "/login");
+        elsewhere
+            .extensions_mut()
+            .insert(SocketAddr::from(([192, 0, 2, 1], 443)));
+        assert!(table.check(&elsewhere).is_ok());
+    }
+
+    #[test]
+    fn keys_by_header() {
+        let table = table();
+        let with_token = |token: &str| {
+            let mut request = request(Method::GET, "/api/users");
+            request
+                .headers_mut()
+                .insert(header::AUTHORIZATION, HeaderValue::from_str(token).unwrap());
+            request
+        };
+        assert!(table.check(&with_token("a")).is_ok());
+        assert!(table.check(&with_token("a")).is_err());
+        assert!(table.check(&with_token("b")).is_ok());
+    }
+
+    #[test]
+    fn keys_by_client_ip() {
+        let table: RouteTable = serde_json::from_str(
+            r#"[{"path": "*", "quota": "1/min", "key": {"client_ip": {"trusted_proxies": ["10.0.0.0/8"]}}}]"#,
+        )
+        .unwrap();
+        let via_proxy = |forwarded_for: &str| {
+            let mut request = request(Method::GET, "/");
+            request
+                .extensions_mut()
+                .insert(SocketAddr::from(([10, 0, 0, 1], 443)));
+            request.headers_mut().insert(
+                "x-forwarded-for",
+                HeaderValue::from_str(forwarded_for).unwrap(),
+            );
+            request
+        };
+        assert!(table.check(&via_proxy("192.0.2.1")).is_ok());
+        // Spoofing an earlier entry doesn't change the key:
+        assert!(table.check(&via_proxy("6.6.6.6, 192.0.2.1")).is_err());
+        assert!(table.check(&via_proxy("192.0.2.2")).is_ok());
+    }
+
+    #[test]
+    fn keys_by_bearer_claim() {
+        let table: RouteTable = serde_json::from_str(
+            r#"[{"path": "*", "quota": "1/min", "key": {"bearer_claim": "sub"}}]"#,
+        )
+        .unwrap();
+        let bearer = |token: &str| {
+            let mut request = request(Method::GET, "/");
+            request.headers_mut().insert(
+                header::AUTHORIZATION,
+                HeaderValue::from_str(&format!("Bearer {}", token)).unwrap(),
+            );
+            request
+        };
+        // {"sub":"alice"} and {"sub":"bob"}:
+        let alice = "eyJhbGciOiJub25lIn0.eyJzdWIiOiJhbGljZSJ9.";
+        let bob = "eyJhbGciOiJub25lIn0.eyJzdWIiOiJib2IifQ.";
+        assert!(table.check(&bearer(alice)).is_ok());
+        assert!(table.check(&bearer(alice)).is_err());
+        assert!(table.check(&bearer(bob)).is_ok());
+        // Requests without a readable subject share a key:
+        assert!(table.check(&request(Method::GET, "/")).is_ok());
+        assert!(table.check(&bearer("garbage")).is_err());
+    }
+
+    #[test]
+    fn rejects_invalid_configuration() {
+        let err =
+            serde_json::from_str::<RouteTable>(r#"[{"path": "/", "quota": "lots"}]"#).unwrap_err();
+        assert!(err.to_string().contains("invalid quota"), "{}", err);
+        let err = serde_json::from_str::<RouteTable>(
+            r#"[{"path": "/", "methods": ["G E T"], "quota": "1/s"}]"#,
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("invalid method"), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn answers_with_too_many_requests() {
+        let table = RouteTable::new().route(
+            "*",
+            &[],
+            Quota::per_hour(nonzero_ext::nonzero!(1u32)),
+            KeyStrategy::Global,
+        );
+        let service = RoutedRateLimitLayer::new(table).layer(service_fn(|_: Request<()>| async {
+            Ok::<_, Infallible>(Response::new(String::from("hello")))
+        }));
+        let response = service
+            .clone()
+            .oneshot(request(Method::GET, "/"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = service.oneshot(request(Method::GET, "/")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.headers()[header::RETRY_AFTER], "3600");
+        assert!(response.body().is_empty());
+    }
+
+    #[tokio::test]
+    async fn responds_with_custom_responses() {
+        let table = RouteTable::new().route(
+            "/api/*",
+            &[],
+            Quota::per_minute(nonzero_ext::nonzero!(1u32)),
+            KeyStrategy::Header(String::from("x-api-key")),
+        );
+        let layer = RoutedRateLimitLayer::new(table).with_responder(|rejection: &Rejection| {
+            let greeting = match rejection.headers().get(header::ACCEPT_LANGUAGE) {
+                Some(lang) if lang == "de" => "Zu viele Anfragen",
+                _ => "Too many requests",
+            };
+            Response::builder()
+                .status(StatusCode::SERVICE_UNAVAILABLE)
+                .header("x-ratelimit-key", rejection.key())
+                .body(format!(
+                    "{} to {} (at most {} per {:?})",
+                    greeting,
+                    rejection.uri().path(),
+                    rejection.quota().burst_size(),
+                    rejection.quota().burst_size_replenished_in(),
+                ))
+                .unwrap()
+        });
+        let service = layer.layer(service_fn(|_: Request<()>| async {
+            Ok::<_, Infallible>(Response::new(String::from("hello")))
+        }));
+        let request = || {
+            Request::builder()
+                .uri("/api/users")
+                .header("x-api-key", "k")
+                .header(header::ACCEPT_LANGUAGE, "de")
+                .body(())
+                .unwrap()
+        };
+        let response = service.clone().oneshot(request()).await.unwrap();
+        assert_eq!(response.body(), "hello");
+        let response = service.oneshot(request()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(response.headers()["x-ratelimit-key"], "k");
+        assert_eq!(
+            response.body(),
+            "Zu viele Anfragen to /api/users (at most 1 per 60s)"
+        );
+    }
+}
@@ -36,6 +36,17 @@
 //! Quota::per_second(nonzero!(20u32));
 //! ```
 //!
+//! By default, a quota allows a burst of as many elements as it
+//! replenishes in its unit of time. The burst size can be adjusted
+//! independently of the rate, e.g. to sustain 10 elements per second,
+//! but allow an initial burst of 500 elements:
+//!
+//! ```rust
+//! # use nonzero_ext::*;
+//! use governor::Quota;
+//! Quota::per_second(nonzero!(10u32)).allow_burst(nonzero!(500u32));
+//! ```
+//!
 //! #### Constructing a direct rate limiter
 //!
 //! To make a direct rate limiter, you have to construct a quota, as
@@ -65,6 +76,30 @@
 //! RateLimiter::direct_with_clock(Quota::per_second(nonzero!(50u32)), &clock);
 //! ```
 //!
+//! `no_std` builds need an allocator, but no 64-bit atomics or
+//! compare-and-swap instructions: On targets without the latter
+//! (like `thumbv6m-none-eabi`), enable the `critical-section`
+//! feature, and link an implementation of the `critical-section`
+//! crate for your platform.
+//!
+//! Nothing that a `no_std` build of governor offers panics on its
+//! inputs: Quota constructors that can be given unrepresentable
+//! parameters (like [`Quota::new`][crate::Quota::new] and
+//! [`Quota::with_period`][crate::Quota::with_period]) return `None`
+//! for them, durations too long to represent saturate at ~584 years,
+//! and methods that would panic on their arguments, like
+//! [`check_arrivals`][crate::RateLimiter::check_arrivals], are only
+//! available with `std`, next to `try_` variants that return `None`
+//! instead.
+//!
+//! With the `defmt` feature, quotas, negative decisions
+//! ([`NotUntil`][crate::NotUntil] and
+//! [`NegativeMultiDecision`][crate::NegativeMultiDecision]), state
+//! snapshots and the [`Nanos`][crate::nanos::Nanos] and
+//! [`Ticks`][crate::clock::Ticks] that they report implement
+//! `defmt::Format`, so firmware can log decisions (e.g. over RTT)
+//! without pulling in `core::fmt`.
+//!
 //! #### Constructing a keyed rate limiter
 //!
 //! For a keyed rate limiter, you have to specify the type of the key:
@@ -93,7 +128,10 @@
 //! to a rate limiter in a program. Since its state lives in
 //! [`AtomicU64`][std::sync::atomic::AtomicU64] integers (which do not
 //! implement [`Clone`]), the rate limiters themselves can not be
-//! cloned.
+//! cloned - unless their state is kept behind an
+//! [`Arc`][std::sync::Arc]: Rate limiters converted with
+//! [`into_shared`][crate::RateLimiter::into_shared] can be cloned
+//! cheaply, with all clones sharing the same rate-limiting state.
 //!
 //! # Usage in multiple threads
 //!
@@ -103,7 +141,7 @@
 //! ends, which would invalidate the reference.
 //!
 //! So, to use a rate limiter in multiple threads without lifetime
-//! issues, there are two equally valid strategies:
+//! issues, there are three equally valid strategies:
 //!
 //! #### `crossbeam` scoped tasks
 //!
@@ -172,3 +210,82 @@
 //! # } #[cfg(not(feature = "std"))] fn main() {}
 //! ```
 //!
+//! #### Sharing the rate limiter's state
+//!
+//! Finally, a rate limiter can keep its state behind an
+//! [`Arc`][std::sync::Arc] itself, using
+//! [`into_shared`][crate::RateLimiter::into_shared]. The resulting
+//! rate limiter implements [`Clone`], so it can be cloned into
+//! threads (or stored in structs that need to be cloneable) directly:
+//!
+//! ```rust
+//! # #[cfg(feature = "std")] fn main() {
+//! # use nonzero_ext::*;
+//! # use governor::{RateLimiter, Quota};
+//! # use std::thread;
+//! let lim = RateLimiter::keyed(Quota::per_second(nonzero!(20u32))).into_shared();
+//! for _i in 0..20 {
+//!     let lim = lim.clone();
+//!     thread::spawn(move || {
+//!         assert_eq!(Ok(()), lim.check_key(&"client"));
+//!     })
+//!     .join()
+//!     .unwrap();
+//! }
+//! assert_ne!(Ok(()), lim.check_key(&"client"));
+//! # } #[cfg(not(feature = "std"))] fn main() {}
+//! ```
+//!
+//! # Performance
+//!
+//! Checking a single cell against a direct rate limiter is designed to
+//! be cheap enough for per-packet use. In the common case (the cell
+//! conforms and nobody else is updating the rate limiter at the same
+//! time), a decision costs:
+//!
+//! * one reading of the rate limiter's clock,
+//! * one atomic load that finds the rate limiter untuned,
+//! * one atomic load and one successful compare-and-swap on the
+//!   rate limiter's state,
+//! * and no allocations.
+//!
+//! A rate limiter constructs its runtime settings (in one
+//! [`Arc`][std::sync::Arc] that its clones share) the first time that
+//! one of them is used: setting the
+//! [pressure][crate::RateLimiter::set_pressure], a
+//! [reset][crate::RateLimiter::reset], a
+//! [resume][crate::RateLimiter::notify_resume] or
+//! [jump policy][crate::RateLimiter::set_jump_policy], a task
+//! [waiting][crate::RateLimiter::until_ready] for cells, or sharing the
+//! rate limiter with [`into_shared`][crate::RateLimiter::into_shared]
+//! and cloning it. From then on, each decision also loads the epoch,
+//! the pressure and the resume floor and jump policy threshold (four
+//! more atomic loads), and with a jump policy, reads the clock a second
+//! time, reads the system's monotonic clock and records both times of
+//! the latest decision with atomic `fetch_max`es, which all decisions
+//! contend on.
+//!
+//! The settings hold a mutex around the wakers of waiting tasks.
+//! Decisions never lock it, but anything that makes cells available
+//! early does, to wake up the waiting tasks: a
+//! [`MultiLimiter`][crate::MultiLimiter] refunding the cells it debited
+//! from some limiters when another one denies them, a
+//! [`RelaxedRateLimiter`][crate::state::direct::RelaxedRateLimiter]
+//! returning the unused cells of a reservation, resets, and lifting the
+//! pressure. Refunds only wake up the tasks waiting for the refunded key,
+//! and only if the refund lets a cell through; the others go on sleeping.
+//!
+//! Under contention, only the compare-and-swap is retried; the
+//! middleware's outcomes (including the
+//! [`NotUntil`][crate::NotUntil] of a negative decision) are only
+//! constructed once the decision is made, out of line of the
+//! conforming path.
+//!
+//! The `hot_path` benchmarks (run them with `cargo bench -- hot_path`)
+//! measure these decisions without the overhead of a real-time clock,
+//! and the `realtime_clock` benchmarks with each of the real-time
+//! clocks. Changes to the decision path are expected to keep the
+//! `hot_path` numbers within a few nanoseconds of their previous
+//! values; on a recent x86_64 machine, a conforming decision on an
+//! untuned rate limiter takes about 16ns, and a nonconforming one about
+//! 7ns.
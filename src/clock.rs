@@ -45,14 +45,14 @@
 //! }
 //! ```
 
-use std::prelude::v1::*;
+use crate::compat::prelude::*;
 
+use crate::sync::Arc;
+use crate::sync::AtomicU64;
+use crate::sync::Ordering;
 use std::convert::TryInto;
 use std::fmt::Debug;
 use std::ops::Add;
-use std::sync::atomic::AtomicU64;
-use std::sync::atomic::Ordering;
-use std::sync::Arc;
 use std::time::Duration;
 
 use crate::nanos::Nanos;
@@ -130,21 +130,19 @@ pub struct FakeRelativeClock {
 }
 
 impl FakeRelativeClock {
-    /// Advances the fake clock by the given amount.
+    /// Advances the fake clock by the given amount, stopping at the latest time it can
+    /// represent (about 584 years after it started).
     pub fn advance(&self, by: Duration) {
-        let by: u64 = by
-            .as_nanos()
-            .try_into()
-            .expect("Can not represent times past ~584 years");
+        let by: u64 = by.as_nanos().try_into().unwrap_or(u64::MAX);
 
         let mut prev = self.now.load(Ordering::Acquire);
-        let mut next = prev + by;
+        let mut next = prev.saturating_add(by);
         while let Err(next_prev) =
             self.now
                 .compare_exchange_weak(prev, next, Ordering::Release, Ordering::Relaxed)
         {
             prev = next_prev;
-            next = prev + by;
+            next = prev.saturating_add(by);
         }
     }
 }
@@ -174,16 +172,72 @@ impl Clock for FakeRelativeClock {
     }
 }
 
+/// A clock that reports a time supplied from outside the program, e.g. by the host of a
+/// WebAssembly plugin or an embedded system's timer interrupt.
+///
+/// The time is given in milliseconds or nanoseconds since an epoch that the supplier chooses
+/// (like the UNIX epoch); instants are counted in [`Nanos`] since that epoch. Setting the time
+/// to an earlier one than the current time has no effect, so the clock never goes backwards,
+/// even if the supplied time comes from a wall clock that does.
+///
+/// Like [`FakeRelativeClock`], clones of this clock all show the same time.
+///
+/// # Example
+/// ```rust
+/// # use nonzero_ext::*;
+/// use governor::{clock::ExternalClock, Quota, RateLimiter};
+///
+/// let clock = ExternalClock::default();
+/// clock.set_now_millis(1_600_000_000_000); // e.g. from the host's get_current_time
+/// let lim = RateLimiter::direct_with_clock(Quota::per_second(nonzero!(1u32)), &clock);
+/// assert_eq!(Ok(()), lim.check());
+/// assert!(lim.check().is_err());
+/// clock.set_now_millis(1_600_000_001_000);
+/// assert_eq!(Ok(()), lim.check());
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct ExternalClock {
+    now: Arc<AtomicU64>,
+}
+
+impl ExternalClock {
+    /// Sets the current time, in nanoseconds since the epoch.
+    pub fn set_now_nanos(&self, nanos: u64) {
+        self.now.fetch_max(nanos, Ordering::AcqRel);
+    }
+
+    /// Sets the current time, in milliseconds since the epoch.
+    pub fn set_now_millis(&self, millis: u64) {
+        self.set_now_nanos(millis.saturating_mul(1_000_000));
+    }
+}
+
+impl Clock for ExternalClock {
+    type Instant = Nanos;
+
+    fn now(&self) -> Self::Instant {
+        self.now.load(Ordering::Acquire).into()
+    }
+}
+
 #[cfg(feature = "std")]
 mod with_std;
 #[cfg(feature = "std")]
 pub use with_std::*;
 
+#[cfg(feature = "std")]
+mod skew;
+#[cfg(feature = "std")]
+pub use self::skew::{HybridLogicalClock, StoreTimeClock};
+
 #[cfg(all(feature = "std", feature = "quanta"))]
 mod quanta;
 #[cfg(all(feature = "std", feature = "quanta"))]
 pub use self::quanta::*;
 
+mod tick;
+pub use self::tick::{TickClock, Ticks};
+
 mod default;
 
 pub use default::*;
@@ -192,7 +246,7 @@ pub use default::*;
 mod test {
     use super::*;
     use crate::nanos::Nanos;
-    use std::iter::repeat;
+    use std::iter::repeat_n;
     use std::sync::Arc;
     use std::thread;
     use std::time::Duration;
@@ -200,12 +254,11 @@ mod test {
     #[test]
     fn fake_clock_parallel_advances() {
         let clock = Arc::new(FakeRelativeClock::default());
-        let threads = repeat(())
-            .take(10)
+        let threads = repeat_n((), 10)
             .map(move |_| {
                 let clock = Arc::clone(&clock);
                 thread::spawn(move || {
-                    for _ in (0..1000000).into_iter() {
+                    for _ in 0..1000000 {
                         let now = clock.now();
                         clock.advance(Duration::from_nanos(1));
                         assert!(clock.now() > now);
@@ -218,6 +271,18 @@ mod test {
         }
     }
 
+    #[test]
+    fn external_clock_never_goes_backwards() {
+        let clock = ExternalClock::default();
+        let clone = clock.clone();
+        clock.set_now_millis(2);
+        assert_eq!(clone.now(), Nanos::new(2_000_000));
+        clone.set_now_nanos(1);
+        assert_eq!(clock.now(), Nanos::new(2_000_000));
+        clock.set_now_millis(u64::MAX);
+        assert_eq!(clock.now(), Nanos::new(u64::MAX));
+    }
+
     #[test]
     fn duration_addition_coverage() {
         let d = Duration::from_secs(1);
@@ -1,9 +1,9 @@
-use std::prelude::v1::*;
+use crate::compat::prelude::*;
 
 use crate::clock::{Clock, ReasonablyRealtime, Reference};
 use crate::nanos::Nanos;
+use crate::sync::Arc;
 use std::ops::Add;
-use std::sync::Arc;
 use std::time::Duration;
 
 /// A clock using the default [`quanta::Clock`] structure.
@@ -12,15 +12,9 @@ use std::time::Duration;
 /// clock that uses a quanta background upkeep thread (which allows retrieving the time with an
 /// atomic read, but requires a background thread that wakes up continually),
 /// see [`QuantaUpkeepClock`].
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default)]
 pub struct QuantaClock(quanta::Clock);
 
-impl Default for QuantaClock {
-    fn default() -> Self {
-        QuantaClock(Default::default())
-    }
-}
-
 impl From<quanta::Instant> for Nanos {
     fn from(instant: quanta::Instant) -> Self {
         instant.as_u64().into()
@@ -71,7 +65,7 @@ impl Reference for QuantaInstant {
 /// and the upkeep interval that you pick; you should measure and compare performance before
 /// picking one or the other.
 #[derive(Debug, Clone)]
-pub struct QuantaUpkeepClock(quanta::Clock, Arc<quanta::Handle>);
+pub struct QuantaUpkeepClock(quanta::Clock, #[allow(dead_code)] Arc<quanta::Handle>);
 
 impl QuantaUpkeepClock {
     /// Returns a new `QuantaUpkeepClock` with an upkeep thread that wakes up once in `interval`.
@@ -0,0 +1,227 @@
+use super::{Clock, ReasonablyRealtime};
+
+use crate::compat::prelude::*;
+
+use crate::sync::Arc;
+use crate::sync::Mutex;
+use crate::sync::{AtomicU64, Ordering};
+use std::convert::TryFrom;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+fn nanos_since_epoch(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map_or(0, |since| {
+        u64::try_from(since.as_nanos()).unwrap_or(u64::MAX)
+    })
+}
+
+/// A sample of the store's time, and the local instant it was taken at.
+#[derive(Debug, Clone, Copy)]
+struct Sample {
+    store_time: SystemTime,
+    local: Instant,
+    round_trip: Duration,
+}
+
+/// A clock that shows the time of a remote state store (like the result of Redis's `TIME`
+/// command), so that all nodes sharing the store make their decisions on the same time line,
+/// no matter how skewed their own clocks are.
+///
+/// The clock is synchronized by [`sync`](#method.sync)ing it with the store's time now and
+/// then; in between, it advances with the local monotonic clock. Each sample is corrected for
+/// half of the round trip it took, and samples whose round trip took more than twice as long
+/// as the best one so far are ignored unless the best one is older than the
+/// [maximum age](#method.with_max_sample_age), as their correction is less precise. The clock
+/// never goes backwards, even if a new sample puts the store's time behind the clock's.
+///
+/// Before the first sample, the clock shows the local wall clock's time.
+///
+/// Clones of this clock share their samples.
+///
+/// # Example
+/// ```rust
+/// # use nonzero_ext::*;
+/// # use std::time::{Duration, SystemTime};
+/// use governor::{clock::StoreTimeClock, Quota, RateLimiter};
+///
+/// let clock = StoreTimeClock::default();
+/// // e.g. the result of Redis's TIME, on a server whose clock is a minute ahead:
+/// clock.sync(|| SystemTime::now() + Duration::from_secs(60));
+/// let lim = RateLimiter::direct_with_clock(Quota::per_second(nonzero!(10u32)), &clock);
+/// assert_eq!(Ok(()), lim.check());
+/// ```
+#[derive(Debug, Clone)]
+pub struct StoreTimeClock {
+    best: Arc<Mutex<Option<Sample>>>,
+    latest: Arc<AtomicU64>,
+    max_sample_age: Duration,
+}
+
+impl Default for StoreTimeClock {
+    fn default() -> Self {
+        StoreTimeClock {
+            best: Arc::new(Mutex::new(None)),
+            latest: Arc::new(AtomicU64::new(0)),
+            max_sample_age: Duration::from_secs(5 * 60),
+        }
+    }
+}
+
+impl StoreTimeClock {
+    /// Sets how long a sample is preferred over later samples with longer round trips; by
+    /// default, 5 minutes.
+    pub fn with_max_sample_age(mut self, max_sample_age: Duration) -> Self {
+        self.max_sample_age = max_sample_age;
+        self
+    }
+
+    /// Synchronizes the clock with the store's time, as returned by `fetch_store_time`, timing
+    /// the round trip.
+    pub fn sync(&self, fetch_store_time: impl FnOnce() -> SystemTime) {
+        let sent = Instant::now();
+        let store_time = fetch_store_time();
+        self.observe(store_time, sent, Instant::now());
+    }
+
+    /// Synchronizes the clock with the store's time, from a request that was sent at the local
+    /// instant `sent` and whose response arrived at `received`.
+    pub fn observe(&self, store_time: SystemTime, sent: Instant, received: Instant) {
+        let round_trip = received.saturating_duration_since(sent);
+        let sample = Sample {
+            store_time: store_time + round_trip / 2,
+            local: received,
+            round_trip,
+        };
+        let mut best = self.best.lock();
+        let replace = match *best {
+            None => true,
+            Some(current) => {
+                sample.round_trip <= current.round_trip * 2
+                    || received.saturating_duration_since(current.local) > self.max_sample_age
+            }
+        };
+        if replace {
+            *best = Some(sample);
+        }
+    }
+
+    /// Returns the round trip of the sample that the clock currently follows, if it was synced.
+    pub fn round_trip(&self) -> Option<Duration> {
+        self.best.lock().map(|sample| sample.round_trip)
+    }
+}
+
+impl Clock for StoreTimeClock {
+    type Instant = SystemTime;
+
+    fn now(&self) -> Self::Instant {
+        let estimate = match *self.best.lock() {
+            Some(sample) => sample.store_time + sample.local.elapsed(),
+            None => SystemTime::now(),
+        };
+        let latest = self
+            .latest
+            .fetch_max(nanos_since_epoch(estimate), Ordering::AcqRel);
+        estimate.max(UNIX_EPOCH + Duration::from_nanos(latest))
+    }
+}
+
+impl ReasonablyRealtime for StoreTimeClock {}
+
+/// A hybrid logical clock: It shows the local wall clock's time, but never a time before one
+/// that it [observed](#method.observe) from another node (e.g. in a state store's records, or
+/// in messages between nodes), and never the same time twice.
+///
+/// Nodes whose clocks run behind are pulled forward to the latest time that any node used, so
+/// that they don't grant cells early that nodes with correct clocks consider taken. No node's
+/// time is ever pulled back, so a node whose clock runs ahead pulls everyone else along, but
+/// none of them grant more cells than the quota allows on that time line.
+///
+/// Clones of this clock share their time.
+///
+/// # Example
+/// ```rust
+/// # use std::time::{Duration, SystemTime};
+/// use governor::clock::{Clock, HybridLogicalClock};
+///
+/// let clock = HybridLogicalClock::default();
+/// let remote = SystemTime::now() + Duration::from_secs(1);
+/// clock.observe(remote);
+/// assert!(clock.now() > remote);
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct HybridLogicalClock {
+    latest: Arc<AtomicU64>,
+}
+
+impl HybridLogicalClock {
+    /// Merges a time that another node used into the clock.
+    pub fn observe(&self, remote: SystemTime) {
+        self.latest
+            .fetch_max(nanos_since_epoch(remote), Ordering::AcqRel);
+    }
+}
+
+impl Clock for HybridLogicalClock {
+    type Instant = SystemTime;
+
+    fn now(&self) -> Self::Instant {
+        let physical = nanos_since_epoch(SystemTime::now());
+        let mut latest = self.latest.load(Ordering::Acquire);
+        loop {
+            let next = physical.max(latest.saturating_add(1));
+            match self.latest.compare_exchange_weak(
+                latest,
+                next,
+                Ordering::AcqRel,
+                Ordering::Acquire,
+            ) {
+                Ok(_) => return UNIX_EPOCH + Duration::from_nanos(next),
+                Err(current) => latest = current,
+            }
+        }
+    }
+}
+
+impl ReasonablyRealtime for HybridLogicalClock {}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn follows_the_store() {
+        let clock = StoreTimeClock::default();
+        let skew = Duration::from_secs(3600);
+        let start = Instant::now();
+        clock.observe(SystemTime::now() + skew, start, start);
+        let ahead = clock.now().duration_since(SystemTime::now()).unwrap();
+        assert!(ahead > skew - Duration::from_secs(1));
+        assert_eq!(clock.round_trip(), Some(Duration::from_secs(0)));
+
+        // A sample with a much longer round trip is ignored:
+        let later = start + Duration::from_millis(10);
+        clock.observe(SystemTime::now(), start, later);
+        assert_eq!(clock.round_trip(), Some(Duration::from_secs(0)));
+
+        // ...and once a sample is accepted that puts the store's time far behind this one, the
+        // clock doesn't go backwards:
+        let clock = clock.with_max_sample_age(Duration::from_millis(0));
+        let before = clock.now();
+        clock.observe(SystemTime::now(), start, later);
+        assert_eq!(clock.round_trip(), Some(Duration::from_millis(10)));
+        assert!(clock.now() >= before);
+    }
+
+    #[test]
+    fn hybrid_clock_is_monotonic() {
+        let clock = HybridLogicalClock::default();
+        let remote = SystemTime::now() + Duration::from_secs(3600);
+        clock.observe(remote);
+        let first = clock.now();
+        assert!(first > remote);
+        assert!(clock.clone().now() > first);
+        // Observing older times has no effect:
+        clock.observe(UNIX_EPOCH);
+        assert!(clock.now() < remote + Duration::from_secs(1));
+    }
+}
@@ -0,0 +1,200 @@
+use super::{Clock, Reference};
+use crate::nanos::Nanos;
+use std::convert::TryFrom;
+use std::fmt;
+use std::num::NonZeroU32;
+use std::ops::Add;
+
+const NANOS_PER_SECOND: u128 = 1_000_000_000;
+
+/// An instant on a hardware timer, counted in the timer's ticks.
+///
+/// Unlike [`Nanos`], which can't represent the length of a tick exactly for most timer
+/// frequencies (e.g. 30517.578125ns at 32.768 kHz), tick instants keep the timer's count, and
+/// only convert the time between them to nanoseconds rounded *up*. Together with a quota in
+/// ticks (see [`Quota::per_ticks`](crate::Quota::per_ticks)), which the rate limiter keeps in
+/// fractions of a nanosecond, this lets cells through on exactly the tick that they replenish
+/// at, without drifting over time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Ticks {
+    ticks: u64,
+    ticks_per_second: NonZeroU32,
+}
+
+impl Ticks {
+    /// Constructs an instant at the given tick count of a timer that counts `ticks_per_second`
+    /// ticks per second.
+    pub const fn new(ticks: u64, ticks_per_second: NonZeroU32) -> Ticks {
+        Ticks {
+            ticks,
+            ticks_per_second,
+        }
+    }
+
+    /// Returns the timer's tick count at this instant.
+    pub const fn ticks(&self) -> u64 {
+        self.ticks
+    }
+
+    /// Returns the number of ticks that the timer counts per second.
+    pub const fn ticks_per_second(&self) -> NonZeroU32 {
+        self.ticks_per_second
+    }
+
+    /// Converts a number of nanoseconds to ticks, rounding up or down.
+    fn ticks_from_nanos(&self, nanos: Nanos, round_up: bool) -> u64 {
+        let scaled = u128::from(nanos.as_u64()) * u128::from(self.ticks_per_second.get());
+        let ticks = match round_up {
+            true => scaled.div_ceil(NANOS_PER_SECOND),
+            false => scaled / NANOS_PER_SECOND,
+        };
+        u64::try_from(ticks).unwrap_or(u64::MAX)
+    }
+}
+
+#[cfg(feature = "defmt")]
+impl defmt::Format for Ticks {
+    fn format(&self, f: defmt::Formatter<'_>) {
+        defmt::write!(
+            f,
+            "{=u64} ticks at {=u32}Hz",
+            self.ticks,
+            self.ticks_per_second.get()
+        )
+    }
+}
+
+impl Add<Nanos> for Ticks {
+    type Output = Ticks;
+
+    /// Returns the first tick at or after the given time from this instant.
+    fn add(self, other: Nanos) -> Ticks {
+        Ticks {
+            ticks: self
+                .ticks
+                .saturating_add(self.ticks_from_nanos(other, true)),
+            ..self
+        }
+    }
+}
+
+impl Reference for Ticks {
+    /// Returns the time between two instants, rounded up to whole nanoseconds.
+    fn duration_since(&self, earlier: Self) -> Nanos {
+        let ticks = u128::from(self.ticks.saturating_sub(earlier.ticks));
+        let nanos = (ticks * NANOS_PER_SECOND).div_ceil(u128::from(self.ticks_per_second.get()));
+        Nanos::from(u64::try_from(nanos).unwrap_or(u64::MAX))
+    }
+
+    /// Returns the first tick at or after the given time before this instant.
+    fn saturating_sub(&self, duration: Nanos) -> Self {
+        Ticks {
+            ticks: self
+                .ticks
+                .saturating_sub(self.ticks_from_nanos(duration, false)),
+            ..*self
+        }
+    }
+}
+
+/// A clock over a monotonic hardware timer's tick count, e.g. a microcontroller's free-running
+/// 64-bit timer or cycle counter.
+///
+/// The clock reads the timer with the function that it was constructed with, and measures time
+/// in the timer's [`Ticks`]; the timer must not wrap around (with a 64-bit counter at 1 GHz,
+/// that takes over 584 years). The function should be safe to call from
+/// every context that the clock is used in, including interrupt handlers.
+///
+/// Besides serving as the clock of a [`RateLimiter`](crate::RateLimiter), this clock makes
+/// decisions of an [`InterruptSafeRateLimiter`](crate::state::InterruptSafeRateLimiter) on its
+/// ticks directly.
+///
+/// # Example
+/// ```rust
+/// # use nonzero_ext::*;
+/// use governor::{clock::{Clock, Reference, TickClock}, nanos::Nanos};
+///
+/// // e.g. reading a timer register; here, a timer that ticks at 32.768 kHz and
+/// // has counted to five seconds:
+/// fn read_timer() -> u64 {
+///     5 * 32_768
+/// }
+///
+/// let clock = TickClock::new(read_timer, nonzero!(32_768u32));
+/// let now = clock.now();
+/// assert_eq!(now.ticks(), 5 * 32_768);
+/// assert_eq!(
+///     now.duration_since(clock.at(0)),
+///     Nanos::from(5_000_000_000u64)
+/// );
+/// ```
+#[derive(Clone, Copy)]
+pub struct TickClock<F> {
+    read_ticks: F,
+    ticks_per_second: NonZeroU32,
+}
+
+impl<F: Fn() -> u64> TickClock<F> {
+    /// Constructs a clock that reads the timer with `read_ticks`, which counts up
+    /// `ticks_per_second` ticks per second.
+    pub const fn new(read_ticks: F, ticks_per_second: NonZeroU32) -> Self {
+        TickClock {
+            read_ticks,
+            ticks_per_second,
+        }
+    }
+
+    /// Returns the timer's current tick count.
+    pub fn ticks(&self) -> u64 {
+        (self.read_ticks)()
+    }
+
+    /// Returns the number of ticks that the timer counts per second.
+    pub fn ticks_per_second(&self) -> NonZeroU32 {
+        self.ticks_per_second
+    }
+
+    /// Returns the instant at the given tick count of the timer.
+    pub fn at(&self, ticks: u64) -> Ticks {
+        Ticks::new(ticks, self.ticks_per_second)
+    }
+}
+
+impl<F> fmt::Debug for TickClock<F> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TickClock")
+            .field("ticks_per_second", &self.ticks_per_second)
+            .finish()
+    }
+}
+
+impl<F: Fn() -> u64 + Clone> Clock for TickClock<F> {
+    type Instant = Ticks;
+
+    fn now(&self) -> Self::Instant {
+        self.at(self.ticks())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use nonzero_ext::nonzero;
+
+    #[test]
+    fn rounds_towards_the_later_tick() {
+        let hz = nonzero!(32_768u32);
+        let start = Ticks::new(1, hz);
+        // 30517.578125ns per tick:
+        assert_eq!(
+            Ticks::new(2, hz).duration_since(start),
+            Nanos::from(30_518u64)
+        );
+        assert_eq!(start.duration_since(Ticks::new(2, hz)), Nanos::from(0u64));
+        assert_eq!((start + Nanos::from(30_517u64)).ticks(), 2);
+        assert_eq!((start + Nanos::from(61_036u64)).ticks(), 4);
+        assert_eq!(start.saturating_sub(Nanos::from(30_517u64)).ticks(), 1);
+        assert_eq!(start.saturating_sub(Nanos::from(30_518u64)).ticks(), 0);
+        assert_eq!(start.saturating_sub(Nanos::from(u64::MAX)).ticks(), 0);
+    }
+}
@@ -1,6 +1,6 @@
 use super::{Clock, Reference};
 
-use std::prelude::v1::*;
+use crate::compat::prelude::*;
 
 use crate::nanos::Nanos;
 use std::ops::Add;
@@ -106,7 +106,7 @@ mod test {
     #[test]
     fn instant_impls_coverage() {
         let one_ns = Nanos::new(1);
-        let c = MonotonicClock::default();
+        let c = MonotonicClock;
         let now = c.now();
         assert_ne!(now + one_ns, now);
         assert_eq!(one_ns, Reference::duration_since(&(now + one_ns), now));
@@ -120,7 +120,7 @@ mod test {
     #[test]
     fn system_clock_impls_coverage() {
         let one_ns = Nanos::new(1);
-        let c = SystemClock::default();
+        let c = SystemClock;
         let now = c.now();
         assert_ne!(now + one_ns, now);
         // Thankfully, we're not comparing two system clock readings
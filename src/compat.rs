@@ -0,0 +1,27 @@
+//! The parts of the standard library that the crate uses beyond `core`, for builds without
+//! it: `alloc`'s types, and `hashbrown`'s hash maps.
+
+// Builds with different features use different parts:
+#![allow(unused_imports)]
+
+/// The types and macros that the standard library's prelude adds to `core`'s.
+pub(crate) mod prelude {
+    #[cfg(not(feature = "std"))]
+    pub(crate) use core::prelude::v1::*;
+    #[cfg(feature = "std")]
+    pub(crate) use std::prelude::v1::*;
+
+    pub(crate) use alloc::borrow::ToOwned;
+    pub(crate) use alloc::boxed::Box;
+    pub(crate) use alloc::string::{String, ToString};
+    pub(crate) use alloc::vec::Vec;
+    pub(crate) use alloc::{format, vec};
+}
+
+pub(crate) mod collections {
+    pub(crate) use alloc::collections::*;
+    #[cfg(not(feature = "std"))]
+    pub(crate) use hashbrown::{hash_map, HashMap, HashSet};
+    #[cfg(feature = "std")]
+    pub(crate) use std::collections::{hash_map, HashMap, HashSet};
+}
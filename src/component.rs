@@ -0,0 +1,134 @@
+//! Rate limiters shaped after the `governor:ratelimit` WebAssembly component interface.
+//!
+//! The `wit/governor.wit` file next to the crate manifest describes a component-model world,
+//! `ratelimiter`, that exports the `limiter` interface: a quota record, a decision variant and
+//! `direct` and `keyed` resources with `check` functions, plus a `decide` function that checks
+//! a key against the component's configured limiter. WASM plugin hosts can load a component
+//! that targets this world to make governor's rate-limiting decisions.
+//!
+//! The types in this module mirror that interface one to one, so that a guest crate only needs
+//! to forward the exports generated by `wit-bindgen` to them; the guest crate in
+//! `wasm/component` does that, and is built with:
+//!
+//! ```sh
+//! cargo component build --release --manifest-path wasm/component/Cargo.toml
+//! ```
+//!
+//! Since nothing here depends on the component model, the types can also be used (and are
+//! tested) on any target.
+
+use crate::compat::prelude::*;
+
+use crate::state::{AnyRateLimiter, DynNotUntil, NotKeyed};
+use crate::{
+    DefaultDirectRateLimiter, DefaultKeyedRateLimiter, NegativeMultiDecision, Quota, RateLimiter,
+};
+use std::convert::TryFrom;
+use std::num::NonZeroU32;
+
+/// The outcome of checking cells against a rate limiter: the `decision` variant of the
+/// `limiter` interface.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Decision {
+    /// The cells were let through.
+    Allowed,
+
+    /// The cells were denied; they may pass after waiting this many nanoseconds.
+    Limited(u64),
+
+    /// The rate limiter's burst size is too small to ever let this many cells through at once.
+    InsufficientCapacity,
+}
+
+impl From<Result<(), NegativeMultiDecision<DynNotUntil>>> for Decision {
+    fn from(result: Result<(), NegativeMultiDecision<DynNotUntil>>) -> Self {
+        match result {
+            Ok(()) => Decision::Allowed,
+            Err(NegativeMultiDecision::BatchNonConforming(_, negative)) => Decision::Limited(
+                u64::try_from(negative.wait_time().as_nanos()).unwrap_or(u64::MAX),
+            ),
+            Err(NegativeMultiDecision::InsufficientCapacity(_)) => Decision::InsufficientCapacity,
+        }
+    }
+}
+
+/// Checks `n` cells, counting zero cells as allowed, like the interface's `check` functions.
+fn decide<K>(limiter: &dyn AnyRateLimiter<K>, key: &K, n: u32) -> Decision {
+    match NonZeroU32::new(n) {
+        Some(n) => limiter.check_key_n(key, n).into(),
+        None => Decision::Allowed,
+    }
+}
+
+/// The `direct` resource of the `limiter` interface.
+#[derive(Debug)]
+pub struct Direct(DefaultDirectRateLimiter);
+
+impl Direct {
+    /// Constructs a direct rate limiter that replenishes one cell every `period_nanos`
+    /// nanoseconds, with bursts of up to `burst` cells. Returns `None` if either is zero.
+    pub fn new(period_nanos: u64, burst: u32) -> Option<Self> {
+        Quota::from_raw_parts(period_nanos, burst).map(|quota| Direct(RateLimiter::direct(quota)))
+    }
+
+    /// Checks whether `n` cells may pass.
+    pub fn check(&self, n: u32) -> Decision {
+        decide(&self.0, &NotKeyed::NonKey, n)
+    }
+}
+
+/// The `keyed` resource of the `limiter` interface, with byte string keys.
+#[derive(Debug)]
+pub struct Keyed(DefaultKeyedRateLimiter<Vec<u8>>);
+
+impl Keyed {
+    /// Constructs a keyed rate limiter that replenishes one cell every `period_nanos`
+    /// nanoseconds for each key, with bursts of up to `burst` cells. Returns `None` if either
+    /// is zero.
+    pub fn new(period_nanos: u64, burst: u32) -> Option<Self> {
+        Quota::from_raw_parts(period_nanos, burst).map(|quota| Keyed(RateLimiter::keyed(quota)))
+    }
+
+    /// Checks whether `n` cells may pass for `key`.
+    pub fn check(&self, key: &[u8], n: u32) -> Decision {
+        decide(&self.0, &key.to_vec(), n)
+    }
+
+    /// Forgets the keys whose state is indistinguishable from a fresh one, and returns the
+    /// number of keys that remain.
+    pub fn retain_recent(&self) -> u64 {
+        self.0.retain_recent();
+        self.0.len() as u64
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const SECOND: u64 = 1_000_000_000;
+
+    #[test]
+    fn direct_decisions() {
+        assert!(Direct::new(0, 1).is_none());
+        assert!(Direct::new(SECOND, 0).is_none());
+        let lim = Direct::new(SECOND, 2).unwrap();
+        assert_eq!(lim.check(0), Decision::Allowed);
+        assert_eq!(lim.check(2), Decision::Allowed);
+        match lim.check(1) {
+            Decision::Limited(wait) => assert!(wait > 0 && wait <= SECOND, "{}", wait),
+            other => panic!("unexpected decision {:?}", other),
+        }
+        assert_eq!(lim.check(3), Decision::InsufficientCapacity);
+    }
+
+    #[test]
+    fn keyed_decisions() {
+        assert!(Keyed::new(SECOND, 0).is_none());
+        let lim = Keyed::new(SECOND, 1).unwrap();
+        assert_eq!(lim.check(b"alice", 1), Decision::Allowed);
+        assert!(matches!(lim.check(b"alice", 1), Decision::Limited(_)));
+        assert_eq!(lim.check(b"bob", 1), Decision::Allowed);
+        assert_eq!(lim.retain_recent(), 2);
+    }
+}
@@ -0,0 +1,192 @@
+//! A C ABI for direct and keyed rate limiters.
+//!
+//! With the `ffi` feature, this module exports functions that create, check and free rate
+//! limiters, so that C and C++ components can share governor's rate limiting with Rust
+//! components in the same process. The rate limiters use the default clock and state stores;
+//! keys of keyed rate limiters are byte strings.
+//!
+//! To link against these functions, build governor as a C library, e.g. with
+//! `cargo rustc --release --features ffi --crate-type cdylib`. The matching header is
+//! `include/governor.h` in the source distribution; it is generated from this module with
+//! [cbindgen](https://github.com/eqrion/cbindgen) using the `cbindgen.toml` next to the crate
+//! manifest:
+//!
+//! ```sh
+//! cbindgen --config cbindgen.toml --output include/governor.h
+//! ```
+//!
+//! Rate limiters returned by the `*_new` functions are safe to check from several threads at
+//! once, and must be released with the corresponding `*_free` function.
+
+use crate::compat::prelude::*;
+
+use crate::state::{AnyRateLimiter, NotKeyed};
+use crate::{
+    DefaultDirectRateLimiter, DefaultKeyedRateLimiter, NegativeMultiDecision, Quota, RateLimiter,
+};
+use std::num::NonZeroU32;
+use std::slice;
+
+/// A direct rate limiter, as created by [`governor_direct_new`].
+pub struct GovernorDirect(DefaultDirectRateLimiter);
+
+/// A keyed rate limiter with byte string keys, as created by [`governor_keyed_new`].
+pub struct GovernorKeyed(DefaultKeyedRateLimiter<Vec<u8>>);
+
+/// The outcome of checking cells against a rate limiter through the C ABI.
+#[repr(C)]
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum GovernorDecision {
+    /// The cells were let through.
+    Allowed = 0,
+
+    /// The cells were rate-limited; the time to wait is written to `wait_nanos`.
+    Limited = 1,
+
+    /// The rate limiter's burst size is too small to ever let this many cells through at once.
+    InsufficientCapacity = 2,
+
+    /// A null pointer or a zero number of cells was passed.
+    InvalidArgument = -1,
+}
+
+fn decide<K>(
+    limiter: &dyn AnyRateLimiter<K>,
+    key: &K,
+    n: u32,
+    wait_nanos: *mut u64,
+) -> GovernorDecision {
+    let n = match NonZeroU32::new(n) {
+        Some(n) => n,
+        None => return GovernorDecision::InvalidArgument,
+    };
+    match limiter.check_key_n(key, n) {
+        Ok(()) => GovernorDecision::Allowed,
+        Err(NegativeMultiDecision::BatchNonConforming(_, negative)) => {
+            if !wait_nanos.is_null() {
+                let nanos = negative.wait_time().as_nanos();
+                // Safety: the caller guarantees that a non-null `wait_nanos` is writable.
+                unsafe { wait_nanos.write(nanos.min(u64::MAX as u128) as u64) };
+            }
+            GovernorDecision::Limited
+        }
+        Err(NegativeMultiDecision::InsufficientCapacity(_)) => {
+            GovernorDecision::InsufficientCapacity
+        }
+    }
+}
+
+/// Creates a direct rate limiter that replenishes one cell every `period_nanos` nanoseconds
+/// and allows bursts of up to `burst` cells.
+///
+/// Returns a null pointer if `period_nanos` or `burst` is zero.
+#[no_mangle]
+pub extern "C" fn governor_direct_new(period_nanos: u64, burst: u32) -> *mut GovernorDirect {
+    match Quota::from_raw_parts(period_nanos, burst) {
+        Some(quota) => Box::into_raw(Box::new(GovernorDirect(RateLimiter::direct(quota)))),
+        None => std::ptr::null_mut(),
+    }
+}
+
+/// Checks whether `n` cells may pass through a direct rate limiter.
+///
+/// If the cells are rate-limited and `wait_nanos` isn't null, the number of nanoseconds to wait
+/// before they can pass is written to it.
+///
+/// # Safety
+/// `limiter` must be null or a pointer returned by [`governor_direct_new`] that wasn't freed
+/// yet, and `wait_nanos` must be null or valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn governor_direct_check(
+    limiter: *const GovernorDirect,
+    n: u32,
+    wait_nanos: *mut u64,
+) -> GovernorDecision {
+    match limiter.as_ref() {
+        Some(GovernorDirect(limiter)) => decide(limiter, &NotKeyed::NonKey, n, wait_nanos),
+        None => GovernorDecision::InvalidArgument,
+    }
+}
+
+/// Frees a direct rate limiter. Passing a null pointer does nothing.
+///
+/// # Safety
+/// `limiter` must be null or a pointer returned by [`governor_direct_new`] that wasn't freed
+/// yet. It must not be used after this call.
+#[no_mangle]
+pub unsafe extern "C" fn governor_direct_free(limiter: *mut GovernorDirect) {
+    if !limiter.is_null() {
+        drop(Box::from_raw(limiter));
+    }
+}
+
+/// Creates a keyed rate limiter that replenishes one cell every `period_nanos` nanoseconds
+/// and allows bursts of up to `burst` cells, for each key.
+///
+/// Returns a null pointer if `period_nanos` or `burst` is zero.
+#[no_mangle]
+pub extern "C" fn governor_keyed_new(period_nanos: u64, burst: u32) -> *mut GovernorKeyed {
+    match Quota::from_raw_parts(period_nanos, burst) {
+        Some(quota) => Box::into_raw(Box::new(GovernorKeyed(RateLimiter::keyed(quota)))),
+        None => std::ptr::null_mut(),
+    }
+}
+
+/// Checks whether `n` cells may pass through a keyed rate limiter for the key made up of the
+/// `key_len` bytes at `key`.
+///
+/// If the cells are rate-limited and `wait_nanos` isn't null, the number of nanoseconds to wait
+/// before they can pass is written to it.
+///
+/// # Safety
+/// `limiter` must be null or a pointer returned by [`governor_keyed_new`] that wasn't freed
+/// yet, `key` must be valid for reads of `key_len` bytes (or may be null if `key_len` is 0),
+/// and `wait_nanos` must be null or valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn governor_keyed_check(
+    limiter: *const GovernorKeyed,
+    key: *const u8,
+    key_len: usize,
+    n: u32,
+    wait_nanos: *mut u64,
+) -> GovernorDecision {
+    let limiter = match limiter.as_ref() {
+        Some(GovernorKeyed(limiter)) => limiter,
+        None => return GovernorDecision::InvalidArgument,
+    };
+    let key = match (key.is_null(), key_len) {
+        (true, 0) => Vec::new(),
+        (true, _) => return GovernorDecision::InvalidArgument,
+        (false, _) => slice::from_raw_parts(key, key_len).to_vec(),
+    };
+    decide(limiter, &key, n, wait_nanos)
+}
+
+/// Removes the keys from a keyed rate limiter whose state is indistinguishable from a fresh
+/// one, and returns the number of keys that remain. Returns 0 for a null pointer.
+///
+/// # Safety
+/// `limiter` must be null or a pointer returned by [`governor_keyed_new`] that wasn't freed
+/// yet.
+#[no_mangle]
+pub unsafe extern "C" fn governor_keyed_retain_recent(limiter: *const GovernorKeyed) -> usize {
+    match limiter.as_ref() {
+        Some(GovernorKeyed(limiter)) => {
+            limiter.retain_recent();
+            limiter.len()
+        }
+        None => 0,
+    }
+}
+
+/// Frees a keyed rate limiter. Passing a null pointer does nothing.
+///
+/// # Safety
+/// `limiter` must be null or a pointer returned by [`governor_keyed_new`] that wasn't freed
+/// yet. It must not be used after this call.
+#[no_mangle]
+pub unsafe extern "C" fn governor_keyed_free(limiter: *mut GovernorKeyed) {
+    if !limiter.is_null() {
+        drop(Box::from_raw(limiter));
+    }
+}
@@ -1,6 +1,16 @@
-use crate::state::StateStore;
-use crate::{clock, middleware::StateSnapshot, NegativeMultiDecision, Quota};
+//! The Generic Cell Rate Algorithm that rate limiters make their decisions with.
+//!
+//! [`RateLimiter`](crate::RateLimiter) ties the algorithm to a state store, a clock and a
+//! middleware. Frameworks that bring their own storage and time sources can use [`Gcra`] to
+//! make decisions directly against any [`StateStore`] instead, with instants from any
+//! [`Reference`](crate::clock::Reference) type.
+
+use crate::compat::prelude::*;
+use crate::state::{Pressure, StateStore};
+use crate::{clock, middleware::StateSnapshot, NegativeMultiDecision, Quota, Rejection};
 use crate::{middleware::RateLimitingMiddleware, nanos::Nanos};
+use std::cell::Cell;
+use std::convert::{Infallible, TryFrom};
 use std::num::NonZeroU32;
 use std::time::Duration;
 use std::{cmp, fmt};
@@ -18,7 +28,7 @@ pub struct NotUntil<P: clock::Reference> {
     start: P,
 }
 
-impl<'a, P: clock::Reference> NotUntil<P> {
+impl<P: clock::Reference> NotUntil<P> {
     /// Create a `NotUntil` as a negative rate-limiting result.
     #[inline]
     pub(crate) fn new(state: StateSnapshot, start: P) -> Self {
@@ -30,7 +40,7 @@ impl<'a, P: clock::Reference> NotUntil<P> {
     /// that are made in the meantime).
     #[inline]
     pub fn earliest_possible(&self) -> P {
-        let tat: Nanos = self.state.tat;
+        let tat: Nanos = self.state.tat_nanos();
         self.start + tat
     }
 
@@ -39,11 +49,14 @@ impl<'a, P: clock::Reference> NotUntil<P> {
     /// decision can be conforming.
     ///
     /// If the time of the next expected positive result is in the past,
-    /// `wait_time_from` returns a zero `Duration`.
+    /// `wait_time_from` returns a zero `Duration`. Otherwise, the wait time is rounded up to
+    /// the rate limiter's [wait granularity](crate::RateLimiter::with_wait_granularity), if it
+    /// has one.
     #[inline]
     pub fn wait_time_from(&self, from: P) -> Duration {
         let earliest = self.earliest_possible();
-        earliest.duration_since(earliest.min(from)).into()
+        self.state
+            .round_wait(earliest.duration_since(earliest.min(from)))
     }
 
     /// Returns the rate limiting [`Quota`] used to reach the decision.
@@ -52,10 +65,44 @@ impl<'a, P: clock::Reference> NotUntil<P> {
         self.state.quota()
     }
 
+    /// Returns the [label](crate::RateLimiter::with_label) of the rate limiter that reached
+    /// the decision, if it has one.
+    #[inline]
+    pub fn label(&self) -> Option<&'static str> {
+        self.state.label()
+    }
+
+    /// Returns a machine-readable description of the outcome, with the time to wait counted
+    /// from the given time: e.g. for generating the error body of an API.
+    pub fn rejection(&self, from: P) -> Rejection {
+        Rejection::new(self, from)
+    }
+
+    /// Returns a [machine-readable description](Rejection) of the outcome as a JSON object,
+    /// with the time to wait counted from the given time.
+    pub fn to_json(&self, from: P) -> String {
+        self.rejection(from).to_json()
+    }
+
+    /// Returns an HTTP response for the outcome (see [`Rejection`]), with the time to wait
+    /// counted from the given time.
+    #[cfg(feature = "http")]
+    pub fn to_response(&self, from: P) -> ::http::Response<()> {
+        self.rejection(from).to_response()
+    }
+
+    /// Returns an HTTP response for the outcome like [`to_response`](#method.to_response),
+    /// with a random amount of jitter added to the advertised time to retry (see
+    /// [`Rejection::with_jitter`]).
+    #[cfg(all(feature = "http", feature = "jitter"))]
+    pub fn to_response_with_jitter(&self, from: P, jitter: crate::Jitter) -> ::http::Response<()> {
+        self.rejection(from).with_jitter(jitter).to_response()
+    }
+
     #[cfg(feature = "std")] // not used unless we use Instant-compatible clocks.
     #[inline]
     pub(crate) fn earliest_possible_with_offset(&self, jitter: Jitter) -> P {
-        let tat = jitter + self.state.tat;
+        let tat = jitter + self.state.tat_nanos();
         self.start + tat
     }
 
@@ -63,39 +110,315 @@ impl<'a, P: clock::Reference> NotUntil<P> {
     #[inline]
     pub(crate) fn wait_time_with_offset(&self, from: P, jitter: Jitter) -> Duration {
         let earliest = self.earliest_possible_with_offset(jitter);
-        earliest.duration_since(earliest.min(from)).into()
+        self.state
+            .round_wait(earliest.duration_since(earliest.min(from)))
     }
 }
 
-impl<'a, P: clock::Reference> fmt::Display for NotUntil<P> {
+impl<P: clock::Reference> fmt::Display for NotUntil<P> {
     fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
-        write!(f, "rate-limited until {:?}", self.start + self.state.tat)
+        let earliest = self.start + self.state.tat_nanos();
+        match self.label() {
+            Some(label) => write!(f, "rate-limited by {} until {:?}", label, earliest),
+            None => write!(f, "rate-limited until {:?}", earliest),
+        }
     }
 }
 
-#[derive(Debug, PartialEq)]
-pub(crate) struct Gcra {
+#[cfg(feature = "defmt")]
+impl<P: clock::Reference + defmt::Format> defmt::Format for NotUntil<P> {
+    fn format(&self, f: defmt::Formatter<'_>) {
+        match self.label() {
+            Some(label) => defmt::write!(
+                f,
+                "rate-limited by {=str} until {}",
+                label,
+                self.earliest_possible()
+            ),
+            None => defmt::write!(f, "rate-limited until {}", self.earliest_possible()),
+        }
+    }
+}
+
+/// The parameters of the Generic Cell Rate Algorithm for a [`Quota`], which make rate-limiting
+/// decisions against the states in a [`StateStore`].
+///
+/// Decisions take the time that they are made at and the time that the states count from
+/// (`start`, e.g. when the state store was created); a state store must always be used with the
+/// same start. The states that decisions store are theoretical arrival times since the start,
+/// in units of time that only the `Gcra` of the same quota understands (fractions of a
+/// nanosecond for quotas that need sub-nanosecond precision).
+///
+/// Unlike a [`RateLimiter`](crate::RateLimiter), a `Gcra` has no pressure or resume floor of
+/// its own.
+///
+/// # Example
+/// ```rust
+/// # use nonzero_ext::*;
+/// use governor::{
+///     gcra::Gcra,
+///     middleware::NoOpMiddleware,
+///     nanos::Nanos,
+///     state::{InMemoryState, NotKeyed},
+///     Quota,
+/// };
+///
+/// let gcra = Gcra::new(Quota::per_second(nonzero!(1u32)));
+/// let state = InMemoryState::default();
+/// let start = Nanos::from(0);
+/// let check = |now: u64| {
+///     gcra.test_and_update::<_, _, _, NoOpMiddleware<Nanos>>(
+///         start,
+///         &NotKeyed::NonKey,
+///         &state,
+///         Nanos::from(now),
+///     )
+/// };
+/// assert_eq!(check(0), Ok(()));
+/// let negative = check(500_000_000).unwrap_err();
+/// assert_eq!(negative.earliest_possible(), Nanos::from(1_000_000_000u64));
+/// assert_eq!(check(1_000_000_000), Ok(()));
+/// ```
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Gcra {
     /// The "weight" of a single packet in units of time.
     t: Nanos,
 
     /// The "burst capacity" of the bucket.
     tau: Nanos,
+
+    /// The number of units of time per nanosecond.
+    scale: u64,
+
+    /// The additional time to wait once the burst capacity is used up.
+    cooldown: Nanos,
+
+    /// The time that the overdraft's cells take to replenish, which decisions may run up as
+    /// debt beyond `tau`.
+    overdraft: Nanos,
+
+    /// The time that a single packet uses up under the rate limiter's current pressure: `t`
+    /// without pressure, more under pressure.
+    weight: Nanos,
+
+    /// The earliest theoretical arrival time that decisions assume, capping the burst capacity
+    /// after a resume.
+    floor: Nanos,
+
+    /// The name of the rate limiter that makes the decisions, if it has one.
+    label: Option<&'static str>,
+
+    /// The time that the rate limiter's [resets](crate::RateLimiter::reset) skipped, in
+    /// nanoseconds: Decisions count it as part of the time since the start, so that the states
+    /// stored before a reset lie in the past.
+    epoch: Nanos,
+
+    /// The granularity that reported wait times are rounded up to, in nanoseconds; 0 for none.
+    granularity: Nanos,
 }
 
 impl Gcra {
-    pub(crate) fn new(quota: Quota) -> Self {
-        let tau: Nanos = (quota.replenish_1_per * quota.max_burst.get()).into();
-        let t: Nanos = quota.replenish_1_per.into();
-        Gcra { t, tau }
+    /// Constructs the algorithm's parameters for a quota.
+    pub fn new(quota: Quota) -> Self {
+        let t: Nanos = quota.replenish_period.into();
+        let tau: Nanos = t * quota.max_burst.get() as u64;
+        let scale = quota.cells_per_period.get() as u64;
+        let cooldown = Nanos::from(quota.cooldown) * scale;
+        let overdraft = t * u64::from(quota.overdraft);
+        Gcra {
+            t,
+            tau,
+            scale,
+            cooldown,
+            overdraft,
+            weight: t,
+            floor: Nanos::from(0),
+            label: None,
+            epoch: Nanos::from(0),
+            granularity: Nanos::from(0),
+        }
+    }
+
+    /// Returns the parameters for decisions that name the rate limiter that made them.
+    #[inline]
+    pub(crate) fn with_label(self, label: Option<&'static str>) -> Gcra {
+        Gcra { label, ..self }
+    }
+
+    /// The name of the rate limiter that the parameters belong to, if it has one.
+    #[inline]
+    pub(crate) fn label(&self) -> Option<&'static str> {
+        self.label
+    }
+
+    /// Returns the parameters for decisions whose wait times are rounded up to the given
+    /// granularity.
+    #[inline]
+    pub(crate) fn with_granularity(self, granularity: Nanos) -> Gcra {
+        Gcra {
+            granularity,
+            ..self
+        }
+    }
+
+    /// The granularity that wait times are rounded up to, if there is one.
+    #[inline]
+    pub(crate) fn granularity(&self) -> Option<Duration> {
+        Some(self.granularity)
+            .filter(|granularity| granularity.as_u64() != 0)
+            .map(Duration::from)
+    }
+
+    /// Rounds a wait time up to the granularity.
+    #[inline]
+    pub(crate) fn round_wait(&self, wait: Nanos) -> Duration {
+        let granularity = self.granularity.as_u64();
+        if granularity == 0 {
+            return wait.into();
+        }
+        Nanos::from(
+            wait.as_u64()
+                .div_ceil(granularity)
+                .saturating_mul(granularity),
+        )
+        .into()
+    }
+
+    /// Returns the parameters for decisions in the given epoch.
+    #[inline]
+    pub(crate) fn with_epoch(self, epoch: Nanos) -> Gcra {
+        Gcra { epoch, ..self }
+    }
+
+    /// Converts a time since the start (in nanoseconds) to a state in the GCRA's units.
+    #[inline]
+    pub(crate) fn units_since_start(&self, nanos: Nanos) -> Nanos {
+        self.units_from_nanos(nanos + self.epoch)
+    }
+
+    /// Converts a state in the GCRA's units back to a time since the start (in nanoseconds,
+    /// rounded up).
+    #[inline]
+    pub(crate) fn nanos_since_start(&self, units: Nanos) -> Nanos {
+        self.nanos_from_units(units).saturating_sub(self.epoch)
+    }
+
+    /// Returns how far, in nanoseconds, the state that a decision stores can run ahead of the
+    /// decision's time, under the current pressure.
+    pub(crate) fn horizon(&self) -> Nanos {
+        self.nanos_from_units(self.ahead()) + Nanos::from(1)
+    }
+
+    /// Returns how far, in the GCRA's units, the state that a decision stores can run ahead of
+    /// the decision's time, under the current pressure.
+    pub(crate) fn ahead(&self) -> Nanos {
+        let ahead = self
+            .allowance()
+            .as_u64()
+            .saturating_add(self.cooldown.as_u64())
+            .saturating_add(self.weight.as_u64());
+        Nanos::from(ahead)
+    }
+
+    /// Returns the parameters for decisions that assume a theoretical arrival time of at least
+    /// `floor`.
+    #[inline]
+    pub(crate) fn with_floor(self, floor: Nanos) -> Gcra {
+        Gcra { floor, ..self }
+    }
+
+    /// Returns the theoretical arrival time that leaves a burst capacity of `credit` cells at
+    /// `t0`.
+    pub(crate) fn floor_for_credit(&self, t0: Nanos, credit: u32) -> Nanos {
+        (t0 + self.tau + self.weight).saturating_sub(self.weight * u64::from(credit))
+    }
+
+    /// Returns the parameters for admitting only the given fraction (out of
+    /// [`Pressure::FULL`]) of the cells that the quota allows, by making each cell use up
+    /// proportionally more of the burst capacity.
+    #[inline]
+    pub(crate) fn under_pressure(&self, admitted: u32) -> Gcra {
+        if admitted >= Pressure::FULL {
+            return *self;
+        }
+        let weight =
+            u128::from(self.t.as_u64()) * u128::from(Pressure::FULL) / u128::from(admitted);
+        Gcra {
+            weight: Nanos::from(u64::try_from(weight).unwrap_or(u64::MAX)),
+            ..*self
+        }
+    }
+
+    /// A way to reconstruct the Quota that a Gcra was created from.
+    ///
+    /// This is useful mainly for [`RateLimitingMiddleware`]
+    /// where custom code may want to construct information based on
+    /// the amount of burst balance remaining.
+    pub fn quota(&self) -> Quota {
+        // Safety assurance: As we do not allow creating a Gcra from 0
+        // parameters, this is, in fact, safe.
+        //
+        // The casts may look a little sketch, but again, they're
+        // constructed from a Quota's parameters exactly like that.
+        let max_burst =
+            unsafe { NonZeroU32::new_unchecked((self.tau.as_u64() / self.t.as_u64()) as u32) };
+        let cells_per_period = unsafe { NonZeroU32::new_unchecked(self.scale as u32) };
+        Quota {
+            max_burst,
+            replenish_period: self.t.into(),
+            cells_per_period,
+            cooldown: Duration::from_nanos(self.cooldown.as_u64() / self.scale),
+            overdraft: (self.overdraft / self.t) as u32,
+        }
+    }
+
+    /// The "weight" of a single packet, in the GCRA's units of time.
+    #[inline]
+    pub(crate) fn t(&self) -> Nanos {
+        self.t
+    }
+
+    /// The time that the burst capacity and the overdraft take to replenish, i.e. how far
+    /// ahead of a decision's time the theoretical arrival time may run.
+    #[inline]
+    fn allowance(&self) -> Nanos {
+        self.tau + self.overdraft
+    }
+
+    /// Converts a time value in the GCRA's units back to nanoseconds, rounding up.
+    #[inline]
+    pub(crate) fn nanos_from_units(&self, units: Nanos) -> Nanos {
+        Nanos::from(units.as_u64().div_ceil(self.scale))
+    }
+
+    /// Returns the theoretical arrival time following a positive decision that used up
+    /// capacity up to `next` at time `t0`, applying the cooldown if that decision exhausted
+    /// the burst capacity (and the overdraft).
+    #[inline]
+    fn with_cooldown(&self, next: Nanos, t0: Nanos) -> Nanos {
+        if next > t0 + self.allowance() {
+            next + self.cooldown
+        } else {
+            next
+        }
+    }
+
+    /// Converts a duration in nanoseconds to the GCRA's units of time.
+    #[inline]
+    pub(crate) fn units_from_nanos(&self, nanos: Nanos) -> Nanos {
+        nanos * self.scale
     }
 
     /// Computes and returns a new ratelimiter state if none exists yet.
+    #[inline]
     fn starting_state(&self, t0: Nanos) -> Nanos {
         t0 + self.t
     }
 
-    /// Tests a single cell against the rate limiter state and updates it at the given key.
-    pub(crate) fn test_and_update<
+    /// Tests a single cell arriving at `t0` against the state for `key`, and updates the state if
+    /// the cell is let through. The middleware `MW` constructs the outcomes.
+    #[inline]
+    pub fn test_and_update<
         K,
         P: clock::Reference,
         S: StateStore<Key = K>,
@@ -107,26 +430,42 @@ impl Gcra {
         state: &S,
         t0: P,
     ) -> Result<MW::PositiveOutcome, MW::NegativeOutcome> {
-        let t0 = t0.duration_since(start);
-        let tau = self.tau;
-        let t = self.t;
-        state.measure_and_replace(key, |tat| {
-            let tat = tat.unwrap_or_else(|| self.starting_state(t0));
+        let t0 = self.units_since_start(t0.duration_since(start));
+        let tau = self.allowance();
+        let t = self.weight;
+        // The closure only computes timestamps: it may run several times under contention, and
+        // constructing the middleware's outcomes only needs to happen once, after the decision.
+        let decision = state.measure_and_replace(key, |tat| {
+            let tat = cmp::max(tat.unwrap_or_else(|| self.starting_state(t0)), self.floor);
             let earliest_time = tat.saturating_sub(tau);
             if t0 < earliest_time {
-                Err(MW::disallow(key, (self, earliest_time), start))
+                Err(earliest_time)
             } else {
-                let next = cmp::max(tat, t0) + t;
-                Ok((
-                    MW::allow(key, StateSnapshot::new(self.t, self.tau, next)),
-                    next,
-                ))
+                let next = self.with_cooldown(cmp::max(tat, t0) + t, t0);
+                Ok((next, next))
             }
-        })
+        });
+        match decision {
+            Ok(next) => Ok(MW::allow(key, (self, next))),
+            Err(earliest_time) => Err(self.disallow::<K, P, MW>(key, earliest_time, start)),
+        }
     }
 
-    /// Tests whether all `n` cells could be accommodated and updates the rate limiter state, if so.
-    pub(crate) fn test_n_all_and_update<
+    /// Constructs the middleware's negative outcome, out of line of the conforming path.
+    #[cold]
+    #[inline(never)]
+    fn disallow<K, P: clock::Reference, MW: RateLimitingMiddleware<P>>(
+        &self,
+        key: &K,
+        earliest_time: Nanos,
+        start: P,
+    ) -> MW::NegativeOutcome {
+        MW::disallow(key, (self, earliest_time), start)
+    }
+
+    /// Tests whether all `n` cells arriving at `t0` could be accommodated by the state for `key`,
+    /// and updates the state if so.
+    pub fn test_n_all_and_update<
         K,
         P: clock::Reference,
         S: StateStore<Key = K>,
@@ -139,38 +478,287 @@ impl Gcra {
         state: &S,
         t0: P,
     ) -> Result<MW::PositiveOutcome, NegativeMultiDecision<MW::NegativeOutcome>> {
-        let t0 = t0.duration_since(start);
-        let tau = self.tau;
-        let t = self.t;
+        let t0 = self.units_since_start(t0.duration_since(start));
+        let tau = self.allowance();
+        let t = self.weight;
         let additional_weight = t * (n.get() - 1) as u64;
 
-        // check that we can allow enough cells through. Note that `additional_weight` is the
-        // value of the cells *in addition* to the first cell - so add that first cell back.
-        if additional_weight + t > tau {
+        // check that we can allow enough cells through (counting the overdraft), under the
+        // current pressure: Batches that don't fit into the shrunk burst capacity could never
+        // conform, not even from a fresh state.
+        if t * n.get() as u64 > tau {
             return Err(NegativeMultiDecision::InsufficientCapacity(
                 (tau.as_u64() / t.as_u64()) as u32,
             ));
         }
-        state.measure_and_replace(key, |tat| {
-            let tat = tat.unwrap_or_else(|| self.starting_state(t0));
+        let decision = state.measure_and_replace(key, |tat| {
+            let tat = cmp::max(tat.unwrap_or_else(|| self.starting_state(t0)), self.floor);
             let earliest_time = (tat + additional_weight).saturating_sub(tau);
             if t0 < earliest_time {
-                Err(NegativeMultiDecision::BatchNonConforming(
-                    n.get(),
-                    MW::disallow(key, (self, earliest_time), start),
-                ))
+                Err(earliest_time)
             } else {
-                let next = cmp::max(tat, t0) + t + additional_weight;
-                Ok((MW::allow(key, (self, next)), next))
+                let next = self.with_cooldown(cmp::max(tat, t0) + t + additional_weight, t0);
+                Ok((next, next))
             }
-        })
+        });
+        match decision {
+            Ok(next) => Ok(MW::allow(key, (self, next))),
+            Err(earliest_time) => Err(NegativeMultiDecision::BatchNonConforming(
+                n.get(),
+                self.disallow::<K, P, MW>(key, earliest_time, start),
+            )),
+        }
+    }
+
+    /// Schedules `n` cells arriving at `t0` for the earliest time that they conform, and updates
+    /// the state at `key` as if they had arrived then. Returns that time, or `None` (without
+    /// updating the state) if the cells exceed the burst capacity.
+    #[cfg(feature = "std")]
+    pub(crate) fn schedule_n<K, P: clock::Reference, S: StateStore<Key = K>>(
+        &self,
+        start: P,
+        key: &K,
+        n: NonZeroU32,
+        state: &S,
+        t0: P,
+    ) -> Option<P> {
+        let t0 = self.units_since_start(t0.duration_since(start));
+        let tau = self.allowance();
+        let t = self.weight;
+        let additional_weight = t * (n.get() - 1) as u64;
+        if t * n.get() as u64 > tau {
+            return None;
+        }
+        let release: Result<Nanos, Infallible> = state.measure_and_replace(key, |tat| {
+            let tat = cmp::max(tat.unwrap_or_else(|| self.starting_state(t0)), self.floor);
+            let release = cmp::max(t0, (tat + additional_weight).saturating_sub(tau));
+            let next = self.with_cooldown(cmp::max(tat, release) + t + additional_weight, release);
+            Ok((release, next))
+        });
+        match release {
+            Ok(release) => Some(start + self.nanos_since_start(release)),
+            Err(never) => match never {},
+        }
+    }
+
+    /// Returns `n` previously-allowed cells to the state at the given key, as if they had never
+    /// been let through.
+    pub fn refund<K, S: StateStore<Key = K>>(&self, key: &K, state: &S, n: u32) {
+        let refund = self.weight * n as u64;
+        state.adjust(key, |tat| tat.map(|tat| tat.saturating_sub(refund)));
+    }
+
+    /// Returns `n` cells to the state at the given key like [`refund`](Gcra::refund), and
+    /// returns whether the state lets a cell through at `t0` afterwards.
+    pub(crate) fn refund_at<K, P: clock::Reference, S: StateStore<Key = K>>(
+        &self,
+        start: P,
+        key: &K,
+        state: &S,
+        n: u32,
+        t0: P,
+    ) -> bool {
+        let t0 = self.units_since_start(t0.duration_since(start));
+        let refund = self.weight * n as u64;
+        let conforms = Cell::new(false);
+        state.adjust(key, |tat| {
+            let tat = tat?.saturating_sub(refund);
+            conforms.set(t0 >= cmp::max(tat, self.floor).saturating_sub(self.allowance()));
+            Some(tat)
+        });
+        conforms.get()
+    }
+
+    /// Returns the fraction of the burst capacity that the state at the given key uses up at
+    /// `t0`, between 0 (none, or no state) and 1 (all of it), without updating the state.
+    pub(crate) fn saturation<K, P: clock::Reference, S: StateStore<Key = K>>(
+        &self,
+        start: P,
+        key: &K,
+        state: &S,
+        t0: P,
+    ) -> f64 {
+        let t0 = self.units_since_start(t0.duration_since(start));
+        let tat = cmp::max(
+            state.peek(key).unwrap_or_else(|| self.starting_state(t0)),
+            self.floor,
+        );
+        self.used_fraction(tat, t0)
+    }
+
+    /// Returns how long after `t0` the state at the given key would let a single cell through
+    /// (zero if it would let one through at `t0`), without updating the state.
+    #[cfg(feature = "std")]
+    pub(crate) fn wait_time<K, P: clock::Reference, S: StateStore<Key = K>>(
+        &self,
+        start: P,
+        key: &K,
+        state: &S,
+        t0: P,
+    ) -> Duration {
+        let since_start = t0.duration_since(start);
+        let t0 = self.units_since_start(since_start);
+        let tat = cmp::max(
+            state.peek(key).unwrap_or_else(|| self.starting_state(t0)),
+            self.floor,
+        );
+        let earliest_time = tat.saturating_sub(self.allowance());
+        if t0 >= earliest_time {
+            return Duration::from_secs(0);
+        }
+        self.round_wait(
+            self.nanos_since_start(earliest_time)
+                .saturating_sub(since_start),
+        )
+    }
+
+    /// Sets the state at the given key to one that uses up the given fraction of the burst
+    /// capacity at `t0` (the inverse of [`saturation`](#method.saturation)), unless the state
+    /// already uses up more.
+    pub(crate) fn seed<K, P: clock::Reference, S: StateStore<Key = K>>(
+        &self,
+        start: P,
+        key: &K,
+        state: &S,
+        t0: P,
+        fraction: f64,
+    ) {
+        if fraction.is_nan() || fraction <= 0.0 {
+            return;
+        }
+        let t0 = self.units_since_start(t0.duration_since(start));
+        let used = self.tau.as_u64() as f64 * fraction.min(1.0);
+        // Rounding up (without `f64::ceil`, which needs std):
+        let used = used as u64 + u64::from((used as u64 as f64) < used);
+        let seeded = t0 + self.t + Nanos::from(used);
+        state.adjust(key, |tat| {
+            Some(tat.map_or(seeded, |tat| cmp::max(tat, seeded)))
+        });
+    }
+
+    /// Tests a sequence of cells arriving at the given times against the rate limiter state,
+    /// recording each cell's decision in the `conforming` bitmap and updating the state at the
+    /// given key once for the entire sequence.
+    ///
+    /// Returns the number of conforming cells, or `None` (without updating the state) if the
+    /// bitmap can not hold `arrivals.len()` bits.
+    pub(crate) fn test_arrivals_and_update<K, P: clock::Reference, S: StateStore<Key = K>>(
+        &self,
+        start: P,
+        key: &K,
+        state: &S,
+        arrivals: &[P],
+        conforming: &mut [u64],
+    ) -> Option<usize> {
+        if conforming.len().saturating_mul(64) < arrivals.len() {
+            return None;
+        }
+        let tau = self.allowance();
+        let t = self.weight;
+        // The closure may run several times under contention, so it writes the decisions
+        // through cells and starts over from an empty bitmap each time.
+        let bitmap = Cell::from_mut(conforming).as_slice_of_cells();
+        let decision: Result<usize, Infallible> = state.measure_and_replace(key, |tat| {
+            for word in bitmap {
+                word.set(0);
+            }
+            let mut tat = tat;
+            let mut allowed = 0;
+            for (i, arrival) in arrivals.iter().enumerate() {
+                let t0 = self.units_since_start(arrival.duration_since(start));
+                let current = cmp::max(tat.unwrap_or_else(|| self.starting_state(t0)), self.floor);
+                if t0 >= current.saturating_sub(tau) {
+                    tat = Some(self.with_cooldown(cmp::max(current, t0) + t, t0));
+                    allowed += 1;
+                    let word = &bitmap[i / 64];
+                    word.set(word.get() | 1 << (i % 64));
+                }
+            }
+            // If no cell was conforming on a fresh state, it stays fresh:
+            Ok((allowed, tat.unwrap_or_else(|| Nanos::from(0u64))))
+        });
+        match decision {
+            Ok(allowed) => Some(allowed),
+            Err(never) => match never {},
+        }
+    }
+}
+
+/// # Inspecting states
+///
+/// These translate a theoretical arrival time as stored in a state store (e.g. by
+/// [`InMemoryState::load`](crate::state::InMemoryState::load)) into the values that decisions at
+/// a given time would see, without making a decision. `tat` is `None` for a fresh state, and
+/// times are in nanoseconds since the start that the state counts from.
+impl Gcra {
+    /// Returns the number of cells that could be let through at `now`.
+    ///
+    /// ```rust
+    /// # use nonzero_ext::*;
+    /// use governor::{gcra::Gcra, middleware::NoOpMiddleware, nanos::Nanos, state::*, Quota};
+    ///
+    /// let gcra = Gcra::new(Quota::per_second(nonzero!(4u32)));
+    /// let state = InMemoryState::default();
+    /// let now = Nanos::from(0);
+    /// gcra.test_and_update::<_, _, _, NoOpMiddleware<Nanos>>(now, &NotKeyed::NonKey, &state, now)
+    ///     .unwrap();
+    /// let tat = state.load();
+    /// assert_eq!(gcra.remaining_burst_capacity(tat, now), 3);
+    /// assert_eq!(gcra.fill_ratio(tat, now), 0.25);
+    /// assert_eq!(gcra.earliest_conforming(tat, now), now);
+    /// // A second later, the burst capacity is back:
+    /// let later = Nanos::from(1_000_000_000);
+    /// assert_eq!(gcra.remaining_burst_capacity(tat, later), 4);
+    /// ```
+    pub fn remaining_burst_capacity(&self, tat: Option<Nanos>, now: Nanos) -> u32 {
+        let t0 = self.units_since_start(now);
+        let used = self.used(tat, t0);
+        (self.tau.saturating_sub(used) / self.t) as u32
+    }
+
+    /// Returns the fraction of the burst capacity that is used up at `now`, between 0 (none)
+    /// and 1 (all of it).
+    pub fn fill_ratio(&self, tat: Option<Nanos>, now: Nanos) -> f64 {
+        let t0 = self.units_since_start(now);
+        match tat {
+            Some(tat) => self.used_fraction(tat, t0),
+            None => 0.0,
+        }
+    }
+
+    /// Returns the earliest time, at or after `now`, at which a single cell would be let
+    /// through.
+    pub fn earliest_conforming(&self, tat: Option<Nanos>, now: Nanos) -> Nanos {
+        let earliest = tat.map_or(Nanos::from(0), |tat| {
+            self.nanos_since_start(tat.saturating_sub(self.allowance()))
+        });
+        cmp::max(earliest, now)
+    }
+
+    /// Returns the number of cells of the [overdraft](crate::Quota::with_overdraft) that are
+    /// still owed at `now`, counting cells that are partly paid back.
+    pub fn debt(&self, tat: Option<Nanos>, now: Nanos) -> u32 {
+        let t0 = self.units_since_start(now);
+        let owed = self.used(tat, t0).saturating_sub(self.tau);
+        owed.as_u64().div_ceil(self.t.as_u64()) as u32
+    }
+
+    /// Returns the part of the burst capacity that `tat` uses up at `t0`, in units of time.
+    fn used(&self, tat: Option<Nanos>, t0: Nanos) -> Nanos {
+        // A fresh state starts out one cell ahead of `t0`, so that cell doesn't count:
+        tat.map_or(Nanos::from(0), |tat| tat.saturating_sub(t0 + self.t))
+    }
+
+    fn used_fraction(&self, tat: Nanos, t0: Nanos) -> f64 {
+        let used = self.used(Some(tat), t0).as_u64() as f64 / self.tau.as_u64() as f64;
+        used.min(1.0)
     }
 }
 
 impl From<(&Gcra, Nanos)> for StateSnapshot {
     #[inline]
     fn from(pair: (&Gcra, Nanos)) -> Self {
-        StateSnapshot::new(pair.0.t, pair.0.tau, pair.1)
+        StateSnapshot::new(*pair.0, pair.1)
     }
 }
 
@@ -192,7 +780,7 @@ mod test {
         let g2 = Gcra::new(Quota::per_second(nonzero!(2u32)));
         assert_eq!(g, g);
         assert_ne!(g, g2);
-        assert!(format!("{:?}", g).len() > 0);
+        assert!(!format!("{:?}", g).is_empty());
     }
 
     /// Exercise derives and convenience impls on NotUntil to make coverage happy
@@ -211,11 +799,94 @@ mod test {
             .check()
             .map_err(|nu| {
                 assert_eq!(nu, nu);
-                assert!(format!("{:?}", nu).len() > 0);
+                assert!(!format!("{:?}", nu).is_empty());
                 assert_eq!(format!("{}", nu), "rate-limited until Nanos(1s)");
                 assert_eq!(nu.quota(), quota);
+                assert_eq!(nu.label(), None);
             })
             .is_err());
+
+        let lb = RateLimiter::direct_with_clock(quota, &clock).with_label("uploads");
+        assert!(lb.check().is_ok());
+        let nu = lb.check().unwrap_err();
+        assert_eq!(nu.label(), Some("uploads"));
+        assert_eq!(format!("{}", nu), "rate-limited by uploads until Nanos(1s)");
+    }
+
+    #[test]
+    fn inspects_exhausted_states() {
+        use crate::middleware::NoOpMiddleware;
+        use crate::state::{InMemoryState, NotKeyed};
+        use nonzero_ext::nonzero;
+
+        // 3 cells per 10ns, which the state keeps in thirds of a nanosecond:
+        let quota = Quota::new(nonzero!(3u32), Duration::from_nanos(10)).unwrap();
+        let gcra = Gcra::new(quota);
+        let state = InMemoryState::default();
+        let start = Nanos::from(0);
+        let now = Nanos::from(100);
+        gcra.test_n_all_and_update::<_, _, _, NoOpMiddleware<Nanos>>(
+            start,
+            &NotKeyed::NonKey,
+            nonzero!(3u32),
+            &state,
+            now,
+        )
+        .unwrap();
+        let tat = state.load();
+        assert_eq!(gcra.remaining_burst_capacity(tat, now), 0);
+        assert_eq!(gcra.fill_ratio(tat, now), 1.0);
+        // The next cell replenishes after 3 1/3 ns:
+        assert_eq!(gcra.earliest_conforming(tat, now), Nanos::from(104));
+        assert_eq!(gcra.remaining_burst_capacity(tat, Nanos::from(104)), 1);
+        assert_eq!(gcra.remaining_burst_capacity(tat, Nanos::from(110)), 3);
+        assert_eq!(gcra.fill_ratio(None, now), 0.0);
+        assert_eq!(gcra.earliest_conforming(None, now), now);
+    }
+
+    #[test]
+    fn runs_up_and_pays_back_debt() {
+        use crate::middleware::NoOpMiddleware;
+        use crate::state::{InMemoryState, NotKeyed};
+        use nonzero_ext::nonzero;
+
+        // One cell per 10ns, a burst of 2 and an overdraft of 3:
+        let quota = Quota::new(nonzero!(2u32), Duration::from_nanos(20))
+            .unwrap()
+            .with_overdraft(3);
+        let gcra = Gcra::new(quota);
+        assert_eq!(gcra.quota(), quota);
+        let state = InMemoryState::default();
+        let start = Nanos::from(0);
+        let check_n = |n: NonZeroU32, now: u64| {
+            gcra.test_n_all_and_update::<_, _, _, NoOpMiddleware<Nanos>>(
+                start,
+                &NotKeyed::NonKey,
+                n,
+                &state,
+                Nanos::from(now),
+            )
+        };
+        assert_eq!(
+            check_n(nonzero!(6u32), 0),
+            Err(NegativeMultiDecision::InsufficientCapacity(5))
+        );
+        assert_eq!(check_n(nonzero!(4u32), 0), Ok(()));
+        let tat = state.load();
+        assert_eq!(gcra.remaining_burst_capacity(tat, Nanos::from(0)), 0);
+        assert_eq!(gcra.debt(tat, Nanos::from(0)), 2);
+        assert_eq!(gcra.debt(tat, Nanos::from(15)), 1);
+        assert_eq!(check_n(nonzero!(1u32), 0), Ok(()));
+        assert!(check_n(nonzero!(1u32), 9).is_err());
+        // The debt is paid back before the burst capacity refills:
+        let tat = state.load();
+        assert_eq!(
+            gcra.earliest_conforming(tat, Nanos::from(0)),
+            Nanos::from(10)
+        );
+        assert_eq!(gcra.debt(tat, Nanos::from(30)), 0);
+        assert_eq!(gcra.remaining_burst_capacity(tat, Nanos::from(30)), 0);
+        assert_eq!(gcra.remaining_burst_capacity(tat, Nanos::from(40)), 1);
     }
 
     #[derive(Debug)]
@@ -245,8 +916,28 @@ mod test {
         proptest!(ProptestConfig::default(), |(per_second: Count, burst: Count)| {
             let quota = Quota::per_second(per_second.0).allow_burst(burst.0);
             let gcra = Gcra::new(quota);
-            let back = Quota::from_gcra_parameters(gcra.t, gcra.tau);
-            assert_eq!(quota, back);
+            assert_eq!(quota, gcra.quota());
+        })
+    }
+
+    #[test]
+    fn roundtrips_cooldown() {
+        proptest!(ProptestConfig::default(), |(per_second: Count, burst: Count, cooldown_ns: Count)| {
+            let quota = Quota::per_second(per_second.0)
+                .allow_burst(burst.0)
+                .with_cooldown(Duration::from_nanos(cooldown_ns.0.get() as u64));
+            assert_eq!(quota, Gcra::new(quota).quota());
+        })
+    }
+
+    #[test]
+    fn roundtrips_fractional_quota() {
+        proptest!(ProptestConfig::default(), |(period_ns: Count, cells: Count, burst: Count)| {
+            let quota = Quota::new(cells.0, Duration::from_nanos(period_ns.0.get() as u64))
+                .unwrap()
+                .allow_burst(burst.0);
+            let gcra = Gcra::new(quota);
+            assert_eq!(quota, gcra.quota());
         })
     }
 }
@@ -0,0 +1,68 @@
+//! Hashing and mixing functions that are available without `std`, and give the same results in
+//! every process and on every platform (unlike the standard library's randomly-seeded hashers).
+
+use std::hash::{Hash, Hasher};
+
+/// The increment of the SplitMix64 sequence: the golden ratio in 64 bits.
+const GOLDEN_GAMMA: u64 = 0x9e37_79b9_7f4a_7c15;
+
+/// The FNV-1a hash.
+pub(crate) struct Fnv1a(u64);
+
+impl Default for Fnv1a {
+    fn default() -> Self {
+        Fnv1a(0xcbf2_9ce4_8422_2325)
+    }
+}
+
+impl Hasher for Fnv1a {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 = (self.0 ^ u64::from(*byte)).wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+}
+
+/// Returns the FNV-1a hash of a key.
+pub(crate) fn fnv1a<K: Hash + ?Sized>(key: &K) -> u64 {
+    let mut hasher = Fnv1a::default();
+    key.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// The SplitMix64 step, which spreads hashes and sequence numbers over all 64 bits: the
+/// output that follows the seed `z`.
+pub(crate) fn mix(mut z: u64) -> u64 {
+    z = z.wrapping_add(GOLDEN_GAMMA);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+/// A small, seedable pseudo-random number generator, so that random sequences can be
+/// reproduced from their seed.
+#[cfg(feature = "testing")]
+#[derive(Debug, Clone)]
+pub(crate) struct SplitMix64(u64);
+
+#[cfg(feature = "testing")]
+impl SplitMix64 {
+    pub(crate) fn new(seed: u64) -> Self {
+        SplitMix64(seed)
+    }
+
+    pub(crate) fn next_u64(&mut self) -> u64 {
+        let next = mix(self.0);
+        self.0 = self.0.wrapping_add(GOLDEN_GAMMA);
+        next
+    }
+
+    /// Returns a number in [0, 1).
+    pub(crate) fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
@@ -1,4 +1,7 @@
-use std::prelude::v1::*;
+// Jitter is only applied by the std-only async helpers:
+#![cfg_attr(not(feature = "std"), allow(dead_code))]
+
+use crate::compat::prelude::*;
 
 use crate::nanos::Nanos;
 #[cfg(feature = "jitter")]
@@ -58,7 +61,6 @@ use std::time::Instant;
 /// # #[cfg(any(not(feature = "jitter"), not(feature = "std")))] fn main() {}
 /// ```
 #[derive(Debug, PartialEq, Default, Clone, Copy)]
-#[cfg_attr(feature = "docs", doc(cfg(jitter)))]
 pub struct Jitter {
     min: Nanos,
     max: Nanos,
@@ -198,7 +200,7 @@ mod test {
         let low = Duration::from_secs(0);
         let high = Duration::from_secs(20);
         let sampler = UniformJitter::new_inclusive(Nanos::from(low), Nanos::from(high));
-        assert!(format!("{:?}", sampler).len() > 0);
-        assert!(format!("{:?}", sampler.clone()).len() > 0);
+        assert!(!format!("{:?}", sampler).is_empty());
+        assert!(!format!("{:?}", sampler.clone()).is_empty());
     }
 }
@@ -34,35 +34,102 @@
 // Unfortunately necessary, otherwise features aren't supported in doctests:
 #![allow(clippy::needless_doctest_main)]
 
-extern crate no_std_compat as std;
+// Without the standard library, `std::` paths lead to `core`, and the crate gets the rest from
+// `alloc` (see the `compat` module):
+extern crate alloc;
+#[cfg(not(feature = "std"))]
+extern crate core as std;
 
 pub mod r#_guide;
 pub mod clock;
+mod compat;
+#[cfg(feature = "component")]
+pub mod component;
 mod errors;
-mod gcra;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+pub mod gcra;
+mod hash;
 mod jitter;
 pub mod middleware;
 pub mod nanos;
-mod quota;
+pub mod quota;
+mod rejection;
 pub mod state;
+mod sync;
+#[cfg(feature = "testing")]
+pub mod testing;
 
 pub use errors::*;
 pub use gcra::NotUntil;
-#[cfg(feature = "jitter")]
 pub use jitter::Jitter;
-#[cfg(all(not(feature = "std"), feature = "jitter"))]
-pub(crate) use jitter::Jitter;
-pub use quota::Quota;
+pub use quota::{Bandwidth, ParseQuotaError, Quota, QuotaAdvisor};
+pub use rejection::Rejection;
+pub use state::multi::{all_of, MultiLimiter};
 #[doc(inline)]
 pub use state::RateLimiter;
+pub use state::{DefaultDirectRateLimiter, DefaultKeyedRateLimiter, KeyedRateLimiter};
 
 #[cfg(feature = "std")]
 pub use state::direct::RatelimitedSink;
 #[cfg(feature = "std")]
 pub use state::direct::RatelimitedStream;
+#[cfg(feature = "std")]
+pub use state::keyed::AsyncKeyedLimiter;
+
+/// Integrations of rate limiters with other libraries and ways of waiting for them.
+///
+/// This gathers the adapters that are spread over the [`state`] module by what they integrate
+/// with; the types are the same as those in [`state::direct`] and [`state::keyed`].
+pub mod integration {
+    /// Rate-limiting `futures` streams and sinks, and keyed rate limiters for spawned futures.
+    #[cfg(feature = "std")]
+    pub mod futures {
+        pub use crate::state::direct::{
+            Enforcement, Overflow, RatelimitedSink, RatelimitedStream, SinkRateLimitExt,
+            StreamRateLimitExt,
+        };
+        pub use crate::state::keyed::AsyncKeyedLimiter;
+    }
+
+    /// Throttling `futures-io` readers and writers, and tokio's (with the `tokio` feature).
+    #[cfg(feature = "std")]
+    pub mod io {
+        pub use crate::state::direct::{ThrottledReader, ThrottledWriter};
+    }
 
-/// The collection of asynchronous traits exported from this crate.
+    /// Pacing threaded pipelines that communicate over channels.
+    #[cfg(feature = "std")]
+    pub mod channels {
+        pub use crate::state::direct::{BlockingReceiver, BlockingSender};
+    }
+
+    /// Pacing `rayon` parallel iterators (requires the `rayon` feature).
+    #[cfg(all(feature = "std", feature = "rayon"))]
+    pub mod rayon {
+        pub use crate::state::direct::{ParallelIteratorRateLimitExt, Throttled};
+    }
+}
+
+/// The types and traits that most users of this crate need.
+///
+/// ```rust
+/// use governor::prelude::*;
+/// # use nonzero_ext::nonzero;
+///
+/// # #[cfg(feature = "std")] fn main() {
+/// let lim: DefaultDirectRateLimiter = RateLimiter::direct(Quota::per_second(nonzero!(5u32)));
+/// assert_eq!(Ok(()), lim.check());
+/// # } #[cfg(not(feature = "std"))] fn main() {}
+/// ```
 pub mod prelude {
+    pub use crate::{
+        DefaultDirectRateLimiter, DefaultKeyedRateLimiter, KeyedRateLimiter, NotUntil, Quota,
+        RateLimiter,
+    };
+
+    #[cfg(all(feature = "std", feature = "rayon"))]
+    pub use crate::state::direct::ParallelIteratorRateLimitExt;
     #[cfg(feature = "std")]
     pub use crate::state::direct::SinkRateLimitExt;
     #[cfg(feature = "std")]
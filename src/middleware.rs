@@ -65,17 +65,15 @@
 //! You can define your own middleware by `impl`ing [`RateLimitingMiddleware`].
 use core::fmt;
 use std::marker::PhantomData;
+use std::time::Duration;
 
-use crate::{clock, nanos::Nanos, NotUntil, Quota};
+use crate::{clock, gcra::Gcra, nanos::Nanos, NotUntil, Quota};
 
 /// Information about the rate-limiting state used to reach a decision.
 #[derive(Clone, PartialEq, Debug)]
 pub struct StateSnapshot {
-    /// The "weight" of a single packet in units of time.
-    t: Nanos,
-
-    /// The "burst capacity" of the bucket.
-    tau: Nanos,
+    /// The parameters of the rate limiter that reached the decision.
+    gcra: Gcra,
 
     /// The next time a cell is expected to arrive
     pub(crate) tat: Nanos,
@@ -83,13 +81,34 @@ pub struct StateSnapshot {
 
 impl StateSnapshot {
     #[inline]
-    pub(crate) fn new(t: Nanos, tau: Nanos, tat: Nanos) -> Self {
-        Self { t, tau, tat }
+    pub(crate) fn new(gcra: Gcra, tat: Nanos) -> Self {
+        Self { gcra, tat }
     }
 
     /// Returns the quota used to make the rate limiting decision.
     pub fn quota(&self) -> Quota {
-        Quota::from_gcra_parameters(self.t, self.tau)
+        self.gcra.quota()
+    }
+
+    /// Returns the [label](crate::RateLimiter::with_label) of the rate limiter that made the
+    /// decision, if it has one.
+    ///
+    /// Middleware that records metrics can use this to tell apart the decisions of the
+    /// different rate limiters in a service.
+    pub fn label(&self) -> Option<&'static str> {
+        self.gcra.label()
+    }
+
+    /// Rounds a wait time up to the rate limiter's wait granularity.
+    #[inline]
+    pub(crate) fn round_wait(&self, wait: Nanos) -> Duration {
+        self.gcra.round_wait(wait)
+    }
+
+    /// The next time a cell is expected to arrive, in nanoseconds (rounded up).
+    #[inline]
+    pub(crate) fn tat_nanos(&self) -> Nanos {
+        self.gcra.nanos_since_start(self.tat)
     }
 
     /// Returns the number of cells that can be let through in
@@ -105,7 +124,23 @@ impl StateSnapshot {
         // As one cell has already been used by the positive
         // decision, we're relying on the "round down" behavior of
         // unsigned integer division.
-        (self.quota().burst_size().get() + 1).saturating_sub((self.tat / self.t) as u32)
+        let tat = self
+            .tat
+            .saturating_sub(self.gcra.units_since_start(Nanos::from(0)));
+        (self.quota().burst_size().get() + 1).saturating_sub((tat / self.gcra.t()) as u32)
+    }
+}
+
+#[cfg(feature = "defmt")]
+impl defmt::Format for StateSnapshot {
+    fn format(&self, f: defmt::Formatter<'_>) {
+        defmt::write!(
+            f,
+            "StateSnapshot {{ quota: {}, tat: {}, remaining_burst_capacity: {=u32} }}",
+            self.quota(),
+            self.tat_nanos(),
+            self.remaining_burst_capacity()
+        )
     }
 }
 
@@ -278,7 +313,7 @@ mod test {
                     phantom: PhantomData::<Duration>,
                 }
             ),
-            "NoOpMiddleware { phantom: PhantomData }"
+            "NoOpMiddleware { phantom: PhantomData<core::time::Duration> }"
         );
     }
 }
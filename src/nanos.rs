@@ -2,16 +2,19 @@
 
 use crate::clock;
 
+use crate::compat::prelude::*;
 use std::convert::TryInto;
 use std::fmt;
 use std::ops::{Add, Div, Mul};
-use std::prelude::v1::*;
 use std::time::Duration;
 
 /// A number of nanoseconds from a reference point.
 ///
 /// Nanos can not represent durations >584 years, but hopefully that
-/// should not be a problem in real-world applications.
+/// should not be a problem in real-world applications. Adding and
+/// multiplying Nanos, and converting longer durations to Nanos,
+/// saturates at the largest representable value rather than
+/// overflowing (or panicking).
 #[derive(PartialEq, Eq, Default, Clone, Copy, PartialOrd, Ord)]
 pub struct Nanos(u64);
 
@@ -30,13 +33,10 @@ impl Nanos {
 }
 
 impl From<Duration> for Nanos {
+    /// Converts a duration to nanoseconds, saturating at the longest duration that `Nanos` can
+    /// represent (about 584 years).
     fn from(d: Duration) -> Self {
-        // This will panic:
-        Nanos(
-            d.as_nanos()
-                .try_into()
-                .expect("Duration is longer than 584 years"),
-        )
+        Nanos(d.as_nanos().try_into().unwrap_or(u64::MAX))
     }
 }
 
@@ -47,11 +47,31 @@ impl fmt::Debug for Nanos {
     }
 }
 
+#[cfg(feature = "defmt")]
+impl defmt::Format for Nanos {
+    fn format(&self, f: defmt::Formatter<'_>) {
+        defmt::write!(f, "{=u64}ns", self.0)
+    }
+}
+
+/// Picks the magnitude first, so that short and long times are equally likely.
+#[cfg(feature = "arbitrary")]
+impl<'a> arbitrary::Arbitrary<'a> for Nanos {
+    fn arbitrary(u: &mut arbitrary::Unstructured<'a>) -> arbitrary::Result<Self> {
+        let shift = u32::from(u8::arbitrary(u)?) % 64;
+        Ok(Nanos(u64::arbitrary(u)? >> shift))
+    }
+
+    fn size_hint(depth: usize) -> (usize, Option<usize>) {
+        arbitrary::size_hint::and(u8::size_hint(depth), u64::size_hint(depth))
+    }
+}
+
 impl Add<Nanos> for Nanos {
     type Output = Nanos;
 
     fn add(self, rhs: Nanos) -> Self::Output {
-        Nanos(self.0 + rhs.0)
+        Nanos(self.0.saturating_add(rhs.0))
     }
 }
 
@@ -59,7 +79,7 @@ impl Mul<u64> for Nanos {
     type Output = Nanos;
 
     fn mul(self, rhs: u64) -> Self::Output {
-        Nanos(self.0 * rhs)
+        Nanos(self.0.saturating_mul(rhs))
     }
 }
 
@@ -138,5 +158,6 @@ mod test {
         assert_eq!(n_half.saturating_sub(n), Nanos::new(0));
         assert_eq!(n.saturating_sub(n_half), n_half);
         assert_eq!(clock::Reference::saturating_sub(&n_half, n), Nanos::new(0));
+        assert_eq!(Nanos::from(Duration::MAX), Nanos::new(u64::MAX));
     }
 }
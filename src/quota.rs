@@ -1,10 +1,19 @@
-use std::prelude::v1::*;
+//! Quotas, the rate-limiting parameters of rate limiters, and ways to construct them.
+
+use crate::compat::prelude::*;
 
 use nonzero_ext::nonzero;
+use std::cmp;
 use std::num::NonZeroU32;
 use std::time::Duration;
 
-use crate::nanos::Nanos;
+mod advisor;
+mod bandwidth;
+mod parse;
+
+pub use advisor::QuotaAdvisor;
+pub use bandwidth::Bandwidth;
+pub use parse::ParseQuotaError;
 
 /// A rate-limiting quota.
 ///
@@ -28,6 +37,21 @@ use crate::nanos::Nanos;
 /// In other words, the burst size is the maximum number of cells that the rate limiter will ever
 /// allow through without replenishing them.
 ///
+/// The burst size is independent of the rate at which cells get replenished: The GCRA's
+/// "burst tolerance" is the time it takes to replenish the entire burst (see
+/// [`burst_size_replenished_in`](#method.burst_size_replenished_in)), so it can be as many
+/// replenish periods long as necessary. To sustain a rate of 10 cells per second, but allow
+/// bursts of up to 500 cells (which then take 50 seconds to replenish), use
+/// [`allow_burst`](#method.allow_burst):
+/// ```rust
+/// # use governor::Quota;
+/// # use nonzero_ext::nonzero;
+/// # use std::time::Duration;
+/// let q = Quota::per_second(nonzero!(10u32)).allow_burst(nonzero!(500u32));
+/// assert_eq!(q.replenish_interval(), Duration::from_millis(100));
+/// assert_eq!(q.burst_size_replenished_in(), Duration::from_secs(50));
+/// ```
+///
 /// # Examples
 ///
 /// Construct a quota that allows 50 cells per second (replenishing at a rate of one cell
@@ -41,8 +65,7 @@ use crate::nanos::Nanos;
 /// assert_eq!(q, Quota::per_second(nonzero!(50u32)).allow_burst(nonzero!(50u32)));
 /// assert_eq!(q.replenish_interval(), Duration::from_millis(20));
 /// assert_eq!(q.burst_size().get(), 50);
-/// // The Quota::new constructor is deprecated, but this constructs the equivalent quota:
-/// #[allow(deprecated)]
+/// // The general Quota::new constructor constructs the equivalent quota:
 /// assert_eq!(q, Quota::new(nonzero!(50u32), Duration::from_secs(1)).unwrap());
 /// ```
 ///
@@ -58,10 +81,130 @@ use crate::nanos::Nanos;
 /// // The entire maximum burst size will be restored if no cells are let through for 45 hours:
 /// assert_eq!(q.burst_size_replenished_in(), Duration::from_secs(60 * 60 * (90 / 2)));
 /// ```
+///
+/// # Precision
+/// Quotas keep their replenishment rate as a fraction ("`n` cells per period"), so rates which
+/// don't divide evenly into nanoseconds are represented exactly, as long as that fraction can be
+/// reduced to one with at most 64 cells per period. E.g., `Quota::per_second(nonzero!(3u32))`
+/// replenishes exactly 3 cells per second, and a quota of 3 cells per nanosecond is possible too:
+/// ```rust
+/// # use governor::Quota;
+/// # use nonzero_ext::nonzero;
+/// # use std::time::Duration;
+/// let q = Quota::per_second(nonzero!(3u32));
+/// assert_eq!(q.replenish_interval(), Duration::from_nanos(333_333_333));
+/// assert_eq!(q.burst_size_replenished_in(), Duration::from_secs(1));
+///
+/// let q = Quota::new(nonzero!(3u32), Duration::from_nanos(1)).unwrap();
+/// assert_eq!(q.burst_size_replenished_in(), Duration::from_nanos(1));
+/// ```
+///
+/// Rates that can not be reduced that far are rounded down to a whole number of nanoseconds
+/// per cell (or, for rates above one cell per nanosecond, to the nearest 64th of a nanosecond).
+///
+/// Rate limiters with a quota that needs sub-nanosecond precision keep their state in units of
+/// that fraction of a nanosecond, which reduces the time span they can track accordingly: from
+/// ~584 years down to ~9 years in the most precise case.
+///
+/// # Constant quotas
+/// All quota constructors and modifiers are `const fn`s, and the
+/// [`nonzero!`][nonzero_ext::nonzero] macro checks its argument at compile time, so
+/// quotas can be declared as constants (e.g., in a table of per-endpoint limits):
+/// ```rust
+/// # use governor::Quota;
+/// # use nonzero_ext::nonzero;
+/// # use std::time::Duration;
+/// const LIMITS: [(&str, Quota); 2] = [
+///     ("login", Quota::per_minute(nonzero!(5u32)).with_cooldown(Duration::from_secs(900))),
+///     ("search", Quota::per_second(nonzero!(20u32)).allow_burst(nonzero!(40u32))),
+/// ];
+/// const PER_FRAME: Option<Quota> = Quota::with_period(Duration::from_micros(16_667));
+/// # assert_eq!(LIMITS[1].1.burst_size().get(), 40);
+/// # assert!(PER_FRAME.is_some());
+/// ```
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
 pub struct Quota {
     pub(crate) max_burst: NonZeroU32,
-    pub(crate) replenish_1_per: Duration,
+
+    /// The time it takes to replenish `cells_per_period` cells.
+    pub(crate) replenish_period: Duration,
+
+    /// The number of cells replenished in `replenish_period`. This is always at most
+    /// [`MAX_CELLS_PER_PERIOD`] and coprime with the number of nanoseconds in the period, so
+    /// that equal rates compare equal.
+    pub(crate) cells_per_period: NonZeroU32,
+
+    /// The time to wait after the burst capacity is used up, before cells start replenishing.
+    pub(crate) cooldown: Duration,
+
+    /// The number of cells that may be let through beyond the burst capacity, as debt that
+    /// replenishment pays back before the burst capacity refills.
+    pub(crate) overdraft: u32,
+}
+
+/// The largest number of cells per replenish period that a quota keeps track of, i.e. the
+/// finest subdivision of a nanosecond that a quota's replenish interval can be expressed in.
+pub(crate) const MAX_CELLS_PER_PERIOD: u128 = 64;
+
+const fn gcd(mut a: u128, mut b: u128) -> u128 {
+    while b != 0 {
+        let r = a % b;
+        a = b;
+        b = r;
+    }
+    a
+}
+
+const fn nonzero_u32(n: u128) -> NonZeroU32 {
+    match NonZeroU32::new(n as u32) {
+        Some(n) => n,
+        // Reduced fractions of non-zero numbers are never zero; this keeps the constructors
+        // free of panics nonetheless:
+        None => nonzero!(1u32),
+    }
+}
+
+impl Quota {
+    /// Constructs a quota replenishing `cells` cells in `period_ns` nanoseconds, reducing the
+    /// fraction to its canonical (and representable) form.
+    ///
+    /// `period_ns` and `cells` must both be non-zero.
+    pub(crate) const fn from_rate(max_burst: NonZeroU32, period_ns: u128, cells: u128) -> Quota {
+        let divisor = gcd(period_ns, cells);
+        let mut period_ns = period_ns / divisor;
+        let mut cells = cells / divisor;
+        if cells > MAX_CELLS_PER_PERIOD {
+            if period_ns >= cells {
+                // Whole nanoseconds per cell:
+                period_ns /= cells;
+                cells = 1;
+            } else {
+                // More than one cell per nanosecond, round to the nearest representable rate:
+                period_ns = (period_ns * MAX_CELLS_PER_PERIOD + cells / 2) / cells;
+                if period_ns == 0 {
+                    period_ns = 1;
+                }
+                let divisor = gcd(period_ns, MAX_CELLS_PER_PERIOD);
+                period_ns /= divisor;
+                cells = MAX_CELLS_PER_PERIOD / divisor;
+            }
+        }
+        Quota {
+            max_burst,
+            replenish_period: Duration::from_nanos(period_ns as u64),
+            cells_per_period: nonzero_u32(cells),
+            cooldown: Duration::from_secs(0),
+            overdraft: 0,
+        }
+    }
+
+    /// Constructs a quota replenishing one cell every `period_nanos` nanoseconds, with bursts of
+    /// up to `burst` cells, as passed in by foreign callers. Returns `None` if either is zero.
+    #[cfg(any(feature = "ffi", feature = "component"))]
+    pub(crate) fn from_raw_parts(period_nanos: u64, burst: u32) -> Option<Quota> {
+        let burst = NonZeroU32::new(burst)?;
+        Quota::with_period(Duration::from_nanos(period_nanos)).map(|q| q.allow_burst(burst))
+    }
 }
 
 /// Constructors for Quotas
@@ -69,42 +212,75 @@ impl Quota {
     /// Construct a quota for a number of cells per second. The given number of cells is also
     /// assumed to be the maximum burst size.
     pub const fn per_second(max_burst: NonZeroU32) -> Quota {
-        let replenish_interval_ns = Duration::from_secs(1).as_nanos() / (max_burst.get() as u128);
-        Quota {
+        Quota::from_rate(
             max_burst,
-            replenish_1_per: Duration::from_nanos(replenish_interval_ns as u64),
-        }
+            Duration::from_secs(1).as_nanos(),
+            max_burst.get() as u128,
+        )
     }
 
     /// Construct a quota for a number of cells per 60-second period. The given number of cells is
     /// also assumed to be the maximum burst size.
     pub const fn per_minute(max_burst: NonZeroU32) -> Quota {
-        let replenish_interval_ns = Duration::from_secs(60).as_nanos() / (max_burst.get() as u128);
-        Quota {
+        Quota::from_rate(
             max_burst,
-            replenish_1_per: Duration::from_nanos(replenish_interval_ns as u64),
-        }
+            Duration::from_secs(60).as_nanos(),
+            max_burst.get() as u128,
+        )
     }
 
     /// Construct a quota for a number of cells per 60-minute (3600-second) period. The given number
     /// of cells is also assumed to be the maximum burst size.
     pub const fn per_hour(max_burst: NonZeroU32) -> Quota {
-        let replenish_interval_ns =
-            Duration::from_secs(60 * 60).as_nanos() / (max_burst.get() as u128);
-        Quota {
+        Quota::from_rate(
             max_burst,
-            replenish_1_per: Duration::from_nanos(replenish_interval_ns as u64),
-        }
+            Duration::from_secs(60 * 60).as_nanos(),
+            max_burst.get() as u128,
+        )
+    }
+
+    /// Construct a quota for a number of cells per 24-hour period. The given number of cells is
+    /// also assumed to be the maximum burst size.
+    pub const fn per_day(max_burst: NonZeroU32) -> Quota {
+        Quota::from_rate(
+            max_burst,
+            Duration::from_secs(24 * 60 * 60).as_nanos(),
+            max_burst.get() as u128,
+        )
+    }
+
+    /// Construct a quota for a number of cells per 7-day period. The given number of cells is
+    /// also assumed to be the maximum burst size.
+    pub const fn per_week(max_burst: NonZeroU32) -> Quota {
+        Quota::from_rate(
+            max_burst,
+            Duration::from_secs(7 * 24 * 60 * 60).as_nanos(),
+            max_burst.get() as u128,
+        )
+    }
+
+    /// Construct a quota for a number of cells per month. The given number of cells is also
+    /// assumed to be the maximum burst size.
+    ///
+    /// Since rate limiters don't follow the calendar, a month is taken to be the average length
+    /// of a month in the Gregorian calendar: 30.436875 days (2629746 seconds), so that 12 months
+    /// make up an average year.
+    pub const fn per_month(max_burst: NonZeroU32) -> Quota {
+        Quota::from_rate(
+            max_burst,
+            Duration::from_secs(2_629_746).as_nanos(),
+            max_burst.get() as u128,
+        )
     }
 
     /// Construct a quota that replenishes one cell in a given
     /// interval.
     ///
-    /// This constructor is meant to replace [`::new`](#method.new),
-    /// in cases where a longer refresh period than 1 cell/hour is
-    /// necessary.
+    /// This constructor is useful in cases where a longer refresh
+    /// period than 1 cell/month is necessary.
     ///
-    /// If the time interval is zero, returns `None`.
+    /// If the time interval is zero, or longer than the ~584 years that
+    /// rate limiters can keep track of, returns `None`.
     ///
     /// # Example
     /// ```rust
@@ -116,59 +292,231 @@ impl Quota {
     ///     .unwrap()
     ///     .allow_burst(nonzero!(10u32));
     /// ```
-    pub fn with_period(replenish_1_per: Duration) -> Option<Quota> {
-        if replenish_1_per.as_nanos() == 0 {
+    pub const fn with_period(replenish_1_per: Duration) -> Option<Quota> {
+        if !is_valid_period(replenish_1_per) {
             None
         } else {
-            Some(Quota {
-                max_burst: nonzero!(1u32),
-                replenish_1_per,
-            })
+            Some(Quota::from_rate(
+                nonzero!(1u32),
+                replenish_1_per.as_nanos(),
+                1,
+            ))
         }
     }
 
     /// Adjusts the maximum burst size for a quota to construct a rate limiter with a capacity
     /// for at most the given number of cells.
+    ///
+    /// This leaves the rate at which cells are replenished unchanged, so the burst size can be
+    /// both smaller and larger than the number of cells replenished in the quota's period.
     pub const fn allow_burst(self, max_burst: NonZeroU32) -> Quota {
         Quota { max_burst, ..self }
     }
 
-    /// Construct a quota for a given burst size, replenishing the entire burst size in that
-    /// given unit of time.
-    ///
-    /// Returns `None` if the duration is zero.
-    ///
-    /// This constructor allows greater control over the resulting
-    /// quota, but doesn't make as much intuitive sense as other
-    /// methods of constructing the same quotas. Unless your quotas
-    /// are given as "max burst size, and time it takes to replenish
-    /// that burst size", you are better served by the
-    /// [`Quota::per_second`](#method.per_second) (and similar)
-    /// constructors with the [`allow_burst`](#method.allow_burst)
-    /// modifier.
-    #[deprecated(
-        since = "0.2.0",
-        note = "This constructor is often confusing and non-intuitive. \
-    Use the `per_(interval)` / `with_period` and `max_burst` constructors instead."
-    )]
-    pub fn new(max_burst: NonZeroU32, replenish_all_per: Duration) -> Option<Quota> {
-        if replenish_all_per.as_nanos() == 0 {
+    /// Adjusts the quota to enforce a cooldown period whenever the burst capacity is used up:
+    /// Once the last available cell has been let through, no cells are replenished until the
+    /// cooldown has passed; after that, replenishment continues at the quota's regular rate.
+    ///
+    /// This emulates the behavior of APIs that lock clients out for a while after they
+    /// exhaust their burst (e.g. "5 login attempts, then wait 15 minutes"). Note that every
+    /// decision that uses up the remaining capacity triggers the cooldown, including those
+    /// using capacity that was replenished after an earlier cooldown.
+    ///
+    /// # Example
+    /// ```rust
+    /// # use nonzero_ext::nonzero;
+    /// # use governor::{clock::FakeRelativeClock, Quota, RateLimiter};
+    /// # use std::time::Duration;
+    /// // Allow 5 attempts, then lock out for 15 minutes before replenishing one per minute:
+    /// let quota = Quota::per_minute(nonzero!(1u32))
+    ///     .allow_burst(nonzero!(5u32))
+    ///     .with_cooldown(Duration::from_secs(15 * 60));
+    /// assert_eq!(quota.cooldown(), Duration::from_secs(15 * 60));
+    ///
+    /// let clock = FakeRelativeClock::default();
+    /// let lim = RateLimiter::direct_with_clock(quota, &clock);
+    /// assert!(lim.check_n(nonzero!(5u32)).is_ok());
+    ///
+    /// clock.advance(Duration::from_secs(15 * 60));
+    /// assert!(lim.check().is_err());
+    /// clock.advance(Duration::from_secs(60));
+    /// assert!(lim.check().is_ok());
+    /// ```
+    pub const fn with_cooldown(self, cooldown: Duration) -> Quota {
+        Quota { cooldown, ..self }
+    }
+
+    /// Adjusts the quota to let up to `cells` cells through beyond the burst capacity, as an
+    /// overdraft: Cells that would have been rate-limited conform instead, running up a debt
+    /// that replenishment pays back (at the quota's regular rate) before any burst capacity
+    /// becomes available again. Only once the debt reaches `cells` are cells rate-limited.
+    ///
+    /// This suits latency-sensitive paths that should rather smooth out a spike than reject
+    /// it: The long-term rate stays the same, but a spike is paid for with the capacity that
+    /// follows it. The cooldown, if any, applies once the overdraft is used up.
+    ///
+    /// # Example
+    /// ```rust
+    /// # use nonzero_ext::nonzero;
+    /// # use governor::{clock::FakeRelativeClock, Quota, RateLimiter};
+    /// # use std::time::Duration;
+    /// let quota = Quota::per_second(nonzero!(2u32)).with_overdraft(3);
+    /// assert_eq!(quota.overdraft(), 3);
+    ///
+    /// let clock = FakeRelativeClock::default();
+    /// let lim = RateLimiter::direct_with_clock(quota, &clock);
+    /// // The burst capacity, and then the overdraft:
+    /// assert!(lim.check_n(nonzero!(5u32)).is_ok());
+    /// assert!(lim.check().is_err());
+    ///
+    /// // Paying back the debt of 3 cells takes 1.5s, during which the burst capacity stays
+    /// // used up; after that, it refills:
+    /// clock.advance(Duration::from_millis(1500));
+    /// assert_eq!(lim.saturation(), 1.0);
+    /// clock.advance(Duration::from_millis(500));
+    /// assert_eq!(lim.saturation(), 0.5);
+    /// ```
+    pub const fn with_overdraft(self, cells: u32) -> Quota {
+        Quota {
+            overdraft: cells,
+            ..self
+        }
+    }
+
+    /// Construct a quota for a given number of cells, replenishing all of them in the given
+    /// period. The given number of cells is also assumed to be the maximum burst size.
+    ///
+    /// This is the general form of the [`Quota::per_second`](#method.per_second) (and
+    /// similar) constructors, for quotas that are given in periods that aren't covered by
+    /// those, e.g. "1000 cells per 90 days". Use the [`allow_burst`](#method.allow_burst)
+    /// modifier to adjust the burst size of the resulting quota.
+    ///
+    /// Returns `None` if the period is zero, or longer than the ~584 years that rate limiters
+    /// can keep track of.
+    ///
+    /// # Example
+    /// ```rust
+    /// # use nonzero_ext::nonzero;
+    /// # use governor::Quota;
+    /// # use std::time::Duration;
+    /// let quarterly = Quota::new(nonzero!(1000u32), Duration::from_secs(90 * 24 * 60 * 60))
+    ///     .unwrap();
+    /// assert_eq!(quarterly.burst_size().get(), 1000);
+    /// assert_eq!(quarterly.replenish_interval(), Duration::from_secs(7776));
+    ///
+    /// assert!(Quota::new(nonzero!(1u32), Duration::from_secs(0)).is_none());
+    /// assert!(Quota::new(nonzero!(1u32), Duration::MAX).is_none());
+    /// ```
+    pub const fn new(cells: NonZeroU32, period: Duration) -> Option<Quota> {
+        if !is_valid_period(period) {
             None
         } else {
-            Some(Quota {
-                max_burst,
-                replenish_1_per: replenish_all_per / max_burst.get(),
-            })
+            Some(Quota::from_rate(
+                cells,
+                period.as_nanos(),
+                cells.get() as u128,
+            ))
         }
     }
+
+    /// Construct a quota for a given number of cells, replenishing all of them in the given
+    /// number of ticks of a timer that counts `tick_hz` ticks per second (see
+    /// [`TickClock`](crate::clock::TickClock)). The given number of cells is also assumed to be
+    /// the maximum burst size.
+    ///
+    /// Tick lengths are rarely whole nanoseconds, but the rate is kept as a fraction of the
+    /// tick frequency: It is exact as long as it comes to a whole number of nanoseconds (or 64th
+    /// of a nanosecond, or coarser fraction) per cell, which covers e.g. all frequencies of
+    /// the form 2^a * 5^b, like 32.768 kHz. With a [`TickClock`](crate::clock::TickClock), cells
+    /// then replenish on exactly the tick that the quota says.
+    ///
+    /// # Example
+    /// ```rust
+    /// # use nonzero_ext::nonzero;
+    /// # use governor::{clock::{Clock, TickClock}, Quota, RateLimiter};
+    /// # use std::sync::atomic::{AtomicU64, Ordering};
+    /// static TIMER: AtomicU64 = AtomicU64::new(0);
+    /// let clock = TickClock::new(|| TIMER.load(Ordering::Relaxed), nonzero!(32_768u32));
+    ///
+    /// // One cell every 3 ticks, i.e. 91552.734375ns:
+    /// let quota = Quota::per_ticks(nonzero!(1u32), nonzero!(3u32), nonzero!(32_768u32));
+    /// let lim = RateLimiter::direct_with_clock(quota, &clock);
+    /// for tick in (0..3_000_000).step_by(3) {
+    ///     TIMER.store(tick, Ordering::Relaxed);
+    ///     assert!(lim.check().is_ok());
+    ///     assert!(lim.check().is_err());
+    /// }
+    /// ```
+    pub const fn per_ticks(cells: NonZeroU32, ticks: NonZeroU32, tick_hz: NonZeroU32) -> Quota {
+        // At most 2^32 seconds, which nanoseconds can represent:
+        Quota::from_rate(
+            cells,
+            ticks.get() as u128 * Duration::from_secs(1).as_nanos(),
+            cells.get() as u128 * tick_hz.get() as u128,
+        )
+    }
+}
+
+#[cfg(feature = "defmt")]
+impl defmt::Format for Quota {
+    fn format(&self, f: defmt::Formatter<'_>) {
+        defmt::write!(
+            f,
+            "Quota {{ max_burst: {=u32}, cells_per_period: {=u32}, replenish_period: {}, cooldown: {}, overdraft: {=u32} }}",
+            self.max_burst.get(),
+            self.cells_per_period.get(),
+            crate::nanos::Nanos::from(self.replenish_period),
+            crate::nanos::Nanos::from(self.cooldown),
+            self.overdraft
+        )
+    }
+}
+
+/// Generates any number of cells per any period that [`Nanos`](crate::nanos::Nanos) can represent, any burst size,
+/// and maybe a cooldown or an overdraft. Cell counts and periods span all their orders of
+/// magnitude.
+#[cfg(feature = "arbitrary")]
+impl<'a> arbitrary::Arbitrary<'a> for Quota {
+    fn arbitrary(u: &mut arbitrary::Unstructured<'a>) -> arbitrary::Result<Self> {
+        use crate::nanos::Nanos;
+
+        fn cells(u: &mut arbitrary::Unstructured<'_>) -> arbitrary::Result<u32> {
+            let shift = u32::from(u8::arbitrary(u)?) % 32;
+            Ok(u32::arbitrary(u)? >> shift)
+        }
+        fn duration(u: &mut arbitrary::Unstructured<'_>) -> arbitrary::Result<Duration> {
+            Ok(Duration::from_nanos(Nanos::arbitrary(u)?.as_u64()))
+        }
+
+        let period = cmp::max(duration(u)?, Duration::from_nanos(1));
+        let per_period = NonZeroU32::new(cells(u)?).unwrap_or(nonzero!(1u32));
+        let burst = NonZeroU32::new(cells(u)?).unwrap_or(nonzero!(1u32));
+        let quota = Quota::new(per_period, period)
+            .ok_or(arbitrary::Error::IncorrectFormat)?
+            .allow_burst(burst);
+        Ok(match u8::arbitrary(u)? {
+            0..=63 => quota.with_cooldown(duration(u)?),
+            64..=95 => quota.with_overdraft(cells(u)?),
+            _ => quota,
+        })
+    }
+}
+
+/// Returns whether a replenishment period is non-zero and can be represented by [`Nanos`].
+const fn is_valid_period(period: Duration) -> bool {
+    period.as_nanos() != 0 && period.as_nanos() <= u64::MAX as u128
 }
 
 /// Retrieving information about a quota
 impl Quota {
     /// The time it takes for a rate limiter with an exhausted burst budget to replenish
     /// a single element.
+    ///
+    /// If the quota replenishes cells at a rate that isn't a whole number of nanoseconds per
+    /// cell, this is rounded down to the next nanosecond.
     pub const fn replenish_interval(&self) -> Duration {
-        self.replenish_1_per
+        let interval_ns = self.replenish_period.as_nanos() / self.cells_per_period.get() as u128;
+        Duration::from_nanos(interval_ns as u64)
     }
 
     /// The maximum number of cells that can be allowed in one burst.
@@ -176,33 +524,106 @@ impl Quota {
         self.max_burst
     }
 
+    /// The time that must pass after the burst capacity is used up, before cells start
+    /// replenishing again. Zero unless set with [`with_cooldown`](#method.with_cooldown).
+    pub const fn cooldown(&self) -> Duration {
+        self.cooldown
+    }
+
+    /// The number of cells that can be let through as debt beyond the burst size. Zero unless
+    /// set with [`with_overdraft`](#method.with_overdraft).
+    pub const fn overdraft(&self) -> u32 {
+        self.overdraft
+    }
+
     /// The time it takes to replenish the entire maximum burst size.
     pub const fn burst_size_replenished_in(&self) -> Duration {
-        let fill_in_ns = self.replenish_1_per.as_nanos() * self.max_burst.get() as u128;
+        let fill_in_ns = self.replenish_period.as_nanos() * self.max_burst.get() as u128
+            / self.cells_per_period.get() as u128;
         Duration::from_nanos(fill_in_ns as u64)
     }
 }
 
+/// Splitting quotas
 impl Quota {
-    /// A way to reconstruct a Quota from an in-use Gcra.
-    ///
-    /// This is useful mainly for [`crate::RateLimitingMiddleware`]
-    /// where custom code may want to construct information based on
-    /// the amount of burst balance remaining.
-    pub(crate) fn from_gcra_parameters(t: Nanos, tau: Nanos) -> Quota {
-        // Safety assurance: As we're calling this from this crate
-        // only, and we do not allow creating a Gcra from 0
-        // parameters, this is, in fact, safe.
-        //
-        // The casts may look a little sketch, but again, they're
-        // constructed from parameters that came from the crate
-        // exactly like that.
-        let max_burst = unsafe { NonZeroU32::new_unchecked((tau.as_u64() / t.as_u64()) as u32) };
-        let replenish_1_per = t.into();
-        Quota {
-            max_burst,
-            replenish_1_per,
+    /// Splits the quota into sub-quotas for several categories of cells (e.g. traffic classes),
+    /// each receiving a share of the quota's rate and burst size in proportion to its weight.
+    ///
+    /// The burst size is distributed so that the sub-quotas' burst sizes add up to the
+    /// original one, except that every sub-quota allows a burst of at least one cell. Sub-quota
+    /// rates that can't be represented exactly are rounded down, so the sub-quotas never allow
+    /// more cells through than the original quota. Cooldowns apply to each sub-quota unchanged,
+    /// and overdrafts are shared out in proportion to the weights, rounding down.
+    ///
+    /// Returns `None` if there are no weights, if any weight is zero, or if a share's rate is
+    /// too low to be represented (i.e., it would replenish less than one cell in ~584 years).
+    ///
+    /// # Example
+    /// ```rust
+    /// # use nonzero_ext::nonzero;
+    /// # use governor::Quota;
+    /// # use std::time::Duration;
+    /// let quota = Quota::per_second(nonzero!(100u32));
+    /// let shares = quota.split(&[70, 20, 10]).unwrap();
+    /// assert_eq!(shares[0], Quota::per_second(nonzero!(70u32)));
+    /// assert_eq!(shares[1], Quota::per_second(nonzero!(20u32)));
+    /// assert_eq!(shares[2], Quota::per_second(nonzero!(10u32)));
+    ///
+    /// // Shares that don't divide evenly keep their exact rate, and the burst sizes still add up:
+    /// let shares = Quota::per_second(nonzero!(10u32)).split(&[1, 1, 1]).unwrap();
+    /// assert_eq!(shares[0].burst_size_replenished_in(), Duration::from_millis(1200));
+    /// let bursts: Vec<u32> = shares.iter().map(|q| q.burst_size().get()).collect();
+    /// assert_eq!(bursts, vec![4, 3, 3]);
+    /// ```
+    pub fn split(&self, weights: &[u32]) -> Option<Vec<Quota>> {
+        if weights.is_empty() || weights.contains(&0) {
+            return None;
         }
+        let total: u128 = weights.iter().map(|&w| w as u128).sum();
+        let burst = self.max_burst.get() as u128;
+
+        // Distribute the burst size by the largest remainder of each share:
+        let mut bursts: Vec<u128> = weights.iter().map(|&w| burst * w as u128 / total).collect();
+        let mut by_remainder: Vec<usize> = (0..weights.len()).collect();
+        by_remainder.sort_by_key(|&i| cmp::Reverse(burst * weights[i] as u128 % total));
+        let assigned: u128 = bursts.iter().sum();
+        for &i in by_remainder.iter().take((burst - assigned) as usize) {
+            bursts[i] += 1;
+        }
+
+        let period_ns = self.replenish_period.as_nanos();
+        let cells = self.cells_per_period.get() as u128;
+        weights
+            .iter()
+            .zip(bursts)
+            .map(|(&weight, burst)| {
+                // The share replenishes `cells * weight` cells in `period_ns * total`.
+                let period_ns = period_ns * total;
+                let cells = cells * weight as u128;
+                let divisor = gcd(period_ns, cells);
+                let (mut period_ns, mut cells) = (period_ns / divisor, cells / divisor);
+                // Round the period up (and so, the rate down) to a representable rate, instead
+                // of to the nearest one like `from_rate`:
+                if cells > MAX_CELLS_PER_PERIOD && period_ns >= cells {
+                    // Whole nanoseconds per cell:
+                    period_ns = period_ns.div_ceil(cells);
+                    cells = 1;
+                } else if cells > MAX_CELLS_PER_PERIOD {
+                    // More than one cell per nanosecond, in periods of the most cells:
+                    period_ns = (period_ns * MAX_CELLS_PER_PERIOD).div_ceil(cells);
+                    cells = MAX_CELLS_PER_PERIOD;
+                }
+                if period_ns > u64::MAX as u128 {
+                    return None;
+                }
+                let max_burst = nonzero_u32(cmp::max(burst, 1));
+                Some(Quota {
+                    cooldown: self.cooldown,
+                    overdraft: (u128::from(self.overdraft) * u128::from(weight) / total) as u32,
+                    ..Quota::from_rate(max_burst, period_ns, cells)
+                })
+            })
+            .collect()
     }
 }
 
@@ -227,13 +648,154 @@ mod test {
         );
     }
 
+    #[test]
+    fn fractional_rates() {
+        let thirds = Quota::per_second(nonzero!(3u32));
+        assert_eq!(thirds.cells_per_period.get(), 3);
+        assert_eq!(thirds.replenish_period, Duration::from_secs(1));
+        assert_eq!(
+            Quota::per_minute(nonzero!(180u32)),
+            thirds.allow_burst(nonzero!(180u32))
+        );
+
+        // Evenly-divisible rates don't need any subdivision:
+        let fiftieths = Quota::per_second(nonzero!(50u32));
+        assert_eq!(fiftieths.cells_per_period.get(), 1);
+        assert_eq!(
+            Quota::with_period(Duration::from_millis(20))
+                .unwrap()
+                .allow_burst(nonzero!(50u32)),
+            fiftieths
+        );
+
+        // Too fine a subdivision gets rounded to whole nanoseconds:
+        let primes = Quota::per_second(nonzero!(9973u32));
+        assert_eq!(primes.cells_per_period.get(), 1);
+        assert_eq!(primes.replenish_interval(), Duration::from_nanos(100_270));
+    }
+
+    #[test]
+    fn extremely_high_rates() {
+        let three_per_ns = Quota::new(nonzero!(3u32), Duration::from_nanos(1)).unwrap();
+        assert_eq!(three_per_ns.cells_per_period.get(), 3);
+        assert_eq!(three_per_ns.replenish_interval(), Duration::from_nanos(0));
+
+        // Beyond the maximum subdivision, rates get rounded to 64ths of a nanosecond:
+        let many_per_ns = Quota::per_second(nonzero!(4_294_967_295u32));
+        assert_eq!(many_per_ns.cells_per_period.get(), 64);
+        assert_eq!(many_per_ns.replenish_period, Duration::from_nanos(15));
+    }
+
+    #[test]
+    fn long_periods() {
+        let daily = Quota::per_day(nonzero!(1u32));
+        let weekly = Quota::per_week(nonzero!(1u32));
+        let monthly = Quota::per_month(nonzero!(1u32));
+
+        assert_eq!(
+            daily.replenish_interval(),
+            Quota::per_hour(nonzero!(1u32)).replenish_interval() * 24
+        );
+        assert_eq!(weekly.replenish_interval(), daily.replenish_interval() * 7);
+        assert_eq!(
+            monthly.replenish_interval() * 12,
+            // 365.2425 days:
+            Duration::from_secs(31_556_952)
+        );
+        assert_eq!(
+            Quota::per_week(nonzero!(7u32)),
+            daily.allow_burst(nonzero!(7u32))
+        );
+        assert_eq!(
+            Quota::new(nonzero!(4u32), Duration::from_secs(4 * 7 * 24 * 60 * 60)),
+            Some(weekly.allow_burst(nonzero!(4u32)))
+        );
+    }
+
     #[test]
     fn period_error_cases() {
         assert!(Quota::with_period(Duration::from_secs(0)).is_none());
+        assert!(Quota::new(nonzero!(1u32), Duration::from_secs(0)).is_none());
+
+        let too_long = Duration::from_nanos(u64::MAX) + Duration::from_nanos(1);
+        assert!(Quota::with_period(too_long).is_none());
+        assert!(Quota::new(nonzero!(1u32), too_long).is_none());
+        assert!(Quota::new(nonzero!(1u32), too_long - Duration::from_nanos(1)).is_some());
+    }
+
+    #[test]
+    fn const_constructors() {
+        const DAILY: Quota = Quota::per_day(nonzero!(24u32));
+        const PERIODIC: Option<Quota> = Quota::with_period(Duration::from_secs(3600));
+        const GENERAL: Option<Quota> = Quota::new(nonzero!(24u32), Duration::from_secs(86400));
+        const INVALID: Option<Quota> = Quota::new(nonzero!(1u32), Duration::from_secs(0));
+        const INTERVAL: Duration = DAILY.replenish_interval();
+
+        assert_eq!(Some(DAILY), GENERAL);
+        assert_eq!(PERIODIC.map(|q| q.replenish_interval()), Some(INTERVAL));
+        assert_eq!(INVALID, None);
+    }
+
+    #[test]
+    fn split_preserves_rate() {
+        let quota = Quota::per_minute(nonzero!(7u32)).with_cooldown(Duration::from_secs(5));
+        let shares = quota.split(&[3, 2, 2]).unwrap();
+        assert_eq!(
+            shares,
+            vec![
+                Quota::per_minute(nonzero!(3u32)).with_cooldown(Duration::from_secs(5)),
+                Quota::per_minute(nonzero!(2u32)).with_cooldown(Duration::from_secs(5)),
+                Quota::per_minute(nonzero!(2u32)).with_cooldown(Duration::from_secs(5)),
+            ]
+        );
+        assert_eq!(quota.split(&[1]), Some(vec![quota]));
 
-        #[allow(deprecated)]
-        {
-            assert!(Quota::new(nonzero!(1u32), Duration::from_secs(0)).is_none());
+        // Every share gets at least one cell of burst capacity:
+        let shares = Quota::per_second(nonzero!(2u32)).split(&[1, 1, 1]).unwrap();
+        assert!(shares.iter().all(|q| q.burst_size().get() == 1));
+        assert_eq!(
+            shares[0].burst_size_replenished_in(),
+            Duration::from_millis(1500)
+        );
+    }
+
+    #[test]
+    fn split_rounds_rates_down() {
+        let quota = Quota::per_second(nonzero!(61u32));
+        let shares = quota.split(&[1, 66]).unwrap();
+        // 61/67 cells per second can be represented exactly:
+        assert_eq!(shares[0].cells_per_period.get(), 61);
+        assert_eq!(shares[0].replenish_period, Duration::from_secs(67));
+        // ...but 61*66/67 can't, and 16641828.1ns per cell get rounded up:
+        assert_eq!(shares[1].cells_per_period.get(), 1);
+        assert_eq!(
+            shares[1].replenish_interval(),
+            Duration::from_nanos(16_641_829)
+        );
+        let bursts: u32 = shares.iter().map(|q| q.burst_size().get()).sum();
+        assert_eq!(bursts, 61);
+
+        // More than a cell per nanosecond: 64*1000/1001 cells per nanosecond would round to
+        // the nearest representable rate of 64, which is faster than the share:
+        let quota = Quota::new(nonzero!(64u32), Duration::from_nanos(1)).unwrap();
+        let shares = quota.split(&[1000, 1]).unwrap();
+        assert_eq!(shares[0].cells_per_period.get(), 32);
+        assert_eq!(shares[0].replenish_period, Duration::from_nanos(1));
+        for (share, weight) in shares.iter().zip([1000u128, 1]) {
+            let rate = share.cells_per_period.get() as u128 * 1001;
+            assert!(
+                rate * quota.replenish_period.as_nanos()
+                    <= 64 * weight * share.replenish_period.as_nanos()
+            );
         }
     }
+
+    #[test]
+    fn split_error_cases() {
+        let quota = Quota::per_second(nonzero!(1u32));
+        assert_eq!(quota.split(&[]), None);
+        assert_eq!(quota.split(&[1, 0]), None);
+        let slow = Quota::with_period(Duration::from_nanos(u64::MAX)).unwrap();
+        assert_eq!(slow.split(&[1, 1]), None);
+    }
 }
@@ -0,0 +1,225 @@
+use crate::compat::prelude::*;
+
+use crate::clock::Reference;
+use crate::Quota;
+use std::cmp;
+use std::num::NonZeroU32;
+use std::time::Duration;
+
+/// Candidate replenishment intervals are tried in steps of this many thousandths.
+const STEP_PER_MILLE: u64 = 1010;
+
+/// Suggests a [`Quota`] that matches the observed behavior of a rate-limited upstream service,
+/// e.g. a third-party API that doesn't document its limits.
+///
+/// Record the times of calls that the upstream service let through and of calls that it
+/// rate-limited (e.g. with a `429 Too Many Requests` response), then ask for a
+/// [`suggestion`](#method.suggest): Among the quotas that would have let through every
+/// successful call, this is the one with the highest rate that would also have rate-limited as
+/// many of the rate-limited calls as any quota can, and the smallest burst size that a quota
+/// with that rate needs.
+///
+/// The suggestion is only as good as the observations: The upstream service should have been
+/// called by nothing else in the meantime, at a rate high enough to hit its limit. Rates are
+/// tried in steps of about 1%, so the suggested rate is accurate to about that much.
+///
+/// # Example
+/// ```rust
+/// # use std::time::Duration;
+/// use governor::{nanos::Nanos, quota::QuotaAdvisor, Quota};
+///
+/// let mut advisor = QuotaAdvisor::new();
+/// // Calls every 100ms; the upstream lets through bursts of 3, then one call per second:
+/// for (i, ok) in [true, true, true, false, false, false, false, false, false, false, true]
+///     .iter()
+///     .enumerate()
+/// {
+///     let at = Nanos::from(Duration::from_millis(100 * i as u64));
+///     if *ok {
+///         advisor.record_success(at);
+///     } else {
+///         advisor.record_rate_limited(at);
+///     }
+/// }
+/// let quota = advisor.suggest().unwrap();
+/// assert_eq!(quota.burst_size().get(), 3);
+/// assert!(quota.replenish_interval() > Duration::from_millis(300));
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QuotaAdvisor<P: Reference> {
+    /// Observed calls, and whether they were let through.
+    calls: Vec<(P, bool)>,
+}
+
+impl<P: Reference> Default for QuotaAdvisor<P> {
+    fn default() -> Self {
+        QuotaAdvisor { calls: Vec::new() }
+    }
+}
+
+impl<P: Reference> QuotaAdvisor<P> {
+    /// Constructs an advisor without any observations.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records a call at the given time that the upstream service let through.
+    pub fn record_success(&mut self, at: P) {
+        self.calls.push((at, true));
+    }
+
+    /// Records a call at the given time that the upstream service rate-limited.
+    pub fn record_rate_limited(&mut self, at: P) {
+        self.calls.push((at, false));
+    }
+
+    /// Returns the number of recorded calls that were let through.
+    pub fn successes(&self) -> usize {
+        self.calls.iter().filter(|(_, ok)| *ok).count()
+    }
+
+    /// Returns the number of recorded calls that were rate-limited.
+    pub fn rate_limited(&self) -> usize {
+        self.calls.len() - self.successes()
+    }
+
+    /// Suggests a quota that matches the recorded calls.
+    ///
+    /// Returns `None` if there is nothing to go by: no calls were let through, none were
+    /// rate-limited, or they all happened at the same time.
+    pub fn suggest(&self) -> Option<Quota> {
+        if self.successes() == 0 || self.rate_limited() == 0 {
+            return None;
+        }
+        let mut calls = self.calls.clone();
+        calls.sort_by_key(|call| call.0);
+        let base = calls[0].0;
+        let calls: Vec<(u64, bool)> = calls
+            .iter()
+            .map(|(at, ok)| (at.duration_since(base).as_u64(), *ok))
+            .collect();
+        let successes: Vec<u64> = calls.iter().filter(|c| c.1).map(|c| c.0).collect();
+
+        let span = calls[calls.len() - 1].0;
+        if span == 0 {
+            return None;
+        }
+        // Replenishing much faster than calls arrived can't explain any rate-limiting:
+        let shortest_gap = calls
+            .windows(2)
+            .map(|w| w[1].0 - w[0].0)
+            .filter(|gap| *gap > 0)
+            .min()
+            .unwrap_or(span);
+
+        let mut best: Option<(usize, u64, u64)> = None;
+        let mut interval = cmp::max(1, shortest_gap / 2);
+        loop {
+            let burst = burst_needed(&successes, interval);
+            let explained = explained(&calls, interval, burst);
+            if !matches!(best, Some((most, _, _)) if most >= explained) {
+                best = Some((explained, interval, burst));
+            }
+            if interval >= span {
+                break;
+            }
+            interval = cmp::min(
+                span,
+                cmp::max(interval + 1, interval.saturating_mul(STEP_PER_MILLE) / 1000),
+            );
+        }
+        let (_, interval, burst) = best?;
+        let burst = NonZeroU32::new(cmp::min(burst, u32::MAX as u64) as u32)?;
+        Quota::with_period(Duration::from_nanos(interval)).map(|q| q.allow_burst(burst))
+    }
+}
+
+/// Returns the smallest burst size that lets all `successes` (in nanoseconds, in order) through
+/// a rate limiter that replenishes one cell every `interval` nanoseconds.
+///
+/// This and [`explained`] replay the calls the way the rate limiter's GCRA does, keeping track
+/// of the theoretical arrival time (TAT): A call at `t0` conforms if `t0 >= TAT - burst *
+/// interval`, and moves the TAT to `max(TAT, t0) + interval`.
+fn burst_needed(successes: &[u64], interval: u64) -> u64 {
+    let mut tat: Option<u64> = None;
+    let mut burst = 1;
+    for &at in successes {
+        let current = tat.unwrap_or_else(|| at.saturating_add(interval));
+        let depth = current.saturating_sub(at);
+        burst = cmp::max(burst, depth.div_ceil(interval));
+        tat = Some(cmp::max(current, at).saturating_add(interval));
+    }
+    burst
+}
+
+/// Returns how many of the rate-limited calls would be rate-limited by the quota, when it lets
+/// the successful calls through.
+fn explained(calls: &[(u64, bool)], interval: u64, burst: u64) -> usize {
+    let tolerance = burst.saturating_mul(interval);
+    let mut tat: Option<u64> = None;
+    let mut explained = 0;
+    for &(at, ok) in calls {
+        let current = tat.unwrap_or_else(|| at.saturating_add(interval));
+        if ok {
+            tat = Some(cmp::max(current, at).saturating_add(interval));
+        } else if at < current.saturating_sub(tolerance) {
+            explained += 1;
+        }
+    }
+    explained
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::clock::{Clock, FakeRelativeClock};
+    use crate::nanos::Nanos;
+    use crate::RateLimiter;
+    use nonzero_ext::nonzero;
+
+    fn observe(quota: Quota, every: Duration, calls: usize) -> QuotaAdvisor<Nanos> {
+        let clock = FakeRelativeClock::default();
+        let upstream = RateLimiter::direct_with_clock(quota, &clock);
+        let mut advisor = QuotaAdvisor::new();
+        for _ in 0..calls {
+            match upstream.check() {
+                Ok(()) => advisor.record_success(clock.now()),
+                Err(_) => advisor.record_rate_limited(clock.now()),
+            }
+            clock.advance(every);
+        }
+        advisor
+    }
+
+    #[test]
+    fn infers_observed_quotas() {
+        let quota = Quota::per_second(nonzero!(10u32)).allow_burst(nonzero!(5u32));
+        let advisor = observe(quota, Duration::from_millis(30), 300);
+        assert!(advisor.rate_limited() > 0);
+        let suggested = advisor.suggest().unwrap();
+        let interval = suggested.replenish_interval().as_secs_f64();
+        assert!((interval - 0.1).abs() < 0.005, "{:?}", suggested);
+        assert_eq!(suggested.burst_size(), nonzero!(5u32));
+
+        let quota = Quota::per_minute(nonzero!(60u32)).allow_burst(nonzero!(1u32));
+        let suggested = observe(quota, Duration::from_millis(400), 100)
+            .suggest()
+            .unwrap();
+        let interval = suggested.replenish_interval().as_secs_f64();
+        assert!((interval - 1.0).abs() < 0.05, "{:?}", suggested);
+        assert_eq!(suggested.burst_size(), nonzero!(1u32));
+    }
+
+    #[test]
+    fn needs_evidence() {
+        let mut advisor = QuotaAdvisor::new();
+        assert_eq!(advisor.suggest(), None);
+        advisor.record_success(Nanos::from(0));
+        assert_eq!(advisor.suggest(), None);
+        advisor.record_rate_limited(Nanos::from(0));
+        assert_eq!(advisor.suggest(), None);
+        advisor.record_rate_limited(Nanos::from(1_000));
+        assert!(advisor.suggest().is_some());
+        assert_eq!((advisor.successes(), advisor.rate_limited()), (1, 2));
+    }
+}
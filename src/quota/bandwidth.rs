@@ -0,0 +1,153 @@
+use crate::Quota;
+use std::num::NonZeroU32;
+
+/// A rate of bytes per second, for rate limiters that count each byte as one cell.
+///
+/// Bandwidths can be given in bytes, in SI (decimal) and IEC (binary) multiples of bytes, and
+/// in bits per second; they are converted to a [`Quota`] using [`Quota::bandwidth`] (or
+/// `From`). Since quotas count cells in `u32`s, the largest representable bandwidth is
+/// `u32::MAX` bytes (~4.29GB) per second; the scaled constructors return `None` for larger
+/// values.
+///
+/// # Example
+/// ```rust
+/// # use nonzero_ext::nonzero;
+/// use governor::{Bandwidth, Quota};
+///
+/// let uplink = Bandwidth::megabits_per_second(nonzero!(100u32)).unwrap();
+/// assert_eq!(uplink.get().get(), 12_500_000);
+/// assert_eq!(Quota::from(uplink), Quota::bytes_per_second(nonzero!(12_500_000u32)));
+/// assert!(Bandwidth::gigabytes_per_second(nonzero!(5u32)).is_none());
+/// ```
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Clone, Copy)]
+pub struct Bandwidth(NonZeroU32);
+
+const fn scaled(n: NonZeroU32, unit: u32) -> Option<Bandwidth> {
+    match n.get().checked_mul(unit) {
+        // `n` and `unit` are both non-zero:
+        Some(bytes) => match NonZeroU32::new(bytes) {
+            Some(bytes) => Some(Bandwidth(bytes)),
+            None => None,
+        },
+        None => None,
+    }
+}
+
+impl Bandwidth {
+    /// A bandwidth of `n` bytes per second.
+    pub const fn bytes_per_second(n: NonZeroU32) -> Bandwidth {
+        Bandwidth(n)
+    }
+
+    /// A bandwidth of `n` kilobytes (1000 bytes) per second.
+    pub const fn kilobytes_per_second(n: NonZeroU32) -> Option<Bandwidth> {
+        scaled(n, 1000)
+    }
+
+    /// A bandwidth of `n` megabytes (1000² bytes) per second.
+    pub const fn megabytes_per_second(n: NonZeroU32) -> Option<Bandwidth> {
+        scaled(n, 1000 * 1000)
+    }
+
+    /// A bandwidth of `n` gigabytes (1000³ bytes) per second.
+    pub const fn gigabytes_per_second(n: NonZeroU32) -> Option<Bandwidth> {
+        scaled(n, 1000 * 1000 * 1000)
+    }
+
+    /// A bandwidth of `n` kibibytes (1024 bytes) per second.
+    pub const fn kibibytes_per_second(n: NonZeroU32) -> Option<Bandwidth> {
+        scaled(n, 1024)
+    }
+
+    /// A bandwidth of `n` mebibytes (1024² bytes) per second.
+    pub const fn mebibytes_per_second(n: NonZeroU32) -> Option<Bandwidth> {
+        scaled(n, 1024 * 1024)
+    }
+
+    /// A bandwidth of `n` gibibytes (1024³ bytes) per second.
+    pub const fn gibibytes_per_second(n: NonZeroU32) -> Option<Bandwidth> {
+        scaled(n, 1024 * 1024 * 1024)
+    }
+
+    /// A bandwidth of `n` kilobits (1000 bits) per second.
+    pub const fn kilobits_per_second(n: NonZeroU32) -> Option<Bandwidth> {
+        scaled(n, 1000 / 8)
+    }
+
+    /// A bandwidth of `n` megabits (1000² bits) per second.
+    pub const fn megabits_per_second(n: NonZeroU32) -> Option<Bandwidth> {
+        scaled(n, 1000 * 1000 / 8)
+    }
+
+    /// A bandwidth of `n` gigabits (1000³ bits) per second.
+    pub const fn gigabits_per_second(n: NonZeroU32) -> Option<Bandwidth> {
+        scaled(n, 1000 * 1000 * 1000 / 8)
+    }
+
+    /// Returns the number of bytes per second.
+    pub const fn get(&self) -> NonZeroU32 {
+        self.0
+    }
+}
+
+/// Bandwidth quotas
+impl Quota {
+    /// Construct a quota for a number of bytes per second, for rate limiters that count each
+    /// byte as one cell (e.g. using [`check_n`](struct.RateLimiter.html#method.check_n) with the
+    /// size of each message, or the throttled I/O wrappers). The burst size is one second's
+    /// worth of bytes.
+    ///
+    /// This is the same as [`per_second`](#method.per_second), under a name that reads
+    /// naturally for bandwidth limits.
+    pub const fn bytes_per_second(bytes: NonZeroU32) -> Quota {
+        Quota::per_second(bytes)
+    }
+
+    /// Construct a quota for a [`Bandwidth`]. The burst size is one second's worth of bytes.
+    pub const fn bandwidth(bandwidth: Bandwidth) -> Quota {
+        Quota::bytes_per_second(bandwidth.0)
+    }
+}
+
+impl From<Bandwidth> for Quota {
+    fn from(bandwidth: Bandwidth) -> Quota {
+        Quota::bandwidth(bandwidth)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use nonzero_ext::nonzero;
+
+    #[test]
+    fn units() {
+        let one = nonzero!(1u32);
+        let bytes = |b: Option<Bandwidth>| b.map(|b| b.get().get());
+        assert_eq!(bytes(Some(Bandwidth::bytes_per_second(one))), Some(1));
+        assert_eq!(bytes(Bandwidth::kilobytes_per_second(one)), Some(1000));
+        assert_eq!(bytes(Bandwidth::megabytes_per_second(one)), Some(1_000_000));
+        assert_eq!(
+            bytes(Bandwidth::gigabytes_per_second(one)),
+            Some(1_000_000_000)
+        );
+        assert_eq!(bytes(Bandwidth::kibibytes_per_second(one)), Some(1024));
+        assert_eq!(bytes(Bandwidth::mebibytes_per_second(one)), Some(1 << 20));
+        assert_eq!(bytes(Bandwidth::gibibytes_per_second(one)), Some(1 << 30));
+        assert_eq!(bytes(Bandwidth::kilobits_per_second(one)), Some(125));
+        assert_eq!(bytes(Bandwidth::megabits_per_second(one)), Some(125_000));
+        assert_eq!(
+            bytes(Bandwidth::gigabits_per_second(one)),
+            Some(125_000_000)
+        );
+        assert_eq!(Bandwidth::gibibytes_per_second(nonzero!(4u32)), None);
+    }
+
+    #[test]
+    fn quotas() {
+        const UPLINK: Option<Bandwidth> = Bandwidth::mebibytes_per_second(nonzero!(2u32));
+        let quota = Quota::from(UPLINK.unwrap());
+        assert_eq!(quota.burst_size().get(), 2 << 20);
+        assert_eq!(quota, Quota::per_second(nonzero!(2_097_152u32)));
+    }
+}
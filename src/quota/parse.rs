@@ -0,0 +1,318 @@
+use crate::compat::prelude::*;
+
+use crate::Quota;
+use std::convert::TryFrom;
+use std::fmt;
+use std::num::NonZeroU32;
+use std::str::FromStr;
+use std::time::Duration;
+
+/// An error that occurred while parsing a [`Quota`] or one of its durations.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParseQuotaError {
+    /// The duration could not be parsed.
+    InvalidDuration,
+
+    /// The number of cells could not be parsed, or was zero.
+    InvalidCells,
+
+    /// The quota wasn't given in the form "`cells` per `period`" (or "`cells`/`period`").
+    InvalidFormat,
+
+    /// The period is zero, or too long for a rate limiter to keep track of.
+    InvalidPeriod,
+}
+
+impl fmt::Display for ParseQuotaError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseQuotaError::InvalidDuration => write!(f, "invalid duration"),
+            ParseQuotaError::InvalidCells => write!(f, "invalid number of cells"),
+            ParseQuotaError::InvalidFormat => {
+                write!(f, "quotas must be given as \"<cells> per <period>\"")
+            }
+            ParseQuotaError::InvalidPeriod => write!(f, "period is zero or too long"),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for ParseQuotaError {}
+
+const NANOS_PER_SEC: u128 = 1_000_000_000;
+const NANOS_PER_MIN: u128 = 60 * NANOS_PER_SEC;
+const NANOS_PER_HOUR: u128 = 60 * NANOS_PER_MIN;
+const NANOS_PER_DAY: u128 = 24 * NANOS_PER_HOUR;
+const NANOS_PER_WEEK: u128 = 7 * NANOS_PER_DAY;
+/// The average length of a month in the Gregorian calendar, as in [`Quota::per_month`].
+const NANOS_PER_MONTH: u128 = 2_629_746 * NANOS_PER_SEC;
+const NANOS_PER_YEAR: u128 = 12 * NANOS_PER_MONTH;
+
+/// Returns the length in nanoseconds of a `humantime`-style unit of time.
+fn unit_nanos(unit: &str) -> Option<u128> {
+    Some(match unit {
+        "ns" | "nsec" | "nanos" | "nanosecond" | "nanoseconds" => 1,
+        "us" | "µs" | "usec" | "micros" | "microsecond" | "microseconds" => 1_000,
+        "ms" | "msec" | "millis" | "millisecond" | "milliseconds" => 1_000_000,
+        "s" | "sec" | "secs" | "second" | "seconds" => NANOS_PER_SEC,
+        "m" | "min" | "mins" | "minute" | "minutes" => NANOS_PER_MIN,
+        "h" | "hr" | "hrs" | "hour" | "hours" => NANOS_PER_HOUR,
+        "d" | "day" | "days" => NANOS_PER_DAY,
+        "w" | "week" | "weeks" => NANOS_PER_WEEK,
+        "M" | "month" | "months" => NANOS_PER_MONTH,
+        "y" | "year" | "years" => NANOS_PER_YEAR,
+        _ => return None,
+    })
+}
+
+/// Splits a leading run of ASCII digits off `s`, returning the number and the rest.
+fn split_number(s: &str) -> Option<(u128, &str)> {
+    let end = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let number = s[..end].parse().ok()?;
+    Some((number, &s[end..]))
+}
+
+/// Parses a `humantime`-style duration, e.g. "250ms", "1h 30m" or "2 days".
+fn parse_human(s: &str) -> Option<u128> {
+    let mut rest = s.trim();
+    let mut total: u128 = 0;
+    if rest.is_empty() {
+        return None;
+    }
+    while !rest.is_empty() {
+        let (number, after) = split_number(rest)?;
+        let after = after.trim_start();
+        let end = after
+            .find(|c: char| c.is_ascii_digit() || c.is_whitespace())
+            .unwrap_or(after.len());
+        let unit = unit_nanos(&after[..end])?;
+        total = total.checked_add(number.checked_mul(unit)?)?;
+        rest = after[end..].trim_start();
+    }
+    Some(total)
+}
+
+/// Parses an ISO-8601 duration, e.g. "PT1M", "P1DT12H" or "PT0.25S".
+///
+/// Only seconds may have a fractional part.
+fn parse_iso8601(s: &str) -> Option<u128> {
+    let mut rest = s.strip_prefix('P')?;
+    let mut total: u128 = 0;
+    let mut in_time = false;
+    if rest.is_empty() {
+        return None;
+    }
+    while !rest.is_empty() {
+        if let Some(after) = rest.strip_prefix('T') {
+            if in_time || after.is_empty() {
+                return None;
+            }
+            in_time = true;
+            rest = after;
+            continue;
+        }
+        let (number, after) = split_number(rest)?;
+        let (fraction, after) = match after.strip_prefix(['.', ',']) {
+            Some(fraction) => {
+                let end = fraction
+                    .find(|c: char| !c.is_ascii_digit())
+                    .unwrap_or(fraction.len());
+                if end == 0 || end > 9 || !in_time {
+                    return None;
+                }
+                let digits: u128 = fraction[..end].parse().ok()?;
+                (Some(digits * 10u128.pow(9 - end as u32)), &fraction[end..])
+            }
+            None => (None, after),
+        };
+        let mut chars = after.chars();
+        let unit = match (in_time, chars.next()?) {
+            (false, 'Y') => NANOS_PER_YEAR,
+            (false, 'M') => NANOS_PER_MONTH,
+            (false, 'W') => NANOS_PER_WEEK,
+            (false, 'D') => NANOS_PER_DAY,
+            (true, 'H') => NANOS_PER_HOUR,
+            (true, 'M') => NANOS_PER_MIN,
+            (true, 'S') => NANOS_PER_SEC,
+            _ => return None,
+        };
+        if fraction.is_some() && unit != NANOS_PER_SEC {
+            return None;
+        }
+        total = total
+            .checked_add(number.checked_mul(unit)?)?
+            .checked_add(fraction.unwrap_or(0))?;
+        rest = chars.as_str();
+    }
+    Some(total)
+}
+
+/// Parsing quotas and their durations
+impl Quota {
+    /// Parses a duration given in the style of the [`humantime`](https://docs.rs/humantime)
+    /// crate (e.g. "250ms", "1h 30m", "2 days"), or as an
+    /// [ISO-8601 duration](https://en.wikipedia.org/wiki/ISO_8601#Durations) (e.g. "PT1M",
+    /// "P1DT12H", "PT0.25S").
+    ///
+    /// Months and years are taken to be their average length in the Gregorian calendar, as in
+    /// [`Quota::per_month`](#method.per_month).
+    ///
+    /// # Example
+    /// ```rust
+    /// # use governor::Quota;
+    /// # use std::time::Duration;
+    /// assert_eq!(Quota::parse_duration("250ms"), Ok(Duration::from_millis(250)));
+    /// assert_eq!(Quota::parse_duration("1m 30s"), Ok(Duration::from_secs(90)));
+    /// assert_eq!(Quota::parse_duration("PT1M30S"), Ok(Duration::from_secs(90)));
+    /// assert!(Quota::parse_duration("soon").is_err());
+    /// ```
+    pub fn parse_duration(s: &str) -> Result<Duration, ParseQuotaError> {
+        let s = s.trim();
+        let nanos = if s.starts_with('P') {
+            parse_iso8601(s)
+        } else {
+            parse_human(s)
+        };
+        let nanos = nanos.ok_or(ParseQuotaError::InvalidDuration)?;
+        let secs =
+            u64::try_from(nanos / NANOS_PER_SEC).map_err(|_| ParseQuotaError::InvalidDuration)?;
+        Ok(Duration::new(secs, (nanos % NANOS_PER_SEC) as u32))
+    }
+
+    /// Construct a quota that replenishes one cell in the given interval, parsed by
+    /// [`parse_duration`](#method.parse_duration).
+    ///
+    /// See [`with_period`](#method.with_period).
+    ///
+    /// # Example
+    /// ```rust
+    /// # use governor::Quota;
+    /// # use std::time::Duration;
+    /// let quota = Quota::with_period_str("250ms").unwrap();
+    /// assert_eq!(quota.replenish_interval(), Duration::from_millis(250));
+    /// ```
+    pub fn with_period_str(replenish_1_per: &str) -> Result<Quota, ParseQuotaError> {
+        Quota::with_period(Quota::parse_duration(replenish_1_per)?)
+            .ok_or(ParseQuotaError::InvalidPeriod)
+    }
+}
+
+/// Quotas can be parsed from strings of the form "`cells` per `period`" or "`cells`/`period`",
+/// where the period is either parsed by [`Quota::parse_duration`], or is a single unit of time
+/// (like "second" or "h"). This constructs the same quota as [`Quota::new`].
+///
+/// # Example
+/// ```rust
+/// # use governor::Quota;
+/// # use nonzero_ext::nonzero;
+/// # use std::time::Duration;
+/// assert_eq!("50 per second".parse(), Ok(Quota::per_second(nonzero!(50u32))));
+/// assert_eq!("50/s".parse(), Ok(Quota::per_second(nonzero!(50u32))));
+/// let quota: Quota = "5 per PT15M".parse().unwrap();
+/// assert_eq!(quota.burst_size_replenished_in(), Duration::from_secs(900));
+/// ```
+impl FromStr for Quota {
+    type Err = ParseQuotaError;
+
+    fn from_str(s: &str) -> Result<Quota, ParseQuotaError> {
+        let (cells, period) = match s.split_once('/') {
+            Some(parts) => parts,
+            None => s
+                .split_once(" per ")
+                .ok_or(ParseQuotaError::InvalidFormat)?,
+        };
+        let cells: NonZeroU32 = cells
+            .trim()
+            .parse()
+            .map_err(|_| ParseQuotaError::InvalidCells)?;
+        let period = period.trim();
+        let period = match unit_nanos(period) {
+            Some(nanos) => Duration::from_nanos(nanos as u64),
+            None => Quota::parse_duration(period)?,
+        };
+        Quota::new(cells, period).ok_or(ParseQuotaError::InvalidPeriod)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use nonzero_ext::nonzero;
+
+    #[test]
+    fn parses_human_durations() {
+        for (s, expected) in [
+            ("1ns", Duration::from_nanos(1)),
+            ("15 us", Duration::from_micros(15)),
+            ("2h 30m", Duration::from_secs(9000)),
+            ("1day 1s", Duration::from_secs(86401)),
+            ("1M", Duration::from_secs(2_629_746)),
+            ("2 weeks", Duration::from_secs(14 * 86400)),
+        ] {
+            assert_eq!(Quota::parse_duration(s), Ok(expected), "{}", s);
+        }
+        for s in ["", "1", "ms", "1 fortnight", "1.5s", "-1s"] {
+            assert_eq!(
+                Quota::parse_duration(s),
+                Err(ParseQuotaError::InvalidDuration),
+                "{}",
+                s
+            );
+        }
+    }
+
+    #[test]
+    fn parses_iso8601_durations() {
+        for (s, expected) in [
+            ("PT1M", Duration::from_secs(60)),
+            ("P1W", Duration::from_secs(7 * 86400)),
+            ("P1DT12H", Duration::from_secs(36 * 3600)),
+            ("PT0.25S", Duration::from_millis(250)),
+            ("PT1,5S", Duration::from_millis(1500)),
+            ("P1Y", Duration::from_secs(12 * 2_629_746)),
+        ] {
+            assert_eq!(Quota::parse_duration(s), Ok(expected), "{}", s);
+        }
+        for s in [
+            "P",
+            "PT",
+            "P1H",
+            "PT1D",
+            "P1.5D",
+            "PT1S1",
+            "P1DT",
+            "PT1.1234567891S",
+        ] {
+            assert_eq!(
+                Quota::parse_duration(s),
+                Err(ParseQuotaError::InvalidDuration),
+                "{}",
+                s
+            );
+        }
+    }
+
+    #[test]
+    fn parses_quotas() {
+        assert_eq!("3 per hour".parse(), Ok(Quota::per_hour(nonzero!(3u32))));
+        assert_eq!(" 3 / 1h ".parse(), Ok(Quota::per_hour(nonzero!(3u32))));
+        assert_eq!(
+            "1000 per 90d".parse(),
+            Ok(Quota::new(nonzero!(1000u32), Duration::from_secs(90 * 86400)).unwrap())
+        );
+        assert_eq!(
+            "0 per second".parse::<Quota>(),
+            Err(ParseQuotaError::InvalidCells)
+        );
+        assert_eq!(
+            "1 per 0s".parse::<Quota>(),
+            Err(ParseQuotaError::InvalidPeriod)
+        );
+        assert_eq!("lots".parse::<Quota>(), Err(ParseQuotaError::InvalidFormat));
+        assert_eq!(
+            Quota::with_period_str("600y"),
+            Err(ParseQuotaError::InvalidPeriod)
+        );
+        assert!(!format!("{}", ParseQuotaError::InvalidFormat).is_empty());
+    }
+}
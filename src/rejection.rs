@@ -0,0 +1,365 @@
+//! Machine-readable descriptions of negative rate-limiting outcomes, for API error bodies.
+
+use crate::compat::prelude::*;
+
+#[cfg(feature = "jitter")]
+use crate::Jitter;
+use crate::{clock, NotUntil};
+use std::convert::TryFrom;
+use std::fmt::{self, Write};
+use std::time::Duration;
+
+/// A machine-readable description of a negative rate-limiting outcome, for generating the
+/// error bodies of APIs without parsing [`NotUntil`]'s human-readable
+/// [`Display`](fmt::Display).
+///
+/// Rejections are made by [`NotUntil::rejection`], and can be turned into [JSON](#method.to_json)
+/// directly, or serialized with `serde` (with the `serde` feature enabled).
+///
+/// # Example
+/// ```rust
+/// # use nonzero_ext::*;
+/// use governor::{clock::{Clock, FakeRelativeClock}, Quota, RateLimiter};
+/// # use std::time::Duration;
+///
+/// let clock = FakeRelativeClock::default();
+/// let lim = RateLimiter::direct_with_clock(Quota::per_second(nonzero!(2u32)), &clock);
+/// lim.check().unwrap();
+/// lim.check().unwrap();
+/// let rejection = lim.check().unwrap_err().rejection(clock.now());
+/// assert_eq!(rejection.retry_after_ms, 500);
+/// assert_eq!(
+///     rejection.to_json(),
+///     r#"{"retry_after_ms":500,"limit":2,"window_ms":1000,"burst":2}"#
+/// );
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Rejection {
+    /// The number of milliseconds (rounded up) after which the decision could be conforming.
+    pub retry_after_ms: u64,
+    /// The number of cells that the quota allows per window.
+    pub limit: u32,
+    /// The number of milliseconds (rounded up) that the quota takes to replenish `limit` cells.
+    pub window_ms: u64,
+    /// The number of cells that can go through at once: the quota's burst size, plus its
+    /// [overdraft](crate::Quota::with_overdraft).
+    pub burst: u32,
+    /// The [label](crate::RateLimiter::with_label) of the rate limiter that made the decision,
+    /// if it has one.
+    pub label: Option<&'static str>,
+    /// The hash of the key that the decision was about, for keyed rate limiters (see
+    /// [`with_key`](#method.with_key)).
+    pub key_hash: Option<u64>,
+}
+
+/// Returns the number of milliseconds in a duration, rounded up.
+fn ceil_millis(duration: Duration) -> u64 {
+    let millis = duration.as_nanos().div_ceil(1_000_000);
+    u64::try_from(millis).unwrap_or(u64::MAX)
+}
+
+impl Rejection {
+    pub(crate) fn new<P: clock::Reference>(negative: &NotUntil<P>, from: P) -> Rejection {
+        let quota = negative.quota();
+        Rejection {
+            retry_after_ms: ceil_millis(negative.wait_time_from(from)),
+            limit: quota.burst_size().get(),
+            window_ms: ceil_millis(quota.burst_size_replenished_in()),
+            burst: quota.burst_size().get().saturating_add(quota.overdraft()),
+            label: negative.label(),
+            key_hash: None,
+        }
+    }
+
+    /// Returns the rejection for a decision about the given key, identified by the same hash
+    /// as [recorded decisions](crate::state::DecisionLog::key_hash).
+    #[cfg(feature = "std")]
+    pub fn with_key<K: std::hash::Hash>(self, key: &K) -> Rejection {
+        Rejection {
+            key_hash: Some(crate::state::DecisionLog::key_hash(key)),
+            ..self
+        }
+    }
+
+    /// Returns the rejection with a random amount of jitter added to the time to wait, so that
+    /// clients that were rejected at the same time don't all retry at once when capacity frees
+    /// up again.
+    ///
+    /// Each call draws a new amount from the jitter's interval, so that every response can
+    /// advertise a different time to retry: The jitter applies to
+    /// [`retry_after_ms`](#structfield.retry_after_ms) and to the headers made from it (which
+    /// count whole seconds, so jitter of less than a second mostly vanishes in them).
+    ///
+    /// ```rust
+    /// # use nonzero_ext::*;
+    /// use governor::{clock::{Clock, FakeRelativeClock}, Jitter, Quota, RateLimiter};
+    /// # use std::time::Duration;
+    ///
+    /// let clock = FakeRelativeClock::default();
+    /// let lim = RateLimiter::direct_with_clock(Quota::per_second(nonzero!(2u32)), &clock);
+    /// lim.check().unwrap();
+    /// lim.check().unwrap();
+    /// let jitter = Jitter::up_to(Duration::from_secs(2));
+    /// let rejection = lim.check().unwrap_err().rejection(clock.now()).with_jitter(jitter);
+    /// assert!(rejection.retry_after_ms >= 500);
+    /// assert!(rejection.retry_after_ms <= 2_500);
+    /// ```
+    #[cfg(feature = "jitter")]
+    pub fn with_jitter(self, jitter: Jitter) -> Rejection {
+        Rejection {
+            retry_after_ms: self
+                .retry_after_ms
+                .saturating_add(ceil_millis(jitter.get().into())),
+            ..self
+        }
+    }
+
+    /// Returns the rejection as a JSON object, leaving out the fields that are `None`.
+    pub fn to_json(&self) -> String {
+        let mut json = String::new();
+        self.write_json(&mut json)
+            .expect("writing to a String can't fail");
+        json
+    }
+
+    fn write_json(&self, json: &mut String) -> fmt::Result {
+        write!(
+            json,
+            r#"{{"retry_after_ms":{},"limit":{},"window_ms":{},"burst":{}"#,
+            self.retry_after_ms, self.limit, self.window_ms, self.burst
+        )?;
+        if let Some(label) = self.label {
+            json.push_str(r#","label":""#);
+            for c in label.chars() {
+                match c {
+                    '"' => json.push_str(r#"\""#),
+                    '\\' => json.push_str(r"\\"),
+                    '\n' => json.push_str(r"\n"),
+                    '\r' => json.push_str(r"\r"),
+                    '\t' => json.push_str(r"\t"),
+                    c if c < ' ' => write!(json, r"\u{:04x}", c as u32)?,
+                    c => json.push(c),
+                }
+            }
+            json.push('"');
+        }
+        if let Some(key_hash) = self.key_hash {
+            write!(json, r#","key_hash":{}"#, key_hash)?;
+        }
+        json.push('}');
+        Ok(())
+    }
+}
+
+/// # Rejections as HTTP responses
+///
+/// With the `http` feature enabled, rejections turn into the status and headers of an
+/// [`http::Response`], which any framework built on the `http` crate can send as is:
+///
+/// * `429 Too Many Requests`,
+/// * `retry-after`: the seconds (rounded up) after which the request could be conforming,
+/// * `ratelimit-limit`: the quota's limit,
+/// * `ratelimit-remaining`: 0, and
+/// * `ratelimit-reset`: the same seconds as `retry-after`.
+///
+/// ```rust
+/// # use nonzero_ext::*;
+/// use governor::{clock::{Clock, FakeRelativeClock}, Quota, RateLimiter};
+///
+/// let clock = FakeRelativeClock::default();
+/// let lim = RateLimiter::direct_with_clock(Quota::per_second(nonzero!(2u32)), &clock);
+/// lim.check().unwrap();
+/// lim.check().unwrap();
+/// let response = lim.check().unwrap_err().to_response(clock.now());
+/// assert_eq!(response.status(), http::StatusCode::TOO_MANY_REQUESTS);
+/// assert_eq!(response.headers()["retry-after"], "1");
+/// ```
+#[cfg(feature = "http")]
+impl Rejection {
+    /// Returns the HTTP status of a rejected request: `429 Too Many Requests`.
+    pub fn status(&self) -> ::http::StatusCode {
+        ::http::StatusCode::TOO_MANY_REQUESTS
+    }
+
+    /// Returns the headers that describe the rejection to the client.
+    pub fn headers(&self) -> ::http::HeaderMap {
+        use ::http::header::{HeaderName, HeaderValue, RETRY_AFTER};
+
+        let retry_after = HeaderValue::from(self.retry_after_ms.div_ceil(1000));
+        let mut headers = ::http::HeaderMap::with_capacity(4);
+        headers.insert(RETRY_AFTER, retry_after.clone());
+        headers.insert(
+            HeaderName::from_static("ratelimit-limit"),
+            HeaderValue::from(self.limit),
+        );
+        headers.insert(
+            HeaderName::from_static("ratelimit-remaining"),
+            HeaderValue::from(0u32),
+        );
+        headers.insert(HeaderName::from_static("ratelimit-reset"), retry_after);
+        headers
+    }
+
+    /// Returns an empty response with the rejection's [status](#method.status) and
+    /// [headers](#method.headers), whose body can be filled in with
+    /// [`map`](http::Response::map).
+    pub fn to_response(&self) -> ::http::Response<()> {
+        let mut response = ::http::Response::new(());
+        *response.status_mut() = self.status();
+        *response.headers_mut() = self.headers();
+        response
+    }
+}
+
+#[cfg(feature = "http")]
+impl From<Rejection> for ::http::StatusCode {
+    fn from(rejection: Rejection) -> Self {
+        rejection.status()
+    }
+}
+
+#[cfg(feature = "http")]
+impl From<Rejection> for ::http::HeaderMap {
+    fn from(rejection: Rejection) -> Self {
+        rejection.headers()
+    }
+}
+
+#[cfg(feature = "http")]
+impl From<Rejection> for ::http::Response<()> {
+    fn from(rejection: Rejection) -> Self {
+        rejection.to_response()
+    }
+}
+
+#[cfg(feature = "http")]
+impl<P: clock::Reference> From<NotUntil<P>> for ::http::StatusCode {
+    fn from(_: NotUntil<P>) -> Self {
+        ::http::StatusCode::TOO_MANY_REQUESTS
+    }
+}
+
+#[cfg(feature = "serde")]
+impl serde::Serialize for Rejection {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        use serde::ser::SerializeStruct;
+
+        let mut s = serializer.serialize_struct("Rejection", 6)?;
+        s.serialize_field("retry_after_ms", &self.retry_after_ms)?;
+        s.serialize_field("limit", &self.limit)?;
+        s.serialize_field("window_ms", &self.window_ms)?;
+        s.serialize_field("burst", &self.burst)?;
+        match self.label {
+            Some(label) => s.serialize_field("label", label)?,
+            None => s.skip_field("label")?,
+        }
+        match self.key_hash {
+            Some(key_hash) => s.serialize_field("key_hash", &key_hash)?,
+            None => s.skip_field("key_hash")?,
+        }
+        s.end()
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod test {
+    use super::*;
+    use crate::clock::{Clock, FakeRelativeClock};
+    use crate::{Quota, RateLimiter};
+    use nonzero_ext::nonzero;
+
+    #[test]
+    fn describes_labelled_keyed_outcomes() {
+        let clock = FakeRelativeClock::default();
+        let quota = Quota::per_minute(nonzero!(3u32)).with_overdraft(1);
+        let lim = RateLimiter::hashmap_with_clock(quota, &clock).with_label("log\"ins\n\u{1}");
+        for _ in 0..4 {
+            lim.check_key(&"alice").unwrap();
+        }
+        clock.advance(Duration::from_nanos(1));
+        let rejection = lim
+            .check_key(&"alice")
+            .unwrap_err()
+            .rejection(clock.now())
+            .with_key(&"alice");
+        assert_eq!(rejection.retry_after_ms, 20_000);
+        assert_eq!(rejection.limit, 3);
+        assert_eq!(rejection.window_ms, 60_000);
+        assert_eq!(rejection.burst, 4);
+        let key_hash = crate::state::DecisionLog::key_hash(&"alice");
+        assert_eq!(rejection.key_hash, Some(key_hash));
+        assert_eq!(
+            rejection.to_json(),
+            format!(
+                r#"{{"retry_after_ms":20000,"limit":3,"window_ms":60000,"burst":4,"label":"log\"ins\n\u0001","key_hash":{}}}"#,
+                key_hash
+            )
+        );
+        #[cfg(feature = "serde")]
+        assert_eq!(
+            serde_json::to_string(&rejection).unwrap(),
+            rejection.to_json()
+        );
+    }
+
+    #[cfg(feature = "jitter")]
+    #[test]
+    fn adds_jitter_per_rejection() {
+        let clock = FakeRelativeClock::default();
+        let lim = RateLimiter::direct_with_clock(Quota::per_second(nonzero!(1u32)), &clock);
+        lim.check().unwrap();
+        let negative = lim.check().unwrap_err();
+        let rejection = negative.rejection(clock.now());
+        assert_eq!(rejection.retry_after_ms, 1_000);
+
+        let exact = Jitter::new(Duration::from_millis(250), Duration::ZERO);
+        assert_eq!(rejection.with_jitter(exact).retry_after_ms, 1_250);
+
+        let jitter = Jitter::up_to(Duration::from_secs(10));
+        let retries: Vec<u64> = (0..100)
+            .map(|_| rejection.with_jitter(jitter).retry_after_ms)
+            .collect();
+        assert!(retries.iter().all(|&ms| (1_000..=11_000).contains(&ms)));
+        assert!(retries.iter().any(|&ms| ms != retries[0]));
+        assert_eq!(
+            rejection.with_jitter(jitter).label,
+            rejection.label,
+            "jitter only affects the time to wait"
+        );
+    }
+
+    #[cfg(feature = "http")]
+    #[test]
+    fn turns_into_http_responses() {
+        let clock = FakeRelativeClock::default();
+        let lim = RateLimiter::direct_with_clock(Quota::per_minute(nonzero!(3u32)), &clock);
+        for _ in 0..3 {
+            lim.check().unwrap();
+        }
+        clock.advance(Duration::from_millis(500));
+        let negative = lim.check().unwrap_err();
+        let rejection = negative.rejection(clock.now());
+        assert_eq!(rejection.retry_after_ms, 19_500);
+
+        let response = http::Response::from(rejection);
+        assert_eq!(response.status(), http::StatusCode::TOO_MANY_REQUESTS);
+        let headers = response.headers();
+        assert_eq!(headers.len(), 4);
+        assert_eq!(headers[http::header::RETRY_AFTER], "20");
+        assert_eq!(headers["ratelimit-limit"], "3");
+        assert_eq!(headers["ratelimit-remaining"], "0");
+        assert_eq!(headers["ratelimit-reset"], "20");
+        assert_eq!(http::HeaderMap::from(rejection), *headers);
+        #[cfg(feature = "jitter")]
+        {
+            let jitter = Jitter::new(Duration::from_secs(2), Duration::ZERO);
+            let response = negative.to_response_with_jitter(clock.now(), jitter);
+            assert_eq!(response.headers()[http::header::RETRY_AFTER], "22");
+            assert_eq!(response.headers()["ratelimit-reset"], "22");
+        }
+        assert_eq!(
+            http::StatusCode::from(negative),
+            http::StatusCode::TOO_MANY_REQUESTS
+        );
+    }
+}
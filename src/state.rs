@@ -1,15 +1,95 @@
 //! State stores for rate limiters
 
-use std::{marker::PhantomData, prelude::v1::*};
+use crate::compat::prelude::*;
+use crate::sync::Arc;
+use std::{marker::PhantomData, time::Duration};
 
+#[cfg(feature = "std")]
+mod bounded_wait;
+#[cfg(feature = "std")]
+mod budget;
+#[cfg(feature = "std")]
+mod closable;
+#[cfg(feature = "std")]
+mod coalescing;
+#[cfg(feature = "std")]
+mod codel;
+mod compact;
+mod correlated;
 pub mod direct;
+mod dry_run;
+mod dynamic;
+mod epoch;
+#[cfg(all(unix, feature = "file-state"))]
+mod file;
 mod in_memory;
+mod interrupt;
 pub mod keyed;
+pub mod multi;
+mod paced;
+mod pressure;
+#[cfg(feature = "std")]
+mod recording;
+mod refresh_gate;
+mod resume;
+#[cfg(feature = "std")]
+mod retry;
+mod sampled;
+#[cfg(feature = "std")]
+mod shaper;
+// Portable-atomic's fallbacks for targets without 64-bit atomics lock within one process, so
+// they can't make the shared memory's decisions atomic across processes:
+#[cfg(all(unix, feature = "shared-memory", target_has_atomic = "64"))]
+mod shared_memory;
+mod tuning;
+#[cfg(feature = "std")]
+mod wait;
+mod waiters;
+mod wire;
 
+#[cfg(feature = "std")]
+pub use self::bounded_wait::{BoundedWaitLimiter, Overloaded, WaitBound};
+#[cfg(feature = "std")]
+pub use self::budget::{BudgetExceeded, Horizon, SendBudget};
+#[cfg(feature = "std")]
+pub use self::closable::{ClosableLimiter, Closed, Closer};
+#[cfg(feature = "std")]
+pub use self::coalescing::CoalescingLimiter;
+#[cfg(feature = "std")]
+pub use self::codel::CodelLimiter;
+pub use self::compact::CompactInMemoryState;
+pub use self::correlated::{Correlated, CorrelatedLimiter, DecisionAudit, DecisionId};
+pub use self::dry_run::DryRunLimiter;
+pub use self::dynamic::{AnyRateLimiter, DynNotUntil, DynRateLimiter};
+#[cfg(all(unix, feature = "file-state"))]
+pub use self::file::FileState;
 pub use self::in_memory::InMemoryState;
+pub use self::interrupt::InterruptSafeRateLimiter;
+pub use self::paced::PacedLimiter;
+#[cfg(feature = "std")]
+pub use self::recording::{DecisionLog, DecisionRecord, DecodeLogError, Outcome, RecordingLimiter};
+pub use self::refresh_gate::{Refresh, RefreshGate};
+#[cfg(feature = "std")]
+pub use self::resume::JumpPolicy;
+#[cfg(feature = "std")]
+pub use self::retry::RetryPolicy;
+pub use self::sampled::{SampledLimiter, Sampling};
+#[cfg(feature = "std")]
+pub use self::shaper::{Released, Shaper};
+#[cfg(all(unix, feature = "shared-memory", target_has_atomic = "64"))]
+pub use self::shared_memory::SharedMemoryState;
+#[cfg(feature = "std")]
+pub use self::wait::{Layer, Layered, Waitable};
+pub use self::wire::{WireFormat, WireStateError};
 
+pub(crate) use self::epoch::Epoch;
+pub(crate) use self::pressure::Pressure;
+pub(crate) use self::resume::Resume;
+use self::tuning::TuningCell;
+pub(crate) use self::waiters::Waiters;
+use crate::clock;
 use crate::nanos::Nanos;
-use crate::{clock, Quota};
+use crate::Quota;
 use crate::{
     gcra::Gcra,
     middleware::{NoOpMiddleware, RateLimitingMiddleware},
@@ -43,9 +123,115 @@ pub trait StateStore {
     /// It is `measure_and_replace`'s job then to safely replace the value at the key - it must
     /// only update the value if the value hasn't changed. The implementations in this
     /// crate use `AtomicU64` operations for this.
+    ///
+    /// With the `testing` feature, the
+    /// [`state_store_contract_tests!`](../macro.state_store_contract_tests.html) macro generates
+    /// tests that check an implementation against these requirements.
     fn measure_and_replace<T, F, E>(&self, key: &Self::Key, f: F) -> Result<T, E>
     where
         F: Fn(Option<Nanos>) -> Result<(T, Nanos), E>;
+
+    /// Returns the state at the key's location (`None` if there wasn't a measurement yet),
+    /// without changing it.
+    ///
+    /// Rate limiters use this for queries that aren't decisions, like
+    /// [`saturation`](RateLimiter::saturation). The default implementation is a
+    /// `measure_and_replace` that doesn't replace the state; wrapping state stores that observe
+    /// decisions override it, so that they can tell queries apart from negative decisions.
+    fn peek(&self, key: &Self::Key) -> Option<Nanos> {
+        match self.measure_and_replace(key, Err::<((), Nanos), _>) {
+            Ok(()) => None,
+            Err(state) => state,
+        }
+    }
+
+    /// Replaces the state at the key's location with the one that `f` computes from it (`None`
+    /// if there wasn't a measurement yet), or leaves it unchanged if `f` returns `None`.
+    ///
+    /// Rate limiters use this for updates that aren't decisions, like
+    /// [refunds](crate::MultiLimiter) and seeded states. The default implementation is a
+    /// `measure_and_replace`; wrapping state stores that observe decisions override it, so
+    /// that they can tell these updates apart from decisions.
+    fn adjust<F>(&self, key: &Self::Key, f: F)
+    where
+        F: Fn(Option<Nanos>) -> Option<Nanos>,
+    {
+        let _: Result<(), ()> =
+            self.measure_and_replace(key, |tat| f(tat).map(|tat| ((), tat)).ok_or(()));
+    }
+
+    /// Returns how far ahead of a decision's time the states that the store keeps may run (at
+    /// least for decisions right after a rate limiter's start), if the store keeps states in
+    /// a limited range, like the compact ones.
+    ///
+    /// States are in the units that rate limiters store them in: nanoseconds, times the
+    /// quota's number of cells per period. [`RateLimiter::new`] refuses quotas whose states
+    /// may run further ahead. The default implementation has no limit.
+    fn reach(&self) -> Option<Nanos> {
+        None
+    }
+}
+
+/// State stores can be shared between multiple rate limiters by wrapping them in an [`Arc`].
+///
+/// This is what allows [`RateLimiter::into_shared`] to construct rate limiters that can be
+/// cheaply cloned.
+impl<S: StateStore> StateStore for Arc<S> {
+    type Key = S::Key;
+
+    #[inline]
+    fn measure_and_replace<T, F, E>(&self, key: &Self::Key, f: F) -> Result<T, E>
+    where
+        F: Fn(Option<Nanos>) -> Result<(T, Nanos), E>,
+    {
+        (**self).measure_and_replace(key, f)
+    }
+
+    #[inline]
+    fn peek(&self, key: &Self::Key) -> Option<Nanos> {
+        (**self).peek(key)
+    }
+
+    fn adjust<F>(&self, key: &Self::Key, f: F)
+    where
+        F: Fn(Option<Nanos>) -> Option<Nanos>,
+    {
+        (**self).adjust(key, f)
+    }
+
+    fn reach(&self) -> Option<Nanos> {
+        (**self).reach()
+    }
+}
+
+/// Makes the decision that `f` computes against states ever further ahead of `created` (the
+/// state that the decision would store), until one is negative: This finds a state that uses
+/// up (up to) the whole burst capacity without knowing the quota, so that state stores can
+/// deny a decision that they can't make or store.
+///
+/// Returns the outcome of the decision on the latest state if it can't be negative.
+#[cfg(feature = "std")]
+pub(crate) fn saturated<T, F, E>(f: &F, created: Nanos) -> Result<T, E>
+where
+    F: Fn(Option<Nanos>) -> Result<(T, Nanos), E>,
+{
+    // Keeps the states far enough from overflowing when decisions add to them:
+    const LATEST: u64 = u64::MAX / 4;
+    let mut step = 0;
+    let mut tat = created;
+    loop {
+        let (outcome, next) = f(Some(tat))?;
+        if tat.as_u64() >= LATEST {
+            // Decisions that can't be negative, e.g. on arrivals, are made on the latest state:
+            return Ok(outcome);
+        }
+        step = if step == 0 {
+            next.as_u64().saturating_sub(tat.as_u64()).max(1)
+        } else {
+            step.saturating_mul(2)
+        };
+        tat = Nanos::from(tat.as_u64().saturating_add(step).min(LATEST));
+    }
 }
 
 /// A rate limiter.
@@ -64,9 +250,45 @@ where
     gcra: Gcra,
     clock: C,
     start: C::Instant,
+    tuning: TuningCell,
     middleware: PhantomData<MW>,
 }
 
+/// A direct (un-keyed) in-memory rate limiter using the default clock, as constructed by
+/// [`RateLimiter::direct`].
+///
+/// This names the rate limiter's type without spelling out all its parameters, e.g. in struct
+/// fields:
+/// ```rust
+/// use governor::DefaultDirectRateLimiter;
+///
+/// struct Client {
+///     limiter: DefaultDirectRateLimiter,
+/// }
+/// ```
+pub type DefaultDirectRateLimiter<MW = NoOpMiddleware> =
+    RateLimiter<NotKeyed, InMemoryState, clock::DefaultClock, MW>;
+
+/// A keyed in-memory rate limiter using the default clock and the
+/// [`DefaultKeyedStateStore`][keyed::DefaultKeyedStateStore], as constructed by
+/// [`RateLimiter::keyed`].
+pub type DefaultKeyedRateLimiter<K, MW = NoOpMiddleware> =
+    RateLimiter<K, keyed::DefaultKeyedStateStore<K>, clock::DefaultClock, MW>;
+
+/// A keyed in-memory rate limiter using the
+/// [`DefaultKeyedStateStore`][keyed::DefaultKeyedStateStore] and any clock, as constructed by
+/// [`RateLimiter::keyed_with_clock`]. With the default clock, this is a
+/// [`DefaultKeyedRateLimiter`].
+///
+/// Rate limiters backed by other state stores have aliases of their own, e.g.
+/// [`HashMapKeyedLimiter`][keyed::HashMapKeyedLimiter] and
+/// [`EnumKeyedLimiter`][keyed::EnumKeyedLimiter].
+pub type KeyedRateLimiter<
+    K,
+    C = clock::DefaultClock,
+    MW = NoOpMiddleware<<C as clock::Clock>::Instant>,
+> = RateLimiter<K, keyed::DefaultKeyedStateStore<K>, C, MW>;
+
 impl<K, S, C, MW> RateLimiter<K, S, C, MW>
 where
     S: StateStore<Key = K>,
@@ -77,8 +299,21 @@ where
     ///
     /// This is the most generic way to construct a rate-limiter; most users should prefer
     /// [`direct`] or other methods instead.
+    ///
+    /// # Panics
+    /// Panics if the state store can't keep states as far ahead as the quota needs (see
+    /// [`StateStore::reach`]): e.g. a compact state whose resolution is too fine for a quota
+    /// that replenishes its burst capacity slowly.
+    #[track_caller]
     pub fn new(quota: Quota, state: S, clock: &C) -> Self {
         let gcra = Gcra::new(quota);
+        if let Some(reach) = state.reach() {
+            assert!(
+                gcra.ahead() <= reach,
+                "the state store can't keep states far enough ahead for {:?}",
+                quota
+            );
+        }
         let start = clock.now();
         let clock = clock.clone();
         RateLimiter {
@@ -86,6 +321,7 @@ where
             clock,
             gcra,
             start,
+            tuning: TuningCell::new(),
             middleware: PhantomData,
         }
     }
@@ -114,6 +350,142 @@ where
             gcra: self.gcra,
             clock: self.clock,
             start: self.start,
+            tuning: self.tuning,
+        }
+    }
+}
+
+/// # Labelling rate limiters
+///
+/// A service that checks several rate limiters can label each of them, so that it can tell
+/// which limit fired: The label shows up in the [`Display`](core::fmt::Display) of negative
+/// outcomes ([`NotUntil`](crate::NotUntil)), and in the [`StateSnapshot`](crate::middleware::StateSnapshot)s
+/// that middleware (e.g. middleware that records metrics) receives.
+impl<K, S, C, MW> RateLimiter<K, S, C, MW>
+where
+    S: StateStore<Key = K>,
+    C: clock::Clock,
+    MW: RateLimitingMiddleware<C::Instant>,
+{
+    /// Labels the rate limiter with a name for its decisions.
+    ///
+    /// ```rust
+    /// # #[cfg(feature = "std")] fn main() {
+    /// # use nonzero_ext::*;
+    /// # use governor::{RateLimiter, Quota};
+    /// let lim = RateLimiter::direct(Quota::per_minute(nonzero!(1u32))).with_label("logins");
+    /// assert_eq!(lim.label(), Some("logins"));
+    /// assert_eq!(Ok(()), lim.check());
+    /// let negative = lim.check().unwrap_err();
+    /// assert_eq!(negative.label(), Some("logins"));
+    /// assert!(negative.to_string().starts_with("rate-limited by logins until "));
+    /// # } #[cfg(not(feature = "std"))] fn main() {}
+    /// ```
+    pub fn with_label(mut self, label: &'static str) -> Self {
+        self.gcra = self.gcra.with_label(Some(label));
+        self
+    }
+
+    /// Returns the rate limiter's label, if it has one.
+    pub fn label(&self) -> Option<&'static str> {
+        self.gcra.label()
+    }
+}
+
+/// # Rounding wait times
+///
+/// Clients that are told to retry at the exact moment that capacity frees up tend to retry in
+/// lockstep. A rate limiter can round the wait times that it reports up to a granularity
+/// instead, e.g. to whole seconds for `Retry-After` headers, or to 10ms for internal retries:
+/// The rounding applies to [`NotUntil::wait_time_from`](crate::NotUntil::wait_time_from), and
+/// so to everything that reports or waits for a negative outcome's wait time, like
+/// [rejections](crate::Rejection) and the `async` waiting methods.
+impl<K, S, C, MW> RateLimiter<K, S, C, MW>
+where
+    S: StateStore<Key = K>,
+    C: clock::Clock,
+    MW: RateLimitingMiddleware<C::Instant>,
+{
+    /// Rounds the wait times that the rate limiter reports up to multiples of `granularity`.
+    /// A zero granularity turns rounding off again.
+    ///
+    /// ```rust
+    /// # use nonzero_ext::*;
+    /// use governor::{clock::{Clock, FakeRelativeClock}, Quota, RateLimiter};
+    /// use std::time::Duration;
+    ///
+    /// let clock = FakeRelativeClock::default();
+    /// let lim = RateLimiter::direct_with_clock(Quota::per_second(nonzero!(4u32)), &clock)
+    ///     .with_wait_granularity(Duration::from_millis(100));
+    /// lim.check_n(nonzero!(4u32)).unwrap();
+    /// clock.advance(Duration::from_millis(1));
+    /// let negative = lim.check().unwrap_err();
+    /// assert_eq!(negative.wait_time_from(clock.now()), Duration::from_millis(300));
+    /// ```
+    pub fn with_wait_granularity(mut self, granularity: Duration) -> Self {
+        self.gcra = self.gcra.with_granularity(granularity.into());
+        self
+    }
+
+    /// Returns the granularity that the rate limiter rounds wait times up to, if it has one.
+    pub fn wait_granularity(&self) -> Option<Duration> {
+        self.gcra.granularity()
+    }
+}
+
+/// # Sharing rate limiters
+///
+/// Rate limiters whose state store lives behind an [`Arc`] implement [`Clone`]. Clones are
+/// cheap, and share their rate-limiting state with each other, so they can be handed to spawned
+/// threads or tasks without worrying about the original limiter's lifetime.
+impl<K, S, C, MW> RateLimiter<K, S, C, MW>
+where
+    S: StateStore<Key = K>,
+    C: clock::Clock,
+    MW: RateLimitingMiddleware<C::Instant>,
+{
+    /// Converts the rate limiter into one that keeps its state store in an [`Arc`], which can
+    /// be cloned cheaply.
+    ///
+    /// ```rust
+    /// # #[cfg(feature = "std")] fn main() {
+    /// # use nonzero_ext::*;
+    /// # use governor::{RateLimiter, Quota};
+    /// # use std::thread;
+    /// let lim = RateLimiter::direct(Quota::per_second(nonzero!(20u32))).into_shared();
+    /// let handle = thread::spawn({
+    ///     let lim = lim.clone();
+    ///     move || assert_eq!(Ok(()), lim.check())
+    /// });
+    /// handle.join().unwrap();
+    /// # } #[cfg(not(feature = "std"))] fn main() {}
+    /// ```
+    pub fn into_shared(self) -> RateLimiter<K, Arc<S>, C, MW> {
+        RateLimiter {
+            middleware: PhantomData,
+            state: Arc::new(self.state),
+            gcra: self.gcra,
+            clock: self.clock,
+            start: self.start,
+            tuning: self.tuning,
+        }
+    }
+}
+
+impl<K, S, C, MW> Clone for RateLimiter<K, Arc<S>, C, MW>
+where
+    S: StateStore<Key = K>,
+    C: clock::Clock,
+    MW: RateLimitingMiddleware<C::Instant>,
+{
+    fn clone(&self) -> Self {
+        RateLimiter {
+            middleware: PhantomData,
+            state: Arc::clone(&self.state),
+            gcra: self.gcra,
+            clock: self.clock.clone(),
+            start: self.start,
+            tuning: self.share_tuning(),
         }
     }
 }
@@ -130,6 +502,66 @@ where
     }
 }
 
+#[cfg(feature = "testing")]
+impl<K, S, C, MW> RateLimiter<K, S, C, MW>
+where
+    S: StateStore<Key = K>,
+    C: clock::Clock,
+    MW: RateLimitingMiddleware<C::Instant>,
+{
+    pub(crate) fn quota(&self) -> Quota {
+        self.gcra.quota()
+    }
+
+    pub(crate) fn clock(&self) -> &C {
+        &self.clock
+    }
+
+    pub(crate) fn start(&self) -> C::Instant {
+        self.start
+    }
+}
+
+#[cfg(feature = "std")]
+impl<K, S, C, MW> RateLimiter<K, S, C, MW>
+where
+    S: StateStore<Key = K>,
+    C: clock::Clock,
+    MW: RateLimitingMiddleware<C::Instant>,
+{
+    /// Checks a single cell for `key` as if it arrived at `t0`, the way `check_key` would.
+    pub(crate) fn check_key_at(
+        &self,
+        key: &K,
+        t0: C::Instant,
+    ) -> Result<MW::PositiveOutcome, MW::NegativeOutcome> {
+        self.gcra()
+            .test_and_update::<K, C::Instant, S, MW>(self.start, key, &self.state, t0)
+    }
+
+    /// Checks `n` cells for `key` as if they arrived at `t0`, the way `check_key` (for a single
+    /// cell) or `check_key_n` would.
+    pub(crate) fn check_key_n_at(
+        &self,
+        key: &K,
+        n: std::num::NonZeroU32,
+        t0: C::Instant,
+    ) -> Result<MW::PositiveOutcome, crate::NegativeMultiDecision<MW::NegativeOutcome>> {
+        if n.get() == 1 {
+            self.check_key_at(key, t0)
+                .map_err(|negative| crate::NegativeMultiDecision::BatchNonConforming(1, negative))
+        } else {
+            self.gcra().test_n_all_and_update::<K, C::Instant, S, MW>(
+                self.start,
+                key,
+                n,
+                &self.state,
+                t0,
+            )
+        }
+    }
+}
+
 #[cfg(all(feature = "std", test))]
 mod test {
     use super::*;
@@ -139,6 +571,6 @@ mod test {
     #[test]
     fn ratelimiter_impl_coverage() {
         let lim = RateLimiter::direct(Quota::per_second(nonzero!(3u32)));
-        assert!(format!("{:?}", lim).len() > 0);
+        assert!(!format!("{:?}", lim).is_empty());
     }
 }
@@ -0,0 +1,252 @@
+//! Rate limiters that bound how many tasks may wait for them.
+
+use crate::compat::prelude::*;
+
+use crate::clock;
+use crate::compat::collections::HashMap;
+use crate::middleware::RateLimitingMiddleware;
+use crate::state::wait::{sealed, Count, Layer, Layered, Waitable};
+use crate::state::{NotKeyed, StateStore};
+use crate::sync::Mutex;
+use crate::RateLimiter;
+use std::error::Error;
+use std::fmt;
+use std::hash::Hash;
+
+/// An error that occurs when a task would have to wait for a
+/// [`BoundedWaitLimiter`], but as many tasks as it allows are waiting already.
+///
+/// The argument is the largest number of tasks that may wait at once (for the same key, in a
+/// keyed rate limiter).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Overloaded(pub usize);
+
+impl Overloaded {
+    /// Returns the largest number of tasks that may wait at once.
+    pub fn max_waiting(&self) -> usize {
+        self.0
+    }
+}
+
+impl fmt::Display for Overloaded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "rate limiter is overloaded with {} waiting tasks",
+            self.0
+        )
+    }
+}
+
+impl Error for Overloaded {}
+
+/// A rate limiter that bounds the number of tasks that wait for it at the same time.
+///
+/// Waiting for a rate limiter with [`until_ready`](RateLimiter::until_ready) queues up the
+/// waiting tasks without bound: During an incast storm, each of them holds on to its memory
+/// (e.g. a request and its connection) until the rate limiter lets it through, which can take
+/// arbitrarily long. A `BoundedWaitLimiter` lets at most `max_waiting` tasks wait at once (per
+/// key, for keyed rate limiters); any further task that would have to wait gets an immediate
+/// [`Overloaded`] error instead, and can shed its load. Tasks that the rate limiter allows
+/// right away never count as waiting.
+///
+/// A task stops counting as waiting once the rate limiter lets it through, or once its future
+/// is dropped. The bound is a [layer](Layered) on top of a rate limiter, or of other layers
+/// like a [`ClosableLimiter`](crate::state::ClosableLimiter).
+///
+/// # Example
+/// ```rust
+/// # use nonzero_ext::*;
+/// # use futures::executor::block_on;
+/// use governor::{state::Overloaded, Quota, RateLimiter};
+///
+/// let lim = RateLimiter::direct(Quota::per_second(nonzero!(1u32))).into_bounded_wait(0);
+/// assert_eq!(block_on(lim.until_ready()), Ok(()));
+/// // Nobody may wait, so the next task is turned away:
+/// assert_eq!(block_on(lim.until_ready()), Err(Overloaded(0)));
+/// ```
+pub type BoundedWaitLimiter<L> = Layered<L, WaitBound<<L as Waitable>::Key>>;
+
+/// The [layer](Layered) of a [`BoundedWaitLimiter`], which counts the waiting tasks.
+pub struct WaitBound<K> {
+    max_waiting: usize,
+    /// The number of tasks waiting for a direct rate limiter.
+    waiting: Count,
+    /// The number of tasks waiting for each key of a keyed rate limiter.
+    waiting_for: Mutex<HashMap<K, usize>>,
+}
+
+impl<K> sealed::Sealed for WaitBound<K> {}
+
+impl Layer<NotKeyed> for WaitBound<NotKeyed> {
+    type Error = Overloaded;
+
+    fn enter(&self, _key: &NotKeyed) -> Result<(), Overloaded> {
+        if self.waiting.enter(self.max_waiting) {
+            Ok(())
+        } else {
+            Err(Overloaded(self.max_waiting))
+        }
+    }
+
+    fn leave(&self, _key: &NotKeyed) {
+        self.waiting.leave();
+    }
+}
+
+impl<K: Hash + Eq + Clone> Layer<K> for WaitBound<K> {
+    type Error = Overloaded;
+
+    fn enter(&self, key: &K) -> Result<(), Overloaded> {
+        let mut waiting_for = self.waiting_for.lock();
+        let waiting = waiting_for.get(key).copied().unwrap_or(0);
+        if waiting >= self.max_waiting {
+            return Err(Overloaded(self.max_waiting));
+        }
+        waiting_for.insert(key.clone(), waiting + 1);
+        Ok(())
+    }
+
+    fn leave(&self, key: &K) {
+        let mut waiting_for = self.waiting_for.lock();
+        if let Some(count) = waiting_for.get_mut(key) {
+            *count -= 1;
+            if *count == 0 {
+                waiting_for.remove(key);
+            }
+        }
+    }
+}
+
+impl<K> fmt::Debug for WaitBound<K> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("WaitBound")
+            .field("max_waiting", &self.max_waiting)
+            .finish()
+    }
+}
+
+impl<L: Waitable> BoundedWaitLimiter<L>
+where
+    WaitBound<L::Key>: Layer<L::Key>,
+{
+    /// Wraps a rate limiter, or other layers on top of one, letting at most `max_waiting`
+    /// tasks wait for it at once.
+    pub fn new(inner: L, max_waiting: usize) -> Self {
+        Layered {
+            inner,
+            layer: WaitBound {
+                max_waiting,
+                waiting: Count::default(),
+                waiting_for: Mutex::new(HashMap::new()),
+            },
+        }
+    }
+
+    /// Returns the largest number of tasks that may wait at once.
+    pub fn max_waiting(&self) -> usize {
+        self.layer.max_waiting
+    }
+}
+
+impl<L: Waitable<Key = NotKeyed>> BoundedWaitLimiter<L> {
+    /// Returns the number of tasks that are waiting for the rate limiter.
+    pub fn waiting(&self) -> usize {
+        self.layer.waiting.get()
+    }
+}
+
+impl<K, L> BoundedWaitLimiter<L>
+where
+    L: Waitable<Key = K>,
+    K: Hash + Eq + Clone,
+{
+    /// Returns the number of tasks that are waiting for the given key.
+    pub fn waiting_for(&self, key: &K) -> usize {
+        self.layer.waiting_for.lock().get(key).copied().unwrap_or(0)
+    }
+}
+
+/// # Bounded-wait rate limiters
+impl<K, S, C, MW> RateLimiter<K, S, C, MW>
+where
+    S: StateStore<Key = K>,
+    C: clock::Clock,
+    MW: RateLimitingMiddleware<C::Instant>,
+    WaitBound<K>: Layer<K>,
+{
+    /// Wraps the rate limiter in a [`BoundedWaitLimiter`] that lets at most `max_waiting`
+    /// tasks wait for it at once (per key, for keyed rate limiters).
+    pub fn into_bounded_wait(self, max_waiting: usize) -> BoundedWaitLimiter<Self> {
+        BoundedWaitLimiter::new(self, max_waiting)
+    }
+}
+
+impl<L, Y> Layered<L, Y>
+where
+    L: Waitable,
+    Y: Layer<L::Key>,
+    WaitBound<L::Key>: Layer<L::Key>,
+{
+    /// Adds a [`BoundedWaitLimiter`] layer on top, which lets at most `max_waiting` tasks wait
+    /// at once (per key, for keyed rate limiters).
+    pub fn into_bounded_wait(self, max_waiting: usize) -> BoundedWaitLimiter<Self> {
+        BoundedWaitLimiter::new(self, max_waiting)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::state::direct::InsufficientCapacity;
+    use crate::Quota;
+    use futures::executor::block_on;
+    use futures::{pin_mut, poll};
+    use nonzero_ext::nonzero;
+
+    #[test]
+    fn rejects_waiters_beyond_the_bound() {
+        let lim = RateLimiter::direct(Quota::per_second(nonzero!(1u32))).into_bounded_wait(1);
+        assert_eq!(lim.max_waiting(), 1);
+        block_on(async {
+            assert_eq!(lim.until_ready().await, Ok(()));
+            let first = lim.until_ready();
+            pin_mut!(first);
+            assert!(poll!(first.as_mut()).is_pending());
+            assert_eq!(lim.waiting(), 1);
+            assert_eq!(lim.until_ready().await, Err(Overloaded(1)));
+            assert_eq!(
+                lim.until_n_ready(nonzero!(2u32)).await,
+                Ok(Err(InsufficientCapacity(1)))
+            );
+            assert_eq!(lim.until_n_ready(nonzero!(1u32)).await, Err(Overloaded(1)));
+        });
+        // The dropped waiter no longer counts:
+        assert_eq!(lim.waiting(), 0);
+        assert!(!format!("{:?}", lim).is_empty());
+        assert!(!format!("{}", Overloaded(1)).is_empty());
+    }
+
+    #[test]
+    fn bounds_waiters_per_key() {
+        let quota = Quota::per_second(nonzero!(10u32)).allow_burst(nonzero!(1u32));
+        let lim = RateLimiter::keyed(quota).into_bounded_wait(1);
+        block_on(async {
+            assert_eq!(lim.until_key_ready(&"a").await, Ok(()));
+            assert_eq!(lim.until_key_ready(&"b").await, Ok(()));
+            let first = lim.until_key_ready(&"a");
+            pin_mut!(first);
+            assert!(poll!(first.as_mut()).is_pending());
+            assert_eq!(lim.waiting_for(&"a"), 1);
+            assert_eq!(
+                lim.until_key_n_ready(&"a", nonzero!(1u32)).await,
+                Err(Overloaded(1))
+            );
+            // Other keys have their own waiters:
+            assert_eq!(lim.until_key_ready(&"b").await, Ok(()));
+            assert_eq!(lim.waiting_for(&"b"), 0);
+        });
+        assert_eq!(lim.waiting_for(&"a"), 0);
+        assert_eq!(lim.into_inner().len(), 2);
+    }
+}
@@ -0,0 +1,380 @@
+//! Send budgets that combine pacing with hourly and daily calendar quotas.
+
+use crate::compat::prelude::*;
+
+use crate::clock::{self, Reference, SystemClock};
+use crate::middleware::NoOpMiddleware;
+use crate::nanos::Nanos;
+use crate::state::direct::InsufficientCapacity;
+use crate::state::{InMemoryState, NotKeyed};
+use crate::sync::Mutex;
+use crate::{NegativeMultiDecision, NotUntil, Quota, RateLimiter};
+use futures_timer::Delay;
+use nonzero_ext::nonzero;
+use std::fmt;
+use std::num::NonZeroU32;
+use std::time::{Duration, SystemTime};
+
+/// A calendar window of a [`SendBudget`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Horizon {
+    /// A calendar hour, starting on the full hour.
+    Hour,
+    /// A calendar day, starting at midnight.
+    Day,
+}
+
+impl Horizon {
+    fn length(self) -> Nanos {
+        match self {
+            Horizon::Hour => Nanos::from(Duration::from_secs(60 * 60)),
+            Horizon::Day => Nanos::from(Duration::from_secs(24 * 60 * 60)),
+        }
+    }
+}
+
+/// The reason that a [`SendBudget`] didn't let messages through.
+#[derive(Debug, PartialEq)]
+pub enum BudgetExceeded<P: clock::Reference> {
+    /// The messages would have been sent faster than the budget's pacing allows.
+    Paced(NotUntil<P>),
+
+    /// The budget of the current calendar window is used up.
+    Window {
+        /// The window whose budget is used up.
+        horizon: Horizon,
+        /// The instant at which the next window starts.
+        resets_at: P,
+    },
+
+    /// More messages were sent at once than the budget can ever let through at once.
+    InsufficientCapacity(u32),
+}
+
+impl<P: clock::Reference> BudgetExceeded<P> {
+    /// Returns the earliest instant at which the messages could be let through, or `None` if
+    /// they never can.
+    ///
+    /// This only accounts for the limit that denied them: Another limit may still deny them
+    /// then.
+    pub fn earliest_possible(&self) -> Option<P> {
+        match self {
+            BudgetExceeded::Paced(not_until) => Some(not_until.earliest_possible()),
+            BudgetExceeded::Window { resets_at, .. } => Some(*resets_at),
+            BudgetExceeded::InsufficientCapacity(_) => None,
+        }
+    }
+
+    /// Returns how long to wait from `from` until the messages could be let through, or `None`
+    /// if they never can.
+    pub fn wait_time_from(&self, from: P) -> Option<Duration> {
+        self.earliest_possible()
+            .map(|earliest| earliest.duration_since(from).into())
+    }
+}
+
+impl<P: clock::Reference> fmt::Display for BudgetExceeded<P> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BudgetExceeded::Paced(not_until) => not_until.fmt(f),
+            BudgetExceeded::Window { horizon, resets_at } => {
+                write!(f, "{:?} budget used up until {:?}", horizon, resets_at)
+            }
+            BudgetExceeded::InsufficientCapacity(capacity) => write!(
+                f,
+                "required number of messages exceeds the budget's capacity of {}",
+                capacity
+            ),
+        }
+    }
+}
+
+impl<P: clock::Reference> std::error::Error for BudgetExceeded<P> {}
+
+/// The usage of a calendar window.
+#[derive(Debug, Clone)]
+struct Window {
+    horizon: Horizon,
+    limit: u32,
+    /// The number of windows between the budget's epoch and this one.
+    index: u64,
+    used: u32,
+}
+
+impl Window {
+    fn roll(&mut self, elapsed: Nanos) {
+        let index = elapsed.as_u64() / self.horizon.length().as_u64();
+        if index != self.index {
+            self.index = index;
+            self.used = 0;
+        }
+    }
+}
+
+/// A budget for sending messages (like emails or text messages) that combines pacing with
+/// hourly and daily calendar quotas, as email and SMS providers impose them.
+///
+/// Messages are paced with a rate limiter, e.g. to 30 a minute, and additionally counted
+/// against fixed calendar windows, e.g. 500 per hour (resetting on the full hour) and 2000 per
+/// day (resetting at midnight UTC). Messages are only let through if every limit allows them,
+/// and then count against all of them: A check with all limits is atomic, so a message denied
+/// by one limit doesn't use up another limit's budget.
+///
+/// Calendar windows are aligned to an *epoch*: the UNIX epoch by default, making hours and days
+/// start at full hours and midnight in UTC. Pass another epoch to
+/// [`with_clock`](#method.with_clock) to align days to midnight in another timezone.
+///
+/// # Example
+/// ```rust
+/// # use nonzero_ext::*;
+/// use governor::{state::SendBudget, Quota};
+///
+/// let budget = SendBudget::new(Quota::per_minute(nonzero!(30u32)))
+///     .per_hour(nonzero!(500u32))
+///     .per_day(nonzero!(2000u32));
+/// assert!(budget.check_n(nonzero!(10u32)).is_ok());
+/// assert_eq!(budget.remaining(governor::state::Horizon::Hour), Some(490));
+/// ```
+pub struct SendBudget<C: clock::Clock = SystemClock> {
+    clock: C,
+    epoch: C::Instant,
+    pacing: RateLimiter<NotKeyed, InMemoryState, C, NoOpMiddleware<C::Instant>>,
+    windows: Mutex<Vec<Window>>,
+}
+
+impl SendBudget<SystemClock> {
+    /// Constructs a budget that paces messages with the given quota, on the system's wall
+    /// clock, with calendar windows in UTC.
+    pub fn new(pacing: Quota) -> Self {
+        SendBudget::with_clock(pacing, &SystemClock, SystemTime::UNIX_EPOCH)
+    }
+}
+
+impl<C: clock::Clock> SendBudget<C> {
+    /// Constructs a budget that paces messages with the given quota, on the given clock, with
+    /// calendar windows that start at `epoch` (which should be a midnight).
+    pub fn with_clock(pacing: Quota, clock: &C, epoch: C::Instant) -> Self {
+        SendBudget {
+            clock: clock.clone(),
+            epoch,
+            pacing: RateLimiter::direct_with_clock(pacing, clock),
+            windows: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Limits the number of messages per calendar hour.
+    pub fn per_hour(self, limit: NonZeroU32) -> Self {
+        self.with_window(Horizon::Hour, limit)
+    }
+
+    /// Limits the number of messages per calendar day.
+    pub fn per_day(self, limit: NonZeroU32) -> Self {
+        self.with_window(Horizon::Day, limit)
+    }
+
+    fn with_window(self, horizon: Horizon, limit: NonZeroU32) -> Self {
+        {
+            let mut windows = self.windows.lock();
+            windows.retain(|window| window.horizon != horizon);
+            windows.push(Window {
+                horizon,
+                limit: limit.get(),
+                index: 0,
+                used: 0,
+            });
+        }
+        self
+    }
+
+    /// Returns the rate limiter that paces messages.
+    pub fn pacing(&self) -> &RateLimiter<NotKeyed, InMemoryState, C, NoOpMiddleware<C::Instant>> {
+        &self.pacing
+    }
+
+    /// Returns the number of messages left in the current calendar window, or `None` if the
+    /// budget doesn't limit the window.
+    pub fn remaining(&self, horizon: Horizon) -> Option<u32> {
+        let elapsed = self.clock.now().duration_since(self.epoch);
+        let mut windows = self.windows.lock();
+        let window = windows
+            .iter_mut()
+            .find(|window| window.horizon == horizon)?;
+        window.roll(elapsed);
+        Some(window.limit - window.used)
+    }
+
+    /// Lets a single message through, if all limits allow it.
+    pub fn check(&self) -> Result<(), BudgetExceeded<C::Instant>> {
+        self.check_n(nonzero!(1u32))
+    }
+
+    /// Lets *only all* `n` messages through, if all limits allow them.
+    pub fn check_n(&self, n: NonZeroU32) -> Result<(), BudgetExceeded<C::Instant>> {
+        let now = self.clock.now();
+        let elapsed = now.duration_since(self.epoch);
+        let mut windows = self.windows.lock();
+        for window in windows.iter_mut() {
+            window.roll(elapsed);
+            if n.get() > window.limit {
+                return Err(BudgetExceeded::InsufficientCapacity(window.limit));
+            }
+            if window.used + n.get() > window.limit {
+                let length = window.horizon.length();
+                return Err(BudgetExceeded::Window {
+                    horizon: window.horizon,
+                    resets_at: self.epoch + length * (window.index + 1),
+                });
+            }
+        }
+        match self.pacing.check_n(n) {
+            Ok(()) => {}
+            Err(NegativeMultiDecision::BatchNonConforming(_, not_until)) => {
+                return Err(BudgetExceeded::Paced(not_until))
+            }
+            Err(NegativeMultiDecision::InsufficientCapacity(capacity)) => {
+                return Err(BudgetExceeded::InsufficientCapacity(capacity))
+            }
+        }
+        for window in windows.iter_mut() {
+            window.used += n.get();
+        }
+        Ok(())
+    }
+}
+
+/// # Send budgets - `async`/`await`
+impl<C: clock::ReasonablyRealtime> SendBudget<C> {
+    /// Asynchronously resolves as soon as all limits let `n` messages through.
+    ///
+    /// Returns `InsufficientCapacity` right away if the `n` provided exceeds a limit, as
+    /// waiting would never succeed.
+    pub async fn until_n_ready(&self, n: NonZeroU32) -> Result<(), InsufficientCapacity> {
+        loop {
+            match self.check_n(n) {
+                Ok(()) => return Ok(()),
+                Err(BudgetExceeded::InsufficientCapacity(capacity)) => {
+                    return Err(InsufficientCapacity(capacity))
+                }
+                Err(exceeded) => {
+                    let wait = exceeded
+                        .wait_time_from(self.clock.now())
+                        .unwrap_or_default();
+                    Delay::new(wait).await;
+                }
+            }
+        }
+    }
+}
+
+impl<C: clock::Clock> fmt::Debug for SendBudget<C>
+where
+    RateLimiter<NotKeyed, InMemoryState, C, NoOpMiddleware<C::Instant>>: fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SendBudget")
+            .field("epoch", &self.epoch)
+            .field("pacing", &self.pacing)
+            .field("windows", &*self.windows.lock())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::clock::{Clock, FakeRelativeClock};
+    use futures::executor::block_on;
+    use nonzero_ext::nonzero;
+
+    fn budget(clock: &FakeRelativeClock) -> SendBudget<FakeRelativeClock> {
+        SendBudget::with_clock(Quota::per_minute(nonzero!(10u32)), clock, clock.now())
+            .per_hour(nonzero!(15u32))
+            .per_day(nonzero!(20u32))
+    }
+
+    #[test]
+    fn checks_all_horizons() {
+        let clock = FakeRelativeClock::default();
+        let budget = budget(&clock);
+        assert!(budget.check_n(nonzero!(10u32)).is_ok());
+        assert!(matches!(budget.check(), Err(BudgetExceeded::Paced(_))));
+        // A denied message doesn't count against the calendar windows:
+        assert_eq!(budget.remaining(Horizon::Hour), Some(5));
+
+        clock.advance(Duration::from_secs(60));
+        assert!(budget.check_n(nonzero!(5u32)).is_ok());
+        let exceeded = budget.check().unwrap_err();
+        assert_eq!(
+            exceeded,
+            BudgetExceeded::Window {
+                horizon: Horizon::Hour,
+                resets_at: Nanos::from(Duration::from_secs(60 * 60)),
+            }
+        );
+        assert_eq!(
+            exceeded.wait_time_from(clock.now()),
+            Some(Duration::from_secs(59 * 60))
+        );
+        // ...and the pacing didn't use up a cell for the denied message:
+        assert!(budget.pacing().check_n(nonzero!(5u32)).is_ok());
+
+        clock.advance(Duration::from_secs(59 * 60));
+        assert_eq!(budget.remaining(Horizon::Hour), Some(15));
+        assert_eq!(budget.remaining(Horizon::Day), Some(5));
+        assert!(budget.check_n(nonzero!(5u32)).is_ok());
+        assert!(matches!(
+            budget.check(),
+            Err(BudgetExceeded::Window {
+                horizon: Horizon::Day,
+                ..
+            })
+        ));
+
+        clock.advance(Duration::from_secs(23 * 60 * 60));
+        assert_eq!(budget.remaining(Horizon::Day), Some(20));
+        assert!(budget.check().is_ok());
+    }
+
+    #[test]
+    fn insufficient_capacity() {
+        let clock = FakeRelativeClock::default();
+        let budget = budget(&clock);
+        assert_eq!(
+            budget.check_n(nonzero!(16u32)),
+            Err(BudgetExceeded::InsufficientCapacity(15))
+        );
+        assert_eq!(
+            budget
+                .check_n(nonzero!(11u32))
+                .unwrap_err()
+                .earliest_possible(),
+            None
+        );
+        assert!(format!("{:?}", budget).contains("SendBudget"));
+    }
+
+    #[test]
+    fn wall_clock_budget() {
+        let budget = SendBudget::new(Quota::per_second(nonzero!(100u32))).per_day(nonzero!(1u32));
+        assert!(budget.check().is_ok());
+        let exceeded = budget.check().unwrap_err();
+        assert!(exceeded.wait_time_from(SystemTime::now()).unwrap() <= Duration::from_secs(86400));
+        assert_eq!(budget.remaining(Horizon::Hour), None);
+        assert_eq!(
+            block_on(budget.until_n_ready(nonzero!(2u32))),
+            Err(InsufficientCapacity(1))
+        );
+    }
+
+    #[test]
+    fn waits_for_pacing() {
+        let budget =
+            SendBudget::new(Quota::per_second(nonzero!(20u32)).allow_burst(nonzero!(1u32)))
+                .per_hour(nonzero!(100u32));
+        let start = std::time::Instant::now();
+        for _ in 0..3 {
+            assert_eq!(block_on(budget.until_n_ready(nonzero!(1u32))), Ok(()));
+        }
+        assert!(start.elapsed() >= Duration::from_millis(100));
+    }
+}
@@ -0,0 +1,197 @@
+//! Rate limiters that can be closed, so that tasks waiting for them don't hold up a shutdown.
+
+use crate::compat::prelude::*;
+
+use crate::clock;
+use crate::middleware::RateLimitingMiddleware;
+use crate::state::wait::{sealed, Count, Layer, Layered, Waitable};
+use crate::state::StateStore;
+use crate::sync::{AtomicBool, Ordering};
+use crate::RateLimiter;
+use std::error::Error;
+use std::fmt;
+
+/// An error that occurs when a task waits for a [`ClosableLimiter`] that is closed, or is
+/// closed while the task waits.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Closed;
+
+impl fmt::Display for Closed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "rate limiter is closed")
+    }
+}
+
+impl Error for Closed {}
+
+/// A rate limiter that can be [closed](#method.close) to shut down gracefully: Once it is
+/// closed, the tasks waiting for it stop waiting with a [`Closed`] error right away, and so do
+/// any tasks that try to wait for it later.
+///
+/// Without this, a service that shuts down has to wait for the tasks that wait for a rate
+/// limiter, which can take as long as the longest rate-limit wait. Checking cells with the
+/// wrapped rate limiter (see [`limiter`](Layered::limiter)) works as before after closing it.
+///
+/// Closing is a [layer](Layered) on top of a rate limiter, or of other layers like a
+/// [`BoundedWaitLimiter`](crate::state::BoundedWaitLimiter): It ends the waits through all of
+/// the layers below it.
+///
+/// # Example
+/// ```rust
+/// # use nonzero_ext::*;
+/// # use futures::executor::block_on;
+/// use governor::{state::Closed, Quota, RateLimiter};
+///
+/// let lim = RateLimiter::direct(Quota::per_hour(nonzero!(1u32))).into_closable();
+/// assert_eq!(block_on(lim.until_ready()), Ok(()));
+/// // No task was waiting:
+/// assert_eq!(lim.close(), 0);
+/// assert_eq!(block_on(lim.until_ready()), Err(Closed));
+/// ```
+pub type ClosableLimiter<L> = Layered<L, Closer>;
+
+/// The [layer](Layered) of a [`ClosableLimiter`], which ends the waits once it is closed.
+#[derive(Debug, Default)]
+pub struct Closer {
+    closed: AtomicBool,
+    /// The number of tasks that are waiting for the rate limiter.
+    waiting: Count,
+}
+
+impl sealed::Sealed for Closer {}
+
+impl<K> Layer<K> for Closer {
+    type Error = Closed;
+
+    fn admit(&self) -> Result<(), Closed> {
+        if self.closed.load(Ordering::Acquire) {
+            Err(Closed)
+        } else {
+            Ok(())
+        }
+    }
+
+    fn enter(&self, _key: &K) -> Result<(), Closed> {
+        self.waiting.enter(usize::MAX);
+        Ok(())
+    }
+
+    fn leave(&self, _key: &K) {
+        self.waiting.leave();
+    }
+}
+
+impl<L: Waitable> ClosableLimiter<L> {
+    /// Wraps a rate limiter, or other layers on top of one, which starts out open.
+    pub fn new(inner: L) -> Self {
+        Layered {
+            inner,
+            layer: Closer::default(),
+        }
+    }
+
+    /// Closes the rate limiter, waking up the tasks that wait for it so that they stop waiting
+    /// with a [`Closed`] error. Returns the number of tasks that were waiting.
+    ///
+    /// Closing a rate limiter that is closed already has no effect.
+    pub fn close(&self) -> usize {
+        if self.layer.closed.swap(true, Ordering::AcqRel) {
+            return 0;
+        }
+        let drained = self.layer.waiting.get();
+        self.limiter().notify_waiters();
+        drained
+    }
+
+    /// Returns whether the rate limiter is closed.
+    pub fn is_closed(&self) -> bool {
+        self.layer.closed.load(Ordering::Acquire)
+    }
+
+    /// Returns the number of tasks that are waiting for the rate limiter.
+    pub fn waiting(&self) -> usize {
+        self.layer.waiting.get()
+    }
+}
+
+/// # Closable rate limiters
+impl<K, S, C, MW> RateLimiter<K, S, C, MW>
+where
+    S: StateStore<Key = K>,
+    C: clock::Clock,
+    MW: RateLimitingMiddleware<C::Instant>,
+{
+    /// Wraps the rate limiter in a [`ClosableLimiter`], which can be closed to stop the tasks
+    /// that wait for it.
+    pub fn into_closable(self) -> ClosableLimiter<Self> {
+        ClosableLimiter::new(self)
+    }
+}
+
+impl<L, Y> Layered<L, Y>
+where
+    L: Waitable,
+    Y: Layer<L::Key>,
+{
+    /// Adds a [`ClosableLimiter`] layer on top, which can be closed to stop the tasks that
+    /// wait through the layers.
+    pub fn into_closable(self) -> ClosableLimiter<Self> {
+        ClosableLimiter::new(self)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::state::direct::InsufficientCapacity;
+    use crate::Quota;
+    use futures::executor::block_on;
+    use futures::{pin_mut, poll};
+    use nonzero_ext::nonzero;
+
+    #[test]
+    fn closing_drains_waiters() {
+        let lim = RateLimiter::direct(Quota::per_hour(nonzero!(1u32))).into_closable();
+        block_on(async {
+            assert_eq!(lim.until_ready().await, Ok(()));
+            let first = lim.until_ready();
+            pin_mut!(first);
+            let second = lim.until_n_ready(nonzero!(1u32));
+            pin_mut!(second);
+            assert!(poll!(first.as_mut()).is_pending());
+            assert!(poll!(second.as_mut()).is_pending());
+            assert_eq!(lim.waiting(), 2);
+            assert_eq!(lim.close(), 2);
+            assert_eq!(lim.close(), 0);
+            assert!(lim.is_closed());
+            assert_eq!(first.await, Err(Closed));
+            assert_eq!(second.await, Err(Closed));
+        });
+        assert_eq!(lim.waiting(), 0);
+        assert!(!format!("{:?}", lim).is_empty());
+        assert!(!format!("{}", Closed).is_empty());
+    }
+
+    #[test]
+    fn closing_stops_keyed_waits() {
+        let lim = RateLimiter::keyed(Quota::per_hour(nonzero!(1u32))).into_closable();
+        block_on(async {
+            assert_eq!(lim.until_key_ready(&"a").await, Ok(()));
+            assert_eq!(
+                lim.until_key_n_ready(&"a", nonzero!(2u32)).await,
+                Ok(Err(InsufficientCapacity(1)))
+            );
+            let waiting = lim.until_key_ready(&"a");
+            pin_mut!(waiting);
+            assert!(poll!(waiting.as_mut()).is_pending());
+            assert_eq!(lim.close(), 1);
+            assert_eq!(waiting.await, Err(Closed));
+            assert_eq!(
+                lim.until_key_n_ready(&"b", nonzero!(1u32)).await,
+                Err(Closed)
+            );
+        });
+        // Checks still work:
+        assert_eq!(lim.into_inner().check_key(&"b"), Ok(()));
+    }
+}
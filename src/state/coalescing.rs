@@ -0,0 +1,268 @@
+//! Keyed rate limiters that coalesce concurrent calls for the same key into one.
+
+use crate::compat::prelude::*;
+
+use crate::clock;
+use crate::compat::collections::HashMap;
+use crate::middleware::RateLimitingMiddleware;
+use crate::state::keyed::KeyedStateStore;
+use crate::state::{RateLimiter, StateStore};
+use crate::sync::Mutex;
+use futures::channel::oneshot;
+use futures::future::{FutureExt, Shared};
+use std::fmt;
+use std::future::Future;
+use std::hash::Hash;
+
+/// A call in flight for a key: the ID that tells it apart from later calls for the same key,
+/// and its result, once the call that runs it sends it.
+struct Flight<T> {
+    id: u64,
+    result: Shared<oneshot::Receiver<T>>,
+}
+
+struct Flights<K, T> {
+    next_id: u64,
+    flights: HashMap<K, Flight<T>>,
+}
+
+/// A keyed rate limiter that lets concurrent calls for the same key share the result of one
+/// call ("singleflight"), instead of each having to go through the rate limiter.
+///
+/// [`coalesce`](#method.coalesce) runs a call for a key if the rate limiter lets a cell
+/// through for it. While that call is in flight, other calls for the same key await its result
+/// instead, without checking the rate limiter: They neither use up cells nor get rejected.
+/// This protects e.g. the refreshing of a cache entry, which many clients might ask for at
+/// once.
+///
+/// # Example
+/// ```rust
+/// # use nonzero_ext::*;
+/// # use futures::executor::block_on;
+/// use governor::{Quota, RateLimiter};
+///
+/// let lim = RateLimiter::keyed(Quota::per_hour(nonzero!(1u32))).into_coalescing();
+/// let (refreshed, refresh) = futures::channel::oneshot::channel();
+/// block_on(async {
+///     let (first, second, _) = futures::join!(
+///         lim.coalesce(&"config", async { refresh.await.unwrap() }),
+///         lim.coalesce(&"config", async { unreachable!() }),
+///         async { refreshed.send(42).unwrap() },
+///     );
+///     assert_eq!((first, second), (Ok(42), Ok(42)));
+///     // Once no call is in flight, the rate limiter decides again:
+///     assert!(lim.coalesce(&"config", async { 43 }).await.is_err());
+/// });
+/// ```
+pub struct CoalescingLimiter<K, T, S, C, MW>
+where
+    S: StateStore<Key = K>,
+    C: clock::Clock,
+    MW: RateLimitingMiddleware<C::Instant>,
+{
+    limiter: RateLimiter<K, S, C, MW>,
+    flights: Mutex<Flights<K, T>>,
+}
+
+impl<K, T, S, C, MW> CoalescingLimiter<K, T, S, C, MW>
+where
+    S: StateStore<Key = K>,
+    C: clock::Clock,
+    MW: RateLimitingMiddleware<C::Instant>,
+{
+    /// Wraps a rate limiter, with no calls in flight.
+    pub fn new(limiter: RateLimiter<K, S, C, MW>) -> Self {
+        CoalescingLimiter {
+            limiter,
+            flights: Mutex::new(Flights {
+                next_id: 0,
+                flights: HashMap::new(),
+            }),
+        }
+    }
+
+    /// Returns the number of keys that have a call in flight.
+    pub fn in_flight(&self) -> usize {
+        self.flights.lock().flights.len()
+    }
+
+    /// Returns the wrapped rate limiter.
+    pub fn limiter(&self) -> &RateLimiter<K, S, C, MW> {
+        &self.limiter
+    }
+
+    /// Returns the wrapped rate limiter.
+    pub fn into_inner(self) -> RateLimiter<K, S, C, MW> {
+        self.limiter
+    }
+}
+
+/// Forgets a call's flight once it is done, or once it is cancelled by dropping it (which
+/// makes the calls awaiting it start over).
+struct Landing<'a, K: Hash + Eq, T> {
+    flights: &'a Mutex<Flights<K, T>>,
+    key: &'a K,
+    id: u64,
+}
+
+impl<K: Hash + Eq, T> Drop for Landing<'_, K, T> {
+    fn drop(&mut self) {
+        let mut flights = self.flights.lock();
+        if flights.flights.get(self.key).map(|flight| flight.id) == Some(self.id) {
+            flights.flights.remove(self.key);
+        }
+    }
+}
+
+/// # Coalescing rate limiters - `async`/`await`
+impl<K, T, S, C, MW> CoalescingLimiter<K, T, S, C, MW>
+where
+    S: KeyedStateStore<K>,
+    K: Hash + Eq + Clone,
+    T: Clone,
+    C: clock::Clock,
+    MW: RateLimitingMiddleware<C::Instant>,
+{
+    /// Runs `call` for the given key, if no call is in flight for the key and the rate limiter
+    /// lets a cell through for it, and returns the call's result. If a call is in flight for
+    /// the key already, awaits that call's result instead (and drops `call` without running
+    /// it).
+    ///
+    /// Fails with the rate limiter's negative outcome if no call is in flight and the rate
+    /// limiter doesn't let a cell through. If the call in flight is cancelled (by dropping its
+    /// future), the calls awaiting it start over.
+    pub async fn coalesce<F>(&self, key: &K, call: F) -> Result<T, MW::NegativeOutcome>
+    where
+        F: Future<Output = T>,
+    {
+        loop {
+            let flight = {
+                let mut flights = self.flights.lock();
+                match flights.flights.get(key) {
+                    Some(flight) => Err(flight.result.clone()),
+                    None => {
+                        self.limiter.check_key(key)?;
+                        let (sender, receiver) = oneshot::channel();
+                        let id = flights.next_id;
+                        flights.next_id += 1;
+                        let result = receiver.shared();
+                        flights.flights.insert(key.clone(), Flight { id, result });
+                        Ok((id, sender))
+                    }
+                }
+            };
+            let (id, sender) = match flight {
+                Ok(flight) => flight,
+                Err(in_flight) => match in_flight.await {
+                    Ok(result) => return Ok(result),
+                    Err(oneshot::Canceled) => continue,
+                },
+            };
+            let landing = Landing {
+                flights: &self.flights,
+                key,
+                id,
+            };
+            let result = call.await;
+            drop(landing);
+            // The calls awaiting this one may all have been dropped:
+            let _ = sender.send(result.clone());
+            return Ok(result);
+        }
+    }
+}
+
+impl<K, T, S, C, MW> fmt::Debug for CoalescingLimiter<K, T, S, C, MW>
+where
+    S: StateStore<Key = K>,
+    C: clock::Clock,
+    MW: RateLimitingMiddleware<C::Instant>,
+    RateLimiter<K, S, C, MW>: fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CoalescingLimiter")
+            .field("limiter", &self.limiter)
+            .field("in_flight", &self.in_flight())
+            .finish()
+    }
+}
+
+/// # Coalescing rate limiters
+impl<K, S, C, MW> RateLimiter<K, S, C, MW>
+where
+    S: KeyedStateStore<K>,
+    K: Hash,
+    C: clock::Clock,
+    MW: RateLimitingMiddleware<C::Instant>,
+{
+    /// Wraps the rate limiter in a [`CoalescingLimiter`], which lets concurrent calls for the
+    /// same key share the result of one call.
+    pub fn into_coalescing<T>(self) -> CoalescingLimiter<K, T, S, C, MW> {
+        CoalescingLimiter::new(self)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::clock::FakeRelativeClock;
+    use crate::Quota;
+    use futures::executor::block_on;
+    use futures::{pin_mut, poll};
+    use nonzero_ext::nonzero;
+    use std::time::Duration;
+
+    #[test]
+    fn shares_results_in_flight() {
+        let clock = FakeRelativeClock::default();
+        let lim = RateLimiter::hashmap_with_clock(Quota::per_second(nonzero!(1u32)), &clock)
+            .into_coalescing();
+        block_on(async {
+            let (sender, receiver) = oneshot::channel::<&str>();
+            let first = lim.coalesce(&"a", async { receiver.await.unwrap() });
+            pin_mut!(first);
+            assert!(poll!(first.as_mut()).is_pending());
+            assert_eq!(lim.in_flight(), 1);
+
+            let second = lim.coalesce(&"a", async { "unused" });
+            pin_mut!(second);
+            assert!(poll!(second.as_mut()).is_pending());
+            // Other keys go through the rate limiter:
+            assert_eq!(lim.coalesce(&"b", async { "b" }).await, Ok("b"));
+            assert!(lim.coalesce(&"b", async { "b" }).await.is_err());
+
+            sender.send("a").unwrap();
+            assert_eq!(first.await, Ok("a"));
+            assert_eq!(second.await, Ok("a"));
+            assert_eq!(lim.in_flight(), 0);
+            assert!(lim.coalesce(&"a", async { "a" }).await.is_err());
+            clock.advance(Duration::from_secs(1));
+            assert_eq!(lim.coalesce(&"a", async { "again" }).await, Ok("again"));
+        });
+        assert!(!format!("{:?}", lim).is_empty());
+
+        fn assert_send<F: Send>(_: F) {}
+        assert_send(lim.coalesce(&"c", async { "c" }));
+    }
+
+    #[test]
+    fn starts_over_when_cancelled() {
+        let clock = FakeRelativeClock::default();
+        let lim = RateLimiter::hashmap_with_clock(Quota::per_second(nonzero!(2u32)), &clock)
+            .into_coalescing();
+        block_on(async {
+            let first = Box::pin(lim.coalesce(&"a", futures::future::pending::<u32>()));
+            let mut first = Some(first);
+            assert!(poll!(first.as_mut().unwrap()).is_pending());
+            let second = lim.coalesce(&"a", async { 2 });
+            pin_mut!(second);
+            assert!(poll!(second.as_mut()).is_pending());
+
+            // Cancelling the call in flight makes the waiting one run its own call:
+            first.take();
+            assert_eq!(lim.in_flight(), 0);
+            assert_eq!(second.await, Ok(2));
+            assert!(lim.coalesce(&"a", async { 3 }).await.is_err());
+        });
+    }
+}
@@ -0,0 +1,293 @@
+//! Admission control driven by the queueing delay of admitted work.
+
+use crate::compat::prelude::*;
+
+use crate::clock::{self, Reference};
+use crate::middleware::RateLimitingMiddleware;
+use crate::state::keyed::KeyedStateStore;
+use crate::state::{DirectStateStore, NotKeyed, StateStore};
+use crate::sync::Mutex;
+use crate::{NegativeMultiDecision, RateLimiter};
+use std::fmt;
+use std::hash::Hash;
+use std::num::NonZeroU32;
+use std::time::Duration;
+
+/// A rate limiter that tightens its quota when the work it admits queues up for too long,
+/// in the style of the CoDel queue management algorithm.
+///
+/// Callers report the queueing delay (or completion latency) of every admitted piece of work
+/// with [`record_delay`](#method.record_delay). The controller watches the lowest delay over
+/// each `interval`: If even that has stayed above the `target` delay, work isn't draining from
+/// the queue, and the controller tightens the quota by putting the rate limiter under
+/// [pressure](struct.RateLimiter.html#method.set_pressure). Like CoDel's drop rate, the
+/// tightening grows with the square root of the number of consecutive intervals spent above
+/// the target; once the delay drops below the target again, the quota is relaxed step by step.
+///
+/// This protects local resources (a thread pool, a database connection) against overload
+/// without having to guess a fixed quota that they can sustain: The quota is only an upper
+/// bound, and the controller finds the rate that keeps the delay around its target.
+///
+/// # Example
+/// ```rust
+/// # use nonzero_ext::*;
+/// # use std::time::Duration;
+/// use governor::{clock::FakeRelativeClock, Quota, RateLimiter};
+///
+/// let clock = FakeRelativeClock::default();
+/// let lim = RateLimiter::direct_with_clock(Quota::per_second(nonzero!(100u32)), &clock)
+///     .into_codel(Duration::from_millis(5), Duration::from_millis(100));
+/// for _ in 0..10 {
+///     clock.advance(Duration::from_millis(50));
+///     if lim.check().is_ok() {
+///         // The work takes much longer than the 5ms target to start:
+///         lim.record_delay(Duration::from_millis(30));
+///     }
+/// }
+/// assert!(lim.is_tightened());
+/// assert!(lim.limiter().pressure() < 1.0);
+/// ```
+pub struct CodelLimiter<K, S, C, MW>
+where
+    S: StateStore<Key = K>,
+    C: clock::Clock,
+    MW: RateLimitingMiddleware<C::Instant>,
+{
+    limiter: RateLimiter<K, S, C, MW>,
+    target: Duration,
+    interval: Duration,
+    window: Mutex<Window<C::Instant>>,
+}
+
+/// The delays observed in the current interval.
+#[derive(Debug)]
+struct Window<P> {
+    started: P,
+    /// The lowest delay reported in the interval, if any was.
+    min_delay: Option<Duration>,
+    /// The number of consecutive intervals (less those spent recovering) above the target.
+    count: u32,
+}
+
+impl<K, S, C, MW> CodelLimiter<K, S, C, MW>
+where
+    S: StateStore<Key = K>,
+    C: clock::Clock,
+    MW: RateLimitingMiddleware<C::Instant>,
+{
+    /// Wraps a rate limiter, tightening its quota whenever the delay reported for admitted work
+    /// has stayed above `target` for an entire `interval`.
+    ///
+    /// CoDel's defaults are a `target` of 5ms and an `interval` of 100ms; the interval should be
+    /// about as long as it normally takes a piece of work to complete.
+    pub fn new(limiter: RateLimiter<K, S, C, MW>, target: Duration, interval: Duration) -> Self {
+        let window = Window {
+            started: limiter.clock.now(),
+            min_delay: None,
+            count: 0,
+        };
+        limiter.set_pressure(1.0);
+        CodelLimiter {
+            limiter,
+            target,
+            interval,
+            window: Mutex::new(window),
+        }
+    }
+
+    /// Returns the delay that the controller aims to keep admitted work under.
+    pub fn target(&self) -> Duration {
+        self.target
+    }
+
+    /// Returns the interval over which the controller observes delays.
+    pub fn interval(&self) -> Duration {
+        self.interval
+    }
+
+    /// Returns whether the controller currently tightens the rate limiter's quota.
+    pub fn is_tightened(&self) -> bool {
+        self.window.lock().count > 0
+    }
+
+    /// Reports the queueing delay of a piece of admitted work, e.g. the time between its
+    /// admission and the start (or completion) of its processing.
+    pub fn record_delay(&self, delay: Duration) {
+        let mut window = self.window.lock();
+        let now = self.limiter.clock.now();
+        window.min_delay = Some(window.min_delay.map_or(delay, |min| min.min(delay)));
+        if Duration::from(now.duration_since(window.started)) < self.interval {
+            return;
+        }
+        // CoDel only reacts if even the shortest delay of an interval exceeded the target:
+        if window.min_delay > Some(self.target) {
+            window.count = window.count.saturating_add(1);
+        } else {
+            window.count /= 2;
+        }
+        window.started = now;
+        window.min_delay = None;
+        self.limiter
+            .set_pressure(1.0 / f64::from(window.count.saturating_add(1)).sqrt());
+    }
+
+    /// Returns the wrapped rate limiter.
+    pub fn limiter(&self) -> &RateLimiter<K, S, C, MW> {
+        &self.limiter
+    }
+
+    /// Returns the wrapped rate limiter, lifting the pressure that the controller put it under.
+    pub fn into_inner(self) -> RateLimiter<K, S, C, MW> {
+        self.limiter.set_pressure(1.0);
+        self.limiter
+    }
+}
+
+/// # Delay-controlled rate limiters - Manually checking cells
+impl<S, C, MW> CodelLimiter<NotKeyed, S, C, MW>
+where
+    S: DirectStateStore,
+    C: clock::Clock,
+    MW: RateLimitingMiddleware<C::Instant>,
+{
+    /// Allow a single cell through the rate limiter, under its current quota.
+    ///
+    /// See [`RateLimiter::check`].
+    pub fn check(&self) -> Result<MW::PositiveOutcome, MW::NegativeOutcome> {
+        self.limiter.check()
+    }
+
+    /// Allow *only all* `n` cells through the rate limiter, under its current quota.
+    ///
+    /// See [`RateLimiter::check_n`].
+    pub fn check_n(
+        &self,
+        n: NonZeroU32,
+    ) -> Result<MW::PositiveOutcome, NegativeMultiDecision<MW::NegativeOutcome>> {
+        self.limiter.check_n(n)
+    }
+}
+
+/// # Keyed delay-controlled rate limiters - Manually checking cells
+impl<K, S, C, MW> CodelLimiter<K, S, C, MW>
+where
+    S: KeyedStateStore<K>,
+    K: Hash,
+    C: clock::Clock,
+    MW: RateLimitingMiddleware<C::Instant>,
+{
+    /// Allow a single cell through the rate limiter for the given key, under its current quota.
+    ///
+    /// See [`RateLimiter::check_key`].
+    pub fn check_key(&self, key: &K) -> Result<MW::PositiveOutcome, MW::NegativeOutcome> {
+        self.limiter.check_key(key)
+    }
+
+    /// Allow *only all* `n` cells through the rate limiter for the given key, under its current
+    /// quota.
+    ///
+    /// See [`RateLimiter::check_key_n`].
+    pub fn check_key_n(
+        &self,
+        key: &K,
+        n: NonZeroU32,
+    ) -> Result<MW::PositiveOutcome, NegativeMultiDecision<MW::NegativeOutcome>> {
+        self.limiter.check_key_n(key, n)
+    }
+}
+
+impl<K, S, C, MW> fmt::Debug for CodelLimiter<K, S, C, MW>
+where
+    S: StateStore<Key = K>,
+    C: clock::Clock,
+    MW: RateLimitingMiddleware<C::Instant>,
+    RateLimiter<K, S, C, MW>: fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CodelLimiter")
+            .field("limiter", &self.limiter)
+            .field("target", &self.target)
+            .field("interval", &self.interval)
+            .field("window", &*self.window.lock())
+            .finish()
+    }
+}
+
+/// # Delay-controlled rate limiters
+impl<K, S, C, MW> RateLimiter<K, S, C, MW>
+where
+    S: StateStore<Key = K>,
+    C: clock::Clock,
+    MW: RateLimitingMiddleware<C::Instant>,
+{
+    /// Wraps the rate limiter in a [`CodelLimiter`] that tightens the quota whenever the delay
+    /// of admitted work stays above `target` for an entire `interval`.
+    pub fn into_codel(self, target: Duration, interval: Duration) -> CodelLimiter<K, S, C, MW> {
+        CodelLimiter::new(self, target, interval)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::clock::FakeRelativeClock;
+    use crate::Quota;
+    use nonzero_ext::nonzero;
+
+    #[test]
+    fn tightens_and_relaxes() {
+        let clock = FakeRelativeClock::default();
+        let lim = RateLimiter::direct_with_clock(Quota::per_second(nonzero!(100u32)), &clock)
+            .into_codel(Duration::from_millis(5), Duration::from_millis(100));
+        assert_eq!(lim.target(), Duration::from_millis(5));
+        assert_eq!(lim.interval(), Duration::from_millis(100));
+
+        // A single short delay in an interval keeps the quota:
+        lim.record_delay(Duration::from_millis(50));
+        clock.advance(Duration::from_millis(50));
+        lim.record_delay(Duration::from_millis(1));
+        clock.advance(Duration::from_millis(50));
+        lim.record_delay(Duration::from_millis(50));
+        assert!(!lim.is_tightened());
+        assert_eq!(lim.limiter().pressure(), 1.0);
+
+        // Delays that stay above the target tighten it further and further:
+        let mut pressure = 1.0;
+        for _ in 0..3 {
+            clock.advance(Duration::from_millis(100));
+            lim.record_delay(Duration::from_millis(50));
+            assert!(lim.limiter().pressure() < pressure);
+            pressure = lim.limiter().pressure();
+        }
+        assert!(lim.is_tightened());
+        assert!((pressure - 0.5).abs() < 0.01);
+
+        // And delays below the target relax it again:
+        while lim.is_tightened() {
+            clock.advance(Duration::from_millis(100));
+            lim.record_delay(Duration::from_millis(1));
+            assert!(lim.limiter().pressure() > pressure);
+            pressure = lim.limiter().pressure();
+        }
+        assert_eq!(pressure, 1.0);
+        assert!(lim.check().is_ok());
+        assert!(lim.check_n(nonzero!(2u32)).is_ok());
+        assert!(format!("{:?}", lim).contains("CodelLimiter"));
+    }
+
+    #[test]
+    fn into_inner_lifts_pressure() {
+        let clock = FakeRelativeClock::default();
+        let lim = CodelLimiter::new(
+            RateLimiter::hashmap_with_clock(Quota::per_second(nonzero!(100u32)), &clock),
+            Duration::from_millis(5),
+            Duration::from_millis(100),
+        );
+        clock.advance(Duration::from_millis(100));
+        lim.record_delay(Duration::from_millis(50));
+        assert!(lim.limiter().pressure() < 1.0);
+        assert!(lim.check_key(&1u32).is_ok());
+        assert!(lim.check_key_n(&1u32, nonzero!(2u32)).is_ok());
+        assert_eq!(lim.into_inner().pressure(), 1.0);
+    }
+}
@@ -0,0 +1,226 @@
+use crate::compat::prelude::*;
+
+use crate::nanos::Nanos;
+use crate::state::{NotKeyed, StateStore};
+use crate::sync::{AtomicU32, Ordering};
+use std::convert::TryFrom;
+use std::fmt;
+use std::time::Duration;
+
+/// The number of bits of a theoretical arrival time (in resolutions) below its era.
+const ERA_BITS: u32 = 31;
+
+/// How far, in resolutions, the state word may lie before its era's start. States run at most
+/// half this far ahead of the decisions' time, so a decision that stores a state in a new
+/// era, and one that stores a state from the era before concurrently, store states at most
+/// this far apart.
+const WINDOW_BEHIND: u64 = 1 << 30;
+
+/// A direct in-memory state that keeps its time in 32-bit atomics, for targets where 64-bit
+/// atomics are unavailable or emulated with locks (most 32-bit microcontrollers).
+///
+/// Like [`InMemoryState`](crate::state::InMemoryState), the state is a theoretical arrival time
+/// that decisions update with a compare-and-swap, with the same memory ordering; but it is
+/// kept in an [`AtomicU32`], in multiples of a configurable *resolution* instead of
+/// nanoseconds. That trades precision and range for the smaller state word:
+///
+/// * States are rounded up to the resolution, so cells may be denied for up to one resolution
+///   longer than the quota says, but are never let through earlier. (For quotas that replenish
+///   several cells per period, the resolution counts in fractions of a nanosecond: divide the
+///   resolution and the range by the quota's number of cells per period.)
+/// * The state word wraps around every 2^32 resolutions (about 49 days at a resolution of one
+///   millisecond). A second 32-bit atomic counts the wraparounds, so the state stays exact
+///   however long the rate limiter runs; but states may only run up to 2^29 resolutions (the
+///   state's [range](#method.range): about 6 days at a resolution of one millisecond) ahead
+///   of the decisions' time. [`RateLimiter::new`](crate::RateLimiter::new) refuses quotas
+///   whose burst capacity (with the overdraft and cooldown) takes longer to replenish.
+///
+/// Targets without a compare-and-swap instruction need the `critical-section` feature, like
+/// the [`InterruptSafeRateLimiter`](crate::state::InterruptSafeRateLimiter).
+///
+/// # Example
+/// ```rust
+/// # use nonzero_ext::*;
+/// # use std::time::Duration;
+/// use governor::{
+///     clock::FakeRelativeClock, middleware::NoOpMiddleware, state::CompactInMemoryState, Quota,
+///     RateLimiter,
+/// };
+///
+/// let clock = FakeRelativeClock::default();
+/// let state = CompactInMemoryState::new(Duration::from_millis(1));
+/// let lim: RateLimiter<_, _, _, NoOpMiddleware<_>> =
+///     RateLimiter::new(Quota::per_second(nonzero!(2u32)), state, &clock);
+/// assert_eq!(lim.check_n(nonzero!(2u32)), Ok(()));
+/// assert!(lim.check().is_err());
+/// clock.advance(Duration::from_millis(500));
+/// assert_eq!(lim.check(), Ok(()));
+/// ```
+pub struct CompactInMemoryState {
+    /// The theoretical arrival time in resolutions, modulo 2^32, or 0 for a fresh state.
+    tat: AtomicU32,
+    /// The latest theoretical arrival time that was stored, in multiples of 2^31 resolutions,
+    /// which tells the wraparound that the state word is in.
+    era: AtomicU32,
+    resolution: u64,
+}
+
+impl CompactInMemoryState {
+    /// Constructs a fresh state that keeps its theoretical arrival time rounded up to the given
+    /// resolution.
+    ///
+    /// The resolution must not be zero; it is raised to one nanosecond otherwise.
+    pub fn new(resolution: Duration) -> Self {
+        CompactInMemoryState {
+            tat: AtomicU32::new(0),
+            era: AtomicU32::new(0),
+            resolution: Nanos::from(resolution).as_u64().max(1),
+        }
+    }
+
+    /// Returns the resolution that the state is kept in.
+    pub fn resolution(&self) -> Duration {
+        Duration::from_nanos(self.resolution)
+    }
+
+    /// Returns how far ahead of the decisions' time the state can run.
+    pub fn range(&self) -> Duration {
+        Duration::from_nanos(self.resolution.saturating_mul(WINDOW_BEHIND / 2))
+    }
+
+    /// Returns the state word for a theoretical arrival time, after moving the era on to the
+    /// time's, so that decisions which see the new word also see its era.
+    fn encode(&self, tat: Nanos) -> u32 {
+        if tat.as_u64() == 0 {
+            return 0;
+        }
+        let mut tat = tat.as_u64().div_ceil(self.resolution);
+        // 0 marks a fresh state, so a state that happens to end there ends a resolution later:
+        if tat as u32 == 0 {
+            tat += 1;
+        }
+        let era = u32::try_from(tat >> ERA_BITS).unwrap_or(u32::MAX);
+        if self.era.load(Ordering::Acquire) < era {
+            self.era.fetch_max(era, Ordering::AcqRel);
+        }
+        tat as u32
+    }
+
+    /// Returns the theoretical arrival time that a state word stands for: the one with the
+    /// word's lower 32 bits that lies closest after the era's start, give or take
+    /// `WINDOW_BEHIND` resolutions.
+    fn decode(&self, tat: u32) -> Option<Nanos> {
+        if tat == 0 {
+            return None;
+        }
+        let era = u64::from(self.era.load(Ordering::Acquire)) << ERA_BITS;
+        let earliest = era.saturating_sub(WINDOW_BEHIND);
+        let tat = earliest + (u64::from(tat).wrapping_sub(earliest) & u64::from(u32::MAX));
+        Some(Nanos::from(tat.saturating_mul(self.resolution)))
+    }
+}
+
+/// A state that keeps its time in milliseconds.
+impl Default for CompactInMemoryState {
+    fn default() -> Self {
+        CompactInMemoryState::new(Duration::from_millis(1))
+    }
+}
+
+impl StateStore for CompactInMemoryState {
+    type Key = NotKeyed;
+
+    fn measure_and_replace<T, F, E>(&self, _key: &Self::Key, f: F) -> Result<T, E>
+    where
+        F: Fn(Option<Nanos>) -> Result<(T, Nanos), E>,
+    {
+        let mut prev = self.tat.load(Ordering::Acquire);
+        loop {
+            let (result, tat) = f(self.decode(prev))?;
+            // A spurious failure would leave the era moved on past the stored state, so this
+            // only fails when another decision stored a state:
+            match self.tat.compare_exchange(
+                prev,
+                self.encode(tat),
+                Ordering::AcqRel,
+                Ordering::Acquire,
+            ) {
+                Ok(_) => return Ok(result),
+                Err(next_prev) => prev = next_prev,
+            }
+        }
+    }
+
+    fn reach(&self) -> Option<Nanos> {
+        Some(self.range().into())
+    }
+}
+
+impl fmt::Debug for CompactInMemoryState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let tat = self.decode(self.tat.load(Ordering::Relaxed));
+        f.debug_struct("CompactInMemoryState")
+            .field(
+                "tat",
+                &Duration::from(tat.unwrap_or_else(|| Nanos::from(0))),
+            )
+            .field("resolution", &self.resolution())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::clock::FakeRelativeClock;
+    use crate::middleware::NoOpMiddleware;
+    use crate::{Quota, RateLimiter};
+    use nonzero_ext::nonzero;
+
+    #[test]
+    fn rounds_states_up() {
+        let clock = FakeRelativeClock::default();
+        let state = CompactInMemoryState::new(Duration::from_millis(10));
+        let lim: RateLimiter<_, _, _, NoOpMiddleware<_>> =
+            RateLimiter::new(Quota::per_second(nonzero!(1000u32)), state, &clock);
+        assert_eq!(lim.check_n(nonzero!(1000u32)), Ok(()));
+        // A cell replenishes after a millisecond, but the state was rounded up to 10:
+        clock.advance(Duration::from_millis(9));
+        assert!(lim.check().is_err());
+        clock.advance(Duration::from_millis(1));
+        assert_eq!(lim.check(), Ok(()));
+    }
+
+    #[test]
+    fn keeps_states_exact_past_wraparounds() {
+        let clock = FakeRelativeClock::default();
+        let quota = Quota::per_second(nonzero!(2u32));
+        // The state word wraps around about every 72 minutes:
+        let state = CompactInMemoryState::new(Duration::from_micros(1));
+        let lim: RateLimiter<_, _, _, NoOpMiddleware<_>> = RateLimiter::new(quota, state, &clock);
+        let exact = RateLimiter::direct_with_clock(quota, &clock);
+        let mut denied = 0;
+        for _ in 0..40 {
+            assert_eq!(lim.check_n(nonzero!(2u32)), exact.check_n(nonzero!(2u32)));
+            for _ in 0..4 {
+                let allowed = lim.check().is_ok();
+                assert_eq!(allowed, exact.check().is_ok());
+                denied += usize::from(!allowed);
+                clock.advance(Duration::from_millis(250));
+            }
+            clock.advance(Duration::from_secs(1000));
+        }
+        assert_eq!(denied, 81);
+        assert!(!format!("{:?}", lim).is_empty());
+    }
+
+    #[test]
+    #[should_panic(expected = "can't keep states far enough ahead")]
+    fn refuses_quotas_beyond_its_range() {
+        let clock = FakeRelativeClock::default();
+        let state = CompactInMemoryState::new(Duration::from_micros(1));
+        assert_eq!(state.range(), Duration::from_micros(1 << 29));
+        let _: RateLimiter<_, _, _, NoOpMiddleware<_>> =
+            RateLimiter::new(Quota::per_hour(nonzero!(1u32)), state, &clock);
+    }
+}
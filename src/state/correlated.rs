@@ -0,0 +1,356 @@
+//! Rate limiters that stamp their decisions with IDs for correlating them.
+
+use crate::compat::prelude::*;
+
+use crate::middleware::RateLimitingMiddleware;
+use crate::state::keyed::KeyedStateStore;
+use crate::state::{DirectStateStore, NotKeyed, StateStore};
+use crate::sync::{AtomicU64, Ordering};
+use crate::{clock, NegativeMultiDecision, RateLimiter};
+use std::fmt;
+use std::hash::Hash;
+use std::num::NonZeroU32;
+
+/// The ID of a decision of a [`CorrelatedLimiter`].
+///
+/// IDs count up from 1, in the order that the rate limiter made its decisions in, and are
+/// unique for each rate limiter. They display as e.g. `#42`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct DecisionId(u64);
+
+impl DecisionId {
+    /// Returns the ID as a number.
+    pub fn get(self) -> u64 {
+        self.0
+    }
+}
+
+impl fmt::Display for DecisionId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "#{}", self.0)
+    }
+}
+
+/// The outcome of a [`CorrelatedLimiter`]'s decision, along with the decision's ID.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Correlated<T> {
+    id: DecisionId,
+    label: Option<&'static str>,
+    outcome: T,
+}
+
+impl<T> Correlated<T> {
+    /// Returns the ID of the decision.
+    pub fn id(&self) -> DecisionId {
+        self.id
+    }
+
+    /// Returns the [label](RateLimiter::with_label) of the rate limiter that made the
+    /// decision, if it has one.
+    pub fn label(&self) -> Option<&'static str> {
+        self.label
+    }
+
+    /// Returns the outcome of the decision, as the wrapped rate limiter returned it.
+    pub fn outcome(&self) -> &T {
+        &self.outcome
+    }
+
+    /// Returns the outcome of the decision, dropping its ID.
+    pub fn into_outcome(self) -> T {
+        self.outcome
+    }
+}
+
+/// Receives every decision that a [`CorrelatedLimiter`] makes, with its ID, e.g. to write it to
+/// an audit log.
+///
+/// This is implemented for `()` (which ignores the decisions) and for closures taking the
+/// decision's ID, key, number of cells and whether they were let through.
+pub trait DecisionAudit<K> {
+    /// Called after each decision, with the number of cells that the decision was about.
+    fn decided(&self, id: DecisionId, key: &K, cells: u32, allowed: bool);
+}
+
+impl<K> DecisionAudit<K> for () {
+    fn decided(&self, _id: DecisionId, _key: &K, _cells: u32, _allowed: bool) {}
+}
+
+impl<K, F: Fn(DecisionId, &K, u32, bool)> DecisionAudit<K> for F {
+    fn decided(&self, id: DecisionId, key: &K, cells: u32, allowed: bool) {
+        self(id, key, cells, allowed)
+    }
+}
+
+/// A rate limiter that stamps each of its decisions with a [`DecisionId`], so that logs, traces
+/// and responses to clients that mention the ID can be correlated when a rate-limited client
+/// disputes a decision.
+///
+/// It makes every decision like the rate limiter it wraps (including running the middleware),
+/// wraps both positive and negative outcomes in [`Correlated`], and hands each decision to its
+/// [`DecisionAudit`].
+///
+/// # Example
+/// ```rust
+/// # use nonzero_ext::*;
+/// use governor::{clock::FakeRelativeClock, state::DecisionId, Quota, RateLimiter};
+/// use std::sync::Mutex;
+///
+/// let clock = FakeRelativeClock::default();
+/// let log = Mutex::new(Vec::new());
+/// let lim = RateLimiter::direct_with_clock(Quota::per_hour(nonzero!(1u32)), &clock)
+///     .into_correlated()
+///     .with_audit(|id: DecisionId, _: &_, _, allowed| log.lock().unwrap().push((id, allowed)));
+///
+/// let first = lim.check().unwrap();
+/// let second = lim.check().unwrap_err();
+/// assert_eq!(format!("{}", second.id()), "#2");
+/// assert_eq!(*log.lock().unwrap(), vec![(first.id(), true), (second.id(), false)]);
+/// ```
+pub struct CorrelatedLimiter<K, S, C, MW, A = ()>
+where
+    S: StateStore<Key = K>,
+    C: clock::Clock,
+    MW: RateLimitingMiddleware<C::Instant>,
+{
+    limiter: RateLimiter<K, S, C, MW>,
+    last_id: AtomicU64,
+    audit: A,
+}
+
+impl<K, S, C, MW> CorrelatedLimiter<K, S, C, MW>
+where
+    S: StateStore<Key = K>,
+    C: clock::Clock,
+    MW: RateLimitingMiddleware<C::Instant>,
+{
+    /// Wraps a rate limiter, starting its decision IDs at 1.
+    pub fn new(limiter: RateLimiter<K, S, C, MW>) -> Self {
+        CorrelatedLimiter {
+            limiter,
+            last_id: AtomicU64::new(0),
+            audit: (),
+        }
+    }
+}
+
+impl<K, S, C, MW, A> CorrelatedLimiter<K, S, C, MW, A>
+where
+    S: StateStore<Key = K>,
+    C: clock::Clock,
+    MW: RateLimitingMiddleware<C::Instant>,
+{
+    /// Hands every decision to `audit` from now on, replacing the current audit.
+    pub fn with_audit<A2: DecisionAudit<K>>(self, audit: A2) -> CorrelatedLimiter<K, S, C, MW, A2> {
+        CorrelatedLimiter {
+            limiter: self.limiter,
+            last_id: self.last_id,
+            audit,
+        }
+    }
+
+    /// Returns the ID of the latest decision, or `None` if there were none yet.
+    pub fn last_id(&self) -> Option<DecisionId> {
+        match self.last_id.load(Ordering::Relaxed) {
+            0 => None,
+            id => Some(DecisionId(id)),
+        }
+    }
+
+    /// Returns the audit that decisions are handed to.
+    pub fn audit(&self) -> &A {
+        &self.audit
+    }
+
+    /// Returns the wrapped rate limiter.
+    pub fn limiter(&self) -> &RateLimiter<K, S, C, MW> {
+        &self.limiter
+    }
+
+    /// Returns the wrapped rate limiter.
+    pub fn into_inner(self) -> RateLimiter<K, S, C, MW> {
+        self.limiter
+    }
+}
+
+impl<K, S, C, MW, A> CorrelatedLimiter<K, S, C, MW, A>
+where
+    S: StateStore<Key = K>,
+    C: clock::Clock,
+    MW: RateLimitingMiddleware<C::Instant>,
+    A: DecisionAudit<K>,
+{
+    /// Stamps a decision with the next ID, and hands it to the audit.
+    fn stamp<T, E>(
+        &self,
+        key: &K,
+        cells: u32,
+        decision: Result<T, E>,
+    ) -> Result<Correlated<T>, Correlated<E>> {
+        // Saturates in the (theoretical) case that the IDs run out, instead of repeating them:
+        let id = DecisionId(
+            self.last_id
+                .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |id| id.checked_add(1))
+                .map_or(u64::MAX, |id| id + 1),
+        );
+        self.audit.decided(id, key, cells, decision.is_ok());
+        let label = self.limiter.label();
+        match decision {
+            Ok(outcome) => Ok(Correlated { id, label, outcome }),
+            Err(outcome) => Err(Correlated { id, label, outcome }),
+        }
+    }
+}
+
+/// # Correlated rate limiters - Manually checking cells
+impl<S, C, MW, A> CorrelatedLimiter<NotKeyed, S, C, MW, A>
+where
+    S: DirectStateStore,
+    C: clock::Clock,
+    MW: RateLimitingMiddleware<C::Instant>,
+    A: DecisionAudit<NotKeyed>,
+{
+    /// Allow a single cell through the rate limiter.
+    ///
+    /// See [`RateLimiter::check`].
+    pub fn check(
+        &self,
+    ) -> Result<Correlated<MW::PositiveOutcome>, Correlated<MW::NegativeOutcome>> {
+        self.stamp(&NotKeyed::NonKey, 1, self.limiter.check())
+    }
+
+    /// Allow *only all* `n` cells through the rate limiter.
+    ///
+    /// See [`RateLimiter::check_n`].
+    #[allow(clippy::type_complexity)]
+    pub fn check_n(
+        &self,
+        n: NonZeroU32,
+    ) -> Result<
+        Correlated<MW::PositiveOutcome>,
+        Correlated<NegativeMultiDecision<MW::NegativeOutcome>>,
+    > {
+        self.stamp(&NotKeyed::NonKey, n.get(), self.limiter.check_n(n))
+    }
+}
+
+/// # Keyed correlated rate limiters - Manually checking cells
+impl<K, S, C, MW, A> CorrelatedLimiter<K, S, C, MW, A>
+where
+    S: KeyedStateStore<K>,
+    K: Hash,
+    C: clock::Clock,
+    MW: RateLimitingMiddleware<C::Instant>,
+    A: DecisionAudit<K>,
+{
+    /// Allow a single cell through the rate limiter for the given key.
+    ///
+    /// See [`RateLimiter::check_key`].
+    pub fn check_key(
+        &self,
+        key: &K,
+    ) -> Result<Correlated<MW::PositiveOutcome>, Correlated<MW::NegativeOutcome>> {
+        self.stamp(key, 1, self.limiter.check_key(key))
+    }
+
+    /// Allow *only all* `n` cells through the rate limiter for the given key.
+    ///
+    /// See [`RateLimiter::check_key_n`].
+    #[allow(clippy::type_complexity)]
+    pub fn check_key_n(
+        &self,
+        key: &K,
+        n: NonZeroU32,
+    ) -> Result<
+        Correlated<MW::PositiveOutcome>,
+        Correlated<NegativeMultiDecision<MW::NegativeOutcome>>,
+    > {
+        self.stamp(key, n.get(), self.limiter.check_key_n(key, n))
+    }
+}
+
+impl<K, S, C, MW, A> fmt::Debug for CorrelatedLimiter<K, S, C, MW, A>
+where
+    S: StateStore<Key = K>,
+    C: clock::Clock,
+    MW: RateLimitingMiddleware<C::Instant>,
+    RateLimiter<K, S, C, MW>: fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CorrelatedLimiter")
+            .field("limiter", &self.limiter)
+            .field("last_id", &self.last_id())
+            .finish()
+    }
+}
+
+/// # Correlated rate limiters
+impl<K, S, C, MW> RateLimiter<K, S, C, MW>
+where
+    S: StateStore<Key = K>,
+    C: clock::Clock,
+    MW: RateLimitingMiddleware<C::Instant>,
+{
+    /// Wraps the rate limiter in a [`CorrelatedLimiter`] that stamps each decision with an ID.
+    pub fn into_correlated(self) -> CorrelatedLimiter<K, S, C, MW> {
+        CorrelatedLimiter::new(self)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::clock::FakeRelativeClock;
+    use crate::Quota;
+    use nonzero_ext::nonzero;
+
+    #[test]
+    fn ids_count_up() {
+        let clock = FakeRelativeClock::default();
+        let lim = RateLimiter::direct_with_clock(Quota::per_second(nonzero!(2u32)), &clock)
+            .into_correlated();
+        assert_eq!(lim.last_id(), None);
+        assert_eq!(lim.check().map(|d| d.id().get()), Ok(1));
+        let batch = lim.check_n(nonzero!(3u32)).unwrap_err();
+        assert_eq!(batch.id(), DecisionId(2));
+        assert!(matches!(
+            batch.outcome(),
+            NegativeMultiDecision::InsufficientCapacity(2)
+        ));
+        assert_eq!(lim.check().map(Correlated::into_outcome), Ok(()));
+        assert_eq!(lim.last_id(), Some(DecisionId(3)));
+        assert!(format!("{:?}", lim).contains("last_id: Some(DecisionId(3))"));
+        let _ = lim.into_inner();
+    }
+
+    #[test]
+    fn carries_the_label() {
+        let clock = FakeRelativeClock::default();
+        let lim = RateLimiter::direct_with_clock(Quota::per_second(nonzero!(1u32)), &clock)
+            .with_label("logins")
+            .into_correlated();
+        assert_eq!(lim.check().map(|d| d.label()), Ok(Some("logins")));
+        assert_eq!(lim.check().unwrap_err().label(), Some("logins"));
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn audits_keyed_decisions() {
+        use std::sync::Mutex;
+
+        let clock = FakeRelativeClock::default();
+        let log = Mutex::new(Vec::new());
+        let lim = RateLimiter::hashmap_with_clock(Quota::per_second(nonzero!(1u32)), &clock)
+            .into_correlated()
+            .with_audit(|id: DecisionId, key: &&'static str, cells, allowed| {
+                log.lock().unwrap().push((id.get(), *key, cells, allowed))
+            });
+        assert!(lim.check_key(&"a").is_ok());
+        assert!(lim.check_key_n(&"a", nonzero!(1u32)).is_err());
+        assert!(lim.check_key(&"b").is_ok());
+        assert_eq!(
+            *log.lock().unwrap(),
+            vec![(1, "a", 1, true), (2, "a", 1, false), (3, "b", 1, true)]
+        );
+    }
+}
@@ -3,7 +3,7 @@
 //! Rate limiters based on these types are constructed with
 //! [the `RateLimiter` constructors](../struct.RateLimiter.html#direct-in-memory-rate-limiters---constructors)
 
-use std::prelude::v1::*;
+use crate::compat::prelude::*;
 
 use std::num::NonZeroU32;
 
@@ -18,7 +18,7 @@ use crate::{
 ///
 /// It's possible to use this to create a "direct" rate limiter. It explicitly does not implement
 /// [`Hash`][std::hash::Hash] so that it is possible to tell apart from "hashable" key types.
-#[derive(PartialEq, Debug, Eq)]
+#[derive(PartialEq, Debug, Eq, Clone, Copy)]
 pub enum NotKeyed {
     /// The value given to state stores' methods.
     NonKey,
@@ -71,8 +71,9 @@ where
     ///
     /// If the rate limit is reached, `check` returns information about the earliest
     /// time that a cell might be allowed through again.
+    #[inline]
     pub fn check(&self) -> Result<MW::PositiveOutcome, MW::NegativeOutcome> {
-        self.gcra.test_and_update::<NotKeyed, C::Instant, S, MW>(
+        self.gcra().test_and_update::<NotKeyed, C::Instant, S, MW>(
             self.start,
             &NotKeyed::NonKey,
             &self.state,
@@ -98,7 +99,7 @@ where
         &self,
         n: NonZeroU32,
     ) -> Result<MW::PositiveOutcome, NegativeMultiDecision<MW::NegativeOutcome>> {
-        self.gcra
+        self.gcra()
             .test_n_all_and_update::<NotKeyed, C::Instant, S, MW>(
                 self.start,
                 &NotKeyed::NonKey,
@@ -107,13 +108,128 @@ where
                 self.clock.now(),
             )
     }
+
+    /// Returns the fraction of the burst capacity that is currently used up, between 0 (the
+    /// full burst capacity is available) and 1 (a cell would be rate-limited), without
+    /// consuming any cells.
+    ///
+    /// This is a measure of the rate limiter's load, e.g. for picking the least-loaded of
+    /// several rate-limited endpoints.
+    ///
+    /// ```rust
+    /// # use nonzero_ext::*;
+    /// use governor::{clock::FakeRelativeClock, Quota, RateLimiter};
+    ///
+    /// let clock = FakeRelativeClock::default();
+    /// let lim = RateLimiter::direct_with_clock(Quota::per_second(nonzero!(4u32)), &clock);
+    /// assert_eq!(lim.saturation(), 0.0);
+    /// lim.check_n(nonzero!(2u32)).unwrap();
+    /// assert_eq!(lim.saturation(), 0.5);
+    /// ```
+    pub fn saturation(&self) -> f64 {
+        self.gcra_in_epoch().saturation(
+            self.start,
+            &NotKeyed::NonKey,
+            &self.state,
+            self.clock.now(),
+        )
+    }
+
+    /// Checks a sequence of cells that arrived at the given times, in order, recording which ones
+    /// conform in the `conforming` bitmap: The decision for `arrivals[i]` is bit `i % 64` of
+    /// `conforming[i / 64]`. Returns the number of conforming cells.
+    ///
+    /// This is meant for workloads like userspace packet processing, where cells arrive in
+    /// batches: The entire sequence of decisions is computed in one pass, updating the rate
+    /// limiter's state only once. Note that this does not consult the rate limiter's clock
+    /// (the arrival times are used instead), and that the rate limiter's middleware is not
+    /// invoked for these decisions.
+    ///
+    /// # Panics
+    /// Panics if the bitmap can not hold `arrivals.len()` bits. This method is only available
+    /// with the `std` feature, so that `no_std` builds have no panicking decisions; use
+    /// [`try_check_arrivals`](#method.try_check_arrivals) there.
+    ///
+    /// # Example
+    /// ```rust
+    /// # use nonzero_ext::*;
+    /// # use std::time::Duration;
+    /// use governor::{clock::{Clock, FakeRelativeClock}, Quota, RateLimiter};
+    ///
+    /// let clock = FakeRelativeClock::default();
+    /// let lim = RateLimiter::direct_with_clock(Quota::per_second(nonzero!(2u32)), &clock);
+    /// let now = clock.now();
+    /// let arrivals = [now, now, now, now + Duration::from_millis(500)];
+    /// let mut conforming = [0u64; 1];
+    /// assert_eq!(3, lim.check_arrivals(&arrivals, &mut conforming));
+    /// assert_eq!(0b1011, conforming[0]);
+    /// ```
+    #[cfg(feature = "std")]
+    pub fn check_arrivals(&self, arrivals: &[C::Instant], conforming: &mut [u64]) -> usize {
+        let words = conforming.len();
+        self.try_check_arrivals(arrivals, conforming)
+            .unwrap_or_else(|| {
+                panic!(
+                    "bitmap of {} words can not hold {} decisions",
+                    words,
+                    arrivals.len()
+                )
+            })
+    }
+
+    /// Like [`check_arrivals`](#method.check_arrivals), but returns `None` (without making any
+    /// decisions) instead of panicking if the bitmap can not hold `arrivals.len()` bits.
+    pub fn try_check_arrivals(
+        &self,
+        arrivals: &[C::Instant],
+        conforming: &mut [u64],
+    ) -> Option<usize> {
+        self.gcra()
+            .test_arrivals_and_update::<NotKeyed, C::Instant, S>(
+                self.start,
+                &NotKeyed::NonKey,
+                &self.state,
+                arrivals,
+                conforming,
+            )
+    }
 }
 
+#[cfg(feature = "std")]
+mod channels;
+#[cfg(feature = "std")]
+pub use channels::*;
+
+#[cfg(feature = "std")]
+mod enforcement;
+#[cfg(feature = "std")]
+pub use enforcement::*;
+
+mod export;
+
 #[cfg(feature = "std")]
 mod future;
 #[cfg(feature = "std")]
 pub use future::*;
 
+#[cfg(feature = "std")]
+mod io;
+#[cfg(feature = "std")]
+pub use io::*;
+
+#[cfg(feature = "std")]
+mod lazy;
+#[cfg(feature = "std")]
+pub use lazy::*;
+
+#[cfg(all(feature = "std", feature = "rayon"))]
+mod rayon;
+#[cfg(all(feature = "std", feature = "rayon"))]
+pub use self::rayon::*;
+
+mod relaxed;
+pub use relaxed::*;
+
 #[cfg(feature = "std")]
 mod sinks;
 #[cfg(feature = "std")]
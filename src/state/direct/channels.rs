@@ -0,0 +1,133 @@
+use crate::{
+    clock,
+    middleware::RateLimitingMiddleware,
+    state::{DirectStateStore, NotKeyed},
+    Jitter, NotUntil, RateLimiter,
+};
+use std::sync::mpsc;
+use std::thread;
+
+/// The receiving half of a channel that can be read from by blocking the current thread.
+///
+/// This is implemented for [`std::sync::mpsc::Receiver`]; implement it for other channels (e.g.
+/// `crossbeam-channel`'s) to use them with [`RateLimiter::throttled_recv`] and
+/// [`RateLimiter::forward_throttled`].
+pub trait BlockingReceiver<T> {
+    /// Blocks until a value arrives, returning `None` once the channel is disconnected.
+    fn recv_blocking(&self) -> Option<T>;
+}
+
+/// The sending half of a channel that can be written to by blocking the current thread.
+///
+/// This is implemented for [`std::sync::mpsc::Sender`] and [`std::sync::mpsc::SyncSender`].
+pub trait BlockingSender<T> {
+    /// Sends a value, blocking if the channel is full; returns the value if the channel is
+    /// disconnected.
+    fn send_blocking(&self, value: T) -> Result<(), T>;
+}
+
+impl<T> BlockingReceiver<T> for mpsc::Receiver<T> {
+    fn recv_blocking(&self) -> Option<T> {
+        self.recv().ok()
+    }
+}
+
+impl<T> BlockingSender<T> for mpsc::Sender<T> {
+    fn send_blocking(&self, value: T) -> Result<(), T> {
+        self.send(value).map_err(|mpsc::SendError(value)| value)
+    }
+}
+
+impl<T> BlockingSender<T> for mpsc::SyncSender<T> {
+    fn send_blocking(&self, value: T) -> Result<(), T> {
+        self.send(value).map_err(|mpsc::SendError(value)| value)
+    }
+}
+
+/// # Direct rate limiters - Blocking the current thread
+///
+/// These methods wait for the rate limiter by putting the current thread to sleep, for
+/// threaded programs that want pacing without an async runtime.
+impl<S, C, MW> RateLimiter<NotKeyed, S, C, MW>
+where
+    S: DirectStateStore,
+    C: clock::ReasonablyRealtime,
+    MW: RateLimitingMiddleware<C::Instant, NegativeOutcome = NotUntil<C::Instant>>,
+{
+    /// Blocks the current thread until the rate limiter allows a cell through.
+    pub fn until_ready_blocking(&self) -> MW::PositiveOutcome {
+        self.until_ready_blocking_with_jitter(Jitter::NONE)
+    }
+
+    /// Blocks the current thread until the rate limiter allows a cell through, with a
+    /// randomized wait period.
+    pub fn until_ready_blocking_with_jitter(&self, jitter: Jitter) -> MW::PositiveOutcome {
+        loop {
+            match self.check() {
+                Ok(x) => return x,
+                Err(negative) => {
+                    thread::sleep(jitter + negative.wait_time_from(self.clock.now()));
+                }
+            }
+        }
+    }
+
+    /// Receives a value from a channel, blocking until one arrives and the rate limiter
+    /// allows it through. Returns `None` once the channel is disconnected.
+    ///
+    /// # Example
+    /// ```rust
+    /// # use nonzero_ext::*;
+    /// use governor::{Quota, RateLimiter};
+    /// use std::sync::mpsc;
+    ///
+    /// let lim = RateLimiter::direct(Quota::per_second(nonzero!(50u32)));
+    /// let (tx, rx) = mpsc::channel();
+    /// tx.send("job").unwrap();
+    /// drop(tx);
+    /// assert_eq!(lim.throttled_recv(&rx), Some("job"));
+    /// assert_eq!(lim.throttled_recv(&rx), None);
+    /// ```
+    pub fn throttled_recv<T>(&self, receiver: &impl BlockingReceiver<T>) -> Option<T> {
+        let value = receiver.recv_blocking()?;
+        self.until_ready_blocking();
+        Some(value)
+    }
+
+    /// Forwards values from `receiver` to `sender` at the rate that the rate limiter allows,
+    /// blocking the current thread until either of them is disconnected. Returns the number of
+    /// values forwarded.
+    ///
+    /// Values are received before waiting for the rate limiter, so that no capacity is used
+    /// up while the receiver is idle.
+    ///
+    /// # Example
+    /// ```rust
+    /// # use nonzero_ext::*;
+    /// use governor::{Quota, RateLimiter};
+    /// use std::{sync::mpsc, thread};
+    ///
+    /// let lim = RateLimiter::direct(Quota::per_second(nonzero!(50u32)));
+    /// let (input, rx) = mpsc::channel();
+    /// let (tx, output) = mpsc::sync_channel(1);
+    /// let forwarder = thread::spawn(move || lim.forward_throttled(&rx, &tx));
+    /// input.send(1).unwrap();
+    /// assert_eq!(output.recv(), Ok(1));
+    /// drop(input);
+    /// assert_eq!(forwarder.join().unwrap(), 1);
+    /// ```
+    pub fn forward_throttled<T>(
+        &self,
+        receiver: &impl BlockingReceiver<T>,
+        sender: &impl BlockingSender<T>,
+    ) -> usize {
+        let mut forwarded = 0;
+        while let Some(value) = self.throttled_recv(receiver) {
+            if sender.send_blocking(value).is_err() {
+                break;
+            }
+            forwarded += 1;
+        }
+        forwarded
+    }
+}
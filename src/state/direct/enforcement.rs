@@ -0,0 +1,121 @@
+use crate::compat::prelude::*;
+
+use crate::compat::collections::VecDeque;
+use std::num::NonZeroUsize;
+
+/// How a stream or sink combinator enforces its rate limiter on items that arrive faster than
+/// the rate limiter allows.
+///
+/// The combinators shape by default, holding back one item at a time:
+/// ```rust
+/// # use governor::state::direct::{Enforcement, Overflow};
+/// # use std::num::NonZeroUsize;
+/// assert_eq!(
+///     Enforcement::default(),
+///     Enforcement::Shape {
+///         max_queue_depth: NonZeroUsize::MIN,
+///         overflow: Overflow::Backpressure,
+///     }
+/// );
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Enforcement {
+    /// Polices the items: An item that the rate limiter doesn't allow right away is dropped.
+    Police,
+
+    /// Shapes the items: Items are queued, and passed on as soon as the rate limiter allows
+    /// them.
+    Shape {
+        /// The number of items that can wait in the queue.
+        max_queue_depth: NonZeroUsize,
+        /// What happens to items that arrive while the queue is full.
+        overflow: Overflow,
+    },
+}
+
+impl Enforcement {
+    /// Shapes the items, queueing up to `max_queue_depth` of them and handling any more as
+    /// `overflow` says.
+    pub const fn shape(max_queue_depth: NonZeroUsize, overflow: Overflow) -> Enforcement {
+        Enforcement::Shape {
+            max_queue_depth,
+            overflow,
+        }
+    }
+}
+
+impl Default for Enforcement {
+    fn default() -> Self {
+        Enforcement::shape(NonZeroUsize::MIN, Overflow::Backpressure)
+    }
+}
+
+/// What a shaping combinator does with an item that arrives while its queue is full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Overflow {
+    /// Stops accepting items until there is room in the queue: A stream stops reading its
+    /// underlying stream, and a sink isn't ready.
+    Backpressure,
+
+    /// Drops the item that arrived.
+    DropNewest,
+
+    /// Drops the oldest item in the queue to make room for the one that arrived.
+    DropOldest,
+}
+
+/// The queue of a shaping combinator, and the count of the items that its enforcement dropped.
+#[derive(Debug)]
+pub(super) struct Queue<T> {
+    pub(super) enforcement: Enforcement,
+    pub(super) items: VecDeque<T>,
+    pub(super) dropped: u64,
+}
+
+impl<T> Queue<T> {
+    pub(super) fn new() -> Self {
+        Queue {
+            enforcement: Enforcement::default(),
+            items: VecDeque::new(),
+            dropped: 0,
+        }
+    }
+
+    /// Returns whether another item can arrive: Unless the queue exerts backpressure, items
+    /// that overflow it are dropped.
+    pub(super) fn accepts(&self) -> bool {
+        match self.enforcement {
+            Enforcement::Police => true,
+            Enforcement::Shape { overflow, .. } => {
+                overflow != Overflow::Backpressure || self.items.len() < self.max_depth()
+            }
+        }
+    }
+
+    /// Returns the number of items that can wait in the queue.
+    pub(super) fn max_depth(&self) -> usize {
+        match self.enforcement {
+            Enforcement::Police => 1,
+            Enforcement::Shape {
+                max_queue_depth, ..
+            } => max_queue_depth.get(),
+        }
+    }
+
+    /// Queues an item, dropping one if the queue overflows.
+    pub(super) fn push(&mut self, item: T) {
+        if self.items.len() < self.max_depth() {
+            self.items.push_back(item);
+            return;
+        }
+        self.dropped += 1;
+        if let Enforcement::Shape {
+            overflow: Overflow::DropOldest,
+            ..
+        } = self.enforcement
+        {
+            self.items.pop_front();
+            self.items.push_back(item);
+        }
+    }
+}
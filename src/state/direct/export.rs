@@ -0,0 +1,142 @@
+use crate::{
+    clock,
+    middleware::RateLimitingMiddleware,
+    nanos::Nanos,
+    state::{InMemoryState, NotKeyed, WireFormat, WireStateError},
+    RateLimiter,
+};
+
+/// # Direct in-memory rate limiters - Exporting state
+///
+/// Hosts that run several isolated instances of a program (e.g. the worker VMs of a proxy-wasm
+/// filter) can share a rate limit by storing the rate limiter's state in a place they all can
+/// reach, like the host's shared data: Before checking cells, an instance imports the shared
+/// state into its rate limiter; afterwards, it exports the updated state and stores it back
+/// (using compare-and-swap, if the host offers it).
+///
+/// The exported state is the theoretical arrival time as a number of nanoseconds on the rate
+/// limiter's clock, so it is only meaningful to rate limiters whose clocks agree, e.g.
+/// [`ExternalClock`][clock::ExternalClock]s that are set from the same host time.
+impl<C, MW> RateLimiter<NotKeyed, InMemoryState, C, MW>
+where
+    C: clock::Clock<Instant = Nanos>,
+    MW: RateLimitingMiddleware<Nanos>,
+{
+    /// Returns the rate limiter's state as eight little-endian bytes. A fresh state is exported
+    /// as all zeroes.
+    pub fn export_state(&self) -> [u8; 8] {
+        let tat = match self.state.load() {
+            Some(tat) => (self.start + self.gcra().nanos_since_start(tat)).as_u64(),
+            None => 0,
+        };
+        tat.to_le_bytes()
+    }
+
+    /// Replaces the rate limiter's state with one exported by
+    /// [`export_state`](#method.export_state).
+    ///
+    /// States that lie before the rate limiter was constructed are indistinguishable from a
+    /// fresh state, and are imported as one.
+    pub fn import_state(&self, state: [u8; 8]) {
+        let tat = Nanos::from(u64::from_le_bytes(state));
+        self.import_tat(Some(tat).filter(|tat| tat.as_u64() != 0));
+    }
+
+    /// Returns the rate limiter's state in the compact [`WireFormat`], e.g. for keeping it in a
+    /// remote store: quantized to milliseconds, and tagged with the format's version and epoch.
+    pub fn export_wire_state(&self, format: &WireFormat) -> [u8; 8] {
+        let gcra = self.gcra();
+        format.encode(
+            self.state
+                .load()
+                .map(|tat| self.start + gcra.nanos_since_start(tat)),
+        )
+    }
+
+    /// Replaces the rate limiter's state with one exported by
+    /// [`export_wire_state`](#method.export_wire_state).
+    ///
+    /// States of another format version or epoch are rejected, and leave the rate limiter's
+    /// state as it is.
+    pub fn import_wire_state(
+        &self,
+        format: &WireFormat,
+        state: [u8; 8],
+    ) -> Result<(), WireStateError> {
+        self.import_tat(format.decode(state)?);
+        Ok(())
+    }
+
+    fn import_tat(&self, tat: Option<Nanos>) {
+        self.state.store(match tat {
+            Some(tat) if tat > self.start => Some(
+                self.gcra()
+                    .units_since_start(Nanos::from(tat.as_u64() - self.start.as_u64())),
+            ),
+            _ => None,
+        });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::clock::ExternalClock;
+    use crate::state::{WireFormat, WireStateError};
+    use crate::{Quota, RateLimiter};
+    use nonzero_ext::nonzero;
+
+    #[test]
+    fn state_roundtrips_between_rate_limiters() {
+        let clock = ExternalClock::default();
+        clock.set_now_millis(1_000);
+        let quota = Quota::per_second(nonzero!(2u32));
+        let first = RateLimiter::direct_with_clock(quota, &clock);
+        assert_eq!(first.export_state(), [0; 8]);
+        first.check().unwrap();
+        first.check().unwrap();
+
+        clock.set_now_millis(1_100);
+        let second = RateLimiter::direct_with_clock(quota, &clock);
+        second.import_state(first.export_state());
+        assert_eq!(second.export_state(), first.export_state());
+        assert!(second.check().is_err());
+
+        clock.set_now_millis(1_500);
+        assert_eq!(Ok(()), second.check());
+
+        second.import_state([0; 8]);
+        assert_eq!(second.export_state(), [0; 8]);
+        second.import_state(1_000u64.to_le_bytes());
+        assert_eq!(second.export_state(), [0; 8]);
+    }
+
+    #[test]
+    fn wire_state_roundtrips() {
+        let clock = ExternalClock::default();
+        clock.set_now_millis(1_000);
+        let quota = Quota::per_second(nonzero!(2u32));
+        let format = WireFormat::new(1);
+        let first = RateLimiter::direct_with_clock(quota, &clock);
+        assert_eq!(first.export_wire_state(&format), [0; 8]);
+        first.check_n(nonzero!(2u32)).unwrap();
+
+        let second = RateLimiter::direct_with_clock(quota, &clock);
+        second
+            .import_wire_state(&format, first.export_wire_state(&format))
+            .unwrap();
+        assert!(second.check().is_err());
+        clock.set_now_millis(1_500);
+        assert_eq!(Ok(()), second.check());
+
+        let other_epoch = WireFormat::new(2).encode(None);
+        assert_eq!(second.import_wire_state(&format, other_epoch), Ok(()));
+        let other_epoch = WireFormat::new(2).encode(Some(5_000_000_000u64.into()));
+        assert_eq!(
+            second.import_wire_state(&format, other_epoch),
+            Err(WireStateError::EpochMismatch {
+                expected: 1,
+                found: 2
+            })
+        );
+    }
+}
@@ -4,21 +4,36 @@ use super::RateLimiter;
 use crate::{
     clock,
     middleware::RateLimitingMiddleware,
-    state::{DirectStateStore, NotKeyed},
+    state::{wait::wait, DirectStateStore, NotKeyed},
     Jitter, NegativeMultiDecision, NotUntil,
 };
-use futures_timer::Delay;
+use futures::stream::{self, Stream};
+use std::cmp;
+use std::ops::ControlFlow;
+use std::time::Duration;
 
 /// An error that occurs when the number of cells required in `check_n`
 /// exceeds the maximum capacity of the limiter.
-#[derive(Debug, Clone)]
+///
+/// The argument is the rate limiter's burst capacity, i.e. the largest
+/// number of cells that can ever be let through at once; batches can be
+/// split into chunks of at most that size.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub struct InsufficientCapacity(pub u32);
 
+impl InsufficientCapacity {
+    /// Returns the largest number of cells that the rate limiter can let through at once.
+    pub fn capacity(&self) -> u32 {
+        self.0
+    }
+}
+
 impl fmt::Display for InsufficientCapacity {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(
             f,
-            "required number of cell {} exceeds bucket's capacity",
+            "required number of cells exceeds bucket's capacity of {}",
             self.0
         )
     }
@@ -26,6 +41,34 @@ impl fmt::Display for InsufficientCapacity {
 
 impl Error for InsufficientCapacity {}
 
+/// An error that occurs when a rate limiter doesn't let cells through before the timeout of a
+/// wait for it runs out.
+///
+/// The argument is the rate limiter's last negative outcome, which tells when the cells would
+/// have been let through.
+#[derive(Debug, PartialEq)]
+pub struct TimedOut<P: clock::Reference>(pub NotUntil<P>);
+
+impl<P: clock::Reference> TimedOut<P> {
+    /// Returns the rate limiter's last negative outcome.
+    pub fn not_until(&self) -> &NotUntil<P> {
+        &self.0
+    }
+
+    /// Returns the rate limiter's last negative outcome, dropping the error.
+    pub fn into_not_until(self) -> NotUntil<P> {
+        self.0
+    }
+}
+
+impl<P: clock::Reference> fmt::Display for TimedOut<P> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "timed out while {}", self.0)
+    }
+}
+
+impl<P: clock::Reference> Error for TimedOut<P> {}
+
 #[cfg(feature = "std")]
 /// # Direct rate limiters - `async`/`await`
 impl<S, C, MW> RateLimiter<NotKeyed, S, C, MW>
@@ -59,17 +102,13 @@ where
     /// which can help reduce the likelihood of thundering herd effects if multiple tasks try to
     /// wait on the same rate limiter.
     pub async fn until_ready_with_jitter(&self, jitter: Jitter) -> MW::PositiveOutcome {
-        loop {
-            match self.check() {
-                Ok(x) => {
-                    return x;
-                }
-                Err(negative) => {
-                    let delay = Delay::new(jitter + negative.wait_time_from(self.clock.now()));
-                    delay.await;
-                }
+        wait(self, &NotKeyed::NonKey, None, || match self.check() {
+            Ok(x) => ControlFlow::Break(x),
+            Err(negative) => {
+                ControlFlow::Continue(jitter + negative.wait_time_from(self.clock.now()))
             }
-        }
+        })
+        .await
     }
 
     /// Asynchronously resolves as soon as the rate limiter allows it.
@@ -99,20 +138,162 @@ where
         n: NonZeroU32,
         jitter: Jitter,
     ) -> Result<MW::PositiveOutcome, InsufficientCapacity> {
-        loop {
-            match self.check_n(n) {
-                Ok(x) => {
-                    return Ok(x);
-                }
-                Err(NegativeMultiDecision::BatchNonConforming(_, negative)) => {
-                    let delay = Delay::new(jitter + negative.wait_time_from(self.clock.now()));
-                    delay.await;
+        wait(self, &NotKeyed::NonKey, None, || match self.check_n(n) {
+            Ok(x) => ControlFlow::Break(Ok(x)),
+            Err(NegativeMultiDecision::BatchNonConforming(_, negative)) => {
+                ControlFlow::Continue(jitter + negative.wait_time_from(self.clock.now()))
+            }
+            Err(NegativeMultiDecision::InsufficientCapacity(cap)) => {
+                ControlFlow::Break(Err(InsufficientCapacity(cap)))
+            }
+        })
+        .await
+    }
+
+    /// Asynchronously resolves as soon as the rate limiter allows it, or with an error once
+    /// `timeout` has passed without the rate limiter allowing it.
+    ///
+    /// This saves racing [`until_ready`](#method.until_ready) against a timer: The error
+    /// holds the rate limiter's last negative outcome, which tells a timeout apart from other
+    /// failures of the caller's.
+    ///
+    /// # Example
+    /// ```rust
+    /// # use nonzero_ext::*;
+    /// # use futures::executor::block_on;
+    /// # use std::time::Duration;
+    /// use governor::{Quota, RateLimiter};
+    ///
+    /// let lim = RateLimiter::direct(Quota::per_minute(nonzero!(1u32)));
+    /// let timeout = Duration::from_millis(10);
+    /// assert_eq!(block_on(lim.until_ready_with_timeout(timeout)), Ok(()));
+    /// assert!(block_on(lim.until_ready_with_timeout(timeout)).is_err());
+    /// ```
+    pub async fn until_ready_with_timeout(
+        &self,
+        timeout: Duration,
+    ) -> Result<MW::PositiveOutcome, TimedOut<C::Instant>> {
+        let deadline = self.clock.now() + timeout.into();
+        wait(self, &NotKeyed::NonKey, None, || match self.check() {
+            Ok(x) => ControlFlow::Break(Ok(x)),
+            Err(negative) => match self.until_deadline(negative, deadline) {
+                ControlFlow::Break(timed_out) => ControlFlow::Break(Err(timed_out)),
+                ControlFlow::Continue(wait) => ControlFlow::Continue(wait),
+            },
+        })
+        .await
+    }
+
+    /// Asynchronously resolves as soon as the rate limiter allows all `n` cells through, or
+    /// with an error once `timeout` has passed without the rate limiter allowing them.
+    ///
+    /// The error is `BatchNonConforming` if the wait timed out, and `InsufficientCapacity` right
+    /// away if the `n` provided exceeds the maximum capacity of the rate limiter.
+    pub async fn until_n_ready_with_timeout(
+        &self,
+        n: NonZeroU32,
+        timeout: Duration,
+    ) -> Result<MW::PositiveOutcome, NegativeMultiDecision<TimedOut<C::Instant>>> {
+        let deadline = self.clock.now() + timeout.into();
+        wait(self, &NotKeyed::NonKey, None, || match self.check_n(n) {
+            Ok(x) => ControlFlow::Break(Ok(x)),
+            Err(NegativeMultiDecision::BatchNonConforming(n, negative)) => {
+                match self.until_deadline(negative, deadline) {
+                    ControlFlow::Break(timed_out) => ControlFlow::Break(Err(
+                        NegativeMultiDecision::BatchNonConforming(n, timed_out),
+                    )),
+                    ControlFlow::Continue(wait) => ControlFlow::Continue(wait),
                 }
-                Err(NegativeMultiDecision::InsufficientCapacity(cap)) => {
-                    return Err(InsufficientCapacity(cap))
+            }
+            Err(NegativeMultiDecision::InsufficientCapacity(cap)) => {
+                ControlFlow::Break(Err(NegativeMultiDecision::InsufficientCapacity(cap)))
+            }
+        })
+        .await
+    }
+
+    /// Splits a batch of `n` cells into chunks that the rate limiter can let through at once,
+    /// and returns a stream that yields the size of each chunk as soon as the rate limiter
+    /// allows it.
+    ///
+    /// Each chunk is at most as large as the rate limiter's burst size, so unlike
+    /// [`until_n_ready`](#method.until_n_ready), this doesn't fail with
+    /// `InsufficientCapacity`: While a [pressure](#method.set_pressure) shrinks the burst
+    /// capacity, chunks shrink along with it. A batch of zero cells yields no chunks.
+    ///
+    /// If the pressure leaves no capacity for even a single cell, the stream ends early, and
+    /// the chunks it yielded add up to less than `n`.
+    ///
+    /// # Example
+    /// ```rust
+    /// # use nonzero_ext::*;
+    /// use futures::{executor::block_on, StreamExt};
+    /// use governor::{Quota, RateLimiter};
+    ///
+    /// let lim = RateLimiter::direct(Quota::per_second(nonzero!(1000u32)).allow_burst(nonzero!(100u32)));
+    /// let chunks: Vec<u32> = block_on(lim.pace_batch(250).map(|n| n.get()).collect());
+    /// assert_eq!(chunks, vec![100, 100, 50]);
+    /// ```
+    pub fn pace_batch(&self, n: u32) -> impl Stream<Item = NonZeroU32> + '_ {
+        self.pace_batch_with_jitter(n, Jitter::NONE)
+    }
+
+    /// Splits a batch of `n` cells into chunks that the rate limiter can let through at once,
+    /// and returns a stream that yields the size of each chunk as soon as the rate limiter
+    /// allows it, with a randomized wait period.
+    ///
+    /// See [`pace_batch`](#method.pace_batch).
+    pub fn pace_batch_with_jitter(
+        &self,
+        n: u32,
+        jitter: Jitter,
+    ) -> impl Stream<Item = NonZeroU32> + '_ {
+        let burst = self.gcra.quota().burst_size().get();
+        stream::unfold(n, move |remaining| async move {
+            let mut chunk = NonZeroU32::new(cmp::min(remaining, burst))?;
+            loop {
+                match self.until_n_ready_with_jitter(chunk, jitter).await {
+                    Ok(_) => return Some((chunk, remaining - chunk.get())),
+                    // The pressure shrank the burst capacity below the chunk:
+                    Err(InsufficientCapacity(capacity)) => chunk = NonZeroU32::new(capacity)?,
                 }
             }
-        }
+        })
+    }
+
+    /// Returns a stream that yields every time the rate limiter lets a cell through, like an
+    /// interval timer ticking at the quota's rate.
+    ///
+    /// The stream lets cells through as fast as the quota allows, so it first yields a burst of
+    /// as many ticks as the quota's burst size; use a quota with a burst size of 1 for evenly
+    /// spaced ticks. Cells let through by other users of the rate limiter delay the ticks
+    /// accordingly. The stream never ends.
+    ///
+    /// # Example
+    /// ```rust
+    /// # use nonzero_ext::*;
+    /// use futures::{executor::block_on, StreamExt};
+    /// use governor::{Quota, RateLimiter};
+    ///
+    /// let lim = RateLimiter::direct(Quota::per_second(nonzero!(100u32)).allow_burst(nonzero!(1u32)));
+    /// // Ticks every 10ms:
+    /// block_on(lim.ticks().take(5).for_each(|()| async { /* ... */ }));
+    /// ```
+    pub fn ticks(&self) -> impl Stream<Item = MW::PositiveOutcome> + '_ {
+        self.ticks_with_jitter(Jitter::NONE)
+    }
+
+    /// Returns a stream that yields every time the rate limiter lets a cell through, with a
+    /// randomized wait period before each tick that has to wait.
+    ///
+    /// See [`ticks`](#method.ticks).
+    pub fn ticks_with_jitter(
+        &self,
+        jitter: Jitter,
+    ) -> impl Stream<Item = MW::PositiveOutcome> + '_ {
+        stream::unfold((), move |()| async move {
+            Some((self.until_ready_with_jitter(jitter).await, ()))
+        })
     }
 }
 
@@ -123,7 +304,8 @@ mod test {
     #[test]
     fn insufficient_capacity_impl_coverage() {
         let i = InsufficientCapacity(1);
-        assert_eq!(i.0, i.clone().0);
-        assert!(format!("{}", i).len() > 0);
+        assert_eq!(i, i.clone());
+        assert_eq!(i.capacity(), 1);
+        assert!(!format!("{}", i).is_empty());
     }
 }
@@ -0,0 +1,348 @@
+use crate::compat::prelude::*;
+
+use crate::{
+    clock,
+    middleware::RateLimitingMiddleware,
+    state::{DirectStateStore, NotKeyed},
+    Jitter, NegativeMultiDecision, NotUntil, RateLimiter,
+};
+use futures::io::{AsyncRead, AsyncWrite};
+use futures::task::{Context, Poll};
+use futures::{ready, Future};
+use futures_timer::Delay;
+use std::cmp;
+use std::io;
+use std::num::NonZeroU32;
+use std::pin::Pin;
+
+/// The rate-limiting part of the throttled I/O wrappers: Each byte is one cell, and cells are
+/// acquired from the rate limiter in chunks of at most `chunk` bytes.
+struct Throttle<'a, D, C, MW>
+where
+    D: DirectStateStore,
+    C: clock::ReasonablyRealtime,
+    MW: RateLimitingMiddleware<C::Instant, NegativeOutcome = NotUntil<C::Instant>>,
+{
+    limiter: &'a RateLimiter<NotKeyed, D, C, MW>,
+    chunk: NonZeroU32,
+    jitter: Jitter,
+    delay: Delay,
+    waiting: bool,
+    /// The number of bytes that the rate limiter let through, but that weren't transferred yet.
+    granted: u32,
+}
+
+impl<'a, D, C, MW> Throttle<'a, D, C, MW>
+where
+    D: DirectStateStore,
+    C: clock::ReasonablyRealtime,
+    MW: RateLimitingMiddleware<C::Instant, NegativeOutcome = NotUntil<C::Instant>>,
+{
+    fn new(limiter: &'a RateLimiter<NotKeyed, D, C, MW>) -> Self {
+        Throttle {
+            limiter,
+            chunk: limiter.gcra.quota().burst_size(),
+            jitter: Jitter::NONE,
+            delay: Delay::new(Default::default()),
+            waiting: false,
+            granted: 0,
+        }
+    }
+
+    fn set_chunk_size(&mut self, chunk: NonZeroU32) {
+        // Chunks larger than the burst size could never be let through:
+        self.chunk = cmp::min(chunk, self.limiter.gcra.quota().burst_size());
+    }
+
+    /// Resolves to the number of bytes (at most `wanted`, which must be non-zero) that may be
+    /// transferred now.
+    fn poll_grant(&mut self, cx: &mut Context<'_>, wanted: usize) -> Poll<usize> {
+        loop {
+            if self.granted > 0 {
+                return Poll::Ready(cmp::min(self.granted as usize, wanted));
+            }
+            if self.waiting {
+                ready!(Pin::new(&mut self.delay).poll(cx));
+                self.waiting = false;
+            }
+            let n = cmp::min(wanted, self.chunk.get() as usize) as u32;
+            let n = NonZeroU32::new(n).expect("must not request a grant for zero bytes");
+            let reference = self.limiter.reference_reading();
+            match self.limiter.check_n(n) {
+                Ok(_) => self.granted = n.get(),
+                Err(NegativeMultiDecision::BatchNonConforming(_, negative)) => {
+                    let earliest = negative.wait_time_with_offset(reference, self.jitter);
+                    self.delay.reset(earliest);
+                    self.waiting = true;
+                }
+                Err(NegativeMultiDecision::InsufficientCapacity(_)) => {
+                    unreachable!("chunks never exceed the burst size"); // !no_rcov!
+                }
+            }
+        }
+    }
+
+    fn consume(&mut self, n: usize) {
+        self.granted -= n as u32;
+    }
+}
+
+/// An [`AsyncWrite`] wrapper that limits the rate at which bytes are written to the inner
+/// writer, counting each byte as one cell of the rate limiter's quota.
+///
+/// The writer acquires cells for at most one chunk of bytes at a time; by default, chunks are
+/// as large as the quota's burst size. Smaller chunks (see
+/// [`with_chunk_size`](#method.with_chunk_size)) make for smoother, but more frequent writes.
+///
+/// This wraps writers that implement the `futures-io` traits, and, with the `tokio` feature,
+/// writers that implement tokio's `AsyncWrite`.
+///
+/// # Example
+/// ```rust
+/// # use nonzero_ext::*;
+/// use futures::{executor::block_on, io::AsyncWriteExt};
+/// use governor::{state::direct::ThrottledWriter, Quota, RateLimiter};
+///
+/// // Write at most 64KiB per second:
+/// let lim = RateLimiter::direct(Quota::per_second(nonzero!(65536u32)));
+/// let mut writer = ThrottledWriter::new(Vec::new(), &lim);
+/// block_on(writer.write_all(b"hello, world")).unwrap();
+/// assert_eq!(writer.into_inner(), b"hello, world");
+/// ```
+pub struct ThrottledWriter<'a, W, D, C, MW>
+where
+    D: DirectStateStore,
+    C: clock::ReasonablyRealtime,
+    MW: RateLimitingMiddleware<C::Instant, NegativeOutcome = NotUntil<C::Instant>>,
+{
+    inner: W,
+    throttle: Throttle<'a, D, C, MW>,
+}
+
+impl<'a, W, D, C, MW> ThrottledWriter<'a, W, D, C, MW>
+where
+    D: DirectStateStore,
+    C: clock::ReasonablyRealtime,
+    MW: RateLimitingMiddleware<C::Instant, NegativeOutcome = NotUntil<C::Instant>>,
+{
+    /// Wraps a writer, limiting the rate at which bytes are written to it.
+    pub fn new(inner: W, limiter: &'a RateLimiter<NotKeyed, D, C, MW>) -> Self {
+        ThrottledWriter {
+            inner,
+            throttle: Throttle::new(limiter),
+        }
+    }
+
+    /// Sets the largest number of bytes to write in one go. This is capped at the quota's
+    /// burst size.
+    pub fn with_chunk_size(mut self, chunk: NonZeroU32) -> Self {
+        self.throttle.set_chunk_size(chunk);
+        self
+    }
+
+    /// Adds a randomized wait period to each wait for the rate limiter.
+    pub fn with_jitter(mut self, jitter: Jitter) -> Self {
+        self.throttle.jitter = jitter;
+        self
+    }
+
+    /// Acquires a reference to the underlying writer.
+    pub fn get_ref(&self) -> &W {
+        &self.inner
+    }
+
+    /// Acquires a mutable reference to the underlying writer.
+    ///
+    /// Bytes written through this reference aren't rate-limited.
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.inner
+    }
+
+    /// Consumes the wrapper, returning the underlying writer.
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+}
+
+impl<'a, W, D, C, MW> AsyncWrite for ThrottledWriter<'a, W, D, C, MW>
+where
+    W: AsyncWrite + Unpin,
+    D: DirectStateStore,
+    C: clock::ReasonablyRealtime,
+    MW: RateLimitingMiddleware<C::Instant, NegativeOutcome = NotUntil<C::Instant>>,
+{
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if buf.is_empty() {
+            return Pin::new(&mut this.inner).poll_write(cx, buf);
+        }
+        let granted = ready!(this.throttle.poll_grant(cx, buf.len()));
+        let result = ready!(Pin::new(&mut this.inner).poll_write(cx, &buf[..granted]));
+        if let Ok(written) = result {
+            this.throttle.consume(written);
+        }
+        Poll::Ready(result)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_close(cx)
+    }
+}
+
+/// An [`AsyncRead`] wrapper that limits the rate at which bytes are read from the inner
+/// reader, counting each byte as one cell of the rate limiter's quota.
+///
+/// Like [`ThrottledWriter`], the reader acquires cells for at most one chunk of bytes at a
+/// time, and reads at most that many bytes from the inner reader. With the `tokio` feature, it
+/// also wraps readers that implement tokio's `AsyncRead`.
+pub struct ThrottledReader<'a, R, D, C, MW>
+where
+    D: DirectStateStore,
+    C: clock::ReasonablyRealtime,
+    MW: RateLimitingMiddleware<C::Instant, NegativeOutcome = NotUntil<C::Instant>>,
+{
+    inner: R,
+    throttle: Throttle<'a, D, C, MW>,
+}
+
+impl<'a, R, D, C, MW> ThrottledReader<'a, R, D, C, MW>
+where
+    D: DirectStateStore,
+    C: clock::ReasonablyRealtime,
+    MW: RateLimitingMiddleware<C::Instant, NegativeOutcome = NotUntil<C::Instant>>,
+{
+    /// Wraps a reader, limiting the rate at which bytes are read from it.
+    pub fn new(inner: R, limiter: &'a RateLimiter<NotKeyed, D, C, MW>) -> Self {
+        ThrottledReader {
+            inner,
+            throttle: Throttle::new(limiter),
+        }
+    }
+
+    /// Sets the largest number of bytes to read in one go. This is capped at the quota's
+    /// burst size.
+    pub fn with_chunk_size(mut self, chunk: NonZeroU32) -> Self {
+        self.throttle.set_chunk_size(chunk);
+        self
+    }
+
+    /// Adds a randomized wait period to each wait for the rate limiter.
+    pub fn with_jitter(mut self, jitter: Jitter) -> Self {
+        self.throttle.jitter = jitter;
+        self
+    }
+
+    /// Acquires a reference to the underlying reader.
+    pub fn get_ref(&self) -> &R {
+        &self.inner
+    }
+
+    /// Acquires a mutable reference to the underlying reader.
+    ///
+    /// Bytes read through this reference aren't rate-limited.
+    pub fn get_mut(&mut self) -> &mut R {
+        &mut self.inner
+    }
+
+    /// Consumes the wrapper, returning the underlying reader.
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+}
+
+impl<'a, R, D, C, MW> AsyncRead for ThrottledReader<'a, R, D, C, MW>
+where
+    R: AsyncRead + Unpin,
+    D: DirectStateStore,
+    C: clock::ReasonablyRealtime,
+    MW: RateLimitingMiddleware<C::Instant, NegativeOutcome = NotUntil<C::Instant>>,
+{
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if buf.is_empty() {
+            return Pin::new(&mut this.inner).poll_read(cx, buf);
+        }
+        let granted = ready!(this.throttle.poll_grant(cx, buf.len()));
+        let result = ready!(Pin::new(&mut this.inner).poll_read(cx, &mut buf[..granted]));
+        if let Ok(read) = result {
+            this.throttle.consume(read);
+        }
+        Poll::Ready(result)
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl<'a, W, D, C, MW> tokio::io::AsyncWrite for ThrottledWriter<'a, W, D, C, MW>
+where
+    W: tokio::io::AsyncWrite + Unpin,
+    D: DirectStateStore,
+    C: clock::ReasonablyRealtime,
+    MW: RateLimitingMiddleware<C::Instant, NegativeOutcome = NotUntil<C::Instant>>,
+{
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if buf.is_empty() {
+            return Pin::new(&mut this.inner).poll_write(cx, buf);
+        }
+        let granted = ready!(this.throttle.poll_grant(cx, buf.len()));
+        let result = ready!(Pin::new(&mut this.inner).poll_write(cx, &buf[..granted]));
+        if let Ok(written) = result {
+            this.throttle.consume(written);
+        }
+        Poll::Ready(result)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl<'a, R, D, C, MW> tokio::io::AsyncRead for ThrottledReader<'a, R, D, C, MW>
+where
+    R: tokio::io::AsyncRead + Unpin,
+    D: DirectStateStore,
+    C: clock::ReasonablyRealtime,
+    MW: RateLimitingMiddleware<C::Instant, NegativeOutcome = NotUntil<C::Instant>>,
+{
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut tokio::io::ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if buf.remaining() == 0 {
+            return Pin::new(&mut this.inner).poll_read(cx, buf);
+        }
+        let granted = ready!(this.throttle.poll_grant(cx, buf.remaining()));
+        // Read into at most the granted part of the buffer:
+        let mut limited = tokio::io::ReadBuf::new(buf.initialize_unfilled_to(granted));
+        let result = ready!(Pin::new(&mut this.inner).poll_read(cx, &mut limited));
+        let read = limited.filled().len();
+        if result.is_ok() {
+            buf.advance(read);
+            this.throttle.consume(read);
+        }
+        Poll::Ready(result)
+    }
+}
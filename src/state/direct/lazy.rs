@@ -0,0 +1,120 @@
+#![cfg(feature = "std")]
+
+use crate::{
+    clock,
+    middleware::NoOpMiddleware,
+    state::{InMemoryState, NotKeyed},
+    Quota, RateLimiter,
+};
+use std::fmt;
+use std::ops::Deref;
+use std::sync::OnceLock;
+
+/// A direct rate limiter that can be declared as a `static`.
+///
+/// The quota is fixed at compile time, but the rate limiter itself (and with it, the starting
+/// point of its clock) is only constructed the first time it is used. `LazyRateLimiter`
+/// dereferences to a [`RateLimiter::direct`] rate limiter, so all its methods can be called on
+/// it directly.
+///
+/// The [`static_limiter!`][crate::static_limiter] macro is the most convenient way to declare
+/// these.
+///
+/// # Example
+/// ```rust
+/// # use nonzero_ext::*;
+/// use governor::{state::direct::LazyRateLimiter, Quota};
+///
+/// static LIMITER: LazyRateLimiter = LazyRateLimiter::new(Quota::per_second(nonzero!(50u32)));
+///
+/// assert_eq!(Ok(()), LIMITER.check());
+/// ```
+pub struct LazyRateLimiter {
+    quota: Quota,
+    limiter: OnceLock<RateLimiter<NotKeyed, InMemoryState, clock::DefaultClock, NoOpMiddleware>>,
+}
+
+impl LazyRateLimiter {
+    /// Declares a direct rate limiter with the default real-time clock, to be constructed on
+    /// first use.
+    pub const fn new(quota: Quota) -> Self {
+        LazyRateLimiter {
+            quota,
+            limiter: OnceLock::new(),
+        }
+    }
+
+    /// Returns the quota that this rate limiter enforces.
+    pub fn quota(&self) -> Quota {
+        self.quota
+    }
+}
+
+impl Deref for LazyRateLimiter {
+    type Target = RateLimiter<NotKeyed, InMemoryState, clock::DefaultClock, NoOpMiddleware>;
+
+    fn deref(&self) -> &Self::Target {
+        self.limiter.get_or_init(|| RateLimiter::direct(self.quota))
+    }
+}
+
+impl fmt::Debug for LazyRateLimiter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("LazyRateLimiter")
+            .field("quota", &self.quota)
+            .field("limiter", &self.limiter.get())
+            .finish()
+    }
+}
+
+/// Declares one or more `static` direct rate limiters.
+///
+/// Each limiter is a [`LazyRateLimiter`][crate::state::direct::LazyRateLimiter] using the default
+/// real-time clock, which gets constructed the first time it is used. The quota expression must
+/// be usable in a constant context; all the [`Quota`][crate::Quota] constructors are.
+///
+/// # Example
+/// ```rust
+/// use governor::{static_limiter, Quota};
+/// use nonzero_ext::nonzero;
+///
+/// static_limiter! {
+///     /// Limits the rate at which we send emails.
+///     pub static EMAIL = Quota::per_minute(nonzero!(10u32));
+///     static LOG_LINES = Quota::per_second(nonzero!(1000u32)).allow_burst(nonzero!(50u32));
+/// }
+///
+/// assert_eq!(Ok(()), EMAIL.check());
+/// assert_eq!(Ok(()), LOG_LINES.check());
+/// ```
+#[macro_export]
+macro_rules! static_limiter {
+    ($($(#[$attr:meta])* $vis:vis static $name:ident = $quota:expr;)+) => {
+        $(
+            $(#[$attr])*
+            $vis static $name: $crate::state::direct::LazyRateLimiter =
+                $crate::state::direct::LazyRateLimiter::new($quota);
+        )+
+    };
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use nonzero_ext::nonzero;
+
+    static_limiter! {
+        static TEST_LIMITER = Quota::per_hour(nonzero!(1u32));
+    }
+
+    #[test]
+    fn constructs_lazily() {
+        assert!(TEST_LIMITER.limiter.get().is_none());
+        assert_eq!(TEST_LIMITER.quota(), Quota::per_hour(nonzero!(1u32)));
+        assert!(!format!("{:?}", TEST_LIMITER).is_empty());
+
+        assert_eq!(Ok(()), TEST_LIMITER.check());
+        assert!(TEST_LIMITER.limiter.get().is_some());
+        assert_ne!(Ok(()), TEST_LIMITER.check());
+    }
+}
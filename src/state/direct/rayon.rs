@@ -0,0 +1,96 @@
+use crate::{
+    clock,
+    middleware::RateLimitingMiddleware,
+    state::{DirectStateStore, NotKeyed},
+    NotUntil, RateLimiter,
+};
+use rayon::iter::plumbing::UnindexedConsumer;
+use rayon::iter::ParallelIterator;
+
+/// Allows pacing the items of a [`rayon`] parallel iterator with a direct rate limiter.
+///
+/// Requires the `rayon` feature.
+pub trait ParallelIteratorRateLimitExt: ParallelIterator {
+    /// Limits the rate at which items are processed, across all worker threads.
+    ///
+    /// Each item passes through [`until_ready_blocking`](RateLimiter::until_ready_blocking)
+    /// before it is handed on, so worker threads that would exceed the rate limit sleep until
+    /// the rate limiter allows their item through. This is meant for data-parallel jobs whose
+    /// items e.g. each call an external API: Sleeping workers can't pick up other work in the
+    /// meantime, so avoid sharing the thread pool with CPU-bound jobs that shouldn't be paced.
+    ///
+    /// # Example
+    /// ```rust
+    /// # use nonzero_ext::*;
+    /// use governor::{state::direct::ParallelIteratorRateLimitExt, Quota, RateLimiter};
+    /// use rayon::prelude::*;
+    ///
+    /// let lim = RateLimiter::direct(Quota::per_second(nonzero!(1000u32)));
+    /// let total: u32 = (1..=100u32).into_par_iter().throttle(&lim).sum();
+    /// assert_eq!(total, 5050);
+    /// ```
+    fn throttle<D, C, MW>(
+        self,
+        limiter: &RateLimiter<NotKeyed, D, C, MW>,
+    ) -> Throttled<'_, Self, D, C, MW>
+    where
+        Self: Sized,
+        D: DirectStateStore + Sync,
+        C: clock::ReasonablyRealtime + Sync,
+        MW: RateLimitingMiddleware<C::Instant, NegativeOutcome = NotUntil<C::Instant>> + Sync;
+}
+
+impl<I: ParallelIterator> ParallelIteratorRateLimitExt for I {
+    fn throttle<D, C, MW>(
+        self,
+        limiter: &RateLimiter<NotKeyed, D, C, MW>,
+    ) -> Throttled<'_, Self, D, C, MW>
+    where
+        Self: Sized,
+        D: DirectStateStore + Sync,
+        C: clock::ReasonablyRealtime + Sync,
+        MW: RateLimitingMiddleware<C::Instant, NegativeOutcome = NotUntil<C::Instant>> + Sync,
+    {
+        Throttled {
+            base: self,
+            limiter,
+        }
+    }
+}
+
+/// A parallel iterator whose items are paced by a rate limiter.
+///
+/// This is returned by [`ParallelIteratorRateLimitExt::throttle`].
+#[derive(Debug)]
+pub struct Throttled<'a, I, D, C, MW>
+where
+    D: DirectStateStore,
+    C: clock::Clock,
+    MW: RateLimitingMiddleware<C::Instant>,
+{
+    base: I,
+    limiter: &'a RateLimiter<NotKeyed, D, C, MW>,
+}
+
+impl<'a, I, D, C, MW> ParallelIterator for Throttled<'a, I, D, C, MW>
+where
+    I: ParallelIterator,
+    D: DirectStateStore + Sync,
+    C: clock::ReasonablyRealtime + Sync,
+    MW: RateLimitingMiddleware<C::Instant, NegativeOutcome = NotUntil<C::Instant>> + Sync,
+{
+    type Item = I::Item;
+
+    fn drive_unindexed<Co>(self, consumer: Co) -> Co::Result
+    where
+        Co: UnindexedConsumer<Self::Item>,
+    {
+        let limiter = self.limiter;
+        self.base
+            .map(move |item| {
+                limiter.until_ready_blocking();
+                item
+            })
+            .drive_unindexed(consumer)
+    }
+}
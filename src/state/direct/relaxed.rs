@@ -0,0 +1,241 @@
+use crate::compat::prelude::*;
+
+use crate::{
+    clock::{self, Reference},
+    middleware::NoOpMiddleware,
+    nanos::Nanos,
+    state::{InMemoryState, NotKeyed},
+    NotUntil, Quota, RateLimiter,
+};
+use std::cell::Cell;
+use std::fmt;
+use std::num::NonZeroU32;
+use std::time::Duration;
+
+/// A direct rate limiter that trades accuracy for less contention between threads.
+///
+/// Checking cells on a regular direct rate limiter updates one atomic value, which can become
+/// a point of contention between CPU cores if many threads check against the same (very hot)
+/// rate limiter. A `RelaxedRateLimiter` instead hands out per-thread [`LocalRateLimiter`]s,
+/// each of which leases a slice of the quota's cells from the shared rate limiter and then
+/// allows them through without touching any shared state.
+///
+/// Leases are reconciled periodically: once a lease is older than the reconciliation interval
+/// (by default, the time it takes to replenish one slice), or once the `LocalRateLimiter` is
+/// dropped, its unused cells are returned to the shared rate limiter.
+///
+/// # Accuracy
+/// Cells in a lease are taken from the shared rate limiter at once, so other threads may see
+/// the rate limiter as exhausted while some of its cells are still waiting to be used in a
+/// lease, and individual threads may get fewer than their share. If a full slice can't be
+/// leased, the local rate limiter falls back to leasing single cells, so the quota can still be
+/// used up entirely.
+///
+/// Leased cells count against the quota when they are leased, not when they are let through,
+/// which makes the rate limiter more permissive than the quota: While the cells of a lease wait
+/// to be used (for up to the reconciliation interval), the shared rate limiter replenishes the
+/// capacity they took, and grants it again. At most a burst's worth of cells can be waiting in
+/// leases, so in any period, up to the burst size more cells than the quota allows can be let
+/// through; at once, that's up to about twice the burst size. Returning the unused cells of an
+/// expired lease credits them back to the shared rate limiter, which is bounded by the same
+/// burst's worth. Shorter [reconciliation intervals](#method.with_reconcile_interval) and
+/// smaller slices keep fewer cells waiting, and the rate limiter closer to the quota.
+///
+/// # Example
+/// ```rust
+/// # #[cfg(feature = "std")] fn main() {
+/// # use nonzero_ext::*;
+/// use governor::{state::direct::RelaxedRateLimiter, Quota};
+/// use std::thread;
+///
+/// let lim = RelaxedRateLimiter::direct(Quota::per_second(nonzero!(1000u32)), nonzero!(50u32));
+/// thread::scope(|s| {
+///     for _ in 0..4 {
+///         s.spawn(|| {
+///             // Keep one local rate limiter per thread:
+///             let local = lim.local();
+///             assert_eq!(Ok(()), local.check());
+///         });
+///     }
+/// });
+/// # } #[cfg(not(feature = "std"))] fn main() {}
+/// ```
+pub struct RelaxedRateLimiter<C = clock::DefaultClock>
+where
+    C: clock::Clock,
+{
+    shared: RateLimiter<NotKeyed, InMemoryState, C, NoOpMiddleware<C::Instant>>,
+    slice: NonZeroU32,
+    reconcile_interval: Nanos,
+}
+
+#[cfg(feature = "std")]
+impl RelaxedRateLimiter<clock::DefaultClock> {
+    /// Constructs a new relaxed rate limiter for a quota with the default real-time clock,
+    /// leasing `slice` cells at a time to each thread's local rate limiter.
+    ///
+    /// The slice is capped at the quota's burst size.
+    pub fn direct(quota: Quota, slice: NonZeroU32) -> Self {
+        let clock = clock::DefaultClock::default();
+        Self::direct_with_clock(quota, slice, &clock)
+    }
+}
+
+impl<C> RelaxedRateLimiter<C>
+where
+    C: clock::Clock,
+{
+    /// Constructs a new relaxed rate limiter for a quota with a custom clock, leasing `slice`
+    /// cells at a time to each thread's local rate limiter.
+    ///
+    /// The slice is capped at the quota's burst size.
+    pub fn direct_with_clock(quota: Quota, slice: NonZeroU32, clock: &C) -> Self {
+        let slice = slice.min(quota.burst_size());
+        let reconcile_interval = quota.replenish_period.as_nanos() * slice.get() as u128
+            / quota.cells_per_period.get() as u128;
+        RelaxedRateLimiter {
+            shared: RateLimiter::direct_with_clock(quota, clock),
+            slice,
+            reconcile_interval: Nanos::from(reconcile_interval as u64),
+        }
+    }
+
+    /// Sets the maximum age of a lease: Once a local rate limiter's lease is older than this,
+    /// its unused cells are returned to the shared rate limiter before a new lease is taken.
+    pub fn with_reconcile_interval(self, interval: Duration) -> Self {
+        RelaxedRateLimiter {
+            reconcile_interval: interval.into(),
+            ..self
+        }
+    }
+
+    /// Returns the number of cells leased to a local rate limiter at a time.
+    pub fn slice(&self) -> NonZeroU32 {
+        self.slice
+    }
+
+    /// Returns the shared rate limiter that cells are leased from.
+    ///
+    /// Checking cells directly on it is possible, but does contend with the other threads
+    /// leasing cells.
+    pub fn shared(&self) -> &RateLimiter<NotKeyed, InMemoryState, C, NoOpMiddleware<C::Instant>> {
+        &self.shared
+    }
+
+    /// Returns a local rate limiter for the current thread, which starts out without a lease.
+    ///
+    /// Local rate limiters are meant to be kept around (e.g. in the state of a worker thread's
+    /// loop) for as long as the thread checks cells: dropping one returns its unused cells.
+    pub fn local(&self) -> LocalRateLimiter<'_, C> {
+        LocalRateLimiter {
+            parent: self,
+            remaining: Cell::new(0),
+            leased_at: Cell::new(None),
+        }
+    }
+
+    /// Returns unused cells to the shared rate limiter.
+    fn refund(&self, n: u32) {
+        if n > 0 {
+            self.shared.refund_cells(&NotKeyed::NonKey, None, n);
+        }
+    }
+}
+
+impl<C> fmt::Debug for RelaxedRateLimiter<C>
+where
+    C: clock::Clock + fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RelaxedRateLimiter")
+            .field("shared", &self.shared)
+            .field("slice", &self.slice)
+            .field("reconcile_interval", &self.reconcile_interval)
+            .finish()
+    }
+}
+
+/// A thread's view of a [`RelaxedRateLimiter`], allowing cells through from its lease.
+///
+/// Local rate limiters can not be shared between threads; construct one per thread using
+/// [`RelaxedRateLimiter::local`].
+pub struct LocalRateLimiter<'a, C>
+where
+    C: clock::Clock,
+{
+    parent: &'a RelaxedRateLimiter<C>,
+    remaining: Cell<u32>,
+    leased_at: Cell<Option<C::Instant>>,
+}
+
+impl<'a, C> LocalRateLimiter<'a, C>
+where
+    C: clock::Clock,
+{
+    /// Allow a single cell through the rate limiter.
+    ///
+    /// If the current lease has cells left, this doesn't touch any shared state. Otherwise, a
+    /// new lease is taken from the shared rate limiter; if that isn't possible either,
+    /// `check` returns information about the earliest time that a cell might be allowed
+    /// through again.
+    pub fn check(&self) -> Result<(), NotUntil<C::Instant>> {
+        let now = self.parent.shared.clock.now();
+        let remaining = self.remaining.get();
+        if remaining > 0 && !self.lease_expired(now) {
+            self.remaining.set(remaining - 1);
+            return Ok(());
+        }
+
+        self.reconcile();
+        let leased = match self.parent.shared.check_n(self.parent.slice) {
+            Ok(()) => self.parent.slice.get(),
+            Err(_) => {
+                // No full slice available; fall back to leasing a single cell.
+                self.parent.shared.check()?;
+                1
+            }
+        };
+        self.leased_at.set(Some(now));
+        self.remaining.set(leased - 1);
+        Ok(())
+    }
+
+    /// Returns the number of cells left in the current lease.
+    pub fn remaining(&self) -> u32 {
+        self.remaining.get()
+    }
+
+    /// Returns the unused cells of the current lease to the shared rate limiter.
+    pub fn reconcile(&self) {
+        self.parent.refund(self.remaining.replace(0));
+        self.leased_at.set(None);
+    }
+
+    fn lease_expired(&self, now: C::Instant) -> bool {
+        match self.leased_at.get() {
+            Some(leased_at) => now.duration_since(leased_at) >= self.parent.reconcile_interval,
+            None => true,
+        }
+    }
+}
+
+impl<'a, C> Drop for LocalRateLimiter<'a, C>
+where
+    C: clock::Clock,
+{
+    fn drop(&mut self) {
+        self.reconcile();
+    }
+}
+
+impl<'a, C> fmt::Debug for LocalRateLimiter<'a, C>
+where
+    C: clock::Clock,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("LocalRateLimiter")
+            .field("remaining", &self.remaining.get())
+            .field("leased_at", &self.leased_at.get())
+            .finish()
+    }
+}
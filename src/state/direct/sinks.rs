@@ -1,5 +1,6 @@
-use std::prelude::v1::*;
+use crate::compat::prelude::*;
 
+use super::enforcement::{Enforcement, Queue};
 use crate::{
     clock,
     middleware::RateLimitingMiddleware,
@@ -7,9 +8,8 @@ use crate::{
     Jitter, NotUntil, RateLimiter,
 };
 use futures::task::{Context, Poll};
-use futures::{Future, Sink, Stream};
+use futures::{ready, Future, Sink, Stream};
 use futures_timer::Delay;
-use std::marker::PhantomData;
 use std::pin::Pin;
 
 /// Allows converting a [`futures::Sink`] combinator into a rate-limited sink.
@@ -53,7 +53,7 @@ impl<Item, S: Sink<Item>> SinkRateLimitExt<Item, S> for S {
     >(
         self,
         limiter: &RateLimiter<NotKeyed, D, C, MW>,
-    ) -> RatelimitedSink<Item, S, D, C, MW>
+    ) -> RatelimitedSink<'_, Item, S, D, C, MW>
     where
         Self: Sized,
     {
@@ -69,7 +69,7 @@ impl<Item, S: Sink<Item>> SinkRateLimitExt<Item, S> for S {
         self,
         limiter: &RateLimiter<NotKeyed, D, C, MW>,
         jitter: Jitter,
-    ) -> RatelimitedSink<Item, S, D, C, MW>
+    ) -> RatelimitedSink<'_, Item, S, D, C, MW>
     where
         Self: Sized,
     {
@@ -81,11 +81,15 @@ impl<Item, S: Sink<Item>> SinkRateLimitExt<Item, S> for S {
 enum State {
     NotReady,
     Wait,
+    /// The rate limiter allowed the oldest queued item, which waits for the inner sink.
     Ready,
 }
 
 /// A [`Sink`][futures::Sink] combinator that only allows sending elements when the rate-limiter
 /// allows it.
+///
+/// By default, it shapes the items sent into it, holding back one item at a time; see
+/// [`with_enforcement`](#method.with_enforcement) for other ways of enforcing the rate limit.
 pub struct RatelimitedSink<
     'a,
     Item,
@@ -99,7 +103,7 @@ pub struct RatelimitedSink<
     limiter: &'a RateLimiter<NotKeyed, D, C, MW>,
     delay: Delay,
     jitter: Jitter,
-    phantom: PhantomData<Item>,
+    queue: Queue<Item>,
 }
 
 /// Conversion methods for the sink combinator.
@@ -119,7 +123,7 @@ impl<
             delay: Delay::new(Default::default()),
             state: State::NotReady,
             jitter,
-            phantom: PhantomData,
+            queue: Queue::new(),
         }
     }
 
@@ -146,9 +150,49 @@ impl<
     }
 
     /// Consumes this combinator, returning the underlying sink.
+    ///
+    /// Items that are still queued are dropped; flush the combinator to send them first.
     pub fn into_inner(self) -> S {
         self.inner
     }
+
+    /// Sets how the combinator enforces the rate limit.
+    ///
+    /// When policing, the sink is ready as soon as the underlying sink is, and drops the items
+    /// that the rate limiter doesn't allow right away; when shaping, it queues the items and
+    /// sends them as the rate limiter allows, while it is polled to be ready or to flush.
+    ///
+    /// ```
+    /// # futures::executor::block_on(async {
+    /// # use futures::sink::SinkExt;
+    /// # use nonzero_ext::nonzero;
+    /// use governor::{prelude::*, state::direct::Enforcement, RateLimiter, Quota};
+    /// let lim = RateLimiter::direct(Quota::per_second(nonzero!(2u32)));
+    /// let mut limited = Vec::new()
+    ///     .ratelimit_sink(&lim)
+    ///     .with_enforcement(Enforcement::Police);
+    /// for i in 0..3 {
+    ///     limited.send(i).await?;
+    /// }
+    /// assert_eq!(limited.dropped(), 1);
+    /// assert_eq!(limited.into_inner(), vec![0, 1]);
+    /// # Ok::<(), futures::never::Never>(()) }).unwrap();
+    /// ```
+    pub fn with_enforcement(mut self, enforcement: Enforcement) -> Self {
+        self.queue.enforcement = enforcement;
+        self
+    }
+
+    /// Returns how the combinator enforces the rate limit.
+    pub fn enforcement(&self) -> Enforcement {
+        self.queue.enforcement
+    }
+
+    /// Returns the number of items that the combinator dropped, when policing or when its
+    /// queue overflowed.
+    pub fn dropped(&self) -> u64 {
+        self.queue.dropped
+    }
 }
 
 impl<
@@ -166,68 +210,90 @@ where
     type Error = S::Error;
 
     fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
-        loop {
-            match self.state {
-                State::NotReady => {
-                    let reference = self.limiter.reference_reading();
-                    if let Err(negative) = self.limiter.check() {
-                        let earliest = negative.wait_time_with_offset(reference, self.jitter);
-                        self.delay.reset(earliest);
-                        let future = Pin::new(&mut self.delay);
-                        match future.poll(cx) {
-                            Poll::Pending => {
-                                self.state = State::Wait;
-                                return Poll::Pending;
-                            }
-                            Poll::Ready(_) => {}
-                        }
-                    } else {
-                        self.state = State::Ready;
-                    }
-                }
-                State::Wait => {
-                    let future = Pin::new(&mut self.delay);
-                    match future.poll(cx) {
-                        Poll::Pending => {
-                            return Poll::Pending;
-                        }
-                        Poll::Ready(_) => {
-                            self.state = State::NotReady;
-                        }
-                    }
-                }
-                State::Ready => {
-                    let inner = Pin::new(&mut self.inner);
-                    return inner.poll_ready(cx);
-                }
-            }
+        if self.queue.enforcement == Enforcement::Police {
+            return Pin::new(&mut self.inner).poll_ready(cx);
+        }
+        if let Poll::Ready(Err(e)) = self.as_mut().poll_queue(cx) {
+            return Poll::Ready(Err(e));
+        }
+        if self.queue.accepts() {
+            Poll::Ready(Ok(()))
+        } else {
+            // The queue is full, so polling it registered a wakeup:
+            Poll::Pending
         }
     }
 
     fn start_send(mut self: Pin<&mut Self>, item: Item) -> Result<(), Self::Error> {
-        match self.state {
-            State::Wait | State::NotReady => {
-                unreachable!("Must not start_send before we're ready"); // !no_rcov!
-            }
-            State::Ready => {
-                self.state = State::NotReady;
-                let inner = Pin::new(&mut self.inner);
-                inner.start_send(item)
+        if self.queue.enforcement == Enforcement::Police {
+            if self.limiter.check().is_err() {
+                self.queue.dropped += 1;
+                return Ok(());
             }
+            return Pin::new(&mut self.inner).start_send(item);
         }
+        self.queue.push(item);
+        Ok(())
     }
 
     fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        ready!(self.as_mut().poll_queue(cx))?;
         let inner = Pin::new(&mut self.inner);
         inner.poll_flush(cx)
     }
 
     fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        ready!(self.as_mut().poll_queue(cx))?;
         let inner = Pin::new(&mut self.inner);
         inner.poll_close(cx)
     }
 }
 
+impl<
+        'a,
+        Item,
+        S: Sink<Item>,
+        D: DirectStateStore,
+        C: clock::ReasonablyRealtime,
+        MW: RateLimitingMiddleware<C::Instant, NegativeOutcome = NotUntil<C::Instant>>,
+    > RatelimitedSink<'a, Item, S, D, C, MW>
+where
+    S: Unpin,
+    Item: Unpin,
+{
+    /// Sends the queued items into the inner sink as the rate limiter allows them, until the
+    /// queue is empty.
+    fn poll_queue(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), S::Error>> {
+        let this = &mut *self;
+        while !this.queue.items.is_empty() {
+            match this.state {
+                State::NotReady => {
+                    let reference = this.limiter.reference_reading();
+                    if let Err(negative) = this.limiter.check() {
+                        let earliest = negative.wait_time_with_offset(reference, this.jitter);
+                        this.delay.reset(earliest);
+                        this.state = State::Wait;
+                    } else {
+                        this.state = State::Ready;
+                    }
+                }
+                State::Wait => {
+                    ready!(Pin::new(&mut this.delay).poll(cx));
+                    this.state = State::NotReady;
+                }
+                State::Ready => {
+                    ready!(Pin::new(&mut this.inner).poll_ready(cx))?;
+                    this.state = State::NotReady;
+                    if let Some(item) = this.queue.items.pop_front() {
+                        Pin::new(&mut this.inner).start_send(item)?;
+                    }
+                }
+            }
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
 /// Pass-through implementation for [`futures::Stream`] if the Sink also implements it.
 impl<
         'a,
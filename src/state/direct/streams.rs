@@ -1,14 +1,16 @@
 #![cfg(feature = "std")]
 
-use std::prelude::v1::*;
+use crate::compat::prelude::*;
 
+use super::enforcement::{Enforcement, Overflow, Queue};
+use crate::compat::collections::VecDeque;
 use crate::{clock, Jitter, NotUntil, RateLimiter};
 use crate::{
     middleware::RateLimitingMiddleware,
     state::{DirectStateStore, NotKeyed},
 };
 use futures::task::{Context, Poll};
-use futures::{Future, Sink, Stream};
+use futures::{ready, Future, Sink, Stream};
 use futures_timer::Delay;
 use std::pin::Pin;
 use std::time::Duration;
@@ -24,15 +26,14 @@ pub trait StreamRateLimitExt<'a>: Stream {
     /// it will not `poll` the underlying stream.
     fn ratelimit_stream<
         D: DirectStateStore,
-        C: clock::Clock,
+        C: clock::ReasonablyRealtime,
         MW: RateLimitingMiddleware<C::Instant>,
     >(
         self,
         limiter: &'a RateLimiter<NotKeyed, D, C, MW>,
     ) -> RatelimitedStream<'a, Self, D, C, MW>
     where
-        Self: Sized,
-        C: clock::ReasonablyRealtime;
+        Self: Sized;
 
     /// Limits the rate at which the stream produces items, with a randomized wait period.
     ///
@@ -43,7 +44,7 @@ pub trait StreamRateLimitExt<'a>: Stream {
     /// it will not `poll` the underlying stream.
     fn ratelimit_stream_with_jitter<
         D: DirectStateStore,
-        C: clock::Clock,
+        C: clock::ReasonablyRealtime,
         MW: RateLimitingMiddleware<C::Instant>,
     >(
         self,
@@ -51,14 +52,13 @@ pub trait StreamRateLimitExt<'a>: Stream {
         jitter: Jitter,
     ) -> RatelimitedStream<'a, Self, D, C, MW>
     where
-        Self: Sized,
-        C: clock::ReasonablyRealtime;
+        Self: Sized;
 }
 
 impl<'a, S: Stream> StreamRateLimitExt<'a> for S {
     fn ratelimit_stream<
         D: DirectStateStore,
-        C: clock::Clock,
+        C: clock::ReasonablyRealtime,
         MW: RateLimitingMiddleware<C::Instant>,
     >(
         self,
@@ -66,14 +66,13 @@ impl<'a, S: Stream> StreamRateLimitExt<'a> for S {
     ) -> RatelimitedStream<'a, Self, D, C, MW>
     where
         Self: Sized,
-        C: clock::ReasonablyRealtime,
     {
         self.ratelimit_stream_with_jitter(limiter, Jitter::NONE)
     }
 
     fn ratelimit_stream_with_jitter<
         D: DirectStateStore,
-        C: clock::Clock,
+        C: clock::ReasonablyRealtime,
         MW: RateLimitingMiddleware<C::Instant>,
     >(
         self,
@@ -82,21 +81,20 @@ impl<'a, S: Stream> StreamRateLimitExt<'a> for S {
     ) -> RatelimitedStream<'a, Self, D, C, MW>
     where
         Self: Sized,
-        C: clock::ReasonablyRealtime,
     {
         RatelimitedStream {
             inner: self,
             limiter,
-            buf: None,
+            queue: Queue::new(),
+            done: false,
             delay: Delay::new(Duration::new(0, 0)),
             jitter,
-            state: State::ReadInner,
+            state: State::NotReady,
         }
     }
 }
 
 enum State {
-    ReadInner,
     NotReady,
     Wait,
 }
@@ -104,7 +102,9 @@ enum State {
 /// A [`Stream`][futures::Stream] combinator which will limit the rate of items being received.
 ///
 /// This is produced by the [`StreamRateLimitExt::ratelimit_stream`] and
-/// [`StreamRateLimitExt::ratelimit_stream_with_jitter`] methods.
+/// [`StreamRateLimitExt::ratelimit_stream_with_jitter`] methods. By default, it shapes the
+/// stream, holding back one item at a time; see
+/// [`with_enforcement`](#method.with_enforcement) for other ways of enforcing the rate limit.
 pub struct RatelimitedStream<
     'a,
     S: Stream,
@@ -115,7 +115,9 @@ pub struct RatelimitedStream<
     inner: S,
     limiter: &'a RateLimiter<NotKeyed, D, C, MW>,
     delay: Delay,
-    buf: Option<S::Item>,
+    queue: Queue<S::Item>,
+    /// Whether the underlying stream has ended.
+    done: bool,
     jitter: Jitter,
     state: State,
 }
@@ -173,8 +175,52 @@ impl<
     /// let (mut inner_again, _) = outer.into_inner();
     /// assert_eq!(block_on(inner_again.next()), Some(()));
     /// ```
+    ///
+    /// If several items are queued, this returns only the oldest; see
+    /// [`into_parts`](#method.into_parts).
     pub fn into_inner(self) -> (S, Option<S::Item>) {
-        (self.inner, self.buf)
+        let (inner, mut queued) = self.into_parts();
+        (inner, queued.pop_front())
+    }
+
+    /// Consumes this combinator, returning the underlying stream and all the items which it
+    /// has already produced but which are still being held back, oldest first.
+    pub fn into_parts(self) -> (S, VecDeque<S::Item>) {
+        (self.inner, self.queue.items)
+    }
+
+    /// Sets how the combinator enforces the rate limit.
+    ///
+    /// When policing, the stream drops the items of the underlying stream that the rate limiter
+    /// doesn't allow right away; when shaping, it reads ahead into its queue while it holds
+    /// back items.
+    ///
+    /// ```rust
+    /// # use futures::{stream, StreamExt};
+    /// # use futures::executor::block_on;
+    /// # use governor::{prelude::*, state::direct::Enforcement, Quota, RateLimiter};
+    /// # use nonzero_ext::nonzero;
+    /// let lim = RateLimiter::direct(Quota::per_second(nonzero!(3u32)));
+    /// let mut outer = stream::iter(0..5)
+    ///     .ratelimit_stream(&lim)
+    ///     .with_enforcement(Enforcement::Police);
+    /// assert_eq!(block_on((&mut outer).collect::<Vec<_>>()), vec![0, 1, 2]);
+    /// assert_eq!(outer.dropped(), 2);
+    /// ```
+    pub fn with_enforcement(mut self, enforcement: Enforcement) -> Self {
+        self.queue.enforcement = enforcement;
+        self
+    }
+
+    /// Returns how the combinator enforces the rate limit.
+    pub fn enforcement(&self) -> Enforcement {
+        self.queue.enforcement
+    }
+
+    /// Returns the number of items that the combinator dropped, when policing or when its
+    /// queue overflowed.
+    pub fn dropped(&self) -> u64 {
+        self.queue.dropped
     }
 }
 
@@ -191,57 +237,75 @@ where
     type Item = S::Item;
 
     fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = &mut *self;
+        if this.queue.enforcement == Enforcement::Police {
+            loop {
+                match ready!(Pin::new(&mut this.inner).poll_next(cx)) {
+                    None => return Poll::Ready(None),
+                    Some(x) if this.limiter.check().is_ok() => return Poll::Ready(Some(x)),
+                    Some(_) => this.queue.dropped += 1,
+                }
+            }
+        }
         loop {
-            match self.state {
-                State::ReadInner => {
-                    let inner = Pin::new(&mut self.inner);
-                    match inner.poll_next(cx) {
-                        Poll::Pending => return Poll::Pending,
-                        Poll::Ready(None) => {
-                            // never talk tome or my inner again
-                            return Poll::Ready(None);
-                        }
-                        Poll::Ready(Some(x)) => {
-                            self.buf.replace(x);
-                            self.state = State::NotReady;
-                        }
+            // Read ahead, but no more than a queue's worth at a time, so that an underlying
+            // stream which is always ready can't keep the queue overflowing forever:
+            let mut read = 0;
+            while !this.done && this.queue.accepts() && read < this.queue.max_depth() {
+                match Pin::new(&mut this.inner).poll_next(cx) {
+                    Poll::Pending => break,
+                    Poll::Ready(None) => this.done = true,
+                    Poll::Ready(Some(x)) => {
+                        read += 1;
+                        this.queue.push(x);
                     }
                 }
+            }
+            if this.queue.items.is_empty() {
+                return if this.done {
+                    // never talk tome or my inner again
+                    Poll::Ready(None)
+                } else {
+                    Poll::Pending
+                };
+            }
+            match this.state {
                 State::NotReady => {
-                    let reference = self.limiter.reference_reading();
-                    if let Err(negative) = self.limiter.check() {
-                        let earliest = negative.wait_time_with_offset(reference, self.jitter);
-                        self.delay.reset(earliest);
-                        let future = Pin::new(&mut self.delay);
-                        match future.poll(cx) {
-                            Poll::Pending => {
-                                self.state = State::Wait;
-                                return Poll::Pending;
-                            }
-                            Poll::Ready(_) => {}
-                        }
+                    let reference = this.limiter.reference_reading();
+                    if let Err(negative) = this.limiter.check() {
+                        let earliest = negative.wait_time_with_offset(reference, this.jitter);
+                        this.delay.reset(earliest);
+                        this.state = State::Wait;
                     } else {
-                        self.state = State::ReadInner;
-                        return Poll::Ready(self.buf.take());
+                        return Poll::Ready(this.queue.items.pop_front());
                     }
                 }
                 State::Wait => {
-                    let future = Pin::new(&mut self.delay);
-                    match future.poll(cx) {
-                        Poll::Pending => {
-                            return Poll::Pending;
-                        }
-                        Poll::Ready(_) => {
-                            self.state = State::NotReady;
-                        }
-                    }
+                    ready!(Pin::new(&mut this.delay).poll(cx));
+                    this.state = State::NotReady;
                 }
             }
         }
     }
 
     fn size_hint(&self) -> (usize, Option<usize>) {
-        self.inner.size_hint()
+        let queued = self.queue.items.len();
+        let (lower, upper) = if self.done {
+            (0, Some(0))
+        } else {
+            self.inner.size_hint()
+        };
+        let upper = upper.and_then(|upper| upper.checked_add(queued));
+        // Unless the combinator only holds items back, it may drop any of the underlying
+        // stream's items:
+        let lower = match self.queue.enforcement {
+            Enforcement::Shape {
+                overflow: Overflow::Backpressure,
+                ..
+            } => lower.saturating_add(queued),
+            _ => queued,
+        };
+        (lower, upper)
     }
 }
 
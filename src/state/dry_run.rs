@@ -0,0 +1,235 @@
+//! Rate limiters that can observe decisions without enforcing them.
+
+use crate::compat::prelude::*;
+
+use crate::middleware::RateLimitingMiddleware;
+use crate::state::keyed::KeyedStateStore;
+use crate::state::{DirectStateStore, NotKeyed, StateStore};
+use crate::sync::{AtomicBool, AtomicU64, Ordering};
+use crate::{clock, NegativeMultiDecision, RateLimiter};
+use std::fmt;
+use std::hash::Hash;
+use std::num::NonZeroU32;
+
+/// A rate limiter that can run in dry-run mode: It makes every decision like the rate limiter it
+/// wraps, including running the middleware, but while it isn't enforcing, lets through the cells
+/// that the rate limiter would have rate-limited.
+///
+/// This allows deploying a new limit in observation-only mode, watching the decisions it would
+/// make (through the middleware, or by counting them with
+/// [`would_have_limited`](#method.would_have_limited)), and then switching to enforcing it at
+/// runtime with [`set_enforcing`](#method.set_enforcing). Since rate-limited cells don't use up
+/// any capacity, the decisions are the same with and without enforcement.
+///
+/// While enforcing, the decisions are returned as they are (with positive outcomes wrapped in
+/// `Some`). While not enforcing, cells that would have been rate-limited result in `Ok(None)`.
+///
+/// # Example
+/// ```rust
+/// # #[cfg(feature = "std")] fn main() {
+/// # use nonzero_ext::*;
+/// use governor::{Quota, RateLimiter};
+///
+/// let lim = RateLimiter::direct(Quota::per_hour(nonzero!(1u32))).into_dry_run();
+/// assert_eq!(Ok(Some(())), lim.check());
+/// // Not enforced yet:
+/// assert_eq!(Ok(None), lim.check());
+/// assert_eq!(1, lim.would_have_limited());
+///
+/// lim.set_enforcing(true);
+/// assert!(lim.check().is_err());
+/// # } #[cfg(not(feature = "std"))] fn main() {}
+/// ```
+pub struct DryRunLimiter<K, S, C, MW>
+where
+    S: StateStore<Key = K>,
+    C: clock::Clock,
+    MW: RateLimitingMiddleware<C::Instant>,
+{
+    limiter: RateLimiter<K, S, C, MW>,
+    enforcing: AtomicBool,
+    would_have_limited: AtomicU64,
+}
+
+impl<K, S, C, MW> DryRunLimiter<K, S, C, MW>
+where
+    S: StateStore<Key = K>,
+    C: clock::Clock,
+    MW: RateLimitingMiddleware<C::Instant>,
+{
+    /// Wraps a rate limiter, enforcing its decisions or not.
+    pub fn new(limiter: RateLimiter<K, S, C, MW>, enforcing: bool) -> Self {
+        DryRunLimiter {
+            limiter,
+            enforcing: AtomicBool::new(enforcing),
+            would_have_limited: AtomicU64::new(0),
+        }
+    }
+
+    /// Returns whether the rate limiter's decisions are enforced.
+    pub fn is_enforcing(&self) -> bool {
+        self.enforcing.load(Ordering::Relaxed)
+    }
+
+    /// Starts or stops enforcing the rate limiter's decisions.
+    pub fn set_enforcing(&self, enforcing: bool) {
+        self.enforcing.store(enforcing, Ordering::Relaxed);
+    }
+
+    /// Returns the number of decisions that would have rate-limited cells, but were not
+    /// enforced.
+    pub fn would_have_limited(&self) -> u64 {
+        self.would_have_limited.load(Ordering::Relaxed)
+    }
+
+    /// Returns the wrapped rate limiter.
+    pub fn limiter(&self) -> &RateLimiter<K, S, C, MW> {
+        &self.limiter
+    }
+
+    /// Returns the wrapped rate limiter.
+    pub fn into_inner(self) -> RateLimiter<K, S, C, MW> {
+        self.limiter
+    }
+
+    /// Applies the enforcement mode to a decision.
+    fn observe<T, E>(&self, decision: Result<T, E>) -> Result<Option<T>, E> {
+        match decision {
+            Ok(positive) => Ok(Some(positive)),
+            Err(negative) if self.is_enforcing() => Err(negative),
+            Err(_) => {
+                self.would_have_limited.fetch_add(1, Ordering::Relaxed);
+                Ok(None)
+            }
+        }
+    }
+}
+
+/// # Dry-run rate limiters - Manually checking cells
+impl<S, C, MW> DryRunLimiter<NotKeyed, S, C, MW>
+where
+    S: DirectStateStore,
+    C: clock::Clock,
+    MW: RateLimitingMiddleware<C::Instant>,
+{
+    /// Allow a single cell through the rate limiter, unless it is rate-limited while enforcing.
+    ///
+    /// See [`RateLimiter::check`].
+    pub fn check(&self) -> Result<Option<MW::PositiveOutcome>, MW::NegativeOutcome> {
+        self.observe(self.limiter.check())
+    }
+
+    /// Allow *only all* `n` cells through the rate limiter, unless they are rate-limited while
+    /// enforcing.
+    ///
+    /// See [`RateLimiter::check_n`].
+    pub fn check_n(
+        &self,
+        n: NonZeroU32,
+    ) -> Result<Option<MW::PositiveOutcome>, NegativeMultiDecision<MW::NegativeOutcome>> {
+        self.observe(self.limiter.check_n(n))
+    }
+}
+
+/// # Keyed dry-run rate limiters - Manually checking cells
+impl<K, S, C, MW> DryRunLimiter<K, S, C, MW>
+where
+    S: KeyedStateStore<K>,
+    K: Hash,
+    C: clock::Clock,
+    MW: RateLimitingMiddleware<C::Instant>,
+{
+    /// Allow a single cell through the rate limiter for the given key, unless it is
+    /// rate-limited while enforcing.
+    ///
+    /// See [`RateLimiter::check_key`].
+    pub fn check_key(&self, key: &K) -> Result<Option<MW::PositiveOutcome>, MW::NegativeOutcome> {
+        self.observe(self.limiter.check_key(key))
+    }
+
+    /// Allow *only all* `n` cells through the rate limiter for the given key, unless they are
+    /// rate-limited while enforcing.
+    ///
+    /// See [`RateLimiter::check_key_n`].
+    pub fn check_key_n(
+        &self,
+        key: &K,
+        n: NonZeroU32,
+    ) -> Result<Option<MW::PositiveOutcome>, NegativeMultiDecision<MW::NegativeOutcome>> {
+        self.observe(self.limiter.check_key_n(key, n))
+    }
+}
+
+impl<K, S, C, MW> fmt::Debug for DryRunLimiter<K, S, C, MW>
+where
+    S: StateStore<Key = K>,
+    C: clock::Clock,
+    MW: RateLimitingMiddleware<C::Instant>,
+    RateLimiter<K, S, C, MW>: fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DryRunLimiter")
+            .field("limiter", &self.limiter)
+            .field("enforcing", &self.is_enforcing())
+            .field("would_have_limited", &self.would_have_limited())
+            .finish()
+    }
+}
+
+/// # Dry-run rate limiters
+impl<K, S, C, MW> RateLimiter<K, S, C, MW>
+where
+    S: StateStore<Key = K>,
+    C: clock::Clock,
+    MW: RateLimitingMiddleware<C::Instant>,
+{
+    /// Wraps the rate limiter in a [`DryRunLimiter`] that doesn't enforce its decisions until it
+    /// is told to.
+    pub fn into_dry_run(self) -> DryRunLimiter<K, S, C, MW> {
+        DryRunLimiter::new(self, false)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::clock::FakeRelativeClock;
+    use crate::Quota;
+    use nonzero_ext::nonzero;
+
+    #[test]
+    fn enforcement_toggles() {
+        let clock = FakeRelativeClock::default();
+        let lim = RateLimiter::direct_with_clock(Quota::per_second(nonzero!(2u32)), &clock)
+            .into_dry_run();
+        assert!(!lim.is_enforcing());
+        assert_eq!(Ok(Some(())), lim.check_n(nonzero!(2u32)));
+        assert_eq!(Ok(None), lim.check());
+        assert_eq!(Ok(None), lim.check_n(nonzero!(3u32)));
+        assert_eq!(lim.would_have_limited(), 2);
+
+        lim.set_enforcing(true);
+        assert!(lim.check().is_err());
+        assert!(matches!(
+            lim.check_n(nonzero!(3u32)),
+            Err(NegativeMultiDecision::InsufficientCapacity(2))
+        ));
+        assert_eq!(lim.would_have_limited(), 2);
+        assert!(format!("{:?}", lim).contains("enforcing: true"));
+        let _ = lim.into_inner();
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn keyed_dry_runs() {
+        let clock = FakeRelativeClock::default();
+        let lim = DryRunLimiter::new(
+            RateLimiter::hashmap_with_clock(Quota::per_second(nonzero!(1u32)), &clock),
+            false,
+        );
+        assert_eq!(Ok(Some(())), lim.check_key(&"a"));
+        assert_eq!(Ok(None), lim.check_key_n(&"a", nonzero!(1u32)));
+        assert_eq!(Ok(Some(())), lim.check_key(&"b"));
+        assert_eq!(lim.limiter().len(), 2);
+    }
+}
@@ -0,0 +1,254 @@
+//! Type-erased rate limiters.
+
+use crate::compat::prelude::*;
+
+use crate::state::{NotKeyed, StateStore};
+use crate::{
+    clock, middleware::RateLimitingMiddleware, NegativeMultiDecision, NotUntil, Quota, RateLimiter,
+};
+use std::fmt;
+use std::num::NonZeroU32;
+use std::time::Duration;
+
+#[cfg(feature = "std")]
+use crate::Jitter;
+#[cfg(feature = "std")]
+use futures_timer::Delay;
+
+/// A negative rate-limiting outcome of a type-erased rate limiter.
+///
+/// Since type-erased rate limiters may use different clocks, this holds the time to wait
+/// relative to when the decision was made, instead of an instant of a particular clock.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct DynNotUntil {
+    wait_time: Duration,
+    quota: Quota,
+}
+
+impl DynNotUntil {
+    /// Returns how long to wait after the decision was made, before a decision can be
+    /// conforming.
+    pub fn wait_time(&self) -> Duration {
+        self.wait_time
+    }
+
+    /// Returns the rate limiting [`Quota`] used to reach the decision.
+    pub fn quota(&self) -> Quota {
+        self.quota
+    }
+}
+
+impl fmt::Display for DynNotUntil {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "rate-limited for {:?}", self.wait_time)
+    }
+}
+
+/// An object-safe view of a rate limiter with keys of type `K`, regardless of its state store,
+/// clock and middleware.
+///
+/// This is implemented by all [`RateLimiter`]s whose middleware produces [`NotUntil`] as its
+/// negative outcome, so that rate limiters of different types can be stored together, e.g. in a
+/// registry of per-plugin limiters. Direct rate limiters implement `AnyRateLimiter<NotKeyed>`;
+/// [`DynRateLimiter`] names a boxed one. Positive outcomes of the middleware are discarded.
+pub trait AnyRateLimiter<K>: Send + Sync {
+    /// Allow a single cell through the rate limiter for the given key.
+    ///
+    /// See [`RateLimiter::check_key`](crate::RateLimiter::check_key).
+    fn check_key(&self, key: &K) -> Result<(), DynNotUntil>;
+
+    /// Allow *only all* `n` cells through the rate limiter for the given key.
+    ///
+    /// See [`RateLimiter::check_key_n`](crate::RateLimiter::check_key_n).
+    fn check_key_n(&self, key: &K, n: NonZeroU32)
+        -> Result<(), NegativeMultiDecision<DynNotUntil>>;
+
+    /// Returns the quota that the rate limiter enforces.
+    fn quota(&self) -> Quota;
+}
+
+/// A boxed, type-erased rate limiter; direct ones by default.
+///
+/// # Example
+/// ```rust
+/// # #[cfg(feature = "std")] fn main() {
+/// # use nonzero_ext::*;
+/// use governor::{clock::FakeRelativeClock, state::DynRateLimiter, Quota, RateLimiter};
+/// use std::collections::HashMap;
+///
+/// let clock = FakeRelativeClock::default();
+/// let mut registry: HashMap<&str, DynRateLimiter> = HashMap::new();
+/// registry.insert("search", RateLimiter::direct(Quota::per_second(nonzero!(10u32))).into_dyn());
+/// registry.insert(
+///     "upload",
+///     RateLimiter::direct_with_clock(Quota::per_minute(nonzero!(1u32)), &clock).into_dyn(),
+/// );
+///
+/// assert_eq!(Ok(()), registry["upload"].check());
+/// let limited = registry["upload"].check().unwrap_err();
+/// assert_eq!(limited.wait_time().as_secs(), 60);
+/// # } #[cfg(not(feature = "std"))] fn main() {}
+/// ```
+pub type DynRateLimiter<K = NotKeyed> = Box<dyn AnyRateLimiter<K>>;
+
+impl<K, S, C, MW> AnyRateLimiter<K> for RateLimiter<K, S, C, MW>
+where
+    S: StateStore<Key = K>,
+    C: clock::Clock,
+    MW: RateLimitingMiddleware<C::Instant, NegativeOutcome = NotUntil<C::Instant>>,
+    RateLimiter<K, S, C, MW>: Send + Sync,
+{
+    fn check_key(&self, key: &K) -> Result<(), DynNotUntil> {
+        self.gcra()
+            .test_and_update::<K, C::Instant, S, MW>(self.start, key, &self.state, self.clock.now())
+            .map(|_| ())
+            .map_err(|negative| self.erase(negative))
+    }
+
+    fn check_key_n(
+        &self,
+        key: &K,
+        n: NonZeroU32,
+    ) -> Result<(), NegativeMultiDecision<DynNotUntil>> {
+        self.gcra()
+            .test_n_all_and_update::<K, C::Instant, S, MW>(
+                self.start,
+                key,
+                n,
+                &self.state,
+                self.clock.now(),
+            )
+            .map(|_| ())
+            .map_err(|negative| match negative {
+                NegativeMultiDecision::BatchNonConforming(n, negative) => {
+                    NegativeMultiDecision::BatchNonConforming(n, self.erase(negative))
+                }
+                NegativeMultiDecision::InsufficientCapacity(cap) => {
+                    NegativeMultiDecision::InsufficientCapacity(cap)
+                }
+            })
+    }
+
+    fn quota(&self) -> Quota {
+        self.gcra.quota()
+    }
+}
+
+impl<K, S, C, MW> RateLimiter<K, S, C, MW>
+where
+    S: StateStore<Key = K>,
+    C: clock::Clock,
+    MW: RateLimitingMiddleware<C::Instant>,
+{
+    fn erase(&self, negative: NotUntil<C::Instant>) -> DynNotUntil {
+        DynNotUntil {
+            wait_time: negative.wait_time_from(self.clock.now()),
+            quota: negative.quota(),
+        }
+    }
+}
+
+/// # Type-erased rate limiters
+impl<K, S, C, MW> RateLimiter<K, S, C, MW>
+where
+    S: StateStore<Key = K> + 'static,
+    C: clock::Clock + 'static,
+    MW: RateLimitingMiddleware<C::Instant, NegativeOutcome = NotUntil<C::Instant>> + 'static,
+    RateLimiter<K, S, C, MW>: Send + Sync,
+    K: 'static,
+{
+    /// Boxes the rate limiter as a type-erased [`DynRateLimiter`].
+    pub fn into_dyn(self) -> DynRateLimiter<K> {
+        Box::new(self)
+    }
+}
+
+/// Direct type-erased rate limiters can be checked without passing a key.
+impl dyn AnyRateLimiter<NotKeyed> {
+    /// Allow a single cell through the rate limiter.
+    ///
+    /// See [`RateLimiter::check`](crate::RateLimiter::check).
+    pub fn check(&self) -> Result<(), DynNotUntil> {
+        self.check_key(&NotKeyed::NonKey)
+    }
+
+    /// Allow *only all* `n` cells through the rate limiter.
+    ///
+    /// See [`RateLimiter::check_n`](crate::RateLimiter::check_n).
+    pub fn check_n(&self, n: NonZeroU32) -> Result<(), NegativeMultiDecision<DynNotUntil>> {
+        self.check_key_n(&NotKeyed::NonKey, n)
+    }
+
+    /// Asynchronously resolves as soon as the rate limiter allows a cell through.
+    ///
+    /// See [`RateLimiter::until_ready`](crate::RateLimiter::until_ready).
+    #[cfg(feature = "std")]
+    pub async fn until_ready(&self) {
+        self.until_key_ready(&NotKeyed::NonKey).await
+    }
+}
+
+#[cfg(feature = "std")]
+impl<K> dyn AnyRateLimiter<K> {
+    /// Asynchronously resolves as soon as the rate limiter allows a cell through for the
+    /// given key.
+    ///
+    /// See [`RateLimiter::until_key_ready`](crate::RateLimiter::until_key_ready).
+    pub async fn until_key_ready(&self, key: &K) {
+        self.until_key_ready_with_jitter(key, Jitter::NONE).await
+    }
+
+    /// Asynchronously resolves as soon as the rate limiter allows a cell through for the
+    /// given key, with a randomized wait period.
+    pub async fn until_key_ready_with_jitter(&self, key: &K, jitter: Jitter) {
+        while let Err(negative) = self.check_key(key) {
+            Delay::new(jitter + negative.wait_time()).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::clock::FakeRelativeClock;
+    use crate::middleware::NoOpMiddleware;
+    use crate::state::keyed::HashMapStateStore;
+    use nonzero_ext::nonzero;
+
+    #[test]
+    fn keyed_dyn_limiters() {
+        let clock = FakeRelativeClock::default();
+        let lim: RateLimiter<u32, HashMapStateStore<u32>, _, NoOpMiddleware<_>> = RateLimiter::new(
+            Quota::per_second(nonzero!(2u32)),
+            Default::default(),
+            &clock,
+        );
+        let lim: DynRateLimiter<u32> = lim.into_dyn();
+
+        assert_eq!(lim.quota(), Quota::per_second(nonzero!(2u32)));
+        assert_eq!(Ok(()), lim.check_key(&1));
+        match lim.check_key_n(&1, nonzero!(2u32)) {
+            Err(NegativeMultiDecision::BatchNonConforming(2, negative)) => {
+                assert_eq!(negative.wait_time(), Duration::from_millis(500));
+                assert!(!format!("{}", negative).is_empty());
+            }
+            other => panic!("unexpected decision {:?}", other),
+        }
+        assert_eq!(
+            lim.check_key_n(&1, nonzero!(3u32)),
+            Err(NegativeMultiDecision::InsufficientCapacity(2))
+        );
+    }
+
+    #[test]
+    fn direct_dyn_limiters() {
+        let clock = FakeRelativeClock::default();
+        let lim =
+            RateLimiter::direct_with_clock(Quota::per_second(nonzero!(2u32)), &clock).into_dyn();
+        assert_eq!(Ok(()), lim.check_n(nonzero!(2u32)));
+        assert_eq!(
+            lim.check().map_err(|n| n.wait_time()),
+            Err(Duration::from_millis(500))
+        );
+    }
+}
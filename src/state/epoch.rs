@@ -0,0 +1,73 @@
+//! Resetting rate limiters in constant time, by moving on to a new epoch.
+
+use crate::clock::{self, Reference};
+use crate::middleware::RateLimitingMiddleware;
+use crate::nanos::Nanos;
+use crate::state::{RateLimiter, StateStore};
+use crate::sync::{AtomicU64, Ordering};
+
+/// The time that a rate limiter's resets skipped, in nanoseconds.
+#[derive(Debug, Default)]
+pub(crate) struct Epoch(AtomicU64);
+
+impl Epoch {
+    pub(crate) fn load(&self) -> Nanos {
+        Nanos::from(self.0.load(Ordering::Acquire))
+    }
+}
+
+/// # Resetting rate limiters
+///
+/// Resetting a rate limiter forgets all of its states at once, e.g. after an incident that
+/// wrongly used up the burst capacity of every client: Instead of erasing the states (which,
+/// for a keyed rate limiter with millions of keys, would hold the state store's locks for a
+/// long time), a reset moves the rate limiter on to a new epoch, in which every state that was
+/// stored before lies in the past. Such states are indistinguishable from fresh ones; keyed
+/// rate limiters reclaim them as stale keys in their housekeeping (like
+/// [`retain_recent`](#method.retain_recent)).
+impl<K, S, C, MW> RateLimiter<K, S, C, MW>
+where
+    S: StateStore<Key = K>,
+    C: clock::Clock,
+    MW: RateLimitingMiddleware<C::Instant>,
+{
+    /// Resets the rate limiter, as if it had no states, in constant time.
+    ///
+    /// The reset applies to the rate limiter and its clones. It doesn't apply to other rate
+    /// limiters that use the same state store (like those in other processes, sharing a file
+    /// or shared memory). States that run further ahead than the quota allows under the
+    /// current pressure (from cells let through under more pressure, or scheduled by a
+    /// `Shaper`) may not be reset entirely.
+    ///
+    /// # Example
+    /// ```rust
+    /// # use nonzero_ext::*;
+    /// use governor::{clock::FakeRelativeClock, Quota, RateLimiter};
+    ///
+    /// let clock = FakeRelativeClock::default();
+    /// let lim = RateLimiter::hashmap_with_clock(Quota::per_hour(nonzero!(1u32)), &clock);
+    /// lim.check_key(&"alice").unwrap();
+    /// assert!(lim.check_key(&"alice").is_err());
+    /// lim.reset();
+    /// assert!(lim.check_key(&"alice").is_ok());
+    /// lim.retain_recent();
+    /// assert_eq!(lim.len(), 1);
+    /// ```
+    pub fn reset(&self) {
+        let skip = self.gcra().horizon();
+        let tuning = self.tuning();
+        let _ = tuning
+            .epoch
+            .0
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |epoch| {
+                Some(epoch.saturating_add(skip.as_u64()))
+            });
+        tuning.waiters.notify(None);
+    }
+
+    /// Returns the state that a decision at `now` would consider fresh, in the GCRA's units.
+    pub(crate) fn units_at(&self, now: C::Instant) -> Nanos {
+        self.gcra_in_epoch()
+            .units_since_start(now.duration_since(self.start))
+    }
+}
@@ -0,0 +1,272 @@
+//! A direct state store kept in a file, for rate limits shared by successive processes.
+
+use crate::compat::prelude::*;
+
+use crate::nanos::Nanos;
+use crate::state::{saturated, NotKeyed, StateStore};
+use crate::{clock, gcra::Gcra, middleware::NoOpMiddleware, Quota, RateLimiter};
+use std::convert::{TryFrom, TryInto};
+use std::env;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::marker::PhantomData;
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Identifies state files (and the version of their format).
+const MAGIC: [u8; 8] = *b"GOVFILE1";
+
+/// The length of a state file: the magic bytes, the epoch and the theoretical arrival time.
+const LEN: usize = 24;
+
+/// A direct rate-limiting state that is stored in a small file, so that processes which run one
+/// after another (or at the same time) share a rate limit: e.g. a CLI tool that is run from
+/// cron or CI and must not make more than 60 API calls per minute, across all its runs.
+///
+/// The state is kept in a `.state` file next to a `.lock` file, which is locked (with `flock`)
+/// for the duration of each decision. The state is the theoretical arrival time on the
+/// [`SystemClock`][clock::SystemClock], counted from an epoch that the state file records when
+/// it is created (like a [`SharedMemoryState`](super::SharedMemoryState)'s). All processes must
+/// use the same quota, and wall clock adjustments can make the rate limiter let through too few
+/// or too many cells.
+///
+/// Every decision locks, reads and writes files, so this is much slower than in-memory state;
+/// it is meant for rate limits on the order of calls per second or less. If the files can't be
+/// locked, read or written during a decision, the decision is negative (as if the rate limit
+/// was used up), so that a broken state file doesn't lift the rate limit. A state file holding
+/// anything other than a state is treated as fresh, and a state that counts from another
+/// epoch (because another process replaced the file) is converted to this one's.
+///
+/// This is only available on Unix platforms, with the `file-state` feature.
+///
+/// # Example
+/// ```rust
+/// # use nonzero_ext::*;
+/// use governor::{state::FileState, Quota, RateLimiter};
+///
+/// # let dir = std::env::temp_dir().join(format!("governor-doctest-{}", std::process::id()));
+/// let quota = Quota::per_minute(nonzero!(60u32));
+/// // A CLI tool would usually use `RateLimiter::persistent(quota, "api-calls")`, which keeps
+/// // the state in the user's cache directory.
+/// let lim = RateLimiter::with_file_state(quota, FileState::open(&dir, "api-calls").unwrap());
+/// assert_eq!(Ok(()), lim.check());
+///
+/// // The next run of the program sees the cells used up by earlier runs:
+/// drop(lim);
+/// let lim = RateLimiter::with_file_state(quota, FileState::open(&dir, "api-calls").unwrap());
+/// assert_eq!(lim.check_n(nonzero!(60u32)).map_err(|_| ()), Err(()));
+/// # std::fs::remove_dir_all(&dir).unwrap();
+/// ```
+pub struct FileState {
+    lock: File,
+    state: File,
+    path: PathBuf,
+    epoch: u64,
+    /// The quota's number of cells per period, which states are scaled by.
+    scale: u64,
+}
+
+/// Holds the lock on the lock file until dropped.
+struct Locked<'a>(&'a File);
+
+impl<'a> Locked<'a> {
+    fn new(file: &'a File) -> io::Result<Locked<'a>> {
+        // Safety: flock only operates on the file descriptor, which `file` keeps open.
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) } == 0 {
+            Ok(Locked(file))
+        } else {
+            Err(io::Error::last_os_error())
+        }
+    }
+}
+
+impl Drop for Locked<'_> {
+    fn drop(&mut self) {
+        // Safety: see `Locked::new`.
+        unsafe {
+            libc::flock(self.0.as_raw_fd(), libc::LOCK_UN);
+        }
+    }
+}
+
+impl FileState {
+    /// Opens the state named `name` in the directory `dir`, creating the directory and the
+    /// state's files if necessary.
+    pub fn open<P: AsRef<Path>>(dir: P, name: &str) -> io::Result<FileState> {
+        let dir = dir.as_ref();
+        fs::create_dir_all(dir)?;
+        let open = |extension: &str| {
+            OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(dir.join(format!("{}.{}", name, extension)))
+        };
+        let mut state = FileState {
+            lock: open("lock")?,
+            state: open("state")?,
+            path: dir.join(format!("{}.state", name)),
+            epoch: 0,
+            scale: 1,
+        };
+        let locked = Locked::new(&state.lock)?;
+        match state.read_header()? {
+            Some((epoch, _)) => state.epoch = epoch,
+            None => {
+                // A new (or garbage) state file: Record the epoch that its state counts from.
+                let now = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map_err(|_| {
+                        io::Error::new(
+                            io::ErrorKind::InvalidInput,
+                            "the system clock is set before the UNIX epoch",
+                        )
+                    })?
+                    .as_nanos() as u64;
+                state.epoch = now.max(1);
+                state.write(Nanos::from(0))?;
+            }
+        }
+        drop(locked);
+        Ok(state)
+    }
+
+    /// Opens the state named `name` in the `governor` subdirectory of the user's cache
+    /// directory: `$XDG_CACHE_HOME/governor` if that variable is set, or `~/.cache/governor`.
+    pub fn in_cache_dir(name: &str) -> io::Result<FileState> {
+        FileState::open(cache_dir()?.join("governor"), name)
+    }
+
+    /// Returns the path of the file holding the state.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Returns the time that the rate-limiting state counts from.
+    fn epoch(&self) -> SystemTime {
+        UNIX_EPOCH + Duration::from_nanos(self.epoch)
+    }
+
+    /// Reads the epoch (in nanoseconds since the UNIX epoch) and the theoretical arrival time,
+    /// if the state file holds them.
+    fn read_header(&self) -> io::Result<Option<(u64, u64)>> {
+        let mut buf = [0u8; LEN];
+        match self.state.read_exact_at(&mut buf, 0) {
+            Ok(()) => {}
+            // A new (or truncated) state file:
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e),
+        }
+        if buf[..8] != MAGIC {
+            return Ok(None);
+        }
+        let epoch = u64::from_le_bytes(buf[8..16].try_into().expect("8 bytes"));
+        let tat = u64::from_le_bytes(buf[16..].try_into().expect("8 bytes"));
+        if epoch == 0 {
+            Ok(None)
+        } else {
+            Ok(Some((epoch, tat)))
+        }
+    }
+
+    /// Reads the theoretical arrival time, counted from the epoch. A state that counts from
+    /// another epoch (because another process replaced the file) is converted to count from
+    /// this one; the next decision overwrites it.
+    fn read(&self) -> io::Result<Option<Nanos>> {
+        let (epoch, tat) = match self.read_header()? {
+            Some((_, 0)) | None => return Ok(None),
+            Some(header) => header,
+        };
+        let skipped = i128::from(epoch) - i128::from(self.epoch);
+        let tat = i128::from(tat) + skipped * i128::from(self.scale);
+        // A state from before this epoch lies in the past of every decision:
+        Ok(u64::try_from(tat)
+            .ok()
+            .filter(|&tat| tat > 0)
+            .map(Nanos::from))
+    }
+
+    fn write(&self, tat: Nanos) -> io::Result<()> {
+        let mut buf = [0u8; LEN];
+        buf[..8].copy_from_slice(&MAGIC);
+        buf[8..16].copy_from_slice(&self.epoch.to_le_bytes());
+        buf[16..].copy_from_slice(&tat.as_u64().to_le_bytes());
+        self.state.write_all_at(&buf, 0)
+    }
+}
+
+fn cache_dir() -> io::Result<PathBuf> {
+    match env::var_os("XDG_CACHE_HOME").filter(|dir| !dir.is_empty()) {
+        Some(dir) => Ok(PathBuf::from(dir)),
+        None => match env::var_os("HOME").filter(|dir| !dir.is_empty()) {
+            Some(home) => Ok(PathBuf::from(home).join(".cache")),
+            None => Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                "neither XDG_CACHE_HOME nor HOME are set",
+            )),
+        },
+    }
+}
+
+impl StateStore for FileState {
+    type Key = NotKeyed;
+
+    fn measure_and_replace<T, F, E>(&self, _key: &Self::Key, f: F) -> Result<T, E>
+    where
+        F: Fn(Option<Nanos>) -> Result<(T, Nanos), E>,
+    {
+        let decided = Locked::new(&self.lock).and_then(|_locked| {
+            let (result, tat) = match f(self.read()?) {
+                Ok(decision) => decision,
+                Err(negative) => return Ok(Err(negative)),
+            };
+            self.write(tat)?;
+            Ok(Ok(result))
+        });
+        decided.unwrap_or_else(|_| {
+            // Without the state, the decision is made on a state that uses up the rate limit:
+            let (_, tat) = f(None)?;
+            saturated(&f, tat)
+        })
+    }
+}
+
+impl fmt::Debug for FileState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("FileState")
+            .field("path", &self.path)
+            .field("epoch", &self.epoch())
+            .finish()
+    }
+}
+
+/// # Direct rate limiters with persistent state - Constructors
+impl RateLimiter<NotKeyed, FileState, clock::SystemClock, NoOpMiddleware<SystemTime>> {
+    /// Constructs a direct rate limiter whose state is kept in the user's cache directory
+    /// under `name`, and shared with every process that uses the same name.
+    ///
+    /// See [`FileState::in_cache_dir`].
+    pub fn persistent(quota: Quota, name: &str) -> io::Result<Self> {
+        Ok(Self::with_file_state(quota, FileState::in_cache_dir(name)?))
+    }
+
+    /// Constructs a direct rate limiter whose state is kept in the given file state.
+    pub fn with_file_state(quota: Quota, mut state: FileState) -> Self {
+        let start = state.epoch();
+        let gcra = Gcra::new(quota);
+        state.scale = gcra.units_from_nanos(Nanos::from(1)).as_u64();
+        RateLimiter {
+            state,
+            clock: clock::SystemClock,
+            gcra,
+            tuning: Default::default(),
+            // States count from the file's epoch, so every process agrees on their meaning:
+            start,
+            middleware: PhantomData,
+        }
+    }
+}
@@ -1,14 +1,17 @@
-use std::prelude::v1::*;
+use crate::compat::prelude::*;
 
 use crate::nanos::Nanos;
 use crate::state::{NotKeyed, StateStore};
 use std::fmt;
 use std::fmt::Debug;
 use std::num::NonZeroU64;
-use std::sync::atomic::AtomicU64;
-use std::sync::atomic::Ordering;
 use std::time::Duration;
 
+#[cfg(not(loom))]
+use crate::sync::{AtomicU64, Ordering};
+#[cfg(loom)]
+use loom::sync::atomic::{AtomicU64, Ordering};
+
 /// An in-memory representation of a GCRA's rate-limiting state.
 ///
 /// Implemented using [`AtomicU64`] operations, this state representation can be used to
@@ -17,10 +20,32 @@ use std::time::Duration;
 ///
 /// Internally, the number tracked here is the theoretical arrival time (a GCRA term) in number of
 /// nanoseconds since the rate limiter was created.
+///
+/// # Memory ordering
+///
+/// Every update of the state reads the state it replaces with `Acquire` ordering and writes
+/// the new state with `Release` ordering (the compare-and-swap loop uses `AcqRel` on success and
+/// `Acquire` on failure). So the decisions that update a state form a single total order, and
+/// everything a thread did before a decision that updated the state *happens before* the
+/// decisions that come after it, even on other threads - e.g., a thread that is let through
+/// after another thread was let through sees all the writes the other thread made before its
+/// decision. Decisions that don't update the state (denied cells) still acquire the state they
+/// read.
+///
+/// The checks that only decide whether a state can be removed from a keyed state store (which
+/// happens under the store's lock) read the state with `Relaxed` ordering, and so don't
+/// synchronize with anything.
+///
+/// These guarantees are model-checked with [loom](https://docs.rs/loom): Built with
+/// `--cfg loom`, the state uses loom's atomics, and the `loom_` tests explore every
+/// interleaving of concurrent decisions (and resets) on a state, checking that no update is
+/// lost and that decisions publish the writes that precede them.
 #[derive(Default)]
+#[repr(transparent)]
 pub struct InMemoryState(AtomicU64);
 
 impl InMemoryState {
+    #[inline]
     pub(crate) fn measure_and_replace_one<T, F, E>(&self, mut f: F) -> Result<T, E>
     where
         F: FnMut(Option<Nanos>) -> Result<(T, Nanos), E>,
@@ -31,8 +56,8 @@ impl InMemoryState {
             match self.0.compare_exchange_weak(
                 prev,
                 new_data.into(),
-                Ordering::Release,
-                Ordering::Relaxed,
+                Ordering::AcqRel,
+                Ordering::Acquire,
             ) {
                 Ok(_) => return Ok(result),
                 Err(next_prev) => prev = next_prev,
@@ -44,15 +69,59 @@ impl InMemoryState {
         decision.map(|(result, _)| result)
     }
 
+    /// Makes a decision on a fresh state, returning the state that a positive decision leaves
+    /// behind, so keyed state stores only make an entry for a key if the decision stores one.
+    pub(crate) fn create<T, F, E>(f: F) -> Result<(T, InMemoryState), E>
+    where
+        F: FnOnce(Option<Nanos>) -> Result<(T, Nanos), E>,
+    {
+        let (result, tat) = f(None)?;
+        Ok((result, InMemoryState(AtomicU64::new(tat.into()))))
+    }
+
     pub(crate) fn is_older_than(&self, nanos: Nanos) -> bool {
         self.0.load(Ordering::Relaxed) <= nanos.into()
     }
+
+    /// Resets the state to a fresh one if it is older than `nanos`, leaving it alone if it was
+    /// updated concurrently.
+    pub(crate) fn reset_if_older_than(&self, nanos: Nanos) {
+        let _ = self
+            .0
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |prev| {
+                if prev <= nanos.into() {
+                    Some(0)
+                } else {
+                    None
+                }
+            });
+    }
+
+    /// Returns the theoretical arrival time, or `None` if the state is fresh.
+    ///
+    /// The time is in the units of the rate limiter's [`Gcra`](crate::gcra::Gcra), whose
+    /// [inspection methods](crate::gcra::Gcra::remaining_burst_capacity) turn it into burst
+    /// capacities and conforming times.
+    pub fn load(&self) -> Option<Nanos> {
+        NonZeroU64::new(self.0.load(Ordering::Acquire)).map(|n| n.get().into())
+    }
+
+    /// Replaces the theoretical arrival time, resetting the state to a fresh one for `None`.
+    pub(crate) fn store(&self, tat: Option<Nanos>) {
+        self.0
+            .store(tat.map(Nanos::as_u64).unwrap_or(0), Ordering::Release);
+    }
+
+    pub(crate) fn is_fresh(&self) -> bool {
+        self.0.load(Ordering::Relaxed) == 0
+    }
 }
 
 /// The InMemoryState is the canonical "direct" state store.
 impl StateStore for InMemoryState {
     type Key = NotKeyed;
 
+    #[inline]
     fn measure_and_replace<T, F, E>(&self, _key: &Self::Key, f: F) -> Result<T, E>
     where
         F: Fn(Option<Nanos>) -> Result<(T, Nanos), E>,
@@ -73,7 +142,7 @@ mod test {
 
     use super::*;
 
-    #[cfg(feature = "std")]
+    #[cfg(all(feature = "std", not(loom)))]
     fn try_triggering_collisions(n_threads: u64, tries_per_thread: u64) -> (u64, u64) {
         use std::sync::Arc;
         use std::thread;
@@ -106,7 +175,7 @@ mod test {
         (*value, hits)
     }
 
-    #[cfg(feature = "std")]
+    #[cfg(all(feature = "std", not(loom)))]
     #[test]
     /// Checks that many threads running simultaneously will collide,
     /// but result in the correct number being recorded in the state.
@@ -129,9 +198,115 @@ mod test {
         assert_gt!(hits, value);
     }
 
+    #[cfg(all(feature = "std", not(loom)))]
+    #[test]
+    /// Checks that a thread that sees a state also sees what the thread that stored it wrote
+    /// before its decision.
+    fn decisions_publish_writes() {
+        use std::sync::Arc;
+        use std::thread;
+
+        for _ in 0..1_000 {
+            let state = Arc::new((InMemoryState::default(), AtomicU64::new(0)));
+            let reader = thread::spawn({
+                let state = Arc::clone(&state);
+                move || loop {
+                    let seen = state.0.measure_and_replace_one(|tat| match tat {
+                        Some(_) => Ok(((), Nanos::from(2))),
+                        None => Err(()),
+                    });
+                    if seen.is_ok() {
+                        assert_eq!(state.1.load(Ordering::Relaxed), 42);
+                        break;
+                    }
+                }
+            });
+            state.1.store(42, Ordering::Relaxed);
+            let _ = state
+                .0
+                .measure_and_replace_one(|_| Ok::<_, ()>(((), Nanos::from(1))));
+            reader.join().unwrap();
+        }
+    }
+
+    #[cfg(not(loom))]
     #[test]
     fn in_memory_state_impls() {
         let state = InMemoryState(AtomicU64::new(0));
-        assert!(format!("{:?}", state).len() > 0);
+        assert!(!format!("{:?}", state).is_empty());
+    }
+
+    #[cfg(loom)]
+    fn increment(state: &InMemoryState) {
+        let _ = state.measure_and_replace_one(|tat| {
+            Ok::<_, ()>(((), Nanos::from(tat.map_or(0, Nanos::as_u64) + 1)))
+        });
+    }
+
+    #[cfg(loom)]
+    #[test]
+    /// Checks that concurrent decisions don't lose each other's updates.
+    fn loom_updates_are_not_lost() {
+        use loom::sync::Arc;
+        use loom::thread;
+
+        loom::model(|| {
+            let state = Arc::new(InMemoryState::default());
+            let other = thread::spawn({
+                let state = Arc::clone(&state);
+                move || increment(&state)
+            });
+            increment(&state);
+            other.join().unwrap();
+            assert_eq!(state.load(), Some(Nanos::from(2)));
+        });
+    }
+
+    #[cfg(loom)]
+    #[test]
+    /// Checks that a decision that sees a state also sees what the thread that stored the state
+    /// wrote before its decision.
+    fn loom_decisions_publish_writes() {
+        use loom::sync::Arc;
+        use loom::thread;
+
+        loom::model(|| {
+            let state = Arc::new((InMemoryState::default(), AtomicU64::new(0)));
+            let reader = thread::spawn({
+                let state = Arc::clone(&state);
+                move || {
+                    let seen = state.0.measure_and_replace_one(|tat| match tat {
+                        Some(_) => Ok(((), Nanos::from(2))),
+                        None => Err(()),
+                    });
+                    if seen.is_ok() {
+                        assert_eq!(state.1.load(Ordering::Relaxed), 42);
+                    }
+                }
+            });
+            state.1.store(42, Ordering::Relaxed);
+            increment(&state.0);
+            reader.join().unwrap();
+        });
+    }
+
+    #[cfg(loom)]
+    #[test]
+    /// Checks that resetting an old state doesn't undo a concurrent decision that updated it.
+    fn loom_resets_keep_concurrent_updates() {
+        use loom::sync::Arc;
+        use loom::thread;
+
+        loom::model(|| {
+            let state = Arc::new(InMemoryState::default());
+            state.store(Some(Nanos::from(3)));
+            let resetter = thread::spawn({
+                let state = Arc::clone(&state);
+                move || state.reset_if_older_than(Nanos::from(5))
+            });
+            let _ = state.measure_and_replace_one(|_| Ok::<_, ()>(((), Nanos::from(10))));
+            resetter.join().unwrap();
+            assert_eq!(state.load(), Some(Nanos::from(10)));
+        });
     }
 }
@@ -0,0 +1,194 @@
+//! A direct rate limiter for interrupt handlers on bare-metal targets.
+
+use crate::clock::TickClock;
+use crate::sync::{AtomicU32, Ordering};
+use crate::{NegativeMultiDecision, Quota};
+use std::num::NonZeroU32;
+
+/// A direct rate limiter whose decisions are safe to make from interrupt handlers, e.g. on
+/// single-core microcontrollers.
+///
+/// Its state is a single 32-bit theoretical arrival time in ticks of a hardware timer, which
+/// decisions update with a compare-and-swap: There are no locks that an interrupt could find
+/// held, and no 64-bit atomics, which many microcontrollers lack. A decision that an
+/// interrupt preempts just retries with the state that the interrupt left behind.
+///
+/// On targets without a compare-and-swap instruction (like the Cortex-M0's `thumbv6m`), enable
+/// the `critical-section` feature and link a `critical-section` implementation (e.g. the
+/// `cortex-m` crate's): Decisions then update the state with interrupts disabled for the few
+/// instructions that it takes. Single-core chips can build with
+/// `--cfg portable_atomic_unsafe_assume_single_core` instead.
+///
+/// Decisions take the timer's current tick count (or its lower 32 bits; the tick count may
+/// wrap around), and return the number of ticks to wait if they are negative. Quotas'
+/// cooldowns aren't supported.
+///
+/// A state never runs more than the burst capacity ahead of the decisions' time, so states
+/// that seem further ahead are taken to be from before the tick count last wrapped around.
+/// This only fails for a rate limiter that made no decisions for (a multiple of) 2^32 ticks,
+/// give or take one burst capacity's worth of ticks: Its next decisions may deny cells for up
+/// to that long.
+///
+/// # Example
+/// ```rust
+/// # use nonzero_ext::*;
+/// use governor::{state::InterruptSafeRateLimiter, Quota};
+///
+/// // A timer ticking at 1 MHz, and a quota of 100 cells per second:
+/// let quota = Quota::per_second(nonzero!(100u32));
+/// let lim = InterruptSafeRateLimiter::new(quota, nonzero!(1_000_000u32)).unwrap();
+/// let now = u32::MAX - 500_000; // about to wrap around
+/// assert_eq!(lim.check_n_at(nonzero!(100u32), now), Ok(()));
+/// // One cell replenishes every 10,000 ticks:
+/// assert_eq!(lim.check_at(now), Err(10_000));
+/// assert_eq!(lim.check_at(now.wrapping_add(10_000)), Ok(()));
+/// ```
+#[derive(Debug)]
+pub struct InterruptSafeRateLimiter {
+    /// The theoretical arrival time in ticks, or 0 for a fresh state.
+    tat: AtomicU32,
+    /// The ticks that each cell uses up.
+    t: u32,
+    /// The burst capacity, in cells.
+    burst: u32,
+}
+
+impl InterruptSafeRateLimiter {
+    /// Constructs a rate limiter for the given quota, on a timer that counts `ticks_per_second`.
+    ///
+    /// Cells are rounded up to whole ticks. Returns `None` if the quota's burst capacity takes
+    /// more than 2^31 ticks to replenish.
+    pub fn new(quota: Quota, ticks_per_second: NonZeroU32) -> Option<Self> {
+        let period = quota.replenish_period.as_nanos() * u128::from(ticks_per_second.get());
+        let per_cell = 1_000_000_000 * u128::from(quota.cells_per_period.get());
+        let t = period.div_ceil(per_cell).max(1);
+        let burst = quota.max_burst.get();
+        if t * u128::from(burst) > i32::MAX as u128 {
+            return None;
+        }
+        Some(InterruptSafeRateLimiter {
+            tat: AtomicU32::new(0),
+            t: t as u32,
+            burst,
+        })
+    }
+
+    /// Returns the number of ticks that each cell uses up.
+    pub fn ticks_per_cell(&self) -> u32 {
+        self.t
+    }
+
+    /// Allows a single cell through the rate limiter at the given tick count, or returns the
+    /// number of ticks until it would be allowed through.
+    pub fn check_at(&self, now: u32) -> Result<(), u32> {
+        self.check_n_at(NonZeroU32::MIN, now)
+            .map_err(|negative| match negative {
+                NegativeMultiDecision::BatchNonConforming(_, wait) => wait,
+                // A burst capacity is never less than one cell:
+                NegativeMultiDecision::InsufficientCapacity(_) => u32::MAX,
+            })
+    }
+
+    /// Allows *only all* `n` cells through the rate limiter at the given tick count.
+    ///
+    /// If they are not let through, the
+    /// [`BatchNonConforming`](NegativeMultiDecision::BatchNonConforming) error gives the number
+    /// of ticks until they would be.
+    pub fn check_n_at(&self, n: NonZeroU32, now: u32) -> Result<(), NegativeMultiDecision<u32>> {
+        if n.get() > self.burst {
+            return Err(NegativeMultiDecision::InsufficientCapacity(self.burst));
+        }
+        // Neither of these overflows, as the burst capacity is at most 2^31 ticks:
+        let tau = self.t * self.burst;
+        let weight = self.t * n.get();
+        let mut tat = self.tat.load(Ordering::Acquire);
+        loop {
+            let ahead = match tat.wrapping_sub(now) {
+                _ if tat == 0 => 0,
+                ahead if ahead > tau => 0,
+                ahead => ahead,
+            };
+            if ahead + weight > tau {
+                return Err(NegativeMultiDecision::BatchNonConforming(
+                    n.get(),
+                    ahead + weight - tau,
+                ));
+            }
+            // 0 marks a fresh state, so a state that happens to end there ends a tick later:
+            let next = now.wrapping_add(ahead + weight).max(1);
+            match self
+                .tat
+                .compare_exchange_weak(tat, next, Ordering::AcqRel, Ordering::Acquire)
+            {
+                Ok(_) => return Ok(()),
+                Err(current) => tat = current,
+            }
+        }
+    }
+
+    /// Allows a single cell through the rate limiter at the tick count of `clock`.
+    pub fn check<F: Fn() -> u64>(&self, clock: &TickClock<F>) -> Result<(), u32> {
+        self.check_at(clock.ticks() as u32)
+    }
+
+    /// Allows *only all* `n` cells through the rate limiter at the tick count of `clock`.
+    pub fn check_n<F: Fn() -> u64>(
+        &self,
+        clock: &TickClock<F>,
+        n: NonZeroU32,
+    ) -> Result<(), NegativeMultiDecision<u32>> {
+        self.check_n_at(n, clock.ticks() as u32)
+    }
+
+    /// Resets the rate limiter to its fresh state.
+    pub fn reset(&self) {
+        self.tat.store(0, Ordering::Release);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use nonzero_ext::nonzero;
+    use std::time::Duration;
+
+    #[test]
+    fn replenishes_in_ticks() {
+        let lim =
+            InterruptSafeRateLimiter::new(Quota::per_second(nonzero!(4u32)), nonzero!(1000u32))
+                .unwrap();
+        assert_eq!(lim.ticks_per_cell(), 250);
+        assert_eq!(lim.check_n_at(nonzero!(4u32), 100), Ok(()));
+        assert_eq!(lim.check_at(100), Err(250));
+        assert_eq!(lim.check_at(349), Err(1));
+        assert_eq!(lim.check_at(350), Ok(()));
+        assert_eq!(
+            lim.check_n_at(nonzero!(5u32), 350),
+            Err(NegativeMultiDecision::InsufficientCapacity(4))
+        );
+        // Long after the last decision, the full burst is back:
+        assert_eq!(lim.check_n_at(nonzero!(4u32), 1_000_000), Ok(()));
+        lim.reset();
+        assert_eq!(lim.check_n_at(nonzero!(4u32), 1_000_000), Ok(()));
+    }
+
+    #[test]
+    fn survives_wraparound() {
+        let lim =
+            InterruptSafeRateLimiter::new(Quota::per_second(nonzero!(2u32)), nonzero!(100u32))
+                .unwrap();
+        // A fresh state at any tick count is fresh:
+        assert_eq!(lim.check_n_at(nonzero!(2u32), u32::MAX - 10), Ok(()));
+        assert_eq!(lim.check_at(u32::MAX), Err(40));
+        assert_eq!(lim.check_at(39), Ok(()));
+        // Half a wraparound later, the state is long in the past:
+        assert_eq!(lim.check_n_at(nonzero!(2u32), 1 << 31), Ok(()));
+    }
+
+    #[test]
+    fn rejects_unrepresentable_quotas() {
+        let slow = Quota::with_period(Duration::from_secs(3600)).unwrap();
+        assert!(InterruptSafeRateLimiter::new(slow, nonzero!(1_000_000u32)).is_none());
+        assert!(InterruptSafeRateLimiter::new(slow, nonzero!(1000u32)).is_some());
+    }
+}
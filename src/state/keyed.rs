@@ -7,14 +7,16 @@
 //! Rate limiters based on these types are constructed with
 //! [the `RateLimiter` constructors](../struct.RateLimiter.html#keyed-rate-limiters---default-constructors)
 
+use crate::compat::prelude::*;
+use crate::sync::Arc;
 use std::hash::Hash;
+use std::mem;
 use std::num::NonZeroU32;
-use std::prelude::v1::*;
 
-use crate::state::StateStore;
+use crate::state::{InMemoryState, StateStore};
 use crate::{
-    clock::{self, Reference},
-    middleware::RateLimitingMiddleware,
+    clock,
+    middleware::{NoOpMiddleware, RateLimitingMiddleware},
     nanos::Nanos,
     NegativeMultiDecision, Quota, RateLimiter,
 };
@@ -77,6 +79,37 @@ where
     }
 }
 
+/// # Keyed rate limiters - custom clocks
+impl<K, C> RateLimiter<K, DefaultKeyedStateStore<K>, C, NoOpMiddleware<C::Instant>>
+where
+    K: Clone + Hash + Eq,
+    C: clock::Clock,
+{
+    /// Constructs a new keyed rate limiter with a custom clock, backed by the
+    /// [`DefaultKeyedStateStore`].
+    ///
+    /// The rate limiter's type can be named with the [`KeyedRateLimiter`](crate::KeyedRateLimiter)
+    /// alias:
+    /// ```rust
+    /// # use nonzero_ext::*;
+    /// use governor::{clock::FakeRelativeClock, KeyedRateLimiter, Quota, RateLimiter};
+    ///
+    /// struct Server {
+    ///     limiter: KeyedRateLimiter<String, FakeRelativeClock>,
+    /// }
+    ///
+    /// let clock = FakeRelativeClock::default();
+    /// let server = Server {
+    ///     limiter: RateLimiter::keyed_with_clock(Quota::per_second(nonzero!(1u32)), &clock),
+    /// };
+    /// assert_eq!(Ok(()), server.limiter.check_key(&"alice".to_string()));
+    /// ```
+    pub fn keyed_with_clock(quota: Quota, clock: &C) -> Self {
+        let state = DefaultKeyedStateStore::default();
+        RateLimiter::new(quota, state, clock)
+    }
+}
+
 /// # Keyed rate limiters - Manually checking cells
 impl<K, S, C, MW> RateLimiter<K, S, C, MW>
 where
@@ -89,8 +122,9 @@ where
     ///
     /// If the rate limit is reached, `check_key` returns information about the earliest
     /// time that a cell might be allowed through again under that key.
+    #[inline]
     pub fn check_key(&self, key: &K) -> Result<MW::PositiveOutcome, MW::NegativeOutcome> {
-        self.gcra.test_and_update::<K, C::Instant, S, MW>(
+        self.gcra().test_and_update::<K, C::Instant, S, MW>(
             self.start,
             key,
             &self.state,
@@ -117,7 +151,7 @@ where
         key: &K,
         n: NonZeroU32,
     ) -> Result<MW::PositiveOutcome, NegativeMultiDecision<MW::NegativeOutcome>> {
-        self.gcra.test_n_all_and_update::<K, C::Instant, S, MW>(
+        self.gcra().test_n_all_and_update::<K, C::Instant, S, MW>(
             self.start,
             key,
             n,
@@ -125,6 +159,88 @@ where
             self.clock.now(),
         )
     }
+
+    /// Returns the fraction of the burst capacity that is currently used up for the given key,
+    /// between 0 and 1, without consuming any cells.
+    ///
+    /// See [`saturation`](#method.saturation) (for direct rate limiters) for details.
+    pub fn key_saturation(&self, key: &K) -> f64 {
+        self.gcra_in_epoch()
+            .saturation(self.start, key, &self.state, self.clock.now())
+    }
+
+    /// Pre-populates the states of the given keys, each with the fraction of its burst capacity
+    /// (between 0 and 1) that it has used up right now, e.g. with usage imported from another
+    /// system, or from the instances being replaced in a blue/green cutover. This keeps a newly
+    /// started rate limiter from granting every client a fresh full burst.
+    ///
+    /// Seeding never returns capacity to a key: Keys whose state already uses up more of their
+    /// burst capacity than the given fraction keep their state.
+    ///
+    /// # Example
+    /// ```rust
+    /// # use nonzero_ext::*;
+    /// use governor::{Quota, RateLimiter};
+    ///
+    /// let lim = RateLimiter::keyed(Quota::per_hour(nonzero!(10u32)));
+    /// lim.seed(vec![("alice", 1.0), ("bob", 0.5)]);
+    /// assert!(lim.check_key(&"alice").is_err());
+    /// assert!(lim.check_key_n(&"bob", nonzero!(5u32)).is_ok());
+    /// assert!(lim.check_key(&"bob").is_err());
+    /// ```
+    pub fn seed(&self, seeds: impl IntoIterator<Item = (K, f64)>) {
+        let now = self.clock.now();
+        let gcra = self.gcra_in_epoch();
+        for (key, fraction) in seeds {
+            gcra.seed(self.start, &key, &self.state, now, fraction);
+        }
+    }
+
+    /// Checks a sequence of cells for the given key that arrived at the given times, in order,
+    /// recording which ones conform in the `conforming` bitmap: The decision for `arrivals[i]`
+    /// is bit `i % 64` of `conforming[i / 64]`. Returns the number of conforming cells.
+    ///
+    /// See [`check_arrivals`](#method.check_arrivals) (for direct rate limiters) for details.
+    ///
+    /// # Panics
+    /// Panics if the bitmap can not hold `arrivals.len()` bits. Like `check_arrivals`, this
+    /// method is only available with the `std` feature; use
+    /// [`try_check_key_arrivals`](#method.try_check_key_arrivals) in `no_std` builds.
+    #[cfg(feature = "std")]
+    pub fn check_key_arrivals(
+        &self,
+        key: &K,
+        arrivals: &[C::Instant],
+        conforming: &mut [u64],
+    ) -> usize {
+        let words = conforming.len();
+        self.try_check_key_arrivals(key, arrivals, conforming)
+            .unwrap_or_else(|| {
+                panic!(
+                    "bitmap of {} words can not hold {} decisions",
+                    words,
+                    arrivals.len()
+                )
+            })
+    }
+
+    /// Like [`check_key_arrivals`](#method.check_key_arrivals), but returns `None` (without
+    /// making any decisions) instead of panicking if the bitmap can not hold `arrivals.len()`
+    /// bits.
+    pub fn try_check_key_arrivals(
+        &self,
+        key: &K,
+        arrivals: &[C::Instant],
+        conforming: &mut [u64],
+    ) -> Option<usize> {
+        self.gcra().test_arrivals_and_update::<K, C::Instant, S>(
+            self.start,
+            key,
+            &self.state,
+            arrivals,
+            conforming,
+        )
+    }
 }
 
 /// Keyed rate limiters that can be "cleaned up".
@@ -140,6 +256,15 @@ pub trait ShrinkableKeyedStateStore<K: Hash>: KeyedStateStore<K> {
     /// Remove those keys with state older than `drop_below`.
     fn retain_recent(&self, drop_below: Nanos);
 
+    /// Remove those keys with state older than `drop_below`, returning the removed keys.
+    ///
+    /// State stores that can't tell which keys they removed return no keys; this is what the
+    /// default implementation does.
+    fn retain_recent_evicted(&self, drop_below: Nanos) -> Vec<K> {
+        self.retain_recent(drop_below);
+        Vec::new()
+    }
+
     /// Shrinks the capacity of the state store, if possible.
     ///
     /// If the state store does not support shrinking, this method is a no-op.
@@ -157,6 +282,57 @@ pub trait ShrinkableKeyedStateStore<K: Hash>: KeyedStateStore<K> {
     /// imprecise results (indicating that the state store is empty
     /// while a concurrent rate-limiting operation is taking place).
     fn is_empty(&self) -> bool;
+
+    /// Returns the number of keys the state store can hold without allocating more memory.
+    ///
+    /// State stores that don't track their capacity return their [`len`](#tymethod.len).
+    fn capacity(&self) -> usize {
+        self.len()
+    }
+
+    /// Returns an estimate of the number of bytes of memory that the state store uses for its
+    /// keys and rate-limiting states.
+    ///
+    /// The default estimate is the size of a key and a rate-limiting state for each key that
+    /// the state store has [`capacity`](#method.capacity) for; it does not account for memory
+    /// that the keys themselves point to (e.g. a `String`'s contents), or for the state store's
+    /// own bookkeeping.
+    fn memory_usage(&self) -> usize {
+        self.capacity() * (mem::size_of::<K>() + mem::size_of::<InMemoryState>())
+    }
+}
+
+impl<K: Hash, S: ShrinkableKeyedStateStore<K>> ShrinkableKeyedStateStore<K> for Arc<S>
+where
+    Arc<S>: KeyedStateStore<K>,
+{
+    fn retain_recent(&self, drop_below: Nanos) {
+        (**self).retain_recent(drop_below)
+    }
+
+    fn retain_recent_evicted(&self, drop_below: Nanos) -> Vec<K> {
+        (**self).retain_recent_evicted(drop_below)
+    }
+
+    fn shrink_to_fit(&self) {
+        (**self).shrink_to_fit()
+    }
+
+    fn len(&self) -> usize {
+        (**self).len()
+    }
+
+    fn is_empty(&self) -> bool {
+        (**self).is_empty()
+    }
+
+    fn capacity(&self) -> usize {
+        (**self).capacity()
+    }
+
+    fn memory_usage(&self) -> usize {
+        (**self).memory_usage()
+    }
 }
 
 /// # Keyed rate limiters - Housekeeping
@@ -181,7 +357,7 @@ where
         // arrival time is larger than a starting state for the bucket gets to stay, everything
         // else (that's indistinguishable from a starting state) goes.
         let now = self.clock.now();
-        let drop_below = now.duration_since(self.start);
+        let drop_below = self.units_at(now);
 
         self.state.retain_recent(drop_below);
     }
@@ -191,6 +367,21 @@ where
         self.state.shrink_to_fit();
     }
 
+    /// Compacts the rate limiter's state store after a spike in traffic: Removes the keys that
+    /// [`retain_recent`](#method.retain_recent) removes, then releases the capacity that the
+    /// remaining keys don't need (see [`shrink_to_fit`](#method.shrink_to_fit)).
+    ///
+    /// Returns the number of keys that were removed, as far as [`len`](#method.len) can tell.
+    /// Compacting holds the state store's locks while it rebuilds its tables (for DashMap
+    /// state stores, one shard after the other), so this is best called from a maintenance
+    /// task, rather than on every request.
+    pub fn compact(&self) -> usize {
+        let before = self.state.len();
+        self.retain_recent();
+        self.shrink_to_fit();
+        before.saturating_sub(self.state.len())
+    }
+
     /// Returns the number of "live" keys in the rate limiter's state store.
     ///
     /// Depending on how the state store is implemented, this may
@@ -207,21 +398,106 @@ where
     pub fn is_empty(&self) -> bool {
         self.state.is_empty()
     }
+
+    /// Returns the number of keys the rate limiter's state store can hold without allocating
+    /// more memory.
+    pub fn capacity(&self) -> usize {
+        self.state.capacity()
+    }
+
+    /// Returns an estimate of the number of bytes of memory that the rate limiter's state
+    /// store uses for its keys and rate-limiting states.
+    ///
+    /// This is meant for monitoring the growth of keyed rate limiters; see
+    /// [`ShrinkableKeyedStateStore::memory_usage`] for what the estimate accounts for.
+    pub fn memory_usage(&self) -> usize {
+        self.state.memory_usage()
+    }
 }
 
 mod hashmap;
 
-pub use hashmap::HashMapStateStore;
+pub use hashmap::{HashMapKeyedLimiter, HashMapStateStore};
 
 #[cfg(all(feature = "std", feature = "dashmap"))]
 mod dashmap;
 
 #[cfg(all(feature = "std", feature = "dashmap"))]
-pub use self::dashmap::DashMapStateStore;
+pub use self::dashmap::{DashMapKeyedLimiter, DashMapStateStore};
+
+mod enum_keyed;
+
+pub use enum_keyed::{EnumArray, EnumKeyedLimiter, EnumStateStore};
+
+mod borrowed;
+
+pub use borrowed::BorrowedKeyedStateStore;
+
+mod observer;
+
+pub use observer::{KeyObserver, ObservedStateStore};
+
+mod tenant;
+
+pub use tenant::TenantRateLimiter;
+
+mod burst;
+
+pub use burst::BurstRateLimiter;
+
+mod dimensional;
+
+pub use dimensional::{DimensionDenied, DimensionalRateLimiter};
+
+mod tree;
+
+pub use tree::{LimiterTree, TreeDenied, TreeError};
 
 #[cfg(feature = "std")]
 mod future;
 
+#[cfg(feature = "std")]
+mod async_keyed;
+
+#[cfg(feature = "std")]
+mod interned;
+
+#[cfg(feature = "std")]
+pub use interned::{InternedKey, Interner};
+
+#[cfg(feature = "std")]
+mod stats;
+
+#[cfg(feature = "std")]
+pub use stats::{KeyStats, StatsStateStore};
+
+#[cfg(feature = "std")]
+mod compact;
+
+#[cfg(feature = "std")]
+pub use compact::CompactStateStore;
+
+#[cfg(feature = "std")]
+mod creation;
+
+#[cfg(feature = "std")]
+pub use creation::CreationLimitedStateStore;
+
+#[cfg(feature = "std")]
+mod prefilter;
+
+#[cfg(feature = "std")]
+pub use prefilter::PrefilteredStateStore;
+
+#[cfg(feature = "std")]
+mod sweeper;
+
+#[cfg(feature = "std")]
+pub use sweeper::Sweeper;
+
+#[cfg(feature = "std")]
+pub use async_keyed::AsyncKeyedLimiter;
+
 #[cfg(any(all(feature = "std", not(feature = "dashmap")), not(feature = "std")))]
 /// The default keyed rate limiter type: a mutex-wrapped [`HashMap`][std::collections::HashMap].
 pub type DefaultKeyedStateStore<K> = HashMapStateStore<K>;
@@ -0,0 +1,179 @@
+#![cfg(feature = "std")]
+
+use crate::compat::prelude::*;
+
+use crate::sync::Arc;
+use crate::{
+    clock::{self, Clock},
+    middleware::{NoOpMiddleware, RateLimitingMiddleware},
+    state::{
+        keyed::{DefaultKeyedStateStore, KeyedStateStore},
+        StateStore,
+    },
+    Jitter, NotUntil, Quota, RateLimiter,
+};
+use std::future::Future;
+use std::hash::Hash;
+
+/// A keyed rate limiter that is made to be shared between `async` tasks.
+///
+/// `AsyncKeyedLimiter` keeps the rate limiter's state behind an [`Arc`], so it can be cloned
+/// cheaply (all clones share the same rate-limiting state) and stored in the shared state of web
+/// frameworks like axum or tonic. Its waiting methods take their key by value and return
+/// `'static` futures that are [`Send`], so they can be spawned onto any executor without
+/// borrowing from the limiter.
+///
+/// The waiting methods use [`futures_timer`], so they work with any async runtime.
+///
+/// # Example
+/// ```rust
+/// # use nonzero_ext::*;
+/// # use futures::executor::block_on;
+/// use governor::{AsyncKeyedLimiter, Quota};
+///
+/// let lim = AsyncKeyedLimiter::keyed(Quota::per_second(nonzero!(20u32)));
+///
+/// // The returned future neither borrows the limiter nor the key:
+/// let ready = lim.until_key_ready(String::from("cus_1"));
+/// block_on(ready);
+///
+/// assert_eq!(Ok(()), lim.check_key(&String::from("cus_2")));
+/// ```
+#[derive(Debug)]
+pub struct AsyncKeyedLimiter<
+    K,
+    S = DefaultKeyedStateStore<K>,
+    C = clock::DefaultClock,
+    MW = NoOpMiddleware<<C as Clock>::Instant>,
+> where
+    K: Hash + Eq + Clone,
+    S: StateStore<Key = K>,
+    C: clock::ReasonablyRealtime,
+    MW: RateLimitingMiddleware<C::Instant>,
+{
+    limiter: RateLimiter<K, Arc<S>, C, MW>,
+}
+
+/// # Async keyed rate limiters - constructors
+impl<K> AsyncKeyedLimiter<K>
+where
+    K: Hash + Eq + Clone,
+{
+    /// Constructs a new shareable keyed rate limiter backed by the
+    /// [`DefaultKeyedStateStore`] and the default clock.
+    pub fn keyed(quota: Quota) -> Self {
+        RateLimiter::keyed(quota).into()
+    }
+}
+
+impl<K, C> AsyncKeyedLimiter<K, DefaultKeyedStateStore<K>, C>
+where
+    K: Hash + Eq + Clone,
+    C: clock::ReasonablyRealtime,
+{
+    /// Constructs a new shareable keyed rate limiter with a custom clock, backed by the
+    /// [`DefaultKeyedStateStore`].
+    pub fn keyed_with_clock(quota: Quota, clock: &C) -> Self {
+        RateLimiter::keyed_with_clock(quota, clock).into()
+    }
+}
+
+impl<K, S, C, MW> From<RateLimiter<K, S, C, MW>> for AsyncKeyedLimiter<K, S, C, MW>
+where
+    K: Hash + Eq + Clone,
+    S: KeyedStateStore<K>,
+    C: clock::ReasonablyRealtime,
+    MW: RateLimitingMiddleware<C::Instant>,
+{
+    /// Converts a keyed rate limiter into a shareable one.
+    fn from(limiter: RateLimiter<K, S, C, MW>) -> Self {
+        AsyncKeyedLimiter {
+            limiter: limiter.into_shared(),
+        }
+    }
+}
+
+impl<K, S, C, MW> Clone for AsyncKeyedLimiter<K, S, C, MW>
+where
+    K: Hash + Eq + Clone,
+    S: KeyedStateStore<K>,
+    C: clock::ReasonablyRealtime,
+    MW: RateLimitingMiddleware<C::Instant>,
+{
+    fn clone(&self) -> Self {
+        AsyncKeyedLimiter {
+            limiter: self.limiter.clone(),
+        }
+    }
+}
+
+/// # Async keyed rate limiters - checking and waiting
+impl<K, S, C, MW> AsyncKeyedLimiter<K, S, C, MW>
+where
+    K: Hash + Eq + Clone + Send + Sync + 'static,
+    S: KeyedStateStore<K> + Send + Sync + 'static,
+    C: clock::ReasonablyRealtime + Send + Sync + 'static,
+    MW: RateLimitingMiddleware<C::Instant, NegativeOutcome = NotUntil<C::Instant>>
+        + Send
+        + Sync
+        + 'static,
+    MW::PositiveOutcome: Send,
+{
+    /// Returns the rate limiter that this shareable limiter wraps.
+    pub fn limiter(&self) -> &RateLimiter<K, Arc<S>, C, MW> {
+        &self.limiter
+    }
+
+    /// Allow a single cell through the rate limiter for the given key.
+    ///
+    /// See [`RateLimiter::check_key`].
+    pub fn check_key(&self, key: &K) -> Result<MW::PositiveOutcome, NotUntil<C::Instant>> {
+        self.limiter.check_key(key)
+    }
+
+    /// Returns a future that resolves as soon as the rate limiter allows a cell through
+    /// for the given key.
+    ///
+    /// See [`RateLimiter::until_key_ready`].
+    pub fn until_key_ready(
+        &self,
+        key: K,
+    ) -> impl Future<Output = MW::PositiveOutcome> + Send + 'static {
+        let shared = self.clone();
+        async move { shared.limiter.until_key_ready(&key).await }
+    }
+
+    /// Returns a future that resolves as soon as the rate limiter allows a cell through
+    /// for the given key, with a randomized wait period.
+    ///
+    /// See [`RateLimiter::until_key_ready_with_jitter`].
+    pub fn until_key_ready_with_jitter(
+        &self,
+        key: K,
+        jitter: Jitter,
+    ) -> impl Future<Output = MW::PositiveOutcome> + Send + 'static {
+        let shared = self.clone();
+        async move {
+            shared
+                .limiter
+                .until_key_ready_with_jitter(&key, jitter)
+                .await
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use nonzero_ext::nonzero;
+
+    fn assert_send_sync<T: Send + Sync + 'static>(_: &T) {}
+
+    #[test]
+    fn async_keyed_limiter_is_send_sync() {
+        let lim: AsyncKeyedLimiter<String> =
+            AsyncKeyedLimiter::keyed(Quota::per_second(nonzero!(1u32)));
+        assert_send_sync(&lim);
+        assert!(!format!("{:?}", lim).is_empty());
+    }
+}
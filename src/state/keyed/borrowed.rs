@@ -0,0 +1,137 @@
+use crate::compat::prelude::*;
+
+use crate::nanos::Nanos;
+use crate::state::keyed::KeyedStateStore;
+use crate::state::StateStore;
+use crate::sync::Arc;
+use crate::{clock, middleware::RateLimitingMiddleware, NegativeMultiDecision, RateLimiter};
+use std::hash::Hash;
+use std::marker::PhantomData;
+use std::num::NonZeroU32;
+
+/// Keyed state stores that can look up rate-limiting states by a borrowed form of their keys.
+///
+/// This mirrors the lookup methods of [`HashMap`][std::collections::HashMap]: A state store
+/// with `String` keys can be queried with a `&str`, and only needs to allocate an owned key
+/// (using [`ToOwned`]) when it encounters a key it doesn't have a state for yet.
+///
+/// The [`HashMapStateStore`][crate::state::keyed::HashMapStateStore] and
+/// [`DashMapStateStore`][crate::state::keyed::DashMapStateStore] implement this trait.
+pub trait BorrowedKeyedStateStore<K: Hash, Q: ?Sized>: KeyedStateStore<K> {
+    /// Updates the rate-limiting state for the key that `key` is a borrowed form of, using the
+    /// given closure.
+    ///
+    /// See [`StateStore::measure_and_replace`] for the closure's semantics.
+    fn measure_and_replace_borrowed<T, F, E>(&self, key: &Q, f: F) -> Result<T, E>
+    where
+        F: Fn(Option<Nanos>) -> Result<(T, Nanos), E>;
+}
+
+impl<K: Hash, Q: ?Sized, S: BorrowedKeyedStateStore<K, Q>> BorrowedKeyedStateStore<K, Q> for Arc<S>
+where
+    Arc<S>: KeyedStateStore<K>,
+{
+    #[inline]
+    fn measure_and_replace_borrowed<T, F, E>(&self, key: &Q, f: F) -> Result<T, E>
+    where
+        F: Fn(Option<Nanos>) -> Result<(T, Nanos), E>,
+    {
+        (**self).measure_and_replace_borrowed(key, f)
+    }
+}
+
+/// Presents a state store as one keyed by borrowed keys, so the GCRA can make decisions on it.
+struct BorrowedLookup<'s, 'q, K, S, Q: ?Sized> {
+    store: &'s S,
+    key: PhantomData<fn(K)>,
+    borrowed: PhantomData<&'q Q>,
+}
+
+impl<'s, 'q, K, S, Q> StateStore for BorrowedLookup<'s, 'q, K, S, Q>
+where
+    K: Hash,
+    S: BorrowedKeyedStateStore<K, Q>,
+    Q: ?Sized,
+{
+    type Key = &'q Q;
+
+    #[inline]
+    fn measure_and_replace<T, F, E>(&self, key: &Self::Key, f: F) -> Result<T, E>
+    where
+        F: Fn(Option<Nanos>) -> Result<(T, Nanos), E>,
+    {
+        self.store.measure_and_replace_borrowed(*key, f)
+    }
+}
+
+/// # Keyed rate limiters - Checking cells by borrowed keys
+impl<K, S, C, MW> RateLimiter<K, S, C, MW>
+where
+    S: KeyedStateStore<K>,
+    K: Hash,
+    C: clock::Clock,
+    MW: RateLimitingMiddleware<C::Instant>,
+{
+    fn borrowed<'q, Q: ?Sized>(&self) -> BorrowedLookup<'_, 'q, K, S, Q> {
+        BorrowedLookup {
+            store: &self.state,
+            key: PhantomData,
+            borrowed: PhantomData,
+        }
+    }
+
+    /// Allow a single cell through the rate limiter for the key that `key` is a borrowed
+    /// form of.
+    ///
+    /// This works like [`check_key`](#method.check_key), but allows checking e.g. a rate limiter
+    /// with `String` keys using a `&str`, without allocating a `String` unless the key is new
+    /// to the rate limiter. The middleware sees the borrowed key.
+    ///
+    /// # Example
+    /// ```rust
+    /// # use nonzero_ext::*;
+    /// use governor::{Quota, RateLimiter};
+    ///
+    /// let lim = RateLimiter::<String, _, _>::keyed(Quota::per_second(nonzero!(1u32)));
+    /// let path: &str = "/api/v1/users";
+    /// assert_eq!(Ok(()), lim.check_key_borrowed(path));
+    /// assert_ne!(Ok(()), lim.check_key(&String::from(path)));
+    /// ```
+    pub fn check_key_borrowed<Q>(&self, key: &Q) -> Result<MW::PositiveOutcome, MW::NegativeOutcome>
+    where
+        S: BorrowedKeyedStateStore<K, Q>,
+        Q: ?Sized,
+    {
+        self.gcra()
+            .test_and_update::<&Q, C::Instant, BorrowedLookup<'_, '_, K, S, Q>, MW>(
+                self.start,
+                &key,
+                &self.borrowed(),
+                self.clock.now(),
+            )
+    }
+
+    /// Allow *only all* `n` cells through the rate limiter for the key that `key` is a
+    /// borrowed form of.
+    ///
+    /// See [`check_key_n`](#method.check_key_n) and
+    /// [`check_key_borrowed`](#method.check_key_borrowed).
+    pub fn check_key_borrowed_n<Q>(
+        &self,
+        key: &Q,
+        n: NonZeroU32,
+    ) -> Result<MW::PositiveOutcome, NegativeMultiDecision<MW::NegativeOutcome>>
+    where
+        S: BorrowedKeyedStateStore<K, Q>,
+        Q: ?Sized,
+    {
+        self.gcra()
+            .test_n_all_and_update::<&Q, C::Instant, BorrowedLookup<'_, '_, K, S, Q>, MW>(
+                self.start,
+                &key,
+                n,
+                &self.borrowed(),
+                self.clock.now(),
+            )
+    }
+}
@@ -0,0 +1,312 @@
+use crate::compat::prelude::*;
+
+use crate::compat::collections::HashMap;
+use crate::gcra::Gcra;
+use crate::state::keyed::tenant::Slot;
+use crate::sync::Mutex;
+use crate::{
+    clock::{self, Reference},
+    middleware::{NoOpMiddleware, RateLimitingMiddleware},
+    NegativeMultiDecision, Quota,
+};
+use std::cell::Cell;
+use std::hash::Hash;
+use std::marker::PhantomData;
+use std::num::NonZeroU32;
+
+/// The rate-limiting state of a key, and the burst size that the key was assigned, if any.
+#[derive(Debug, Default)]
+struct KeyState {
+    tat: u64,
+    burst: Option<NonZeroU32>,
+}
+
+/// A keyed rate limiter whose keys all replenish cells at the same rate, but can be assigned
+/// different burst sizes at runtime: e.g. a larger burst for established accounts than for
+/// new ones.
+///
+/// Keys that weren't [assigned](#method.set_key_burst) a burst size get the quota's. The burst
+/// size is stored alongside the key's state, and takes effect with the key's next decision;
+/// negative outcomes report the quota with the key's burst size.
+///
+/// # Performance
+///
+/// The states and burst sizes of all keys are kept in one hash map behind one mutex, like in
+/// a [`HashMapStateStore`](super::HashMapStateStore): Every decision, and every assignment or
+/// lookup of a burst size, takes the same lock, so decisions on different keys serialize when
+/// many threads make them at once. Where all keys can share the quota's burst size, a
+/// [`DashMapStateStore`](super::DashMapStateStore)-backed rate limiter spreads the keys over
+/// independently locked shards instead.
+///
+/// # Example
+/// ```rust
+/// # use nonzero_ext::*;
+/// use governor::{state::keyed::BurstRateLimiter, Quota};
+///
+/// let lim = BurstRateLimiter::new(Quota::per_minute(nonzero!(1u32)));
+/// lim.set_key_burst(&"veteran", nonzero!(3u32));
+/// assert_eq!(Ok(()), lim.check_key_n(&"veteran", nonzero!(3u32)));
+/// assert_eq!(Ok(()), lim.check_key(&"newcomer"));
+/// assert_ne!(Ok(()), lim.check_key(&"newcomer"));
+/// assert_eq!(lim.key_burst(&"veteran"), nonzero!(3u32));
+/// ```
+#[derive(Debug)]
+pub struct BurstRateLimiter<
+    K,
+    C: clock::Clock = clock::DefaultClock,
+    MW: RateLimitingMiddleware<C::Instant> = NoOpMiddleware<<C as clock::Clock>::Instant>,
+> {
+    states: Mutex<HashMap<K, KeyState>>,
+    quota: Quota,
+    gcra: Gcra,
+    clock: C,
+    start: C::Instant,
+    middleware: PhantomData<MW>,
+}
+
+impl<K> BurstRateLimiter<K>
+where
+    K: Hash + Eq + Clone,
+{
+    /// Constructs a rate limiter that allows each key the given quota, until it is assigned a
+    /// different burst size.
+    pub fn new(quota: Quota) -> Self {
+        let clock = clock::DefaultClock::default();
+        BurstRateLimiter::with_clock(quota, &clock)
+    }
+}
+
+impl<K, C> BurstRateLimiter<K, C, NoOpMiddleware<C::Instant>>
+where
+    K: Hash + Eq + Clone,
+    C: clock::Clock,
+{
+    /// Constructs a rate limiter for keys with their own burst sizes with a custom clock.
+    pub fn with_clock(quota: Quota, clock: &C) -> Self {
+        BurstRateLimiter {
+            states: Mutex::new(HashMap::new()),
+            quota,
+            gcra: Gcra::new(quota),
+            clock: clock.clone(),
+            start: clock.now(),
+            middleware: PhantomData,
+        }
+    }
+}
+
+impl<K, C, MW> BurstRateLimiter<K, C, MW>
+where
+    K: Hash + Eq + Clone,
+    C: clock::Clock,
+    MW: RateLimitingMiddleware<C::Instant>,
+{
+    /// Convert the given rate limiter into one that uses a different middleware.
+    pub fn with_middleware<Outer: RateLimitingMiddleware<C::Instant>>(
+        self,
+    ) -> BurstRateLimiter<K, C, Outer> {
+        BurstRateLimiter {
+            states: self.states,
+            quota: self.quota,
+            gcra: self.gcra,
+            clock: self.clock,
+            start: self.start,
+            middleware: PhantomData,
+        }
+    }
+
+    /// Returns the quota that keys are limited to, with the burst size of keys that weren't
+    /// assigned one.
+    pub fn quota(&self) -> Quota {
+        self.quota
+    }
+
+    /// Assigns the given burst size to a key, keeping the quota's rate of replenishing cells.
+    ///
+    /// Lowering a key's burst size below the cells that it has used up makes it wait until
+    /// enough cells are replenished; raising it lets the key use the additional cells right
+    /// away.
+    pub fn set_key_burst(&self, key: &K, burst: NonZeroU32) {
+        self.states.lock().entry(key.clone()).or_default().burst = Some(burst);
+    }
+
+    /// Makes a key use the quota's burst size again, returning the burst size that it was
+    /// assigned, if any.
+    pub fn reset_key_burst(&self, key: &K) -> Option<NonZeroU32> {
+        self.states
+            .lock()
+            .get_mut(key)
+            .and_then(|state| state.burst.take())
+    }
+
+    /// Returns the burst size of a key: the one that it was assigned, or the quota's.
+    pub fn key_burst(&self, key: &K) -> NonZeroU32 {
+        self.states
+            .lock()
+            .get(key)
+            .and_then(|state| state.burst)
+            .unwrap_or_else(|| self.quota.burst_size())
+    }
+
+    /// Returns the GCRA parameters for a key with the given burst size.
+    fn gcra_for(&self, burst: Option<NonZeroU32>) -> Gcra {
+        match burst {
+            Some(burst) => Gcra::new(self.quota.allow_burst(burst)),
+            None => self.gcra,
+        }
+    }
+
+    /// Runs `decide` on a copy of the key's state with the GCRA for its burst size, and stores
+    /// the updated state if it returns a positive decision.
+    fn decide<P, E>(
+        &self,
+        key: &K,
+        decide: impl FnOnce(&Gcra, &Cell<u64>) -> Result<P, E>,
+    ) -> Result<P, E> {
+        let mut states = self.states.lock();
+        let existing = states.get_mut(key);
+        let burst = existing.as_ref().and_then(|state| state.burst);
+        let tat = Cell::new(existing.as_ref().map_or(0, |state| state.tat));
+        let positive = decide(&self.gcra_for(burst), &tat)?;
+        match existing {
+            Some(state) => state.tat = tat.get(),
+            None => {
+                states.insert(
+                    key.clone(),
+                    KeyState {
+                        tat: tat.get(),
+                        burst: None,
+                    },
+                );
+            }
+        }
+        Ok(positive)
+    }
+
+    /// Allow a single cell through the rate limiter for the given key, using the key's burst
+    /// size.
+    ///
+    /// See [`RateLimiter::check_key`](crate::RateLimiter::check_key).
+    pub fn check_key(&self, key: &K) -> Result<MW::PositiveOutcome, MW::NegativeOutcome> {
+        let now = self.clock.now();
+        self.decide(key, |gcra, tat| {
+            gcra.test_and_update::<K, C::Instant, _, MW>(self.start, key, &Slot::new(tat), now)
+        })
+    }
+
+    /// Allow *only all* `n` cells through the rate limiter for the given key, using the key's
+    /// burst size.
+    ///
+    /// See [`RateLimiter::check_key_n`](crate::RateLimiter::check_key_n).
+    pub fn check_key_n(
+        &self,
+        key: &K,
+        n: NonZeroU32,
+    ) -> Result<MW::PositiveOutcome, NegativeMultiDecision<MW::NegativeOutcome>> {
+        let now = self.clock.now();
+        self.decide(key, |gcra, tat| {
+            gcra.test_n_all_and_update::<K, C::Instant, _, MW>(
+                self.start,
+                key,
+                n,
+                &Slot::new(tat),
+                now,
+            )
+        })
+    }
+
+    /// Removes the states of keys that are indistinguishable from fresh ones, unless they were
+    /// assigned a burst size.
+    ///
+    /// See [`RateLimiter::retain_recent`](crate::RateLimiter::retain_recent).
+    pub fn retain_recent(&self) {
+        let now = self.clock.now().duration_since(self.start);
+        let below: u64 = self.gcra.units_from_nanos(now).into();
+        self.states
+            .lock()
+            .retain(|_, state| state.burst.is_some() || state.tat > below);
+    }
+
+    /// Returns the number of keys that the rate limiter holds a state or a burst size for.
+    pub fn len(&self) -> usize {
+        self.states.lock().len()
+    }
+
+    /// Returns `true` if the rate limiter holds no states.
+    pub fn is_empty(&self) -> bool {
+        self.states.lock().is_empty()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::clock::FakeRelativeClock;
+    use nonzero_ext::nonzero;
+    use std::time::Duration;
+
+    #[test]
+    fn burst_limiter_impls() {
+        let lim = BurstRateLimiter::<u32>::new(Quota::per_second(nonzero!(2u32)));
+        assert!(!format!("{:?}", lim).is_empty());
+        assert_eq!(lim.quota(), Quota::per_second(nonzero!(2u32)));
+        assert_eq!(lim.key_burst(&1), nonzero!(2u32));
+        assert!(lim.is_empty());
+    }
+
+    #[test]
+    fn keys_share_the_rate() {
+        let clock = FakeRelativeClock::default();
+        let lim = BurstRateLimiter::with_clock(Quota::per_second(nonzero!(1u32)), &clock);
+        lim.set_key_burst(&"big", nonzero!(4u32));
+        assert_eq!(Ok(()), lim.check_key_n(&"big", nonzero!(4u32)));
+        let denied = lim.check_key(&"big").unwrap_err();
+        assert_eq!(denied.quota().burst_size(), nonzero!(4u32));
+        assert_eq!(
+            Err(NegativeMultiDecision::InsufficientCapacity(1)),
+            lim.check_key_n(&"small", nonzero!(2u32))
+        );
+
+        // Both keys replenish one cell per second:
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(Ok(()), lim.check_key(&"big"));
+        assert_ne!(Ok(()), lim.check_key(&"big"));
+        assert_eq!(Ok(()), lim.check_key(&"small"));
+        assert_ne!(Ok(()), lim.check_key(&"small"));
+    }
+
+    #[test]
+    fn bursts_change_at_runtime() {
+        let clock = FakeRelativeClock::default();
+        let lim = BurstRateLimiter::with_clock(Quota::per_second(nonzero!(1u32)), &clock);
+        assert_eq!(Ok(()), lim.check_key(&1));
+        assert_ne!(Ok(()), lim.check_key(&1));
+        lim.set_key_burst(&1, nonzero!(3u32));
+        assert_eq!(Ok(()), lim.check_key_n(&1, nonzero!(2u32)));
+        assert_ne!(Ok(()), lim.check_key(&1));
+
+        assert_eq!(lim.reset_key_burst(&1), Some(nonzero!(3u32)));
+        assert_eq!(lim.reset_key_burst(&1), None);
+        assert_eq!(lim.key_burst(&1), nonzero!(1u32));
+        // The cells used under the larger burst take a while to replenish:
+        clock.advance(Duration::from_secs(2));
+        assert_ne!(Ok(()), lim.check_key(&1));
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(Ok(()), lim.check_key(&1));
+    }
+
+    #[test]
+    fn keeps_assigned_bursts() {
+        let clock = FakeRelativeClock::default();
+        let lim = BurstRateLimiter::with_clock(Quota::per_second(nonzero!(1u32)), &clock);
+        lim.set_key_burst(&1, nonzero!(2u32));
+        assert_eq!(Ok(()), lim.check_key(&1));
+        assert_eq!(Ok(()), lim.check_key(&2));
+        assert_eq!(lim.len(), 2);
+
+        clock.advance(Duration::from_secs(10));
+        lim.retain_recent();
+        assert_eq!(lim.len(), 1);
+        assert_eq!(lim.key_burst(&1), nonzero!(2u32));
+        assert_eq!(Ok(()), lim.check_key_n(&1, nonzero!(2u32)));
+    }
+}
@@ -0,0 +1,391 @@
+use crate::compat::prelude::*;
+
+use crate::compat::collections::hash_map::RandomState;
+use crate::nanos::Nanos;
+use crate::state::keyed::ShrinkableKeyedStateStore;
+use crate::state::{saturated, StateStore};
+use crate::sync::{AtomicU64, AtomicUsize, Ordering};
+use std::fmt;
+use std::hash::{BuildHasher, Hash};
+use std::marker::PhantomData;
+use std::num::NonZeroUsize;
+use std::ptr;
+use std::time::Duration;
+
+/// The number of slots, starting at a key's home slot, that the key's entry can be in.
+const WINDOW: usize = 16;
+
+/// The number of bits of an entry that hold its state.
+const STATE_BITS: u32 = 40;
+
+const STATE_MASK: u64 = (1 << STATE_BITS) - 1;
+
+/// An approximate keyed state store for very large numbers of keys, which keeps each key's
+/// state in 8 bytes of a fixed-size, open-addressing table.
+///
+/// Instead of the keys themselves, the table holds a 24-bit fingerprint of each key's hash,
+/// next to its state with reduced precision, so it uses 8 bytes per slot no matter how large
+/// the keys are; at a load of 75%, that's under 11 bytes per key (compared to more than 48 for
+/// the hash map-based state stores). A key's entry is in one of the 16 slots that follow the
+/// slot its hash points to.
+///
+/// This makes decisions approximate, within these bounds:
+///
+/// * States are rounded up to the table's *resolution*, so a key may be denied for up to one
+///   resolution longer than the quota says, but never let through earlier. States are kept in
+///   40 bits of the resolution, which covers over 34 years at a resolution of one
+///   millisecond; decisions that would leave a key in a later state are denied. (For quotas
+///   that replenish several cells per period, the resolution counts in fractions of a
+///   nanosecond: divide both the resolution and the [range](#method.range) by the quota's
+///   number of cells per period.) [`RateLimiter::new`](../../struct.RateLimiter.html#method.new)
+///   refuses quotas whose states don't fit into the range from the rate limiter's start.
+/// * Two keys whose entries share a window and a fingerprint share their state. With 16
+///   slots per window, this happens to about one in a million keys.
+/// * When all 16 slots of a new key's window are taken, the new key takes the slot whose state
+///   is the earliest, and the key that had it starts over with a fresh state when it comes
+///   back. Sizing the table for at least a third more slots than keys makes this rare, and
+///   [cleaning up](../../struct.RateLimiter.html#method.retain_recent) regularly frees the
+///   slots of keys whose states are indistinguishable from fresh ones.
+///
+/// The table doesn't grow, and can't tell which keys it evicted.
+///
+/// # Example
+/// ```rust
+/// # use nonzero_ext::*;
+/// # use std::time::Duration;
+/// use governor::{
+///     clock::FakeRelativeClock, middleware::NoOpMiddleware, state::keyed::CompactStateStore,
+///     Quota, RateLimiter,
+/// };
+///
+/// let state = CompactStateStore::new(nonzero!(1usize << 20), Duration::from_millis(1));
+/// let clock = FakeRelativeClock::default();
+/// let lim: RateLimiter<u64, _, _, NoOpMiddleware<_>> =
+///     RateLimiter::new(Quota::per_second(nonzero!(2u32)), state, &clock);
+/// assert!(lim.check_key_n(&42, nonzero!(2u32)).is_ok());
+/// assert!(lim.check_key(&42).is_err());
+/// assert!(lim.check_key(&23).is_ok());
+/// ```
+pub struct CompactStateStore<K, H = RandomState> {
+    slots: Box<[AtomicU64]>,
+    resolution: u64,
+    len: AtomicUsize,
+    hasher: H,
+    key: PhantomData<fn(K)>,
+}
+
+impl<K> CompactStateStore<K> {
+    /// Constructs a table with the given number of slots (of 8 bytes each), which keeps states
+    /// rounded up to the given resolution.
+    pub fn new(slots: NonZeroUsize, resolution: Duration) -> Self {
+        Self::with_hasher(slots, resolution, RandomState::new())
+    }
+}
+
+impl<K, H> CompactStateStore<K, H> {
+    /// Constructs a table with the given number of slots and resolution, which hashes keys
+    /// with `hasher`.
+    ///
+    /// The resolution must not be zero; it is raised to one nanosecond otherwise. It limits
+    /// the [range](#method.range) of states, so rate limiters refuse a table whose resolution
+    /// is too fine for their quota.
+    pub fn with_hasher(slots: NonZeroUsize, resolution: Duration, hasher: H) -> Self {
+        CompactStateStore {
+            slots: (0..slots.get()).map(|_| AtomicU64::new(0)).collect(),
+            resolution: Nanos::from(resolution).as_u64().max(1),
+            len: AtomicUsize::new(0),
+            hasher,
+            key: PhantomData,
+        }
+    }
+
+    /// Returns the resolution that states are kept in.
+    pub fn resolution(&self) -> Duration {
+        Duration::from_nanos(self.resolution)
+    }
+
+    /// Returns the latest state that the table can keep, counted from the rate limiter's start.
+    ///
+    /// Decisions that would leave a key in a later state are denied.
+    pub fn range(&self) -> Duration {
+        Duration::from_nanos(STATE_MASK.saturating_mul(self.resolution))
+    }
+
+    /// Returns the entry for the fingerprint and state, or `None` if the state is beyond the
+    /// table's range.
+    fn encode(&self, fingerprint: u64, tat: Nanos) -> Option<u64> {
+        let state = tat.as_u64().div_ceil(self.resolution);
+        (state <= STATE_MASK).then_some(fingerprint << STATE_BITS | state)
+    }
+
+    fn decode(&self, entry: u64) -> Option<Nanos> {
+        match entry & STATE_MASK {
+            0 => None,
+            state => Some(Nanos::from(state.saturating_mul(self.resolution))),
+        }
+    }
+}
+
+impl<K: Hash, H: BuildHasher> CompactStateStore<K, H> {
+    /// Returns the index of the key's home slot and the key's (non-zero) fingerprint.
+    fn locate(&self, key: &K) -> (usize, u64) {
+        let hash = self.hasher.hash_one(key);
+        let home = ((u128::from(hash) * self.slots.len() as u128) >> 64) as usize;
+        let fingerprint = (hash & ((1 << (64 - STATE_BITS)) - 1)).max(1);
+        (home, fingerprint)
+    }
+}
+
+impl<K, H> StateStore for CompactStateStore<K, H>
+where
+    K: Hash,
+    H: BuildHasher,
+{
+    type Key = K;
+
+    fn measure_and_replace<T, F, E>(&self, key: &Self::Key, f: F) -> Result<T, E>
+    where
+        F: Fn(Option<Nanos>) -> Result<(T, Nanos), E>,
+    {
+        let (home, fingerprint) = self.locate(key);
+        let window = WINDOW.min(self.slots.len());
+        // A fresh entry for the key, which claims a slot before the key's first decision:
+        let fresh = fingerprint << STATE_BITS;
+        let mut claimed: Option<&AtomicU64> = None;
+        'lookup: loop {
+            // The slot to make a new entry in, and the entry that it holds now:
+            let mut vacancy: Option<(&AtomicU64, u64)> = None;
+            for i in 0..window {
+                let slot = &self.slots[(home + i) % self.slots.len()];
+                let mut entry = slot.load(Ordering::Acquire);
+                if entry >> STATE_BITS == fingerprint {
+                    // Concurrent first decisions may have claimed several slots; they all
+                    // decide on the first one, and give up the others:
+                    if let Some(claimed) = claimed.take() {
+                        if !ptr::eq(claimed, slot)
+                            && claimed
+                                .compare_exchange(fresh, 0, Ordering::Relaxed, Ordering::Relaxed)
+                                .is_ok()
+                        {
+                            self.len.fetch_sub(1, Ordering::Relaxed);
+                        }
+                    }
+                    loop {
+                        let (result, tat) = f(self.decode(entry))?;
+                        let replacement = match self.encode(fingerprint, tat) {
+                            Some(replacement) => replacement,
+                            None => return saturated(&f, tat),
+                        };
+                        match slot.compare_exchange_weak(
+                            entry,
+                            replacement,
+                            Ordering::AcqRel,
+                            Ordering::Acquire,
+                        ) {
+                            Ok(_) => return Ok(result),
+                            Err(current) if current >> STATE_BITS == fingerprint => entry = current,
+                            // The entry was evicted (or cleaned up) in the meantime:
+                            Err(_) => continue 'lookup,
+                        }
+                    }
+                }
+                vacancy = match vacancy {
+                    Some((_, 0)) => vacancy,
+                    Some((_, earliest)) if earliest & STATE_MASK <= entry & STATE_MASK => vacancy,
+                    _ => Some((slot, entry)),
+                };
+            }
+            // Keys whose first decision is negative (or can't be kept) don't take a slot:
+            let (result, tat) = f(None)?;
+            if self.encode(fingerprint, tat).is_none() {
+                return saturated(&f, tat);
+            }
+            let (slot, entry) = match vacancy {
+                Some(vacancy) => vacancy,
+                None => return Ok(result),
+            };
+            // The decision itself is made on the claimed entry (or on an entry that a
+            // concurrent first decision claimed before), once the lookup finds it:
+            if slot
+                .compare_exchange(entry, fresh, Ordering::AcqRel, Ordering::Acquire)
+                .is_ok()
+            {
+                if entry == 0 {
+                    self.len.fetch_add(1, Ordering::Relaxed);
+                }
+                claimed = Some(slot);
+            }
+        }
+    }
+
+    fn reach(&self) -> Option<Nanos> {
+        Some(self.range().into())
+    }
+}
+
+impl<K, H> ShrinkableKeyedStateStore<K> for CompactStateStore<K, H>
+where
+    K: Hash + Eq + Clone,
+    H: BuildHasher,
+{
+    fn retain_recent(&self, drop_below: Nanos) {
+        for slot in self.slots.iter() {
+            let entry = slot.load(Ordering::Relaxed);
+            if entry != 0
+                && self.decode(entry).unwrap_or_else(|| Nanos::from(0)) <= drop_below
+                && slot
+                    .compare_exchange(entry, 0, Ordering::Relaxed, Ordering::Relaxed)
+                    .is_ok()
+            {
+                self.len.fetch_sub(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.len.load(Ordering::Relaxed)
+    }
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn capacity(&self) -> usize {
+        self.slots.len()
+    }
+
+    fn memory_usage(&self) -> usize {
+        self.slots.len() * std::mem::size_of::<AtomicU64>()
+    }
+}
+
+impl<K, H> fmt::Debug for CompactStateStore<K, H> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CompactStateStore")
+            .field("slots", &self.slots.len())
+            .field("len", &self.len.load(Ordering::Relaxed))
+            .field("resolution", &self.resolution())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::clock::FakeRelativeClock;
+    use crate::middleware::NoOpMiddleware;
+    use crate::{Quota, RateLimiter};
+    use nonzero_ext::nonzero;
+    use std::cell::Cell;
+
+    #[test]
+    fn rounds_states_up() {
+        let clock = FakeRelativeClock::default();
+        let state = CompactStateStore::new(nonzero!(64usize), Duration::from_millis(10));
+        let lim: RateLimiter<u32, _, _, NoOpMiddleware<_>> =
+            RateLimiter::new(Quota::per_second(nonzero!(1000u32)), state, &clock);
+        assert!(lim.check_key_n(&1, nonzero!(1000u32)).is_ok());
+        // A cell replenishes after a millisecond, but the state was rounded up to 10:
+        clock.advance(Duration::from_millis(9));
+        assert!(lim.check_key(&1).is_err());
+        clock.advance(Duration::from_millis(1));
+        assert!(lim.check_key(&1).is_ok());
+        assert_eq!(lim.len(), 1);
+    }
+
+    #[test]
+    fn evicts_the_earliest_state() {
+        let clock = FakeRelativeClock::default();
+        // A single window, so all keys compete for its slots:
+        let state = CompactStateStore::new(nonzero!(16usize), Duration::from_nanos(1));
+        let lim: RateLimiter<u32, _, _, NoOpMiddleware<_>> =
+            RateLimiter::new(Quota::per_second(nonzero!(1u32)), state, &clock);
+        for key in 0..16 {
+            assert!(lim.check_key(&key).is_ok());
+            clock.advance(Duration::from_millis(1));
+        }
+        assert_eq!(lim.len(), 16);
+        assert!(lim.check_key(&100).is_ok());
+        assert!(lim.check_key(&100).is_err());
+        // Key 0 had the earliest state, so it starts over:
+        assert!(lim.check_key(&0).is_ok());
+        assert!(lim.check_key(&15).is_err());
+        assert_eq!(lim.len(), 16);
+
+        clock.advance(Duration::from_secs(2));
+        lim.retain_recent();
+        assert!(lim.is_empty());
+    }
+
+    #[test]
+    #[should_panic(expected = "can't keep states far enough ahead")]
+    fn refuses_quotas_beyond_its_range() {
+        let clock = FakeRelativeClock::default();
+        // Around 18 minutes, which a single cell of the quota takes longer than to replenish:
+        let state = CompactStateStore::new(nonzero!(1024usize), Duration::from_nanos(1));
+        let _: RateLimiter<u32, _, _, NoOpMiddleware<_>> =
+            RateLimiter::new(Quota::per_hour(nonzero!(1u32)), state, &clock);
+    }
+
+    #[test]
+    fn denies_states_beyond_its_range() {
+        let clock = FakeRelativeClock::default();
+        let state = CompactStateStore::new(nonzero!(1024usize), Duration::from_nanos(1));
+        let range = state.range();
+        let lim: RateLimiter<u32, _, _, NoOpMiddleware<_>> =
+            RateLimiter::new(Quota::per_second(nonzero!(1u32)), state, &clock);
+        assert!(lim.check_key(&1).is_ok());
+        assert!(lim.check_key(&1).is_err());
+
+        clock.advance(range - Duration::from_millis(500));
+        for _ in 0..100 {
+            assert!(lim.check_key(&1).is_err());
+            assert!(lim.check_key(&2).is_err());
+        }
+        assert_eq!(lim.len(), 1);
+    }
+
+    /// Hashes `u32` keys to themselves, so that tests can place keys in the table.
+    #[derive(Default)]
+    struct Identity(u64);
+
+    impl std::hash::Hasher for Identity {
+        fn finish(&self) -> u64 {
+            self.0
+        }
+
+        fn write(&mut self, _bytes: &[u8]) {
+            unimplemented!()
+        }
+
+        fn write_u32(&mut self, n: u32) {
+            self.0 = n.into();
+        }
+    }
+
+    #[test]
+    fn concurrent_first_decisions_share_an_entry() {
+        let state = CompactStateStore::<u32, _>::with_hasher(
+            nonzero!(16usize),
+            Duration::from_nanos(1),
+            std::hash::BuildHasherDefault::<Identity>::default(),
+        );
+        // Keys 1 and 2 share their home slot, which key 2 takes:
+        let stored = state.measure_and_replace(&2, |_| Ok::<_, ()>(((), Nanos::from(10))));
+        assert_eq!(stored, Ok(()));
+        let interrupted = Cell::new(false);
+        let seen = state.measure_and_replace(&1, |tat| {
+            if !interrupted.replace(true) {
+                // While this decision is made, key 2 is cleaned up, and another first decision
+                // on key 1 takes its slot:
+                state.retain_recent(Nanos::from(10));
+                let stored = state.measure_and_replace(&1, |_| Ok::<_, ()>(((), Nanos::from(20))));
+                assert_eq!(stored, Ok(()));
+            }
+            Ok::<_, ()>((tat, Nanos::from(30)))
+        });
+        assert_eq!(seen, Ok(Some(Nanos::from(20))));
+        assert_eq!(state.len(), 1);
+        assert_eq!(state.peek(&1), Some(Nanos::from(30)));
+    }
+}
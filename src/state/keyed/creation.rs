@@ -0,0 +1,270 @@
+use crate::compat::prelude::*;
+
+use crate::nanos::Nanos;
+use crate::state::keyed::{DefaultKeyedStateStore, KeyedStateStore, ShrinkableKeyedStateStore};
+use crate::state::{saturated, InMemoryState, NotKeyed, StateStore};
+use crate::{clock, middleware::NoOpMiddleware, Quota, RateLimiter};
+use std::cell::Cell;
+use std::fmt;
+use std::hash::Hash;
+
+/// The result of a decision that was refused a new key, escaping the wrapped state store
+/// without storing a state.
+enum Refused<T, E> {
+    Negative(E),
+    Outcome(T),
+}
+
+/// A keyed state store that limits the rate at which new keys are created, with a quota of its
+/// own.
+///
+/// Eviction bounds the memory that a keyed rate limiter uses for keys that are no longer in
+/// use, but a client that sprays decisions over ever-new keys still gets every one of them
+/// created (and each one a full burst). Wrapped in this state store, a decision on a key that
+/// has no state only creates it if the creation quota allows one more key; otherwise, the key
+/// is treated as if it had already used up (up to) its whole burst capacity, and the decision is
+/// made without storing any state. Decisions on existing keys are unaffected.
+///
+/// Refused decisions are negative, and tell clients to come back once some of that burst
+/// capacity would have replenished; by then, the creation quota may let the key in.
+///
+/// # Example
+/// ```rust
+/// # use nonzero_ext::*;
+/// use governor::{Quota, RateLimiter};
+///
+/// let lim = RateLimiter::keyed_with_creation_quota(
+///     Quota::per_second(nonzero!(5u32)),
+///     Quota::per_hour(nonzero!(2u32)),
+/// );
+/// assert!(lim.check_key(&"alice").is_ok());
+/// assert!(lim.check_key(&"bob").is_ok());
+/// assert!(lim.check_key(&"mallory").is_err());
+/// // Existing keys aren't affected:
+/// assert!(lim.check_key(&"alice").is_ok());
+/// ```
+pub struct CreationLimitedStateStore<S, C: clock::Clock = clock::DefaultClock> {
+    store: S,
+    creations: RateLimiter<NotKeyed, InMemoryState, C, NoOpMiddleware<C::Instant>>,
+}
+
+impl<S, C: clock::Clock> CreationLimitedStateStore<S, C> {
+    /// Wraps a state store, creating new keys at no more than the rate that `creation_quota`
+    /// allows, as measured by `clock`.
+    pub fn new(store: S, creation_quota: Quota, clock: &C) -> Self {
+        CreationLimitedStateStore {
+            store,
+            creations: RateLimiter::direct_with_clock(creation_quota, clock),
+        }
+    }
+
+    /// Returns the wrapped state store.
+    pub fn store(&self) -> &S {
+        &self.store
+    }
+
+    /// Returns the rate limiter that new keys are created through.
+    pub fn creation_limiter(
+        &self,
+    ) -> &RateLimiter<NotKeyed, InMemoryState, C, NoOpMiddleware<C::Instant>> {
+        &self.creations
+    }
+
+    /// Checks whether a new key may be created, once per update whose closure may run several
+    /// times.
+    fn admits(&self, admitted: &Cell<Option<bool>>) -> bool {
+        match admitted.get() {
+            Some(admitted) => admitted,
+            None => {
+                let check = self.creations.check().is_ok();
+                admitted.set(Some(check));
+                check
+            }
+        }
+    }
+}
+
+impl<S, C> StateStore for CreationLimitedStateStore<S, C>
+where
+    S: StateStore,
+    C: clock::Clock,
+{
+    type Key = S::Key;
+
+    fn measure_and_replace<T, F, E>(&self, key: &Self::Key, f: F) -> Result<T, E>
+    where
+        F: Fn(Option<Nanos>) -> Result<(T, Nanos), E>,
+    {
+        // The closure may run several times, so a key's creation is only checked once:
+        let admitted = Cell::new(None);
+        let result = self.store.measure_and_replace(key, |tat| {
+            if tat.is_some() {
+                return f(tat).map_err(Refused::Negative);
+            }
+            let created = f(None).map_err(Refused::Negative)?;
+            if self.admits(&admitted) {
+                Ok(created)
+            } else {
+                Err(match saturated(&f, created.1) {
+                    Err(negative) => Refused::Negative(negative),
+                    Ok(outcome) => Refused::Outcome(outcome),
+                })
+            }
+        });
+        match result {
+            Ok(outcome) => Ok(outcome),
+            Err(Refused::Negative(negative)) => Err(negative),
+            Err(Refused::Outcome(outcome)) => Ok(outcome),
+        }
+    }
+
+    fn reach(&self) -> Option<Nanos> {
+        self.store.reach()
+    }
+
+    /// Refunds and seeded states aren't decisions, but seeding a new key's state creates it.
+    fn adjust<F>(&self, key: &Self::Key, f: F)
+    where
+        F: Fn(Option<Nanos>) -> Option<Nanos>,
+    {
+        let admitted = Cell::new(None);
+        self.store.adjust(key, |tat| {
+            if tat.is_none() && !self.admits(&admitted) {
+                return None;
+            }
+            f(tat)
+        })
+    }
+}
+
+impl<K, S, C> ShrinkableKeyedStateStore<K> for CreationLimitedStateStore<S, C>
+where
+    K: Hash + Eq + Clone,
+    S: ShrinkableKeyedStateStore<K>,
+    C: clock::Clock,
+{
+    fn retain_recent(&self, drop_below: Nanos) {
+        self.store.retain_recent(drop_below)
+    }
+
+    fn retain_recent_evicted(&self, drop_below: Nanos) -> Vec<K> {
+        self.store.retain_recent_evicted(drop_below)
+    }
+
+    fn shrink_to_fit(&self) {
+        self.store.shrink_to_fit()
+    }
+
+    fn len(&self) -> usize {
+        self.store.len()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.store.is_empty()
+    }
+
+    fn capacity(&self) -> usize {
+        self.store.capacity()
+    }
+
+    fn memory_usage(&self) -> usize {
+        self.store.memory_usage()
+    }
+}
+
+impl<S: fmt::Debug, C: clock::Clock> fmt::Debug for CreationLimitedStateStore<S, C> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CreationLimitedStateStore")
+            .field("store", &self.store)
+            .field("creation_quota", &self.creations.gcra.quota())
+            .finish()
+    }
+}
+
+/// # Keyed rate limiters - Limiting the creation of keys
+impl<K>
+    RateLimiter<
+        K,
+        CreationLimitedStateStore<DefaultKeyedStateStore<K>, clock::DefaultClock>,
+        clock::DefaultClock,
+    >
+where
+    K: Clone + Hash + Eq,
+    DefaultKeyedStateStore<K>: KeyedStateStore<K>,
+{
+    /// Constructs a new keyed rate limiter backed by the [`DefaultKeyedStateStore`], which
+    /// creates new keys at no more than the rate that `creation_quota` allows.
+    ///
+    /// See [`CreationLimitedStateStore`].
+    pub fn keyed_with_creation_quota(quota: Quota, creation_quota: Quota) -> Self {
+        let clock = clock::DefaultClock::default();
+        RateLimiter::keyed_with_creation_quota_and_clock(quota, creation_quota, &clock)
+    }
+}
+
+impl<K, C>
+    RateLimiter<
+        K,
+        CreationLimitedStateStore<DefaultKeyedStateStore<K>, C>,
+        C,
+        NoOpMiddleware<C::Instant>,
+    >
+where
+    K: Clone + Hash + Eq,
+    C: clock::Clock,
+    DefaultKeyedStateStore<K>: KeyedStateStore<K>,
+{
+    /// Constructs a new keyed rate limiter with a custom clock, backed by the
+    /// [`DefaultKeyedStateStore`], which creates new keys at no more than the rate that
+    /// `creation_quota` allows.
+    pub fn keyed_with_creation_quota_and_clock(
+        quota: Quota,
+        creation_quota: Quota,
+        clock: &C,
+    ) -> Self {
+        let state = CreationLimitedStateStore::new(
+            DefaultKeyedStateStore::default(),
+            creation_quota,
+            clock,
+        );
+        RateLimiter::new(quota, state, clock)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::clock::{Clock, FakeRelativeClock};
+    use crate::state::keyed::HashMapStateStore;
+    use nonzero_ext::nonzero;
+    use std::time::Duration;
+
+    #[test]
+    fn limits_new_keys() {
+        let clock = FakeRelativeClock::default();
+        let state = CreationLimitedStateStore::new(
+            HashMapStateStore::default(),
+            Quota::per_second(nonzero!(1u32)),
+            &clock,
+        );
+        let lim: RateLimiter<u32, _, _, NoOpMiddleware<_>> =
+            RateLimiter::new(Quota::per_second(nonzero!(10u32)), state, &clock);
+        assert!(lim.check_key_n(&1, nonzero!(3u32)).is_ok());
+        let refused = lim.check_key(&2).unwrap_err();
+        assert!(refused.wait_time_from(clock.now()) <= Duration::from_secs(1));
+        assert!(lim.check_key_n(&2, nonzero!(10u32)).is_err());
+        assert_eq!(lim.len(), 1);
+        let mut conforming = [0u64];
+        assert_eq!(
+            lim.check_key_arrivals(&3, &[clock.now()], &mut conforming),
+            0
+        );
+        assert_eq!(lim.len(), 1);
+
+        assert!(lim.check_key_n(&1, nonzero!(7u32)).is_ok());
+        clock.advance(Duration::from_secs(1));
+        assert!(lim.check_key(&2).is_ok());
+        assert!(lim.check_key(&3).is_err());
+        assert_eq!(lim.len(), 2);
+    }
+}
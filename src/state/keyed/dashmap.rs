@@ -1,12 +1,17 @@
 #![cfg(all(feature = "std", feature = "dashmap"))]
 
-use std::prelude::v1::*;
+use crate::compat::prelude::*;
 
 use crate::nanos::Nanos;
 use crate::state::{InMemoryState, StateStore};
 use crate::{clock, Quota, RateLimiter};
-use crate::{middleware::NoOpMiddleware, state::keyed::ShrinkableKeyedStateStore};
+use crate::{
+    middleware::NoOpMiddleware,
+    state::keyed::{BorrowedKeyedStateStore, ShrinkableKeyedStateStore},
+};
+use dashmap::mapref::entry::Entry;
 use dashmap::DashMap;
+use std::borrow::Borrow;
 use std::hash::Hash;
 
 /// A concurrent, thread-safe and fairly performant hashmap based on [`DashMap`].
@@ -23,12 +28,49 @@ impl<K: Hash + Eq + Clone> StateStore for DashMapStateStore<K> {
             // fast path: measure existing entry
             return v.measure_and_replace_one(f);
         }
-        // make an entry and measure that:
-        let entry = self.entry(key.clone()).or_default();
-        (*entry).measure_and_replace_one(f)
+        // decide on a fresh state, and make an entry if that stores one:
+        match self.entry(key.clone()) {
+            Entry::Occupied(entry) => entry.get().measure_and_replace_one(f),
+            Entry::Vacant(entry) => {
+                let (result, state) = InMemoryState::create(f)?;
+                entry.insert(state);
+                Ok(result)
+            }
+        }
+    }
+}
+
+impl<K, Q> BorrowedKeyedStateStore<K, Q> for DashMapStateStore<K>
+where
+    K: Hash + Eq + Clone + Borrow<Q>,
+    Q: Hash + Eq + ToOwned<Owned = K> + ?Sized,
+{
+    fn measure_and_replace_borrowed<T, F, E>(&self, key: &Q, f: F) -> Result<T, E>
+    where
+        F: Fn(Option<Nanos>) -> Result<(T, Nanos), E>,
+    {
+        if let Some(v) = self.get(key) {
+            return v.measure_and_replace_one(f);
+        }
+        match self.entry(key.to_owned()) {
+            Entry::Occupied(entry) => entry.get().measure_and_replace_one(f),
+            Entry::Vacant(entry) => {
+                let (result, state) = InMemoryState::create(f)?;
+                entry.insert(state);
+                Ok(result)
+            }
+        }
     }
 }
 
+/// A keyed rate limiter backed by a [`DashMapStateStore`], as constructed by
+/// [`RateLimiter::dashmap_with_clock`].
+pub type DashMapKeyedLimiter<
+    K,
+    C = clock::DefaultClock,
+    MW = NoOpMiddleware<<C as clock::Clock>::Instant>,
+> = RateLimiter<K, DashMapStateStore<K>, C, MW>;
+
 /// # Keyed rate limiters - [`DashMap`]-backed
 impl<K, C> RateLimiter<K, DashMapStateStore<K>, C, NoOpMiddleware<C::Instant>>
 where
@@ -48,6 +90,18 @@ impl<K: Hash + Eq + Clone> ShrinkableKeyedStateStore<K> for DashMapStateStore<K>
         self.retain(|_, v| !v.is_older_than(drop_below));
     }
 
+    fn retain_recent_evicted(&self, drop_below: Nanos) -> Vec<K> {
+        let mut evicted = Vec::new();
+        self.retain(|k, v| {
+            let retain = !v.is_older_than(drop_below);
+            if !retain {
+                evicted.push(k.clone());
+            }
+            retain
+        });
+        evicted
+    }
+
     fn shrink_to_fit(&self) {
         self.shrink_to_fit();
     }
@@ -59,4 +113,8 @@ impl<K: Hash + Eq + Clone> ShrinkableKeyedStateStore<K> for DashMapStateStore<K>
     fn is_empty(&self) -> bool {
         self.is_empty()
     }
+
+    fn capacity(&self) -> usize {
+        self.capacity()
+    }
 }
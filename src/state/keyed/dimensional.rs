@@ -0,0 +1,240 @@
+use crate::compat::prelude::*;
+
+use super::tenant::Slot;
+use crate::compat::collections::HashMap;
+use crate::gcra::{Gcra, NotUntil};
+use crate::sync::Mutex;
+use crate::{
+    clock::{self, Reference},
+    middleware::NoOpMiddleware,
+    NegativeMultiDecision, Quota,
+};
+use std::cell::Cell;
+use std::fmt;
+use std::hash::Hash;
+use std::num::NonZeroU32;
+
+/// A keyed rate limiter that enforces quotas over several dimensions at once, e.g. requests
+/// per second *and* bytes per second.
+///
+/// Each check debits an amount of cells from each of the `D` dimensions of a key, all of them
+/// or none: If any dimension's quota denies its amount, no dimension's state is updated. The
+/// states of all dimensions of a key share one entry, and each check is made under one lock.
+/// Amounts of 0 leave their dimension alone.
+///
+/// # Performance
+///
+/// The lock is a single mutex around the hash map of all keys, so checks on different keys
+/// contend on it just like checks on the same key. A check holds it for the `D` decisions
+/// only; where the dimensions don't need to be debited all-or-nothing, a keyed rate limiter
+/// per dimension (e.g. on a [`DashMapStateStore`](super::DashMapStateStore)) avoids the
+/// global lock.
+///
+/// # Example
+/// ```rust
+/// # use nonzero_ext::*;
+/// use governor::{state::keyed::DimensionalRateLimiter, Quota};
+///
+/// // 10 requests and 1 KiB per second:
+/// let lim = DimensionalRateLimiter::new([
+///     Quota::per_second(nonzero!(10u32)),
+///     Quota::bytes_per_second(nonzero!(1024u32)),
+/// ]);
+/// assert_eq!(Ok(()), lim.check_key(&"acme", [1, 800]));
+/// let denied = lim.check_key(&"acme", [1, 800]).unwrap_err();
+/// assert_eq!(denied.dimension(), 1);
+/// // The denied request didn't count against the requests per second:
+/// assert_eq!(Ok(()), lim.check_key(&"acme", [9, 200]));
+/// ```
+pub struct DimensionalRateLimiter<K, const D: usize, C: clock::Clock = clock::DefaultClock> {
+    states: Mutex<HashMap<K, [u64; D]>>,
+    gcras: [Gcra; D],
+    clock: C,
+    start: C::Instant,
+}
+
+/// The negative outcome of a [`DimensionalRateLimiter`]'s check: the first dimension whose
+/// quota denied its amount of cells, and its decision.
+#[derive(Debug, PartialEq)]
+pub struct DimensionDenied<P: clock::Reference> {
+    dimension: usize,
+    decision: NegativeMultiDecision<NotUntil<P>>,
+}
+
+impl<P: clock::Reference> DimensionDenied<P> {
+    /// Returns the index of the dimension that denied the cells.
+    pub fn dimension(&self) -> usize {
+        self.dimension
+    }
+
+    /// Returns the decision of the dimension that denied the cells.
+    pub fn decision(&self) -> &NegativeMultiDecision<NotUntil<P>> {
+        &self.decision
+    }
+
+    /// Returns the decision of the dimension that denied the cells, dropping the dimension.
+    pub fn into_decision(self) -> NegativeMultiDecision<NotUntil<P>> {
+        self.decision
+    }
+}
+
+impl<P: clock::Reference> fmt::Display for DimensionDenied<P> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.decision {
+            NegativeMultiDecision::BatchNonConforming(_, not_until) => {
+                write!(f, "dimension {} is {}", self.dimension, not_until)
+            }
+            NegativeMultiDecision::InsufficientCapacity(capacity) => write!(
+                f,
+                "dimension {} has a capacity of only {} cells",
+                self.dimension, capacity
+            ),
+        }
+    }
+}
+
+impl<K, const D: usize> DimensionalRateLimiter<K, D>
+where
+    K: Hash + Eq + Clone,
+{
+    /// Constructs a rate limiter that allows each key one quota per dimension.
+    pub fn new(quotas: [Quota; D]) -> Self {
+        let clock = clock::DefaultClock::default();
+        DimensionalRateLimiter::with_clock(quotas, &clock)
+    }
+}
+
+impl<K, const D: usize, C> DimensionalRateLimiter<K, D, C>
+where
+    K: Hash + Eq + Clone,
+    C: clock::Clock,
+{
+    /// Constructs a rate limiter over several dimensions with a custom clock.
+    pub fn with_clock(quotas: [Quota; D], clock: &C) -> Self {
+        DimensionalRateLimiter {
+            states: Mutex::new(HashMap::new()),
+            gcras: quotas.map(Gcra::new),
+            clock: clock.clone(),
+            start: clock.now(),
+        }
+    }
+
+    /// Returns the quotas of the dimensions.
+    pub fn quotas(&self) -> [Quota; D] {
+        self.gcras.map(|gcra| gcra.quota())
+    }
+
+    /// Allows the given amount of cells per dimension through the rate limiter for the given
+    /// key, or in none of the dimensions.
+    ///
+    /// If a dimension's quota denies its amount, `check_key` returns that dimension's
+    /// decision, as [`RateLimiter::check_key_n`](crate::RateLimiter::check_key_n) would.
+    pub fn check_key(&self, key: &K, amounts: [u32; D]) -> Result<(), DimensionDenied<C::Instant>> {
+        let now = self.clock.now();
+        let mut states = self.states.lock();
+        let existing = states.get_mut(key);
+        let tats = existing
+            .as_deref()
+            .map_or([0; D], |tats| *tats)
+            .map(Cell::new);
+        for (dimension, ((gcra, tat), amount)) in
+            self.gcras.iter().zip(&tats).zip(amounts).enumerate()
+        {
+            let n = match NonZeroU32::new(amount) {
+                Some(n) => n,
+                None => continue,
+            };
+            gcra.test_n_all_and_update::<K, C::Instant, _, NoOpMiddleware<C::Instant>>(
+                self.start,
+                key,
+                n,
+                &Slot::new(tat),
+                now,
+            )
+            .map_err(|decision| DimensionDenied {
+                dimension,
+                decision,
+            })?;
+        }
+        let tats = tats.map(Cell::into_inner);
+        match existing {
+            Some(state) => *state = tats,
+            None => {
+                states.insert(key.clone(), tats);
+            }
+        }
+        Ok(())
+    }
+
+    /// Removes the states of keys whose dimensions are all indistinguishable from fresh ones.
+    ///
+    /// See [`RateLimiter::retain_recent`](crate::RateLimiter::retain_recent).
+    pub fn retain_recent(&self) {
+        let now = self.clock.now().duration_since(self.start);
+        let below = self.gcras.map(|gcra| u64::from(gcra.units_from_nanos(now)));
+        self.states
+            .lock()
+            .retain(|_, tats| tats.iter().zip(&below).any(|(tat, below)| tat > below));
+    }
+
+    /// Returns the number of keys that the rate limiter holds a state for.
+    pub fn len(&self) -> usize {
+        self.states.lock().len()
+    }
+
+    /// Returns `true` if the rate limiter holds no states.
+    pub fn is_empty(&self) -> bool {
+        self.states.lock().is_empty()
+    }
+}
+
+impl<K, const D: usize, C> fmt::Debug for DimensionalRateLimiter<K, D, C>
+where
+    K: Hash + Eq + Clone,
+    C: clock::Clock,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DimensionalRateLimiter")
+            .field("quotas", &self.quotas())
+            .field("len", &self.len())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::clock::FakeRelativeClock;
+    use nonzero_ext::nonzero;
+    use std::time::Duration;
+
+    #[test]
+    fn debits_all_dimensions_or_none() {
+        let clock = FakeRelativeClock::default();
+        let requests = Quota::per_second(nonzero!(2u32));
+        let bytes = Quota::per_second(nonzero!(100u32));
+        let lim = DimensionalRateLimiter::with_clock([requests, bytes], &clock);
+        assert_eq!(lim.quotas(), [requests, bytes]);
+        assert_eq!(Ok(()), lim.check_key(&1, [1, 60]));
+        let denied = lim.check_key(&1, [1, 60]).unwrap_err();
+        assert_eq!(denied.dimension(), 1);
+        assert!(matches!(
+            denied.decision(),
+            NegativeMultiDecision::BatchNonConforming(60, _)
+        ));
+        assert!(!format!("{}", denied).is_empty());
+        assert_eq!(Ok(()), lim.check_key(&1, [1, 0]));
+        let denied = lim.check_key(&1, [1, 0]).unwrap_err();
+        assert_eq!(denied.dimension(), 0);
+        assert_eq!(
+            lim.check_key(&2, [0, 101]).unwrap_err().into_decision(),
+            NegativeMultiDecision::InsufficientCapacity(100)
+        );
+        assert_eq!(lim.len(), 1);
+
+        clock.advance(Duration::from_secs(2));
+        lim.retain_recent();
+        assert!(lim.is_empty());
+        assert!(!format!("{:?}", lim).is_empty());
+    }
+}
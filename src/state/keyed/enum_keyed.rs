@@ -0,0 +1,202 @@
+use crate::compat::prelude::*;
+
+use crate::nanos::Nanos;
+use crate::state::keyed::ShrinkableKeyedStateStore;
+use crate::state::{InMemoryState, StateStore};
+use crate::{clock, middleware::NoOpMiddleware, Quota, RateLimiter};
+use std::fmt;
+use std::hash::Hash;
+use std::marker::PhantomData;
+use std::mem;
+
+/// Key types with a small, fixed set of values that can be used as array indexes.
+///
+/// This is meant to be implemented by fieldless enums, e.g. a request class or an API endpoint,
+/// so that they can be used as keys in an [`EnumStateStore`].
+///
+/// # Example
+/// ```rust
+/// use governor::state::keyed::EnumArray;
+///
+/// #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+/// enum RequestClass {
+///     Read,
+///     Write,
+///     Admin,
+/// }
+///
+/// impl EnumArray for RequestClass {
+///     const LENGTH: usize = 3;
+///
+///     fn index(&self) -> usize {
+///         *self as usize
+///     }
+/// }
+/// ```
+pub trait EnumArray: Hash + Eq + Clone {
+    /// The number of distinct values of this type.
+    const LENGTH: usize;
+
+    /// Returns the position of this value among all the values of this type. This must be less
+    /// than [`LENGTH`](#associatedconstant.LENGTH), and distinct for each distinct value.
+    fn index(&self) -> usize;
+}
+
+/// A keyed state store for keys with a small, fixed set of values.
+///
+/// Instead of looking keys up in a hash map, the `EnumStateStore` keeps one rate-limiting state
+/// per key in a fixed-size array that gets allocated when the state store is constructed. Checking
+/// a key is then as cheap as checking a direct rate limiter.
+///
+/// A key whose [`index`](EnumArray::index) is not less than [`EnumArray::LENGTH`] has no state:
+/// Decisions on it are made against a fresh state each time, and don't store the next one.
+pub struct EnumStateStore<K: EnumArray> {
+    states: Box<[InMemoryState]>,
+    keys: PhantomData<fn(K)>,
+}
+
+impl<K: EnumArray> Default for EnumStateStore<K> {
+    fn default() -> Self {
+        EnumStateStore {
+            states: (0..K::LENGTH).map(|_| InMemoryState::default()).collect(),
+            keys: PhantomData,
+        }
+    }
+}
+
+impl<K: EnumArray> fmt::Debug for EnumStateStore<K> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("EnumStateStore")
+            .field("states", &self.states)
+            .finish()
+    }
+}
+
+impl<K: EnumArray> StateStore for EnumStateStore<K> {
+    type Key = K;
+
+    #[inline]
+    fn measure_and_replace<T, F, E>(&self, key: &Self::Key, f: F) -> Result<T, E>
+    where
+        F: Fn(Option<Nanos>) -> Result<(T, Nanos), E>,
+    {
+        match self.states.get(key.index()) {
+            Some(state) => state.measure_and_replace_one(f),
+            None => f(None).map(|(outcome, _)| outcome),
+        }
+    }
+}
+
+/// The states of an `EnumStateStore` can't be removed, so "dropping" a key resets its state to a
+/// fresh one, and only keys with non-fresh states are counted towards its length.
+impl<K: EnumArray> ShrinkableKeyedStateStore<K> for EnumStateStore<K> {
+    fn retain_recent(&self, drop_below: Nanos) {
+        for state in self.states.iter() {
+            state.reset_if_older_than(drop_below);
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.states.iter().filter(|s| !s.is_fresh()).count()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.states.iter().all(|s| s.is_fresh())
+    }
+
+    fn capacity(&self) -> usize {
+        self.states.len()
+    }
+
+    fn memory_usage(&self) -> usize {
+        self.states.len() * mem::size_of::<InMemoryState>()
+    }
+}
+
+/// A keyed rate limiter for keys with a small, fixed set of values, backed by an
+/// [`EnumStateStore`].
+pub type EnumKeyedLimiter<
+    K,
+    C = clock::DefaultClock,
+    MW = NoOpMiddleware<<C as clock::Clock>::Instant>,
+> = RateLimiter<K, EnumStateStore<K>, C, MW>;
+
+/// # Keyed rate limiters - [`EnumArray`]-keyed
+impl<K> RateLimiter<K, EnumStateStore<K>, clock::DefaultClock>
+where
+    K: EnumArray,
+{
+    /// Constructs a new keyed rate limiter for a fixed set of keys, backed by an
+    /// [`EnumStateStore`].
+    pub fn enum_keyed(quota: Quota) -> Self {
+        let clock = clock::DefaultClock::default();
+        RateLimiter::enum_keyed_with_clock(quota, &clock)
+    }
+}
+
+impl<K, C> RateLimiter<K, EnumStateStore<K>, C, NoOpMiddleware<C::Instant>>
+where
+    K: EnumArray,
+    C: clock::Clock,
+{
+    /// Constructs a new rate limiter for a fixed set of keys with a custom clock, backed by
+    /// an [`EnumStateStore`].
+    pub fn enum_keyed_with_clock(quota: Quota, clock: &C) -> Self {
+        let state: EnumStateStore<K> = EnumStateStore::default();
+        RateLimiter::new(quota, state, clock)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use nonzero_ext::nonzero;
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+    enum Key {
+        A,
+        B,
+    }
+
+    impl EnumArray for Key {
+        const LENGTH: usize = 2;
+
+        fn index(&self) -> usize {
+            *self as usize
+        }
+    }
+
+    #[test]
+    fn enum_state_store_impls() {
+        let store = EnumStateStore::<Key>::default();
+        assert_eq!(store.states.len(), 2);
+        assert!(!format!("{:?}", store).is_empty());
+        assert!(store.is_empty());
+    }
+
+    #[test]
+    fn out_of_range_indexes_get_fresh_states() {
+        #[derive(Debug, Clone, PartialEq, Eq, Hash)]
+        struct Broken;
+        impl EnumArray for Broken {
+            const LENGTH: usize = 1;
+
+            fn index(&self) -> usize {
+                1
+            }
+        }
+
+        let lim = RateLimiter::enum_keyed(Quota::per_second(nonzero!(1u32)));
+        assert_eq!(lim.check_key(&Broken), Ok(()));
+        assert_eq!(lim.check_key(&Broken), Ok(()));
+        assert!(lim.is_empty());
+    }
+
+    #[test]
+    fn default_constructor() {
+        let lim = RateLimiter::enum_keyed(Quota::per_second(nonzero!(1u32)));
+        assert_eq!(Ok(()), lim.check_key(&Key::A));
+        assert_eq!(Ok(()), lim.check_key(&Key::B));
+        assert_ne!(Ok(()), lim.check_key(&Key::A));
+    }
+}
@@ -1,11 +1,20 @@
-use std::prelude::v1::*;
+use crate::compat::prelude::*;
 
 use crate::{
-    clock, middleware::RateLimitingMiddleware, state::keyed::KeyedStateStore, Jitter, NotUntil,
-    RateLimiter,
+    clock,
+    hash::fnv1a,
+    middleware::RateLimitingMiddleware,
+    state::{
+        direct::{InsufficientCapacity, TimedOut},
+        keyed::KeyedStateStore,
+        wait::wait,
+    },
+    Jitter, NegativeMultiDecision, NotUntil, RateLimiter,
 };
-use futures_timer::Delay;
 use std::hash::Hash;
+use std::num::NonZeroU32;
+use std::ops::ControlFlow;
+use std::time::Duration;
 
 #[cfg(feature = "std")]
 /// # Keyed rate limiters - `async`/`await`
@@ -45,16 +54,109 @@ where
         key: &K,
         jitter: Jitter,
     ) -> MW::PositiveOutcome {
-        loop {
-            match self.check_key(key) {
-                Ok(x) => {
-                    return x;
+        wait(self, key, Some(fnv1a(key)), || match self.check_key(key) {
+            Ok(x) => ControlFlow::Break(x),
+            Err(negative) => {
+                ControlFlow::Continue(jitter + negative.wait_time_from(self.clock.now()))
+            }
+        })
+        .await
+    }
+
+    /// Asynchronously resolves as soon as the rate limiter allows all `n` cells through for
+    /// the given key.
+    ///
+    /// This is similar to `until_key_ready` except it waits for an abitrary number
+    /// of `n` cells to be available.
+    ///
+    /// Returns `InsufficientCapacity` right away if the `n` provided exceeds the maximum
+    /// capacity of the rate limiter, as waiting would never succeed. The error holds that
+    /// capacity, so the batch can be split into chunks that fit.
+    pub async fn until_key_n_ready(
+        &self,
+        key: &K,
+        n: NonZeroU32,
+    ) -> Result<MW::PositiveOutcome, InsufficientCapacity> {
+        self.until_key_n_ready_with_jitter(key, n, Jitter::NONE)
+            .await
+    }
+
+    /// Asynchronously resolves as soon as the rate limiter allows all `n` cells through for
+    /// the given key, with a randomized wait period.
+    ///
+    /// This is similar to `until_key_ready_with_jitter` except it waits for an
+    /// abitrary number of `n` cells to be available.
+    ///
+    /// Returns `InsufficientCapacity` right away if the `n` provided exceeds the maximum
+    /// capacity of the rate limiter.
+    pub async fn until_key_n_ready_with_jitter(
+        &self,
+        key: &K,
+        n: NonZeroU32,
+        jitter: Jitter,
+    ) -> Result<MW::PositiveOutcome, InsufficientCapacity> {
+        wait(self, key, Some(fnv1a(key)), || {
+            match self.check_key_n(key, n) {
+                Ok(x) => ControlFlow::Break(Ok(x)),
+                Err(NegativeMultiDecision::BatchNonConforming(_, negative)) => {
+                    ControlFlow::Continue(jitter + negative.wait_time_from(self.clock.now()))
+                }
+                Err(NegativeMultiDecision::InsufficientCapacity(cap)) => {
+                    ControlFlow::Break(Err(InsufficientCapacity(cap)))
+                }
+            }
+        })
+        .await
+    }
+
+    /// Asynchronously resolves as soon as the rate limiter allows a cell through for the given
+    /// key, or with an error once `timeout` has passed without the rate limiter allowing it.
+    ///
+    /// See [`until_ready_with_timeout`](#method.until_ready_with_timeout).
+    pub async fn until_key_ready_with_timeout(
+        &self,
+        key: &K,
+        timeout: Duration,
+    ) -> Result<MW::PositiveOutcome, TimedOut<C::Instant>> {
+        let deadline = self.clock.now() + timeout.into();
+        wait(self, key, Some(fnv1a(key)), || match self.check_key(key) {
+            Ok(x) => ControlFlow::Break(Ok(x)),
+            Err(negative) => match self.until_deadline(negative, deadline) {
+                ControlFlow::Break(timed_out) => ControlFlow::Break(Err(timed_out)),
+                ControlFlow::Continue(wait) => ControlFlow::Continue(wait),
+            },
+        })
+        .await
+    }
+
+    /// Asynchronously resolves as soon as the rate limiter allows all `n` cells through for
+    /// the given key, or with an error once `timeout` has passed without the rate limiter
+    /// allowing them.
+    ///
+    /// See [`until_n_ready_with_timeout`](#method.until_n_ready_with_timeout).
+    pub async fn until_key_n_ready_with_timeout(
+        &self,
+        key: &K,
+        n: NonZeroU32,
+        timeout: Duration,
+    ) -> Result<MW::PositiveOutcome, NegativeMultiDecision<TimedOut<C::Instant>>> {
+        let deadline = self.clock.now() + timeout.into();
+        wait(self, key, Some(fnv1a(key)), || {
+            match self.check_key_n(key, n) {
+                Ok(x) => ControlFlow::Break(Ok(x)),
+                Err(NegativeMultiDecision::BatchNonConforming(n, negative)) => {
+                    match self.until_deadline(negative, deadline) {
+                        ControlFlow::Break(timed_out) => ControlFlow::Break(Err(
+                            NegativeMultiDecision::BatchNonConforming(n, timed_out),
+                        )),
+                        ControlFlow::Continue(wait) => ControlFlow::Continue(wait),
+                    }
                 }
-                Err(negative) => {
-                    let delay = Delay::new(jitter + negative.wait_time_from(self.clock.now()));
-                    delay.await;
+                Err(NegativeMultiDecision::InsufficientCapacity(cap)) => {
+                    ControlFlow::Break(Err(NegativeMultiDecision::InsufficientCapacity(cap)))
                 }
             }
-        }
+        })
+        .await
     }
 }
@@ -1,16 +1,17 @@
-use std::prelude::v1::*;
+use crate::compat::prelude::*;
 
+use crate::compat::collections::HashMap;
 use crate::nanos::Nanos;
 use crate::{clock, Quota, RateLimiter};
 use crate::{
     middleware::NoOpMiddleware,
     state::{InMemoryState, StateStore},
 };
-use std::collections::HashMap;
 use std::hash::Hash;
 
-use crate::state::keyed::ShrinkableKeyedStateStore;
-use parking_lot::Mutex;
+use crate::state::keyed::{BorrowedKeyedStateStore, ShrinkableKeyedStateStore};
+use crate::sync::Mutex;
+use std::borrow::Borrow;
 
 /// A thread-safe (but not very performant) implementation of a keyed rate limiter state
 /// store using [`HashMap`].
@@ -31,11 +32,29 @@ impl<K: Hash + Eq + Clone> StateStore for HashMapStateStore<K> {
             // fast path: a rate limiter is already present for the key.
             return v.measure_and_replace_one(f);
         }
-        // not-so-fast path: make a new entry and measure it.
-        let entry = (*map)
-            .entry(key.clone())
-            .or_insert_with(InMemoryState::default);
-        entry.measure_and_replace_one(f)
+        // not-so-fast path: decide on a fresh state, and make a new entry if that stores one.
+        let (result, state) = InMemoryState::create(f)?;
+        map.insert(key.clone(), state);
+        Ok(result)
+    }
+}
+
+impl<K, Q> BorrowedKeyedStateStore<K, Q> for HashMapStateStore<K>
+where
+    K: Hash + Eq + Clone + Borrow<Q>,
+    Q: Hash + Eq + ToOwned<Owned = K> + ?Sized,
+{
+    fn measure_and_replace_borrowed<T, F, E>(&self, key: &Q, f: F) -> Result<T, E>
+    where
+        F: Fn(Option<Nanos>) -> Result<(T, Nanos), E>,
+    {
+        let mut map = self.lock();
+        if let Some(v) = (*map).get(key) {
+            return v.measure_and_replace_one(f);
+        }
+        let (result, state) = InMemoryState::create(f)?;
+        map.insert(key.to_owned(), state);
+        Ok(result)
     }
 }
 
@@ -45,6 +64,19 @@ impl<K: Hash + Eq + Clone> ShrinkableKeyedStateStore<K> for HashMapStateStore<K>
         map.retain(|_, v| !v.is_older_than(drop_below));
     }
 
+    fn retain_recent_evicted(&self, drop_below: Nanos) -> Vec<K> {
+        let mut map = self.lock();
+        let mut evicted = Vec::new();
+        map.retain(|k, v| {
+            let retain = !v.is_older_than(drop_below);
+            if !retain {
+                evicted.push(k.clone());
+            }
+            retain
+        });
+        evicted
+    }
+
     fn shrink_to_fit(&self) {
         let mut map = self.lock();
         map.shrink_to_fit();
@@ -58,8 +90,21 @@ impl<K: Hash + Eq + Clone> ShrinkableKeyedStateStore<K> for HashMapStateStore<K>
         let map = self.lock();
         (*map).is_empty()
     }
+
+    fn capacity(&self) -> usize {
+        let map = self.lock();
+        (*map).capacity()
+    }
 }
 
+/// A keyed rate limiter backed by a [`HashMapStateStore`], as constructed by
+/// [`RateLimiter::hashmap_with_clock`].
+pub type HashMapKeyedLimiter<
+    K,
+    C = clock::DefaultClock,
+    MW = NoOpMiddleware<<C as clock::Clock>::Instant>,
+> = RateLimiter<K, HashMapStateStore<K>, C, MW>;
+
 /// # Keyed rate limiters - [`HashMap`]-backed
 impl<K, C> RateLimiter<K, HashMapStateStore<K>, C, NoOpMiddleware<C::Instant>>
 where
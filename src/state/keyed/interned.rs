@@ -0,0 +1,180 @@
+#![cfg(feature = "std")]
+
+use crate::compat::prelude::*;
+
+use crate::compat::collections::hash_map::DefaultHasher;
+use crate::compat::collections::HashSet;
+use crate::sync::Arc;
+use crate::sync::Mutex;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+
+/// A key that was interned by an [`Interner`], for use in keyed rate limiters.
+///
+/// Interned keys carry their hash with them and compare equal by pointer first, so checking
+/// a rate limiter with an interned key neither hashes nor compares the key's contents (on
+/// the fast path). Cloning an interned key only increments a reference count.
+///
+/// Keys interned by the same `Interner` are equal exactly if their contents are equal.
+pub struct InternedKey<T: ?Sized = str> {
+    hash: u64,
+    key: Arc<T>,
+}
+
+impl<T: ?Sized> InternedKey<T> {
+    /// Returns the contents of the interned key.
+    pub fn get(&self) -> &T {
+        &self.key
+    }
+}
+
+impl<T: ?Sized> Clone for InternedKey<T> {
+    fn clone(&self) -> Self {
+        InternedKey {
+            hash: self.hash,
+            key: Arc::clone(&self.key),
+        }
+    }
+}
+
+impl<T: ?Sized> Hash for InternedKey<T> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        state.write_u64(self.hash);
+    }
+}
+
+impl<T: ?Sized + Eq> PartialEq for InternedKey<T> {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.key, &other.key) || (self.hash == other.hash && self.key == other.key)
+    }
+}
+
+impl<T: ?Sized + Eq> Eq for InternedKey<T> {}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for InternedKey<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("InternedKey").field(&&*self.key).finish()
+    }
+}
+
+/// Interns keys (e.g. `str` request paths, or `[u8]` byte strings), so that repeated rate
+/// limiting decisions on the same key don't need to hash or allocate it.
+///
+/// Interning is meant to happen once per distinct key, e.g. when an HTTP route is registered,
+/// with the resulting [`InternedKey`] being kept around and used for all rate-limiting decisions
+/// on that key.
+///
+/// # Performance
+///
+/// The interned keys are kept in one hash set behind one mutex, which every call to
+/// [`intern`](#method.intern) takes; interning on the hot path of every decision would
+/// serialize them. Decisions on [`InternedKey`]s don't touch the interner.
+///
+/// # Example
+/// ```rust
+/// # use nonzero_ext::*;
+/// use governor::{state::keyed::{InternedKey, Interner}, Quota, RateLimiter};
+///
+/// let interner: Interner = Interner::default();
+/// let users = interner.intern("/api/v1/users");
+/// let lim = RateLimiter::<InternedKey, _, _>::keyed(Quota::per_second(nonzero!(1u32)));
+///
+/// assert_eq!(Ok(()), lim.check_key(&users));
+/// // Interning the same path again returns an equal key:
+/// assert_ne!(Ok(()), lim.check_key(&interner.intern("/api/v1/users")));
+/// ```
+pub struct Interner<T: ?Sized = str> {
+    keys: Mutex<HashSet<Arc<T>>>,
+}
+
+impl<T: ?Sized> Default for Interner<T> {
+    fn default() -> Self {
+        Interner {
+            keys: Mutex::new(HashSet::new()),
+        }
+    }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for Interner<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Interner")
+            .field("keys", &*self.keys.lock())
+            .finish()
+    }
+}
+
+impl<T> Interner<T>
+where
+    T: ?Sized + Hash + Eq,
+    Arc<T>: for<'a> From<&'a T>,
+{
+    /// Returns the interned key for `key`, interning it if necessary.
+    pub fn intern(&self, key: &T) -> InternedKey<T> {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        let hash = hasher.finish();
+
+        let mut keys = self.keys.lock();
+        let key = match keys.get(key) {
+            Some(interned) => Arc::clone(interned),
+            None => {
+                let interned: Arc<T> = Arc::from(key);
+                keys.insert(Arc::clone(&interned));
+                interned
+            }
+        };
+        InternedKey { hash, key }
+    }
+
+    /// Returns the number of keys that were interned.
+    pub fn len(&self) -> usize {
+        self.keys.lock().len()
+    }
+
+    /// Returns `true` if no keys are interned.
+    pub fn is_empty(&self) -> bool {
+        self.keys.lock().is_empty()
+    }
+
+    /// Forgets all keys that are no longer in use outside the interner.
+    ///
+    /// Interning a forgotten key again returns an `InternedKey` that is equal to the ones
+    /// handed out earlier, so this is safe to call at any time.
+    pub fn forget_unused(&self) {
+        self.keys.lock().retain(|key| Arc::strong_count(key) > 1);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn interned_key_impls() {
+        let interner: Interner<[u8]> = Interner::default();
+        let key = interner.intern(b"foo");
+        assert_eq!(key.get(), b"foo");
+        assert_eq!(key, key.clone());
+        assert_ne!(key, interner.intern(b"bar"));
+        assert!(!format!("{:?}", key).is_empty());
+        assert!(!format!("{:?}", interner).is_empty());
+    }
+
+    #[test]
+    fn forgets_unused_keys() {
+        let interner: Interner = Interner::default();
+        let foo = interner.intern("foo");
+        drop(interner.intern("bar"));
+        assert_eq!(interner.len(), 2);
+
+        interner.forget_unused();
+        assert_eq!(interner.len(), 1);
+        assert_eq!(foo, interner.intern("foo"));
+
+        drop(foo);
+        interner.forget_unused();
+        assert!(interner.is_empty());
+        // Keys interned again compare equal to each other, even if they were forgotten:
+        assert_eq!(interner.intern("foo"), interner.intern("foo"));
+    }
+}
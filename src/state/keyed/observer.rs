@@ -0,0 +1,179 @@
+use crate::compat::prelude::*;
+
+use crate::nanos::Nanos;
+use crate::state::keyed::ShrinkableKeyedStateStore;
+use crate::state::StateStore;
+use std::cell::Cell;
+use std::hash::Hash;
+
+/// Callbacks for the lifecycle of keys in a keyed rate limiter.
+///
+/// Attach an observer to a keyed state store using [`ObservedStateStore`] to be notified when
+/// a key's rate-limiting state is first created, when a key is denied, and when a key is
+/// evicted from the state store (e.g. by
+/// [`retain_recent`](../../struct.RateLimiter.html#method.retain_recent)). All methods do nothing
+/// by default.
+///
+/// The callbacks are invoked after the state store has been updated, and without holding any
+/// locks on it, so it is safe to use the rate limiter from them.
+pub trait KeyObserver<K> {
+    /// Called when a rate-limiting decision is made on a key that had no state in the store.
+    fn key_created(&self, _key: &K) {}
+
+    /// Called when a key that is present in the state store is evicted from it.
+    fn key_evicted(&self, _key: &K) {}
+
+    /// Called when a rate-limiting decision for a key is negative.
+    ///
+    /// Queries that don't decide anything, like
+    /// [`key_saturation`](../../struct.RateLimiter.html#method.key_saturation), aren't
+    /// reported.
+    fn key_penalized(&self, _key: &K) {}
+}
+
+/// A keyed state store that reports the lifecycle of its keys to a [`KeyObserver`].
+///
+/// Eviction can only be reported for state stores that implement
+/// [`ShrinkableKeyedStateStore::retain_recent_evicted`]; all the hash map-based state stores in
+/// this crate do.
+///
+/// # Example
+/// ```rust
+/// # use nonzero_ext::*;
+/// use governor::{
+///     clock::FakeRelativeClock,
+///     middleware::NoOpMiddleware,
+///     state::keyed::{HashMapStateStore, KeyObserver, ObservedStateStore},
+///     Quota, RateLimiter,
+/// };
+/// use std::sync::atomic::{AtomicUsize, Ordering};
+///
+/// #[derive(Default)]
+/// struct Sessions(AtomicUsize);
+///
+/// impl KeyObserver<&'static str> for Sessions {
+///     fn key_created(&self, _key: &&'static str) {
+///         self.0.fetch_add(1, Ordering::Relaxed);
+///     }
+/// }
+///
+/// let state = ObservedStateStore::new(HashMapStateStore::default(), Sessions::default());
+/// let clock = FakeRelativeClock::default();
+/// let lim: RateLimiter<_, _, _, NoOpMiddleware<_>> =
+///     RateLimiter::new(Quota::per_second(nonzero!(10u32)), state, &clock);
+/// lim.check_key(&"alice").unwrap();
+/// lim.check_key(&"alice").unwrap();
+/// lim.check_key(&"bob").unwrap();
+/// assert_eq!(lim.into_state_store().observer().0.load(Ordering::Relaxed), 2);
+/// ```
+#[derive(Debug, Default)]
+pub struct ObservedStateStore<S, O> {
+    store: S,
+    observer: O,
+}
+
+impl<S, O> ObservedStateStore<S, O> {
+    /// Wraps a state store, reporting the lifecycle of its keys to `observer`.
+    pub fn new(store: S, observer: O) -> Self {
+        ObservedStateStore { store, observer }
+    }
+
+    /// Returns the wrapped state store.
+    pub fn store(&self) -> &S {
+        &self.store
+    }
+
+    /// Returns the observer.
+    pub fn observer(&self) -> &O {
+        &self.observer
+    }
+
+    /// Splits the observed state store into the wrapped state store and the observer.
+    pub fn into_parts(self) -> (S, O) {
+        (self.store, self.observer)
+    }
+}
+
+impl<S, O> StateStore for ObservedStateStore<S, O>
+where
+    S: StateStore,
+    O: KeyObserver<S::Key>,
+{
+    type Key = S::Key;
+
+    fn measure_and_replace<T, F, E>(&self, key: &Self::Key, f: F) -> Result<T, E>
+    where
+        F: Fn(Option<Nanos>) -> Result<(T, Nanos), E>,
+    {
+        // The closure may run several times, so only its last invocation counts:
+        let was_fresh = Cell::new(false);
+        let result = self.store.measure_and_replace(key, |tat| {
+            was_fresh.set(tat.is_none());
+            f(tat)
+        });
+        match result {
+            Ok(_) if was_fresh.get() => self.observer.key_created(key),
+            Ok(_) => {}
+            Err(_) => self.observer.key_penalized(key),
+        }
+        result
+    }
+
+    /// Queries aren't decisions, so they aren't reported to the observer.
+    fn peek(&self, key: &Self::Key) -> Option<Nanos> {
+        self.store.peek(key)
+    }
+
+    fn reach(&self) -> Option<Nanos> {
+        self.store.reach()
+    }
+
+    /// Refunds and seeded states aren't decisions, so they aren't reported to the observer.
+    fn adjust<F>(&self, key: &Self::Key, f: F)
+    where
+        F: Fn(Option<Nanos>) -> Option<Nanos>,
+    {
+        self.store.adjust(key, f)
+    }
+}
+
+impl<K, S, O> ShrinkableKeyedStateStore<K> for ObservedStateStore<S, O>
+where
+    K: Hash + Eq + Clone,
+    S: ShrinkableKeyedStateStore<K>,
+    O: KeyObserver<K>,
+{
+    fn retain_recent(&self, drop_below: Nanos) {
+        for key in self.store.retain_recent_evicted(drop_below) {
+            self.observer.key_evicted(&key);
+        }
+    }
+
+    fn retain_recent_evicted(&self, drop_below: Nanos) -> Vec<K> {
+        let evicted = self.store.retain_recent_evicted(drop_below);
+        for key in evicted.iter() {
+            self.observer.key_evicted(key);
+        }
+        evicted
+    }
+
+    fn shrink_to_fit(&self) {
+        self.store.shrink_to_fit()
+    }
+
+    fn len(&self) -> usize {
+        self.store.len()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.store.is_empty()
+    }
+
+    fn capacity(&self) -> usize {
+        self.store.capacity()
+    }
+
+    fn memory_usage(&self) -> usize {
+        self.store.memory_usage()
+    }
+}
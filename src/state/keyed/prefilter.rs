@@ -0,0 +1,260 @@
+use crate::compat::prelude::*;
+
+use crate::compat::collections::hash_map::RandomState;
+use crate::nanos::Nanos;
+use crate::state::keyed::ShrinkableKeyedStateStore;
+use crate::state::StateStore;
+use crate::sync::{AtomicU64, Ordering};
+use std::cell::Cell;
+use std::fmt;
+use std::hash::{BuildHasher, Hash};
+use std::num::NonZeroUsize;
+
+/// A keyed state store with a probabilistic fast path for rejecting keys that were denied
+/// recently, without touching the wrapped state store (and its locks).
+///
+/// The fast path is a Bloom filter whose slots hold rate-limiting states instead of bits: Each
+/// negative decision records the denied key's state in the key's two slots (keeping the later
+/// state in each), and each decision is first made against the earlier of its key's two slots.
+/// If that is negative, the key is rejected right away; otherwise, the decision goes to the
+/// wrapped state store as usual. Entries expire by themselves, as the states they hold stop
+/// denying cells. Queries that don't decide anything, like
+/// [`key_saturation`](../../struct.RateLimiter.html#method.key_saturation), bypass the filter.
+///
+/// Rejections on the fast path are never more permissive than the wrapped state store, but the
+/// filter can hold states of other keys in both of a key's slots: A key that wasn't denied
+/// recently can be rejected when the filter is too small for the number of keys that are
+/// denied at the same time. With `m` slots and `n` such keys, this happens to about
+/// `(1 - e^(-2n/m))^2` of the other keys; e.g., 1 in 100 for 10 slots per key.
+///
+/// # Example
+/// ```rust
+/// # use nonzero_ext::*;
+/// use governor::{
+///     clock::FakeRelativeClock,
+///     middleware::NoOpMiddleware,
+///     state::keyed::{HashMapStateStore, PrefilteredStateStore},
+///     Quota, RateLimiter,
+/// };
+///
+/// let state = PrefilteredStateStore::new(HashMapStateStore::default(), nonzero!(1024usize));
+/// let clock = FakeRelativeClock::default();
+/// let lim: RateLimiter<_, _, _, NoOpMiddleware<_>> =
+///     RateLimiter::new(Quota::per_second(nonzero!(1u32)), state, &clock);
+/// lim.check_key(&"attacker").unwrap();
+/// assert!(lim.check_key(&"attacker").is_err());
+/// // This one is rejected by the filter:
+/// assert!(lim.check_key(&"attacker").is_err());
+/// assert!(lim.check_key(&"someone else").is_ok());
+/// ```
+pub struct PrefilteredStateStore<S, H = RandomState> {
+    store: S,
+    slots: Box<[AtomicU64]>,
+    hasher: H,
+}
+
+impl<S> PrefilteredStateStore<S> {
+    /// Wraps a state store, with a filter of the given number of slots (of 8 bytes each).
+    pub fn new(store: S, slots: NonZeroUsize) -> Self {
+        Self::with_hasher(store, slots, RandomState::new())
+    }
+}
+
+impl<S, H> PrefilteredStateStore<S, H> {
+    /// Wraps a state store, with a filter of the given number of slots that hashes keys with
+    /// `hasher`.
+    pub fn with_hasher(store: S, slots: NonZeroUsize, hasher: H) -> Self {
+        PrefilteredStateStore {
+            store,
+            slots: (0..slots.get()).map(|_| AtomicU64::new(0)).collect(),
+            hasher,
+        }
+    }
+
+    /// Returns the wrapped state store.
+    pub fn store(&self) -> &S {
+        &self.store
+    }
+
+    /// Forgets all the states in the filter.
+    pub fn clear_filter(&self) {
+        for slot in self.slots.iter() {
+            slot.store(0, Ordering::Relaxed);
+        }
+    }
+}
+
+impl<S: StateStore, H: BuildHasher> PrefilteredStateStore<S, H>
+where
+    S::Key: Hash,
+{
+    /// Returns the key's two slots.
+    fn slots(&self, key: &S::Key) -> [&AtomicU64; 2] {
+        let hash = self.hasher.hash_one(key);
+        let len = self.slots.len() as u64;
+        // The second index comes from the other half of the hash, so keys that share one slot
+        // rarely share the other:
+        [
+            &self.slots[(hash % len) as usize],
+            &self.slots[(hash.rotate_left(32) % len) as usize],
+        ]
+    }
+}
+
+impl<S, H> StateStore for PrefilteredStateStore<S, H>
+where
+    S: StateStore,
+    S::Key: Hash,
+    H: BuildHasher,
+{
+    type Key = S::Key;
+
+    fn measure_and_replace<T, F, E>(&self, key: &Self::Key, f: F) -> Result<T, E>
+    where
+        F: Fn(Option<Nanos>) -> Result<(T, Nanos), E>,
+    {
+        let slots = self.slots(key);
+        let filtered = slots[0]
+            .load(Ordering::Relaxed)
+            .min(slots[1].load(Ordering::Relaxed));
+        if filtered != 0 {
+            f(Some(Nanos::from(filtered)))?;
+        }
+        // The closure may run several times, so only the state of its last invocation counts:
+        let denied = Cell::new(None);
+        let result = self.store.measure_and_replace(key, |tat| {
+            let decision = f(tat);
+            denied.set(decision.as_ref().err().and(tat));
+            decision
+        });
+        if let (Err(_), Some(tat)) = (&result, denied.get()) {
+            for slot in slots.iter() {
+                slot.fetch_max(tat.as_u64(), Ordering::Relaxed);
+            }
+        }
+        result
+    }
+
+    /// Queries aren't decisions, so they neither go through the filter nor are recorded in it.
+    fn peek(&self, key: &Self::Key) -> Option<Nanos> {
+        self.store.peek(key)
+    }
+
+    fn reach(&self) -> Option<Nanos> {
+        self.store.reach()
+    }
+
+    /// Refunds and seeded states aren't decisions, so they don't go through the filter.
+    fn adjust<F>(&self, key: &Self::Key, f: F)
+    where
+        F: Fn(Option<Nanos>) -> Option<Nanos>,
+    {
+        self.store.adjust(key, f)
+    }
+}
+
+impl<K, S, H> ShrinkableKeyedStateStore<K> for PrefilteredStateStore<S, H>
+where
+    K: Hash + Eq + Clone,
+    S: ShrinkableKeyedStateStore<K>,
+    H: BuildHasher,
+{
+    fn retain_recent(&self, drop_below: Nanos) {
+        self.store.retain_recent(drop_below)
+    }
+
+    fn retain_recent_evicted(&self, drop_below: Nanos) -> Vec<K> {
+        self.store.retain_recent_evicted(drop_below)
+    }
+
+    fn shrink_to_fit(&self) {
+        self.store.shrink_to_fit()
+    }
+
+    fn len(&self) -> usize {
+        self.store.len()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.store.is_empty()
+    }
+
+    fn capacity(&self) -> usize {
+        self.store.capacity()
+    }
+
+    fn memory_usage(&self) -> usize {
+        self.store.memory_usage() + self.slots.len() * std::mem::size_of::<AtomicU64>()
+    }
+}
+
+impl<S: fmt::Debug, H> fmt::Debug for PrefilteredStateStore<S, H> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PrefilteredStateStore")
+            .field("store", &self.store)
+            .field("slots", &self.slots.len())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::clock::FakeRelativeClock;
+    use crate::middleware::NoOpMiddleware;
+    use crate::state::keyed::{HashMapStateStore, KeyObserver, ObservedStateStore};
+    use crate::{Quota, RateLimiter};
+    use nonzero_ext::nonzero;
+    use std::sync::atomic::AtomicUsize;
+    use std::time::Duration;
+
+    #[derive(Default)]
+    struct Penalties(AtomicUsize);
+
+    impl KeyObserver<u32> for Penalties {
+        fn key_penalized(&self, _key: &u32) {
+            self.0.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    #[test]
+    fn rejects_denied_keys_early() {
+        let clock = FakeRelativeClock::default();
+        let observed = ObservedStateStore::new(HashMapStateStore::default(), Penalties::default());
+        let state = PrefilteredStateStore::new(observed, nonzero!(64usize));
+        let lim: RateLimiter<u32, _, _, NoOpMiddleware<_>> =
+            RateLimiter::new(Quota::per_second(nonzero!(2u32)), state, &clock);
+        let penalties = || lim.state.store().observer().0.load(Ordering::Relaxed);
+        assert!(lim.check_key_n(&1, nonzero!(2u32)).is_ok());
+        assert!(lim.check_key(&1).is_err());
+        assert_eq!(penalties(), 1);
+
+        // Only the filter rejects the key from here:
+        assert!(lim.check_key(&1).is_err());
+        assert!(lim.check_key_n(&1, nonzero!(2u32)).is_err());
+        assert_eq!(penalties(), 1);
+        assert!(lim.check_key(&2).is_ok());
+
+        // Entries expire as their states stop denying cells:
+        clock.advance(Duration::from_millis(500));
+        assert!(lim.check_key(&1).is_ok());
+        assert!(lim.check_key(&1).is_err());
+        assert_eq!(penalties(), 2);
+    }
+
+    #[test]
+    fn queries_are_not_recorded() {
+        let clock = FakeRelativeClock::default();
+        let state = PrefilteredStateStore::new(HashMapStateStore::default(), nonzero!(64usize));
+        let lim: RateLimiter<u32, _, _, NoOpMiddleware<_>> =
+            RateLimiter::new(Quota::per_second(nonzero!(2u32)), state, &clock);
+        assert!(lim.check_key(&1).is_ok());
+        assert_eq!(lim.key_saturation(&1), 0.5);
+        assert_eq!(lim.key_saturation(&2), 0.0);
+        assert!(lim
+            .state
+            .slots
+            .iter()
+            .all(|slot| slot.load(Ordering::Relaxed) == 0));
+    }
+}
@@ -0,0 +1,364 @@
+use crate::compat::prelude::*;
+
+use crate::clock::{self, Reference};
+use crate::compat::collections::HashMap;
+use crate::middleware::{NoOpMiddleware, RateLimitingMiddleware};
+use crate::nanos::Nanos;
+use crate::state::keyed::{DefaultKeyedStateStore, KeyedStateStore, ShrinkableKeyedStateStore};
+use crate::state::StateStore;
+use crate::sync::Mutex;
+use crate::{Quota, RateLimiter};
+use std::cell::Cell;
+use std::hash::Hash;
+use std::time::Duration;
+
+/// Decayed counts below this are dropped when the state store is cleaned up.
+const NEGLIGIBLE_COUNT: f64 = 0.01;
+
+/// Statistics about the rate-limiting decisions for a key, as tracked by a
+/// [`StatsStateStore`].
+///
+/// The counts decay exponentially with the state store's half-life: a decision made one
+/// half-life ago counts as half a decision, one made two half-lives ago as a quarter, and so on.
+/// This makes them rates of sorts, which can be compared to thresholds, e.g. to detect keys
+/// that keep being rate-limited.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct KeyStats {
+    admitted: f64,
+    rejected: f64,
+}
+
+impl KeyStats {
+    /// The decayed number of decisions that let cells through.
+    pub fn admitted(&self) -> f64 {
+        self.admitted
+    }
+
+    /// The decayed number of decisions that didn't let cells through.
+    pub fn rejected(&self) -> f64 {
+        self.rejected
+    }
+
+    /// The fraction of decisions that didn't let cells through, between 0 and 1.
+    pub fn rejection_ratio(&self) -> f64 {
+        let total = self.admitted + self.rejected;
+        if total > 0.0 {
+            self.rejected / total
+        } else {
+            0.0
+        }
+    }
+
+    fn decay(&mut self, factor: f64) {
+        self.admitted *= factor;
+        self.rejected *= factor;
+    }
+}
+
+#[derive(Debug)]
+struct Counters {
+    stats: KeyStats,
+    updated: Nanos,
+}
+
+/// A keyed state store that tracks [`KeyStats`] for each key: decayed counts of the decisions
+/// that let the key's cells through, and of those that didn't. Queries that don't decide
+/// anything, like [`key_saturation`](../../struct.RateLimiter.html#method.key_saturation),
+/// aren't counted.
+///
+/// The statistics are kept next to the wrapped state store, behind a mutex, so tracking them
+/// makes decisions slower. They are looked up with
+/// [`RateLimiter::key_stats`](../../struct.RateLimiter.html#method.key_stats), and dropped
+/// along with the keys when the rate limiter is
+/// [cleaned up](../../struct.RateLimiter.html#method.retain_recent), once their counts have
+/// decayed to almost nothing.
+///
+/// # Example
+/// ```rust
+/// # use nonzero_ext::*;
+/// # use std::time::Duration;
+/// use governor::{Quota, RateLimiter};
+///
+/// let lim = RateLimiter::keyed_with_stats(
+///     Quota::per_second(nonzero!(1u32)),
+///     Duration::from_secs(60),
+/// );
+/// for _ in 0..10 {
+///     let _ = lim.check_key(&"scraper");
+/// }
+/// let stats = lim.key_stats(&"scraper").unwrap();
+/// assert!(stats.rejection_ratio() > 0.8);
+/// assert_eq!(lim.key_stats(&"someone else"), None);
+/// ```
+pub struct StatsStateStore<S: StateStore, C: clock::Clock> {
+    store: S,
+    stats: Mutex<HashMap<S::Key, Counters>>,
+    half_life: Nanos,
+    clock: C,
+    start: C::Instant,
+}
+
+impl<S, C> StatsStateStore<S, C>
+where
+    S: StateStore,
+    S::Key: Hash + Eq + Clone,
+    C: clock::Clock,
+{
+    /// Wraps a state store, tracking statistics for each key that decay with the given
+    /// half-life, as measured by `clock`.
+    ///
+    /// The half-life must not be zero; it is raised to one nanosecond otherwise.
+    pub fn new(store: S, half_life: Duration, clock: &C) -> Self {
+        let half_life = Nanos::from(half_life);
+        StatsStateStore {
+            store,
+            stats: Mutex::new(HashMap::new()),
+            half_life: half_life.max(Nanos::new(1)),
+            clock: clock.clone(),
+            start: clock.now(),
+        }
+    }
+
+    /// Returns the wrapped state store.
+    pub fn store(&self) -> &S {
+        &self.store
+    }
+
+    /// Returns the statistics for `key`, or `None` if no decisions were made for it (or if its
+    /// statistics were dropped).
+    pub fn key_stats(&self, key: &S::Key) -> Option<KeyStats> {
+        let now = self.now();
+        let mut stats = self.stats.lock();
+        let counters = stats.get_mut(key)?;
+        self.catch_up(counters, now);
+        Some(counters.stats)
+    }
+
+    fn now(&self) -> Nanos {
+        self.clock.now().duration_since(self.start)
+    }
+
+    /// Decays the counters to the given time.
+    fn catch_up(&self, counters: &mut Counters, now: Nanos) {
+        if now > counters.updated {
+            let elapsed = (now.as_u64() - counters.updated.as_u64()) as f64;
+            counters
+                .stats
+                .decay((-elapsed / self.half_life.as_u64() as f64).exp2());
+            counters.updated = now;
+        }
+    }
+
+    /// Drops the statistics whose counts have decayed to almost nothing.
+    fn retain_stats(&self) {
+        let now = self.now();
+        self.stats.lock().retain(|_, counters| {
+            self.catch_up(counters, now);
+            counters.stats.admitted + counters.stats.rejected >= NEGLIGIBLE_COUNT
+        });
+    }
+
+    fn record(&self, key: &S::Key, admitted: bool) {
+        let now = self.now();
+        let mut stats = self.stats.lock();
+        let counters = stats.entry(key.clone()).or_insert(Counters {
+            stats: KeyStats::default(),
+            updated: now,
+        });
+        self.catch_up(counters, now);
+        if admitted {
+            counters.stats.admitted += 1.0;
+        } else {
+            counters.stats.rejected += 1.0;
+        }
+    }
+}
+
+impl<S, C> StateStore for StatsStateStore<S, C>
+where
+    S: StateStore,
+    S::Key: Hash + Eq + Clone,
+    C: clock::Clock,
+{
+    type Key = S::Key;
+
+    fn measure_and_replace<T, F, E>(&self, key: &Self::Key, f: F) -> Result<T, E>
+    where
+        F: Fn(Option<Nanos>) -> Result<(T, Nanos), E>,
+    {
+        // The closure may run several times, so only count the decision that was stored:
+        let admitted = Cell::new(false);
+        let result = self.store.measure_and_replace(key, |tat| {
+            let decision = f(tat);
+            admitted.set(decision.is_ok());
+            decision
+        });
+        self.record(key, admitted.get() && result.is_ok());
+        result
+    }
+
+    /// Queries aren't decisions, so they aren't counted.
+    fn peek(&self, key: &Self::Key) -> Option<Nanos> {
+        self.store.peek(key)
+    }
+
+    fn reach(&self) -> Option<Nanos> {
+        self.store.reach()
+    }
+
+    /// Refunds and seeded states aren't decisions, so they aren't counted.
+    fn adjust<F>(&self, key: &Self::Key, f: F)
+    where
+        F: Fn(Option<Nanos>) -> Option<Nanos>,
+    {
+        self.store.adjust(key, f)
+    }
+}
+
+impl<K, S, C> ShrinkableKeyedStateStore<K> for StatsStateStore<S, C>
+where
+    K: Hash + Eq + Clone,
+    S: ShrinkableKeyedStateStore<K>,
+    C: clock::Clock,
+{
+    fn retain_recent(&self, drop_below: Nanos) {
+        self.store.retain_recent(drop_below);
+        self.retain_stats();
+    }
+
+    fn retain_recent_evicted(&self, drop_below: Nanos) -> Vec<K> {
+        let evicted = self.store.retain_recent_evicted(drop_below);
+        self.retain_stats();
+        evicted
+    }
+
+    fn shrink_to_fit(&self) {
+        self.store.shrink_to_fit();
+        self.stats.lock().shrink_to_fit();
+    }
+
+    fn len(&self) -> usize {
+        self.store.len()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.store.is_empty()
+    }
+
+    fn capacity(&self) -> usize {
+        self.store.capacity()
+    }
+
+    fn memory_usage(&self) -> usize {
+        let stats = self.stats.lock().capacity()
+            * (std::mem::size_of::<K>() + std::mem::size_of::<Counters>());
+        self.store.memory_usage() + stats
+    }
+}
+
+impl<S, C> std::fmt::Debug for StatsStateStore<S, C>
+where
+    S: StateStore + std::fmt::Debug,
+    C: clock::Clock,
+{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("StatsStateStore")
+            .field("store", &self.store)
+            .field("half_life", &self.half_life)
+            .finish()
+    }
+}
+
+/// # Keyed rate limiters - Per-key statistics
+impl<K>
+    RateLimiter<
+        K,
+        StatsStateStore<DefaultKeyedStateStore<K>, clock::DefaultClock>,
+        clock::DefaultClock,
+    >
+where
+    K: Clone + Hash + Eq,
+{
+    /// Constructs a new keyed rate limiter backed by the [`DefaultKeyedStateStore`], which
+    /// tracks [`KeyStats`] for each key that decay with the given half-life.
+    pub fn keyed_with_stats(quota: Quota, half_life: Duration) -> Self {
+        let clock = clock::DefaultClock::default();
+        RateLimiter::keyed_with_stats_and_clock(quota, half_life, &clock)
+    }
+}
+
+impl<K, C>
+    RateLimiter<K, StatsStateStore<DefaultKeyedStateStore<K>, C>, C, NoOpMiddleware<C::Instant>>
+where
+    K: Clone + Hash + Eq,
+    C: clock::Clock,
+{
+    /// Constructs a new keyed rate limiter with a custom clock, backed by the
+    /// [`DefaultKeyedStateStore`], which tracks [`KeyStats`] for each key that decay with the
+    /// given half-life.
+    pub fn keyed_with_stats_and_clock(quota: Quota, half_life: Duration, clock: &C) -> Self {
+        let state = StatsStateStore::new(DefaultKeyedStateStore::default(), half_life, clock);
+        RateLimiter::new(quota, state, clock)
+    }
+}
+
+impl<K, S, C, MW> RateLimiter<K, StatsStateStore<S, C>, C, MW>
+where
+    S: KeyedStateStore<K>,
+    K: Hash + Eq + Clone,
+    C: clock::Clock,
+    MW: RateLimitingMiddleware<C::Instant>,
+{
+    /// Returns the statistics for `key`, or `None` if no decisions were made for it (or if its
+    /// statistics were dropped).
+    ///
+    /// See [`StatsStateStore`].
+    pub fn key_stats(&self, key: &K) -> Option<KeyStats> {
+        self.state.key_stats(key)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::clock::FakeRelativeClock;
+    use crate::middleware::NoOpMiddleware;
+    use crate::state::keyed::HashMapStateStore;
+    use nonzero_ext::nonzero;
+
+    #[test]
+    fn stats_decay() {
+        let clock = FakeRelativeClock::default();
+        let state = StatsStateStore::new(
+            HashMapStateStore::default(),
+            Duration::from_secs(10),
+            &clock,
+        );
+        let lim: RateLimiter<u32, _, _, NoOpMiddleware<_>> =
+            RateLimiter::new(Quota::per_second(nonzero!(2u32)), state, &clock);
+        for _ in 0..4 {
+            let _ = lim.check_key(&1);
+        }
+        let _ = lim.check_key_n(&1, nonzero!(2u32));
+        // Queries don't count as decisions:
+        assert_eq!(lim.key_saturation(&1), 1.0);
+        assert_eq!(lim.key_saturation(&2), 0.0);
+        assert_eq!(lim.key_stats(&2), None);
+        let stats = lim.key_stats(&1).unwrap();
+        assert_eq!((stats.admitted(), stats.rejected()), (2.0, 3.0));
+        assert_eq!(stats.rejection_ratio(), 0.6);
+
+        clock.advance(Duration::from_secs(10));
+        let stats = lim.key_stats(&1).unwrap();
+        assert_eq!((stats.admitted(), stats.rejected()), (1.0, 1.5));
+
+        clock.advance(Duration::from_secs(60));
+        lim.retain_recent();
+        assert_eq!(lim.key_stats(&1).unwrap().rejected(), 1.5 / 64.0);
+        clock.advance(Duration::from_secs(60));
+        lim.retain_recent();
+        assert_eq!(lim.key_stats(&1), None);
+        assert_eq!(KeyStats::default().rejection_ratio(), 0.0);
+        assert!(format!("{:?}", lim).contains("StatsStateStore"));
+    }
+}
@@ -0,0 +1,143 @@
+#![cfg(feature = "std")]
+
+use crate::compat::prelude::*;
+
+use crate::{
+    clock::{self, Reference},
+    gcra::Gcra,
+    middleware::RateLimitingMiddleware,
+    state::{keyed::ShrinkableKeyedStateStore, tuning::Tuning},
+    RateLimiter,
+};
+use futures_timer::Delay;
+use std::future::Future;
+use std::hash::Hash;
+use std::marker::PhantomData;
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::{Arc, Weak};
+use std::thread;
+use std::time::Duration;
+
+/// The parts of a shared rate limiter needed to clean up its state store, holding on to the
+/// state store only weakly.
+struct Sweep<K, S, C: clock::Clock> {
+    state: Weak<S>,
+    gcra: Gcra,
+    clock: C,
+    start: C::Instant,
+    tuning: Arc<Tuning>,
+    key: PhantomData<fn(K)>,
+}
+
+impl<K, S, C> Sweep<K, S, C>
+where
+    K: Hash,
+    S: ShrinkableKeyedStateStore<K>,
+    C: clock::Clock,
+{
+    /// Removes stale keys from the state store; returns `false` if all rate limiters using the
+    /// state store were dropped.
+    fn sweep(&self) -> bool {
+        match self.state.upgrade() {
+            Some(state) => {
+                let now = self.clock.now();
+                let gcra = self.gcra.with_epoch(self.tuning.epoch.load());
+                state.retain_recent(gcra.units_since_start(now.duration_since(self.start)));
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// A handle to a thread that periodically removes stale keys from a keyed rate limiter.
+///
+/// This is returned by [`RateLimiter::spawn_sweeper`]. Dropping the handle lets the thread run
+/// on until all clones of the rate limiter are dropped; use [`stop`](#method.stop) to stop it
+/// earlier.
+#[derive(Debug)]
+pub struct Sweeper {
+    stop: mpsc::Sender<()>,
+    thread: thread::JoinHandle<()>,
+}
+
+impl Sweeper {
+    /// Stops the sweeper thread and waits for it to exit.
+    pub fn stop(self) {
+        // The thread may have exited already, in which case there is nobody to notify:
+        let _ = self.stop.send(());
+        let _ = self.thread.join();
+    }
+}
+
+/// # Keyed rate limiters - Background housekeeping
+///
+/// Shared keyed rate limiters (see [`into_shared`](#method.into_shared)) can clean up their
+/// state stores in the background, removing stale keys (as
+/// [`retain_recent`](#method.retain_recent) does) at regular intervals. The sweepers only keep
+/// a weak reference to the rate limiter's state, and stop once all clones of the rate limiter
+/// are dropped.
+impl<K, S, C, MW> RateLimiter<K, Arc<S>, C, MW>
+where
+    K: Hash + 'static,
+    S: ShrinkableKeyedStateStore<K> + Send + Sync + 'static,
+    C: clock::Clock + Send + 'static,
+    MW: RateLimitingMiddleware<C::Instant>,
+{
+    fn sweep(&self) -> Sweep<K, S, C> {
+        Sweep {
+            state: Arc::downgrade(&self.state),
+            gcra: self.gcra,
+            clock: self.clock.clone(),
+            start: self.start,
+            tuning: Arc::clone(self.tuning()),
+            key: PhantomData,
+        }
+    }
+
+    /// Spawns a thread that removes stale keys from the rate limiter every `interval`.
+    ///
+    /// # Example
+    /// ```rust
+    /// # use nonzero_ext::*;
+    /// use governor::{Quota, RateLimiter};
+    /// use std::time::Duration;
+    ///
+    /// let lim = RateLimiter::keyed(Quota::per_second(nonzero!(10u32))).into_shared();
+    /// let sweeper = lim.spawn_sweeper(Duration::from_secs(60));
+    /// assert_eq!(Ok(()), lim.check_key(&"client"));
+    /// sweeper.stop();
+    /// ```
+    pub fn spawn_sweeper(&self, interval: Duration) -> Sweeper {
+        let sweep = self.sweep();
+        let (stop, stopped) = mpsc::channel();
+        let thread = thread::spawn(move || loop {
+            match stopped.recv_timeout(interval) {
+                Ok(()) => return,
+                Err(RecvTimeoutError::Timeout) => {}
+                // The handle was dropped, so only the rate limiter going away stops us:
+                Err(RecvTimeoutError::Disconnected) => thread::sleep(interval),
+            }
+            if !sweep.sweep() {
+                return;
+            }
+        });
+        Sweeper { stop, thread }
+    }
+
+    /// Returns a future that removes stale keys from the rate limiter every `interval`.
+    ///
+    /// The future is not tied to any particular async runtime; spawn it onto the runtime
+    /// you're using. It resolves once all clones of the rate limiter are dropped.
+    pub fn sweeper(&self, interval: Duration) -> impl Future<Output = ()> + Send + 'static {
+        let sweep = self.sweep();
+        async move {
+            loop {
+                Delay::new(interval).await;
+                if !sweep.sweep() {
+                    return;
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,482 @@
+use crate::compat::prelude::*;
+
+use crate::compat::collections::HashMap;
+use crate::gcra::Gcra;
+use crate::nanos::Nanos;
+use crate::state::StateStore;
+use crate::sync::Mutex;
+use crate::{
+    clock::{self, Reference},
+    middleware::{NoOpMiddleware, RateLimitingMiddleware},
+    NegativeMultiDecision, Quota,
+};
+use std::cell::Cell;
+use std::hash::Hash;
+use std::marker::PhantomData;
+use std::num::{NonZeroU32, NonZeroU64};
+
+/// The rate-limiting states of one tenant: Its aggregate state, and one per resource.
+#[derive(Debug, Default)]
+struct TenantState<R> {
+    tenant: u64,
+    resources: HashMap<R, u64>,
+}
+
+/// Presents a copy of a single rate-limiting state as a state store, so the GCRA can make
+/// tentative decisions on it that only get written back once all levels agree.
+pub(super) struct Slot<'a, K> {
+    tat: &'a Cell<u64>,
+    key: PhantomData<fn(K)>,
+}
+
+impl<'a, K> Slot<'a, K> {
+    pub(super) fn new(tat: &'a Cell<u64>) -> Self {
+        Slot {
+            tat,
+            key: PhantomData,
+        }
+    }
+}
+
+impl<K> StateStore for Slot<'_, K> {
+    type Key = K;
+
+    #[inline]
+    fn measure_and_replace<T, F, E>(&self, _key: &Self::Key, f: F) -> Result<T, E>
+    where
+        F: Fn(Option<Nanos>) -> Result<(T, Nanos), E>,
+    {
+        let (result, tat) = f(NonZeroU64::new(self.tat.get()).map(|n| n.get().into()))?;
+        self.tat.set(tat.into());
+        Ok(result)
+    }
+}
+
+/// A keyed rate limiter for (tenant, resource) keys, which enforces both a quota per
+/// resource of each tenant and an aggregate quota per tenant.
+///
+/// A cell is only let through if it conforms to both the resource's and its tenant's quota;
+/// if either one denies it, neither state is updated, so a busy resource doesn't use up its
+/// tenant's capacity with cells that weren't allowed. Both decisions are made under one lock.
+///
+/// Negative outcomes come from whichever level denied the cell (resources are checked first),
+/// so [`NotUntil::quota`](crate::NotUntil::quota) tells the two apart. The middleware sees the
+/// tenant or resource key that the decision was made for; positive outcomes are those of the
+/// resource decision.
+///
+/// With [`with_borrowing`](#method.with_borrowing), a resource that is out of capacity may
+/// borrow cells from the capacity that its tenant has left unused, instead.
+///
+/// # Performance
+///
+/// All tenants and their resources are kept in one hash map behind one mutex, which every
+/// decision takes, for any tenant: That's what makes the two decisions of a check atomic
+/// without a transaction across state stores, but it also means that decisions for different
+/// tenants serialize under contention. Services with many busy tenants can give each tenant
+/// (or each group of tenants) its own `TenantRateLimiter` to spread the contention.
+///
+/// # Example
+/// ```rust
+/// # use nonzero_ext::*;
+/// use governor::{state::keyed::TenantRateLimiter, Quota};
+///
+/// // Each endpoint gets 2 requests per second, but each tenant only 3 in total:
+/// let lim = TenantRateLimiter::new(
+///     Quota::per_second(nonzero!(3u32)),
+///     Quota::per_second(nonzero!(2u32)),
+/// );
+/// assert_eq!(Ok(()), lim.check_key(&"acme", &"/users"));
+/// assert_eq!(Ok(()), lim.check_key(&"acme", &"/users"));
+/// assert_ne!(Ok(()), lim.check_key(&"acme", &"/users"));
+/// assert_eq!(Ok(()), lim.check_key(&"acme", &"/orders"));
+/// assert_ne!(Ok(()), lim.check_key(&"acme", &"/orders"));
+/// assert_eq!(Ok(()), lim.check_key(&"initech", &"/orders"));
+/// ```
+#[derive(Debug)]
+pub struct TenantRateLimiter<
+    T,
+    R,
+    C: clock::Clock = clock::DefaultClock,
+    MW: RateLimitingMiddleware<C::Instant> = NoOpMiddleware<<C as clock::Clock>::Instant>,
+> {
+    tenants: Mutex<HashMap<T, TenantState<R>>>,
+    tenant_gcra: Gcra,
+    resource_gcra: Gcra,
+    /// The fraction of a tenant's unused capacity that its exhausted resources may borrow.
+    borrowing: f64,
+    clock: C,
+    start: C::Instant,
+    middleware: PhantomData<MW>,
+}
+
+impl<T, R> TenantRateLimiter<T, R>
+where
+    T: Hash + Eq + Clone,
+    R: Hash + Eq + Clone,
+{
+    /// Constructs a rate limiter that allows each tenant `tenant_quota` in total, and each of
+    /// its resources `resource_quota`.
+    pub fn new(tenant_quota: Quota, resource_quota: Quota) -> Self {
+        let clock = clock::DefaultClock::default();
+        TenantRateLimiter::with_clock(tenant_quota, resource_quota, &clock)
+    }
+}
+
+impl<T, R, C> TenantRateLimiter<T, R, C, NoOpMiddleware<C::Instant>>
+where
+    T: Hash + Eq + Clone,
+    R: Hash + Eq + Clone,
+    C: clock::Clock,
+{
+    /// Constructs a rate limiter for tenants and their resources with a custom clock.
+    pub fn with_clock(tenant_quota: Quota, resource_quota: Quota, clock: &C) -> Self {
+        TenantRateLimiter {
+            tenants: Mutex::new(HashMap::new()),
+            tenant_gcra: Gcra::new(tenant_quota),
+            resource_gcra: Gcra::new(resource_quota),
+            borrowing: 0.0,
+            clock: clock.clone(),
+            start: clock.now(),
+            middleware: PhantomData,
+        }
+    }
+}
+
+impl<T, R, C, MW> TenantRateLimiter<T, R, C, MW>
+where
+    T: Hash + Eq + Clone,
+    R: Hash + Eq + Clone,
+    C: clock::Clock,
+    MW: RateLimitingMiddleware<C::Instant>,
+{
+    /// Convert the given rate limiter into one that uses a different middleware.
+    pub fn with_middleware<Outer: RateLimitingMiddleware<C::Instant>>(
+        self,
+    ) -> TenantRateLimiter<T, R, C, Outer> {
+        TenantRateLimiter {
+            tenants: self.tenants,
+            tenant_gcra: self.tenant_gcra,
+            resource_gcra: self.resource_gcra,
+            borrowing: self.borrowing,
+            clock: self.clock,
+            start: self.start,
+            middleware: PhantomData,
+        }
+    }
+
+    /// Lets resources that are out of capacity borrow from their tenant's unused capacity:
+    /// A cell that the resource's quota denies is let through anyway if it fits into the
+    /// given `fraction` (between 0 and 1) of the burst capacity that the tenant has left, so
+    /// that the tenant keeps the rest for its other resources.
+    ///
+    /// Borrowed cells only use up the tenant's capacity. The decision to borrow is made under
+    /// the same lock as the tenant's decision, and positive outcomes of borrowed cells are
+    /// those of the tenant decision; if a cell can't be borrowed, the negative outcome is the
+    /// resource's.
+    ///
+    /// # Example
+    /// ```rust
+    /// # use nonzero_ext::*;
+    /// use governor::{state::keyed::TenantRateLimiter, Quota};
+    ///
+    /// // Each endpoint gets 1 request per second, and may borrow half of what's left of the
+    /// // tenant's 8:
+    /// let lim = TenantRateLimiter::new(
+    ///     Quota::per_second(nonzero!(8u32)),
+    ///     Quota::per_second(nonzero!(1u32)),
+    /// )
+    /// .with_borrowing(0.5);
+    /// assert_eq!(Ok(()), lim.check_key(&"acme", &"/users"));
+    /// // Of the tenant's 7 remaining cells, a batch of 3 can be borrowed; then of 4, only 2:
+    /// assert_eq!(Ok(()), lim.check_key_n(&"acme", &"/users", nonzero!(3u32)));
+    /// assert!(lim.check_key_n(&"acme", &"/users", nonzero!(3u32)).is_err());
+    /// assert_eq!(Ok(()), lim.check_key_n(&"acme", &"/users", nonzero!(2u32)));
+    /// ```
+    pub fn with_borrowing(self, fraction: f64) -> Self {
+        let borrowing = if fraction.is_nan() {
+            0.0
+        } else {
+            fraction.clamp(0.0, 1.0)
+        };
+        TenantRateLimiter { borrowing, ..self }
+    }
+
+    /// Returns the fraction of their tenant's unused capacity that resources may borrow.
+    pub fn borrowing(&self) -> f64 {
+        self.borrowing
+    }
+
+    /// Returns the aggregate quota that each tenant is limited to.
+    pub fn tenant_quota(&self) -> Quota {
+        self.tenant_gcra.quota()
+    }
+
+    /// Returns the quota that each resource of a tenant is limited to.
+    pub fn resource_quota(&self) -> Quota {
+        self.resource_gcra.quota()
+    }
+
+    /// Runs `decide` on copies of the tenant's and the resource's states, and stores the
+    /// updated states if it returns a positive decision.
+    fn decide<P, E>(
+        &self,
+        tenant: &T,
+        resource: &R,
+        decide: impl FnOnce(&Cell<u64>, &Cell<u64>) -> Result<P, E>,
+    ) -> Result<P, E> {
+        let mut tenants = self.tenants.lock();
+        let existing = tenants.get_mut(tenant);
+        let tenant_tat = Cell::new(existing.as_ref().map_or(0, |s| s.tenant));
+        let resource_tat = Cell::new(
+            existing
+                .as_ref()
+                .and_then(|s| s.resources.get(resource).copied())
+                .unwrap_or(0),
+        );
+        let positive = decide(&tenant_tat, &resource_tat)?;
+        match existing {
+            Some(state) => {
+                state.tenant = tenant_tat.get();
+                match state.resources.get_mut(resource) {
+                    Some(tat) => *tat = resource_tat.get(),
+                    None => {
+                        state.resources.insert(resource.clone(), resource_tat.get());
+                    }
+                }
+            }
+            None => {
+                let mut state = TenantState {
+                    tenant: tenant_tat.get(),
+                    resources: HashMap::new(),
+                };
+                state.resources.insert(resource.clone(), resource_tat.get());
+                tenants.insert(tenant.clone(), state);
+            }
+        }
+        Ok(positive)
+    }
+
+    /// Returns whether a resource may borrow `n` cells from the tenant's state at `now`.
+    fn may_borrow(&self, tenant_tat: &Cell<u64>, now: C::Instant, n: u32) -> bool {
+        if self.borrowing == 0.0 {
+            return false;
+        }
+        let tat = NonZeroU64::new(tenant_tat.get()).map(|tat| Nanos::from(tat.get()));
+        let unused = self
+            .tenant_gcra
+            .remaining_burst_capacity(tat, now.duration_since(self.start));
+        f64::from(n) <= self.borrowing * f64::from(unused)
+    }
+
+    /// Allow a single cell through the rate limiter for the given resource of a tenant.
+    ///
+    /// If either the resource's or the tenant's rate limit is reached (and the cell can't be
+    /// [borrowed](#method.with_borrowing)), `check_key` returns information about the earliest
+    /// time that a cell might be allowed through again at that level.
+    pub fn check_key(
+        &self,
+        tenant: &T,
+        resource: &R,
+    ) -> Result<MW::PositiveOutcome, MW::NegativeOutcome> {
+        let now = self.clock.now();
+        self.decide(tenant, resource, |tenant_tat, resource_tat| {
+            let resource_decision = self.resource_gcra.test_and_update::<R, C::Instant, _, MW>(
+                self.start,
+                resource,
+                &Slot::new(resource_tat),
+                now,
+            );
+            let tenant_decision = |tenant_tat| {
+                self.tenant_gcra.test_and_update::<T, C::Instant, _, MW>(
+                    self.start,
+                    tenant,
+                    &Slot::new(tenant_tat),
+                    now,
+                )
+            };
+            match resource_decision {
+                Ok(positive) => tenant_decision(tenant_tat).map(|_| positive),
+                Err(_) if self.may_borrow(tenant_tat, now, 1) => tenant_decision(tenant_tat),
+                Err(negative) => Err(negative),
+            }
+        })
+    }
+
+    /// Allow *only all* `n` cells through the rate limiter for the given resource of a tenant.
+    ///
+    /// The batch must conform to both the resource's and the tenant's quota (or be
+    /// [borrowed](#method.with_borrowing) from the tenant); see
+    /// [`RateLimiter::check_key_n`](crate::RateLimiter::check_key_n) for how the decision is
+    /// reported.
+    pub fn check_key_n(
+        &self,
+        tenant: &T,
+        resource: &R,
+        n: NonZeroU32,
+    ) -> Result<MW::PositiveOutcome, NegativeMultiDecision<MW::NegativeOutcome>> {
+        let now = self.clock.now();
+        self.decide(tenant, resource, |tenant_tat, resource_tat| {
+            let resource_decision = self
+                .resource_gcra
+                .test_n_all_and_update::<R, C::Instant, _, MW>(
+                    self.start,
+                    resource,
+                    n,
+                    &Slot::new(resource_tat),
+                    now,
+                );
+            let tenant_decision = |tenant_tat| {
+                self.tenant_gcra
+                    .test_n_all_and_update::<T, C::Instant, _, MW>(
+                        self.start,
+                        tenant,
+                        n,
+                        &Slot::new(tenant_tat),
+                        now,
+                    )
+            };
+            match resource_decision {
+                Ok(positive) => tenant_decision(tenant_tat).map(|_| positive),
+                Err(_) if self.may_borrow(tenant_tat, now, n.get()) => tenant_decision(tenant_tat),
+                Err(negative) => Err(negative),
+            }
+        })
+    }
+
+    /// Removes the states of resources, and of tenants without resources, that are
+    /// indistinguishable from fresh ones.
+    ///
+    /// See [`RateLimiter::retain_recent`](crate::RateLimiter::retain_recent).
+    pub fn retain_recent(&self) {
+        let now = self.clock.now().duration_since(self.start);
+        let tenant_below: u64 = self.tenant_gcra.units_from_nanos(now).into();
+        let resource_below: u64 = self.resource_gcra.units_from_nanos(now).into();
+        self.tenants.lock().retain(|_, state| {
+            state.resources.retain(|_, tat| *tat > resource_below);
+            !state.resources.is_empty() || state.tenant > tenant_below
+        });
+    }
+
+    /// Returns the number of (tenant, resource) pairs that the rate limiter holds a state for.
+    pub fn len(&self) -> usize {
+        self.tenants
+            .lock()
+            .values()
+            .map(|state| state.resources.len())
+            .sum()
+    }
+
+    /// Returns `true` if the rate limiter holds no states.
+    pub fn is_empty(&self) -> bool {
+        self.tenants.lock().is_empty()
+    }
+
+    /// Returns the number of tenants that the rate limiter holds a state for.
+    pub fn tenants(&self) -> usize {
+        self.tenants.lock().len()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::clock::FakeRelativeClock;
+    use nonzero_ext::nonzero;
+    use std::time::Duration;
+
+    #[test]
+    fn tenant_limiter_impls() {
+        let lim = TenantRateLimiter::<u32, u32>::new(
+            Quota::per_second(nonzero!(2u32)),
+            Quota::per_second(nonzero!(1u32)),
+        );
+        assert!(!format!("{:?}", lim).is_empty());
+        assert_eq!(lim.tenant_quota(), Quota::per_second(nonzero!(2u32)));
+        assert_eq!(lim.resource_quota(), Quota::per_second(nonzero!(1u32)));
+    }
+
+    #[test]
+    fn denied_cells_leave_states_alone() {
+        let clock = FakeRelativeClock::default();
+        let lim = TenantRateLimiter::with_clock(
+            Quota::per_second(nonzero!(2u32)),
+            Quota::per_second(nonzero!(5u32)),
+            &clock,
+        );
+        assert_eq!(Ok(()), lim.check_key(&1, &"a"));
+        assert_eq!(Ok(()), lim.check_key(&1, &"b"));
+        // The tenant is out of capacity, so the new resource isn't recorded:
+        let denied = lim.check_key(&1, &"c").unwrap_err();
+        assert_eq!(denied.quota(), lim.tenant_quota());
+        assert_eq!(lim.len(), 2);
+
+        clock.advance(Duration::from_millis(500));
+        assert_eq!(Ok(()), lim.check_key(&1, &"c"));
+    }
+
+    #[test]
+    fn batches_conform_to_both_levels() {
+        let clock = FakeRelativeClock::default();
+        let lim = TenantRateLimiter::with_clock(
+            Quota::per_second(nonzero!(5u32)),
+            Quota::per_second(nonzero!(3u32)),
+            &clock,
+        );
+        assert_eq!(
+            Err(NegativeMultiDecision::InsufficientCapacity(3)),
+            lim.check_key_n(&1, &1, nonzero!(4u32))
+        );
+        assert_eq!(Ok(()), lim.check_key_n(&1, &1, nonzero!(3u32)));
+        assert!(matches!(
+            lim.check_key_n(&1, &2, nonzero!(3u32)),
+            Err(NegativeMultiDecision::BatchNonConforming(3, _))
+        ));
+        assert_eq!(Ok(()), lim.check_key_n(&1, &2, nonzero!(2u32)));
+    }
+
+    #[test]
+    fn borrows_from_the_tenant() {
+        let clock = FakeRelativeClock::default();
+        let lim = TenantRateLimiter::with_clock(
+            Quota::per_second(nonzero!(4u32)),
+            Quota::per_second(nonzero!(1u32)),
+            &clock,
+        )
+        .with_borrowing(0.5);
+        assert_eq!(lim.borrowing(), 0.5);
+        assert_eq!(Ok(()), lim.check_key(&1, &"a"));
+        // Half of the tenant's 3 remaining cells cover one borrowed cell, and half of the 2
+        // left after that another; but half of the last cell doesn't:
+        assert_eq!(Ok(()), lim.check_key(&1, &"a"));
+        assert_eq!(Ok(()), lim.check_key(&1, &"a"));
+        let denied = lim.check_key(&1, &"a").unwrap_err();
+        assert_eq!(denied.quota(), lim.resource_quota());
+        // The other resource still has its own capacity:
+        assert_eq!(Ok(()), lim.check_key(&1, &"b"));
+
+        assert_eq!(lim.with_borrowing(f64::NAN).borrowing(), 0.0);
+    }
+
+    #[test]
+    fn retains_recent_states() {
+        let clock = FakeRelativeClock::default();
+        let lim = TenantRateLimiter::with_clock(
+            Quota::per_second(nonzero!(1u32)),
+            Quota::per_second(nonzero!(10u32)),
+            &clock,
+        );
+        assert_eq!(Ok(()), lim.check_key(&1, &1));
+        assert_eq!(lim.tenants(), 1);
+
+        // The resource's state is fresh again, but the tenant's isn't:
+        clock.advance(Duration::from_millis(500));
+        lim.retain_recent();
+        assert_eq!(lim.len(), 0);
+        assert_eq!(lim.tenants(), 1);
+        assert_ne!(Ok(()), lim.check_key(&1, &2));
+
+        clock.advance(Duration::from_secs(2));
+        lim.retain_recent();
+        assert!(lim.is_empty());
+    }
+}
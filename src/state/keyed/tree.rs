@@ -0,0 +1,417 @@
+use crate::compat::prelude::*;
+
+use super::tenant::Slot;
+use crate::compat::collections::HashMap;
+use crate::gcra::{Gcra, NotUntil};
+use crate::nanos::Nanos;
+use crate::sync::Mutex;
+use crate::{clock, middleware::NoOpMiddleware, NegativeMultiDecision, Quota};
+use std::cell::Cell;
+use std::fmt;
+use std::hash::Hash;
+use std::num::NonZeroU32;
+
+/// A node of a [`LimiterTree`]: its quota and rate-limiting state, and its place in the tree.
+#[derive(Debug)]
+struct Node<N> {
+    parent: Option<N>,
+    children: Vec<N>,
+    gcra: Gcra,
+    tat: u64,
+}
+
+/// An error that occurs when changing the structure of a [`LimiterTree`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TreeError {
+    /// The node that was to be added is in the tree already.
+    DuplicateNode,
+
+    /// The node that the operation was about isn't in the tree (or, when adding a node, its
+    /// parent isn't).
+    UnknownNode,
+}
+
+impl fmt::Display for TreeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TreeError::DuplicateNode => write!(f, "node is in the tree already"),
+            TreeError::UnknownNode => write!(f, "node is not in the tree"),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for TreeError {}
+
+/// The negative outcome of a [`LimiterTree`]'s check.
+#[derive(Debug, PartialEq)]
+pub enum TreeDenied<N, P: clock::Reference> {
+    /// The node that was checked isn't in the tree.
+    UnknownNode,
+
+    /// The quota of a node (the first one on the way from the checked node to the root that
+    /// denied the cells) denied the cells, with its decision.
+    Denied(N, NegativeMultiDecision<NotUntil<P>>),
+}
+
+impl<N: fmt::Debug, P: clock::Reference> fmt::Display for TreeDenied<N, P> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TreeDenied::UnknownNode => write!(f, "node is not in the tree"),
+            TreeDenied::Denied(node, NegativeMultiDecision::BatchNonConforming(_, not_until)) => {
+                write!(f, "node {:?} is {}", node, not_until)
+            }
+            TreeDenied::Denied(node, NegativeMultiDecision::InsufficientCapacity(capacity)) => {
+                write!(
+                    f,
+                    "node {:?} has a capacity of only {} cells",
+                    node, capacity
+                )
+            }
+        }
+    }
+}
+
+/// A tree of rate limiters, in which each node has its own quota and a check for a node debits
+/// its cells from the node and from all of its ancestors, e.g. an organization, its teams and
+/// their users.
+///
+/// Each check walks from the checked node up to the root and lets the cells through only if
+/// every node's quota allows them: If any node denies them, no node's state is updated, so a
+/// busy user doesn't use up their team's capacity with cells that weren't allowed. All nodes
+/// are stored in one map, and each check is made under one lock.
+///
+/// # Performance
+///
+/// That lock is global for the tree: Checks for unrelated nodes of the tree (e.g. users of
+/// different organizations) serialize on it, as do changes to the tree's structure. Each check
+/// holds the lock for one decision per node on the path to the root, plus collecting that path
+/// (which allocates). Trees whose roots are independent of each other scale better as separate
+/// `LimiterTree`s, one per root.
+///
+/// Nodes can be [added](#method.add_node) and [removed](#method.remove_node), and their
+/// quotas [changed](#method.set_quota), at runtime.
+///
+/// # Example
+/// ```rust
+/// # use nonzero_ext::*;
+/// use governor::{state::keyed::{LimiterTree, TreeDenied}, Quota};
+///
+/// let tree = LimiterTree::new();
+/// tree.add_node("acme", None, Quota::per_second(nonzero!(3u32))).unwrap();
+/// tree.add_node("platform", Some(&"acme"), Quota::per_second(nonzero!(5u32))).unwrap();
+/// tree.add_node("alice", Some(&"platform"), Quota::per_second(nonzero!(2u32))).unwrap();
+/// tree.add_node("bob", Some(&"platform"), Quota::per_second(nonzero!(2u32))).unwrap();
+///
+/// assert_eq!(Ok(()), tree.check_n(&"alice", nonzero!(2u32)));
+/// assert_eq!(Ok(()), tree.check(&"bob"));
+/// // The organization is out of capacity:
+/// match tree.check(&"bob") {
+///     Err(TreeDenied::Denied(node, _)) => assert_eq!(node, "acme"),
+///     other => panic!("{:?}", other),
+/// }
+/// ```
+pub struct LimiterTree<N, C: clock::Clock = clock::DefaultClock> {
+    nodes: Mutex<HashMap<N, Node<N>>>,
+    clock: C,
+    start: C::Instant,
+}
+
+impl<N> LimiterTree<N>
+where
+    N: Hash + Eq + Clone,
+{
+    /// Constructs an empty tree.
+    pub fn new() -> Self {
+        let clock = clock::DefaultClock::default();
+        LimiterTree::with_clock(&clock)
+    }
+}
+
+impl<N> Default for LimiterTree<N>
+where
+    N: Hash + Eq + Clone,
+{
+    fn default() -> Self {
+        LimiterTree::new()
+    }
+}
+
+impl<N, C> LimiterTree<N, C>
+where
+    N: Hash + Eq + Clone,
+    C: clock::Clock,
+{
+    /// Constructs an empty tree with a custom clock.
+    pub fn with_clock(clock: &C) -> Self {
+        LimiterTree {
+            nodes: Mutex::new(HashMap::new()),
+            clock: clock.clone(),
+            start: clock.now(),
+        }
+    }
+
+    /// Adds a node with the given quota to the tree, as a child of `parent`, or as a root if
+    /// there is no parent.
+    ///
+    /// The node starts out with a fresh state; its cells are debited from its ancestors from
+    /// its first check on.
+    pub fn add_node(&self, node: N, parent: Option<&N>, quota: Quota) -> Result<(), TreeError> {
+        let mut nodes = self.nodes.lock();
+        if nodes.contains_key(&node) {
+            return Err(TreeError::DuplicateNode);
+        }
+        if let Some(parent) = parent {
+            nodes
+                .get_mut(parent)
+                .ok_or(TreeError::UnknownNode)?
+                .children
+                .push(node.clone());
+        }
+        nodes.insert(
+            node,
+            Node {
+                parent: parent.cloned(),
+                children: Vec::new(),
+                gcra: Gcra::new(quota),
+                tat: 0,
+            },
+        );
+        Ok(())
+    }
+
+    /// Removes a node and all of its descendants from the tree, returning the number of nodes
+    /// that were removed.
+    ///
+    /// The cells that the removed nodes used up stay debited from the remaining ancestors.
+    pub fn remove_node(&self, node: &N) -> Result<usize, TreeError> {
+        let mut nodes = self.nodes.lock();
+        let removed = nodes.remove(node).ok_or(TreeError::UnknownNode)?;
+        if let Some(parent) = removed.parent.as_ref().and_then(|p| nodes.get_mut(p)) {
+            parent.children.retain(|child| child != node);
+        }
+        let mut count = 1;
+        let mut orphans = removed.children;
+        while let Some(orphan) = orphans.pop() {
+            if let Some(removed) = nodes.remove(&orphan) {
+                count += 1;
+                orphans.extend(removed.children);
+            }
+        }
+        Ok(count)
+    }
+
+    /// Changes the quota of a node, keeping its state: Cells that it let through under the old
+    /// quota count against the new one.
+    pub fn set_quota(&self, node: &N, quota: Quota) -> Result<(), TreeError> {
+        let mut nodes = self.nodes.lock();
+        let node = nodes.get_mut(node).ok_or(TreeError::UnknownNode)?;
+        let gcra = Gcra::new(quota);
+        // The quotas' GCRAs may count in different units:
+        let tat = node.gcra.nanos_from_units(Nanos::from(node.tat));
+        node.tat = gcra.units_from_nanos(tat).into();
+        node.gcra = gcra;
+        Ok(())
+    }
+
+    /// Returns the quota of a node, or `None` if the node isn't in the tree.
+    pub fn quota(&self, node: &N) -> Option<Quota> {
+        self.nodes.lock().get(node).map(|node| node.gcra.quota())
+    }
+
+    /// Returns the parent of a node, or `None` if the node is a root or isn't in the tree.
+    pub fn parent(&self, node: &N) -> Option<N> {
+        self.nodes.lock().get(node)?.parent.clone()
+    }
+
+    /// Allows a single cell through the given node and all of its ancestors, or through none
+    /// of them.
+    ///
+    /// See [`check_n`](#method.check_n).
+    pub fn check(&self, node: &N) -> Result<(), TreeDenied<N, C::Instant>> {
+        self.check_n(node, NonZeroU32::MIN)
+    }
+
+    /// Allows `n` cells through the given node and all of its ancestors, or through none of
+    /// them.
+    ///
+    /// If a node's quota denies the cells, `check_n` returns that node's decision, as
+    /// [`RateLimiter::check_key_n`](crate::RateLimiter::check_key_n) would; nodes are checked
+    /// from the given node up to the root.
+    pub fn check_n(&self, node: &N, n: NonZeroU32) -> Result<(), TreeDenied<N, C::Instant>> {
+        let now = self.clock.now();
+        let mut nodes = self.nodes.lock();
+        let mut path = Vec::new();
+        let mut next = Some(node);
+        while let Some(key) = next {
+            let node = nodes.get(key).ok_or(TreeDenied::UnknownNode)?;
+            path.push((key, Cell::new(node.tat)));
+            next = node.parent.as_ref();
+        }
+        for (key, tat) in &path {
+            nodes[*key]
+                .gcra
+                .test_n_all_and_update::<N, C::Instant, _, NoOpMiddleware<C::Instant>>(
+                    self.start,
+                    key,
+                    n,
+                    &Slot::new(tat),
+                    now,
+                )
+                .map_err(|decision| TreeDenied::Denied((*key).clone(), decision))?;
+        }
+        let path: Vec<(N, u64)> = path
+            .into_iter()
+            .map(|(key, tat)| (key.clone(), tat.get()))
+            .collect();
+        for (key, tat) in path {
+            if let Some(node) = nodes.get_mut(&key) {
+                node.tat = tat;
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the number of nodes in the tree.
+    pub fn len(&self) -> usize {
+        self.nodes.lock().len()
+    }
+
+    /// Returns `true` if the tree has no nodes.
+    pub fn is_empty(&self) -> bool {
+        self.nodes.lock().is_empty()
+    }
+}
+
+impl<N, C> fmt::Debug for LimiterTree<N, C>
+where
+    N: Hash + Eq + Clone,
+    C: clock::Clock,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("LimiterTree")
+            .field("len", &self.len())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::clock::{Clock, FakeRelativeClock};
+    use nonzero_ext::nonzero;
+    use std::time::Duration;
+
+    fn tree(clock: &FakeRelativeClock) -> LimiterTree<&'static str, FakeRelativeClock> {
+        let tree = LimiterTree::with_clock(clock);
+        tree.add_node("org", None, Quota::per_second(nonzero!(4u32)))
+            .unwrap();
+        tree.add_node("team", Some(&"org"), Quota::per_second(nonzero!(3u32)))
+            .unwrap();
+        tree.add_node("user", Some(&"team"), Quota::per_second(nonzero!(2u32)))
+            .unwrap();
+        tree
+    }
+
+    #[test]
+    fn tree_impls() {
+        let clock = FakeRelativeClock::default();
+        let tree = tree(&clock);
+        assert!(!format!("{:?}", tree).is_empty());
+        assert_eq!(tree.len(), 3);
+        assert_eq!(tree.parent(&"user"), Some("team"));
+        assert_eq!(tree.parent(&"org"), None);
+        assert_eq!(tree.quota(&"team"), Some(Quota::per_second(nonzero!(3u32))));
+        assert_eq!(
+            tree.add_node("user", Some(&"org"), Quota::per_second(nonzero!(1u32))),
+            Err(TreeError::DuplicateNode)
+        );
+        assert_eq!(
+            tree.add_node("other", Some(&"nobody"), Quota::per_second(nonzero!(1u32))),
+            Err(TreeError::UnknownNode)
+        );
+        assert_eq!(tree.check(&"nobody"), Err(TreeDenied::UnknownNode));
+        assert!(!format!("{}", TreeError::UnknownNode).is_empty());
+        assert!(!format!("{}", TreeDenied::<&str, Nanos>::UnknownNode).is_empty());
+        assert!(LimiterTree::<u32>::default().is_empty());
+    }
+
+    #[test]
+    fn debits_all_levels_or_none() {
+        let clock = FakeRelativeClock::default();
+        let tree = tree(&clock);
+        tree.add_node("peer", Some(&"team"), Quota::per_second(nonzero!(2u32)))
+            .unwrap();
+        assert_eq!(Ok(()), tree.check_n(&"user", nonzero!(2u32)));
+        let denied = tree.check(&"user").unwrap_err();
+        assert!(matches!(&denied, TreeDenied::Denied("user", _)));
+        assert!(format!("{}", denied).contains("user"));
+
+        // The team has one cell left, which the denied check didn't use up:
+        assert_eq!(Ok(()), tree.check(&"peer"));
+        assert!(matches!(
+            tree.check(&"peer"),
+            Err(TreeDenied::Denied("team", _))
+        ));
+        // The organization still has one cell left for itself:
+        assert_eq!(Ok(()), tree.check(&"org"));
+        assert!(matches!(
+            tree.check(&"org"),
+            Err(TreeDenied::Denied("org", _))
+        ));
+        assert!(matches!(
+            tree.check_n(&"user", nonzero!(3u32)),
+            Err(TreeDenied::Denied(
+                "user",
+                NegativeMultiDecision::InsufficientCapacity(2)
+            ))
+        ));
+
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(Ok(()), tree.check_n(&"user", nonzero!(2u32)));
+    }
+
+    #[test]
+    fn changes_at_runtime() {
+        let clock = FakeRelativeClock::default();
+        let tree = tree(&clock);
+        assert_eq!(Ok(()), tree.check_n(&"user", nonzero!(2u32)));
+        let larger = Quota::per_second(nonzero!(2u32)).allow_burst(nonzero!(3u32));
+        tree.set_quota(&"user", larger).unwrap();
+        assert_eq!(tree.quota(&"user"), Some(larger));
+        assert_eq!(Ok(()), tree.check(&"user"));
+        let wait = |denied| match denied {
+            Err(TreeDenied::Denied("user", NegativeMultiDecision::BatchNonConforming(1, n))) => {
+                n.wait_time_from(clock.now())
+            }
+            other => panic!("{:?}", other),
+        };
+        // The user's burst of 3 is used up:
+        let before = wait(tree.check(&"user"));
+
+        // With the same tolerance in other units, the user has to wait just as long:
+        let other_units = Quota::per_second(nonzero!(6u32)).allow_burst(nonzero!(9u32));
+        tree.set_quota(&"user", other_units).unwrap();
+        assert_eq!(wait(tree.check(&"user")), before);
+
+        assert_eq!(tree.remove_node(&"team"), Ok(2));
+        assert_eq!(tree.remove_node(&"team"), Err(TreeError::UnknownNode));
+        assert_eq!(tree.len(), 1);
+        assert_eq!(
+            tree.set_quota(&"user", Quota::per_second(nonzero!(1u32))),
+            Err(TreeError::UnknownNode)
+        );
+        // The removed nodes' cells stay debited from the organization:
+        assert_eq!(Ok(()), tree.check(&"org"));
+        assert!(tree.check(&"org").is_err());
+
+        tree.add_node("team", Some(&"org"), Quota::per_second(nonzero!(3u32)))
+            .unwrap();
+        assert!(matches!(
+            tree.check(&"team"),
+            Err(TreeDenied::Denied("org", _))
+        ));
+        assert_eq!(tree.remove_node(&"org"), Ok(2));
+        assert!(tree.is_empty());
+    }
+}
@@ -0,0 +1,266 @@
+//! Checking cells against several rate limiters at once.
+
+use crate::hash::fnv1a;
+use crate::state::keyed::KeyedStateStore;
+use crate::state::{DirectStateStore, NotKeyed};
+use crate::{clock, middleware::RateLimitingMiddleware, NegativeMultiDecision, RateLimiter};
+use nonzero_ext::nonzero;
+use std::hash::Hash;
+use std::num::NonZeroU32;
+
+/// The position of a rate limiter (and key) in the canonical order that [`all_of`] debits
+/// cells in.
+///
+/// Rate limiters are ordered by their address in memory, and the states of keyed rate limiters
+/// by the hash of their key after that; the order is the same for every combination that
+/// contains them, regardless of the order that they were passed to [`all_of`] in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct DebitOrder {
+    limiter: usize,
+    key: u64,
+}
+
+impl DebitOrder {
+    fn of<T: ?Sized>(limiter: &T, key: u64) -> Self {
+        DebitOrder {
+            limiter: limiter as *const T as *const u8 as usize,
+            key,
+        }
+    }
+}
+
+/// A rate limiter (or a keyed rate limiter together with a key) that cells can be debited from
+/// and refunded to.
+///
+/// This is implemented by references to direct rate limiters, and by `(&limiter, &key)` pairs
+/// for keyed rate limiters, so they can be combined using [`all_of`].
+pub trait Debit {
+    /// The negative outcome of a rate-limiting decision.
+    type NegativeOutcome;
+
+    /// Allows a single cell through the rate limiter; see
+    /// [`RateLimiter::check`](crate::RateLimiter::check).
+    fn debit(&self) -> Result<(), Self::NegativeOutcome>;
+
+    /// Allows *only all* `n` cells through the rate limiter; see
+    /// [`RateLimiter::check_n`](crate::RateLimiter::check_n).
+    fn debit_n(&self, n: NonZeroU32) -> Result<(), NegativeMultiDecision<Self::NegativeOutcome>>;
+
+    /// Returns `n` cells that were let through to the rate limiter, as if they had never been
+    /// let through.
+    fn refund(&self, n: NonZeroU32);
+
+    /// Returns the rate limiter's position in the canonical order that [`all_of`] debits cells
+    /// in; by default, the order of its address in memory.
+    ///
+    /// Implementations that forward to another `Debit` must forward this too, so that the
+    /// order is the same no matter how a rate limiter is passed to [`all_of`].
+    fn debit_order(&self) -> DebitOrder {
+        DebitOrder::of(self, 0)
+    }
+}
+
+impl<T: Debit + ?Sized> Debit for &T {
+    type NegativeOutcome = T::NegativeOutcome;
+
+    fn debit(&self) -> Result<(), Self::NegativeOutcome> {
+        (**self).debit()
+    }
+
+    fn debit_n(&self, n: NonZeroU32) -> Result<(), NegativeMultiDecision<Self::NegativeOutcome>> {
+        (**self).debit_n(n)
+    }
+
+    fn refund(&self, n: NonZeroU32) {
+        (**self).refund(n)
+    }
+
+    fn debit_order(&self) -> DebitOrder {
+        (**self).debit_order()
+    }
+}
+
+impl<S, C, MW> Debit for RateLimiter<NotKeyed, S, C, MW>
+where
+    S: DirectStateStore,
+    C: clock::Clock,
+    MW: RateLimitingMiddleware<C::Instant>,
+{
+    type NegativeOutcome = MW::NegativeOutcome;
+
+    fn debit(&self) -> Result<(), Self::NegativeOutcome> {
+        self.check().map(|_| ())
+    }
+
+    fn debit_n(&self, n: NonZeroU32) -> Result<(), NegativeMultiDecision<Self::NegativeOutcome>> {
+        self.check_n(n).map(|_| ())
+    }
+
+    fn refund(&self, n: NonZeroU32) {
+        self.refund_cells(&NotKeyed::NonKey, None, n.get());
+    }
+}
+
+impl<K, S, C, MW> Debit for (&RateLimiter<K, S, C, MW>, &K)
+where
+    K: Hash,
+    S: KeyedStateStore<K>,
+    C: clock::Clock,
+    MW: RateLimitingMiddleware<C::Instant>,
+{
+    type NegativeOutcome = MW::NegativeOutcome;
+
+    fn debit(&self) -> Result<(), Self::NegativeOutcome> {
+        self.0.check_key(self.1).map(|_| ())
+    }
+
+    fn debit_n(&self, n: NonZeroU32) -> Result<(), NegativeMultiDecision<Self::NegativeOutcome>> {
+        self.0.check_key_n(self.1, n).map(|_| ())
+    }
+
+    fn refund(&self, n: NonZeroU32) {
+        self.0.refund_cells(self.1, Some(fnv1a(self.1)), n.get());
+    }
+
+    fn debit_order(&self) -> DebitOrder {
+        DebitOrder::of(self.0, fnv1a(self.1))
+    }
+}
+
+/// Collections of rate limiters that [`all_of`] can combine.
+///
+/// This is implemented for tuples of up to eight [`Debit`]s with the same negative outcome
+/// type.
+pub trait DebitAll {
+    /// The negative outcome of the rate limiters' decisions.
+    type NegativeOutcome;
+
+    /// Calls `f` with all the rate limiters in the collection, sorted by their
+    /// [`debit_order`](Debit::debit_order).
+    fn with_members<T>(
+        &self,
+        f: impl FnOnce(&[&dyn Debit<NegativeOutcome = Self::NegativeOutcome>]) -> T,
+    ) -> T;
+}
+
+macro_rules! impl_debit_all {
+    ($first:ident $(, $name:ident)*) => {
+        impl<$first: Debit, $($name: Debit<NegativeOutcome = $first::NegativeOutcome>),*> DebitAll
+            for ($first, $($name,)*)
+        {
+            type NegativeOutcome = $first::NegativeOutcome;
+
+            #[allow(non_snake_case)]
+            fn with_members<T>(
+                &self,
+                f: impl FnOnce(&[&dyn Debit<NegativeOutcome = Self::NegativeOutcome>]) -> T,
+            ) -> T {
+                let ($first, $($name,)*) = self;
+                let mut members = [
+                    $first as &dyn Debit<NegativeOutcome = Self::NegativeOutcome>,
+                    $($name),*
+                ];
+                members.sort_unstable_by_key(|member| member.debit_order());
+                f(&members)
+            }
+        }
+    };
+}
+
+impl_debit_all!(A);
+impl_debit_all!(A, B);
+impl_debit_all!(A, B, C);
+impl_debit_all!(A, B, C, D);
+impl_debit_all!(A, B, C, D, E);
+impl_debit_all!(A, B, C, D, E, F);
+impl_debit_all!(A, B, C, D, E, F, G);
+impl_debit_all!(A, B, C, D, E, F, G, H);
+
+/// Combines several rate limiters, so that cells are only let through if all of them allow it.
+///
+/// See [`MultiLimiter`].
+pub fn all_of<L: DebitAll>(limiters: L) -> MultiLimiter<L> {
+    MultiLimiter { limiters }
+}
+
+/// Several rate limiters that cells are checked against together, constructed using [`all_of`].
+///
+/// A cell is let through only if every rate limiter allows it. The rate limiters are checked one
+/// after the other; if one of them denies the cell, the cell is refunded to the rate limiters
+/// that already let it through, so a cell that is denied by e.g. a per-user limit doesn't use
+/// up the global limit's capacity.
+///
+/// Refunds happen after the fact, so concurrent decisions on the same rate limiters may see
+/// their capacity reduced for a moment, and deny cells that would have been allowed otherwise.
+/// Refunds also don't undo a [cooldown](crate::Quota::with_cooldown) that a cell triggered.
+///
+/// # Debit order
+///
+/// No lock is held across rate limiters: Each debit completes (taking and releasing whatever
+/// lock its state store uses) before the next one starts, so combinations can't deadlock. To
+/// keep combinations with overlapping rate limiters from starving each other, too, cells are
+/// always debited in one canonical [order](DebitOrder), not in the order that the rate
+/// limiters were passed in: If `all_of((&a, &b))` and `all_of((&b, &a))` race for the last
+/// cell of each, both debit `a` first, so one of them gets both cells instead of each getting
+/// one, denying the other and refunding its own. When a cell is denied by several rate
+/// limiters, the negative outcome is that of the first one in the canonical order.
+///
+/// # Example
+/// ```rust
+/// # #[cfg(feature = "std")] fn main() {
+/// # use nonzero_ext::*;
+/// use governor::{all_of, Quota, RateLimiter};
+///
+/// let global = RateLimiter::direct(Quota::per_second(nonzero!(100u32)));
+/// let per_user = RateLimiter::keyed(Quota::per_second(nonzero!(1u32)));
+///
+/// assert_eq!(Ok(()), all_of((&global, (&per_user, &"alice"))).check());
+/// assert_ne!(Ok(()), all_of((&global, (&per_user, &"alice"))).check());
+/// assert_eq!(Ok(()), all_of((&global, (&per_user, &"bob"))).check());
+/// # } #[cfg(not(feature = "std"))] fn main() {}
+/// ```
+#[derive(Debug, Clone, Copy)]
+pub struct MultiLimiter<L> {
+    limiters: L,
+}
+
+impl<L: DebitAll> MultiLimiter<L> {
+    /// Allows a single cell through all the rate limiters, or none of them.
+    ///
+    /// If a rate limiter denies the cell, its negative outcome is returned.
+    pub fn check(&self) -> Result<(), L::NegativeOutcome> {
+        self.limiters.with_members(|members| {
+            for (i, member) in members.iter().enumerate() {
+                if let Err(negative) = member.debit() {
+                    for debited in &members[..i] {
+                        debited.refund(nonzero!(1u32));
+                    }
+                    return Err(negative);
+                }
+            }
+            Ok(())
+        })
+    }
+
+    /// Allows *only all* `n` cells through all the rate limiters, or none of them.
+    ///
+    /// See [`RateLimiter::check_n`](crate::RateLimiter::check_n) for the ways this can fail.
+    pub fn check_n(&self, n: NonZeroU32) -> Result<(), NegativeMultiDecision<L::NegativeOutcome>> {
+        self.limiters.with_members(|members| {
+            for (i, member) in members.iter().enumerate() {
+                if let Err(negative) = member.debit_n(n) {
+                    for debited in &members[..i] {
+                        debited.refund(n);
+                    }
+                    return Err(negative);
+                }
+            }
+            Ok(())
+        })
+    }
+
+    /// Returns the combined rate limiters.
+    pub fn into_inner(self) -> L {
+        self.limiters
+    }
+}
@@ -0,0 +1,293 @@
+//! Rate limiters that space out the cells of a burst.
+
+use crate::compat::prelude::*;
+
+use crate::clock::{self, Reference};
+use crate::gcra::Gcra;
+use crate::middleware::{RateLimitingMiddleware, StateSnapshot};
+use crate::nanos::Nanos;
+use crate::state::keyed::{KeyedStateStore, ShrinkableKeyedStateStore};
+use crate::state::{DirectStateStore, NotKeyed, StateStore};
+use crate::{NegativeMultiDecision, Quota, RateLimiter};
+use std::fmt;
+use std::hash::Hash;
+use std::num::NonZeroU32;
+use std::time::Duration;
+
+/// A rate limiter that paces the cells it lets through: Even while the burst capacity would
+/// let cells through right away, consecutive cells (of the same key) are spaced by at least a
+/// minimum gap.
+///
+/// This combines the wrapped rate limiter's tolerance for bursts, which lets a client that was
+/// quiet for a while catch up, with pacing for downstreams that are sensitive to
+/// micro-bursts: A burst is spread out over an interval of one gap per cell instead of arriving
+/// all at once. A batch of `n` cells counts as `n` paced cells, so the next cell waits `n`
+/// gaps after it.
+///
+/// A cell is let through if it is both paced and conforms to the wrapped rate limiter; cells
+/// that arrive too early don't use up any capacity. Negative outcomes of cells that arrived too
+/// early come from a quota of one cell per gap, so that
+/// [`NotUntil::quota`](crate::NotUntil::quota) tells them apart from the wrapped rate limiter's.
+///
+/// The pacing states are kept in a second state store of the wrapped rate limiter's type.
+///
+/// # Example
+/// ```rust
+/// # use nonzero_ext::*;
+/// use governor::{clock::FakeRelativeClock, Quota, RateLimiter};
+/// use std::time::Duration;
+///
+/// let clock = FakeRelativeClock::default();
+/// // Bursts of up to 10 cells, spaced 10ms apart:
+/// let lim = RateLimiter::direct_with_clock(Quota::per_second(nonzero!(10u32)), &clock)
+///     .into_paced(Duration::from_millis(10));
+/// assert_eq!(lim.check(), Ok(()));
+/// let negative = lim.check().unwrap_err();
+/// assert_eq!(negative.quota().replenish_interval(), Duration::from_millis(10));
+/// clock.advance(Duration::from_millis(10));
+/// assert_eq!(lim.check(), Ok(()));
+/// ```
+pub struct PacedLimiter<K, S, C, MW>
+where
+    S: StateStore<Key = K>,
+    C: clock::Clock,
+    MW: RateLimitingMiddleware<C::Instant>,
+{
+    limiter: RateLimiter<K, S, C, MW>,
+    /// The earliest time (in nanoseconds since the rate limiter's start) that the next cell may
+    /// be let through at.
+    pacing: S,
+    /// One cell per gap, which describes the pacing in negative outcomes.
+    gap: Gcra,
+}
+
+impl<K, S, C, MW> PacedLimiter<K, S, C, MW>
+where
+    S: StateStore<Key = K> + Default,
+    C: clock::Clock,
+    MW: RateLimitingMiddleware<C::Instant>,
+{
+    /// Wraps a rate limiter, spacing the cells it lets through by at least `gap`.
+    ///
+    /// Gaps are kept to between one nanosecond and the ~584 years that rate limiters can keep
+    /// track of.
+    pub fn new(limiter: RateLimiter<K, S, C, MW>, gap: Duration) -> Self {
+        let gap = Nanos::from(gap).as_u64().max(1);
+        let quota = Quota::with_period(Duration::from_nanos(gap)).unwrap();
+        PacedLimiter {
+            limiter,
+            pacing: S::default(),
+            gap: Gcra::new(quota),
+        }
+    }
+}
+
+impl<K, S, C, MW> PacedLimiter<K, S, C, MW>
+where
+    S: StateStore<Key = K>,
+    C: clock::Clock,
+    MW: RateLimitingMiddleware<C::Instant>,
+{
+    /// Returns the minimum gap between cells.
+    pub fn gap(&self) -> Duration {
+        self.gap.quota().replenish_interval()
+    }
+
+    /// Returns the wrapped rate limiter.
+    pub fn limiter(&self) -> &RateLimiter<K, S, C, MW> {
+        &self.limiter
+    }
+
+    /// Returns the wrapped rate limiter, dropping the pacing states.
+    pub fn into_inner(self) -> RateLimiter<K, S, C, MW> {
+        self.limiter
+    }
+
+    /// Lets `n` cells for `key` through at `now` if they are paced and `decide` lets them
+    /// through too; otherwise, leaves the pacing state as it was.
+    fn pace<T, E>(
+        &self,
+        key: &K,
+        n: u32,
+        now: C::Instant,
+        decide: impl FnOnce() -> Result<T, E>,
+    ) -> Result<Result<T, E>, MW::NegativeOutcome> {
+        let t0 = self
+            .gap
+            .units_from_nanos(now.duration_since(self.limiter.start));
+        let next = t0 + self.gap.t() * u64::from(n);
+        let prev = self
+            .pacing
+            .measure_and_replace(key, |earliest| match earliest {
+                Some(earliest) if t0 < earliest => Err(earliest),
+                _ => Ok((earliest, next)),
+            });
+        let prev = match prev {
+            Ok(prev) => prev,
+            Err(earliest) => {
+                let snapshot = StateSnapshot::new(self.gap, earliest);
+                return Err(MW::disallow(key, snapshot, self.limiter.start));
+            }
+        };
+        let decision = decide();
+        if decision.is_err() {
+            // Unless another cell was paced since, the next one may come as early as before:
+            self.pacing.adjust(key, |earliest| match earliest {
+                Some(earliest) if earliest == next => Some(prev.unwrap_or_else(|| Nanos::from(0))),
+                _ => None,
+            });
+        }
+        Ok(decision)
+    }
+}
+
+/// # Paced rate limiters - Manually checking cells
+impl<S, C, MW> PacedLimiter<NotKeyed, S, C, MW>
+where
+    S: DirectStateStore,
+    C: clock::Clock,
+    MW: RateLimitingMiddleware<C::Instant>,
+{
+    /// Allow a single cell through the rate limiter, if it is paced.
+    ///
+    /// See [`RateLimiter::check`].
+    pub fn check(&self) -> Result<MW::PositiveOutcome, MW::NegativeOutcome> {
+        let now = self.limiter.clock.now();
+        self.pace(&NotKeyed::NonKey, 1, now, || self.limiter.check())?
+    }
+
+    /// Allow *only all* `n` cells through the rate limiter, if the first of them is paced.
+    ///
+    /// See [`RateLimiter::check_n`].
+    pub fn check_n(
+        &self,
+        n: NonZeroU32,
+    ) -> Result<MW::PositiveOutcome, NegativeMultiDecision<MW::NegativeOutcome>> {
+        let now = self.limiter.clock.now();
+        self.pace(&NotKeyed::NonKey, n.get(), now, || self.limiter.check_n(n))
+            .map_err(|negative| NegativeMultiDecision::BatchNonConforming(n.get(), negative))?
+    }
+}
+
+/// # Keyed paced rate limiters - Manually checking cells
+impl<K, S, C, MW> PacedLimiter<K, S, C, MW>
+where
+    S: KeyedStateStore<K>,
+    K: Hash,
+    C: clock::Clock,
+    MW: RateLimitingMiddleware<C::Instant>,
+{
+    /// Allow a single cell through the rate limiter for the given key, if it is paced.
+    ///
+    /// See [`RateLimiter::check_key`].
+    pub fn check_key(&self, key: &K) -> Result<MW::PositiveOutcome, MW::NegativeOutcome> {
+        let now = self.limiter.clock.now();
+        self.pace(key, 1, now, || self.limiter.check_key(key))?
+    }
+
+    /// Allow *only all* `n` cells through the rate limiter for the given key, if the first of
+    /// them is paced.
+    ///
+    /// See [`RateLimiter::check_key_n`].
+    pub fn check_key_n(
+        &self,
+        key: &K,
+        n: NonZeroU32,
+    ) -> Result<MW::PositiveOutcome, NegativeMultiDecision<MW::NegativeOutcome>> {
+        let now = self.limiter.clock.now();
+        self.pace(key, n.get(), now, || self.limiter.check_key_n(key, n))
+            .map_err(|negative| NegativeMultiDecision::BatchNonConforming(n.get(), negative))?
+    }
+}
+
+/// # Keyed paced rate limiters - Housekeeping
+impl<K, S, C, MW> PacedLimiter<K, S, C, MW>
+where
+    S: ShrinkableKeyedStateStore<K>,
+    K: Hash,
+    C: clock::Clock,
+    MW: RateLimitingMiddleware<C::Instant>,
+{
+    /// Removes the rate limiting and pacing states that are indistinguishable from fresh ones.
+    ///
+    /// See [`RateLimiter::retain_recent`].
+    pub fn retain_recent(&self) {
+        self.limiter.retain_recent();
+        let now = self.limiter.clock.now().duration_since(self.limiter.start);
+        self.pacing.retain_recent(self.gap.units_from_nanos(now));
+    }
+}
+
+impl<K, S, C, MW> fmt::Debug for PacedLimiter<K, S, C, MW>
+where
+    S: StateStore<Key = K>,
+    C: clock::Clock,
+    MW: RateLimitingMiddleware<C::Instant>,
+    RateLimiter<K, S, C, MW>: fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PacedLimiter")
+            .field("limiter", &self.limiter)
+            .field("gap", &self.gap())
+            .finish()
+    }
+}
+
+/// # Paced rate limiters
+impl<K, S, C, MW> RateLimiter<K, S, C, MW>
+where
+    S: StateStore<Key = K> + Default,
+    C: clock::Clock,
+    MW: RateLimitingMiddleware<C::Instant>,
+{
+    /// Wraps the rate limiter in a [`PacedLimiter`] that spaces the cells it lets through by
+    /// at least `gap`.
+    pub fn into_paced(self, gap: Duration) -> PacedLimiter<K, S, C, MW> {
+        PacedLimiter::new(self, gap)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::clock::{Clock, FakeRelativeClock};
+    use nonzero_ext::nonzero;
+
+    #[test]
+    fn spaces_out_bursts() {
+        let clock = FakeRelativeClock::default();
+        let lim = RateLimiter::direct_with_clock(Quota::per_second(nonzero!(3u32)), &clock)
+            .into_paced(Duration::from_millis(100));
+        assert_eq!(lim.gap(), Duration::from_millis(100));
+        assert_eq!(lim.check_n(nonzero!(2u32)), Ok(()));
+        clock.advance(Duration::from_millis(199));
+        assert!(matches!(
+            lim.check(),
+            Err(negative) if negative.wait_time_from(clock.now()) == Duration::from_millis(1)
+        ));
+        clock.advance(Duration::from_millis(1));
+        assert_eq!(lim.check(), Ok(()));
+        // The burst capacity is used up, which doesn't hold up the next paced cell:
+        clock.advance(Duration::from_millis(100));
+        let negative = lim.check().unwrap_err();
+        assert_eq!(negative.quota(), Quota::per_second(nonzero!(3u32)));
+        clock.advance(Duration::from_millis(34));
+        assert_eq!(lim.check(), Ok(()));
+        assert!(!format!("{:?}", lim).is_empty());
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn paces_each_key() {
+        let clock = FakeRelativeClock::default();
+        let lim = RateLimiter::hashmap_with_clock(Quota::per_second(nonzero!(5u32)), &clock)
+            .into_paced(Duration::from_millis(10));
+        assert_eq!(lim.check_key(&"a"), Ok(()));
+        assert!(lim.check_key_n(&"a", nonzero!(2u32)).is_err());
+        assert_eq!(lim.check_key_n(&"b", nonzero!(2u32)), Ok(()));
+        clock.advance(Duration::from_secs(2));
+        lim.retain_recent();
+        assert!(lim.limiter().is_empty());
+        assert_eq!(lim.into_inner().len(), 0);
+    }
+}
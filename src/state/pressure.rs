@@ -0,0 +1,211 @@
+//! Scaling a rate limiter's admissions down under external pressure.
+
+use crate::clock;
+use crate::middleware::RateLimitingMiddleware;
+use crate::state::{RateLimiter, StateStore};
+use crate::sync::{AtomicU32, Ordering};
+
+/// The fraction of the quota that a rate limiter currently admits, in units of
+/// `1/Pressure::FULL`.
+#[derive(Debug)]
+pub(crate) struct Pressure(AtomicU32);
+
+impl Pressure {
+    /// The fraction that admits the entire quota.
+    pub(crate) const FULL: u32 = 1 << 16;
+
+    pub(crate) fn load(&self) -> u32 {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    fn store(&self, fraction: f64) {
+        let admitted = if fraction >= 1.0 {
+            Pressure::FULL
+        } else if fraction * f64::from(Pressure::FULL) >= 1.0 {
+            (fraction * f64::from(Pressure::FULL)) as u32
+        } else {
+            // This includes NaN:
+            1
+        };
+        self.0.store(admitted, Ordering::Relaxed);
+    }
+}
+
+impl Default for Pressure {
+    fn default() -> Self {
+        Pressure(AtomicU32::new(Pressure::FULL))
+    }
+}
+
+/// # Load shedding
+///
+/// A rate limiter's effective quota can be scaled down at runtime by a factor fed from an
+/// external signal (like CPU usage or a queue's depth), without reconstructing the rate limiter
+/// or losing its state: Under pressure, each cell uses up proportionally more of the burst
+/// capacity, so admissions slow down smoothly (and speed up again once the pressure is lifted).
+///
+/// The pressure applies to the rate limiter it is set on and to its clones (like those of a
+/// [shared](#method.into_shared) rate limiter), which admit cells from the same state.
+impl<K, S, C, MW> RateLimiter<K, S, C, MW>
+where
+    S: StateStore<Key = K>,
+    C: clock::Clock,
+    MW: RateLimitingMiddleware<C::Instant>,
+{
+    /// Sets the fraction of the quota that the rate limiter admits: `1.0` (the default) admits
+    /// the entire quota, `0.6` admits cells at 60% of the quota's rate.
+    ///
+    /// Fractions are clamped to lie between `1/65536` and `1`. The burst capacity shrinks along
+    /// with the rate: Batches of cells that fit into the quota, but not into the shrunk burst
+    /// capacity, are reported as
+    /// [`InsufficientCapacity`](crate::NegativeMultiDecision::InsufficientCapacity) (with the
+    /// shrunk capacity) until the pressure is lifted.
+    ///
+    /// # Example
+    /// ```rust
+    /// # use nonzero_ext::*;
+    /// # use std::time::Duration;
+    /// use governor::{clock::FakeRelativeClock, Quota, RateLimiter};
+    ///
+    /// let clock = FakeRelativeClock::default();
+    /// let lim = RateLimiter::direct_with_clock(Quota::per_second(nonzero!(10u32)), &clock);
+    /// lim.set_pressure(0.5);
+    /// let mut allowed = 0;
+    /// for _ in 0..100 {
+    ///     clock.advance(Duration::from_millis(100));
+    ///     if lim.check().is_ok() {
+    ///         allowed += 1;
+    ///     }
+    /// }
+    /// // Half the burst capacity, then one cell every 200ms:
+    /// assert_eq!(allowed, 5 + 50);
+    /// ```
+    pub fn set_pressure(&self, fraction: f64) {
+        let tuning = self.tuning();
+        let before = tuning.pressure.load();
+        tuning.pressure.store(fraction);
+        if tuning.pressure.load() > before {
+            tuning.waiters.notify(None);
+        }
+    }
+
+    /// Returns the fraction of the quota that the rate limiter admits.
+    pub fn pressure(&self) -> f64 {
+        f64::from(self.admitted()) / f64::from(Pressure::FULL)
+    }
+
+    /// Returns the fraction of the quota that the rate limiter admits, in units of
+    /// `1/Pressure::FULL`.
+    pub(crate) fn admitted(&self) -> u32 {
+        self.tuned()
+            .map_or(Pressure::FULL, |tuning| tuning.pressure.load())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::clock::FakeRelativeClock;
+    use crate::{NegativeMultiDecision, Quota, RateLimiter};
+    use nonzero_ext::nonzero;
+    use std::time::Duration;
+
+    #[test]
+    fn pressure_scales_admissions() {
+        let clock = FakeRelativeClock::default();
+        let lim = RateLimiter::direct_with_clock(Quota::per_second(nonzero!(4u32)), &clock);
+        assert_eq!(lim.pressure(), 1.0);
+        let admitted = || {
+            (0..40)
+                .filter(|_| {
+                    clock.advance(Duration::from_millis(50));
+                    lim.check().is_ok()
+                })
+                .count()
+        };
+        // The first cells use up the burst capacity:
+        assert_eq!(admitted(), 11);
+        lim.set_pressure(0.5);
+        assert_eq!(lim.pressure(), 0.5);
+        assert_eq!(admitted(), 4);
+        lim.set_pressure(1.0);
+        assert_eq!(admitted(), 8);
+    }
+
+    #[test]
+    fn pressure_applies_to_clones() {
+        let clock = FakeRelativeClock::default();
+        let lim =
+            RateLimiter::direct_with_clock(Quota::per_second(nonzero!(4u32)), &clock).into_shared();
+        let clone = lim.clone();
+        lim.set_pressure(0.5);
+        assert_eq!(clone.pressure(), 0.5);
+        assert_eq!(
+            clone.check_n(nonzero!(3u32)),
+            Err(NegativeMultiDecision::InsufficientCapacity(2))
+        );
+        clone.set_pressure(1.0);
+        assert_eq!(lim.pressure(), 1.0);
+    }
+
+    #[test]
+    fn pressure_clamps() {
+        let clock = FakeRelativeClock::default();
+        let lim = RateLimiter::direct_with_clock(Quota::per_second(nonzero!(4u32)), &clock);
+        lim.set_pressure(2.0);
+        assert_eq!(lim.pressure(), 1.0);
+        lim.set_pressure(f64::NAN);
+        assert!(lim.pressure() > 0.0);
+        lim.set_pressure(-1.0);
+        assert!(lim.pressure() > 0.0);
+    }
+
+    #[test]
+    fn pressure_shrinks_batches() {
+        let clock = FakeRelativeClock::default();
+        let lim = RateLimiter::direct_with_clock(Quota::per_second(nonzero!(4u32)), &clock);
+        lim.set_pressure(0.5);
+        // Batches that don't fit into the halved capacity could never conform, not even from a
+        // fresh state:
+        assert_eq!(
+            lim.check_n(nonzero!(3u32)),
+            Err(NegativeMultiDecision::InsufficientCapacity(2))
+        );
+        assert_eq!(
+            lim.check_n(nonzero!(5u32)),
+            Err(NegativeMultiDecision::InsufficientCapacity(2))
+        );
+        assert_eq!(lim.check_n(nonzero!(2u32)), Ok(()));
+
+        lim.set_pressure(1.0);
+        clock.advance(Duration::from_secs(2));
+        assert_eq!(lim.check_n(nonzero!(4u32)), Ok(()));
+    }
+
+    #[test]
+    fn pressure_caps_batches_on_stale_states() {
+        let clock = FakeRelativeClock::default();
+        let lim = RateLimiter::direct_with_clock(Quota::per_second(nonzero!(4u32)), &clock);
+        assert_eq!(lim.check(), Ok(()));
+        clock.advance(Duration::from_secs(10));
+        lim.set_pressure(0.5);
+        assert_eq!(
+            lim.check_n(nonzero!(3u32)),
+            Err(NegativeMultiDecision::InsufficientCapacity(2))
+        );
+        assert_eq!(lim.check_n(nonzero!(2u32)), Ok(()));
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn pressure_ends_waits_for_oversized_batches() {
+        use crate::state::direct::InsufficientCapacity;
+        use futures::executor::block_on;
+
+        let lim = RateLimiter::direct(Quota::per_second(nonzero!(4u32)));
+        lim.set_pressure(0.5);
+        assert_eq!(
+            block_on(lim.until_n_ready(nonzero!(3u32))),
+            Err(InsufficientCapacity(2))
+        );
+    }
+}
@@ -0,0 +1,595 @@
+//! Rate limiters that record their decisions for replaying them later.
+
+use crate::compat::prelude::*;
+
+use crate::clock::{self, Reference};
+use crate::compat::collections::VecDeque;
+use crate::hash::fnv1a;
+use crate::middleware::RateLimitingMiddleware;
+use crate::nanos::Nanos;
+use crate::state::keyed::KeyedStateStore;
+use crate::state::{DirectStateStore, NotKeyed, StateStore};
+use crate::sync::Mutex;
+use crate::{NegativeMultiDecision, Quota, RateLimiter};
+use nonzero_ext::nonzero;
+use std::convert::TryFrom;
+use std::fmt;
+use std::hash::Hash;
+use std::num::{NonZeroU32, NonZeroUsize};
+use std::time::Duration;
+
+/// The outcome of a recorded decision.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Outcome {
+    /// The cells were let through.
+    Conforming,
+    /// The cells were rate-limited.
+    NonConforming,
+    /// The cells can never be let through at once.
+    InsufficientCapacity,
+}
+
+impl Outcome {
+    fn from_result<T, E>(decision: &Result<T, E>) -> Outcome {
+        match decision {
+            Ok(_) => Outcome::Conforming,
+            Err(_) => Outcome::NonConforming,
+        }
+    }
+
+    pub(crate) fn from_multi<T, E>(decision: &Result<T, NegativeMultiDecision<E>>) -> Outcome {
+        match decision {
+            Ok(_) => Outcome::Conforming,
+            Err(NegativeMultiDecision::BatchNonConforming(..)) => Outcome::NonConforming,
+            Err(NegativeMultiDecision::InsufficientCapacity(_)) => Outcome::InsufficientCapacity,
+        }
+    }
+}
+
+/// A decision made by a [`RecordingLimiter`], with the rate-limiting state of its key before and
+/// after the decision.
+///
+/// States are the raw values that the rate limiter's state store keeps: its key's theoretical
+/// arrival time, since the rate limiter's start, in the rate limiter's internal units of time.
+/// They are `None` if the state store had no state for the key.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DecisionRecord {
+    /// The hash of the key that the decision was made for (see [`DecisionLog::key_hash`]), or 0
+    /// for direct rate limiters.
+    pub key_hash: u64,
+    /// The time the decision was made at, since the rate limiter's start (counting the time
+    /// that [resets](crate::RateLimiter::reset) skipped as passed).
+    pub at: Duration,
+    /// The number of cells that were checked.
+    pub cells: NonZeroU32,
+    /// The rate limiter's [pressure](crate::RateLimiter::set_pressure) at the time.
+    pub pressure: f64,
+    /// The decision's outcome.
+    pub outcome: Outcome,
+    /// The key's state before the decision.
+    pub before: Option<u64>,
+    /// The key's state after the decision.
+    pub after: Option<u64>,
+}
+
+/// The decisions recorded by a [`RecordingLimiter`], together with its quota.
+///
+/// Logs are [encoded](#method.encode) in a compact binary format, so they can be dumped from a
+/// production process and [replayed](crate::testing::replay) in a test, to reproduce a
+/// throttling anomaly with the exact same states and times.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DecisionLog {
+    /// The quota of the rate limiter that made the decisions.
+    pub quota: Quota,
+    /// The [label](crate::RateLimiter::with_label) of the rate limiter that made the
+    /// decisions, if it has one. An empty label is encoded as no label.
+    pub label: Option<String>,
+    /// The recorded decisions, oldest first.
+    pub records: Vec<DecisionRecord>,
+}
+
+/// The reason that a [`DecisionLog`] couldn't be decoded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DecodeLogError {
+    /// The bytes don't start with a decision log's header.
+    NotALog,
+    /// The log was encoded in a format version that this version of governor doesn't know.
+    UnsupportedVersion(u8),
+    /// The log ends in the middle of its header or of a record.
+    Truncated,
+    /// The log contains a value that can't occur in a log.
+    Invalid,
+}
+
+impl fmt::Display for DecodeLogError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeLogError::NotALog => write!(f, "not a decision log"),
+            DecodeLogError::UnsupportedVersion(version) => {
+                write!(f, "unsupported decision log version {}", version)
+            }
+            DecodeLogError::Truncated => write!(f, "decision log is truncated"),
+            DecodeLogError::Invalid => write!(f, "decision log contains an invalid value"),
+        }
+    }
+}
+
+impl std::error::Error for DecodeLogError {}
+
+const MAGIC: &[u8; 4] = b"GVDL";
+const VERSION: u8 = 1;
+
+const HAS_BEFORE: u8 = 1 << 2;
+const HAS_AFTER: u8 = 1 << 3;
+
+struct Reader<'a>(&'a [u8]);
+
+impl Reader<'_> {
+    fn take<const N: usize>(&mut self) -> Result<[u8; N], DecodeLogError> {
+        if self.0.len() < N {
+            return Err(DecodeLogError::Truncated);
+        }
+        let (taken, rest) = self.0.split_at(N);
+        self.0 = rest;
+        Ok(<[u8; N]>::try_from(taken).unwrap())
+    }
+
+    fn u8(&mut self) -> Result<u8, DecodeLogError> {
+        Ok(self.take::<1>()?[0])
+    }
+
+    fn u32(&mut self) -> Result<u32, DecodeLogError> {
+        Ok(u32::from_le_bytes(self.take()?))
+    }
+
+    fn u64(&mut self) -> Result<u64, DecodeLogError> {
+        Ok(u64::from_le_bytes(self.take()?))
+    }
+
+    fn nonzero_u32(&mut self) -> Result<NonZeroU32, DecodeLogError> {
+        NonZeroU32::new(self.u32()?).ok_or(DecodeLogError::Invalid)
+    }
+
+    fn label(&mut self) -> Result<Option<String>, DecodeLogError> {
+        let len = usize::try_from(self.u32()?).map_err(|_| DecodeLogError::Invalid)?;
+        if self.0.len() < len {
+            return Err(DecodeLogError::Truncated);
+        }
+        let (label, rest) = self.0.split_at(len);
+        self.0 = rest;
+        let label = std::str::from_utf8(label).map_err(|_| DecodeLogError::Invalid)?;
+        Ok(Some(label.to_string()).filter(|label| !label.is_empty()))
+    }
+}
+
+impl DecisionLog {
+    /// Returns the hash that recorded decisions identify a key by.
+    ///
+    /// The hash (FNV-1a) is the same in every process and with every version of governor, so
+    /// keys can be looked up in logs that were recorded elsewhere.
+    pub fn key_hash<K: Hash>(key: &K) -> u64 {
+        fnv1a(key)
+    }
+
+    /// Encodes the log: a header with the quota and the label, followed by 41 bytes per
+    /// decision.
+    pub fn encode(&self) -> Vec<u8> {
+        let label = self.label.as_deref().unwrap_or_default().as_bytes();
+        let mut bytes = Vec::with_capacity(45 + label.len() + 41 * self.records.len());
+        bytes.extend_from_slice(MAGIC);
+        bytes.push(VERSION);
+        let period = u64::try_from(self.quota.replenish_period.as_nanos()).unwrap_or(u64::MAX);
+        let cooldown = u64::try_from(self.quota.cooldown.as_nanos()).unwrap_or(u64::MAX);
+        bytes.extend_from_slice(&period.to_le_bytes());
+        bytes.extend_from_slice(&self.quota.cells_per_period.get().to_le_bytes());
+        bytes.extend_from_slice(&self.quota.max_burst.get().to_le_bytes());
+        bytes.extend_from_slice(&cooldown.to_le_bytes());
+        bytes.extend_from_slice(&self.quota.overdraft.to_le_bytes());
+        bytes.extend_from_slice(&(label.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(label);
+        bytes.extend_from_slice(&(self.records.len() as u64).to_le_bytes());
+        for record in &self.records {
+            let at = u64::try_from(record.at.as_nanos()).unwrap_or(u64::MAX);
+            let pressure = (record.pressure * f64::from(super::Pressure::FULL)) as u32;
+            let mut flags = match record.outcome {
+                Outcome::Conforming => 0,
+                Outcome::NonConforming => 1,
+                Outcome::InsufficientCapacity => 2,
+            };
+            if record.before.is_some() {
+                flags |= HAS_BEFORE;
+            }
+            if record.after.is_some() {
+                flags |= HAS_AFTER;
+            }
+            bytes.extend_from_slice(&record.key_hash.to_le_bytes());
+            bytes.extend_from_slice(&at.to_le_bytes());
+            bytes.extend_from_slice(&record.cells.get().to_le_bytes());
+            bytes.extend_from_slice(&pressure.to_le_bytes());
+            bytes.push(flags);
+            bytes.extend_from_slice(&record.before.unwrap_or(0).to_le_bytes());
+            bytes.extend_from_slice(&record.after.unwrap_or(0).to_le_bytes());
+        }
+        bytes
+    }
+
+    /// Decodes a log that was [encoded](#method.encode) before.
+    pub fn decode(bytes: &[u8]) -> Result<DecisionLog, DecodeLogError> {
+        let mut reader = Reader(bytes);
+        if reader.take::<4>().ok().as_ref() != Some(MAGIC) {
+            return Err(DecodeLogError::NotALog);
+        }
+        match reader.u8()? {
+            VERSION => {}
+            version => return Err(DecodeLogError::UnsupportedVersion(version)),
+        }
+        let quota = Quota {
+            replenish_period: Duration::from_nanos(reader.u64()?),
+            cells_per_period: reader.nonzero_u32()?,
+            max_burst: reader.nonzero_u32()?,
+            cooldown: Duration::from_nanos(reader.u64()?),
+            overdraft: reader.u32()?,
+        };
+        if quota.replenish_period == Duration::from_nanos(0) {
+            return Err(DecodeLogError::Invalid);
+        }
+        let label = reader.label()?;
+        let len = reader.u64()?;
+        let mut records = Vec::with_capacity(usize::try_from(len).unwrap_or(0).min(1 << 16));
+        for _ in 0..len {
+            let key_hash = reader.u64()?;
+            let at = Duration::from_nanos(reader.u64()?);
+            let cells = reader.nonzero_u32()?;
+            let pressure = f64::from(reader.u32()?) / f64::from(super::Pressure::FULL);
+            let flags = reader.u8()?;
+            let before = reader.u64()?;
+            let after = reader.u64()?;
+            let outcome = match flags & 0b11 {
+                0 => Outcome::Conforming,
+                1 => Outcome::NonConforming,
+                2 => Outcome::InsufficientCapacity,
+                _ => return Err(DecodeLogError::Invalid),
+            };
+            records.push(DecisionRecord {
+                key_hash,
+                at,
+                cells,
+                pressure,
+                outcome,
+                before: Some(before).filter(|_| flags & HAS_BEFORE != 0),
+                after: Some(after).filter(|_| flags & HAS_AFTER != 0),
+            });
+        }
+        Ok(DecisionLog {
+            quota,
+            label,
+            records,
+        })
+    }
+}
+
+/// A rate limiter that records every decision it makes, with the state of the key before and
+/// after the decision, in a ring buffer of the most recent decisions.
+///
+/// The ring buffer can be [dumped](#method.dump) as a [`DecisionLog`] at any time, e.g. when
+/// the rate limiter throttles in unexpected ways in production, and
+/// [replayed](crate::testing::replay) through the test harness to reproduce the anomaly: Since
+/// the log has every decision's time, pressure and states, the replay makes the same decisions
+/// no matter how much of the rate limiter's history fell out of the ring buffer.
+///
+/// Decisions are serialized behind the ring buffer's lock, so that the recorded states and
+/// times are exactly the ones the decisions were made with.
+///
+/// # Example
+/// ```rust
+/// # use nonzero_ext::*;
+/// use governor::state::{DecisionLog, Outcome};
+/// use governor::{clock::FakeRelativeClock, Quota, RateLimiter};
+///
+/// let clock = FakeRelativeClock::default();
+/// let lim = RateLimiter::direct_with_clock(Quota::per_second(nonzero!(1u32)), &clock)
+///     .into_recording(nonzero!(100usize));
+/// assert!(lim.check().is_ok());
+/// assert!(lim.check().is_err());
+///
+/// let log = DecisionLog::decode(&lim.dump()).unwrap();
+/// assert_eq!(log.records.len(), 2);
+/// assert_eq!(log.records[1].outcome, Outcome::NonConforming);
+/// ```
+pub struct RecordingLimiter<K, S, C, MW>
+where
+    S: StateStore<Key = K>,
+    C: clock::Clock,
+    MW: RateLimitingMiddleware<C::Instant>,
+{
+    limiter: RateLimiter<K, S, C, MW>,
+    capacity: NonZeroUsize,
+    records: Mutex<VecDeque<DecisionRecord>>,
+}
+
+impl<K, S, C, MW> RecordingLimiter<K, S, C, MW>
+where
+    S: StateStore<Key = K>,
+    C: clock::Clock,
+    MW: RateLimitingMiddleware<C::Instant>,
+{
+    /// Wraps a rate limiter, recording up to `capacity` of its most recent decisions.
+    pub fn new(limiter: RateLimiter<K, S, C, MW>, capacity: NonZeroUsize) -> Self {
+        RecordingLimiter {
+            limiter,
+            capacity,
+            records: Mutex::new(VecDeque::with_capacity(capacity.get().min(1 << 16))),
+        }
+    }
+
+    /// Returns the recorded decisions, oldest first.
+    pub fn records(&self) -> Vec<DecisionRecord> {
+        self.records.lock().iter().copied().collect()
+    }
+
+    /// Returns the recorded decisions as a log.
+    pub fn log(&self) -> DecisionLog {
+        DecisionLog {
+            quota: self.limiter.gcra.quota(),
+            label: self.limiter.label().map(str::to_string),
+            records: self.records(),
+        }
+    }
+
+    /// Returns the [encoded](DecisionLog::encode) log of the recorded decisions.
+    pub fn dump(&self) -> Vec<u8> {
+        self.log().encode()
+    }
+
+    /// Forgets the recorded decisions.
+    pub fn clear(&self) {
+        self.records.lock().clear();
+    }
+
+    /// Returns the wrapped rate limiter.
+    pub fn limiter(&self) -> &RateLimiter<K, S, C, MW> {
+        &self.limiter
+    }
+
+    /// Returns the wrapped rate limiter.
+    pub fn into_inner(self) -> RateLimiter<K, S, C, MW> {
+        self.limiter
+    }
+
+    /// Makes a decision at the current time with `decide`, and records it.
+    fn record<T>(
+        &self,
+        key: &K,
+        key_hash: u64,
+        cells: NonZeroU32,
+        decide: impl FnOnce(C::Instant) -> (T, Outcome),
+    ) -> T {
+        let mut records = self.records.lock();
+        let before = self.limiter.raw_state(key);
+        let pressure = self.limiter.pressure();
+        let t0 = self.limiter.clock.now();
+        let (decision, outcome) = decide(t0);
+        let record = DecisionRecord {
+            key_hash,
+            at: (t0.duration_since(self.limiter.start) + self.limiter.epoch()).into(),
+            cells,
+            pressure,
+            outcome,
+            before,
+            after: self.limiter.raw_state(key),
+        };
+        if records.len() == self.capacity.get() {
+            records.pop_front();
+        }
+        records.push_back(record);
+        decision
+    }
+}
+
+/// # Recording rate limiters - Manually checking cells
+impl<S, C, MW> RecordingLimiter<NotKeyed, S, C, MW>
+where
+    S: DirectStateStore,
+    C: clock::Clock,
+    MW: RateLimitingMiddleware<C::Instant>,
+{
+    /// Allow a single cell through the rate limiter, recording the decision.
+    ///
+    /// See [`RateLimiter::check`].
+    pub fn check(&self) -> Result<MW::PositiveOutcome, MW::NegativeOutcome> {
+        self.record(&NotKeyed::NonKey, 0, nonzero!(1u32), |t0| {
+            let decision = self.limiter.check_key_at(&NotKeyed::NonKey, t0);
+            let outcome = Outcome::from_result(&decision);
+            (decision, outcome)
+        })
+    }
+
+    /// Allow *only all* `n` cells through the rate limiter, recording the decision.
+    ///
+    /// See [`RateLimiter::check_n`].
+    pub fn check_n(
+        &self,
+        n: NonZeroU32,
+    ) -> Result<MW::PositiveOutcome, NegativeMultiDecision<MW::NegativeOutcome>> {
+        self.record(&NotKeyed::NonKey, 0, n, |t0| {
+            let decision = self.limiter.check_key_n_at(&NotKeyed::NonKey, n, t0);
+            let outcome = Outcome::from_multi(&decision);
+            (decision, outcome)
+        })
+    }
+}
+
+/// # Keyed recording rate limiters - Manually checking cells
+impl<K, S, C, MW> RecordingLimiter<K, S, C, MW>
+where
+    S: KeyedStateStore<K>,
+    K: Hash,
+    C: clock::Clock,
+    MW: RateLimitingMiddleware<C::Instant>,
+{
+    /// Allow a single cell through the rate limiter for the given key, recording the decision.
+    ///
+    /// See [`RateLimiter::check_key`].
+    pub fn check_key(&self, key: &K) -> Result<MW::PositiveOutcome, MW::NegativeOutcome> {
+        let one = nonzero!(1u32);
+        self.record(key, DecisionLog::key_hash(key), one, |t0| {
+            let decision = self.limiter.check_key_at(key, t0);
+            let outcome = Outcome::from_result(&decision);
+            (decision, outcome)
+        })
+    }
+
+    /// Allow *only all* `n` cells through the rate limiter for the given key, recording the
+    /// decision.
+    ///
+    /// See [`RateLimiter::check_key_n`].
+    pub fn check_key_n(
+        &self,
+        key: &K,
+        n: NonZeroU32,
+    ) -> Result<MW::PositiveOutcome, NegativeMultiDecision<MW::NegativeOutcome>> {
+        self.record(key, DecisionLog::key_hash(key), n, |t0| {
+            let decision = self.limiter.check_key_n_at(key, n, t0);
+            let outcome = Outcome::from_multi(&decision);
+            (decision, outcome)
+        })
+    }
+}
+
+impl<K, S, C, MW> fmt::Debug for RecordingLimiter<K, S, C, MW>
+where
+    S: StateStore<Key = K>,
+    C: clock::Clock,
+    MW: RateLimitingMiddleware<C::Instant>,
+    RateLimiter<K, S, C, MW>: fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RecordingLimiter")
+            .field("limiter", &self.limiter)
+            .field("capacity", &self.capacity)
+            .field("recorded", &self.records.lock().len())
+            .finish()
+    }
+}
+
+/// # Recording rate limiters
+impl<K, S, C, MW> RateLimiter<K, S, C, MW>
+where
+    S: StateStore<Key = K>,
+    C: clock::Clock,
+    MW: RateLimitingMiddleware<C::Instant>,
+{
+    /// Wraps the rate limiter in a [`RecordingLimiter`] that records up to `capacity` of its
+    /// most recent decisions.
+    pub fn into_recording(self, capacity: NonZeroUsize) -> RecordingLimiter<K, S, C, MW> {
+        RecordingLimiter::new(self, capacity)
+    }
+
+    /// Returns the raw state that the state store keeps for the key, without changing it.
+    pub(crate) fn raw_state(&self, key: &K) -> Option<u64> {
+        self.state.peek(key).map(Nanos::as_u64)
+    }
+
+    /// Replaces the raw state that the state store keeps for the key, if there is a state.
+    #[cfg(feature = "testing")]
+    pub(crate) fn set_raw_state(&self, key: &K, state: Option<u64>) {
+        if let Some(state) = state {
+            self.state.adjust(key, |_| Some(Nanos::from(state)));
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::clock::FakeRelativeClock;
+    use crate::state::keyed::HashMapStateStore;
+    use crate::{middleware::NoOpMiddleware, Quota};
+    use nonzero_ext::nonzero;
+
+    #[test]
+    fn records_decisions_in_a_ring() {
+        let clock = FakeRelativeClock::default();
+        let lim: RateLimiter<_, HashMapStateStore<_>, _, NoOpMiddleware<_>> = RateLimiter::new(
+            Quota::per_second(nonzero!(2u32)),
+            Default::default(),
+            &clock,
+        );
+        let lim = lim.into_recording(nonzero!(3usize));
+        assert!(lim.check_key(&"a").is_ok());
+        clock.advance(Duration::from_millis(100));
+        assert!(lim.check_key_n(&"a", nonzero!(2u32)).is_err());
+        assert!(lim.check_key_n(&"b", nonzero!(3u32)).is_err());
+        lim.limiter().set_pressure(0.5);
+        assert!(lim.check_key(&"b").is_ok());
+
+        let records = lim.records();
+        assert_eq!(records.len(), 3);
+        let first = records[0];
+        assert_eq!(first.key_hash, DecisionLog::key_hash(&"a"));
+        // Key hashes are stable, so logs can be searched for keys anywhere:
+        assert_eq!(DecisionLog::key_hash(&"uploads"), 0x769e_bbb6_cd34_4986);
+        assert_eq!(first.at, Duration::from_millis(100));
+        assert_eq!(first.outcome, Outcome::NonConforming);
+        assert!(first.before.is_some());
+        assert_eq!(first.before, first.after);
+        assert_eq!(records[1].outcome, Outcome::InsufficientCapacity);
+        assert_eq!(records[1].before, None);
+        assert_eq!(records[2].outcome, Outcome::Conforming);
+        assert_eq!(records[2].pressure, 0.5);
+        assert!(records[2].after.is_some());
+        assert!(format!("{:?}", lim).contains("RecordingLimiter"));
+    }
+
+    #[test]
+    fn log_roundtrips() {
+        let clock = FakeRelativeClock::default();
+        let quota = Quota::per_second(nonzero!(3u32))
+            .with_cooldown(Duration::from_secs(1))
+            .with_overdraft(2);
+        let lim = RateLimiter::direct_with_clock(quota, &clock).into_recording(nonzero!(10usize));
+        for _ in 0..5 {
+            let _ = lim.check();
+        }
+        let log = lim.log();
+        assert_eq!(log.quota, quota);
+        assert_eq!(DecisionLog::decode(&log.encode()).as_ref(), Ok(&log));
+
+        let bytes = lim.dump();
+        assert_eq!(bytes.len(), 45 + 41 * 5);
+        assert_eq!(
+            DecisionLog::decode(&bytes[..bytes.len() - 1]),
+            Err(DecodeLogError::Truncated)
+        );
+        assert_eq!(DecisionLog::decode(b"nope"), Err(DecodeLogError::NotALog));
+        let mut future = bytes;
+        future[4] = 99;
+        assert_eq!(
+            DecisionLog::decode(&future),
+            Err(DecodeLogError::UnsupportedVersion(99))
+        );
+        lim.clear();
+        assert!(lim.records().is_empty());
+    }
+
+    #[test]
+    fn log_carries_the_label() {
+        let clock = FakeRelativeClock::default();
+        let lim = RateLimiter::direct_with_clock(Quota::per_second(nonzero!(1u32)), &clock)
+            .with_label("uploads")
+            .into_recording(nonzero!(10usize));
+        let _ = lim.check();
+        let log = lim.log();
+        assert_eq!(log.label.as_deref(), Some("uploads"));
+        let bytes = log.encode();
+        assert_eq!(bytes.len(), 45 + "uploads".len() + 41);
+        assert_eq!(DecisionLog::decode(&bytes).as_ref(), Ok(&log));
+        assert_eq!(
+            DecisionLog::decode(&bytes[..40]),
+            Err(DecodeLogError::Truncated)
+        );
+        let mut invalid = bytes;
+        invalid[37] = 0xff;
+        assert_eq!(DecisionLog::decode(&invalid), Err(DecodeLogError::Invalid));
+    }
+}
@@ -0,0 +1,227 @@
+//! Keyed rate limiters that protect caches from stampedes of refreshes.
+
+use crate::compat::prelude::*;
+
+use crate::jitter::Jitter;
+use crate::middleware::{NoOpMiddleware, RateLimitingMiddleware};
+use crate::state::keyed::{DefaultKeyedStateStore, KeyedStateStore};
+use crate::state::StateStore;
+use crate::sync::{AtomicU64, Ordering};
+use crate::{clock, Quota, RateLimiter};
+use std::fmt;
+use std::hash::Hash;
+use std::num::NonZeroU32;
+use std::time::Duration;
+
+/// A [`RefreshGate`]'s decision about refreshing a cache entry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Refresh {
+    /// Refresh the entry now.
+    Now,
+
+    /// Don't refresh the entry: Serve the stale entry instead, since the key was refreshed as
+    /// often as the gate allows already.
+    ServeStale,
+}
+
+impl Refresh {
+    /// Returns whether the entry should be refreshed now.
+    pub fn is_now(self) -> bool {
+        self == Refresh::Now
+    }
+}
+
+/// A keyed rate limiter that decides when the entries of a cache may be refreshed, so that
+/// a burst of requests for an expired entry (a "cache stampede") doesn't turn into a burst of
+/// refreshes of it.
+///
+/// The gate lets at most as many refreshes per key through as its rate limiter allows (see
+/// [`keyed`](#method.keyed) for a gate that allows a number of refreshes per interval), and
+/// decides that the stale entry should be served for all others. To keep many entries that
+/// were cached at once from expiring at once, the gate can also add
+/// [jitter](#method.with_ttl_jitter) to the [time to live](#method.ttl) of entries.
+///
+/// # Example
+/// ```rust
+/// # #[cfg(feature = "std")] fn main() {
+/// # use nonzero_ext::*;
+/// use governor::state::{Refresh, RefreshGate};
+/// # use std::time::Duration;
+///
+/// let gate = RefreshGate::keyed(nonzero!(1u32), Duration::from_secs(60)).unwrap();
+/// assert_eq!(gate.check_key(&"/index.html"), Refresh::Now);
+/// assert_eq!(gate.check_key(&"/index.html"), Refresh::ServeStale);
+/// assert_eq!(gate.check_key(&"/about.html"), Refresh::Now);
+/// assert_eq!(gate.served_stale(), 1);
+/// # } #[cfg(not(feature = "std"))] fn main() {}
+/// ```
+pub struct RefreshGate<K, S, C, MW>
+where
+    S: StateStore<Key = K>,
+    C: clock::Clock,
+    MW: RateLimitingMiddleware<C::Instant>,
+{
+    limiter: RateLimiter<K, S, C, MW>,
+    ttl_jitter: Jitter,
+    served_stale: AtomicU64,
+}
+
+impl<K, S, C, MW> RefreshGate<K, S, C, MW>
+where
+    S: StateStore<Key = K>,
+    C: clock::Clock,
+    MW: RateLimitingMiddleware<C::Instant>,
+{
+    /// Wraps a rate limiter, which lets one refresh through for each cell, with no jitter on
+    /// the time to live of entries.
+    pub fn new(limiter: RateLimiter<K, S, C, MW>) -> Self {
+        RefreshGate {
+            limiter,
+            ttl_jitter: Jitter::default(),
+            served_stale: AtomicU64::new(0),
+        }
+    }
+
+    /// Adds the given jitter to the [time to live](#method.ttl) of entries from now on.
+    #[cfg(feature = "jitter")]
+    pub fn with_ttl_jitter(self, jitter: Jitter) -> Self {
+        RefreshGate {
+            ttl_jitter: jitter,
+            ..self
+        }
+    }
+
+    /// Returns the time to live for an entry that is cached (or refreshed) now: the given
+    /// time, with a random amount of the gate's [jitter](#method.with_ttl_jitter) added.
+    pub fn ttl(&self, ttl: Duration) -> Duration {
+        self.ttl_jitter + ttl
+    }
+
+    /// Returns the number of decisions to serve a stale entry that the gate made.
+    pub fn served_stale(&self) -> u64 {
+        self.served_stale.load(Ordering::Relaxed)
+    }
+
+    /// Returns the wrapped rate limiter.
+    pub fn limiter(&self) -> &RateLimiter<K, S, C, MW> {
+        &self.limiter
+    }
+
+    /// Returns the wrapped rate limiter.
+    pub fn into_inner(self) -> RateLimiter<K, S, C, MW> {
+        self.limiter
+    }
+}
+
+/// # Refresh gates - default constructors
+impl<K>
+    RefreshGate<
+        K,
+        DefaultKeyedStateStore<K>,
+        clock::DefaultClock,
+        NoOpMiddleware<<clock::DefaultClock as clock::Clock>::Instant>,
+    >
+where
+    K: Clone + Hash + Eq,
+{
+    /// Constructs a gate that lets at most `refreshes` refreshes per key through in each
+    /// `interval`, backed by the [`DefaultKeyedStateStore`].
+    ///
+    /// Returns `None` if the interval is too short or too long to be a valid
+    /// [quota](Quota::new).
+    pub fn keyed(refreshes: NonZeroU32, interval: Duration) -> Option<Self> {
+        Quota::new(refreshes, interval).map(|quota| RefreshGate::new(RateLimiter::keyed(quota)))
+    }
+}
+
+impl<K, S, C, MW> RefreshGate<K, S, C, MW>
+where
+    S: KeyedStateStore<K>,
+    K: Hash,
+    C: clock::Clock,
+    MW: RateLimitingMiddleware<C::Instant>,
+{
+    /// Decides whether the cache entry for the given key should be refreshed now, using up a
+    /// cell of the rate limiter if so.
+    pub fn check_key(&self, key: &K) -> Refresh {
+        match self.limiter.check_key(key) {
+            Ok(_) => Refresh::Now,
+            Err(_) => {
+                self.served_stale.fetch_add(1, Ordering::Relaxed);
+                Refresh::ServeStale
+            }
+        }
+    }
+}
+
+impl<K, S, C, MW> fmt::Debug for RefreshGate<K, S, C, MW>
+where
+    S: StateStore<Key = K>,
+    C: clock::Clock,
+    MW: RateLimitingMiddleware<C::Instant>,
+    RateLimiter<K, S, C, MW>: fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RefreshGate")
+            .field("limiter", &self.limiter)
+            .field("ttl_jitter", &self.ttl_jitter)
+            .field("served_stale", &self.served_stale())
+            .finish()
+    }
+}
+
+/// # Refresh gates
+impl<K, S, C, MW> RateLimiter<K, S, C, MW>
+where
+    S: KeyedStateStore<K>,
+    K: Hash,
+    C: clock::Clock,
+    MW: RateLimitingMiddleware<C::Instant>,
+{
+    /// Wraps the rate limiter in a [`RefreshGate`], which lets one refresh of a cache entry
+    /// through for each cell, and decides to serve stale entries otherwise.
+    pub fn into_refresh_gate(self) -> RefreshGate<K, S, C, MW> {
+        RefreshGate::new(self)
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod test {
+    use super::*;
+    use crate::clock::FakeRelativeClock;
+    use nonzero_ext::nonzero;
+
+    #[test]
+    fn serves_stale_entries_until_the_interval_passes() {
+        let clock = FakeRelativeClock::default();
+        let quota = Quota::new(nonzero!(2u32), Duration::from_secs(60)).unwrap();
+        let gate = RateLimiter::hashmap_with_clock(quota, &clock).into_refresh_gate();
+        assert!(gate.check_key(&"a").is_now());
+        assert!(gate.check_key(&"a").is_now());
+        assert_eq!(gate.check_key(&"a"), Refresh::ServeStale);
+        assert_eq!(gate.check_key(&"b"), Refresh::Now);
+        clock.advance(Duration::from_secs(30));
+        assert_eq!(gate.check_key(&"a"), Refresh::Now);
+        assert_eq!(gate.check_key(&"a"), Refresh::ServeStale);
+        assert_eq!(gate.served_stale(), 2);
+        assert_eq!(gate.ttl(Duration::from_secs(5)), Duration::from_secs(5));
+        assert!(!format!("{:?}", gate).is_empty());
+
+        assert!(RefreshGate::<u32, _, _, _>::keyed(nonzero!(1u32), Duration::ZERO).is_none());
+    }
+
+    #[cfg(feature = "jitter")]
+    #[test]
+    fn jitters_ttls() {
+        let gate = RefreshGate::<&str, _, _, _>::keyed(nonzero!(1u32), Duration::from_secs(1))
+            .unwrap()
+            .with_ttl_jitter(Jitter::up_to(Duration::from_secs(10)));
+        let ttls: Vec<Duration> = (0..100)
+            .map(|_| gate.ttl(Duration::from_secs(60)))
+            .collect();
+        assert!(ttls
+            .iter()
+            .all(|ttl| (Duration::from_secs(60)..=Duration::from_secs(70)).contains(ttl)));
+        assert!(ttls.iter().any(|ttl| *ttl != ttls[0]));
+    }
+}
@@ -0,0 +1,299 @@
+//! Capping the burst capacity that rate limiters grant after clock jumps and system resumes.
+
+use crate::clock;
+use crate::gcra::Gcra;
+use crate::middleware::RateLimitingMiddleware;
+use crate::nanos::Nanos;
+use crate::state::{RateLimiter, StateStore};
+use crate::sync::{AtomicU64, Ordering};
+#[cfg(feature = "std")]
+use crate::{clock::Reference, sync::AtomicU32};
+#[cfg(feature = "std")]
+use std::{
+    sync::OnceLock,
+    time::{Duration, Instant},
+};
+
+/// A policy for clock jumps: When a rate limiter's clock advances by more than the `threshold`
+/// further than the system's monotonic clock ([`Instant`]) between two consecutive decisions,
+/// the burst capacity that the rate limiter grants is capped at `credit` cells, as if
+/// [`notify_resume`](crate::RateLimiter::notify_resume) had been called.
+///
+/// Time in which the rate limiter makes no decisions passes on both clocks alike, so quiet
+/// periods aren't jumps.
+#[cfg(feature = "std")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct JumpPolicy {
+    threshold: Duration,
+    credit: u32,
+}
+
+#[cfg(feature = "std")]
+impl JumpPolicy {
+    /// Constructs a policy that caps the burst capacity at `credit` cells after the rate
+    /// limiter's clock jumps ahead of the system's monotonic clock by more than `threshold`.
+    pub fn new(threshold: Duration, credit: u32) -> Self {
+        JumpPolicy {
+            threshold: threshold.max(Duration::from_nanos(1)),
+            credit,
+        }
+    }
+
+    /// Returns how far the rate limiter's clock must jump ahead to count as a jump.
+    pub fn threshold(&self) -> Duration {
+        self.threshold
+    }
+
+    /// Returns the number of cells that the burst capacity is capped at after a jump.
+    pub fn credit(&self) -> u32 {
+        self.credit
+    }
+}
+
+/// A rate limiter's resume state: the earliest theoretical arrival time that decisions assume,
+/// and the jump policy that moves it, if any.
+#[derive(Debug, Default)]
+pub(crate) struct Resume(Inner);
+
+#[derive(Debug, Default)]
+struct Inner {
+    /// The earliest theoretical arrival time, in the GCRA's units since the rate limiter's
+    /// start; 0 if no resume happened yet.
+    floor: AtomicU64,
+    /// The jump policy's threshold in nanoseconds, or 0 without a policy.
+    #[cfg(feature = "std")]
+    threshold: AtomicU64,
+    #[cfg(feature = "std")]
+    credit: AtomicU32,
+    /// The time of the latest decision, in nanoseconds since the rate limiter's start.
+    #[cfg(feature = "std")]
+    last: AtomicU64,
+    /// The time of the latest decision on the monotonic clock, in nanoseconds since `base`.
+    #[cfg(feature = "std")]
+    reference: AtomicU64,
+    /// The monotonic clock's instant that `reference` counts from, set with the jump policy.
+    #[cfg(feature = "std")]
+    base: OnceLock<Instant>,
+}
+
+impl Resume {
+    /// Returns the floor that decisions made at `now` (since the rate limiter's start) assume,
+    /// applying the jump policy first.
+    #[inline]
+    pub(crate) fn floor(&self, now: impl FnOnce() -> Nanos, gcra: &Gcra) -> Nanos {
+        let inner = &self.0;
+        #[cfg(feature = "std")]
+        {
+            let threshold = inner.threshold.load(Ordering::Relaxed);
+            if threshold != 0 {
+                self.detect_jump(now().as_u64(), threshold, gcra);
+            }
+        }
+        #[cfg(not(feature = "std"))]
+        let _ = (now, gcra);
+        Nanos::from(inner.floor.load(Ordering::Relaxed))
+    }
+
+    /// Resumes if the rate limiter's clock advanced by more than `threshold` further than the
+    /// monotonic clock since the latest decision.
+    #[cfg(feature = "std")]
+    fn detect_jump(&self, now: u64, threshold: u64, gcra: &Gcra) {
+        let inner = &self.0;
+        let reference = self.reference_now();
+        let last = inner.last.fetch_max(now, Ordering::Relaxed);
+        let last_reference = inner.reference.fetch_max(reference, Ordering::Relaxed);
+        let elapsed = reference.saturating_sub(last_reference);
+        if now.saturating_sub(last) > elapsed.saturating_add(threshold) {
+            let now = gcra.units_since_start(Nanos::from(now));
+            self.resume_at(now, inner.credit.load(Ordering::Relaxed), gcra);
+        }
+    }
+
+    /// Returns the monotonic clock's time, in nanoseconds since the jump policy was set.
+    #[cfg(feature = "std")]
+    fn reference_now(&self) -> u64 {
+        let base = *self.0.base.get_or_init(Instant::now);
+        Nanos::from(Instant::now().saturating_duration_since(base)).as_u64()
+    }
+
+    fn resume_at(&self, now: Nanos, credit: u32, gcra: &Gcra) {
+        let floor = gcra.floor_for_credit(now, credit);
+        self.0.floor.fetch_max(floor.as_u64(), Ordering::Relaxed);
+    }
+}
+
+/// # Clock jumps and system resumes
+///
+/// A GCRA never grants more than the quota's burst capacity, but after a long pause (a laptop
+/// or VM resuming from suspend, or a clock that jumped forward), every key has its *full* burst
+/// capacity back at once, and all clients that were waiting get to send their bursts at the
+/// same time. These methods cap the burst capacity that is available right after such a
+/// pause; from then on, capacity replenishes at the quota's rate as usual.
+///
+/// Jumps backwards (e.g. of a [`SystemClock`](crate::clock::SystemClock)) need no handling:
+/// Decisions at an earlier time only ever deny more cells.
+///
+/// The resume state applies to the rate limiter it is set on and to its clones (like those of a
+/// [shared](#method.into_shared) rate limiter), which grant bursts from the same state.
+impl<K, S, C, MW> RateLimiter<K, S, C, MW>
+where
+    S: StateStore<Key = K>,
+    C: clock::Clock,
+    MW: RateLimitingMiddleware<C::Instant>,
+{
+    /// Notifies the rate limiter that the system just resumed (or its clock jumped), capping
+    /// the burst capacity of every key at `credit` cells, right now.
+    ///
+    /// Keys' states aren't touched: The cap is applied as they make their next decisions.
+    ///
+    /// # Example
+    /// ```rust
+    /// # use nonzero_ext::*;
+    /// # use std::time::Duration;
+    /// use governor::{clock::FakeRelativeClock, Quota, RateLimiter};
+    ///
+    /// let clock = FakeRelativeClock::default();
+    /// let lim = RateLimiter::direct_with_clock(Quota::per_second(nonzero!(50u32)), &clock);
+    /// clock.advance(Duration::from_secs(8 * 60 * 60));
+    /// lim.notify_resume(5);
+    /// assert!(lim.check_n(nonzero!(5u32)).is_ok());
+    /// assert!(lim.check().is_err());
+    /// ```
+    pub fn notify_resume(&self, credit: u32) {
+        let gcra = self.gcra.under_pressure(self.admitted());
+        let now = self.units_at(self.clock.now());
+        self.tuning().resume.resume_at(now, credit, &gcra);
+    }
+
+    /// Sets the policy that detects clock jumps, or removes it. Without a policy (the default),
+    /// the rate limiter only caps the burst capacity when it is
+    /// [notified](#method.notify_resume) of a resume.
+    ///
+    /// Detecting jumps costs two clock readings and two atomic operations on every decision.
+    /// The system's monotonic clock doesn't advance while the system is suspended, so this
+    /// detects resumes and jumps of clocks that do (like the
+    /// [`SystemClock`](crate::clock::SystemClock)); a rate limiter on a monotonic clock only
+    /// ever detects jumps if its clock keeps counting through suspends.
+    #[cfg(feature = "std")]
+    pub fn set_jump_policy(&self, policy: Option<JumpPolicy>) {
+        let resume = &self.tuning().resume;
+        let reference = resume.reference_now();
+        let resume = &resume.0;
+        let now = self.clock.now().duration_since(self.start);
+        resume.last.store(now.as_u64(), Ordering::Relaxed);
+        resume.reference.store(reference, Ordering::Relaxed);
+        match policy {
+            Some(policy) => {
+                let threshold = Nanos::from(policy.threshold).as_u64().max(1);
+                resume.credit.store(policy.credit, Ordering::Relaxed);
+                resume.threshold.store(threshold, Ordering::Relaxed);
+            }
+            None => resume.threshold.store(0, Ordering::Relaxed),
+        }
+    }
+
+    /// Returns the policy that detects clock jumps, if there is one.
+    #[cfg(feature = "std")]
+    pub fn jump_policy(&self) -> Option<JumpPolicy> {
+        let resume = &self.tuned()?.resume.0;
+        match resume.threshold.load(Ordering::Relaxed) {
+            0 => None,
+            threshold => Some(JumpPolicy::new(
+                Duration::from_nanos(threshold),
+                resume.credit.load(Ordering::Relaxed),
+            )),
+        }
+    }
+}
+
+#[cfg(all(feature = "std", test))]
+mod test {
+    use super::*;
+    use crate::clock::FakeRelativeClock;
+    use crate::state::keyed::HashMapStateStore;
+    use crate::{middleware::NoOpMiddleware, Quota};
+    use nonzero_ext::nonzero;
+
+    #[test]
+    fn caps_credit_after_resume() {
+        let clock = FakeRelativeClock::default();
+        let lim: RateLimiter<_, HashMapStateStore<_>, _, NoOpMiddleware<_>> = RateLimiter::new(
+            Quota::per_second(nonzero!(10u32)),
+            Default::default(),
+            &clock,
+        );
+        assert!(lim.check_key_n(&"a", nonzero!(10u32)).is_ok());
+        clock.advance(Duration::from_secs(3600));
+        lim.notify_resume(2);
+        for key in &["a", "b"] {
+            assert!(lim.check_key_n(key, nonzero!(2u32)).is_ok());
+            assert!(lim.check_key(key).is_err());
+        }
+        // Capacity replenishes at the quota's rate from there:
+        clock.advance(Duration::from_millis(500));
+        assert!(lim.check_key_n(&"a", nonzero!(5u32)).is_ok());
+        assert!(lim.check_key(&"a").is_err());
+        // ...up to the full burst capacity:
+        clock.advance(Duration::from_secs(5));
+        assert!(lim.check_key_n(&"b", nonzero!(10u32)).is_ok());
+        // Credit beyond the burst capacity doesn't change anything:
+        lim.notify_resume(100);
+        clock.advance(Duration::from_secs(1));
+        assert!(lim.check_key_n(&"b", nonzero!(10u32)).is_ok());
+    }
+
+    #[test]
+    fn resumes_apply_to_clones() {
+        let clock = FakeRelativeClock::default();
+        let lim = RateLimiter::direct_with_clock(Quota::per_second(nonzero!(10u32)), &clock)
+            .into_shared();
+        let clone = lim.clone();
+        clock.advance(Duration::from_secs(3600));
+        lim.notify_resume(2);
+        assert!(clone.check_n(nonzero!(2u32)).is_ok());
+        assert!(clone.check().is_err());
+
+        let policy = JumpPolicy::new(Duration::from_secs(60), 0);
+        clone.set_jump_policy(Some(policy));
+        assert_eq!(lim.jump_policy(), Some(policy));
+        clock.advance(Duration::from_secs(61));
+        assert!(lim.check().is_err());
+    }
+
+    #[test]
+    fn detects_jumps() {
+        let clock = FakeRelativeClock::default();
+        let lim = RateLimiter::direct_with_clock(Quota::per_second(nonzero!(10u32)), &clock);
+        let policy = JumpPolicy::new(Duration::from_secs(60), 0);
+        lim.set_jump_policy(Some(policy));
+        assert_eq!(lim.jump_policy(), Some(policy));
+        clock.advance(Duration::from_secs(30));
+        assert!(lim.check_n(nonzero!(10u32)).is_ok());
+
+        clock.advance(Duration::from_secs(61));
+        assert!(lim.check().is_err());
+        clock.advance(Duration::from_millis(100));
+        assert!(lim.check().is_ok());
+
+        lim.set_jump_policy(None);
+        assert_eq!(lim.jump_policy(), None);
+        clock.advance(Duration::from_secs(120));
+        assert!(lim.check_n(nonzero!(10u32)).is_ok());
+    }
+
+    #[test]
+    fn quiet_periods_arent_jumps() {
+        let clock = crate::clock::MonotonicClock;
+        let lim: RateLimiter<_, HashMapStateStore<_>, _, NoOpMiddleware<_>> = RateLimiter::new(
+            Quota::per_second(nonzero!(10u32)),
+            Default::default(),
+            &clock,
+        );
+        lim.set_jump_policy(Some(JumpPolicy::new(Duration::from_millis(10), 0)));
+        assert!(lim.check_key(&"a").is_ok());
+        // Both clocks advance while the rate limiter is idle:
+        std::thread::sleep(Duration::from_millis(50));
+        assert!(lim.check_key(&"a").is_ok());
+        assert!(lim.check_key(&"b").is_ok());
+    }
+}
@@ -0,0 +1,282 @@
+//! Retry policies that combine exponential backoff with a rate limit on attempts.
+
+use crate::compat::prelude::*;
+
+use crate::clock::{self, DefaultClock};
+use crate::middleware::NoOpMiddleware;
+use crate::state::{InMemoryState, NotKeyed};
+use crate::sync::Mutex;
+use crate::{NotUntil, Quota, RateLimiter};
+use futures_timer::Delay;
+use std::fmt;
+use std::future::Future;
+use std::num::NonZeroU32;
+use std::time::Duration;
+
+/// A retry policy that unifies rate limiting and retrying: Every attempt (the first one and
+/// each retry) counts against a rate limiter, and failed attempts are retried with exponential
+/// backoff, but never sooner than the rate limiter allows.
+///
+/// Retry executors (like those of the `backoff` or `tryhard` crates) drive the policy by
+/// reporting each attempt's outcome: [`record_failure`](#method.record_failure) registers a
+/// failure and returns the minimum delay before the next attempt (or `None` once the retries
+/// are used up), which can be returned from a `next_backoff`-style callback;
+/// [`record_success`](#method.record_success) resets the backoff. Before each attempt,
+/// [`check`](#method.check) takes the attempt's cell from the rate limiter. For async code,
+/// [`retry`](#method.retry) runs the whole loop.
+///
+/// The backoff after the `n`th consecutive failure is `initial * 2^(n-1)`, capped at `max`; by
+/// default, it starts at 100ms and grows up to 10s, and retries go on indefinitely.
+///
+/// # Example
+/// ```rust
+/// # use nonzero_ext::*;
+/// # use std::time::Duration;
+/// use governor::{clock::FakeRelativeClock, state::RetryPolicy, Quota};
+///
+/// let clock = FakeRelativeClock::default();
+/// let policy = RetryPolicy::with_clock(Quota::per_second(nonzero!(1u32)), &clock)
+///     .backoff(Duration::from_millis(100), Duration::from_secs(1))
+///     .max_retries(nonzero!(3u32));
+/// assert!(policy.check().is_ok());
+/// // The attempt failed; the backoff would allow a retry after 100ms, but the rate limit
+/// // only allows the next attempt after a second:
+/// assert_eq!(policy.record_failure(), Some(Duration::from_secs(1)));
+/// ```
+pub struct RetryPolicy<C: clock::Clock = DefaultClock> {
+    limiter: RateLimiter<NotKeyed, InMemoryState, C, NoOpMiddleware<C::Instant>>,
+    initial: Duration,
+    max: Duration,
+    max_retries: Option<NonZeroU32>,
+    failures: Mutex<u32>,
+}
+
+impl RetryPolicy<DefaultClock> {
+    /// Constructs a policy whose attempts are rate-limited to the given quota.
+    pub fn new(quota: Quota) -> Self {
+        RetryPolicy::with_clock(quota, &DefaultClock::default())
+    }
+}
+
+impl<C: clock::Clock> RetryPolicy<C> {
+    /// Constructs a policy whose attempts are rate-limited to the given quota, on the given
+    /// clock.
+    pub fn with_clock(quota: Quota, clock: &C) -> Self {
+        RetryPolicy {
+            limiter: RateLimiter::direct_with_clock(quota, clock),
+            initial: Duration::from_millis(100),
+            max: Duration::from_secs(10),
+            max_retries: None,
+            failures: Mutex::new(0),
+        }
+    }
+
+    /// Sets the backoff after the first failure, and the most that the backoff grows to.
+    pub fn backoff(mut self, initial: Duration, max: Duration) -> Self {
+        self.initial = initial;
+        self.max = max.max(initial);
+        self
+    }
+
+    /// Limits the number of consecutive failures that are retried.
+    pub fn max_retries(mut self, max_retries: NonZeroU32) -> Self {
+        self.max_retries = Some(max_retries);
+        self
+    }
+
+    /// Returns the rate limiter that attempts count against.
+    pub fn limiter(&self) -> &RateLimiter<NotKeyed, InMemoryState, C, NoOpMiddleware<C::Instant>> {
+        &self.limiter
+    }
+
+    /// Returns the number of consecutive failures registered since the last success.
+    pub fn failures(&self) -> u32 {
+        *self.failures.lock()
+    }
+
+    /// Takes the cell of an attempt from the rate limiter, if the rate limit allows an attempt
+    /// right now.
+    pub fn check(&self) -> Result<(), NotUntil<C::Instant>> {
+        self.limiter.check()
+    }
+
+    /// Registers a failed attempt, and returns the minimum delay before the next attempt, or
+    /// `None` if the policy's retries are used up.
+    pub fn record_failure(&self) -> Option<Duration> {
+        let failures = {
+            let mut failures = self.failures.lock();
+            *failures = failures.saturating_add(1);
+            *failures
+        };
+        if let Some(max_retries) = self.max_retries {
+            if failures > max_retries.get() {
+                return None;
+            }
+        }
+        Some(self.min_delay())
+    }
+
+    /// Registers a successful attempt, resetting the backoff.
+    pub fn record_success(&self) {
+        *self.failures.lock() = 0;
+    }
+
+    /// Returns the minimum delay before the next attempt: the backoff for the consecutive
+    /// failures so far, or the time until the rate limiter allows an attempt, whichever is
+    /// longer. Querying the delay doesn't take a cell from the rate limiter.
+    pub fn min_delay(&self) -> Duration {
+        self.backoff_after(self.failures())
+            .max(self.limiter_delay())
+    }
+
+    fn backoff_after(&self, failures: u32) -> Duration {
+        if failures == 0 {
+            return Duration::from_secs(0);
+        }
+        let factor = 1u32.checked_shl(failures - 1).unwrap_or(u32::MAX);
+        self.initial
+            .checked_mul(factor)
+            .map_or(self.max, |backoff| backoff.min(self.max))
+    }
+
+    /// Returns the time until the rate limiter lets a cell through, without taking it.
+    fn limiter_delay(&self) -> Duration {
+        let limiter = &self.limiter;
+        limiter.gcra().wait_time(
+            limiter.start,
+            &NotKeyed::NonKey,
+            &limiter.state,
+            limiter.clock.now(),
+        )
+    }
+}
+
+/// # Retry policies - `async`/`await`
+impl<C: clock::ReasonablyRealtime> RetryPolicy<C> {
+    /// Runs `attempt` until it succeeds or the policy's retries are used up, and returns the
+    /// last attempt's result.
+    ///
+    /// Each attempt waits until the rate limiter allows it, and failed attempts are retried
+    /// after the backoff.
+    pub async fn retry<F, Fut, T, E>(&self, mut attempt: F) -> Result<T, E>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, E>>,
+    {
+        loop {
+            self.limiter.until_ready().await;
+            match attempt().await {
+                Ok(value) => {
+                    self.record_success();
+                    return Ok(value);
+                }
+                Err(error) => {
+                    if self.record_failure().is_none() {
+                        return Err(error);
+                    }
+                    // The rate limit is waited for before the next attempt:
+                    Delay::new(self.backoff_after(self.failures())).await;
+                }
+            }
+        }
+    }
+}
+
+impl<C: clock::Clock> fmt::Debug for RetryPolicy<C>
+where
+    RateLimiter<NotKeyed, InMemoryState, C, NoOpMiddleware<C::Instant>>: fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RetryPolicy")
+            .field("limiter", &self.limiter)
+            .field("initial", &self.initial)
+            .field("max", &self.max)
+            .field("max_retries", &self.max_retries)
+            .field("failures", &self.failures())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::clock::FakeRelativeClock;
+    use futures::executor::block_on;
+    use nonzero_ext::nonzero;
+
+    #[test]
+    fn backs_off_exponentially() {
+        let clock = FakeRelativeClock::default();
+        let policy = RetryPolicy::with_clock(Quota::per_second(nonzero!(1000u32)), &clock)
+            .backoff(Duration::from_millis(100), Duration::from_millis(500))
+            .max_retries(nonzero!(4u32));
+        assert_eq!(policy.min_delay(), Duration::from_secs(0));
+        let delays: Vec<_> = (0..5).map(|_| policy.record_failure()).collect();
+        assert_eq!(
+            delays,
+            vec![
+                Some(Duration::from_millis(100)),
+                Some(Duration::from_millis(200)),
+                Some(Duration::from_millis(400)),
+                Some(Duration::from_millis(500)),
+                None,
+            ]
+        );
+        policy.record_success();
+        assert_eq!(policy.failures(), 0);
+        assert_eq!(policy.record_failure(), Some(Duration::from_millis(100)));
+    }
+
+    #[test]
+    fn waits_for_the_rate_limit() {
+        let clock = FakeRelativeClock::default();
+        let policy = RetryPolicy::with_clock(Quota::per_second(nonzero!(2u32)), &clock)
+            .backoff(Duration::from_millis(10), Duration::from_secs(1));
+        assert!(policy.check().is_ok());
+        // Querying the delay doesn't take a cell:
+        let saturation = policy.limiter().saturation();
+        assert_eq!(policy.min_delay(), Duration::from_secs(0));
+        assert_eq!(policy.limiter().saturation(), saturation);
+        assert!(policy.check().is_ok());
+        assert_eq!(policy.record_failure(), Some(Duration::from_millis(500)));
+        clock.advance(Duration::from_millis(500));
+        assert_eq!(policy.min_delay(), Duration::from_millis(10));
+        assert!(format!("{:?}", policy).contains("RetryPolicy"));
+    }
+
+    #[test]
+    fn querying_the_delay_keeps_cooldowns_off() {
+        let clock = FakeRelativeClock::default();
+        let quota = Quota::per_second(nonzero!(2u32)).with_cooldown(Duration::from_secs(10));
+        let policy = RetryPolicy::with_clock(quota, &clock);
+        assert!(policy.check().is_ok());
+        // Taking the last cell would start the cooldown, but querying the delay doesn't:
+        assert_eq!(policy.min_delay(), Duration::from_secs(0));
+        assert!(policy.check().is_ok());
+    }
+
+    #[test]
+    fn retries_until_success() {
+        let policy = RetryPolicy::new(Quota::per_second(nonzero!(1000u32)))
+            .backoff(Duration::from_millis(1), Duration::from_millis(5))
+            .max_retries(nonzero!(5u32));
+        let mut attempts = 0;
+        let result: Result<u32, &str> = block_on(policy.retry(|| {
+            attempts += 1;
+            let attempt = attempts;
+            async move {
+                if attempt < 3 {
+                    Err("unavailable")
+                } else {
+                    Ok(attempt)
+                }
+            }
+        }));
+        assert_eq!(result, Ok(3));
+        assert_eq!(policy.failures(), 0);
+
+        let result: Result<(), &str> = block_on(policy.retry(|| async { Err("down") }));
+        assert_eq!(result, Err("down"));
+        assert_eq!(policy.failures(), 6);
+    }
+}
@@ -0,0 +1,305 @@
+//! Rate limiters that enforce their limit on only a part of the traffic.
+
+use crate::compat::prelude::*;
+
+use crate::hash::{fnv1a, mix, Fnv1a};
+use crate::middleware::RateLimitingMiddleware;
+use crate::state::keyed::KeyedStateStore;
+use crate::state::{DirectStateStore, NotKeyed, StateStore};
+use crate::sync::{AtomicU64, Ordering};
+use crate::{clock, NegativeMultiDecision, RateLimiter};
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::num::NonZeroU32;
+
+/// How a [`SampledLimiter`] picks the cells that it enforces its limit on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Sampling {
+    /// Pick cells by the hash of their key: All cells for a key are either enforced or not, and
+    /// raising the fraction only adds keys to the sample. This suits gradual rollouts of a
+    /// limit.
+    ///
+    /// Direct rate limiters have only one key, so they enforce either all or no cells.
+    ByKey,
+
+    /// Pick each decision independently, with a pseudo-random sequence. This suits
+    /// probabilistic load shedding.
+    Random,
+}
+
+/// A rate limiter that enforces its limit on only a configurable fraction of the traffic.
+///
+/// Cells that are in the sample are checked with the wrapped rate limiter, and their decision
+/// is returned (with positive outcomes wrapped in `Some`). Cells outside the sample aren't
+/// checked at all, so they don't use up any of the rate limiter's capacity: They result in
+/// `Ok(None)`, and are counted by [`bypassed`](#method.bypassed).
+///
+/// The fraction can be changed at runtime with [`set_fraction`](#method.set_fraction).
+///
+/// # Example
+/// ```rust
+/// # #[cfg(feature = "std")] fn main() {
+/// # use nonzero_ext::*;
+/// use governor::{state::Sampling, Quota, RateLimiter};
+///
+/// // Enforce a limit on a quarter of the keys:
+/// let lim = RateLimiter::keyed(Quota::per_hour(nonzero!(1u32))).into_sampled(Sampling::ByKey, 0.25);
+/// let enforced = (0..1000u32)
+///     .filter(|key| lim.check_key(key).ok() != Some(None))
+///     .count();
+/// assert!((150..350).contains(&enforced));
+/// assert_eq!(lim.bypassed(), 1000 - enforced as u64);
+/// # } #[cfg(not(feature = "std"))] fn main() {}
+/// ```
+pub struct SampledLimiter<K, S, C, MW>
+where
+    S: StateStore<Key = K>,
+    C: clock::Clock,
+    MW: RateLimitingMiddleware<C::Instant>,
+{
+    limiter: RateLimiter<K, S, C, MW>,
+    sampling: Sampling,
+    /// Cells whose sample point falls below this out of `2^32` are enforced.
+    threshold: AtomicU64,
+    /// The position in the pseudo-random sequence, for [`Sampling::Random`].
+    sequence: AtomicU64,
+    bypassed: AtomicU64,
+}
+
+impl<K, S, C, MW> SampledLimiter<K, S, C, MW>
+where
+    S: StateStore<Key = K>,
+    C: clock::Clock,
+    MW: RateLimitingMiddleware<C::Instant>,
+{
+    /// Wraps a rate limiter, enforcing it on the given fraction (between 0 and 1) of the
+    /// traffic, sampled the given way.
+    pub fn new(limiter: RateLimiter<K, S, C, MW>, sampling: Sampling, fraction: f64) -> Self {
+        SampledLimiter {
+            limiter,
+            sampling,
+            threshold: AtomicU64::new(threshold(fraction)),
+            sequence: AtomicU64::new(0),
+            bypassed: AtomicU64::new(0),
+        }
+    }
+
+    /// Returns how the rate limiter picks the cells that it enforces.
+    pub fn sampling(&self) -> Sampling {
+        self.sampling
+    }
+
+    /// Returns the fraction of the traffic that the limit is enforced on.
+    pub fn fraction(&self) -> f64 {
+        self.threshold.load(Ordering::Relaxed) as f64 / SAMPLE_POINTS as f64
+    }
+
+    /// Sets the fraction of the traffic that the limit is enforced on. Fractions are clamped to
+    /// lie between 0 (enforcing on no cells) and 1 (enforcing on all cells).
+    pub fn set_fraction(&self, fraction: f64) {
+        self.threshold.store(threshold(fraction), Ordering::Relaxed);
+    }
+
+    /// Returns the number of decisions that were let through without checking them, because
+    /// they were outside the sample.
+    pub fn bypassed(&self) -> u64 {
+        self.bypassed.load(Ordering::Relaxed)
+    }
+
+    /// Returns the wrapped rate limiter.
+    pub fn limiter(&self) -> &RateLimiter<K, S, C, MW> {
+        &self.limiter
+    }
+
+    /// Returns the wrapped rate limiter.
+    pub fn into_inner(self) -> RateLimiter<K, S, C, MW> {
+        self.limiter
+    }
+
+    /// Returns whether a cell whose key hashes to `key_hash` is in the sample.
+    fn sampled(&self, key_hash: u64) -> bool {
+        let point = match self.sampling {
+            Sampling::ByKey => mix(key_hash),
+            Sampling::Random => mix(self.sequence.fetch_add(1, Ordering::Relaxed)),
+        };
+        (point >> 32) < self.threshold.load(Ordering::Relaxed)
+    }
+
+    /// Checks a cell with the wrapped rate limiter if it is in the sample.
+    fn check_sampled<T, E>(
+        &self,
+        key_hash: u64,
+        check: impl FnOnce(&RateLimiter<K, S, C, MW>) -> Result<T, E>,
+    ) -> Result<Option<T>, E> {
+        if self.sampled(key_hash) {
+            check(&self.limiter).map(Some)
+        } else {
+            self.bypassed.fetch_add(1, Ordering::Relaxed);
+            Ok(None)
+        }
+    }
+}
+
+/// The number of points that cells are sampled at.
+const SAMPLE_POINTS: u64 = 1 << 32;
+
+fn threshold(fraction: f64) -> u64 {
+    if fraction >= 1.0 {
+        SAMPLE_POINTS
+    } else if fraction > 0.0 {
+        (fraction * SAMPLE_POINTS as f64) as u64
+    } else {
+        // This includes NaN:
+        0
+    }
+}
+
+/// # Sampled rate limiters - Manually checking cells
+impl<S, C, MW> SampledLimiter<NotKeyed, S, C, MW>
+where
+    S: DirectStateStore,
+    C: clock::Clock,
+    MW: RateLimitingMiddleware<C::Instant>,
+{
+    /// Allow a single cell through the rate limiter, unless it is in the sample and
+    /// rate-limited.
+    ///
+    /// See [`RateLimiter::check`].
+    pub fn check(&self) -> Result<Option<MW::PositiveOutcome>, MW::NegativeOutcome> {
+        self.check_sampled(Fnv1a::default().finish(), |lim| lim.check())
+    }
+
+    /// Allow *only all* `n` cells through the rate limiter, unless they are in the sample and
+    /// rate-limited. The `n` cells are sampled together.
+    ///
+    /// See [`RateLimiter::check_n`].
+    pub fn check_n(
+        &self,
+        n: NonZeroU32,
+    ) -> Result<Option<MW::PositiveOutcome>, NegativeMultiDecision<MW::NegativeOutcome>> {
+        self.check_sampled(Fnv1a::default().finish(), |lim| lim.check_n(n))
+    }
+}
+
+/// # Keyed sampled rate limiters - Manually checking cells
+impl<K, S, C, MW> SampledLimiter<K, S, C, MW>
+where
+    S: KeyedStateStore<K>,
+    K: Hash,
+    C: clock::Clock,
+    MW: RateLimitingMiddleware<C::Instant>,
+{
+    /// Allow a single cell through the rate limiter for the given key, unless it is in the
+    /// sample and rate-limited.
+    ///
+    /// See [`RateLimiter::check_key`].
+    pub fn check_key(&self, key: &K) -> Result<Option<MW::PositiveOutcome>, MW::NegativeOutcome> {
+        self.check_sampled(fnv1a(key), |lim| lim.check_key(key))
+    }
+
+    /// Allow *only all* `n` cells through the rate limiter for the given key, unless they are in
+    /// the sample and rate-limited. The `n` cells are sampled together.
+    ///
+    /// See [`RateLimiter::check_key_n`].
+    pub fn check_key_n(
+        &self,
+        key: &K,
+        n: NonZeroU32,
+    ) -> Result<Option<MW::PositiveOutcome>, NegativeMultiDecision<MW::NegativeOutcome>> {
+        self.check_sampled(fnv1a(key), |lim| lim.check_key_n(key, n))
+    }
+}
+
+impl<K, S, C, MW> fmt::Debug for SampledLimiter<K, S, C, MW>
+where
+    S: StateStore<Key = K>,
+    C: clock::Clock,
+    MW: RateLimitingMiddleware<C::Instant>,
+    RateLimiter<K, S, C, MW>: fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SampledLimiter")
+            .field("limiter", &self.limiter)
+            .field("sampling", &self.sampling)
+            .field("fraction", &self.fraction())
+            .field("bypassed", &self.bypassed())
+            .finish()
+    }
+}
+
+/// # Sampled rate limiters
+impl<K, S, C, MW> RateLimiter<K, S, C, MW>
+where
+    S: StateStore<Key = K>,
+    C: clock::Clock,
+    MW: RateLimitingMiddleware<C::Instant>,
+{
+    /// Wraps the rate limiter in a [`SampledLimiter`] that enforces it on only the given
+    /// fraction (between 0 and 1) of the traffic.
+    pub fn into_sampled(self, sampling: Sampling, fraction: f64) -> SampledLimiter<K, S, C, MW> {
+        SampledLimiter::new(self, sampling, fraction)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::clock::FakeRelativeClock;
+    use crate::Quota;
+    use nonzero_ext::nonzero;
+
+    #[test]
+    fn random_sampling() {
+        let clock = FakeRelativeClock::default();
+        let lim = RateLimiter::direct_with_clock(Quota::per_hour(nonzero!(1u32)), &clock)
+            .into_sampled(Sampling::Random, 0.5);
+        let limited = (0..1000).filter(|_| lim.check().is_err()).count();
+        assert!((400..600).contains(&limited), "{} were limited", limited);
+        // The one cell in the quota conformed:
+        assert_eq!(lim.bypassed(), 999 - limited as u64);
+
+        lim.set_fraction(0.0);
+        assert_eq!(lim.fraction(), 0.0);
+        assert_eq!(lim.check(), Ok(None));
+        assert_eq!(lim.check_n(nonzero!(2u32)), Ok(None));
+        lim.set_fraction(2.0);
+        assert_eq!(lim.fraction(), 1.0);
+        assert!(lim.check().is_err());
+        lim.set_fraction(f64::NAN);
+        assert_eq!(lim.fraction(), 0.0);
+
+        assert_eq!(lim.sampling(), Sampling::Random);
+        assert!(format!("{:?}", lim).contains("bypassed"));
+        let _ = lim.into_inner();
+    }
+
+    #[test]
+    fn sampling_by_key_is_stable() {
+        let clock = FakeRelativeClock::default();
+        let lim = RateLimiter::direct_with_clock(Quota::per_hour(nonzero!(1u32)), &clock)
+            .into_sampled(Sampling::ByKey, 0.5);
+        let first = lim.check().is_ok() && lim.check().is_ok();
+        for _ in 0..10 {
+            assert_eq!(lim.check().is_ok(), first);
+        }
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn keyed_sampling_grows() {
+        let clock = FakeRelativeClock::default();
+        let lim = RateLimiter::hashmap_with_clock(Quota::per_hour(nonzero!(1u32)), &clock)
+            .into_sampled(Sampling::ByKey, 0.3);
+        let sampled = |lim: &SampledLimiter<_, _, _, _>| {
+            (0..200u32)
+                .filter(|key| lim.check_key_n(key, nonzero!(1u32)) != Ok(None))
+                .collect::<Vec<_>>()
+        };
+        let few = sampled(&lim);
+        lim.set_fraction(0.6);
+        let more = sampled(&lim);
+        assert!(few.len() < more.len());
+        assert!(few.iter().all(|key| more.contains(key)));
+        assert_eq!(lim.limiter().len(), more.len());
+    }
+}
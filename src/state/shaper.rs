@@ -0,0 +1,459 @@
+//! Traffic shaping: scheduling cells for the time that they conform, instead of rejecting them.
+
+use crate::compat::prelude::*;
+
+use crate::clock::{self, Reference};
+use crate::compat::collections::BinaryHeap;
+use crate::middleware::RateLimitingMiddleware;
+use crate::state::direct::InsufficientCapacity;
+use crate::state::keyed::KeyedStateStore;
+use crate::state::{DirectStateStore, NotKeyed, StateStore};
+use crate::sync::Mutex;
+use crate::RateLimiter;
+use futures::task::{Context, Poll, Waker};
+use futures::{Future, Stream};
+use futures_timer::Delay;
+use std::cmp::Ordering;
+use std::fmt;
+use std::hash::Hash;
+use std::num::NonZeroU32;
+use std::pin::Pin;
+
+/// # Direct rate limiters - Scheduling cells
+///
+/// Instead of letting a cell through or rejecting it, these methods schedule it for the
+/// earliest time that it conforms (its *release time*), and use up the capacity as if it had
+/// arrived then. This is the GCRA's virtual scheduling, which shapes traffic rather than
+/// policing it: Cells are never rejected, but can be scheduled arbitrarily far into the future
+/// if they keep arriving faster than the quota allows.
+///
+/// Scheduling doesn't make a decision, so it doesn't consult the middleware.
+impl<S, C, MW> RateLimiter<NotKeyed, S, C, MW>
+where
+    S: DirectStateStore,
+    C: clock::Clock,
+    MW: RateLimitingMiddleware<C::Instant>,
+{
+    /// Schedules a single cell, returning its release time.
+    ///
+    /// # Example
+    /// ```rust
+    /// # use nonzero_ext::*;
+    /// use governor::{clock::{Clock, FakeRelativeClock}, Quota, RateLimiter};
+    /// use std::time::Duration;
+    ///
+    /// let clock = FakeRelativeClock::default();
+    /// let lim = RateLimiter::direct_with_clock(Quota::per_second(nonzero!(2u32)), &clock);
+    /// let now = clock.now();
+    /// assert_eq!(lim.schedule(), now);
+    /// assert_eq!(lim.schedule(), now);
+    /// assert_eq!(lim.schedule(), now + Duration::from_millis(500));
+    /// assert_eq!(lim.schedule(), now + Duration::from_secs(1));
+    /// ```
+    pub fn schedule(&self) -> C::Instant {
+        self.schedule_n(NonZeroU32::MIN)
+            .unwrap_or_else(|_| self.clock.now())
+    }
+
+    /// Schedules `n` cells together, returning their release time, or an error if they exceed
+    /// the burst capacity (without scheduling them).
+    pub fn schedule_n(&self, n: NonZeroU32) -> Result<C::Instant, InsufficientCapacity> {
+        self.gcra()
+            .schedule_n(
+                self.start,
+                &NotKeyed::NonKey,
+                n,
+                &self.state,
+                self.clock.now(),
+            )
+            .ok_or_else(|| InsufficientCapacity(self.gcra.quota().burst_size().get()))
+    }
+}
+
+/// # Keyed rate limiters - Scheduling cells
+///
+/// See [the direct rate limiters' scheduling](#direct-rate-limiters---scheduling-cells).
+impl<K, S, C, MW> RateLimiter<K, S, C, MW>
+where
+    S: KeyedStateStore<K>,
+    K: Hash,
+    C: clock::Clock,
+    MW: RateLimitingMiddleware<C::Instant>,
+{
+    /// Schedules a single cell for the given key, returning its release time.
+    pub fn schedule_key(&self, key: &K) -> C::Instant {
+        self.schedule_key_n(key, NonZeroU32::MIN)
+            .unwrap_or_else(|_| self.clock.now())
+    }
+
+    /// Schedules `n` cells together for the given key, returning their release time, or an
+    /// error if they exceed the burst capacity (without scheduling them).
+    pub fn schedule_key_n(
+        &self,
+        key: &K,
+        n: NonZeroU32,
+    ) -> Result<C::Instant, InsufficientCapacity> {
+        self.gcra()
+            .schedule_n(self.start, key, n, &self.state, self.clock.now())
+            .ok_or_else(|| InsufficientCapacity(self.gcra.quota().burst_size().get()))
+    }
+}
+
+/// An item waiting in a [`Shaper`] for its release time.
+struct Scheduled<T, P> {
+    release: P,
+    /// The order that the item was pushed in, which breaks ties between release times.
+    seq: u64,
+    item: T,
+}
+
+impl<T, P: Ord> PartialEq for Scheduled<T, P> {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl<T, P: Ord> Eq for Scheduled<T, P> {}
+
+impl<T, P: Ord> PartialOrd for Scheduled<T, P> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<T, P: Ord> Ord for Scheduled<T, P> {
+    /// Orders the earliest release first, as the greatest item of a [`BinaryHeap`].
+    fn cmp(&self, other: &Self) -> Ordering {
+        (&other.release, other.seq).cmp(&(&self.release, self.seq))
+    }
+}
+
+struct Queue<T, P> {
+    heap: BinaryHeap<Scheduled<T, P>>,
+    next_seq: u64,
+    /// The task waiting for the next release, which a push wakes up to look again.
+    waker: Option<Waker>,
+}
+
+/// A traffic shaper: a queue that releases each item that is pushed into it at the time that
+/// its rate limiter schedules it for, earliest release time first.
+///
+/// Items are scheduled when they are pushed (see [`RateLimiter::schedule`]), and
+/// [`released`](#method.released) emits them once their release time comes. For a keyed rate
+/// limiter, release times of different keys interleave; the shaper emits them in
+/// earliest-deadline-first order, and items with the same release time in the order that they
+/// were pushed in.
+///
+/// The queue is unbounded: Check [`len`](#method.len) or the release times that
+/// [`push`](#method.push) returns to stop accepting items that would wait too long.
+///
+/// # Example
+/// ```rust
+/// # use nonzero_ext::*;
+/// use futures::{executor::block_on, StreamExt};
+/// use governor::{Quota, RateLimiter};
+///
+/// let shaper = RateLimiter::direct(Quota::per_second(nonzero!(50u32))).into_shaper();
+/// for i in 0..3 {
+///     shaper.push(i);
+/// }
+/// let released: Vec<_> = block_on(shaper.released().take(3).collect());
+/// assert_eq!(released, vec![0, 1, 2]);
+/// ```
+pub struct Shaper<T, K, S, C, MW>
+where
+    S: StateStore<Key = K>,
+    C: clock::Clock,
+    MW: RateLimitingMiddleware<C::Instant>,
+{
+    limiter: RateLimiter<K, S, C, MW>,
+    queue: Mutex<Queue<T, C::Instant>>,
+}
+
+impl<T, K, S, C, MW> Shaper<T, K, S, C, MW>
+where
+    S: StateStore<Key = K>,
+    C: clock::Clock,
+    MW: RateLimitingMiddleware<C::Instant>,
+{
+    /// Constructs an empty shaper that schedules items with the given rate limiter.
+    pub fn new(limiter: RateLimiter<K, S, C, MW>) -> Self {
+        Shaper {
+            limiter,
+            queue: Mutex::new(Queue {
+                heap: BinaryHeap::new(),
+                next_seq: 0,
+                waker: None,
+            }),
+        }
+    }
+
+    /// Returns the rate limiter that schedules the items.
+    pub fn limiter(&self) -> &RateLimiter<K, S, C, MW> {
+        &self.limiter
+    }
+
+    /// Returns the number of items waiting for their release time.
+    pub fn len(&self) -> usize {
+        self.queue.lock().heap.len()
+    }
+
+    /// Returns `true` if no items are waiting.
+    pub fn is_empty(&self) -> bool {
+        self.queue.lock().heap.is_empty()
+    }
+
+    /// Returns the release time of the item that is released next, if any.
+    pub fn next_release(&self) -> Option<C::Instant> {
+        self.queue.lock().heap.peek().map(|next| next.release)
+    }
+
+    /// Removes and returns the item that is released next if it is due at `now`, without
+    /// waiting.
+    pub fn pop_due(&self, now: C::Instant) -> Option<T> {
+        let mut queue = self.queue.lock();
+        match queue.heap.peek() {
+            Some(next) if next.release <= now => queue.heap.pop().map(|next| next.item),
+            _ => None,
+        }
+    }
+
+    /// Queues an item for the given release time.
+    fn enqueue(&self, release: C::Instant, item: T) -> C::Instant {
+        let mut queue = self.queue.lock();
+        let seq = queue.next_seq;
+        queue.next_seq += 1;
+        queue.heap.push(Scheduled { release, seq, item });
+        if let Some(waker) = queue.waker.take() {
+            waker.wake();
+        }
+        release
+    }
+
+    /// Returns a stream that emits the items when their release time comes, earliest first.
+    ///
+    /// The stream waits for new items once the queue is empty, and never ends. Only the task
+    /// that polled a stream last is woken up by new items, so the items should be read from
+    /// one stream at a time.
+    pub fn released(&self) -> Released<'_, T, K, S, C, MW> {
+        Released {
+            shaper: self,
+            delay: None,
+        }
+    }
+}
+
+/// # Direct shapers
+impl<T, S, C, MW> Shaper<T, NotKeyed, S, C, MW>
+where
+    S: DirectStateStore,
+    C: clock::Clock,
+    MW: RateLimitingMiddleware<C::Instant>,
+{
+    /// Schedules an item as a single cell, and queues it for its release time, which it
+    /// returns.
+    pub fn push(&self, item: T) -> C::Instant {
+        let release = self.limiter.schedule();
+        self.enqueue(release, item)
+    }
+
+    /// Schedules an item as `n` cells (e.g. its size in bytes), and queues it for its release
+    /// time. Returns the item if it exceeds the burst capacity.
+    pub fn push_n(&self, n: NonZeroU32, item: T) -> Result<C::Instant, (InsufficientCapacity, T)> {
+        match self.limiter.schedule_n(n) {
+            Ok(release) => Ok(self.enqueue(release, item)),
+            Err(error) => Err((error, item)),
+        }
+    }
+}
+
+/// # Keyed shapers
+impl<T, K, S, C, MW> Shaper<T, K, S, C, MW>
+where
+    S: KeyedStateStore<K>,
+    K: Hash,
+    C: clock::Clock,
+    MW: RateLimitingMiddleware<C::Instant>,
+{
+    /// Schedules an item as a single cell for the given key, and queues it for its release
+    /// time, which it returns.
+    pub fn push_key(&self, key: &K, item: T) -> C::Instant {
+        let release = self.limiter.schedule_key(key);
+        self.enqueue(release, item)
+    }
+
+    /// Schedules an item as `n` cells for the given key, and queues it for its release time.
+    /// Returns the item if it exceeds the burst capacity.
+    pub fn push_key_n(
+        &self,
+        key: &K,
+        n: NonZeroU32,
+        item: T,
+    ) -> Result<C::Instant, (InsufficientCapacity, T)> {
+        match self.limiter.schedule_key_n(key, n) {
+            Ok(release) => Ok(self.enqueue(release, item)),
+            Err(error) => Err((error, item)),
+        }
+    }
+}
+
+impl<T, K, S, C, MW> fmt::Debug for Shaper<T, K, S, C, MW>
+where
+    S: StateStore<Key = K>,
+    C: clock::Clock,
+    MW: RateLimitingMiddleware<C::Instant>,
+    RateLimiter<K, S, C, MW>: fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Shaper")
+            .field("limiter", &self.limiter)
+            .field("len", &self.len())
+            .field("next_release", &self.next_release())
+            .finish()
+    }
+}
+
+/// A [`Stream`] of the items in a [`Shaper`], as their release times come.
+///
+/// This is produced by [`Shaper::released`].
+pub struct Released<'a, T, K, S, C, MW>
+where
+    S: StateStore<Key = K>,
+    C: clock::Clock,
+    MW: RateLimitingMiddleware<C::Instant>,
+{
+    shaper: &'a Shaper<T, K, S, C, MW>,
+    delay: Option<Delay>,
+}
+
+impl<T, K, S, C, MW> Stream for Released<'_, T, K, S, C, MW>
+where
+    S: StateStore<Key = K>,
+    C: clock::ReasonablyRealtime,
+    MW: RateLimitingMiddleware<C::Instant>,
+{
+    type Item = T;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        loop {
+            let wait = {
+                let mut queue = self.shaper.queue.lock();
+                let now = self.shaper.limiter.clock.now();
+                let wait = match queue.heap.peek() {
+                    Some(next) if next.release <= now => {
+                        return Poll::Ready(queue.heap.pop().map(|next| next.item));
+                    }
+                    Some(next) => Some(next.release.duration_since(now)),
+                    None => None,
+                };
+                queue.waker = Some(cx.waker().clone());
+                wait
+            };
+            match wait {
+                None => {
+                    self.delay = None;
+                    return Poll::Pending;
+                }
+                Some(wait) => {
+                    let mut delay = Delay::new(wait.into());
+                    if Pin::new(&mut delay).poll(cx).is_pending() {
+                        self.delay = Some(delay);
+                        return Poll::Pending;
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl<T, K, S, C, MW> fmt::Debug for Released<'_, T, K, S, C, MW>
+where
+    S: StateStore<Key = K>,
+    C: clock::Clock,
+    MW: RateLimitingMiddleware<C::Instant>,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Released").finish()
+    }
+}
+
+/// # Traffic shapers
+impl<K, S, C, MW> RateLimiter<K, S, C, MW>
+where
+    S: StateStore<Key = K>,
+    C: clock::Clock,
+    MW: RateLimitingMiddleware<C::Instant>,
+{
+    /// Turns the rate limiter into a [`Shaper`] that releases the items pushed into it as the
+    /// rate limiter schedules them.
+    pub fn into_shaper<T>(self) -> Shaper<T, K, S, C, MW> {
+        Shaper::new(self)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::clock::{Clock, FakeRelativeClock};
+    use crate::Quota;
+    use futures::executor::block_on;
+    use futures::StreamExt;
+    use nonzero_ext::nonzero;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn schedules_batches() {
+        let clock = FakeRelativeClock::default();
+        let lim = RateLimiter::direct_with_clock(Quota::per_second(nonzero!(4u32)), &clock);
+        let now = clock.now();
+        assert_eq!(lim.schedule_n(nonzero!(3u32)), Ok(now));
+        assert_eq!(
+            lim.schedule_n(nonzero!(2u32)),
+            Ok(now + Duration::from_millis(250))
+        );
+        assert_eq!(lim.schedule_n(nonzero!(5u32)), Err(InsufficientCapacity(4)));
+        // Scheduled cells use up the capacity, so checks see it:
+        clock.advance(Duration::from_millis(300));
+        assert!(lim.check().is_err());
+    }
+
+    #[test]
+    fn releases_keys_earliest_first() {
+        let clock = FakeRelativeClock::default();
+        let shaper = RateLimiter::hashmap_with_clock(Quota::per_second(nonzero!(1u32)), &clock)
+            .into_shaper();
+        let now = clock.now();
+        assert_eq!(shaper.push_key(&"a", 1), now);
+        assert_eq!(shaper.push_key(&"a", 2), now + Duration::from_secs(1));
+        assert_eq!(shaper.push_key(&"b", 3), now);
+        assert!(shaper.push_key_n(&"b", nonzero!(2u32), 4).is_err());
+        assert_eq!(shaper.len(), 3);
+        assert_eq!(shaper.pop_due(now), Some(1));
+        assert_eq!(shaper.pop_due(now), Some(3));
+        assert_eq!(shaper.pop_due(now), None);
+        assert_eq!(shaper.next_release(), Some(now + Duration::from_secs(1)));
+        assert!(!format!("{:?}", shaper).is_empty());
+    }
+
+    #[test]
+    fn streams_items_at_their_release_time() {
+        let shaper =
+            RateLimiter::direct(Quota::per_second(nonzero!(20u32)).allow_burst(nonzero!(1u32)))
+                .into_shaper();
+        let start = Instant::now();
+        for i in 0..3 {
+            shaper.push(i);
+        }
+        assert_eq!(
+            shaper.push_n(nonzero!(2u32), 3).map_err(|(_, item)| item),
+            Err(3)
+        );
+        let mut released = shaper.released();
+        assert!(!format!("{:?}", released).is_empty());
+        assert_eq!(block_on(released.next()), Some(0));
+        assert_eq!(block_on(released.next()), Some(1));
+        assert_eq!(block_on(released.next()), Some(2));
+        assert!(start.elapsed() >= Duration::from_millis(100));
+        assert!(shaper.is_empty());
+    }
+}
@@ -0,0 +1,195 @@
+//! A direct state store in memory shared between processes.
+
+use crate::compat::prelude::*;
+
+use crate::nanos::Nanos;
+use crate::state::{InMemoryState, NotKeyed, StateStore};
+use crate::{clock, gcra::Gcra, middleware::NoOpMiddleware, Quota, RateLimiter};
+use std::fmt;
+use std::fs::OpenOptions;
+use std::io;
+use std::marker::PhantomData;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::ptr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Identifies files holding a [`SharedMemoryState`] (and the version of their layout).
+const MAGIC: u64 = u64::from_le_bytes(*b"GOVSHM01");
+
+/// The layout of the shared region. All fields start out as zero in a newly-created file.
+///
+/// The state store is only built for targets with native 64-bit atomics, where the state's
+/// atomics are lock-free like the header's, and work across processes.
+#[repr(C)]
+struct Region {
+    magic: AtomicU64,
+    /// The time on the [`SystemClock`][clock::SystemClock] (in nanoseconds since the UNIX
+    /// epoch) that all rate limiters using the region count their state from.
+    epoch: AtomicU64,
+    state: InMemoryState,
+}
+
+/// A direct rate-limiting state that lives in a file mapped into the memory of every process
+/// that uses it, so that several processes on one machine can enforce a single rate limit
+/// together, e.g. the pre-forked workers of a server, or a sidecar and its host process.
+///
+/// Decisions are made with atomic operations on the shared memory, just like with
+/// [`InMemoryState`], so they are as fast as in-process ones. To agree on the time, rate
+/// limiters with this state use the [`SystemClock`][clock::SystemClock], counting from an epoch
+/// that the first process to open the file records in it.
+///
+/// All processes must use the same quota and the same file (ideally one on a `tmpfs`, like
+/// `/dev/shm`, that isn't written back to disk). Like any rate limiter using the system clock,
+/// wall clock adjustments can make it let through too few or too many cells.
+///
+/// This is only available on Unix platforms with native 64-bit atomics, with the
+/// `shared-memory` feature.
+///
+/// # Example
+/// ```rust
+/// # use nonzero_ext::*;
+/// use governor::{Quota, RateLimiter};
+///
+/// # let path = std::env::temp_dir().join(format!("governor-doctest-{}", std::process::id()));
+/// let quota = Quota::per_second(nonzero!(2u32));
+/// // In two different processes:
+/// let first = RateLimiter::shared_memory(quota, &path).unwrap();
+/// let second = RateLimiter::shared_memory(quota, &path).unwrap();
+///
+/// assert_eq!(Ok(()), first.check());
+/// assert_eq!(Ok(()), second.check());
+/// assert!(first.check().is_err());
+/// # std::fs::remove_file(&path).unwrap();
+/// ```
+pub struct SharedMemoryState {
+    region: ptr::NonNull<Region>,
+}
+
+// Safety: the region is only ever accessed through atomics, and stays mapped for as long as
+// the SharedMemoryState exists.
+unsafe impl Send for SharedMemoryState {}
+unsafe impl Sync for SharedMemoryState {}
+
+impl SharedMemoryState {
+    /// Opens the shared state in the file at `path`, creating the file if necessary.
+    ///
+    /// Returns an error if the file can't be created or mapped, or if it holds something other
+    /// than a shared rate-limiting state.
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<SharedMemoryState> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+        let len = std::mem::size_of::<Region>();
+        if file.metadata()?.len() < len as u64 {
+            // Concurrent openers all extend the file with zeroes, which is harmless.
+            file.set_len(len as u64)?;
+        }
+        // Safety: the file is at least `len` bytes long; the mapping outlives the file handle.
+        let addr = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED,
+                file.as_raw_fd(),
+                0,
+            )
+        };
+        if addr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        let state = SharedMemoryState {
+            // Mappings are page-aligned, and mmap never returns null on success:
+            region: ptr::NonNull::new(addr as *mut Region).expect("mmap returned null"),
+        };
+        let region = state.region();
+        match region
+            .magic
+            .compare_exchange(0, MAGIC, Ordering::AcqRel, Ordering::Acquire)
+        {
+            Ok(_) => {}
+            Err(MAGIC) => {}
+            Err(_) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "not a governor shared-memory state",
+                ))
+            }
+        }
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos() as u64;
+        let _ = region
+            .epoch
+            .compare_exchange(0, now.max(1), Ordering::AcqRel, Ordering::Acquire);
+        Ok(state)
+    }
+
+    fn region(&self) -> &Region {
+        // Safety: the region stays mapped until `self` is dropped.
+        unsafe { self.region.as_ref() }
+    }
+
+    /// Returns the time that the rate-limiting state counts from.
+    fn epoch(&self) -> SystemTime {
+        let epoch = self.region().epoch.load(Ordering::Acquire);
+        UNIX_EPOCH + Duration::from_nanos(epoch)
+    }
+}
+
+impl Drop for SharedMemoryState {
+    fn drop(&mut self) {
+        // Safety: the region was mapped with this length in `open`, and isn't used after this.
+        unsafe {
+            libc::munmap(
+                self.region.as_ptr() as *mut libc::c_void,
+                std::mem::size_of::<Region>(),
+            );
+        }
+    }
+}
+
+impl StateStore for SharedMemoryState {
+    type Key = NotKeyed;
+
+    fn measure_and_replace<T, F, E>(&self, key: &Self::Key, f: F) -> Result<T, E>
+    where
+        F: Fn(Option<Nanos>) -> Result<(T, Nanos), E>,
+    {
+        self.region().state.measure_and_replace(key, f)
+    }
+}
+
+impl fmt::Debug for SharedMemoryState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SharedMemoryState")
+            .field("epoch", &self.epoch())
+            .field("state", &self.region().state)
+            .finish()
+    }
+}
+
+/// # Direct rate limiters in shared memory - Constructors
+impl RateLimiter<NotKeyed, SharedMemoryState, clock::SystemClock, NoOpMiddleware<SystemTime>> {
+    /// Constructs a direct rate limiter whose state is shared with all other rate limiters
+    /// using the file at `path`, in this or other processes on the same machine.
+    ///
+    /// See [`SharedMemoryState`] for the requirements on the file and the quota.
+    pub fn shared_memory<P: AsRef<Path>>(quota: Quota, path: P) -> io::Result<Self> {
+        let state = SharedMemoryState::open(path)?;
+        Ok(RateLimiter {
+            start: state.epoch(),
+            state,
+            clock: clock::SystemClock,
+            gcra: Gcra::new(quota),
+            tuning: Default::default(),
+            middleware: PhantomData,
+        })
+    }
+}
@@ -0,0 +1,110 @@
+//! The settings that rate limiters only keep once they are tuned at runtime.
+
+use crate::clock::{self, Reference};
+use crate::gcra::Gcra;
+use crate::middleware::RateLimitingMiddleware;
+use crate::nanos::Nanos;
+use crate::state::{Epoch, Pressure, RateLimiter, Resume, StateStore, Waiters};
+use crate::sync::{Arc, Once};
+use alloc::borrow::Cow;
+
+/// A rate limiter's runtime settings: its [pressure](RateLimiter::set_pressure), the epoch
+/// that [resets](RateLimiter::reset) move forward, its [resume](RateLimiter::notify_resume)
+/// state, and the tasks [waiting](RateLimiter::until_ready) for it.
+///
+/// Rate limiters construct their settings the first time that one of them is used, so that
+/// decisions of rate limiters that are never tuned skip them entirely. Clones share the
+/// settings, like [shared](RateLimiter::into_shared) rate limiters share their state: Shedding
+/// load, resetting or resuming must apply to all rate limiters that admit cells from a state
+/// store.
+#[derive(Debug, Default)]
+pub(crate) struct Tuning {
+    pub(crate) pressure: Pressure,
+    pub(crate) epoch: Epoch,
+    pub(crate) resume: Resume,
+    pub(crate) waiters: Waiters,
+}
+
+/// A rate limiter's settings, if it was tuned yet.
+pub(crate) type TuningCell = Once<Arc<Tuning>>;
+
+impl<K, S, C, MW> RateLimiter<K, S, C, MW>
+where
+    S: StateStore<Key = K>,
+    C: clock::Clock,
+    MW: RateLimitingMiddleware<C::Instant>,
+{
+    /// Returns the rate limiter's settings, if it was tuned yet.
+    #[inline]
+    pub(crate) fn tuned(&self) -> Option<&Tuning> {
+        self.tuning.get().map(|tuning| &**tuning)
+    }
+
+    /// Returns the rate limiter's settings, constructing them if it wasn't tuned yet.
+    pub(crate) fn tuning(&self) -> &Arc<Tuning> {
+        self.tuning.call_once(Arc::default)
+    }
+
+    /// Returns a cell that shares the rate limiter's settings, for a clone of it.
+    pub(crate) fn share_tuning(&self) -> TuningCell {
+        Once::initialized(Arc::clone(self.tuning()))
+    }
+
+    /// Returns the GCRA's parameters under the rate limiter's current settings; those of an
+    /// untuned rate limiter are borrowed, so that its decisions don't copy them.
+    #[inline]
+    pub(crate) fn gcra(&self) -> Cow<'_, Gcra> {
+        match self.tuned() {
+            None => Cow::Borrowed(&self.gcra),
+            Some(tuning) => Cow::Owned(self.tuned_gcra(tuning)),
+        }
+    }
+
+    /// Returns the GCRA's parameters under the given settings.
+    fn tuned_gcra(&self, tuning: &Tuning) -> Gcra {
+        let gcra = self
+            .gcra
+            .with_epoch(tuning.epoch.load())
+            .under_pressure(tuning.pressure.load());
+        let now = || self.clock.now().duration_since(self.start);
+        gcra.with_floor(tuning.resume.floor(now, &gcra))
+    }
+
+    /// Returns the GCRA's parameters in the rate limiter's current epoch, without pressure or
+    /// resumes, like the [housekeeping](#method.retain_recent) uses them.
+    pub(crate) fn gcra_in_epoch(&self) -> Gcra {
+        self.gcra.with_epoch(self.epoch())
+    }
+
+    /// Returns the time that the rate limiter's resets skipped.
+    pub(crate) fn epoch(&self) -> Nanos {
+        self.tuned()
+            .map_or_else(|| Nanos::from(0), |tuning| tuning.epoch.load())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::clock::FakeRelativeClock;
+    use crate::{Quota, RateLimiter};
+    use nonzero_ext::nonzero;
+
+    #[test]
+    fn decisions_dont_construct_settings() {
+        let clock = FakeRelativeClock::default();
+        let lim = RateLimiter::direct_with_clock(Quota::per_second(nonzero!(2u32)), &clock);
+        assert_eq!(lim.check(), Ok(()));
+        assert_eq!(lim.check_n(nonzero!(2u32)).ok(), None);
+        assert_eq!(lim.pressure(), 1.0);
+        #[cfg(feature = "std")]
+        assert_eq!(lim.jump_policy(), None);
+        lim.wake_waiters();
+        assert!(lim.tuned().is_none());
+
+        // Sharing the rate limiter shares settings that its clones construct later:
+        let lim = lim.into_shared();
+        let clone = lim.clone();
+        clone.set_pressure(0.5);
+        assert_eq!(lim.pressure(), 0.5);
+    }
+}
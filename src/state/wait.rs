@@ -0,0 +1,523 @@
+//! The wait loop that all `async` waiting methods share, and the layers on top of rate limiters
+//! that change how tasks wait for them.
+
+use crate::clock::{self, Reference};
+use crate::middleware::RateLimitingMiddleware;
+use crate::state::direct::{InsufficientCapacity, TimedOut};
+use crate::state::keyed::KeyedStateStore;
+use crate::state::{DirectStateStore, NotKeyed, StateStore};
+use crate::sync::{AtomicUsize, Ordering};
+use crate::{hash::fnv1a, Jitter, NegativeMultiDecision, NotUntil, RateLimiter};
+use std::cmp;
+use std::fmt;
+use std::hash::Hash;
+use std::num::NonZeroU32;
+use std::ops::ControlFlow;
+use std::time::Duration;
+
+pub(crate) mod sealed {
+    pub trait Sealed {}
+}
+
+/// A rate limiter, or a rate limiter with [layers](Layered) on top of it, that tasks can wait
+/// for.
+///
+/// This trait is sealed: It is implemented for [`RateLimiter`] and for [`Layered`] rate
+/// limiters only.
+pub trait Waitable: sealed::Sealed {
+    /// The rate limiter's type of keys.
+    type Key;
+    /// The rate limiter's state store.
+    type State: StateStore<Key = Self::Key>;
+    /// The rate limiter's clock.
+    type Clock: clock::Clock;
+    /// The rate limiter's middleware.
+    type Middleware: RateLimitingMiddleware<<Self::Clock as clock::Clock>::Instant>;
+    /// What a wait results in when the rate limiter's outcome is `T`: `T` itself without any
+    /// layers, and a `Result` with the error of the layer on top around the result of the
+    /// layers below it otherwise.
+    type Output<T>;
+
+    /// Returns the rate limiter at the bottom of the layers.
+    fn limiter(&self) -> &RateLimiter<Self::Key, Self::State, Self::Clock, Self::Middleware>;
+
+    /// Ends the wait before an attempt if one of the layers doesn't allow another.
+    #[doc(hidden)]
+    fn admit<T>(&self) -> Result<(), Self::Output<T>>;
+
+    /// Counts the task as waiting for `key` in each layer, unless one of them doesn't let it
+    /// wait; the task stops counting as waiting with [`leave`](Waitable::leave).
+    #[doc(hidden)]
+    fn enter<T>(&self, key: &Self::Key) -> Result<(), Self::Output<T>>;
+
+    /// Stops counting the task as waiting for `key`.
+    #[doc(hidden)]
+    fn leave(&self, key: &Self::Key);
+
+    /// Returns the result of a wait that the rate limiter ended with `outcome`.
+    #[doc(hidden)]
+    fn output<T>(outcome: T) -> Self::Output<T>;
+}
+
+/// A layer of a [`Layered`] rate limiter, which adds its rules to waiting for the layers
+/// below: A [`WaitBound`](crate::state::WaitBound) bounds the number of waiting tasks, and a
+/// [`Closer`](crate::state::Closer) ends the waits once a service shuts down.
+///
+/// This trait is sealed.
+pub trait Layer<K>: sealed::Sealed {
+    /// The error of the waits that the layer ends.
+    type Error;
+
+    /// Ends the wait before an attempt, or lets it go on.
+    #[doc(hidden)]
+    fn admit(&self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    /// Counts the task as waiting for `key`, or ends its wait.
+    #[doc(hidden)]
+    fn enter(&self, key: &K) -> Result<(), Self::Error>;
+
+    /// Stops counting the task as waiting for `key`.
+    #[doc(hidden)]
+    fn leave(&self, key: &K);
+}
+
+/// The number of tasks waiting in a layer.
+#[derive(Debug, Default)]
+pub(crate) struct Count(AtomicUsize);
+
+impl Count {
+    /// Returns the number of waiting tasks.
+    pub(crate) fn get(&self) -> usize {
+        self.0.load(Ordering::Acquire)
+    }
+
+    /// Counts another waiting task, unless `max` tasks are waiting already.
+    pub(crate) fn enter(&self, max: usize) -> bool {
+        self.0
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |waiting| {
+                (waiting < max).then_some(waiting + 1)
+            })
+            .is_ok()
+    }
+
+    /// Stops counting a waiting task.
+    pub(crate) fn leave(&self) {
+        self.0.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+/// Counts a task as waiting in the layers of a rate limiter while it is alive.
+struct Waiting<'a, W: Waitable> {
+    waiter: &'a W,
+    key: &'a W::Key,
+}
+
+impl<W: Waitable> Drop for Waiting<'_, W> {
+    fn drop(&mut self) {
+        self.waiter.leave(self.key);
+    }
+}
+
+/// Makes attempts until one breaks, waiting for as long as the others ask for in between: the
+/// wait loop of all `async` waiting methods.
+///
+/// The layers of `waiter` may end the wait before each attempt, and count the task as waiting
+/// from its first wait on. `hash` is the hash of the key that the attempts check (`None` for
+/// direct rate limiters), which refunds for that key wake the task up for.
+pub(crate) async fn wait<W, T>(
+    waiter: &W,
+    key: &W::Key,
+    hash: Option<u64>,
+    mut attempt: impl FnMut() -> ControlFlow<T, Duration>,
+) -> W::Output<T>
+where
+    W: Waitable,
+    W::Clock: clock::ReasonablyRealtime,
+{
+    let limiter = waiter.limiter();
+    let mut waiting = None;
+    loop {
+        let generation = limiter.tuning().waiters.generation();
+        // Layers that end waits notify the waiters after they change, so a wait that starts
+        // after they admit it here is woken up:
+        if let Err(output) = waiter.admit() {
+            return output;
+        }
+        match attempt() {
+            ControlFlow::Break(outcome) => return W::output(outcome),
+            ControlFlow::Continue(wait) => {
+                if waiting.is_none() {
+                    if let Err(output) = waiter.enter(key) {
+                        return output;
+                    }
+                    waiting = Some(Waiting { waiter, key });
+                }
+                limiter.delay(generation, hash, wait).await;
+            }
+        }
+    }
+}
+
+impl<K, S, C, MW> RateLimiter<K, S, C, MW>
+where
+    S: StateStore<Key = K>,
+    C: clock::ReasonablyRealtime,
+    MW: RateLimitingMiddleware<C::Instant>,
+{
+    /// Returns how long to wait for after a negative outcome, or that the wait times out
+    /// because the deadline has passed.
+    pub(crate) fn until_deadline(
+        &self,
+        negative: NotUntil<C::Instant>,
+        deadline: C::Instant,
+    ) -> ControlFlow<TimedOut<C::Instant>, Duration> {
+        let now = self.clock.now();
+        if now >= deadline {
+            return ControlFlow::Break(TimedOut(negative));
+        }
+        let remaining = deadline.duration_since(now).into();
+        ControlFlow::Continue(cmp::min(negative.wait_time_from(now), remaining))
+    }
+}
+
+impl<K, S, C, MW> sealed::Sealed for RateLimiter<K, S, C, MW>
+where
+    S: StateStore<Key = K>,
+    C: clock::Clock,
+    MW: RateLimitingMiddleware<C::Instant>,
+{
+}
+
+impl<K, S, C, MW> Waitable for RateLimiter<K, S, C, MW>
+where
+    S: StateStore<Key = K>,
+    C: clock::Clock,
+    MW: RateLimitingMiddleware<C::Instant>,
+{
+    type Key = K;
+    type State = S;
+    type Clock = C;
+    type Middleware = MW;
+    type Output<T> = T;
+
+    fn limiter(&self) -> &RateLimiter<K, S, C, MW> {
+        self
+    }
+
+    fn admit<T>(&self) -> Result<(), T> {
+        Ok(())
+    }
+
+    fn enter<T>(&self, _key: &K) -> Result<(), T> {
+        Ok(())
+    }
+
+    fn leave(&self, _key: &K) {}
+
+    fn output<T>(outcome: T) -> T {
+        outcome
+    }
+}
+
+/// A rate limiter with a layer on top that changes how tasks wait for it: A
+/// [`BoundedWaitLimiter`](crate::state::BoundedWaitLimiter) bounds the number of waiting tasks,
+/// and a [`ClosableLimiter`](crate::state::ClosableLimiter) can be closed to end their waits.
+///
+/// The layers wrap a rate limiter, or another `Layered` rate limiter, so they stack: Their
+/// `async` waiting methods go through the rules of every layer, and fail with the error of the
+/// layer that ends the wait, nested in the `Result`s of the layers above it. Checking cells and
+/// waiting on the rate limiter at the bottom (see [`limiter`](#method.limiter)) skips the
+/// layers.
+///
+/// # Example
+/// ```rust
+/// # use nonzero_ext::*;
+/// # use futures::executor::block_on;
+/// use governor::{state::{Closed, Overloaded}, Quota, RateLimiter};
+///
+/// let lim = RateLimiter::direct(Quota::per_hour(nonzero!(1u32)))
+///     .into_bounded_wait(0)
+///     .into_closable();
+/// assert_eq!(block_on(lim.until_ready()), Ok(Ok(())));
+/// // Nobody may wait, so the bounded wait turns the next task away:
+/// assert_eq!(block_on(lim.until_ready()), Ok(Err(Overloaded(0))));
+/// lim.close();
+/// assert_eq!(block_on(lim.until_ready()), Err(Closed));
+/// ```
+pub struct Layered<L, Y> {
+    pub(crate) inner: L,
+    pub(crate) layer: Y,
+}
+
+impl<L, Y> Layered<L, Y>
+where
+    L: Waitable,
+    Y: Layer<L::Key>,
+{
+    /// Returns the rate limiter at the bottom of the layers.
+    pub fn limiter(&self) -> &RateLimiter<L::Key, L::State, L::Clock, L::Middleware> {
+        self.inner.limiter()
+    }
+
+    /// Returns the layers below this one.
+    pub fn inner(&self) -> &L {
+        &self.inner
+    }
+
+    /// Returns the layers below this one, dropping this layer.
+    pub fn into_inner(self) -> L {
+        self.inner
+    }
+}
+
+impl<L, Y> sealed::Sealed for Layered<L, Y> {}
+
+impl<L, Y> Waitable for Layered<L, Y>
+where
+    L: Waitable,
+    Y: Layer<L::Key>,
+{
+    type Key = L::Key;
+    type State = L::State;
+    type Clock = L::Clock;
+    type Middleware = L::Middleware;
+    type Output<T> = Result<L::Output<T>, Y::Error>;
+
+    fn limiter(&self) -> &RateLimiter<L::Key, L::State, L::Clock, L::Middleware> {
+        self.inner.limiter()
+    }
+
+    fn admit<T>(&self) -> Result<(), Self::Output<T>> {
+        self.layer.admit().map_err(Err)?;
+        self.inner.admit().map_err(Ok)
+    }
+
+    fn enter<T>(&self, key: &L::Key) -> Result<(), Self::Output<T>> {
+        self.layer.enter(key).map_err(Err)?;
+        self.inner.enter(key).map_err(|output| {
+            self.layer.leave(key);
+            Ok(output)
+        })
+    }
+
+    fn leave(&self, key: &L::Key) {
+        self.inner.leave(key);
+        self.layer.leave(key);
+    }
+
+    fn output<T>(outcome: T) -> Self::Output<T> {
+        Ok(L::output(outcome))
+    }
+}
+
+/// # Layered rate limiters - `async`/`await`
+impl<L, Y, S, C, MW> Layered<L, Y>
+where
+    L: Waitable<Key = NotKeyed, State = S, Clock = C, Middleware = MW>,
+    Y: Layer<NotKeyed>,
+    S: DirectStateStore,
+    C: clock::ReasonablyRealtime,
+    MW: RateLimitingMiddleware<C::Instant, NegativeOutcome = NotUntil<C::Instant>>,
+{
+    /// Asynchronously resolves as soon as the rate limiter allows it, or with the error of the
+    /// layer that ends the wait.
+    ///
+    /// See [`RateLimiter::until_ready`].
+    pub async fn until_ready(&self) -> Result<L::Output<MW::PositiveOutcome>, Y::Error> {
+        self.until_ready_with_jitter(Jitter::NONE).await
+    }
+
+    /// Asynchronously resolves as soon as the rate limiter allows it, with a randomized wait
+    /// period, or with the error of the layer that ends the wait.
+    ///
+    /// See [`RateLimiter::until_ready_with_jitter`].
+    pub async fn until_ready_with_jitter(
+        &self,
+        jitter: Jitter,
+    ) -> Result<L::Output<MW::PositiveOutcome>, Y::Error> {
+        let limiter = self.limiter();
+        wait(self, &NotKeyed::NonKey, None, || match limiter.check() {
+            Ok(x) => ControlFlow::Break(x),
+            Err(negative) => {
+                ControlFlow::Continue(jitter + negative.wait_time_from(limiter.clock.now()))
+            }
+        })
+        .await
+    }
+
+    /// Asynchronously resolves as soon as the rate limiter allows all `n` cells through, or
+    /// with the error of the layer that ends the wait.
+    ///
+    /// See [`RateLimiter::until_n_ready`].
+    pub async fn until_n_ready(
+        &self,
+        n: NonZeroU32,
+    ) -> Result<L::Output<Result<MW::PositiveOutcome, InsufficientCapacity>>, Y::Error> {
+        self.until_n_ready_with_jitter(n, Jitter::NONE).await
+    }
+
+    /// Asynchronously resolves as soon as the rate limiter allows all `n` cells through, with a
+    /// randomized wait period, or with the error of the layer that ends the wait.
+    ///
+    /// See [`RateLimiter::until_n_ready_with_jitter`].
+    pub async fn until_n_ready_with_jitter(
+        &self,
+        n: NonZeroU32,
+        jitter: Jitter,
+    ) -> Result<L::Output<Result<MW::PositiveOutcome, InsufficientCapacity>>, Y::Error> {
+        let limiter = self.limiter();
+        wait(self, &NotKeyed::NonKey, None, || match limiter.check_n(n) {
+            Ok(x) => ControlFlow::Break(Ok(x)),
+            Err(NegativeMultiDecision::BatchNonConforming(_, negative)) => {
+                ControlFlow::Continue(jitter + negative.wait_time_from(limiter.clock.now()))
+            }
+            Err(NegativeMultiDecision::InsufficientCapacity(cap)) => {
+                ControlFlow::Break(Err(InsufficientCapacity(cap)))
+            }
+        })
+        .await
+    }
+}
+
+/// # Keyed layered rate limiters - `async`/`await`
+impl<L, Y, K, S, C, MW> Layered<L, Y>
+where
+    L: Waitable<Key = K, State = S, Clock = C, Middleware = MW>,
+    Y: Layer<K>,
+    K: Hash + Eq + Clone,
+    S: KeyedStateStore<K>,
+    C: clock::ReasonablyRealtime,
+    MW: RateLimitingMiddleware<C::Instant, NegativeOutcome = NotUntil<C::Instant>>,
+{
+    /// Asynchronously resolves as soon as the rate limiter allows a cell through for the given
+    /// key, or with the error of the layer that ends the wait.
+    ///
+    /// See [`RateLimiter::until_key_ready`].
+    pub async fn until_key_ready(
+        &self,
+        key: &K,
+    ) -> Result<L::Output<MW::PositiveOutcome>, Y::Error> {
+        self.until_key_ready_with_jitter(key, Jitter::NONE).await
+    }
+
+    /// Asynchronously resolves as soon as the rate limiter allows a cell through for the given
+    /// key, with a randomized wait period, or with the error of the layer that ends the wait.
+    ///
+    /// See [`RateLimiter::until_key_ready_with_jitter`].
+    pub async fn until_key_ready_with_jitter(
+        &self,
+        key: &K,
+        jitter: Jitter,
+    ) -> Result<L::Output<MW::PositiveOutcome>, Y::Error> {
+        let limiter = self.limiter();
+        wait(self, key, Some(fnv1a(key)), || {
+            match limiter.check_key(key) {
+                Ok(x) => ControlFlow::Break(x),
+                Err(negative) => {
+                    ControlFlow::Continue(jitter + negative.wait_time_from(limiter.clock.now()))
+                }
+            }
+        })
+        .await
+    }
+
+    /// Asynchronously resolves as soon as the rate limiter allows all `n` cells through for the
+    /// given key, or with the error of the layer that ends the wait.
+    ///
+    /// See [`RateLimiter::until_key_n_ready`].
+    pub async fn until_key_n_ready(
+        &self,
+        key: &K,
+        n: NonZeroU32,
+    ) -> Result<L::Output<Result<MW::PositiveOutcome, InsufficientCapacity>>, Y::Error> {
+        self.until_key_n_ready_with_jitter(key, n, Jitter::NONE)
+            .await
+    }
+
+    /// Asynchronously resolves as soon as the rate limiter allows all `n` cells through for the
+    /// given key, with a randomized wait period, or with the error of the layer that ends the
+    /// wait.
+    ///
+    /// See [`RateLimiter::until_key_n_ready_with_jitter`].
+    pub async fn until_key_n_ready_with_jitter(
+        &self,
+        key: &K,
+        n: NonZeroU32,
+        jitter: Jitter,
+    ) -> Result<L::Output<Result<MW::PositiveOutcome, InsufficientCapacity>>, Y::Error> {
+        let limiter = self.limiter();
+        wait(self, key, Some(fnv1a(key)), || {
+            match limiter.check_key_n(key, n) {
+                Ok(x) => ControlFlow::Break(Ok(x)),
+                Err(NegativeMultiDecision::BatchNonConforming(_, negative)) => {
+                    ControlFlow::Continue(jitter + negative.wait_time_from(limiter.clock.now()))
+                }
+                Err(NegativeMultiDecision::InsufficientCapacity(cap)) => {
+                    ControlFlow::Break(Err(InsufficientCapacity(cap)))
+                }
+            }
+        })
+        .await
+    }
+}
+
+impl<L, Y> fmt::Debug for Layered<L, Y>
+where
+    L: fmt::Debug,
+    Y: fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Layered")
+            .field("inner", &self.inner)
+            .field("layer", &self.layer)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::state::{Closed, Overloaded};
+    use crate::{Quota, RateLimiter};
+    use futures::executor::block_on;
+    use futures::{pin_mut, poll};
+    use nonzero_ext::nonzero;
+
+    #[test]
+    fn layers_stack() {
+        let lim = RateLimiter::keyed(Quota::per_hour(nonzero!(1u32)))
+            .into_bounded_wait(1)
+            .into_closable();
+        block_on(async {
+            assert_eq!(lim.until_key_ready(&"a").await, Ok(Ok(())));
+            let first = lim.until_key_ready(&"a");
+            pin_mut!(first);
+            assert!(poll!(first.as_mut()).is_pending());
+            assert_eq!(lim.waiting(), 1);
+            assert_eq!(lim.inner().waiting_for(&"a"), 1);
+            // The bound turns the next task away, which doesn't count as waiting for the
+            // closable layer either:
+            assert_eq!(
+                lim.until_key_n_ready(&"a", nonzero!(1u32)).await,
+                Ok(Err(Overloaded(1)))
+            );
+            assert_eq!(lim.waiting(), 1);
+            // Closing ends the waits through the bound:
+            assert_eq!(lim.close(), 1);
+            assert_eq!(first.await, Err(Closed));
+        });
+        assert_eq!(lim.waiting(), 0);
+        assert_eq!(lim.inner().waiting_for(&"a"), 0);
+        assert!(!format!("{:?}", lim).is_empty());
+
+        // Closed layers below a bound end its waits as well:
+        let lim = RateLimiter::direct(Quota::per_hour(nonzero!(1u32)))
+            .into_closable()
+            .into_bounded_wait(1);
+        lim.inner().close();
+        assert_eq!(block_on(lim.until_ready()), Ok(Err(Closed)));
+        assert_eq!(lim.waiting(), 0);
+        assert_eq!(lim.limiter().check(), Ok(()));
+    }
+}
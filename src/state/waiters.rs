@@ -0,0 +1,255 @@
+//! Waking up the tasks that wait for a rate limiter when capacity frees up early.
+
+use crate::compat::prelude::*;
+
+use crate::clock;
+use crate::middleware::RateLimitingMiddleware;
+use crate::state::{RateLimiter, StateStore};
+use crate::sync::Mutex;
+use crate::sync::{AtomicU64, Ordering};
+use std::task::Waker;
+
+/// The tasks waiting for a rate limiter, and a generation that counts the times that capacity
+/// freed up early.
+#[derive(Debug, Default)]
+pub(crate) struct Waiters(Inner);
+
+#[derive(Debug, Default)]
+struct Inner {
+    generation: AtomicU64,
+    wakers: Mutex<Wakers>,
+}
+
+/// The wakers of the waiting tasks, by the IDs of their waits and the hashes of the keys they
+/// wait for (`None` for direct rate limiters); without `std`, there are no `async` waiting
+/// methods to register any.
+#[derive(Debug, Default)]
+#[cfg_attr(not(feature = "std"), allow(dead_code))]
+struct Wakers {
+    next_id: u64,
+    wakers: Vec<(u64, Option<u64>, Waker)>,
+}
+
+impl Waiters {
+    /// Wakes up the tasks waiting for the key with the given hash, or all waiting tasks for
+    /// `None`, so that they check the rate limiter again.
+    ///
+    /// The generation moves on either way: tasks that read it before they check the rate
+    /// limiter, but register their wakers only after this, check the rate limiter once more
+    /// rather than miss the capacity that freed up.
+    pub(crate) fn notify(&self, key: Option<u64>) {
+        let woken = {
+            let mut wakers = self.0.wakers.lock();
+            self.0.generation.fetch_add(1, Ordering::AcqRel);
+            match key {
+                None => std::mem::take(&mut wakers.wakers),
+                Some(key) => {
+                    let (woken, waiting) = std::mem::take(&mut wakers.wakers)
+                        .into_iter()
+                        .partition(|(_, other, _)| *other == Some(key));
+                    wakers.wakers = waiting;
+                    woken
+                }
+            }
+        };
+        for (_, _, waker) in woken {
+            waker.wake();
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+mod with_std {
+    use super::*;
+    use futures::future::{self, Future};
+    use futures_timer::Delay;
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+    use std::time::Duration;
+
+    /// A future that resolves once the generation has moved on from the one it was made
+    /// with; its task is woken for notifications about the key with the hash `key`.
+    #[derive(Debug)]
+    pub(crate) struct Notified<'a> {
+        waiters: &'a Waiters,
+        generation: u64,
+        key: Option<u64>,
+        id: Option<u64>,
+    }
+
+    impl Waiters {
+        /// Returns the current generation, which a task reads before it checks the rate limiter
+        /// (so that it notices capacity freeing up between its check and its wait).
+        pub(crate) fn generation(&self) -> u64 {
+            self.0.generation.load(Ordering::Acquire)
+        }
+
+        pub(super) fn notified(&self, generation: u64, key: Option<u64>) -> Notified<'_> {
+            Notified {
+                waiters: self,
+                generation,
+                key,
+                id: None,
+            }
+        }
+    }
+
+    impl Future for Notified<'_> {
+        type Output = ();
+
+        fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+            let inner = &self.waiters.0;
+            let mut wakers = inner.wakers.lock();
+            if inner.generation.load(Ordering::Acquire) != self.generation {
+                return Poll::Ready(());
+            }
+            let id = match self.id {
+                Some(id) => id,
+                None => {
+                    let id = wakers.next_id;
+                    wakers.next_id += 1;
+                    id
+                }
+            };
+            match wakers.wakers.iter_mut().find(|(other, _, _)| *other == id) {
+                Some((_, _, waker)) => waker.clone_from(cx.waker()),
+                None => wakers.wakers.push((id, self.key, cx.waker().clone())),
+            }
+            drop(wakers);
+            self.id = Some(id);
+            Poll::Pending
+        }
+    }
+
+    impl Drop for Notified<'_> {
+        fn drop(&mut self) {
+            if let Some(id) = self.id {
+                self.waiters
+                    .0
+                    .wakers
+                    .lock()
+                    .wakers
+                    .retain(|(other, _, _)| *other != id);
+            }
+        }
+    }
+
+    impl<K, S, C, MW> RateLimiter<K, S, C, MW>
+    where
+        S: StateStore<Key = K>,
+        C: clock::ReasonablyRealtime,
+        MW: RateLimitingMiddleware<C::Instant>,
+    {
+        /// Waits for `duration`, or until capacity frees up for the key with the hash `key`
+        /// (`None` for direct rate limiters) after the given generation.
+        pub(crate) async fn delay(&self, generation: u64, key: Option<u64>, duration: Duration) {
+            future::select(
+                Delay::new(duration),
+                self.tuning().waiters.notified(generation, key),
+            )
+            .await;
+        }
+    }
+}
+
+/// # Waking up waiting tasks
+///
+/// The `async` waiting methods (like [`until_ready`](RateLimiter::until_ready)) sleep for as
+/// long as the rate limiter's negative outcome asks for. Operations that free up capacity
+/// early wake the waiting tasks up so they check the rate limiter again right away:
+/// [Lifting pressure](RateLimiter::set_pressure) wakes all of them, and refunding cells (which
+/// [`all_of`](crate::all_of) and relaxed rate limiters do) wakes the tasks waiting for the
+/// refunded key, once the refund lets a cell through.
+impl<K, S, C, MW> RateLimiter<K, S, C, MW>
+where
+    S: StateStore<Key = K>,
+    C: clock::Clock,
+    MW: RateLimitingMiddleware<C::Instant>,
+{
+    /// Wakes up the tasks waiting for the rate limiter, so that they check it again.
+    ///
+    /// The rate limiter's own operations that free up capacity do this already; call this
+    /// after freeing capacity in another way, e.g. by updating a state store that the rate
+    /// limiter shares with others.
+    pub fn wake_waiters(&self) {
+        self.notify_waiters();
+    }
+
+    /// Wakes up the tasks waiting for the rate limiter. Waiting tasks construct the rate
+    /// limiter's [settings](super::tuning::Tuning) before they read the generation, so without
+    /// settings, there is nobody to wake up.
+    pub(crate) fn notify_waiters(&self) {
+        if let Some(tuning) = self.tuned() {
+            tuning.waiters.notify(None);
+        }
+    }
+
+    /// Returns `n` cells to the state for `key`, and wakes up the tasks waiting for that key
+    /// (whose hash is `hash`, or `None` for direct rate limiters) if the refund lets a cell
+    /// through now.
+    pub(crate) fn refund_cells(&self, key: &K, hash: Option<u64>, n: u32) {
+        let gcra = self.gcra();
+        match self.tuned() {
+            None => gcra.refund(key, &self.state, n),
+            Some(tuning) => {
+                if gcra.refund_at(self.start, key, &self.state, n, self.clock.now()) {
+                    tuning.waiters.notify(hash);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(all(feature = "std", test))]
+mod test {
+    use crate::hash::fnv1a;
+    use crate::sync::{Arc, AtomicBool, Ordering};
+    use crate::{Quota, RateLimiter};
+    use futures::task::{self, ArcWake};
+    use futures::FutureExt;
+    use nonzero_ext::nonzero;
+    use std::task::Context;
+
+    #[derive(Default)]
+    struct Woken(AtomicBool);
+
+    impl ArcWake for Woken {
+        fn wake_by_ref(arc_self: &Arc<Self>) {
+            arc_self.0.store(true, Ordering::Release);
+        }
+    }
+
+    #[test]
+    fn refunds_only_wake_their_keys_waiters() {
+        let lim = RateLimiter::keyed(Quota::per_minute(nonzero!(1u32)));
+        lim.check_key(&1u32).unwrap();
+        lim.check_key(&2u32).unwrap();
+
+        let waiters = &lim.tuning().waiters;
+        let generation = waiters.generation();
+        let woken: Vec<_> = (0..3).map(|_| Arc::new(Woken::default())).collect();
+        let mut notified: Vec<_> = [Some(fnv1a(&1u32)), Some(fnv1a(&2u32)), None]
+            .iter()
+            .map(|&key| waiters.notified(generation, key))
+            .collect();
+        for (notified, woken) in notified.iter_mut().zip(&woken) {
+            let waker = task::waker(Arc::clone(woken));
+            assert!(notified
+                .poll_unpin(&mut Context::from_waker(&waker))
+                .is_pending());
+        }
+        let woken = || -> Vec<_> { woken.iter().map(|w| w.0.load(Ordering::Acquire)).collect() };
+
+        // Refunds that don't let a cell through wake nobody:
+        lim.refund_cells(&2, Some(fnv1a(&2u32)), 0);
+        lim.refund_cells(&3, Some(fnv1a(&3u32)), 1);
+        assert_eq!(woken(), [false, false, false]);
+
+        lim.refund_cells(&1, Some(fnv1a(&1u32)), 1);
+        assert_eq!(woken(), [true, false, false]);
+        assert_eq!(lim.check_key(&1), Ok(()));
+
+        lim.wake_waiters();
+        assert_eq!(woken(), [true, true, true]);
+    }
+}
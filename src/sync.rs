@@ -0,0 +1,26 @@
+//! Synchronization primitives that work on every target the crate builds for.
+//!
+//! Atomics come from `portable-atomic`, which uses the native instructions where the target
+//! has them, and emulates the ones it lacks: 64-bit atomics on 32-bit microcontrollers, and
+//! compare-and-swap on ones without it, like `thumbv6m` (which needs the `critical-section`
+//! feature and a `critical-section` implementation, or
+//! `--cfg portable_atomic_unsafe_assume_single_core` on single-core chips).
+//! Without the standard library, locks are spinlocks on top of these atomics; the settings that
+//! rate limiters construct on first use are behind a spinning `Once` on every target.
+
+// Builds with different features use different parts:
+#![allow(unused_imports)]
+
+pub(crate) use portable_atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering};
+
+#[cfg(feature = "std")]
+pub(crate) use parking_lot::Mutex;
+#[cfg(not(feature = "std"))]
+pub(crate) type Mutex<T> = spin::mutex::SpinMutex<T>;
+
+pub(crate) use spin::once::Once;
+
+#[cfg(target_has_atomic = "ptr")]
+pub(crate) use alloc::sync::Arc;
+#[cfg(not(target_has_atomic = "ptr"))]
+pub(crate) use portable_atomic_util::Arc;
@@ -0,0 +1,649 @@
+//! Utilities for testing code that uses rate limiters, and for testing custom state stores.
+//!
+//! With the `testing` feature, this module offers:
+//!
+//! * [`ArrivalProcess`]es that generate the arrival times of cells: at a constant rate, as a
+//!   Poisson process, or in bursts. Random processes are seeded, so they always generate the
+//!   same arrivals.
+//! * [`drive`] and [`drive_keyed`], which check arrivals against a rate limiter on a
+//!   [`FakeRelativeClock`], advancing the clock in virtual time, and report the decisions as
+//!   a [`Conformance`] that can be asserted on.
+//! * [`check_state_store`] and [`check_keyed_state_store`], which make the same decisions
+//!   with a custom state store and with this crate's in-memory state stores, and report the
+//!   first decision where they diverge. Use them with many arrival processes (e.g. from a
+//!   property-testing crate) to test a state store against the reference semantics.
+//! * The [`contract`] checks of the requirements on state stores, which the
+//!   [`state_store_contract_tests!`](crate::state_store_contract_tests) macro turns into a test
+//!   suite for a state store implementation.
+//! * A [`naive::ReferenceLimiter`] that makes decisions as plainly as possible, and a
+//!   [`ShadowLimiter`] that runs it alongside a rate limiter (e.g. one with a custom state store
+//!   or clock) and records where their decisions diverge.
+//! * A [`fuzz`] harness that decodes quotas and operations from a fuzzer's bytes and checks
+//!   rate limiters against the reference limiter.
+//! * [`replay`], which replays the decisions that a
+//!   [`RecordingLimiter`](crate::state::RecordingLimiter) recorded (e.g. in production), to
+//!   reproduce them in a test.
+//!
+//! # Example
+//! ```rust
+//! # use nonzero_ext::*;
+//! # use std::time::Duration;
+//! use governor::{clock::FakeRelativeClock, testing, Quota, RateLimiter};
+//!
+//! let clock = FakeRelativeClock::default();
+//! let lim = RateLimiter::direct_with_clock(Quota::per_second(nonzero!(10u32)), &clock);
+//! // Twice as many cells as the quota allows, for 10 seconds:
+//! let arrivals = testing::ArrivalProcess::constant(Duration::from_millis(50))
+//!     .until(Duration::from_secs(10));
+//! let conformance = testing::drive(&lim, &clock, arrivals);
+//! conformance.assert_conforming(100..=110);
+//! ```
+
+use crate::compat::prelude::*;
+
+use crate::clock::{Clock, FakeRelativeClock, Reference};
+use crate::hash::SplitMix64;
+use crate::middleware::{NoOpMiddleware, RateLimitingMiddleware};
+use crate::nanos::Nanos;
+use crate::state::keyed::{HashMapStateStore, KeyedStateStore};
+use crate::state::{DirectStateStore, InMemoryState, NotKeyed};
+use crate::{NegativeMultiDecision, NotUntil, Quota, RateLimiter};
+use std::convert::TryFrom;
+use std::fmt;
+use std::hash::Hash;
+use std::ops::RangeBounds;
+use std::time::Duration;
+
+pub mod contract;
+pub mod fuzz;
+pub mod naive;
+mod replay;
+mod shadow;
+
+pub use replay::{replay, ReplayDivergence};
+pub use shadow::ShadowLimiter;
+
+/// A process that generates the arrival times of cells, as offsets from the start of a test.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ArrivalProcess {
+    /// One cell arrives every `interval`, starting at the beginning.
+    Constant {
+        /// The time between two arrivals.
+        interval: Duration,
+    },
+
+    /// Cells arrive independently of each other, on average every `mean_interval`: the times
+    /// between arrivals are exponentially distributed.
+    Poisson {
+        /// The average time between two arrivals.
+        mean_interval: Duration,
+        /// The seed of the random number generator.
+        seed: u64,
+    },
+
+    /// Bursts of `burst` cells arrive `within` apart; each burst starts `between` after the
+    /// previous burst started.
+    Bursty {
+        /// The number of cells in a burst.
+        burst: u32,
+        /// The time between two arrivals in a burst.
+        within: Duration,
+        /// The time between the starts of two bursts.
+        between: Duration,
+    },
+}
+
+impl ArrivalProcess {
+    /// Cells arriving once every `interval`.
+    ///
+    /// # Panics
+    /// Panics if `interval` is zero.
+    #[track_caller]
+    pub fn constant(interval: Duration) -> Self {
+        ArrivalProcess::Constant { interval }.validated()
+    }
+
+    /// Cells arriving as a Poisson process, on average once every `mean_interval`, with
+    /// randomness generated from `seed`.
+    ///
+    /// # Panics
+    /// Panics if `mean_interval` is zero.
+    #[track_caller]
+    pub fn poisson(mean_interval: Duration, seed: u64) -> Self {
+        ArrivalProcess::Poisson {
+            mean_interval,
+            seed,
+        }
+        .validated()
+    }
+
+    /// Bursts of `burst` cells that arrive `within` apart, starting every `between`.
+    ///
+    /// # Panics
+    /// Panics if `between` is zero, or if the bursts overlap (i.e. `between` is shorter than
+    /// `burst * within`), which would put the arrivals out of order.
+    #[track_caller]
+    pub fn bursty(burst: u32, within: Duration, between: Duration) -> Self {
+        ArrivalProcess::Bursty {
+            burst,
+            within,
+            between,
+        }
+        .validated()
+    }
+
+    /// Panics if the process never advances in time, or doesn't generate its arrivals in
+    /// order.
+    #[track_caller]
+    fn validated(self) -> Self {
+        match self {
+            ArrivalProcess::Constant { interval } => {
+                assert!(interval > Duration::ZERO, "the interval must not be zero")
+            }
+            ArrivalProcess::Poisson { mean_interval, .. } => assert!(
+                mean_interval > Duration::ZERO,
+                "the mean interval must not be zero"
+            ),
+            ArrivalProcess::Bursty {
+                burst,
+                within,
+                between,
+            } => {
+                assert!(between > Duration::ZERO, "bursts must not start at once");
+                assert!(
+                    within.as_nanos() * u128::from(burst.max(1)) <= between.as_nanos(),
+                    "bursts must not overlap"
+                );
+            }
+        }
+        self
+    }
+
+    /// Returns an iterator over all the arrival times of the process, in order. The iterator
+    /// ends once the arrival times don't fit into 64 bits of nanoseconds.
+    ///
+    /// # Panics
+    /// Panics if the process has a zero interval or overlapping bursts (see the constructors).
+    #[track_caller]
+    pub fn arrivals(&self) -> Arrivals {
+        let process = self.clone().validated();
+        Arrivals {
+            process,
+            rng: match self {
+                ArrivalProcess::Poisson { seed, .. } => SplitMix64::new(*seed),
+                _ => SplitMix64::new(0),
+            },
+            index: 0,
+            at: Duration::from_secs(0),
+        }
+    }
+
+    /// Returns the arrival times of the process before `end`.
+    pub fn until(&self, end: Duration) -> Vec<Duration> {
+        self.arrivals().take_while(|at| *at < end).collect()
+    }
+}
+
+/// The arrival times of an [`ArrivalProcess`], as returned by
+/// [`ArrivalProcess::arrivals`].
+#[derive(Debug, Clone)]
+pub struct Arrivals {
+    process: ArrivalProcess,
+    rng: SplitMix64,
+    index: u64,
+    at: Duration,
+}
+
+impl Iterator for Arrivals {
+    type Item = Duration;
+
+    fn next(&mut self) -> Option<Duration> {
+        let index = self.index;
+        self.index += 1;
+        let at = match self.process {
+            ArrivalProcess::Constant { interval } => times(interval, index)?,
+            ArrivalProcess::Poisson { mean_interval, .. } => {
+                if index > 0 {
+                    // Inverse transform sampling, with the uniform sample in (0, 1]:
+                    let uniform = 1.0 - self.rng.next_f64();
+                    let gap = -uniform.ln() * mean_interval.as_nanos() as f64;
+                    if gap >= u64::MAX as f64 {
+                        return None;
+                    }
+                    self.at = self.at.checked_add(Duration::from_nanos(gap as u64))?;
+                }
+                self.at
+            }
+            ArrivalProcess::Bursty {
+                burst,
+                within,
+                between,
+            } => {
+                let burst = u64::from(burst.max(1));
+                times(between, index / burst)?.checked_add(times(within, index % burst)?)?
+            }
+        };
+        u64::try_from(at.as_nanos()).ok().map(|_| at)
+    }
+}
+
+/// Returns `n` times `interval`, if that fits into 64 bits of nanoseconds.
+fn times(interval: Duration, n: u64) -> Option<Duration> {
+    let nanos = interval.as_nanos().checked_mul(u128::from(n))?;
+    u64::try_from(nanos).ok().map(Duration::from_nanos)
+}
+
+/// The rate-limiting decisions made for a sequence of arrivals, as returned by [`drive`].
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Conformance {
+    decisions: Vec<(Duration, bool)>,
+}
+
+impl Conformance {
+    /// Returns the arrival times, each with whether the cell conformed.
+    pub fn decisions(&self) -> &[(Duration, bool)] {
+        &self.decisions
+    }
+
+    /// Returns the number of cells that conformed.
+    pub fn conforming(&self) -> usize {
+        self.decisions.iter().filter(|(_, ok)| *ok).count()
+    }
+
+    /// Returns the number of cells that didn't conform.
+    pub fn nonconforming(&self) -> usize {
+        self.decisions.len() - self.conforming()
+    }
+
+    /// Returns the number of cells that conformed among those that arrived in the given range
+    /// of times.
+    pub fn conforming_during<R: RangeBounds<Duration>>(&self, during: R) -> usize {
+        self.decisions
+            .iter()
+            .filter(|(at, ok)| *ok && during.contains(at))
+            .count()
+    }
+
+    /// Asserts that the number of conforming cells is in the expected range.
+    ///
+    /// # Panics
+    /// Panics with a message that shows how many cells conformed, if the number is out of range.
+    #[track_caller]
+    pub fn assert_conforming<R: RangeBounds<usize> + fmt::Debug>(&self, expected: R) {
+        let conforming = self.conforming();
+        assert!(
+            expected.contains(&conforming),
+            "{} of {} cells conformed, expected {:?}",
+            conforming,
+            self.decisions.len(),
+            expected
+        );
+    }
+}
+
+/// Advances `clock` to the given offset from `start`; arrivals must be in order.
+fn advance_to(clock: &FakeRelativeClock, start: Nanos, at: Duration) {
+    let target = start + Nanos::from(at);
+    let now = clock.now();
+    assert!(target >= now, "arrivals must be given in order");
+    clock.advance(target.duration_since(now).into());
+}
+
+/// Checks cells arriving at the given times (as offsets from the clock's current time, in
+/// order) against a direct rate limiter, advancing the rate limiter's clock to each arrival.
+///
+/// # Panics
+/// Panics if the arrivals are out of order.
+pub fn drive<S, MW>(
+    limiter: &RateLimiter<NotKeyed, S, FakeRelativeClock, MW>,
+    clock: &FakeRelativeClock,
+    arrivals: impl IntoIterator<Item = Duration>,
+) -> Conformance
+where
+    S: DirectStateStore,
+    MW: RateLimitingMiddleware<Nanos>,
+{
+    let start = clock.now();
+    let decisions = arrivals
+        .into_iter()
+        .map(|at| {
+            advance_to(clock, start, at);
+            (at, limiter.check().is_ok())
+        })
+        .collect();
+    Conformance { decisions }
+}
+
+/// Checks cells for the given keys arriving at the given times (as offsets from the clock's
+/// current time, in order) against a keyed rate limiter, advancing the rate limiter's clock to
+/// each arrival.
+///
+/// # Panics
+/// Panics if the arrivals are out of order.
+pub fn drive_keyed<K, S, MW>(
+    limiter: &RateLimiter<K, S, FakeRelativeClock, MW>,
+    clock: &FakeRelativeClock,
+    arrivals: impl IntoIterator<Item = (Duration, K)>,
+) -> Conformance
+where
+    K: Hash,
+    S: KeyedStateStore<K>,
+    MW: RateLimitingMiddleware<Nanos>,
+{
+    let start = clock.now();
+    let decisions = arrivals
+        .into_iter()
+        .map(|(at, key)| {
+            advance_to(clock, start, at);
+            (at, limiter.check_key(&key).is_ok())
+        })
+        .collect();
+    Conformance { decisions }
+}
+
+/// A rate-limiting decision, independent of the clock it was made with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+pub enum Decision {
+    /// The cells conform.
+    Conforming,
+    /// The cells don't conform, and can't until the given time has passed.
+    Wait(Duration),
+    /// The cells can never conform, as the quota allows bursts of at most the given number of
+    /// cells.
+    InsufficientCapacity(u32),
+}
+
+impl Decision {
+    /// Returns whether the cells conform.
+    pub fn is_conforming(&self) -> bool {
+        *self == Decision::Conforming
+    }
+
+    /// Converts a rate limiter's decision made at `now`.
+    pub(crate) fn from_outcome<T, P: Reference>(
+        outcome: &Result<T, NegativeMultiDecision<NotUntil<P>>>,
+        now: P,
+    ) -> Decision {
+        match outcome {
+            Ok(_) => Decision::Conforming,
+            Err(NegativeMultiDecision::BatchNonConforming(_, not_until)) => {
+                Decision::Wait(not_until.wait_time_from(now))
+            }
+            Err(NegativeMultiDecision::InsufficientCapacity(max)) => {
+                Decision::InsufficientCapacity(*max)
+            }
+        }
+    }
+}
+
+/// A decision where a rate limiter diverged from the reference semantics, as returned by
+/// [`check_state_store`] or recorded by a [`ShadowLimiter`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Divergence {
+    /// The index of the decision among the decisions made.
+    pub index: usize,
+    /// The time the decision was made at, since the rate limiters started.
+    pub at: Duration,
+    /// The reference decision.
+    pub expected: Decision,
+    /// The decision made by the rate limiter under test.
+    pub actual: Decision,
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "decision #{} at {:?} diverged: expected {:?}, got {:?}",
+            self.index, self.at, self.expected, self.actual
+        )
+    }
+}
+
+impl std::error::Error for Divergence {}
+
+/// Checks cells arriving at the given times (as offsets from the start, in order) against a
+/// rate limiter using `store`, and against one using an [`InMemoryState`], returning the
+/// decisions if they all agree: both whether each cell conforms and, if not, how long to wait.
+///
+/// `store` must be fresh, i.e. not have been used by another rate limiter.
+///
+/// # Panics
+/// Panics if the arrivals are out of order.
+pub fn check_state_store<S: DirectStateStore>(
+    store: S,
+    quota: Quota,
+    arrivals: impl IntoIterator<Item = Duration>,
+) -> Result<Conformance, Divergence> {
+    let clock = FakeRelativeClock::default();
+    let actual: RateLimiter<_, _, _, NoOpMiddleware<Nanos>> =
+        RateLimiter::new(quota, store, &clock);
+    let expected: RateLimiter<_, _, _, NoOpMiddleware<Nanos>> =
+        RateLimiter::new(quota, InMemoryState::default(), &clock);
+    compare(
+        &clock,
+        arrivals.into_iter().map(|at| (at, ())),
+        |_| actual.check(),
+        |_| expected.check(),
+    )
+}
+
+/// Checks cells for the given keys arriving at the given times (as offsets from the start, in
+/// order) against a rate limiter using `store`, and against one using a
+/// [`HashMapStateStore`], returning the decisions if they all agree: both whether each cell
+/// conforms and, if not, how long to wait.
+///
+/// `store` must be fresh, i.e. not have been used by another rate limiter.
+///
+/// # Panics
+/// Panics if the arrivals are out of order.
+pub fn check_keyed_state_store<K, S>(
+    store: S,
+    quota: Quota,
+    arrivals: impl IntoIterator<Item = (Duration, K)>,
+) -> Result<Conformance, Divergence>
+where
+    K: Hash + Eq + Clone,
+    S: KeyedStateStore<K>,
+{
+    let clock = FakeRelativeClock::default();
+    let actual: RateLimiter<_, _, _, NoOpMiddleware<Nanos>> =
+        RateLimiter::new(quota, store, &clock);
+    let expected: RateLimiter<_, _, _, NoOpMiddleware<Nanos>> =
+        RateLimiter::new(quota, HashMapStateStore::default(), &clock);
+    compare(
+        &clock,
+        arrivals,
+        |key| actual.check_key(key),
+        |key| expected.check_key(key),
+    )
+}
+
+/// Makes the decisions for the given arrivals with both `actual` and `expected`, returning the
+/// first decision where they differ.
+fn compare<K>(
+    clock: &FakeRelativeClock,
+    arrivals: impl IntoIterator<Item = (Duration, K)>,
+    actual: impl Fn(&K) -> Result<(), NotUntil<Nanos>>,
+    expected: impl Fn(&K) -> Result<(), NotUntil<Nanos>>,
+) -> Result<Conformance, Divergence> {
+    let decide = |result: Result<(), NotUntil<Nanos>>| {
+        Decision::from_outcome(
+            &result.map_err(|negative| NegativeMultiDecision::BatchNonConforming(1, negative)),
+            clock.now(),
+        )
+    };
+    let start = clock.now();
+    let mut decisions = Vec::new();
+    for (index, (at, key)) in arrivals.into_iter().enumerate() {
+        advance_to(clock, start, at);
+        let expected = decide(expected(&key));
+        let actual = decide(actual(&key));
+        if actual != expected {
+            return Err(Divergence {
+                index,
+                at,
+                expected,
+                actual,
+            });
+        }
+        decisions.push((at, actual.is_conforming()));
+    }
+    Ok(Conformance { decisions })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::state::StateStore;
+    use nonzero_ext::nonzero;
+
+    #[test]
+    fn arrival_processes() {
+        let constant = ArrivalProcess::constant(Duration::from_millis(10));
+        assert_eq!(
+            constant.until(Duration::from_millis(30)),
+            vec![
+                Duration::from_millis(0),
+                Duration::from_millis(10),
+                Duration::from_millis(20)
+            ]
+        );
+
+        let bursty = ArrivalProcess::bursty(2, Duration::from_millis(1), Duration::from_secs(1));
+        assert_eq!(
+            bursty.arrivals().take(3).collect::<Vec<_>>(),
+            vec![
+                Duration::from_millis(0),
+                Duration::from_millis(1),
+                Duration::from_secs(1)
+            ]
+        );
+
+        let poisson = ArrivalProcess::poisson(Duration::from_millis(10), 42);
+        let arrivals = poisson.until(Duration::from_secs(100));
+        assert_eq!(arrivals, poisson.until(Duration::from_secs(100)));
+        assert!(arrivals.windows(2).all(|w| w[0] <= w[1]));
+        // On average one arrival every 10ms:
+        assert!(
+            (9_000..11_000).contains(&arrivals.len()),
+            "{}",
+            arrivals.len()
+        );
+        assert_ne!(
+            arrivals,
+            ArrivalProcess::poisson(Duration::from_millis(10), 43).until(Duration::from_secs(100))
+        );
+    }
+
+    #[test]
+    fn arrival_times_span_64_bits_of_nanoseconds() {
+        assert_eq!(
+            times(Duration::from_secs(1), 1 << 32),
+            Some(Duration::from_secs(1 << 32))
+        );
+        assert_eq!(times(Duration::from_secs(1 << 35), 1 << 32), None);
+        let far = ArrivalProcess::constant(Duration::from_secs(10_000_000_000));
+        assert_eq!(
+            far.arrivals().collect::<Vec<_>>(),
+            vec![Duration::ZERO, Duration::from_secs(10_000_000_000)]
+        );
+        let far = ArrivalProcess::bursty(2, Duration::from_secs(1), Duration::from_secs(1 << 33));
+        assert_eq!(far.arrivals().count(), 6);
+    }
+
+    #[test]
+    #[should_panic(expected = "the interval must not be zero")]
+    fn rejects_zero_intervals() {
+        ArrivalProcess::constant(Duration::ZERO);
+    }
+
+    #[test]
+    #[should_panic(expected = "bursts must not overlap")]
+    fn rejects_overlapping_bursts() {
+        let _ = ArrivalProcess::Bursty {
+            burst: 3,
+            within: Duration::from_secs(1),
+            between: Duration::from_secs(2),
+        }
+        .arrivals();
+    }
+
+    #[test]
+    fn driving_rate_limiters() {
+        let clock = FakeRelativeClock::default();
+        let lim = RateLimiter::direct_with_clock(Quota::per_second(nonzero!(5u32)), &clock);
+        let arrivals = ArrivalProcess::bursty(10, Duration::from_secs(0), Duration::from_secs(2))
+            .until(Duration::from_secs(10));
+        let conformance = drive(&lim, &clock, arrivals);
+        // The burst capacity is regained between bursts (and the cell replenished while the
+        // first cell of a burst is let through counts, too):
+        assert_eq!(conformance.conforming_during(..Duration::from_secs(2)), 5);
+        conformance.assert_conforming(25..=30);
+        assert_eq!(conformance.nonconforming(), 50 - conformance.conforming());
+
+        let clock = FakeRelativeClock::default();
+        let lim = RateLimiter::hashmap_with_clock(Quota::per_second(nonzero!(1u32)), &clock);
+        let conformance = drive_keyed(
+            &lim,
+            &clock,
+            vec![
+                (Duration::from_millis(0), 1),
+                (Duration::from_millis(1), 2),
+                (Duration::from_millis(2), 1),
+            ],
+        );
+        assert_eq!(conformance.conforming(), 2);
+    }
+
+    #[test]
+    #[should_panic(expected = "1 of 1 cells conformed")]
+    fn failed_assertions() {
+        let clock = FakeRelativeClock::default();
+        let lim = RateLimiter::direct_with_clock(Quota::per_second(nonzero!(5u32)), &clock);
+        drive(&lim, &clock, vec![Duration::from_secs(0)]).assert_conforming(2..);
+    }
+
+    /// A state store that forgets every other state.
+    #[derive(Default)]
+    struct Forgetful(InMemoryState, std::sync::atomic::AtomicBool);
+
+    impl StateStore for Forgetful {
+        type Key = NotKeyed;
+
+        fn measure_and_replace<T, F, E>(&self, key: &NotKeyed, f: F) -> Result<T, E>
+        where
+            F: Fn(Option<Nanos>) -> Result<(T, Nanos), E>,
+        {
+            let forget = !self.1.fetch_xor(true, std::sync::atomic::Ordering::Relaxed);
+            self.0
+                .measure_and_replace(key, |tat| f(if forget { None } else { tat }))
+        }
+    }
+
+    #[test]
+    fn checking_state_stores() {
+        let quota = Quota::per_second(nonzero!(2u32));
+        let arrivals = ArrivalProcess::poisson(Duration::from_millis(100), 1);
+        let conformance = check_state_store(
+            InMemoryState::default(),
+            quota,
+            arrivals.until(Duration::from_secs(10)),
+        )
+        .unwrap();
+        assert!(conformance.nonconforming() > 0);
+
+        let divergence = check_state_store(
+            Forgetful::default(),
+            quota,
+            arrivals.until(Duration::from_secs(10)),
+        )
+        .unwrap_err();
+        assert!(!divergence.expected.is_conforming() && divergence.actual.is_conforming());
+        assert!(divergence.to_string().contains("diverged"));
+
+        let keyed = (0..100u32).map(|i| (Duration::from_millis(u64::from(i) * 10), i % 3));
+        assert!(check_keyed_state_store(HashMapStateStore::default(), quota, keyed).is_ok());
+    }
+}
@@ -0,0 +1,288 @@
+//! The requirements on [`StateStore`] implementations, as checks that can be run against any
+//! state store.
+//!
+//! Each check panics if the state store doesn't meet the requirement. Usually, they are run
+//! with the [`state_store_contract_tests!`](../../macro.state_store_contract_tests.html) macro,
+//! which generates a test for each of them.
+
+use crate::compat::prelude::*;
+
+use super::{check_keyed_state_store, check_state_store, ArrivalProcess};
+use crate::nanos::Nanos;
+use crate::state::{DirectStateStore, StateStore};
+use crate::Quota;
+use nonzero_ext::nonzero;
+use std::cell::Cell;
+use std::fmt;
+use std::hash::Hash;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+/// Returns the state that `store` passes to the closure for `key`, without changing it.
+fn peek<S: StateStore>(store: &S, key: &S::Key) -> Option<Nanos> {
+    let seen = Cell::new(None);
+    let _ = store.measure_and_replace(key, |tat| -> Result<((), Nanos), ()> {
+        seen.set(tat);
+        Err(())
+    });
+    seen.get()
+}
+
+/// Checks that the closure gets `None` for a key that was never updated.
+#[track_caller]
+pub fn fresh_state<S: StateStore>(store: &S, key: &S::Key) {
+    assert_eq!(peek(store, key), None, "a fresh key must have no state");
+}
+
+/// Checks that the state the closure returns is the one it gets next time, and that the value
+/// it returns is passed on.
+#[track_caller]
+pub fn replaces_state<S: StateStore>(store: &S, key: &S::Key) {
+    for tat in [42u64, 7, 1 << 40].iter().copied() {
+        let result = store.measure_and_replace(key, |_| Ok::<_, ()>((tat, Nanos::from(tat))));
+        assert_eq!(result, Ok(tat), "the closure's result must be returned");
+        assert_eq!(
+            peek(store, key),
+            Some(Nanos::from(tat)),
+            "the closure's new state must be stored"
+        );
+    }
+}
+
+/// Checks that the state doesn't change when the closure returns an error, and that the error
+/// is passed on.
+#[track_caller]
+pub fn keeps_state_on_error<S: StateStore>(store: &S, key: &S::Key) {
+    let _ = store.measure_and_replace(key, |_| Ok::<_, ()>(((), Nanos::from(1_000))));
+    let result = store.measure_and_replace(key, |_| Err::<((), Nanos), _>("rate-limited"));
+    assert_eq!(
+        result,
+        Err("rate-limited"),
+        "the closure's error must be returned"
+    );
+    assert_eq!(
+        peek(store, key),
+        Some(Nanos::from(1_000)),
+        "the state must not change if the closure fails"
+    );
+}
+
+/// Checks that [`StateStore::peek`] returns the state that the closure gets, without changing
+/// it.
+#[track_caller]
+pub fn peeks_state<S: StateStore>(store: &S, key: &S::Key) {
+    assert_eq!(store.peek(key), None, "a fresh key must have no state");
+    let _ = store.measure_and_replace(key, |_| Ok::<_, ()>(((), Nanos::from(1_000))));
+    assert_eq!(
+        store.peek(key),
+        Some(Nanos::from(1_000)),
+        "peeking must return the stored state"
+    );
+    assert_eq!(
+        peek(store, key),
+        Some(Nanos::from(1_000)),
+        "peeking must not change the state"
+    );
+}
+
+/// Checks that updating one key doesn't change the state of another.
+#[track_caller]
+pub fn independent_keys<S: StateStore>(store: &S, key: &S::Key, other_key: &S::Key) {
+    let _ = store.measure_and_replace(key, |_| Ok::<_, ()>(((), Nanos::from(1_000))));
+    assert_eq!(
+        peek(store, other_key),
+        None,
+        "updating a key must not change other keys"
+    );
+    let _ = store.measure_and_replace(other_key, |_| Ok::<_, ()>(((), Nanos::from(2_000))));
+    assert_eq!(peek(store, key), Some(Nanos::from(1_000)));
+    assert_eq!(peek(store, other_key), Some(Nanos::from(2_000)));
+}
+
+/// Checks that concurrent updates are atomic and that their states are monotonic: `threads`
+/// threads each increment the state `increments` times, which must neither lose an increment
+/// nor let any thread see a state older than one it has seen before.
+#[track_caller]
+pub fn atomic_updates<S>(store: Arc<S>, key: S::Key, threads: usize, increments: u64)
+where
+    S: StateStore + Send + Sync + 'static,
+    S::Key: Clone + Send + 'static,
+{
+    let handles: Vec<_> = (0..threads)
+        .map(|_| {
+            let store = Arc::clone(&store);
+            let key = key.clone();
+            thread::spawn(move || {
+                let mut last = 0;
+                for _ in 0..increments {
+                    let seen = store
+                        .measure_and_replace(&key, |tat| {
+                            let seen = tat.map_or(0, |tat| tat.as_u64());
+                            Ok::<_, ()>((seen, Nanos::from(seen + 1)))
+                        })
+                        .unwrap();
+                    assert!(
+                        seen >= last,
+                        "states must not go back in time: saw {} after {}",
+                        seen,
+                        last
+                    );
+                    last = seen;
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().expect("a thread updating the state panicked");
+    }
+    assert_eq!(
+        peek(&*store, &key),
+        Some(Nanos::from(threads as u64 * increments)),
+        "concurrent updates must not be lost"
+    );
+}
+
+/// The quotas and arrival processes that [`matches_reference`] and [`keyed_matches_reference`]
+/// check.
+fn scenarios() -> Vec<(Quota, ArrivalProcess)> {
+    let quota = Quota::per_second(nonzero!(10u32)).allow_burst(nonzero!(5u32));
+    vec![
+        (quota, ArrivalProcess::constant(Duration::from_millis(50))),
+        (
+            quota,
+            ArrivalProcess::poisson(Duration::from_millis(100), 1),
+        ),
+        (
+            Quota::per_hour(nonzero!(1u32)),
+            ArrivalProcess::poisson(Duration::from_secs(60), 2),
+        ),
+        (
+            quota,
+            ArrivalProcess::bursty(20, Duration::from_millis(1), Duration::from_secs(1)),
+        ),
+    ]
+}
+
+fn expect_no_divergence<E: fmt::Display, T>(result: Result<T, E>) {
+    if let Err(divergence) = result {
+        panic!("{}", divergence);
+    }
+}
+
+/// Checks that rate limiters using fresh direct state stores from `new_store` make the same
+/// decisions as ones using an [`InMemoryState`](crate::state::InMemoryState).
+#[track_caller]
+pub fn matches_reference<S: DirectStateStore>(new_store: impl Fn() -> S) {
+    for (quota, process) in scenarios() {
+        let arrivals = process.arrivals().take(200);
+        expect_no_divergence(check_state_store(new_store(), quota, arrivals));
+    }
+}
+
+/// Checks that rate limiters using fresh keyed state stores from `new_store` make the same
+/// decisions as ones using a [`HashMapStateStore`](crate::state::keyed::HashMapStateStore),
+/// for cells alternating between `key` and `other_key`.
+#[track_caller]
+pub fn keyed_matches_reference<K, S>(new_store: impl Fn() -> S, key: K, other_key: K)
+where
+    K: Hash + Eq + Clone,
+    S: StateStore<Key = K>,
+{
+    for (quota, process) in scenarios() {
+        let keys = [key.clone(), other_key.clone()];
+        let arrivals = process
+            .arrivals()
+            .take(200)
+            .zip(keys.iter().cycle().cloned());
+        expect_no_divergence(check_keyed_state_store(new_store(), quota, arrivals));
+    }
+}
+
+/// Generates a test module that checks a [`StateStore`](crate::state::StateStore)
+/// implementation against the requirements that rate limiters place on state stores, as
+/// checked by the functions in [`governor::testing::contract`](testing/contract/index.html).
+///
+/// The first form is for direct state stores, the second for keyed ones, which also takes two
+/// different keys to check with. Each test constructs its own state store from the given
+/// expression, which is evaluated in the scope that the macro is used in. The state store must
+/// be `Send + Sync + 'static`, so that it can be updated from several threads; its key must be
+/// `Clone + Send + 'static`.
+///
+/// This is only available with the `testing` feature.
+///
+/// # Example
+/// ```rust
+/// use governor::state::{keyed::HashMapStateStore, InMemoryState};
+/// use governor::state_store_contract_tests;
+///
+/// state_store_contract_tests!(in_memory_state, direct, InMemoryState::default());
+/// state_store_contract_tests!(
+///     hashmap_state,
+///     keyed,
+///     HashMapStateStore::<String>::default(),
+///     "a".to_string(),
+///     "b".to_string()
+/// );
+/// # fn main() {}
+/// ```
+#[macro_export]
+macro_rules! state_store_contract_tests {
+    ($name:ident, direct, $store:expr) => {
+        mod $name {
+            #[allow(unused_imports)]
+            use super::*;
+
+            $crate::state_store_contract_tests!(@common $store, $crate::state::NotKeyed::NonKey);
+
+            #[test]
+            fn matches_reference() {
+                $crate::testing::contract::matches_reference(|| $store);
+            }
+        }
+    };
+    ($name:ident, keyed, $store:expr, $key:expr, $other_key:expr) => {
+        mod $name {
+            #[allow(unused_imports)]
+            use super::*;
+
+            $crate::state_store_contract_tests!(@common $store, $key);
+
+            #[test]
+            fn independent_keys() {
+                $crate::testing::contract::independent_keys(&$store, &$key, &$other_key);
+            }
+
+            #[test]
+            fn matches_reference() {
+                $crate::testing::contract::keyed_matches_reference(|| $store, $key, $other_key);
+            }
+        }
+    };
+    (@common $store:expr, $key:expr) => {
+        #[test]
+        fn fresh_state() {
+            $crate::testing::contract::fresh_state(&$store, &$key);
+        }
+
+        #[test]
+        fn replaces_state() {
+            $crate::testing::contract::replaces_state(&$store, &$key);
+        }
+
+        #[test]
+        fn keeps_state_on_error() {
+            $crate::testing::contract::keeps_state_on_error(&$store, &$key);
+        }
+
+        #[test]
+        fn peeks_state() {
+            $crate::testing::contract::peeks_state(&$store, &$key);
+        }
+
+        #[test]
+        fn atomic_updates() {
+            $crate::testing::contract::atomic_updates(::std::sync::Arc::new($store), $key, 8, 1_000);
+        }
+    };
+}
@@ -0,0 +1,225 @@
+//! Fuzzing rate limiters against a model of their semantics.
+//!
+//! Fuzzers (e.g. `cargo fuzz`) hand their targets a slice of bytes. [`Bytes`] decodes the
+//! crate's inputs from such a slice - quotas, durations and cell counts, spanning all their
+//! orders of magnitude - and [`run`] decodes a whole scenario: a quota and a sequence of
+//! [`Operation`]s, which it runs against a rate limiter and against a
+//! [`ReferenceLimiter`](super::naive::ReferenceLimiter), which computes with 128-bit integers.
+//!
+//! [`run`] panics if the rate limiter panics, or if it makes a decision that differs from the
+//! reference's, as long as the reference's times fit into the 64 bits of nanoseconds that rate
+//! limiters keep their state in (beyond that, rate limiters saturate, and only must not panic).
+//!
+//! The `fuzz` directory of the repository has a fuzz target that runs this; it can also be run
+//! with random bytes from a property-testing crate:
+//!
+//! ```rust
+//! governor::testing::fuzz::run(&[23, 5, 42, 0, 0, 1, 255, 4, 2, 9]);
+//! ```
+//!
+//! With the `arbitrary` feature, quotas, [`Operation`]s and whole [`Scenario`]s implement
+//! [`arbitrary::Arbitrary`](https://docs.rs/arbitrary), for fuzzers that generate structured
+//! inputs.
+
+use crate::compat::prelude::*;
+
+use super::naive::ReferenceLimiter;
+use super::Decision;
+use crate::clock::{Clock, FakeRelativeClock};
+use crate::nanos::Nanos;
+use crate::{NegativeMultiDecision, Quota, RateLimiter};
+use std::cmp;
+use std::num::NonZeroU32;
+use std::time::Duration;
+
+/// The most operations that [`run`] decodes, to keep runs short.
+const MAX_OPERATIONS: usize = 1_000;
+
+/// Decodes values from a fuzzer's bytes.
+///
+/// Every method consumes some bytes and returns a valid value, reading zeroes once the bytes
+/// run out.
+#[derive(Debug, Clone)]
+pub struct Bytes<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Bytes<'a> {
+    /// Decodes values from `data`.
+    pub fn new(data: &'a [u8]) -> Self {
+        Bytes { data }
+    }
+
+    /// Returns whether all bytes were consumed.
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    fn take<const N: usize>(&mut self) -> [u8; N] {
+        let mut buf = [0u8; N];
+        let len = cmp::min(N, self.data.len());
+        buf[..len].copy_from_slice(&self.data[..len]);
+        self.data = &self.data[len..];
+        buf
+    }
+
+    /// Decodes a byte.
+    pub fn u8(&mut self) -> u8 {
+        self.take::<1>()[0]
+    }
+
+    /// Decodes a `u32` whose magnitude is picked first, so that small and large numbers are
+    /// equally likely.
+    pub fn u32(&mut self) -> u32 {
+        let shift = u32::from(self.u8()) % 32;
+        u32::from_le_bytes(self.take()) >> shift
+    }
+
+    /// Decodes a `u64` whose magnitude is picked first, so that small and large numbers are
+    /// equally likely.
+    pub fn u64(&mut self) -> u64 {
+        let shift = u32::from(self.u8()) % 64;
+        u64::from_le_bytes(self.take()) >> shift
+    }
+
+    /// Decodes a non-zero `u32`.
+    pub fn nonzero_u32(&mut self) -> NonZeroU32 {
+        NonZeroU32::new(self.u32()).unwrap_or_else(|| nonzero_ext::nonzero!(1u32))
+    }
+
+    /// Decodes a number of nanoseconds.
+    pub fn nanos(&mut self) -> Nanos {
+        Nanos::from(self.u64())
+    }
+
+    /// Decodes a duration that is at most `u64::MAX` nanoseconds long.
+    pub fn duration(&mut self) -> Duration {
+        Duration::from_nanos(self.u64())
+    }
+
+    /// Decodes a quota: any number of cells per any period, any burst size, and maybe a
+    /// cooldown or an overdraft.
+    pub fn quota(&mut self) -> Quota {
+        let cells = self.nonzero_u32();
+        let period = cmp::max(self.duration(), Duration::from_nanos(1));
+        let quota = Quota::new(cells, period)
+            .expect("valid period")
+            .allow_burst(self.nonzero_u32());
+        match self.u8() {
+            0..=63 => quota.with_cooldown(self.duration()),
+            64..=95 => quota.with_overdraft(self.u32()),
+            _ => quota,
+        }
+    }
+
+    /// Decodes an operation on a rate limiter.
+    pub fn operation(&mut self) -> Operation {
+        match self.u8() % 3 {
+            0 => Operation::Advance(self.duration()),
+            1 => Operation::Check(nonzero_ext::nonzero!(1u32)),
+            _ => Operation::Check(self.nonzero_u32()),
+        }
+    }
+}
+
+/// An operation on a rate limiter, as decoded by [`Bytes::operation`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operation {
+    /// Advance the rate limiter's clock.
+    Advance(Duration),
+    /// Check this many cells.
+    Check(NonZeroU32),
+}
+
+/// Generates operations like [`Bytes::operation`] decodes them: durations and cell counts
+/// spanning all their orders of magnitude, with single cells checked more often.
+#[cfg(feature = "arbitrary")]
+impl<'a> arbitrary::Arbitrary<'a> for Operation {
+    fn arbitrary(u: &mut arbitrary::Unstructured<'a>) -> arbitrary::Result<Self> {
+        Ok(match u8::arbitrary(u)? % 3 {
+            0 => Operation::Advance(Duration::from_nanos(Nanos::arbitrary(u)?.as_u64())),
+            1 => Operation::Check(nonzero_ext::nonzero!(1u32)),
+            _ => {
+                let shift = u32::from(u8::arbitrary(u)?) % 32;
+                let n = NonZeroU32::new(u32::arbitrary(u)? >> shift);
+                Operation::Check(n.unwrap_or_else(|| nonzero_ext::nonzero!(1u32)))
+            }
+        })
+    }
+}
+
+/// A quota and a sequence of operations to run against a rate limiter with it.
+///
+/// With the `arbitrary` feature, fuzzers that generate structured inputs (like `cargo fuzz`'s
+/// typed targets) can generate scenarios directly, instead of having [`run`] decode them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+pub struct Scenario {
+    /// The quota of the rate limiter.
+    pub quota: Quota,
+    /// The operations to run, of which only the first 1000 are run.
+    pub operations: Vec<Operation>,
+}
+
+impl Scenario {
+    /// Decodes a scenario from a fuzzer's bytes.
+    pub fn decode(data: &[u8]) -> Self {
+        let mut bytes = Bytes::new(data);
+        let quota = bytes.quota();
+        let mut operations = Vec::new();
+        while !bytes.is_empty() && operations.len() < MAX_OPERATIONS {
+            operations.push(bytes.operation());
+        }
+        Scenario { quota, operations }
+    }
+
+    /// Runs the operations against a direct rate limiter and a reference limiter.
+    ///
+    /// # Panics
+    /// Panics if the rate limiter panics, or if it makes a decision that differs from the
+    /// reference's (unless the reference's times no longer fit into 64 bits).
+    pub fn run(&self) {
+        let quota = self.quota;
+        let clock = FakeRelativeClock::default();
+        let lim = RateLimiter::direct_with_clock(quota, &clock);
+        let reference = ReferenceLimiter::new(quota);
+        let mut now = Duration::from_secs(0);
+
+        for operation in self.operations.iter().take(MAX_OPERATIONS) {
+            match *operation {
+                Operation::Advance(by) => {
+                    clock.advance(by);
+                    now += by;
+                }
+                Operation::Check(n) => {
+                    let actual = if n.get() == 1 {
+                        lim.check().map_err(|not_until| {
+                            NegativeMultiDecision::BatchNonConforming(1, not_until)
+                        })
+                    } else {
+                        lim.check_n(n)
+                    };
+                    let actual = Decision::from_outcome(&actual, clock.now());
+                    let expected = reference.check_n_at(n, now);
+                    if reference.fits_in_64_bits() {
+                        assert_eq!(
+                            actual, expected,
+                            "checking {} cells after {:?} with {:?}",
+                            n, now, quota
+                        );
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Decodes a quota and a sequence of operations from `data`, and runs them against a direct rate
+/// limiter and a reference limiter.
+///
+/// # Panics
+/// Panics if the rate limiter panics, or if it makes a decision that differs from the
+/// reference's (unless the reference's times no longer fit into 64 bits).
+pub fn run(data: &[u8]) {
+    Scenario::decode(data).run();
+}
@@ -0,0 +1,222 @@
+//! A slow but straightforward implementation of the rate-limiting semantics, for checking rate
+//! limiters against.
+
+use crate::compat::prelude::*;
+
+use super::Decision;
+use crate::state::NotKeyed;
+use crate::Quota;
+use nonzero_ext::nonzero;
+use parking_lot::Mutex;
+use std::cmp;
+use std::convert::TryFrom;
+use std::fmt;
+use std::num::NonZeroU32;
+use std::time::Duration;
+
+/// A rate limiter that makes the decisions a [`RateLimiter`](crate::RateLimiter) with the same
+/// quota should make, computed as plainly as possible.
+///
+/// The reference limiter implements the GCRA variant that rate limiters use with 128-bit
+/// integers, so none of its computations overflow or saturate, and keeps the theoretical arrival
+/// time of each key in a list. It doesn't read a clock: each decision is made at a time that is
+/// given as the time since the limiter started.
+///
+/// Rate limiters keep their times in 64 bits of nanoseconds (or fractions of nanoseconds, for
+/// quotas that replenish more than one cell per nanosecond). Once a time exceeds that,
+/// their decisions may differ from the reference's, which
+/// [`fits_in_64_bits`](#method.fits_in_64_bits) tells.
+///
+/// Use it to check that a rate limiter (with a custom state store or clock) makes the right
+/// decisions, either directly or with a [`ShadowLimiter`](super::ShadowLimiter):
+///
+/// ```rust
+/// # use nonzero_ext::*;
+/// # use std::time::Duration;
+/// use governor::testing::{naive::ReferenceLimiter, Decision};
+/// use governor::Quota;
+///
+/// let reference = ReferenceLimiter::new(Quota::per_second(nonzero!(2u32)));
+/// assert_eq!(reference.check_at(Duration::from_secs(0)), Decision::Conforming);
+/// assert_eq!(reference.check_at(Duration::from_secs(0)), Decision::Conforming);
+/// assert_eq!(
+///     reference.check_at(Duration::from_millis(100)),
+///     Decision::Wait(Duration::from_millis(400))
+/// );
+/// ```
+pub struct ReferenceLimiter<K = NotKeyed> {
+    quota: Quota,
+    /// The time it takes to replenish one cell, in units of `1/scale` nanoseconds.
+    t: u128,
+    /// The time it takes to replenish the burst capacity and the overdraft, in the same units.
+    tau: u128,
+    /// The number of units per nanosecond.
+    scale: u128,
+    /// The cooldown, in the same units.
+    cooldown: u128,
+    states: Mutex<States<K>>,
+}
+
+struct States<K> {
+    /// The theoretical arrival time of each key that has one, in units.
+    tats: Vec<(K, u128)>,
+    /// Whether all times so far fit into 64 bits.
+    fits_in_64_bits: bool,
+}
+
+fn fits_in_64_bits(units: u128) -> bool {
+    units <= u128::from(u64::MAX)
+}
+
+impl<K: PartialEq + Clone> ReferenceLimiter<K> {
+    /// Constructs a reference limiter for the given quota.
+    pub fn new(quota: Quota) -> Self {
+        let scale = u128::from(quota.cells_per_period.get());
+        let t = quota.replenish_period.as_nanos();
+        let tau = t * (u128::from(quota.max_burst.get()) + u128::from(quota.overdraft));
+        let cooldown = quota.cooldown.as_nanos() * scale;
+        ReferenceLimiter {
+            quota,
+            t,
+            tau,
+            scale,
+            cooldown,
+            states: Mutex::new(States {
+                tats: Vec::new(),
+                fits_in_64_bits: fits_in_64_bits(tau) && fits_in_64_bits(cooldown),
+            }),
+        }
+    }
+
+    /// Returns the quota that the reference limiter enforces.
+    pub fn quota(&self) -> Quota {
+        self.quota
+    }
+
+    /// Returns whether all the times the reference limiter computed with so far fit into the
+    /// 64 bits that rate limiters keep them in. If they don't, rate limiters may make different
+    /// decisions.
+    pub fn fits_in_64_bits(&self) -> bool {
+        self.states.lock().fits_in_64_bits
+    }
+
+    /// Decides whether a cell for `key` that arrives `at` the given time since the limiter
+    /// started conforms, and records it if so.
+    pub fn check_key_at(&self, key: &K, at: Duration) -> Decision {
+        self.check_key_n_at(key, nonzero!(1u32), at)
+    }
+
+    /// Decides whether all `n` cells for `key` that arrive `at` the given time since the limiter
+    /// started conform, and records them if so.
+    pub fn check_key_n_at(&self, key: &K, n: NonZeroU32, at: Duration) -> Decision {
+        let n = u128::from(n.get());
+        // The batch must fit into the burst capacity (and the overdraft):
+        if n * self.t > self.tau {
+            return Decision::InsufficientCapacity((self.tau / self.t) as u32);
+        }
+        let mut states = self.states.lock();
+        let now = at.as_nanos() * self.scale;
+        // A key without a state has just had one cell replenished:
+        let tat = states
+            .tats
+            .iter()
+            .find(|(k, _)| k == key)
+            .map_or(now + self.t, |(_, tat)| *tat);
+        // The last of the cells conforms if it arrives no earlier than the burst capacity
+        // before the theoretical arrival time:
+        let last = tat + (n - 1) * self.t;
+        let earliest = last.saturating_sub(self.tau);
+        let fits = fits_in_64_bits(now) && fits_in_64_bits(last);
+        states.fits_in_64_bits &= fits;
+        if now < earliest {
+            // Rate limiters report times in whole nanoseconds, rounding up:
+            let wait = earliest.div_ceil(self.scale) - at.as_nanos();
+            return Decision::Wait(duration_from_nanos(wait));
+        }
+        // The cells conform; they move the theoretical arrival time on by their weight, and
+        // trigger the cooldown if they use up the burst capacity and the overdraft:
+        let mut next = cmp::max(tat, now) + n * self.t;
+        if next > now + self.tau {
+            next += self.cooldown;
+        }
+        states.fits_in_64_bits &= fits_in_64_bits(next) && fits_in_64_bits(now + self.tau);
+        match states.tats.iter_mut().find(|(k, _)| k == key) {
+            Some((_, tat)) => *tat = next,
+            None => states.tats.push((key.clone(), next)),
+        }
+        Decision::Conforming
+    }
+}
+
+/// Converts nanoseconds to a duration, saturating at the longest duration.
+fn duration_from_nanos(nanos: u128) -> Duration {
+    const NANOS_PER_SEC: u128 = 1_000_000_000;
+    match u64::try_from(nanos / NANOS_PER_SEC) {
+        Ok(secs) => Duration::new(secs, (nanos % NANOS_PER_SEC) as u32),
+        Err(_) => Duration::MAX,
+    }
+}
+
+impl ReferenceLimiter<NotKeyed> {
+    /// Decides whether a cell that arrives `at` the given time since the limiter started
+    /// conforms, and records it if so.
+    pub fn check_at(&self, at: Duration) -> Decision {
+        self.check_key_at(&NotKeyed::NonKey, at)
+    }
+
+    /// Decides whether all `n` cells that arrive `at` the given time since the limiter started
+    /// conform, and records them if so.
+    pub fn check_n_at(&self, n: NonZeroU32, at: Duration) -> Decision {
+        self.check_key_n_at(&NotKeyed::NonKey, n, at)
+    }
+}
+
+impl<K> fmt::Debug for ReferenceLimiter<K> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ReferenceLimiter")
+            .field("quota", &self.quota)
+            .field("keys", &self.states.lock().tats.len())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn reference_semantics() {
+        let quota = Quota::per_second(nonzero!(5u32)).allow_burst(nonzero!(2u32));
+        let reference = ReferenceLimiter::new(quota);
+        let start = Duration::from_secs(0);
+        assert_eq!(reference.check_key_at(&"a", start), Decision::Conforming);
+        assert_eq!(reference.check_key_at(&"a", start), Decision::Conforming);
+        assert_eq!(
+            reference.check_key_at(&"a", start),
+            Decision::Wait(Duration::from_millis(200))
+        );
+        assert_eq!(
+            reference.check_key_n_at(&"b", nonzero!(2u32), start),
+            Decision::Conforming
+        );
+        assert_eq!(
+            reference.check_key_n_at(&"b", nonzero!(3u32), start),
+            Decision::InsufficientCapacity(2)
+        );
+        assert_eq!(
+            reference.check_key_at(&"a", Duration::from_millis(200)),
+            Decision::Conforming
+        );
+        assert!(reference.fits_in_64_bits());
+        assert!(format!("{:?}", reference).contains("keys: 2"));
+    }
+
+    #[test]
+    fn exceeding_64_bits() {
+        let reference = ReferenceLimiter::new(Quota::per_second(nonzero!(1u32)));
+        let far = Duration::from_nanos(u64::MAX) * 2;
+        assert_eq!(reference.check_at(far), Decision::Conforming);
+        assert!(!reference.fits_in_64_bits());
+        assert_eq!(duration_from_nanos(u128::MAX), Duration::MAX);
+    }
+}
@@ -0,0 +1,135 @@
+use crate::compat::prelude::*;
+
+use crate::clock::FakeRelativeClock;
+use crate::middleware::NoOpMiddleware;
+use crate::nanos::Nanos;
+use crate::state::keyed::HashMapStateStore;
+use crate::state::{DecisionLog, DecisionRecord, Outcome};
+use crate::RateLimiter;
+use std::fmt;
+
+/// A recorded decision that a [`replay`] made differently.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ReplayDivergence {
+    /// The index of the decision in the log.
+    pub index: usize,
+    /// The recorded decision.
+    pub recorded: DecisionRecord,
+    /// The outcome of the replayed decision.
+    pub outcome: Outcome,
+    /// The key's state after the replayed decision.
+    pub after: Option<u64>,
+}
+
+impl fmt::Display for ReplayDivergence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "decision #{} at {:?} diverged: recorded {:?} (state {:?}), replayed {:?} (state {:?})",
+            self.index,
+            self.recorded.at,
+            self.recorded.outcome,
+            self.recorded.after,
+            self.outcome,
+            self.after
+        )
+    }
+}
+
+impl std::error::Error for ReplayDivergence {}
+
+/// Replays the decisions of a [`DecisionLog`] (as dumped by a
+/// [`RecordingLimiter`](crate::state::RecordingLimiter)) through an in-memory rate limiter with
+/// the log's quota on a [`FakeRelativeClock`], and returns the number of decisions replayed, or
+/// the first one that was made differently.
+///
+/// Each decision is made at its recorded time and pressure. Keys start out with their recorded
+/// states, and whenever a key's recorded state before a decision differs from the replayed one
+/// (because earlier decisions fell out of the ring buffer, or the state store was changed
+/// outside of decisions), the recorded state is taken as given; only the decisions themselves
+/// and the states they leave behind are compared.
+///
+/// # Example
+/// ```rust
+/// # use nonzero_ext::*;
+/// # use std::time::Duration;
+/// use governor::{clock::FakeRelativeClock, state::DecisionLog, testing, Quota, RateLimiter};
+///
+/// let clock = FakeRelativeClock::default();
+/// let lim = RateLimiter::hashmap_with_clock(Quota::per_second(nonzero!(2u32)), &clock)
+///     .into_recording(nonzero!(4usize));
+/// for key in 0..10 {
+///     clock.advance(Duration::from_millis(300));
+///     let _ = lim.check_key(&(key % 3));
+/// }
+/// let log = DecisionLog::decode(&lim.dump()).unwrap();
+/// assert_eq!(testing::replay(&log), Ok(4));
+/// ```
+pub fn replay(log: &DecisionLog) -> Result<usize, ReplayDivergence> {
+    let clock = FakeRelativeClock::default();
+    let lim: RateLimiter<u64, HashMapStateStore<u64>, _, NoOpMiddleware<Nanos>> =
+        RateLimiter::new(log.quota, HashMapStateStore::default(), &clock);
+    for (index, recorded) in log.records.iter().enumerate() {
+        let key = recorded.key_hash;
+        if recorded.before.is_some() && lim.raw_state(&key) != recorded.before {
+            lim.set_raw_state(&key, recorded.before);
+        }
+        lim.set_pressure(recorded.pressure);
+        let t0 = lim.start() + Nanos::from(recorded.at);
+        let outcome = Outcome::from_multi(&lim.check_key_n_at(&key, recorded.cells, t0));
+        let after = lim.raw_state(&key);
+        if outcome != recorded.outcome || after != recorded.after {
+            return Err(ReplayDivergence {
+                index,
+                recorded: *recorded,
+                outcome,
+                after,
+            });
+        }
+    }
+    Ok(log.records.len())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::state::InMemoryState;
+    use crate::Quota;
+    use nonzero_ext::nonzero;
+    use std::time::Duration;
+
+    #[test]
+    fn replays_direct_limiters() {
+        let clock = FakeRelativeClock::default();
+        let quota = Quota::per_second(nonzero!(5u32)).with_cooldown(Duration::from_millis(500));
+        let lim: RateLimiter<_, InMemoryState, _, NoOpMiddleware<_>> =
+            RateLimiter::direct_with_clock(quota, &clock);
+        let lim = lim.into_recording(nonzero!(16usize));
+        for i in 0..40u32 {
+            clock.advance(Duration::from_millis(u64::from(i % 7) * 30));
+            if i == 20 {
+                lim.limiter().set_pressure(0.3);
+            }
+            let _ = lim.check_n(std::num::NonZeroU32::new(i % 4 + 1).unwrap());
+        }
+        let log = DecisionLog::decode(&lim.dump()).unwrap();
+        assert_eq!(replay(&log), Ok(16));
+    }
+
+    #[test]
+    fn reports_divergences() {
+        let clock = FakeRelativeClock::default();
+        let lim = RateLimiter::direct_with_clock(Quota::per_second(nonzero!(1u32)), &clock)
+            .into_recording(nonzero!(4usize));
+        assert!(lim.check().is_ok());
+        assert!(lim.check().is_err());
+
+        // Pretend the second check was let through:
+        let mut log = lim.log();
+        log.records[1].outcome = Outcome::Conforming;
+        let divergence = replay(&log).unwrap_err();
+        assert_eq!(divergence.index, 1);
+        assert_eq!(divergence.outcome, Outcome::NonConforming);
+        assert!(divergence.to_string().contains("decision #1"));
+    }
+}
@@ -0,0 +1,273 @@
+use crate::compat::prelude::*;
+
+use super::naive::ReferenceLimiter;
+use super::{Decision, Divergence};
+use crate::clock::{Clock, Reference};
+use crate::middleware::RateLimitingMiddleware;
+use crate::state::{NotKeyed, StateStore};
+use crate::{NegativeMultiDecision, NotUntil, RateLimiter};
+use nonzero_ext::nonzero;
+use parking_lot::Mutex;
+use std::fmt;
+use std::num::NonZeroU32;
+use std::time::Duration;
+
+/// A rate limiter that makes every decision twice: with the rate limiter it wraps, whose
+/// decisions it returns, and with a [`ReferenceLimiter`] for the same quota, recording every
+/// decision where the two diverge.
+///
+/// Use it to validate a custom state store or clock in a test, or in a staging deployment:
+/// Both decisions are made at the same time, as read from the rate limiter's clock once, and
+/// decisions are serialized behind a lock, so concurrent decisions don't make the rate limiter
+/// and the reference diverge. Divergences are only recorded while the reference limiter's times
+/// [fit into 64 bits](ReferenceLimiter::fits_in_64_bits).
+///
+/// The wrapped rate limiter's middleware must produce [`NotUntil`] as its negative outcome, so
+/// that the time to wait can be compared.
+///
+/// # Example
+/// ```rust
+/// # use nonzero_ext::*;
+/// use governor::{clock::FakeRelativeClock, testing::ShadowLimiter, Quota, RateLimiter};
+///
+/// let clock = FakeRelativeClock::default();
+/// let lim = ShadowLimiter::new(RateLimiter::direct_with_clock(
+///     Quota::per_second(nonzero!(2u32)),
+///     &clock,
+/// ));
+/// assert_eq!(Ok(()), lim.check());
+/// assert_eq!(Ok(()), lim.check());
+/// assert!(lim.check().is_err());
+/// assert_eq!(lim.decisions(), 3);
+/// lim.assert_no_divergences();
+/// ```
+pub struct ShadowLimiter<K, S, C, MW>
+where
+    S: StateStore<Key = K>,
+    C: Clock,
+    MW: RateLimitingMiddleware<C::Instant>,
+{
+    limiter: RateLimiter<K, S, C, MW>,
+    reference: ReferenceLimiter<K>,
+    record: Mutex<Record>,
+}
+
+#[derive(Default)]
+struct Record {
+    decisions: usize,
+    divergences: Vec<Divergence>,
+}
+
+impl<K, S, C, MW> ShadowLimiter<K, S, C, MW>
+where
+    K: PartialEq + Clone,
+    S: StateStore<Key = K>,
+    C: Clock,
+    MW: RateLimitingMiddleware<C::Instant, NegativeOutcome = NotUntil<C::Instant>>,
+{
+    /// Wraps a rate limiter, which must not have made any decisions yet.
+    pub fn new(limiter: RateLimiter<K, S, C, MW>) -> Self {
+        ShadowLimiter {
+            reference: ReferenceLimiter::new(limiter.quota()),
+            limiter,
+            record: Mutex::new(Record::default()),
+        }
+    }
+
+    /// Returns the wrapped rate limiter.
+    pub fn limiter(&self) -> &RateLimiter<K, S, C, MW> {
+        &self.limiter
+    }
+
+    /// Returns the reference limiter that the wrapped rate limiter's decisions are compared to.
+    pub fn reference(&self) -> &ReferenceLimiter<K> {
+        &self.reference
+    }
+
+    /// Returns the wrapped rate limiter.
+    pub fn into_inner(self) -> RateLimiter<K, S, C, MW> {
+        self.limiter
+    }
+
+    /// Returns the number of decisions made so far.
+    pub fn decisions(&self) -> usize {
+        self.record.lock().decisions
+    }
+
+    /// Returns the decisions so far where the wrapped rate limiter diverged from the reference.
+    pub fn divergences(&self) -> Vec<Divergence> {
+        self.record.lock().divergences.clone()
+    }
+
+    /// Asserts that the wrapped rate limiter didn't diverge from the reference.
+    ///
+    /// # Panics
+    /// Panics with the first divergence, if there is one.
+    #[track_caller]
+    pub fn assert_no_divergences(&self) {
+        let record = self.record.lock();
+        if let Some(divergence) = record.divergences.first() {
+            panic!(
+                "{} of {} decisions diverged, the first: {}",
+                record.divergences.len(),
+                record.decisions,
+                divergence
+            );
+        }
+    }
+
+    /// Allow a single cell through the rate limiter for the given key, comparing the decision
+    /// with the reference's.
+    ///
+    /// See [`RateLimiter::check_key`].
+    pub fn check_key(&self, key: &K) -> Result<MW::PositiveOutcome, NotUntil<C::Instant>> {
+        self.check_key_n(key, nonzero!(1u32))
+            .map_err(|negative| match negative {
+                NegativeMultiDecision::BatchNonConforming(_, not_until) => not_until,
+                NegativeMultiDecision::InsufficientCapacity(_) => {
+                    unreachable!("single cells always fit into the burst capacity")
+                }
+            })
+    }
+
+    /// Allow *only all* `n` cells through the rate limiter for the given key, comparing the
+    /// decision with the reference's.
+    ///
+    /// See [`RateLimiter::check_key_n`].
+    pub fn check_key_n(
+        &self,
+        key: &K,
+        n: NonZeroU32,
+    ) -> Result<MW::PositiveOutcome, NegativeMultiDecision<NotUntil<C::Instant>>> {
+        let mut record = self.record.lock();
+        let t0 = self.limiter.clock().now();
+        let outcome = self.limiter.check_key_n_at(key, n, t0);
+        let actual = Decision::from_outcome(&outcome, t0);
+        let at: Duration = t0.duration_since(self.limiter.start()).into();
+        let expected = self.reference.check_key_n_at(key, n, at);
+        let index = record.decisions;
+        record.decisions += 1;
+        if actual != expected && self.reference.fits_in_64_bits() {
+            record.divergences.push(Divergence {
+                index,
+                at,
+                expected,
+                actual,
+            });
+        }
+        outcome
+    }
+}
+
+impl<S, C, MW> ShadowLimiter<NotKeyed, S, C, MW>
+where
+    S: StateStore<Key = NotKeyed>,
+    C: Clock,
+    MW: RateLimitingMiddleware<C::Instant, NegativeOutcome = NotUntil<C::Instant>>,
+{
+    /// Allow a single cell through the rate limiter, comparing the decision with the
+    /// reference's.
+    ///
+    /// See [`RateLimiter::check`].
+    pub fn check(&self) -> Result<MW::PositiveOutcome, NotUntil<C::Instant>> {
+        self.check_key(&NotKeyed::NonKey)
+    }
+
+    /// Allow *only all* `n` cells through the rate limiter, comparing the decision with the
+    /// reference's.
+    ///
+    /// See [`RateLimiter::check_n`].
+    pub fn check_n(
+        &self,
+        n: NonZeroU32,
+    ) -> Result<MW::PositiveOutcome, NegativeMultiDecision<NotUntil<C::Instant>>> {
+        self.check_key_n(&NotKeyed::NonKey, n)
+    }
+}
+
+impl<K, S, C, MW> fmt::Debug for ShadowLimiter<K, S, C, MW>
+where
+    S: StateStore<Key = K>,
+    C: Clock,
+    MW: RateLimitingMiddleware<C::Instant>,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let record = self.record.lock();
+        f.debug_struct("ShadowLimiter")
+            .field("reference", &self.reference)
+            .field("decisions", &record.decisions)
+            .field("divergences", &record.divergences.len())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::clock::FakeRelativeClock;
+    use crate::nanos::Nanos;
+    use crate::state::InMemoryState;
+    use crate::Quota;
+
+    /// A state store that drops every third update.
+    #[derive(Default)]
+    struct Lossy(InMemoryState, Mutex<usize>);
+
+    impl StateStore for Lossy {
+        type Key = NotKeyed;
+
+        fn measure_and_replace<T, F, E>(&self, key: &NotKeyed, f: F) -> Result<T, E>
+        where
+            F: Fn(Option<Nanos>) -> Result<(T, Nanos), E>,
+        {
+            let mut updates = self.1.lock();
+            *updates = (*updates + 1) % 3;
+            if *updates == 0 {
+                f(self.0.load()).map(|(result, _)| result)
+            } else {
+                self.0.measure_and_replace(key, f)
+            }
+        }
+    }
+
+    #[test]
+    fn records_divergences() {
+        let clock = FakeRelativeClock::default();
+        let quota = Quota::per_second(nonzero!(4u32));
+        let lim = ShadowLimiter::new(RateLimiter::direct_with_clock(quota, &clock));
+        for _ in 0..10 {
+            let _ = lim.check();
+            let _ = lim.check_n(nonzero!(2u32));
+            let _ = lim.check_n(nonzero!(5u32));
+            clock.advance(Duration::from_millis(300));
+        }
+        assert_eq!(lim.decisions(), 30);
+        lim.assert_no_divergences();
+
+        let lim: ShadowLimiter<_, _, _, crate::middleware::NoOpMiddleware<Nanos>> =
+            ShadowLimiter::new(RateLimiter::new(quota, Lossy::default(), &clock));
+        for _ in 0..10 {
+            let _ = lim.check();
+        }
+        let divergences = lim.divergences();
+        assert!(!divergences.is_empty());
+        assert!(divergences[0].actual.is_conforming());
+        assert!(format!("{:?}", lim).contains("ShadowLimiter"));
+    }
+
+    #[test]
+    #[should_panic(expected = "decisions diverged")]
+    fn asserting_no_divergences() {
+        let clock = FakeRelativeClock::default();
+        let lim: ShadowLimiter<_, _, _, crate::middleware::NoOpMiddleware<Nanos>> =
+            ShadowLimiter::new(RateLimiter::new(
+                Quota::per_second(nonzero!(10u32)),
+                Lossy::default(),
+                &clock,
+            ));
+        for _ in 0..20 {
+            let _ = lim.check();
+        }
+        lim.assert_no_divergences();
+    }
+}
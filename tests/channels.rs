@@ -0,0 +1,51 @@
+#![cfg(feature = "std")]
+
+use all_asserts::*;
+use governor::{Quota, RateLimiter};
+use nonzero_ext::*;
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+fn quota() -> Quota {
+    Quota::with_period(Duration::from_millis(5))
+        .unwrap()
+        .allow_burst(nonzero!(2u32))
+}
+
+#[test]
+fn blocks_until_ready() {
+    let i = Instant::now();
+    let lim = RateLimiter::direct(quota());
+    for _ in 0..4 {
+        lim.until_ready_blocking();
+    }
+    assert_ge!(i.elapsed(), Duration::from_millis(10));
+}
+
+#[test]
+fn forwards_at_the_paced_rate() {
+    let i = Instant::now();
+    let lim = RateLimiter::direct(quota());
+    let (input, rx) = mpsc::channel();
+    let (tx, output) = mpsc::channel();
+    for n in 0..6 {
+        input.send(n).unwrap();
+    }
+    drop(input);
+
+    let forwarder = thread::spawn(move || lim.forward_throttled(&rx, &tx));
+    assert_eq!(forwarder.join().unwrap(), 6);
+    assert_ge!(i.elapsed(), Duration::from_millis(20));
+    assert_eq!(output.iter().collect::<Vec<_>>(), vec![0, 1, 2, 3, 4, 5]);
+}
+
+#[test]
+fn stops_forwarding_when_the_output_is_dropped() {
+    let lim = RateLimiter::direct(quota());
+    let (input, rx) = mpsc::channel();
+    let (tx, output) = mpsc::sync_channel(0);
+    input.send(1).unwrap();
+    drop(output);
+    assert_eq!(lim.forward_throttled(&rx, &tx), 0);
+}
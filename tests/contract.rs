@@ -0,0 +1,24 @@
+#![cfg(feature = "testing")]
+
+use governor::state::keyed::HashMapStateStore;
+use governor::state::InMemoryState;
+use governor::state_store_contract_tests;
+
+state_store_contract_tests!(in_memory_state, direct, InMemoryState::default());
+
+state_store_contract_tests!(
+    hashmap_state_store,
+    keyed,
+    HashMapStateStore::<u32>::default(),
+    1,
+    2
+);
+
+#[cfg(feature = "dashmap")]
+state_store_contract_tests!(
+    dashmap_state_store,
+    keyed,
+    governor::state::keyed::DashMapStateStore::<String>::default(),
+    "one".to_string(),
+    "two".to_string()
+);
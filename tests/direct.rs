@@ -1,5 +1,6 @@
 use governor::{
     clock::{Clock, FakeRelativeClock},
+    state::direct::RelaxedRateLimiter,
     NegativeMultiDecision, Quota, RateLimiter,
 };
 use nonzero_ext::nonzero;
@@ -163,3 +164,319 @@ fn actual_threadsafety() {
     clock.advance(ms * 998);
     assert_eq!(Ok(()), lim.check());
 }
+
+#[test]
+fn fractional_rates_are_exact() {
+    let clock = FakeRelativeClock::default();
+    // 1.5M cells per second replenish one cell every 666.67ns:
+    let lb = RateLimiter::direct_with_clock(
+        Quota::per_second(nonzero!(1_500_000u32)).allow_burst(nonzero!(1500u32)),
+        &clock,
+    );
+    let ms = Duration::from_millis(1);
+
+    let mut conforming = 0;
+    for _ in 0..1000 {
+        while lb.check().is_ok() {
+            conforming += 1;
+        }
+        clock.advance(ms);
+    }
+    while lb.check().is_ok() {
+        conforming += 1;
+    }
+    assert_eq!(conforming, 1500 + 1_500_000);
+}
+
+#[test]
+fn sub_nanosecond_rates() {
+    let clock = FakeRelativeClock::default();
+    let lb = RateLimiter::direct_with_clock(
+        Quota::new(nonzero!(3u32), Duration::from_nanos(1)).unwrap(),
+        &clock,
+    );
+
+    for _ in 0..3 {
+        assert_eq!(Ok(()), lb.check());
+    }
+    assert_ne!(Ok(()), lb.check());
+
+    clock.advance(Duration::from_nanos(1));
+    for _ in 0..3 {
+        assert_eq!(Ok(()), lb.check());
+    }
+    assert_ne!(Ok(()), lb.check());
+}
+
+#[test]
+fn burst_larger_than_period() {
+    let clock = FakeRelativeClock::default();
+    let lb = RateLimiter::direct_with_clock(
+        Quota::per_second(nonzero!(10u32)).allow_burst(nonzero!(500u32)),
+        &clock,
+    );
+    let ms = Duration::from_millis(1);
+
+    // The entire burst goes through at once:
+    assert_eq!(Ok(()), lb.check_n(nonzero!(500u32)));
+    assert_ne!(Ok(()), lb.check());
+
+    // ...after which cells replenish at the sustained rate:
+    for _ in 0..10 {
+        clock.advance(ms * 100);
+        assert_eq!(Ok(()), lb.check());
+        assert_ne!(Ok(()), lb.check());
+    }
+
+    // ...and it takes 50s to replenish the entire burst:
+    clock.advance(ms * 49_000);
+    assert_eq!(Ok(()), lb.check_n(nonzero!(490u32)));
+    assert_ne!(Ok(()), lb.check_n(nonzero!(20u32)));
+    clock.advance(ms * 1000);
+    assert_eq!(Ok(()), lb.check_n(nonzero!(10u32)));
+    assert_ne!(Ok(()), lb.check());
+}
+
+#[test]
+fn cooldown_after_burst() {
+    let clock = FakeRelativeClock::default();
+    let lb = RateLimiter::direct_with_clock(
+        Quota::per_second(nonzero!(2u32)).with_cooldown(Duration::from_secs(10)),
+        &clock,
+    );
+    let ms = Duration::from_millis(1);
+
+    // Partially using up the burst doesn't trigger the cooldown:
+    assert_eq!(Ok(()), lb.check());
+    clock.advance(ms * 500);
+    assert_eq!(Ok(()), lb.check());
+    clock.advance(ms * 500);
+    assert_eq!(Ok(()), lb.check_n(nonzero!(2u32)));
+
+    // But using it up entirely does:
+    assert_ne!(Ok(()), lb.check());
+    clock.advance(ms * 10_000);
+    assert_ne!(Ok(()), lb.check());
+    clock.advance(ms * 499);
+    assert_ne!(Ok(()), lb.check());
+    clock.advance(ms);
+    assert_eq!(Ok(()), lb.check());
+
+    // Using up the replenished cell triggers the cooldown again:
+    clock.advance(ms * 500);
+    assert_ne!(Ok(()), lb.check());
+    clock.advance(ms * 10_000);
+    assert_eq!(Ok(()), lb.check());
+}
+
+#[test]
+fn shared_clones_share_state() {
+    let clock = FakeRelativeClock::default();
+    let lim =
+        RateLimiter::direct_with_clock(Quota::per_second(nonzero!(20u32)), &clock).into_shared();
+    let ms = Duration::from_millis(1);
+
+    let threads: Vec<_> = (0..20)
+        .map(|_| {
+            let lim = lim.clone();
+            std::thread::spawn(move || {
+                assert_eq!(Ok(()), lim.check());
+            })
+        })
+        .collect();
+    for t in threads {
+        t.join().unwrap();
+    }
+
+    let other = lim.clone();
+    assert_ne!(Ok(()), other.check());
+    clock.advance(ms * 50);
+    assert_eq!(Ok(()), other.check());
+    assert_ne!(Ok(()), lim.check());
+}
+
+#[cfg(feature = "std")]
+#[test]
+fn arrivals_match_individual_checks() {
+    let quota = Quota::per_second(nonzero!(10u32)).allow_burst(nonzero!(3u32));
+    let clock = FakeRelativeClock::default();
+    let individual = RateLimiter::direct_with_clock(quota, &clock);
+    let bulk = RateLimiter::direct_with_clock(quota, &clock);
+
+    let mut arrivals = vec![];
+    let mut expected = [0u64; 3];
+    for i in 0..150 {
+        clock.advance(Duration::from_millis((i * 7) % 130));
+        arrivals.push(clock.now());
+        if individual.check().is_ok() {
+            expected[i as usize / 64] |= 1 << (i % 64);
+        }
+    }
+
+    let mut conforming = [u64::MAX; 3];
+    let allowed = bulk.check_arrivals(&arrivals, &mut conforming);
+    assert_eq!(conforming, expected);
+    assert_eq!(
+        allowed,
+        expected.iter().map(|w| w.count_ones() as usize).sum()
+    );
+    // the bulk check updated the state just like the individual checks did:
+    assert_eq!(individual.check().is_ok(), bulk.check().is_ok());
+}
+
+#[cfg(feature = "std")]
+#[test]
+#[should_panic]
+fn arrivals_bitmap_too_small() {
+    let clock = FakeRelativeClock::default();
+    let lb = RateLimiter::direct_with_clock(Quota::per_second(nonzero!(5u32)), &clock);
+    let arrivals = vec![clock.now(); 65];
+    lb.check_arrivals(&arrivals, &mut [0u64; 1]);
+}
+
+#[test]
+fn arrivals_bitmap_too_small_without_panicking() {
+    let clock = FakeRelativeClock::default();
+    let lb = RateLimiter::direct_with_clock(Quota::per_second(nonzero!(5u32)), &clock);
+    let arrivals = vec![clock.now(); 65];
+    assert_eq!(lb.try_check_arrivals(&arrivals, &mut [0u64; 1]), None);
+    // No decisions were made:
+    assert_eq!(lb.try_check_arrivals(&arrivals, &mut [0u64; 2]), Some(5));
+}
+
+#[test]
+fn relaxed_shares_the_quota_between_threads() {
+    let clock = FakeRelativeClock::default();
+    let lim = RelaxedRateLimiter::direct_with_clock(
+        Quota::per_second(nonzero!(10u32)),
+        nonzero!(4u32),
+        &clock,
+    );
+    let a = lim.local();
+    let b = lim.local();
+
+    // a takes a lease of 4, b another 4, and the remaining 2 go to a in single cells:
+    let mut allowed = 0;
+    for _ in 0..12 {
+        allowed += a.check().is_ok() as u32;
+        allowed += b.check().is_ok() as u32;
+    }
+    assert_eq!(allowed, 10);
+    assert_ne!(Ok(()), lim.shared().check());
+
+    // 100ms later, one cell has been replenished:
+    clock.advance(Duration::from_millis(100));
+    assert_eq!(Ok(()), b.check());
+    assert_ne!(Ok(()), a.check());
+}
+
+#[test]
+fn relaxed_admits_up_to_twice_the_burst() {
+    let clock = FakeRelativeClock::default();
+    let lim = RelaxedRateLimiter::direct_with_clock(
+        Quota::per_second(nonzero!(10u32)),
+        nonzero!(10u32),
+        &clock,
+    );
+    let a = lim.local();
+    let b = lim.local();
+    assert_eq!(Ok(()), a.check());
+    assert_eq!(a.remaining(), 9);
+
+    // Just before a's lease expires, the shared rate limiter has replenished the cells it
+    // leased, so both a's lease and the shared rate limiter let cells through at once:
+    clock.advance(Duration::from_millis(999));
+    let mut allowed = 0;
+    for _ in 0..20 {
+        allowed += a.check().is_ok() as u32;
+        allowed += b.check().is_ok() as u32;
+    }
+    assert_eq!(allowed, 18);
+}
+
+#[test]
+fn relaxed_returns_unused_cells() {
+    let clock = FakeRelativeClock::default();
+    let lim = RelaxedRateLimiter::direct_with_clock(
+        Quota::per_second(nonzero!(10u32)),
+        nonzero!(5u32),
+        &clock,
+    )
+    .with_reconcile_interval(Duration::from_millis(10));
+    assert_eq!(lim.slice(), nonzero!(5u32));
+
+    {
+        let a = lim.local();
+        assert_eq!(Ok(()), a.check());
+        assert_eq!(a.remaining(), 4);
+    }
+    // dropping `a` returned its 4 unused cells:
+    assert_eq!(Ok(()), lim.shared().check_n(nonzero!(9u32)));
+    assert_ne!(Ok(()), lim.shared().check());
+
+    clock.advance(Duration::from_millis(500));
+    let b = lim.local();
+    assert_eq!(Ok(()), b.check());
+    assert_eq!(b.remaining(), 4);
+
+    // once the lease is older than the reconcile interval, its cells are returned and a new
+    // lease (including the cell replenished in the meantime) gets taken:
+    clock.advance(Duration::from_millis(100));
+    assert_eq!(Ok(()), b.check());
+    assert_eq!(b.remaining(), 4);
+    b.reconcile();
+    assert_eq!(b.remaining(), 0);
+    assert_eq!(Ok(()), lim.shared().check_n(nonzero!(4u32)));
+    assert_ne!(Ok(()), lim.shared().check());
+}
+
+#[test]
+fn reset_applies_to_clones() {
+    let clock = FakeRelativeClock::default();
+    let lim =
+        RateLimiter::direct_with_clock(Quota::per_second(nonzero!(4u32)), &clock).into_shared();
+    let clone = lim.clone();
+    assert_eq!(Ok(()), lim.check_n(nonzero!(4u32)));
+    assert!(clone.check().is_err());
+    assert_eq!(lim.saturation(), 1.0);
+
+    clone.reset();
+    assert_eq!(lim.saturation(), 0.0);
+    assert_eq!(Ok(()), lim.check_n(nonzero!(4u32)));
+    let negative = clone.check_n(nonzero!(2u32)).unwrap_err();
+    match negative {
+        NegativeMultiDecision::BatchNonConforming(2, negative) => {
+            assert_eq!(
+                negative.wait_time_from(clock.now()),
+                Duration::from_millis(250)
+            );
+            assert_eq!(
+                negative.earliest_possible(),
+                clock.now() + Duration::from_millis(250)
+            );
+        }
+        other => panic!("expected a non-conforming batch, got {:?}", other),
+    }
+}
+
+#[test]
+fn rounds_wait_times() {
+    let clock = FakeRelativeClock::default();
+    let lim = RateLimiter::direct_with_clock(Quota::per_second(nonzero!(2u32)), &clock)
+        .with_wait_granularity(Duration::from_secs(1));
+    assert_eq!(lim.wait_granularity(), Some(Duration::from_secs(1)));
+    lim.check_n(nonzero!(2u32)).unwrap();
+    clock.advance(Duration::from_millis(400));
+    let negative = lim.check().unwrap_err();
+    assert_eq!(negative.wait_time_from(clock.now()), Duration::from_secs(1));
+    assert_eq!(negative.rejection(clock.now()).retry_after_ms, 1000);
+    assert_eq!(
+        negative.earliest_possible(),
+        clock.now() + Duration::from_millis(100)
+    );
+
+    clock.advance(Duration::from_millis(100));
+    assert_eq!(negative.wait_time_from(clock.now()), Duration::ZERO);
+    let lim = lim.with_wait_granularity(Duration::ZERO);
+    assert_eq!(lim.wait_granularity(), None);
+}
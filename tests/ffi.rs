@@ -0,0 +1,81 @@
+#![cfg(feature = "ffi")]
+
+use governor::ffi::*;
+use std::time::Duration;
+
+const SECOND: u64 = 1_000_000_000;
+
+#[test]
+fn direct_limiter_through_c_abi() {
+    let lim = governor_direct_new(SECOND, 2);
+    assert!(!lim.is_null());
+    let mut wait = 0;
+    unsafe {
+        assert_eq!(
+            governor_direct_check(lim, 2, &mut wait),
+            GovernorDecision::Allowed
+        );
+        assert_eq!(
+            governor_direct_check(lim, 1, &mut wait),
+            GovernorDecision::Limited
+        );
+        assert!(wait > 0 && wait <= SECOND, "wait was {}", wait);
+        assert_eq!(
+            governor_direct_check(lim, 1, std::ptr::null_mut()),
+            GovernorDecision::Limited
+        );
+        assert_eq!(
+            governor_direct_check(lim, 3, &mut wait),
+            GovernorDecision::InsufficientCapacity
+        );
+        assert_eq!(
+            governor_direct_check(lim, 0, &mut wait),
+            GovernorDecision::InvalidArgument
+        );
+        governor_direct_free(lim);
+    }
+}
+
+#[test]
+fn keyed_limiter_through_c_abi() {
+    let lim = governor_keyed_new(Duration::from_millis(20).as_nanos() as u64, 1);
+    assert!(!lim.is_null());
+    let alice = b"alice";
+    let bob = b"bob";
+    unsafe {
+        let check = |key: &[u8]| {
+            governor_keyed_check(lim, key.as_ptr(), key.len(), 1, std::ptr::null_mut())
+        };
+        assert_eq!(check(alice), GovernorDecision::Allowed);
+        assert_eq!(check(alice), GovernorDecision::Limited);
+        assert_eq!(check(bob), GovernorDecision::Allowed);
+        assert_eq!(
+            governor_keyed_check(lim, std::ptr::null(), 0, 1, std::ptr::null_mut()),
+            GovernorDecision::Allowed
+        );
+        assert_eq!(
+            governor_keyed_check(lim, std::ptr::null(), 1, 1, std::ptr::null_mut()),
+            GovernorDecision::InvalidArgument
+        );
+        assert_eq!(governor_keyed_retain_recent(lim), 3);
+        std::thread::sleep(Duration::from_millis(40));
+        assert_eq!(governor_keyed_retain_recent(lim), 0);
+        governor_keyed_free(lim);
+    }
+}
+
+#[test]
+fn invalid_arguments() {
+    assert!(governor_direct_new(0, 1).is_null());
+    assert!(governor_direct_new(SECOND, 0).is_null());
+    assert!(governor_keyed_new(0, 1).is_null());
+    unsafe {
+        assert_eq!(
+            governor_direct_check(std::ptr::null(), 1, std::ptr::null_mut()),
+            GovernorDecision::InvalidArgument
+        );
+        assert_eq!(governor_keyed_retain_recent(std::ptr::null()), 0);
+        governor_direct_free(std::ptr::null_mut());
+        governor_keyed_free(std::ptr::null_mut());
+    }
+}
@@ -0,0 +1,118 @@
+#![cfg(all(unix, feature = "file-state"))]
+
+use governor::{state::FileState, Quota, RateLimiter};
+use nonzero_ext::nonzero;
+use std::convert::TryInto;
+use std::path::PathBuf;
+
+fn dir(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("governor-file-{}-{}", name, std::process::id()))
+}
+
+#[test]
+fn state_persists_across_rate_limiters() {
+    let dir = dir("persists");
+    let quota = Quota::per_hour(nonzero!(2u32));
+    let lim = RateLimiter::with_file_state(quota, FileState::open(&dir, "calls").unwrap());
+    assert_eq!(Ok(()), lim.check());
+    drop(lim);
+
+    let lim = RateLimiter::with_file_state(quota, FileState::open(&dir, "calls").unwrap());
+    let other = RateLimiter::with_file_state(quota, FileState::open(&dir, "calls").unwrap());
+    assert_eq!(Ok(()), other.check());
+    assert!(lim.check().is_err());
+
+    // Other names have their own state:
+    let unrelated = RateLimiter::with_file_state(quota, FileState::open(&dir, "other").unwrap());
+    assert_eq!(Ok(()), unrelated.check());
+
+    assert!(lim.into_state_store().path().ends_with("calls.state"));
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn concurrent_decisions_are_serialized() {
+    let dir = dir("concurrent");
+    let quota = Quota::per_hour(nonzero!(20u32));
+    let admitted: usize = (0..4)
+        .map(|_| {
+            let dir = dir.clone();
+            std::thread::spawn(move || {
+                let lim = RateLimiter::with_file_state(quota, FileState::open(&dir, "c").unwrap());
+                (0..10).filter(|_| lim.check().is_ok()).count()
+            })
+        })
+        .collect::<Vec<_>>()
+        .into_iter()
+        .map(|t| t.join().unwrap())
+        .sum();
+    assert_eq!(admitted, 20);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn fine_grained_quotas_count_from_the_files_epoch() {
+    // States are scaled by the number of cells per period, which mustn't overflow for the
+    // time since the state file's epoch:
+    let dir = dir("fine");
+    // 13 cells per minute don't take whole nanoseconds, so they are counted in 13ths:
+    let quota = Quota::per_minute(nonzero!(13u32));
+    let lim = RateLimiter::with_file_state(quota, FileState::open(&dir, "f").unwrap());
+    assert_eq!(Ok(()), lim.check_n(nonzero!(12u32)));
+    assert_eq!(Ok(()), lim.check());
+    assert!(lim.check().is_err());
+    drop(lim);
+
+    let lim = RateLimiter::with_file_state(quota, FileState::open(&dir, "f").unwrap());
+    assert!(lim.check().is_err());
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn garbage_state_is_treated_as_fresh() {
+    let dir = dir("garbage");
+    let quota = Quota::per_hour(nonzero!(1u32));
+    let state = FileState::open(&dir, "g").unwrap();
+    std::fs::write(state.path(), b"definitely not a rate limiter").unwrap();
+    let lim = RateLimiter::with_file_state(quota, state);
+    assert_eq!(Ok(()), lim.check());
+    assert!(lim.check().is_err());
+    assert!(format!("{:?}", lim).contains("FileState"));
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn states_from_another_epoch_are_converted() {
+    let dir = dir("epoch");
+    let quota = Quota::per_hour(nonzero!(2u32));
+    let state = FileState::open(&dir, "e").unwrap();
+    let path = state.path().to_owned();
+    let lim = RateLimiter::with_file_state(quota, state);
+    assert_eq!(Ok(()), lim.check_n(nonzero!(2u32)));
+
+    // Another process replaces the file with the same state, counted from an epoch a minute
+    // later (in units of half a nanosecond, for two cells per period):
+    let mut file = std::fs::read(&path).unwrap();
+    let field = |file: &[u8], at: usize| u64::from_le_bytes(file[at..at + 8].try_into().unwrap());
+    let (epoch, tat) = (field(&file, 8), field(&file, 16));
+    let minute = 60_000_000_000;
+    file[8..16].copy_from_slice(&(epoch + minute).to_le_bytes());
+    file[16..24].copy_from_slice(&(tat - 2 * minute).to_le_bytes());
+    std::fs::write(&path, &file).unwrap();
+    assert!(lim.check().is_err());
+    assert!(lim.check().is_err());
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn persistent_limiters_live_in_the_cache_dir() {
+    let dir = dir("cache");
+    std::env::set_var("XDG_CACHE_HOME", &dir);
+    let lim = RateLimiter::persistent(Quota::per_hour(nonzero!(1u32)), "cli").unwrap();
+    assert_eq!(Ok(()), lim.check());
+    assert_eq!(
+        lim.into_state_store().path(),
+        dir.join("governor").join("cli.state")
+    );
+    std::fs::remove_dir_all(&dir).unwrap();
+}
@@ -2,7 +2,9 @@
 
 use all_asserts::*;
 use futures::executor::block_on;
-use governor::{Quota, RateLimiter};
+use futures::StreamExt;
+use governor::clock::{Clock, DefaultClock};
+use governor::{AsyncKeyedLimiter, NegativeMultiDecision, Quota, RateLimiter};
 use nonzero_ext::*;
 use std::sync::Arc;
 use std::thread;
@@ -24,6 +26,17 @@ fn pauses() {
     assert_ge!(i.elapsed(), Duration::from_millis(100));
 }
 
+#[test]
+fn pauses_for_rounded_wait_times() {
+    let lim = RateLimiter::direct(Quota::per_second(nonzero!(100u32)))
+        .with_wait_granularity(Duration::from_millis(200));
+    while lim.check().is_ok() {}
+
+    let i = Instant::now();
+    block_on(lim.until_ready());
+    assert_ge!(i.elapsed(), Duration::from_millis(200));
+}
+
 #[test]
 fn pauses_n() {
     let i = Instant::now();
@@ -53,6 +66,19 @@ fn pauses_keyed() {
     assert_ge!(i.elapsed(), Duration::from_millis(100));
 }
 
+#[test]
+fn pauses_keyed_n() {
+    let i = Instant::now();
+    let lim = RateLimiter::keyed(Quota::per_second(nonzero!(10u32)));
+
+    for _ in 0..6 {
+        lim.check_key(&1u32).unwrap();
+    }
+
+    block_on(lim.until_key_n_ready(&1u32, nonzero!(5u32))).unwrap();
+    assert_ge!(i.elapsed(), Duration::from_millis(100));
+}
+
 #[test]
 fn proceeds() {
     let i = Instant::now();
@@ -128,3 +154,143 @@ fn errors_on_exceeded_capacity() {
 
     block_on(lim.until_n_ready(nonzero!(11u32))).unwrap_err();
 }
+
+#[test]
+fn paces_batches() {
+    let i = Instant::now();
+    let quota = Quota::with_period(Duration::from_millis(1))
+        .unwrap()
+        .allow_burst(nonzero!(10u32));
+    let lim = RateLimiter::direct(quota);
+
+    let chunks: Vec<u32> = block_on(lim.pace_batch(25).map(|n| n.get()).collect());
+    assert_eq!(chunks, vec![10, 10, 5]);
+    assert_ge!(i.elapsed(), Duration::from_millis(15));
+    assert_eq!(block_on(lim.pace_batch(0).count()), 0);
+}
+
+#[test]
+fn paces_batches_under_pressure() {
+    let quota = Quota::with_period(Duration::from_millis(1))
+        .unwrap()
+        .allow_burst(nonzero!(10u32));
+    let lim = RateLimiter::direct(quota);
+    lim.set_pressure(0.5);
+    let chunks: Vec<u32> = block_on(lim.pace_batch(12).map(|n| n.get()).collect());
+    assert_eq!(chunks, vec![5, 5, 2]);
+
+    // Without the capacity for a single cell, the stream ends:
+    lim.set_pressure(0.0);
+    assert_eq!(block_on(lim.pace_batch(12).count()), 0);
+}
+
+#[test]
+fn ticks() {
+    let i = Instant::now();
+    let quota = Quota::with_period(Duration::from_millis(5)).unwrap();
+    let lim = RateLimiter::direct(quota);
+
+    assert_eq!(block_on(lim.ticks().take(4).count()), 4);
+    // The first tick is immediate, then the rest come every 5ms:
+    assert_ge!(i.elapsed(), Duration::from_millis(15));
+}
+
+#[test]
+fn keyed_errors_on_exceeded_capacity() {
+    let lim = RateLimiter::keyed(Quota::per_second(nonzero!(10u32)));
+
+    let err = block_on(lim.until_key_n_ready(&1u32, nonzero!(11u32))).unwrap_err();
+    assert_eq!(err.capacity(), 10);
+}
+
+#[test]
+fn async_keyed_spawned_futures() {
+    let i = Instant::now();
+    let lim = AsyncKeyedLimiter::from(RateLimiter::keyed(Quota::per_second(nonzero!(10u32))));
+    let mut children = vec![];
+
+    for _i in 0..20 {
+        // The future owns everything it needs, so it can be moved into another thread:
+        let ready = lim.until_key_ready(1u32);
+        children.push(thread::spawn(move || block_on(ready)));
+    }
+    for child in children {
+        child.join().unwrap();
+    }
+    let elapsed = i.elapsed();
+    assert_ge!(elapsed, Duration::from_millis(8),);
+    assert_ne!(Ok(()), lim.check_key(&1u32));
+    assert_eq!(Ok(()), lim.check_key(&2u32));
+}
+
+#[test]
+fn times_out() {
+    let i = Instant::now();
+    let lim = RateLimiter::direct(Quota::per_second(nonzero!(10u32)));
+    block_on(lim.until_n_ready_with_timeout(nonzero!(10u32), Duration::from_millis(10))).unwrap();
+
+    let timed_out = block_on(lim.until_ready_with_timeout(Duration::from_millis(20))).unwrap_err();
+    assert_ge!(i.elapsed(), Duration::from_millis(20));
+    assert_lt!(i.elapsed(), Duration::from_millis(100));
+    let wait = timed_out
+        .not_until()
+        .wait_time_from(DefaultClock::default().now());
+    assert_range!((1..=100), wait.as_millis());
+    assert!(!format!("{}", timed_out).is_empty());
+
+    assert_eq!(
+        block_on(lim.until_n_ready_with_timeout(nonzero!(11u32), Duration::from_secs(1))),
+        Err(NegativeMultiDecision::InsufficientCapacity(10))
+    );
+    block_on(lim.until_ready_with_timeout(Duration::from_secs(1))).unwrap();
+    assert_ge!(i.elapsed(), Duration::from_millis(100));
+}
+
+#[test]
+fn keyed_times_out() {
+    let lim = RateLimiter::keyed(Quota::per_second(nonzero!(10u32)));
+    block_on(lim.until_key_ready_with_timeout(&1u32, Duration::from_millis(10))).unwrap();
+    assert!(matches!(
+        block_on(lim.until_key_n_ready_with_timeout(
+            &1u32,
+            nonzero!(10u32),
+            Duration::from_millis(10)
+        )),
+        Err(NegativeMultiDecision::BatchNonConforming(10, _))
+    ));
+    block_on(lim.until_key_n_ready_with_timeout(&2u32, nonzero!(10u32), Duration::from_millis(10)))
+        .unwrap();
+    assert!(block_on(lim.until_key_ready_with_timeout(&2u32, Duration::from_millis(10))).is_err());
+}
+
+#[test]
+fn wakes_early_when_capacity_frees_up() {
+    use governor::state::multi::Debit;
+
+    let i = Instant::now();
+    let lim = Arc::new(RateLimiter::direct(Quota::per_minute(nonzero!(1u32))));
+    lim.check().unwrap();
+    let refunding = thread::spawn({
+        let lim = Arc::clone(&lim);
+        move || {
+            thread::sleep(Duration::from_millis(50));
+            lim.refund(nonzero!(1u32));
+        }
+    });
+    block_on(lim.until_ready());
+    assert_range!((50..=1000), i.elapsed().as_millis());
+    refunding.join().unwrap();
+
+    let lim = Arc::new(RateLimiter::keyed(Quota::per_minute(nonzero!(1u32))));
+    lim.check_key(&"a").unwrap();
+    let refunding = thread::spawn({
+        let lim = Arc::clone(&lim);
+        move || {
+            thread::sleep(Duration::from_millis(50));
+            (&*lim, &"a").refund(nonzero!(1u32));
+        }
+    });
+    block_on(lim.until_key_ready_with_timeout(&"a", Duration::from_secs(10))).unwrap();
+    assert_range!((100..=2000), i.elapsed().as_millis());
+    refunding.join().unwrap();
+}
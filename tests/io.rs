@@ -0,0 +1,94 @@
+#![cfg(feature = "std")]
+
+use all_asserts::*;
+use futures::executor::block_on;
+use futures::io::{AsyncReadExt, AsyncWriteExt, Cursor};
+use governor::{
+    state::direct::{ThrottledReader, ThrottledWriter},
+    Quota, RateLimiter,
+};
+use nonzero_ext::*;
+use std::time::{Duration, Instant};
+
+fn quota() -> Quota {
+    // One byte per millisecond, in bursts of up to 10 bytes:
+    Quota::with_period(Duration::from_millis(1))
+        .unwrap()
+        .allow_burst(nonzero!(10u32))
+}
+
+#[test]
+fn throttles_writes() {
+    let i = Instant::now();
+    let lim = RateLimiter::direct(quota());
+    let mut writer = ThrottledWriter::new(Vec::new(), &lim);
+
+    block_on(writer.write_all(&[1; 25])).unwrap();
+    assert_ge!(i.elapsed(), Duration::from_millis(15));
+    assert_eq!(writer.into_inner(), vec![1; 25]);
+}
+
+#[test]
+fn writes_in_chunks() {
+    let lim = RateLimiter::direct(quota());
+    let mut writer = ThrottledWriter::new(Vec::new(), &lim).with_chunk_size(nonzero!(4u32));
+
+    assert_eq!(block_on(writer.write(&[1; 12])).unwrap(), 4);
+    assert_eq!(block_on(writer.write(&[])).unwrap(), 0);
+    // Chunks are capped at the burst size:
+    let lim = RateLimiter::direct(quota());
+    let mut writer = ThrottledWriter::new(Vec::new(), &lim).with_chunk_size(nonzero!(100u32));
+    assert_eq!(block_on(writer.write(&[1; 12])).unwrap(), 10);
+}
+
+#[test]
+fn throttles_reads() {
+    let i = Instant::now();
+    let lim = RateLimiter::direct(quota());
+    let mut reader = ThrottledReader::new(Cursor::new(vec![2; 25]), &lim);
+
+    let mut read = Vec::new();
+    block_on(reader.read_to_end(&mut read)).unwrap();
+    assert_ge!(i.elapsed(), Duration::from_millis(15));
+    assert_eq!(read, vec![2; 25]);
+}
+
+#[cfg(feature = "tokio")]
+#[test]
+fn throttles_tokio_writes() {
+    use tokio::io::AsyncWriteExt;
+
+    let i = Instant::now();
+    let lim = RateLimiter::direct(quota());
+    let mut writer = ThrottledWriter::new(Vec::new(), &lim).with_chunk_size(nonzero!(4u32));
+
+    assert_eq!(
+        block_on(AsyncWriteExt::write(&mut writer, &[1; 12])).unwrap(),
+        4
+    );
+    block_on(AsyncWriteExt::write_all(&mut writer, &[1; 21])).unwrap();
+    block_on(AsyncWriteExt::shutdown(&mut writer)).unwrap();
+    assert_ge!(i.elapsed(), Duration::from_millis(15));
+    assert_eq!(writer.into_inner(), vec![1; 25]);
+}
+
+#[cfg(feature = "tokio")]
+#[test]
+fn throttles_tokio_reads() {
+    use tokio::io::AsyncReadExt;
+
+    let i = Instant::now();
+    let lim = RateLimiter::direct(quota());
+    let data = [2; 25];
+    let mut reader = ThrottledReader::new(&data[..], &lim).with_chunk_size(nonzero!(4u32));
+
+    let mut buf = [0; 12];
+    assert_eq!(
+        block_on(AsyncReadExt::read(&mut reader, &mut buf)).unwrap(),
+        4
+    );
+    let mut read = buf[..4].to_vec();
+    block_on(AsyncReadExt::read_to_end(&mut reader, &mut read)).unwrap();
+    assert_ge!(i.elapsed(), Duration::from_millis(15));
+    assert_eq!(read, vec![2; 25]);
+}
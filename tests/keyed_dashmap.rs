@@ -1,5 +1,6 @@
 #![cfg(all(feature = "std", feature = "dashmap"))]
 
+use all_asserts::{assert_ge, assert_lt};
 use governor::{
     clock::{Clock, FakeRelativeClock},
     Quota, RateLimiter,
@@ -145,3 +146,54 @@ fn dashmap_length() {
     assert_eq!(lim.len(), 3);
     assert!(!lim.is_empty());
 }
+
+#[test]
+fn memory_accounting() {
+    let clock = FakeRelativeClock::default();
+    let lb = RateLimiter::dashmap_with_clock(Quota::per_second(nonzero!(1u32)), &clock);
+    assert_eq!(
+        lb.memory_usage(),
+        lb.capacity() * (std::mem::size_of::<u32>() + 8)
+    );
+
+    for key in 0..100u32 {
+        lb.check_key(&key).unwrap();
+    }
+    assert_eq!(lb.len(), 100);
+    assert_ge!(lb.capacity(), 100);
+    assert_ge!(lb.memory_usage(), 100 * (std::mem::size_of::<u32>() + 8));
+}
+
+#[test]
+fn compaction() {
+    let clock = FakeRelativeClock::default();
+    let lim = RateLimiter::dashmap_with_clock(Quota::per_second(nonzero!(1u32)), &clock);
+    for key in 0..1000u32 {
+        lim.check_key(&key).unwrap();
+    }
+    let peak = lim.capacity();
+    assert_ge!(peak, 1000);
+    clock.advance(Duration::from_secs(2));
+    lim.check_key(&1u32).unwrap();
+
+    assert_eq!(lim.compact(), 999);
+    assert_eq!(lim.len(), 1);
+    assert_lt!(lim.capacity(), peak);
+    assert_eq!(lim.compact(), 0);
+}
+
+#[test]
+fn borrowed_keys() {
+    let clock = FakeRelativeClock::default();
+    let lb: RateLimiter<String, _, _, NoOpMiddleware<_>> =
+        RateLimiter::dashmap_with_clock(Quota::per_second(nonzero!(2u32)), &clock);
+
+    assert_eq!(Ok(()), lb.check_key_borrowed("foo"));
+    assert_eq!(Ok(()), lb.check_key(&"foo".to_string()));
+    assert_ne!(Ok(()), lb.check_key_borrowed("foo"));
+    assert_eq!(lb.len(), 1);
+
+    assert_eq!(Ok(()), lb.check_key_borrowed_n("bar", nonzero!(2u32)));
+    assert_ne!(Ok(()), lb.check_key_borrowed_n("bar", nonzero!(1u32)));
+    assert_eq!(lb.len(), 2);
+}
@@ -0,0 +1,103 @@
+use governor::{
+    clock::{Clock, FakeRelativeClock},
+    state::keyed::{EnumArray, EnumKeyedLimiter},
+    Quota, RateLimiter,
+};
+use nonzero_ext::nonzero;
+use std::time::Duration;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Class {
+    Read,
+    Write,
+    Admin,
+}
+
+impl EnumArray for Class {
+    const LENGTH: usize = 3;
+
+    fn index(&self) -> usize {
+        *self as usize
+    }
+}
+
+const KEYS: &[Class] = &[Class::Read, Class::Write, Class::Admin];
+
+#[test]
+fn accepts_first_cell() {
+    let clock = FakeRelativeClock::default();
+    let lb = RateLimiter::enum_keyed_with_clock(Quota::per_second(nonzero!(5u32)), &clock);
+    for key in KEYS {
+        assert_eq!(Ok(()), lb.check_key(key), "key {:?}", key);
+    }
+}
+
+#[test]
+fn rejects_too_many() {
+    let clock = FakeRelativeClock::default();
+    let lb: EnumKeyedLimiter<Class, FakeRelativeClock> =
+        RateLimiter::enum_keyed_with_clock(Quota::per_second(nonzero!(2u32)), &clock);
+    let ms = Duration::from_millis(1);
+
+    for key in KEYS {
+        // use up our burst capacity (2 in the first second):
+        assert_eq!(Ok(()), lb.check_key(key), "Now: {:?}", clock.now());
+        clock.advance(ms);
+        assert_eq!(Ok(()), lb.check_key(key), "Now: {:?}", clock.now());
+
+        clock.advance(ms);
+        assert_ne!(Ok(()), lb.check_key(key), "Now: {:?}", clock.now());
+
+        // should be ok again in 1s:
+        clock.advance(ms * 1000);
+        assert_eq!(Ok(()), lb.check_key(key), "Now: {:?}", clock.now());
+        clock.advance(ms);
+        assert_eq!(Ok(()), lb.check_key(key));
+
+        clock.advance(ms);
+        assert_ne!(Ok(()), lb.check_key(key), "{:?}", lb);
+    }
+}
+
+#[test]
+fn keys_are_independent() {
+    let clock = FakeRelativeClock::default();
+    let lb = RateLimiter::enum_keyed_with_clock(Quota::per_second(nonzero!(1u32)), &clock);
+
+    assert_eq!(Ok(()), lb.check_key_n(&Class::Write, nonzero!(1u32)));
+    assert_ne!(Ok(()), lb.check_key(&Class::Write));
+    assert_eq!(Ok(()), lb.check_key(&Class::Read));
+    assert_eq!(Ok(()), lb.check_key(&Class::Admin));
+}
+
+#[test]
+fn expiring_keys() {
+    let clock = FakeRelativeClock::default();
+    let lb = RateLimiter::enum_keyed_with_clock(Quota::per_second(nonzero!(1u32)), &clock);
+    assert!(lb.is_empty());
+
+    lb.check_key(&Class::Read).unwrap();
+    clock.advance(Duration::from_millis(500));
+    lb.check_key(&Class::Admin).unwrap();
+    assert_eq!(lb.len(), 2);
+
+    clock.advance(Duration::from_millis(1500));
+    lb.retain_recent();
+    assert_eq!(lb.len(), 1);
+    assert_eq!(Ok(()), lb.check_key(&Class::Admin));
+    assert_eq!(Ok(()), lb.check_key(&Class::Read));
+
+    clock.advance(Duration::from_secs(2));
+    lb.retain_recent();
+    lb.shrink_to_fit();
+    assert!(lb.is_empty());
+}
+
+#[test]
+fn memory_accounting() {
+    let lb = RateLimiter::enum_keyed(Quota::per_second(nonzero!(1u32)));
+    assert_eq!(lb.capacity(), 3);
+    assert_eq!(lb.memory_usage(), 3 * 8);
+    lb.check_key(&Class::Write).unwrap();
+    assert_eq!(lb.len(), 1);
+}
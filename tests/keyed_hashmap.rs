@@ -1,6 +1,7 @@
+use all_asserts::{assert_ge, assert_lt};
 use governor::{
     clock::{Clock, FakeRelativeClock},
-    Quota, RateLimiter,
+    NegativeMultiDecision, Quota, RateLimiter,
 };
 use governor::{middleware::NoOpMiddleware, state::keyed::HashMapStateStore};
 use nonzero_ext::nonzero;
@@ -139,3 +140,310 @@ fn hashmap_length() {
     assert_eq!(lim.len(), 3);
     assert!(!lim.is_empty());
 }
+
+#[test]
+fn shared_housekeeping() {
+    let clock = FakeRelativeClock::default();
+    let lim =
+        RateLimiter::hashmap_with_clock(Quota::per_second(nonzero!(1u32)), &clock).into_shared();
+    let other = lim.clone();
+
+    lim.check_key(&"foo").unwrap();
+    assert_ne!(Ok(()), other.check_key(&"foo"));
+    assert_eq!(other.len(), 1);
+
+    clock.advance(Duration::from_secs(2));
+    other.retain_recent();
+    assert!(lim.is_empty());
+}
+
+#[cfg(feature = "std")]
+#[test]
+fn arrivals_per_key() {
+    let clock = FakeRelativeClock::default();
+    let lb = RateLimiter::hashmap_with_clock(Quota::per_second(nonzero!(2u32)), &clock);
+    let now = clock.now();
+    let arrivals = [now, now, now, now + Duration::from_millis(500)];
+
+    let mut conforming = [0u64];
+    assert_eq!(3, lb.check_key_arrivals(&1u32, &arrivals, &mut conforming));
+    assert_eq!(0b1011, conforming[0]);
+    assert_eq!(Ok(()), lb.check_key(&2u32));
+    assert_ne!(Ok(()), lb.check_key(&1u32));
+}
+
+#[test]
+fn saturation_per_key() {
+    let clock = FakeRelativeClock::default();
+    let lb = RateLimiter::hashmap_with_clock(Quota::per_second(nonzero!(4u32)), &clock);
+    assert_eq!(0.0, lb.key_saturation(&1u32));
+    assert_eq!(Ok(()), lb.check_key_n(&1u32, nonzero!(4u32)));
+    assert_eq!(1.0, lb.key_saturation(&1u32));
+    assert_eq!(0.0, lb.key_saturation(&2u32));
+    clock.advance(Duration::from_millis(500));
+    assert_eq!(0.5, lb.key_saturation(&1u32));
+    // Peeking doesn't use up any capacity:
+    assert_eq!(Ok(()), lb.check_key_n(&2u32, nonzero!(4u32)));
+}
+
+#[test]
+fn seeded_keys() {
+    let clock = FakeRelativeClock::default();
+    let lb = RateLimiter::hashmap_with_clock(Quota::per_second(nonzero!(4u32)), &clock);
+    assert_eq!(Ok(()), lb.check_key_n(&1u32, nonzero!(4u32)));
+    lb.seed(vec![
+        (1u32, 0.25),
+        (2u32, 0.75),
+        (3u32, 0.0),
+        (4u32, f64::NAN),
+    ]);
+    // Seeding doesn't return capacity that was already used up:
+    assert_eq!(1.0, lb.key_saturation(&1u32));
+    assert_eq!(0.75, lb.key_saturation(&2u32));
+    assert_eq!(Ok(()), lb.check_key(&2u32));
+    assert_ne!(Ok(()), lb.check_key(&2u32));
+    assert_eq!(lb.len(), 2);
+    assert_eq!(Ok(()), lb.check_key_n(&3u32, nonzero!(4u32)));
+}
+
+#[test]
+fn compaction() {
+    let clock = FakeRelativeClock::default();
+    let lim = RateLimiter::hashmap_with_clock(Quota::per_second(nonzero!(1u32)), &clock);
+    for key in 0..1000u32 {
+        lim.check_key(&key).unwrap();
+    }
+    let peak = lim.capacity();
+    assert_ge!(peak, 1000);
+    clock.advance(Duration::from_secs(2));
+    lim.check_key(&1u32).unwrap();
+
+    assert_eq!(lim.compact(), 999);
+    assert_eq!(lim.len(), 1);
+    assert_lt!(lim.capacity(), peak);
+    assert_eq!(lim.compact(), 0);
+}
+
+#[test]
+fn reset() {
+    let clock = FakeRelativeClock::default();
+    let lim = RateLimiter::hashmap_with_clock(
+        Quota::per_second(nonzero!(2u32)).with_cooldown(Duration::from_secs(1)),
+        &clock,
+    );
+    clock.advance(Duration::from_secs(10));
+    for key in 0..100u32 {
+        lim.check_key_n(&key, nonzero!(2u32)).unwrap();
+    }
+    let negative = lim.check_key(&0).unwrap_err();
+    assert_eq!(
+        negative.wait_time_from(clock.now()),
+        Duration::from_millis(1500)
+    );
+
+    lim.reset();
+    assert_eq!(lim.len(), 100);
+    lim.check_key_n(&0, nonzero!(2u32)).unwrap();
+    match lim.check_key_n(&0, nonzero!(2u32)) {
+        Err(NegativeMultiDecision::BatchNonConforming(2, negative)) => assert_eq!(
+            negative.wait_time_from(clock.now()),
+            Duration::from_millis(500)
+        ),
+        other => panic!("expected a non-conforming batch, got {:?}", other),
+    }
+    lim.retain_recent();
+    assert_eq!(lim.len(), 1);
+    assert_eq!(Ok(()), lim.check_key_n(&1, nonzero!(2u32)));
+}
+
+#[test]
+fn memory_accounting() {
+    let clock = FakeRelativeClock::default();
+    let lb = RateLimiter::hashmap_with_clock(Quota::per_second(nonzero!(1u32)), &clock);
+    assert_eq!(
+        lb.memory_usage(),
+        lb.capacity() * (std::mem::size_of::<u32>() + 8)
+    );
+
+    for key in 0..100u32 {
+        lb.check_key(&key).unwrap();
+    }
+    assert_eq!(lb.len(), 100);
+    assert_ge!(lb.capacity(), 100);
+    assert_ge!(lb.memory_usage(), 100 * (std::mem::size_of::<u32>() + 8));
+}
+
+#[cfg(feature = "std")]
+#[test]
+fn background_sweeper() {
+    let clock = FakeRelativeClock::default();
+    let lim: RateLimiter<u32, HashMapStateStore<u32>, _, _> =
+        RateLimiter::hashmap_with_clock(Quota::per_second(nonzero!(1u32)), &clock);
+    let lim = lim.into_shared();
+    let sweeper = lim.spawn_sweeper(Duration::from_millis(1));
+
+    lim.check_key(&1u32).unwrap();
+    clock.advance(Duration::from_secs(3));
+    for _ in 0..1000 {
+        if lim.is_empty() {
+            break;
+        }
+        std::thread::sleep(Duration::from_millis(1));
+    }
+    assert!(lim.is_empty());
+    sweeper.stop();
+}
+
+#[cfg(feature = "std")]
+#[test]
+fn sweepers_stop_with_the_limiter() {
+    let clock = FakeRelativeClock::default();
+    let lim: RateLimiter<u32, HashMapStateStore<u32>, _, _> =
+        RateLimiter::hashmap_with_clock(Quota::per_second(nonzero!(1u32)), &clock);
+    let lim = lim.into_shared();
+    let sweeper = lim.spawn_sweeper(Duration::from_millis(1));
+    let future = lim.sweeper(Duration::from_millis(1));
+    drop(lim);
+
+    futures::executor::block_on(future);
+    sweeper.stop();
+}
+
+/// Logs the lifecycle of keys, for the observer tests.
+#[derive(Default)]
+struct Log(std::sync::Mutex<Vec<(&'static str, u32)>>);
+
+impl governor::state::keyed::KeyObserver<u32> for Log {
+    fn key_created(&self, key: &u32) {
+        self.0.lock().unwrap().push(("created", *key));
+    }
+    fn key_evicted(&self, key: &u32) {
+        self.0.lock().unwrap().push(("evicted", *key));
+    }
+    fn key_penalized(&self, key: &u32) {
+        self.0.lock().unwrap().push(("penalized", *key));
+    }
+}
+
+#[test]
+fn lifecycle_observer() {
+    use governor::state::keyed::ObservedStateStore;
+
+    let clock = FakeRelativeClock::default();
+    let state = ObservedStateStore::new(HashMapStateStore::default(), Log::default());
+    let lim: RateLimiter<_, _, _, NoOpMiddleware<_>> =
+        RateLimiter::new(Quota::per_second(nonzero!(1u32)), state, &clock);
+
+    lim.check_key(&1).unwrap();
+    lim.check_key(&1).unwrap_err();
+    // Queries are neither decisions nor creations:
+    assert_eq!(lim.key_saturation(&1), 1.0);
+    assert_eq!(lim.key_saturation(&3), 0.0);
+    clock.advance(Duration::from_millis(500));
+    lim.check_key(&2).unwrap();
+    clock.advance(Duration::from_millis(1500));
+    lim.retain_recent();
+    assert_eq!(lim.len(), 1);
+    clock.advance(Duration::from_secs(1));
+    lim.check_key(&1).unwrap();
+
+    let log = lim.into_state_store().into_parts().1;
+    assert_eq!(
+        log.0.into_inner().unwrap(),
+        vec![
+            ("created", 1),
+            ("penalized", 1),
+            ("created", 2),
+            ("evicted", 1),
+            ("created", 1)
+        ]
+    );
+}
+
+#[test]
+fn observers_dont_see_refunds() {
+    use governor::{all_of, state::keyed::ObservedStateStore, state::multi::Debit};
+
+    let clock = FakeRelativeClock::default();
+    let state = ObservedStateStore::new(HashMapStateStore::default(), Log::default());
+    let lim: RateLimiter<_, _, _, NoOpMiddleware<_>> =
+        RateLimiter::new(Quota::per_second(nonzero!(1u32)), state, &clock);
+
+    // Whichever of each pair of keys is debited first, one of the pairs is rolled back:
+    lim.check_key(&2).unwrap();
+    lim.check_key(&3).unwrap();
+    all_of(((&lim, &1), (&lim, &2))).check().unwrap_err();
+    all_of(((&lim, &3), (&lim, &4))).check().unwrap_err();
+    (&lim, &5).refund(nonzero!(1u32));
+    for key in &[1, 4] {
+        lim.check_key(key).unwrap();
+    }
+
+    let mut log = lim
+        .into_state_store()
+        .into_parts()
+        .1
+         .0
+        .into_inner()
+        .unwrap();
+    log.sort_unstable();
+    assert_eq!(
+        log,
+        vec![
+            ("created", 1),
+            ("created", 2),
+            ("created", 3),
+            ("created", 4),
+            ("penalized", 2),
+            ("penalized", 3)
+        ]
+    );
+}
+
+#[test]
+fn borrowed_keys() {
+    let clock = FakeRelativeClock::default();
+    let lb: RateLimiter<String, _, _, NoOpMiddleware<_>> =
+        RateLimiter::hashmap_with_clock(Quota::per_second(nonzero!(2u32)), &clock);
+
+    assert_eq!(Ok(()), lb.check_key_borrowed("foo"));
+    assert_eq!(Ok(()), lb.check_key(&"foo".to_string()));
+    assert_ne!(Ok(()), lb.check_key_borrowed("foo"));
+    assert_eq!(lb.len(), 1);
+
+    assert_eq!(Ok(()), lb.check_key_borrowed_n("bar", nonzero!(2u32)));
+    assert_ne!(Ok(()), lb.check_key_borrowed_n("bar", nonzero!(1u32)));
+    assert_eq!(lb.len(), 2);
+}
+
+#[cfg(feature = "std")]
+#[test]
+fn names_limiters_with_custom_clocks() {
+    use governor::{state::keyed::HashMapKeyedLimiter, KeyedRateLimiter};
+
+    struct Limits {
+        plain: HashMapKeyedLimiter<u32, FakeRelativeClock>,
+        default_store: KeyedRateLimiter<u32, FakeRelativeClock>,
+    }
+
+    let clock = FakeRelativeClock::default();
+    let quota = Quota::per_second(nonzero!(1u32));
+    let limits = Limits {
+        plain: RateLimiter::hashmap_with_clock(quota, &clock),
+        default_store: RateLimiter::keyed_with_clock(quota, &clock),
+    };
+    let stats = RateLimiter::keyed_with_stats_and_clock(quota, Duration::from_secs(60), &clock);
+    let creations = RateLimiter::keyed_with_creation_quota_and_clock(quota, quota, &clock);
+
+    assert_eq!(Ok(()), limits.plain.check_key(&1));
+    assert_eq!(Ok(()), limits.default_store.check_key(&1));
+    assert_eq!(Ok(()), stats.check_key(&1));
+    assert_eq!(Ok(()), creations.check_key(&1));
+    assert_ne!(Ok(()), creations.check_key(&2));
+
+    clock.advance(Duration::from_secs(1));
+    assert_eq!(Ok(()), limits.plain.check_key(&1));
+    assert_eq!(Ok(()), limits.default_store.check_key(&1));
+    assert_eq!(stats.key_stats(&1).map(|s| s.admitted() > 0.0), Some(true));
+    assert_eq!(Ok(()), creations.check_key(&2));
+}
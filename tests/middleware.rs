@@ -22,7 +22,6 @@ impl RateLimitingMiddleware<<FakeRelativeClock as clock::Clock>::Instant> for My
         _limiter: impl Into<StateSnapshot>,
         _start_time: <FakeRelativeClock as clock::Clock>::Instant,
     ) -> Self::NegativeOutcome {
-        ()
     }
 }
 
@@ -63,6 +62,20 @@ fn state_information() {
     assert!(lim.check().is_err());
 }
 
+#[test]
+fn state_information_carries_the_label() {
+    let clock = FakeRelativeClock::default();
+    let lim = RateLimiter::direct_with_clock(Quota::per_second(nonzero!(4u32)), &clock)
+        .with_middleware::<StateInformationMiddleware>();
+    assert_eq!(Ok(None), lim.check().map(|outcome| outcome.label()));
+    let lim = lim.with_label("search");
+    assert_eq!(lim.label(), Some("search"));
+    assert_eq!(
+        Ok(Some("search")),
+        lim.check().map(|outcome| outcome.label())
+    );
+}
+
 #[test]
 #[cfg(feature = "std")]
 fn mymw_derives() {
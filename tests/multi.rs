@@ -0,0 +1,75 @@
+use governor::{
+    all_of,
+    clock::FakeRelativeClock,
+    middleware::NoOpMiddleware,
+    state::{keyed::HashMapStateStore, InMemoryState, NotKeyed},
+    NegativeMultiDecision, Quota, RateLimiter,
+};
+use nonzero_ext::nonzero;
+use std::time::Duration;
+
+#[test]
+fn denied_cells_are_refunded() {
+    let clock = FakeRelativeClock::default();
+    let global = RateLimiter::direct_with_clock(Quota::per_second(nonzero!(3u32)), &clock);
+    let endpoint = RateLimiter::direct_with_clock(Quota::per_second(nonzero!(2u32)), &clock);
+    let user: RateLimiter<_, HashMapStateStore<_>, _, NoOpMiddleware<_>> = RateLimiter::new(
+        Quota::per_second(nonzero!(1u32)),
+        Default::default(),
+        &clock,
+    );
+
+    assert_eq!(Ok(()), all_of((&global, &endpoint, (&user, &1))).check());
+    // The user is out of capacity, so the global & endpoint limits get their cells back:
+    for _ in 0..5 {
+        let denied = all_of((&global, &endpoint, (&user, &1)))
+            .check()
+            .unwrap_err();
+        assert_eq!(denied.quota(), Quota::per_second(nonzero!(1u32)));
+    }
+    assert_eq!(Ok(()), all_of((&global, &endpoint, (&user, &2))).check());
+    assert_eq!(Ok(()), global.check());
+    assert_ne!(Ok(()), global.check());
+
+    clock.advance(Duration::from_secs(1));
+    assert_eq!(Ok(()), all_of((&global, &endpoint, (&user, &1))).check());
+}
+
+#[test]
+fn batches_are_refunded() {
+    let clock = FakeRelativeClock::default();
+    let big: RateLimiter<NotKeyed, InMemoryState, _, NoOpMiddleware<_>> =
+        RateLimiter::direct_with_clock(Quota::per_second(nonzero!(10u32)), &clock);
+    let small = RateLimiter::direct_with_clock(Quota::per_second(nonzero!(5u32)), &clock);
+
+    let both = all_of((&big, &small));
+    assert_eq!(
+        Err(NegativeMultiDecision::InsufficientCapacity(5)),
+        both.check_n(nonzero!(6u32))
+    );
+    assert_eq!(Ok(()), both.check_n(nonzero!(4u32)));
+    assert!(matches!(
+        both.check_n(nonzero!(4u32)),
+        Err(NegativeMultiDecision::BatchNonConforming(4, _))
+    ));
+    // Only the small limiter used up capacity:
+    assert_eq!(Ok(()), big.check_n(nonzero!(6u32)));
+}
+
+#[test]
+fn debits_in_canonical_order() {
+    let clock = FakeRelativeClock::default();
+    let first = RateLimiter::direct_with_clock(Quota::per_second(nonzero!(1u32)), &clock);
+    let second = RateLimiter::direct_with_clock(Quota::per_minute(nonzero!(1u32)), &clock);
+    let keyed: RateLimiter<_, HashMapStateStore<_>, _, NoOpMiddleware<_>> =
+        RateLimiter::new(Quota::per_hour(nonzero!(1u32)), Default::default(), &clock);
+    assert_eq!(Ok(()), first.check());
+    assert_eq!(Ok(()), second.check());
+    assert_eq!(Ok(()), keyed.check_key(&1));
+
+    // All three deny the cell; the denial comes from whichever is first in the canonical order,
+    // regardless of the order that they were passed in:
+    let forward = all_of((&first, &second, (&keyed, &1))).check().unwrap_err();
+    let backward = all_of(((&keyed, &1), &second, &first)).check().unwrap_err();
+    assert_eq!(forward.quota(), backward.quota());
+}
@@ -22,11 +22,12 @@ impl Arbitrary for Count {
 }
 
 fn test_config() -> ProptestConfig {
-    let mut cfg = ProptestConfig::default();
-    cfg.failure_persistence = Some(Box::new(FileFailurePersistence::WithSource("regressions")));
-    //cfg.timeout = 20;
-    cfg.verbose = 0; // 2 for extra verbosity;
-    cfg
+    ProptestConfig {
+        failure_persistence: Some(Box::new(FileFailurePersistence::WithSource("regressions"))),
+        //timeout: 20,
+        verbose: 0, // 2 for extra verbosity;
+        ..ProptestConfig::default()
+    }
 }
 
 #[cfg(feature = "std")]
@@ -42,7 +43,8 @@ fn accurate_not_until() {
     proptest!(test_config(), |(capacity: Count, additional: Count, wait_time_parts: Count)| {
         let clock = FakeRelativeClock::default();
         let lb = RateLimiter::direct_with_clock(Quota::per_second(capacity.0), &clock);
-        let step = Duration::from_secs(1) / capacity.0.get();
+        // Quotas replenish at exact fractional rates, so round the step up to a whole ns:
+        let step = Duration::from_nanos(1_000_000_000u64.div_ceil(capacity.0.get() as u64));
 
         // use up the burst capacity:
         for _ in 0..capacity.0.get() {
@@ -0,0 +1,21 @@
+#![cfg(all(feature = "std", feature = "rayon"))]
+
+use all_asserts::*;
+use governor::{state::direct::ParallelIteratorRateLimitExt, Quota, RateLimiter};
+use nonzero_ext::*;
+use rayon::prelude::*;
+use std::time::{Duration, Instant};
+
+#[test]
+fn paces_across_workers() {
+    let i = Instant::now();
+    let quota = Quota::with_period(Duration::from_millis(1))
+        .unwrap()
+        .allow_burst(nonzero!(10u32));
+    let lim = RateLimiter::direct(quota);
+
+    let items: Vec<u32> = (0..50u32).into_par_iter().throttle(&lim).collect();
+    assert_eq!(items, (0..50).collect::<Vec<_>>());
+    // The first 10 items go through in a burst, the remaining 40 at 1 per millisecond:
+    assert_ge!(i.elapsed(), Duration::from_millis(40));
+}
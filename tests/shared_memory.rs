@@ -0,0 +1,59 @@
+#![cfg(all(unix, feature = "shared-memory"))]
+
+use governor::{state::SharedMemoryState, Quota, RateLimiter};
+use nonzero_ext::nonzero;
+use std::path::PathBuf;
+
+fn path(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("governor-{}-{}", name, std::process::id()))
+}
+
+#[test]
+fn limiters_share_state() {
+    let path = path("shared");
+    let quota = Quota::per_minute(nonzero!(3u32));
+    let first = RateLimiter::shared_memory(quota, &path).unwrap();
+    assert_eq!(Ok(()), first.check());
+    let second = RateLimiter::shared_memory(quota, &path).unwrap();
+    assert_eq!(Ok(()), second.check());
+    assert_eq!(Ok(()), first.check());
+    assert!(second.check().is_err());
+    assert!(first.check().is_err());
+    drop(first);
+
+    // The state outlives the rate limiters that used it:
+    drop(second);
+    let third = RateLimiter::shared_memory(quota, &path).unwrap();
+    assert!(third.check().is_err());
+    assert!(format!("{:?}", third).contains("SharedMemoryState"));
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn limiters_share_state_across_threads() {
+    let path = path("threads");
+    let quota = Quota::per_hour(nonzero!(100u32));
+    let admitted: u32 = (0..4)
+        .map(|_| {
+            let path = path.clone();
+            std::thread::spawn(move || {
+                let lim = RateLimiter::shared_memory(quota, &path).unwrap();
+                (0..50).filter(|_| lim.check().is_ok()).count() as u32
+            })
+        })
+        .collect::<Vec<_>>()
+        .into_iter()
+        .map(|t| t.join().unwrap())
+        .sum();
+    assert_eq!(admitted, 100);
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn rejects_other_files() {
+    let path = path("other");
+    std::fs::write(&path, b"this is not a rate limiter state at all").unwrap();
+    let err = SharedMemoryState::open(&path).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    std::fs::remove_file(&path).unwrap();
+}
@@ -1,9 +1,11 @@
 #![cfg(feature = "std")]
+#![allow(clippy::unit_cmp)]
 
 use all_asserts::*;
 use futures::executor::block_on;
 use futures::SinkExt;
-use governor::{prelude::*, Jitter, Quota, RateLimiter};
+use governor::state::direct::{Enforcement, Overflow};
+use governor::{prelude::*, Quota, RateLimiter};
 use nonzero_ext::*;
 use std::sync::Arc;
 use std::time::{Duration, Instant};
@@ -27,7 +29,7 @@ fn sink() {
 
     let result = sink.get_ref();
     assert_eq!(result.len(), 12);
-    assert!(result.into_iter().all(|&elt| elt == ()));
+    assert!(result.iter().all(|&elt| elt == ()));
 }
 
 #[test]
@@ -42,8 +44,11 @@ fn auxilliary_sink_methods() {
     assert!(block_on(sink.close()).is_ok());
 }
 
-#[cfg_attr(feature = "jitter", test)]
+#[cfg(feature = "jitter")]
+#[test]
 fn sink_with_jitter() {
+    use governor::Jitter;
+
     let lim = Arc::new(RateLimiter::direct(Quota::per_second(nonzero!(10u32))));
     let mut sink =
         Vec::new().ratelimit_sink_with_jitter(&lim, Jitter::up_to(Duration::from_nanos(1)));
@@ -64,3 +69,37 @@ fn sink_with_jitter() {
     assert_eq!(result.len(), 12);
     assert!(result.into_iter().all(|elt| elt == ()));
 }
+
+#[test]
+fn policed_sink() {
+    let lim = RateLimiter::direct(Quota::per_second(nonzero!(10u32)).allow_burst(nonzero!(2u32)));
+    let mut sink = Vec::new()
+        .ratelimit_sink(&lim)
+        .with_enforcement(Enforcement::Police);
+    assert_eq!(sink.enforcement(), Enforcement::Police);
+    let i = Instant::now();
+    for elt in 0..5 {
+        block_on(sink.send(elt)).unwrap();
+    }
+    assert_lt!(i.elapsed(), Duration::from_millis(100));
+    assert_eq!(sink.dropped(), 3);
+    assert_eq!(sink.into_inner(), vec![0, 1]);
+}
+
+#[test]
+fn shaped_sink_with_overflowing_queue() {
+    let lim = RateLimiter::direct(Quota::per_second(nonzero!(10u32)).allow_burst(nonzero!(2u32)));
+    let mut sink = Vec::new()
+        .ratelimit_sink(&lim)
+        .with_enforcement(Enforcement::shape(nonzero!(2usize), Overflow::DropOldest));
+    let i = Instant::now();
+    for elt in 0..5 {
+        block_on(sink.feed(elt)).unwrap();
+    }
+    assert_lt!(i.elapsed(), Duration::from_millis(100));
+    assert_eq!(sink.get_ref(), &vec![0, 1]);
+    block_on(sink.flush()).unwrap();
+    assert_range!((200..=300), i.elapsed().as_millis());
+    assert_eq!(sink.dropped(), 1);
+    assert_eq!(sink.into_inner(), vec![0, 1, 3, 4]);
+}
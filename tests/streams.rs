@@ -1,7 +1,8 @@
 #![cfg(feature = "std")]
 
 use futures::executor::block_on;
-use futures::{stream, StreamExt};
+use futures::{stream, Stream, StreamExt};
+use governor::state::direct::{Enforcement, Overflow};
 use governor::{prelude::*, Quota, RateLimiter};
 use nonzero_ext::*;
 use std::sync::Arc;
@@ -26,3 +27,65 @@ fn stream() {
     assert!(i.elapsed() > Duration::from_millis(200));
     assert!(i.elapsed() <= Duration::from_millis(300));
 }
+
+#[test]
+fn policed_stream() {
+    let lim = RateLimiter::direct(Quota::per_second(nonzero!(10u32)).allow_burst(nonzero!(2u32)));
+    let mut stream = stream::iter(0..5)
+        .ratelimit_stream(&lim)
+        .with_enforcement(Enforcement::Police);
+    assert_eq!(stream.enforcement(), Enforcement::Police);
+    let i = Instant::now();
+    assert_eq!(block_on((&mut stream).collect::<Vec<_>>()), vec![0, 1]);
+    assert!(i.elapsed() < Duration::from_millis(100));
+    assert_eq!(stream.dropped(), 3);
+}
+
+#[test]
+fn shaped_stream_with_overflowing_queue() {
+    let lim = RateLimiter::direct(Quota::per_second(nonzero!(10u32)).allow_burst(nonzero!(2u32)));
+    let mut stream = stream::iter(0..5)
+        .ratelimit_stream(&lim)
+        .with_enforcement(Enforcement::shape(nonzero!(2usize), Overflow::DropNewest));
+    let i = Instant::now();
+    assert_eq!(
+        block_on((&mut stream).collect::<Vec<_>>()),
+        vec![0, 1, 2, 4]
+    );
+    assert!(i.elapsed() >= Duration::from_millis(200));
+    assert_eq!(stream.dropped(), 1);
+
+    let lim = RateLimiter::direct(Quota::per_second(nonzero!(10u32)).allow_burst(nonzero!(1u32)));
+    let mut stream = stream::iter(0..4)
+        .ratelimit_stream(&lim)
+        .with_enforcement(Enforcement::shape(nonzero!(2usize), Overflow::Backpressure));
+    assert_eq!(block_on(stream.next()), Some(0));
+    assert_eq!(block_on(stream.next()), Some(1));
+    let (_, queued) = stream.into_parts();
+    assert_eq!(queued, vec![2]);
+}
+
+#[test]
+fn size_hints_count_queued_items() {
+    let lim = RateLimiter::direct(Quota::per_second(nonzero!(10u32)));
+    let stream = stream::iter(0..5)
+        .ratelimit_stream(&lim)
+        .with_enforcement(Enforcement::Police);
+    // Policing may drop any of the items:
+    assert_eq!(stream.size_hint(), (0, Some(5)));
+
+    let lim = RateLimiter::direct(Quota::per_second(nonzero!(10u32)).allow_burst(nonzero!(1u32)));
+    let mut stream = stream::iter(0..4)
+        .ratelimit_stream(&lim)
+        .with_enforcement(Enforcement::shape(nonzero!(2usize), Overflow::Backpressure));
+    assert_eq!(stream.size_hint(), (4, Some(4)));
+    assert_eq!(block_on(stream.next()), Some(0));
+    assert_eq!(block_on(stream.next()), Some(1));
+    // One item is queued, and one is left in the underlying stream:
+    assert_eq!(stream.size_hint(), (2, Some(2)));
+    let mut stream =
+        stream.with_enforcement(Enforcement::shape(nonzero!(2usize), Overflow::DropNewest));
+    assert_eq!(stream.size_hint(), (1, Some(2)));
+    assert_eq!(block_on((&mut stream).collect::<Vec<_>>()), vec![2, 3]);
+    assert_eq!(stream.size_hint(), (0, Some(0)));
+}
@@ -0,0 +1,101 @@
+#![cfg(feature = "testing")]
+
+use governor::{
+    nanos::Nanos,
+    state::{NotKeyed, StateStore},
+    testing::{self, ArrivalProcess},
+    Quota,
+};
+use nonzero_ext::nonzero;
+use proptest::prelude::*;
+use std::num::NonZeroU32;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// A state store that is implemented differently from the crate's own stores.
+#[derive(Default)]
+struct MutexState(Mutex<Option<Nanos>>);
+
+impl StateStore for MutexState {
+    type Key = NotKeyed;
+
+    fn measure_and_replace<T, F, E>(&self, _key: &Self::Key, f: F) -> Result<T, E>
+    where
+        F: Fn(Option<Nanos>) -> Result<(T, Nanos), E>,
+    {
+        let mut state = self.0.lock().unwrap();
+        let (result, tat) = f(*state)?;
+        *state = Some(tat);
+        Ok(result)
+    }
+}
+
+fn arrival_process() -> impl Strategy<Value = ArrivalProcess> {
+    prop_oneof![
+        (1..1_000u64).prop_map(|ms| ArrivalProcess::constant(Duration::from_millis(ms))),
+        (1..1_000u64, any::<u64>())
+            .prop_map(|(ms, seed)| ArrivalProcess::poisson(Duration::from_millis(ms), seed)),
+        (1..20u32, 0..10u64, 1..5_000u64).prop_map(|(burst, within, pause)| {
+            ArrivalProcess::bursty(
+                burst,
+                Duration::from_millis(within),
+                Duration::from_millis(within * u64::from(burst) + pause),
+            )
+        }),
+    ]
+}
+
+proptest! {
+    #[test]
+    fn custom_state_store_matches_reference(
+        per_second in 1..100u32,
+        burst in 1..20u32,
+        process in arrival_process(),
+    ) {
+        let quota = Quota::per_second(NonZeroU32::new(per_second).unwrap())
+            .allow_burst(NonZeroU32::new(burst).unwrap());
+        let arrivals = process.arrivals().take(500);
+        if let Err(divergence) = testing::check_state_store(MutexState::default(), quota, arrivals) {
+            panic!("{}", divergence);
+        }
+    }
+}
+
+proptest! {
+    #[test]
+    fn rate_limiters_match_model(data in proptest::collection::vec(any::<u8>(), 0..512)) {
+        testing::fuzz::run(&data);
+    }
+}
+
+#[cfg(feature = "arbitrary")]
+proptest! {
+    #[test]
+    fn arbitrary_scenarios_match_model(data in proptest::collection::vec(any::<u8>(), 0..512)) {
+        use arbitrary::{Arbitrary, Unstructured};
+
+        let scenario = testing::fuzz::Scenario::arbitrary(&mut Unstructured::new(&data)).unwrap();
+        scenario.run();
+    }
+}
+
+#[cfg(feature = "arbitrary")]
+#[test]
+fn arbitrary_quotas_are_valid() {
+    use arbitrary::{Arbitrary, Unstructured};
+
+    // Running out of bytes generates the smallest quota:
+    let quota = Quota::arbitrary(&mut Unstructured::new(&[])).unwrap();
+    assert_eq!(quota.burst_size(), nonzero!(1u32));
+    assert_eq!(quota.replenish_interval(), Duration::from_nanos(1));
+
+    // One cell per second (with no shifts of the magnitudes), a burst of 5 and no cooldown:
+    let data = [
+        0, 0x00, 0xca, 0x9a, 0x3b, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 5, 0, 0, 0, 255,
+    ];
+    let quota = Quota::arbitrary(&mut Unstructured::new(&data)).unwrap();
+    assert_eq!(
+        quota,
+        Quota::per_second(nonzero!(1u32)).allow_burst(nonzero!(5u32))
+    );
+}
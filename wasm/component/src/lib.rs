@@ -0,0 +1,75 @@
+//! A WebAssembly component exporting governor's rate limiters through the
+//! `governor:ratelimit/limiter` interface in `wit/governor.wit`.
+
+use governor::component::{self, Direct, Keyed};
+use std::sync::OnceLock;
+
+wit_bindgen::generate!({
+    world: "ratelimiter",
+    path: "../../wit",
+});
+
+use exports::governor::ratelimit::limiter::{self, Decision, Guest, Quota};
+
+struct Component;
+
+/// The keyed rate limiter that `decide` checks against, set by `configure`.
+static CONFIGURED: OnceLock<Keyed> = OnceLock::new();
+
+fn decision(decision: component::Decision) -> Decision {
+    match decision {
+        component::Decision::Allowed => Decision::Allowed,
+        component::Decision::Limited(wait) => Decision::Limited(wait),
+        component::Decision::InsufficientCapacity => Decision::InsufficientCapacity,
+    }
+}
+
+impl Guest for Component {
+    type Direct = Direct;
+    type Keyed = Keyed;
+
+    fn configure(quota: Quota) -> Result<(), String> {
+        let limiter = Keyed::new(quota.period_nanos, quota.burst)
+            .ok_or_else(|| format!("invalid quota {:?}", quota))?;
+        CONFIGURED
+            .set(limiter)
+            .map_err(|_| "the rate limiter is already configured".to_string())
+    }
+
+    fn decide(key: Vec<u8>) -> Result<Decision, String> {
+        let limiter = CONFIGURED
+            .get()
+            .ok_or_else(|| "the rate limiter is not configured".to_string())?;
+        Ok(decision(limiter.check(&key, 1)))
+    }
+}
+
+impl limiter::GuestDirect for Direct {
+    fn create(quota: Quota) -> Result<limiter::Direct, String> {
+        Direct::new(quota.period_nanos, quota.burst)
+            .map(limiter::Direct::new)
+            .ok_or_else(|| format!("invalid quota {:?}", quota))
+    }
+
+    fn check(&self, n: u32) -> Decision {
+        decision(Direct::check(self, n))
+    }
+}
+
+impl limiter::GuestKeyed for Keyed {
+    fn create(quota: Quota) -> Result<limiter::Keyed, String> {
+        Keyed::new(quota.period_nanos, quota.burst)
+            .map(limiter::Keyed::new)
+            .ok_or_else(|| format!("invalid quota {:?}", quota))
+    }
+
+    fn check(&self, key: Vec<u8>, n: u32) -> Decision {
+        decision(Keyed::check(self, &key, n))
+    }
+
+    fn retain_recent(&self) -> u64 {
+        Keyed::retain_recent(self)
+    }
+}
+
+export!(Component);
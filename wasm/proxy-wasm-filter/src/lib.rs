@@ -0,0 +1,145 @@
+//! An Envoy HTTP filter that rate-limits requests by the value of a request header, sharing
+//! the limit between all of Envoy's worker threads.
+//!
+//! Each worker runs its own instance of the filter, so the rate-limiting state of each key
+//! lives in Envoy's shared data: For every request, the filter imports the key's state into a
+//! rate limiter whose [`ExternalClock`] is set to the host's time, makes the decision, and
+//! stores the exported state back with compare-and-swap, retrying if another worker updated it
+//! in the meantime.
+//!
+//! The plugin configuration is a quota like `"100/min"`, optionally followed by a header name
+//! to take keys from (`":authority"` by default), e.g. `"10 per second x-api-key"`. Shared data
+//! entries are never removed, so keys should come from a bounded set.
+
+use governor::clock::{Clock, ExternalClock};
+use governor::{Quota, RateLimiter};
+use proxy_wasm::traits::{Context, HttpContext, RootContext};
+use proxy_wasm::types::{Action, ContextType, LogLevel, Status};
+use std::convert::TryFrom;
+use std::time::UNIX_EPOCH;
+
+proxy_wasm::main! {{
+    proxy_wasm::set_log_level(LogLevel::Info);
+    proxy_wasm::set_root_context(|_| -> Box<dyn RootContext> {
+        Box::new(Root { config: None })
+    });
+}}
+
+const DEFAULT_HEADER: &str = ":authority";
+
+#[derive(Clone)]
+struct Config {
+    quota: Quota,
+    header: String,
+}
+
+fn parse_config(config: &str) -> Option<Config> {
+    let config = config.trim();
+    // The quota may contain spaces ("10 per second"), so the header is whatever follows it:
+    match config.rsplit_once(' ') {
+        Some((quota, header)) if quota.parse::<Quota>().is_ok() => Some(Config {
+            quota: quota.parse().ok()?,
+            header: header.to_string(),
+        }),
+        _ => Some(Config {
+            quota: config.parse().ok()?,
+            header: DEFAULT_HEADER.to_string(),
+        }),
+    }
+}
+
+struct Root {
+    config: Option<Config>,
+}
+
+impl Context for Root {}
+
+impl RootContext for Root {
+    fn on_configure(&mut self, _plugin_configuration_size: usize) -> bool {
+        let config = self
+            .get_plugin_configuration()
+            .and_then(|bytes| String::from_utf8(bytes).ok())
+            .and_then(|config| parse_config(&config));
+        self.config = config;
+        self.config.is_some()
+    }
+
+    fn create_http_context(&self, _context_id: u32) -> Option<Box<dyn HttpContext>> {
+        let config = self.config.clone()?;
+        Some(Box::new(Filter { config }))
+    }
+
+    fn get_type(&self) -> Option<ContextType> {
+        Some(ContextType::HttpContext)
+    }
+}
+
+struct Filter {
+    config: Config,
+}
+
+impl Filter {
+    /// Decides whether a request for `key` may pass, returning the seconds to wait otherwise.
+    fn decide(&self, key: &str) -> Result<(), u64> {
+        let name = format!("governor:{}", key);
+        loop {
+            let clock = ExternalClock::default();
+            if let Ok(since_epoch) = self.get_current_time().duration_since(UNIX_EPOCH) {
+                clock.set_now_nanos(u64::try_from(since_epoch.as_nanos()).unwrap_or(u64::MAX));
+            }
+            let limiter = RateLimiter::direct_with_clock(self.config.quota, &clock);
+
+            let (state, cas) = self.get_shared_data(&name);
+            if let Some(state) = state.and_then(|s| <[u8; 8]>::try_from(&s[..]).ok()) {
+                limiter.import_state(state);
+            }
+            let decision = limiter
+                .check()
+                .map_err(|negative| negative.wait_time_from(clock.now()));
+            if decision.is_err() {
+                // Denied requests don't change the state.
+                return decision.map_err(|wait| wait.as_secs() + 1);
+            }
+            match self.set_shared_data(&name, Some(&limiter.export_state()), cas) {
+                Err(Status::CasMismatch) => continue,
+                _ => return Ok(()),
+            }
+        }
+    }
+}
+
+impl Context for Filter {}
+
+impl HttpContext for Filter {
+    fn on_http_request_headers(&mut self, _num_headers: usize, _end_of_stream: bool) -> Action {
+        let key = self
+            .get_http_request_header(&self.config.header)
+            .unwrap_or_default();
+        match self.decide(&key) {
+            Ok(()) => Action::Continue,
+            Err(retry_after) => {
+                self.send_http_response(
+                    429,
+                    vec![("retry-after", &retry_after.to_string())],
+                    Some(b"Too Many Requests\n"),
+                );
+                Action::Pause
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parses_configs() {
+        let config = parse_config("10 per second x-api-key").unwrap();
+        assert_eq!(config.quota, "10/s".parse().unwrap());
+        assert_eq!(config.header, "x-api-key");
+        let config = parse_config("100/min").unwrap();
+        assert_eq!(config.header, DEFAULT_HEADER);
+        assert!(parse_config("nonsense").is_none());
+    }
+}